@@ -0,0 +1,727 @@
+// Obtains certificates from an ACME server (RFC 8555), validating the domains with TLS-ALPN-01
+// challenges (RFC 8737) that are answered by the TLS server itself.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+    Engine as _,
+};
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::AcmeConfig;
+use crate::http_client::{http_request, HttpResponse};
+use crate::ocsp::StaplingSource;
+use crate::rustls_util::{cert_not_after, der, der_sequence};
+
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+const RENEW_BEFORE_SECS: u64 = 30 * 24 * 60 * 60;
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+
+const OID_COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+const OID_EC_PUBLIC_KEY: &[u64] = &[1, 2, 840, 10045, 2, 1];
+const OID_PRIME256V1: &[u64] = &[1, 2, 840, 10045, 3, 1, 7];
+const OID_ECDSA_WITH_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const OID_SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+const OID_ACME_IDENTIFIER: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 31];
+const OID_EXTENSION_REQUEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 14];
+
+fn acme_error(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+struct CurrentCertificate {
+    certified_key: Arc<CertifiedKey>,
+    not_after: u64,
+}
+
+// Serves the obtained certificate, and the challenge certificates to ACME servers that connect
+// with the acme-tls/1 protocol. The certificate is replaced when it's renewed, without affecting
+// open connections.
+pub struct AcmeCertResolver {
+    domains: Vec<String>,
+    current: RwLock<Option<CurrentCertificate>>,
+    challenge_keys: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl std::fmt::Debug for AcmeCertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcmeCertResolver")
+            .field("domains", &self.domains)
+            .field(
+                "not_after",
+                &self.current.read().as_ref().map(|c| c.not_after),
+            )
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for AcmeCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if is_acme_challenge(&client_hello) {
+            let server_name = client_hello.server_name()?;
+            return self.challenge_keys.read().get(server_name).cloned();
+        }
+        self.current_certified_key()
+    }
+}
+
+impl StaplingSource for AcmeCertResolver {
+    fn current_certified_key(&self) -> Option<Arc<CertifiedKey>> {
+        self.current
+            .read()
+            .as_ref()
+            .map(|c| c.certified_key.clone())
+    }
+}
+
+impl AcmeCertResolver {
+    fn not_after(&self) -> Option<u64> {
+        self.current.read().as_ref().map(|c| c.not_after)
+    }
+}
+
+pub fn is_acme_challenge(client_hello: &ClientHello) -> bool {
+    client_hello
+        .alpn()
+        .is_some_and(|mut alpn| alpn.any(|protocol| protocol == ACME_TLS_ALPN_PROTOCOL))
+}
+
+fn resolver_key(config: &AcmeConfig) -> String {
+    format!(
+        "{} {} {}",
+        config.directory_url,
+        config.cache_dir,
+        config.domains.iter().cloned().collect::<Vec<_>>().join(",")
+    )
+}
+
+// Returns the resolver for the config, so that reloading the config keeps the certificate and
+// the task that renews it. The task stops once the resolver is no longer used.
+pub fn acme_cert_resolver(config: &AcmeConfig) -> Arc<AcmeCertResolver> {
+    static RESOLVERS: OnceLock<Mutex<HashMap<String, Weak<AcmeCertResolver>>>> = OnceLock::new();
+    let mut resolvers = RESOLVERS.get_or_init(|| Mutex::new(HashMap::new())).lock();
+    resolvers.retain(|_, resolver| resolver.strong_count() > 0);
+
+    let key = resolver_key(config);
+    if let Some(resolver) = resolvers.get(&key).and_then(Weak::upgrade) {
+        return resolver;
+    }
+
+    let domains = config
+        .domains
+        .iter()
+        .map(|domain| domain.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let current = match load_cached_certificate(config, &domains[0]) {
+        Ok(current) => current,
+        Err(e) => {
+            warn!(
+                "Failed to load cached ACME certificate for {}: {}",
+                domains[0], e
+            );
+            None
+        }
+    };
+    let resolver = Arc::new(AcmeCertResolver {
+        domains,
+        current: RwLock::new(current),
+        challenge_keys: RwLock::new(HashMap::new()),
+    });
+    resolvers.insert(key, Arc::downgrade(&resolver));
+
+    tokio::spawn(run_acme_manager(config.clone(), Arc::downgrade(&resolver)));
+    resolver
+}
+
+async fn run_acme_manager(config: AcmeConfig, resolver: Weak<AcmeCertResolver>) {
+    loop {
+        let resolver = match resolver.upgrade() {
+            Some(resolver) => resolver,
+            None => {
+                debug!("Stopping ACME certificate renewal for {}", config.cache_dir);
+                return;
+            }
+        };
+        let delay = match renew_if_needed(&config, &resolver).await {
+            Ok(()) => CHECK_INTERVAL,
+            Err(e) => {
+                error!(
+                    "Failed to obtain ACME certificate for {}, retrying in {} minutes: {}",
+                    resolver.domains.join(", "),
+                    RETRY_INTERVAL.as_secs() / 60,
+                    e
+                );
+                RETRY_INTERVAL
+            }
+        };
+        drop(resolver);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn renew_if_needed(config: &AcmeConfig, resolver: &AcmeCertResolver) -> std::io::Result<()> {
+    if let Some(not_after) = resolver.not_after() {
+        if not_after > unix_time() + RENEW_BEFORE_SECS {
+            return Ok(());
+        }
+    }
+
+    info!(
+        "Requesting ACME certificate for {} from {}",
+        resolver.domains.join(", "),
+        config.directory_url
+    );
+    let account_key = load_or_create_account_key(config)?;
+    let mut client = AcmeClient::new(&config.directory_url, account_key).await?;
+    client.create_account(config.email.as_deref()).await?;
+
+    let result = client.obtain_certificate(resolver).await;
+    resolver.challenge_keys.write().clear();
+    let (cert_pem, key_pkcs8) = result?;
+
+    let key_pem = encode_pem("PRIVATE KEY", &key_pkcs8);
+    let current = parse_certificate(cert_pem.as_bytes(), key_pem.as_bytes())?;
+    let domain = &resolver.domains[0];
+    let cache_dir = Path::new(&config.cache_dir);
+    write_cache_file(
+        &cache_dir.join(format!("{}.key", domain)),
+        key_pem.as_bytes(),
+    )?;
+    write_cache_file(
+        &cache_dir.join(format!("{}.crt", domain)),
+        cert_pem.as_bytes(),
+    )?;
+
+    info!(
+        "Obtained ACME certificate for {}, expiring in {} days",
+        resolver.domains.join(", "),
+        current.not_after.saturating_sub(unix_time()) / (24 * 60 * 60)
+    );
+    *resolver.current.write() = Some(current);
+    Ok(())
+}
+
+fn encode_pem(label: &str, der: &[u8]) -> String {
+    let encoded = BASE64.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+fn read_pkcs8_key(pem: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut reader = std::io::Cursor::new(pem);
+    for item in std::iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose()) {
+        if let rustls_pemfile::Item::PKCS8Key(key) = item? {
+            return Ok(key);
+        }
+    }
+    Err(acme_error("no PKCS8 private key found".to_string()))
+}
+
+fn parse_certificate(cert_pem: &[u8], key_pem: &[u8]) -> std::io::Result<CurrentCertificate> {
+    let mut reader = std::io::Cursor::new(cert_pem);
+    let mut certs = vec![];
+    for item in std::iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose()) {
+        if let rustls_pemfile::Item::X509Certificate(cert) = item? {
+            certs.push(rustls::Certificate(cert));
+        }
+    }
+    let not_after = certs
+        .first()
+        .and_then(|cert| cert_not_after(&cert.0))
+        .ok_or_else(|| acme_error("invalid certificate chain".to_string()))?;
+
+    let key = rustls::PrivateKey(read_pkcs8_key(key_pem)?);
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| acme_error(format!("invalid certificate key: {}", e)))?;
+    Ok(CurrentCertificate {
+        certified_key: Arc::new(CertifiedKey::new(certs, signing_key)),
+        not_after,
+    })
+}
+
+fn load_cached_certificate(
+    config: &AcmeConfig,
+    domain: &str,
+) -> std::io::Result<Option<CurrentCertificate>> {
+    let cache_dir = Path::new(&config.cache_dir);
+    let cert_pem = match std::fs::read(cache_dir.join(format!("{}.crt", domain))) {
+        Ok(cert_pem) => cert_pem,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key_pem = std::fs::read(cache_dir.join(format!("{}.key", domain)))?;
+    parse_certificate(&cert_pem, &key_pem).map(Some)
+}
+
+// Writes to a temporary file first, so that an interrupted write doesn't leave a broken file.
+fn write_cache_file(path: &PathBuf, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    std::io::Write::write_all(&mut file, contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+fn generate_key() -> std::io::Result<Vec<u8>> {
+    let rng = SystemRandom::new();
+    EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map(|pkcs8| pkcs8.as_ref().to_vec())
+        .map_err(|_| acme_error("failed to generate key".to_string()))
+}
+
+// The account key is kept per directory, since accounts aren't shared between ACME servers.
+fn load_or_create_account_key(config: &AcmeConfig) -> std::io::Result<Vec<u8>> {
+    let directory_hash = Sha256::digest(config.directory_url.as_bytes());
+    let directory_id = directory_hash[0..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let path = Path::new(&config.cache_dir).join(format!("account-{}.key", directory_id));
+    match std::fs::read(&path) {
+        Ok(pem) => return read_pkcs8_key(&pem),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    std::fs::create_dir_all(&config.cache_dir)?;
+    let pkcs8 = generate_key()?;
+    write_cache_file(&path, encode_pem("PRIVATE KEY", &pkcs8).as_bytes())?;
+    Ok(pkcs8)
+}
+
+struct AcmeClient {
+    key_pair: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    thumbprint: String,
+    directory: Value,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(directory_url: &str, account_key: Vec<u8>) -> std::io::Result<Self> {
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &account_key)
+            .map_err(|e| acme_error(format!("invalid account key: {}", e)))?;
+        // The public key is an uncompressed point.
+        let public_key = key_pair.public_key().as_ref();
+        let x = BASE64_URL.encode(&public_key[1..33]);
+        let y = BASE64_URL.encode(&public_key[33..65]);
+        // The thumbprint is of the required members in lexicographic order (RFC 7638).
+        let thumbprint = BASE64_URL.encode(Sha256::digest(
+            format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y).as_bytes(),
+        ));
+        let jwk = json!({"crv": "P-256", "kty": "EC", "x": x, "y": y});
+
+        let response = http_request("GET", directory_url, &[], &[]).await?;
+        let directory = parse_json_response(&response)?;
+
+        Ok(Self {
+            key_pair,
+            rng: SystemRandom::new(),
+            jwk,
+            thumbprint,
+            directory,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn directory_url(&self, name: &str) -> std::io::Result<String> {
+        json_str(&self.directory, name).map(str::to_string)
+    }
+
+    async fn take_nonce(&mut self) -> std::io::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let new_nonce_url = self.directory_url("newNonce")?;
+        let response = http_request("GET", &new_nonce_url, &[], &[]).await?;
+        response
+            .header("Replay-Nonce")
+            .map(str::to_string)
+            .ok_or_else(|| acme_error("no nonce in newNonce response".to_string()))
+    }
+
+    // Sends a signed request, or a POST-as-GET request when there's no payload.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> std::io::Result<HttpResponse> {
+        let payload = match payload {
+            Some(payload) => BASE64_URL.encode(payload.to_string()),
+            None => String::new(),
+        };
+        // A rejected nonce is retried once with the fresh nonce from the error response.
+        for attempt in 0..2 {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.take_nonce().await?,
+                "url": url,
+            });
+            match self.kid {
+                Some(ref kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = BASE64_URL.encode(protected.to_string());
+            let signature = self
+                .key_pair
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| acme_error("failed to sign ACME request".to_string()))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": BASE64_URL.encode(signature.as_ref()),
+            });
+
+            let response = http_request(
+                "POST",
+                url,
+                &[("Content-Type", "application/jose+json")],
+                body.to_string().as_bytes(),
+            )
+            .await?;
+            self.nonce = response.header("Replay-Nonce").map(str::to_string);
+            if response.is_success() {
+                return Ok(response);
+            }
+
+            let problem = serde_json::from_slice::<Value>(&response.body).unwrap_or_default();
+            let problem_type = problem["type"].as_str().unwrap_or_default();
+            if problem_type == "urn:ietf:params:acme:error:badNonce" && attempt == 0 {
+                debug!("ACME server rejected the nonce, retrying");
+                continue;
+            }
+            return Err(acme_error(format!(
+                "ACME request to {} failed with {}: {}",
+                url,
+                response.status_line,
+                String::from_utf8_lossy(&response.body)
+            )));
+        }
+        unreachable!()
+    }
+
+    async fn post_json(&mut self, url: &str, payload: Option<&Value>) -> std::io::Result<Value> {
+        let response = self.post(url, payload).await?;
+        parse_json_response(&response)
+    }
+
+    async fn create_account(&mut self, email: Option<&str>) -> std::io::Result<()> {
+        let mut payload = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let new_account_url = self.directory_url("newAccount")?;
+        let response = self.post(&new_account_url, Some(&payload)).await?;
+        let kid = response
+            .header("Location")
+            .ok_or_else(|| acme_error("no account URL in newAccount response".to_string()))?;
+        self.kid = Some(kid.to_string());
+        Ok(())
+    }
+
+    // Polls the object until its status is one of the expected ones.
+    async fn poll_status(&mut self, url: &str, expected: &[&str]) -> std::io::Result<Value> {
+        for _ in 0..MAX_POLLS {
+            let object = self.post_json(url, None).await?;
+            let status = json_str(&object, "status")?;
+            if expected.contains(&status) {
+                return Ok(object);
+            }
+            if status == "invalid" {
+                return Err(acme_error(format!(
+                    "ACME object {} is invalid: {}",
+                    url, object
+                )));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("timed out waiting for ACME object {}", url),
+        ))
+    }
+
+    async fn authorize(
+        &mut self,
+        authorization_url: &str,
+        resolver: &AcmeCertResolver,
+    ) -> std::io::Result<()> {
+        let authorization = self.post_json(authorization_url, None).await?;
+        if json_str(&authorization, "status")? == "valid" {
+            return Ok(());
+        }
+        let domain = json_str(&authorization["identifier"], "value")?.to_ascii_lowercase();
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|challenges| {
+                challenges
+                    .iter()
+                    .find(|challenge| challenge["type"] == "tls-alpn-01")
+            })
+            .ok_or_else(|| acme_error(format!("no tls-alpn-01 challenge for {}", domain)))?;
+        let challenge_url = json_str(challenge, "url")?.to_string();
+        let key_authorization = format!("{}.{}", json_str(challenge, "token")?, self.thumbprint);
+
+        let challenge_key = create_challenge_key(&domain, &key_authorization)?;
+        resolver
+            .challenge_keys
+            .write()
+            .insert(domain.clone(), challenge_key);
+
+        debug!("Answering ACME tls-alpn-01 challenge for {}", domain);
+        self.post_json(&challenge_url, Some(&json!({}))).await?;
+        self.poll_status(authorization_url, &["valid"]).await?;
+        Ok(())
+    }
+
+    // Returns the certificate chain in PEM and the PKCS8 key of the certificate.
+    async fn obtain_certificate(
+        &mut self,
+        resolver: &AcmeCertResolver,
+    ) -> std::io::Result<(String, Vec<u8>)> {
+        let identifiers = resolver
+            .domains
+            .iter()
+            .map(|domain| json!({"type": "dns", "value": domain}))
+            .collect::<Vec<_>>();
+        let new_order_url = self.directory_url("newOrder")?;
+        let response = self
+            .post(&new_order_url, Some(&json!({"identifiers": identifiers})))
+            .await?;
+        let order_url = response
+            .header("Location")
+            .ok_or_else(|| acme_error("no order URL in newOrder response".to_string()))?
+            .to_string();
+        let order = parse_json_response(&response)?;
+
+        let authorization_urls = order["authorizations"]
+            .as_array()
+            .map(|urls| {
+                urls.iter()
+                    .filter_map(|url| url.as_str().map(str::to_string))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for authorization_url in authorization_urls {
+            self.authorize(&authorization_url, resolver).await?;
+        }
+
+        let order = self.poll_status(&order_url, &["ready", "valid"]).await?;
+        let key_pkcs8 = generate_key()?;
+        if json_str(&order, "status")? == "ready" {
+            let csr = create_csr(&resolver.domains, &key_pkcs8)?;
+            let finalize_url = json_str(&order, "finalize")?.to_string();
+            self.post_json(&finalize_url, Some(&json!({"csr": BASE64_URL.encode(csr)})))
+                .await?;
+        }
+        let order = self.poll_status(&order_url, &["valid"]).await?;
+
+        let certificate_url = json_str(&order, "certificate")?.to_string();
+        let response = self.post(&certificate_url, None).await?;
+        let cert_pem = String::from_utf8(response.body)
+            .map_err(|e| acme_error(format!("invalid certificate chain: {}", e)))?;
+        Ok((cert_pem, key_pkcs8))
+    }
+}
+
+fn parse_json_response(response: &HttpResponse) -> std::io::Result<Value> {
+    serde_json::from_slice(&response.body)
+        .map_err(|e| acme_error(format!("invalid ACME response: {}", e)))
+}
+
+fn json_str<'a>(value: &'a Value, name: &str) -> std::io::Result<&'a str> {
+    value[name]
+        .as_str()
+        .ok_or_else(|| acme_error(format!("missing {} in ACME response", name)))
+}
+
+fn der_oid(oid: &[u64]) -> Vec<u8> {
+    let mut contents = vec![(oid[0] * 40 + oid[1]) as u8];
+    for component in &oid[2..] {
+        let mut bytes = vec![(component & 0x7f) as u8];
+        let mut remaining = component >> 7;
+        while remaining > 0 {
+            bytes.push(0x80 | (remaining & 0x7f) as u8);
+            remaining >>= 7;
+        }
+        bytes.reverse();
+        contents.extend_from_slice(&bytes);
+    }
+    der(0x06, &contents)
+}
+
+fn der_bit_string(contents: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0], contents].concat())
+}
+
+fn der_time(unix_secs: u64) -> Vec<u8> {
+    let days = unix_secs / 86400;
+    let secs_of_day = unix_secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    let time = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    );
+    // UTCTime is required for dates before 2050 (RFC 5280).
+    if year < 2050 {
+        der(0x17, format!("{:02}{}", year % 100, time).as_bytes())
+    } else {
+        der(0x18, format!("{:04}{}", year, time).as_bytes())
+    }
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn der_name(common_name: &str) -> Vec<u8> {
+    let attribute = der_sequence(&[
+        &der_oid(OID_COMMON_NAME),
+        &der(0x0c, common_name.as_bytes()),
+    ]);
+    der_sequence(&[&der(0x31, &attribute)])
+}
+
+fn der_subject_alt_name(domains: &[String]) -> Vec<u8> {
+    let names = domains
+        .iter()
+        .map(|domain| der(0x82, domain.as_bytes()))
+        .collect::<Vec<_>>()
+        .concat();
+    der_sequence(&[
+        &der_oid(OID_SUBJECT_ALT_NAME),
+        &der(0x04, &der_sequence(&[&names])),
+    ])
+}
+
+// Returns the key pair, and the encoded subject public key info.
+fn load_signing_key(key_pkcs8: &[u8]) -> std::io::Result<(EcdsaKeyPair, Vec<u8>)> {
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key_pkcs8)
+        .map_err(|e| acme_error(format!("invalid key: {}", e)))?;
+    let public_key_info = der_sequence(&[
+        &der_sequence(&[&der_oid(OID_EC_PUBLIC_KEY), &der_oid(OID_PRIME256V1)]),
+        &der_bit_string(key_pair.public_key().as_ref()),
+    ]);
+    Ok((key_pair, public_key_info))
+}
+
+fn sign_der(key_pair: &EcdsaKeyPair, contents: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let signature = key_pair
+        .sign(&SystemRandom::new(), &contents)
+        .map_err(|_| acme_error("failed to sign".to_string()))?;
+    Ok(der_sequence(&[
+        &contents,
+        &der_sequence(&[&der_oid(OID_ECDSA_WITH_SHA256)]),
+        &der_bit_string(signature.as_ref()),
+    ]))
+}
+
+// The self-signed certificate for a TLS-ALPN-01 challenge, which has the domain and the digest
+// of the key authorization in the critical acmeIdentifier extension (RFC 8737).
+fn create_challenge_key(
+    domain: &str,
+    key_authorization: &str,
+) -> std::io::Result<Arc<CertifiedKey>> {
+    let key_pkcs8 = generate_key()?;
+    let (key_pair, public_key_info) = load_signing_key(&key_pkcs8)?;
+
+    let now = unix_time();
+    let mut serial_number = rand::random::<[u8; 16]>();
+    serial_number[0] &= 0x7f;
+    let key_authorization_digest = Sha256::digest(key_authorization.as_bytes());
+    let acme_identifier = der_sequence(&[
+        &der_oid(OID_ACME_IDENTIFIER),
+        &der(0x01, &[0xff]),
+        &der(0x04, &der(0x04, &key_authorization_digest)),
+    ]);
+    let extensions = der_sequence(&[
+        &der_subject_alt_name(&[domain.to_string()]),
+        &acme_identifier,
+    ]);
+    let tbs_certificate = der_sequence(&[
+        &der(0xa0, &der(0x02, &[2])),
+        &der(0x02, &serial_number),
+        &der_sequence(&[&der_oid(OID_ECDSA_WITH_SHA256)]),
+        &der_name(domain),
+        &der_sequence(&[&der_time(now - 86400), &der_time(now + 7 * 86400)]),
+        &der_name(domain),
+        &public_key_info,
+        &der(0xa3, &extensions),
+    ]);
+    let cert = sign_der(&key_pair, tbs_certificate)?;
+
+    let signing_key = rustls::sign::any_ecdsa_type(&rustls::PrivateKey(key_pkcs8))
+        .map_err(|e| acme_error(format!("invalid challenge key: {}", e)))?;
+    Ok(Arc::new(CertifiedKey::new(
+        vec![rustls::Certificate(cert)],
+        signing_key,
+    )))
+}
+
+fn create_csr(domains: &[String], key_pkcs8: &[u8]) -> std::io::Result<Vec<u8>> {
+    let (key_pair, public_key_info) = load_signing_key(key_pkcs8)?;
+    let extension_request = der_sequence(&[
+        &der_oid(OID_EXTENSION_REQUEST),
+        &der(0x31, &der_sequence(&[&der_subject_alt_name(domains)])),
+    ]);
+    let request_info = der_sequence(&[
+        &der(0x02, &[0]),
+        &der_name(&domains[0]),
+        &public_key_info,
+        &der(0xa0, &extension_request),
+    ]);
+    sign_der(&key_pair, request_info)
+}
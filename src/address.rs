@@ -1,4 +1,8 @@
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::domain_set::DomainSet;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Address {
@@ -59,6 +63,25 @@ impl Address {
         ))
     }
 
+    // Parses an address that a client sent in a protocol header. Although it's supposed to be a
+    // hostname, some clients send IP addresses as well. An empty address is rejected here rather
+    // than when it fails to resolve.
+    pub fn from_header_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        if bytes.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Empty address in header",
+            ));
+        }
+        let s = std::str::from_utf8(bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to decode address: {}", e),
+            )
+        })?;
+        Self::from(s)
+    }
+
     pub fn is_ipv6(&self) -> bool {
         matches!(self, Address::Ipv6(_))
     }
@@ -67,6 +90,14 @@ impl Address {
         matches!(self, Address::Hostname(_))
     }
 
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            Address::Ipv4(i) => i.is_unspecified(),
+            Address::Ipv6(i) => i.is_unspecified(),
+            Address::Hostname(_) => false,
+        }
+    }
+
     pub fn hostname(&self) -> Option<&str> {
         match self {
             Address::Hostname(ref hostname) => Some(hostname),
@@ -114,6 +145,14 @@ impl NetLocation {
             None => (s, default_port, false),
         };
 
+        let (address_str, expect_ipv6) = match address_str
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+        {
+            Some(ipv6_str) => (ipv6_str, true),
+            None => (address_str, expect_ipv6),
+        };
+
         let address = Address::from(address_str)?;
         if expect_ipv6 && !address.is_ipv6() {
             return Err(std::io::Error::new(
@@ -209,7 +248,7 @@ impl AddressMask {
             Some(i) => {
                 let num_bits = s[i + 1..].parse::<u8>().map_err(|e| {
                     std::io::Error::new(
-                        std::io::ErrorKind::Other,
+                        std::io::ErrorKind::InvalidInput,
                         format!("Failed to parse netmask: {}", e),
                     )
                 })?;
@@ -217,13 +256,13 @@ impl AddressMask {
             }
             None => (s, None),
         };
-        let address = Address::from(address_str)?;
+        let mut address = Address::from(address_str)?;
         let keep_bits = match address {
             Address::Ipv4(_) => {
                 let num_bits = num_bits.unwrap_or(32);
                 if num_bits > 32 {
                     return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
+                        std::io::ErrorKind::InvalidInput,
                         format!("Invalid number of bits for ipv4 address: {}", num_bits),
                     ));
                 }
@@ -235,20 +274,28 @@ impl AddressMask {
                     96 + num_bits
                 }
             }
-            Address::Ipv6(_) => {
+            Address::Ipv6(ip) => {
                 let num_bits = num_bits.unwrap_or(128);
                 if num_bits > 128 {
                     return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Invalid number of bits for ipv4 address: {}", num_bits),
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid number of bits for ipv6 address: {}", num_bits),
                     ));
                 }
+                // IPv4 destinations are matched as IPv4-mapped addresses, so a mapped mask that
+                // only covers IPv4 addresses is the same as its IPv4 form.
+                if num_bits > 96 {
+                    if let Some(ipv4) = ip.to_ipv4_mapped() {
+                        address = Address::Ipv4(ipv4);
+                    }
+                }
+                // ::/0 is ANY, and matches IPv4 destinations as well.
                 num_bits
             }
             Address::Hostname(ref hostname) => {
                 if num_bits.is_some() {
                     return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
+                        std::io::ErrorKind::InvalidInput,
                         format!(
                             "Cannot specify number of number of netmask bits for hostnames: {}",
                             hostname
@@ -269,37 +316,308 @@ impl AddressMask {
 
         Ok(Self { address, netmask })
     }
+
+    // Whether every address matched by `other` is also matched by this mask. Hostnames are only
+    // compared against hostnames, since what they resolve to isn't known ahead of time.
+    pub fn contains(&self, other: &AddressMask) -> bool {
+        if self.netmask == 0 {
+            return true;
+        }
+        if other.netmask == 0 {
+            return false;
+        }
+        match (&self.address, &other.address) {
+            (Address::Hostname(base_domain), Address::Hostname(hostname)) => {
+                hostname == base_domain
+                    || (hostname.ends_with(base_domain.as_str())
+                        && hostname.as_bytes()[hostname.len() - base_domain.len() - 1] == b'.')
+            }
+            (Address::Hostname(_), _) | (_, Address::Hostname(_)) => false,
+            (address, other_address) => {
+                let ip = address_to_u128(address);
+                let other_ip = address_to_u128(other_address);
+                self.netmask & other.netmask == self.netmask
+                    && ip & self.netmask == other_ip & self.netmask
+            }
+        }
+    }
+}
+
+fn address_to_u128(address: &Address) -> u128 {
+    match address {
+        Address::Ipv4(ip) => u128::from(ip.to_ipv6_mapped()),
+        Address::Ipv6(ip) => u128::from(*ip),
+        Address::Hostname(_) => 0,
+    }
+}
+
+impl std::fmt::Display for AddressMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.netmask == 0 {
+            return write!(f, "*");
+        }
+        match self.address {
+            Address::Hostname(_) => write!(f, "{}", self.address),
+            Address::Ipv4(_) => write!(f, "{}/{}", self.address, self.netmask.count_ones() - 96),
+            Address::Ipv6(_) => write!(f, "{}/{}", self.address, self.netmask.count_ones()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct NetLocationMask {
     pub address_mask: AddressMask,
     pub port: u16,
+    // Set for masks starting with !, which match locations that the rest of the mask doesn't.
+    pub negated: bool,
+    // Set for domain-set:<path> masks. Their address mask is a hostname that's only used to
+    // compare them with other masks.
+    pub domain_set: Option<DomainSetMask>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DomainSetMask {
+    pub path: PathBuf,
+    // Loaded when the config is validated.
+    pub domains: Option<Arc<DomainSet>>,
+}
+
+impl DomainSetMask {
+    // Whether the hostname is in the set. IP addresses are never in a domain set.
+    pub fn contains(&self, address: &Address) -> bool {
+        match (address.hostname(), self.domains.as_ref()) {
+            (Some(hostname), Some(domains)) => domains.contains(hostname),
+            _ => false,
+        }
+    }
 }
 
 impl NetLocationMask {
     pub const ANY: Self = NetLocationMask {
         address_mask: AddressMask::ANY,
         port: 0,
+        negated: false,
+        domain_set: None,
     };
 
+    // IPv6 masks need brackets when a port is given, either around the address
+    // ([2001:db8::]/32:443) or around the whole mask ([2001:db8::/32]:443). Without brackets, a
+    // mask with more than one colon is an IPv6 mask without a port.
     pub fn from(s: &str) -> std::io::Result<Self> {
-        let (address_mask_str, port) = match s.find(':') {
-            Some(i) => {
-                let port = s[i + 1..].parse::<u16>().map_err(|e| {
+        let (s, negated) = match s.strip_prefix('!') {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        if let Some(path) = s.strip_prefix("domain-set:") {
+            if path.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "domain-set mask needs a file path",
+                ));
+            }
+            return Ok(Self {
+                address_mask: AddressMask {
+                    address: Address::Hostname(s.to_string()),
+                    netmask: u128::MAX,
+                },
+                port: 0,
+                negated,
+                domain_set: Some(DomainSetMask {
+                    path: PathBuf::from(path),
+                    domains: None,
+                }),
+            });
+        }
+        let (address_mask_str, port_str) = match s.strip_prefix('[') {
+            Some(bracketed) => {
+                let end = bracketed.find(']').ok_or_else(|| {
                     std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to parse port: {}", e),
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Missing closing bracket in mask: {}", s),
                     )
                 })?;
-                (&s[0..i], port)
+                let suffix = &bracketed[end + 1..];
+                let (netmask_str, port_str) = match suffix.find(':') {
+                    Some(i) => (&suffix[0..i], Some(&suffix[i + 1..])),
+                    None => (suffix, None),
+                };
+                if !netmask_str.is_empty() && !netmask_str.starts_with('/') {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unexpected characters after closing bracket in mask: {}", s),
+                    ));
+                }
+                (format!("{}{}", &bracketed[0..end], netmask_str), port_str)
             }
-            None => (s, 0),
+            None => match s.rfind(':') {
+                Some(i) if !s[0..i].contains(':') => (s[0..i].to_string(), Some(&s[i + 1..])),
+                _ => (s.to_string(), None),
+            },
+        };
+
+        let port = match port_str {
+            Some(port_str) => port_str.parse::<u16>().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Failed to parse port: {}", e),
+                )
+            })?,
+            None => 0,
         };
 
         Ok(Self {
-            address_mask: AddressMask::from(address_mask_str)?,
+            address_mask: AddressMask::from(&address_mask_str)?,
             port,
+            negated,
+            domain_set: None,
         })
     }
+
+    // Always false when either mask is negated, since those aren't compared.
+    pub fn contains(&self, other: &NetLocationMask) -> bool {
+        !self.negated
+            && !other.negated
+            && (self.port == 0 || self.port == other.port)
+            && self.address_mask.contains(&other.address_mask)
+    }
+}
+
+impl std::fmt::Display for NetLocationMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.negated {
+            write!(f, "!")?;
+        }
+        if self.port > 0 && self.address_mask.netmask != 0 && self.address_mask.address.is_ipv6() {
+            write!(f, "[{}]:{}", self.address_mask, self.port)
+        } else if self.port > 0 {
+            write!(f, "{}:{}", self.address_mask, self.port)
+        } else {
+            write!(f, "{}", self.address_mask)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(s: &str) -> AddressMask {
+        AddressMask::from(s).unwrap()
+    }
+
+    fn contains(base: &str, other: &str) -> bool {
+        mask(base).contains(&mask(other))
+    }
+
+    #[test]
+    fn zero_prefix_matches_both_families() {
+        for s in ["0.0.0.0/0", "1.2.3.4/0", "::/0", "2001:db8::1/0"] {
+            assert_eq!(mask(s).netmask, 0, "{}", s);
+            assert!(contains(s, "1.2.3.4"), "{}", s);
+            assert!(contains(s, "2001:db8::1"), "{}", s);
+        }
+    }
+
+    #[test]
+    fn full_prefix_matches_a_single_address() {
+        assert_eq!(mask("1.2.3.4/32").netmask, u128::MAX);
+        assert_eq!(mask("1.2.3.4").netmask, u128::MAX);
+        assert!(contains("1.2.3.4/32", "1.2.3.4"));
+        assert!(!contains("1.2.3.4/32", "1.2.3.5"));
+
+        assert_eq!(mask("2001:db8::1/128").netmask, u128::MAX);
+        assert_eq!(mask("2001:db8::1").netmask, u128::MAX);
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+    }
+
+    #[test]
+    fn prefix_boundaries() {
+        assert_eq!(mask("1.2.3.0/31").netmask, u128::MAX << 1);
+        assert!(contains("1.2.3.0/31", "1.2.3.1"));
+        assert!(!contains("1.2.3.0/31", "1.2.3.2"));
+
+        assert_eq!(mask("2001:db8::/127").netmask, u128::MAX << 1);
+        assert!(contains("2001:db8::/127", "2001:db8::1"));
+        assert!(!contains("2001:db8::/127", "2001:db8::2"));
+
+        assert_eq!(mask("1.0.0.0/1").netmask, u128::MAX << 31);
+        assert!(contains("1.0.0.0/1", "127.255.255.255"));
+        assert!(!contains("1.0.0.0/1", "128.0.0.0"));
+
+        // A narrower mask can't contain a wider one.
+        assert!(contains("10.0.0.0/8", "10.1.0.0/16"));
+        assert!(!contains("10.1.0.0/16", "10.0.0.0/8"));
+    }
+
+    #[test]
+    fn over_long_prefix_is_rejected() {
+        for s in [
+            "1.2.3.4/33",
+            "2001:db8::/129",
+            "1.2.3.4/256",
+            "example.com/24",
+        ] {
+            let error = AddressMask::from(s).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "{}", s);
+        }
+        assert!(AddressMask::from("1.2.3.4/").is_err());
+        assert!(AddressMask::from("1.2.3.4/-1").is_err());
+    }
+
+    #[test]
+    fn host_bits_beyond_prefix_are_ignored() {
+        assert!(contains("10.1.2.3/8", "10.200.0.1"));
+        assert!(contains("10.1.2.3/8", "10.0.0.0/8"));
+        assert!(!contains("10.1.2.3/8", "11.1.2.3"));
+
+        assert!(contains("2001:db8:ffff::1/32", "2001:db8::1"));
+        assert!(!contains("2001:db8:ffff::1/32", "2001:db9::1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_masks_match_ipv4() {
+        // A mapped mask that only covers IPv4 addresses becomes an IPv4 mask.
+        let mapped = mask("::ffff:1.2.3.0/120");
+        assert_eq!(mapped.address, Address::Ipv4(Ipv4Addr::new(1, 2, 3, 0)));
+        assert_eq!(mapped.to_string(), "1.2.3.0/24");
+        assert!(contains("::ffff:1.2.3.0/120", "1.2.3.4"));
+
+        assert!(contains("1.2.3.0/24", "::ffff:1.2.3.4"));
+        assert!(!contains("1.2.3.0/24", "::ffff:1.2.4.4"));
+
+        // IPv4 addresses only match IPv6 masks through their mapped form.
+        assert!(!contains("2001:db8::/32", "1.2.3.4"));
+        assert!(!contains("1.2.3.0/24", "2001:db8::1"));
+        assert!(contains("::ffff:0:0/96", "1.2.3.4"));
+    }
+
+    #[test]
+    fn header_bytes_at_max_hostname_length() {
+        // A one byte length prefix allows hostnames of up to 255 bytes.
+        let hostname = "a".repeat(255);
+        assert_eq!(
+            Address::from_header_bytes(hostname.as_bytes()).unwrap(),
+            Address::Hostname(hostname)
+        );
+        assert_eq!(
+            Address::from_header_bytes(b"1.2.3.4").unwrap(),
+            Address::Ipv4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[test]
+    fn malformed_header_bytes_are_rejected() {
+        let error = Address::from_header_bytes(b"").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Empty address in header");
+
+        let error = Address::from_header_bytes(b"example\xff.com").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("Failed to decode address"));
+
+        let error = Address::from_header_bytes(b"1.2.3.4:443").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Failed to parse address: 1.2.3.4:443");
+    }
 }
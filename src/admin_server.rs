@@ -0,0 +1,306 @@
+use log::{debug, error};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::task::JoinHandle;
+
+use crate::bandwidth_stats::bandwidth_stats;
+use crate::client_proxy_selector::ConnectAction;
+use crate::config::{AdminConfig, BindLocation};
+use crate::connection_registry::connection_registry;
+use crate::privilege_util::wait_until_accepting;
+use crate::resolver::shared_resolver_list;
+use crate::user_quota::user_quotas;
+
+const HELP_TEXT: &str =
+    "commands: connections [throughput], rules, udp, resolvers, bans, quotas, reset_quota [user], stats, reset_stats, panics, reload, help";
+
+// Requests are single line commands, and each response is a single line of JSON.
+async fn handle_admin_stream<S>(stream: S) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        let response = match command {
+            "connections" => list_connections(false),
+            "connections throughput" => list_connections(true),
+            "quotas" => list_quotas(),
+            "reset_quota" => reset_quota(None),
+            _ if command.starts_with("reset_quota ") => reset_quota(Some(command[12..].trim())),
+            "stats" => list_stats(),
+            "reset_stats" => reset_stats(),
+            "rules" => list_rules(),
+            "udp" => list_udp_sessions(),
+            "resolvers" => list_resolvers(),
+            "bans" => list_bans(),
+            "panics" => json!({ "panics": connection_registry().panic_count() }),
+            "reload" => reload().await,
+            "help" => json!({ "help": HELP_TEXT }),
+            _ => json!({ "error": format!("unknown command: {}", command), "help": HELP_TEXT }),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+// Lists the connections by id, or with the highest current throughput first.
+fn list_connections(by_throughput: bool) -> Value {
+    let mut connections = connection_registry().connections();
+    if by_throughput {
+        connections
+            .sort_by_cached_key(|info| std::cmp::Reverse(info.send_rate() + info.receive_rate()));
+    }
+    let connections = connections
+        .into_iter()
+        .map(|info| {
+            json!({
+                "id": info.id,
+                "trace_id": info.trace_id,
+                "server": info.server,
+                "source": info.source,
+                "destination": info.destination().map(|location| location.to_string()),
+                "protocol": info.protocol,
+                "user": info.user(),
+                "rule": info.matched_rule(),
+                "egress_local": info.egress().map(|egress| egress.local.to_string()),
+                "egress_peer": info.egress().map(|egress| egress.peer.to_string()),
+                "bytes_sent": info.bytes_sent(),
+                "bytes_received": info.bytes_received(),
+                "bytes_sent_per_sec": info.send_rate(),
+                "bytes_received_per_sec": info.receive_rate(),
+                "duration_secs": info.duration().as_secs(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "connections": connections })
+}
+
+fn list_rules() -> Value {
+    let servers = connection_registry()
+        .selectors()
+        .into_iter()
+        .map(|(label, selector)| {
+            let rules = selector
+                .rules()
+                .iter()
+                .enumerate()
+                .map(|(i, rule)| {
+                    let mut rule_json = json!({
+                        "index": i,
+                        "name": rule.name,
+                        "rule": rule.to_string(),
+                        "hits": rule.hit_count(),
+                        // In the same order as the rule's client proxies.
+                        "active_connections": rule.action.active_connections(),
+                    });
+                    if let ConnectAction::Allow {
+                        select_by: Some(ref select_by),
+                        ..
+                    } = rule.action
+                    {
+                        // For the client proxies of each select_by key.
+                        rule_json["group_active_connections"] = select_by
+                            .groups()
+                            .map(|(value, pool)| {
+                                (value.to_string(), json!(pool.active_connections()))
+                            })
+                            .collect::<serde_json::Map<_, _>>()
+                            .into();
+                    }
+                    rule_json
+                })
+                .collect::<Vec<_>>();
+            // Locations that don't match any rule are blocked.
+            json!({
+                "server": label,
+                "rules": rules,
+                "fallthrough_hits": selector.fallthrough_count(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "servers": servers })
+}
+
+fn list_udp_sessions() -> Value {
+    let servers = connection_registry()
+        .udp_session_tables()
+        .into_iter()
+        .map(|(label, table)| {
+            json!({
+                "server": label,
+                "sessions": table.session_count(),
+                "max_sessions": table.max_sessions(),
+                "idle_timeout_secs": table.config().idle_timeout_secs,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "servers": servers })
+}
+
+fn list_resolvers() -> Value {
+    let resolvers = shared_resolver_list()
+        .into_iter()
+        .map(|resolver| {
+            json!({
+                "resolver": resolver.description(),
+                "lookups": resolver.lookups(),
+                "failures": resolver.failures(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "resolvers": resolvers })
+}
+
+fn list_bans() -> Value {
+    let servers = connection_registry()
+        .auth_ban_tables()
+        .into_iter()
+        .map(|(label, table)| {
+            let bans = table
+                .bans()
+                .into_iter()
+                .map(|(ip, remaining)| {
+                    json!({
+                        "source": ip.to_string(),
+                        "remaining_secs": remaining.as_secs(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "server": label,
+                "bans": bans,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "servers": servers })
+}
+
+fn list_quotas() -> Value {
+    let users = user_quotas()
+        .usage()
+        .into_iter()
+        .map(|info| {
+            json!({
+                "user": info.username,
+                "used_bytes": info.used_bytes,
+                "quota_bytes": info.quota_bytes,
+                "period_start": info.period_start,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "users": users })
+}
+
+// Resets the usage of a user, or of all users.
+fn reset_quota(username: Option<&str>) -> Value {
+    if user_quotas().reset(username) {
+        json!({ "reset": true })
+    } else {
+        json!({ "error": format!("unknown user: {}", username.unwrap_or_default()) })
+    }
+}
+
+fn list_stats() -> Value {
+    match bandwidth_stats().totals() {
+        Some(state) => json!(state),
+        None => json!({ "error": "stats_settings is not configured" }),
+    }
+}
+
+// Resets the totals of every rule and client proxy. The file keeps the old totals until the next
+// save.
+fn reset_stats() -> Value {
+    if bandwidth_stats().reset() {
+        json!({ "reset": true })
+    } else {
+        json!({ "error": "stats_settings is not configured" })
+    }
+}
+
+async fn reload() -> Value {
+    // Reloading reads the config and certificate files.
+    let result = tokio::task::spawn_blocking(|| connection_registry().reload()).await;
+    match result {
+        Ok(Ok(())) => json!({ "reloaded": true }),
+        Ok(Err(e)) => json!({ "error": format!("reload failed: {}", e) }),
+        Err(e) => json!({ "error": format!("reload failed: {}", e) }),
+    }
+}
+
+pub async fn start_admin_server(config: AdminConfig) -> std::io::Result<JoinHandle<()>> {
+    let AdminConfig { bind_location } = config;
+
+    println!("Starting admin server at {}", &bind_location);
+
+    match bind_location {
+        BindLocation::Address(a) => {
+            let socket_addr = a.to_socket_addr()?;
+            let listener = tokio::net::TcpListener::bind(socket_addr).await?;
+            Ok(tokio::spawn(async move {
+                wait_until_accepting().await;
+                loop {
+                    let (stream, addr) = match listener.accept().await {
+                        Ok(v) => v,
+                        Err(e) => {
+                            error!("Admin accept failed: {}", e);
+                            continue;
+                        }
+                    };
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_admin_stream(stream).await {
+                            error!("Admin client {} finished with error: {}", addr, e);
+                        } else {
+                            debug!("Admin client {} finished", addr);
+                        }
+                    });
+                }
+            }))
+        }
+        BindLocation::Path(path_buf) => {
+            #[cfg(target_family = "unix")]
+            {
+                if tokio::fs::symlink_metadata(&path_buf).await.is_ok() {
+                    println!(
+                        "WARNING: replacing file at socket path {}",
+                        path_buf.display()
+                    );
+                    let _ = tokio::fs::remove_file(&path_buf).await;
+                }
+                let listener = tokio::net::UnixListener::bind(path_buf)?;
+                Ok(tokio::spawn(async move {
+                    wait_until_accepting().await;
+                    loop {
+                        let (stream, _) = match listener.accept().await {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("Admin accept failed: {}", e);
+                                continue;
+                            }
+                        };
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_admin_stream(stream).await {
+                                error!("Admin client finished with error: {}", e);
+                            } else {
+                                debug!("Admin client finished");
+                            }
+                        });
+                    }
+                }))
+            }
+            #[cfg(not(target_family = "unix"))]
+            {
+                panic!("Unix sockets are not supported on non-unix OSes.");
+            }
+        }
+    }
+}
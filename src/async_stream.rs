@@ -1,24 +1,49 @@
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
 use tokio::net::{TcpStream, UdpSocket};
 
 #[cfg(target_family = "unix")]
 use tokio::net::UnixStream;
 
 use crate::address::NetLocation;
+use crate::tls_record_stream::TlsRecordStream;
+
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(60);
 
 pub trait AsyncPing {
     fn supports_ping(&self) -> bool;
 
+    // How often a ping should be written when the stream supports pings. Wrapping streams
+    // should forward this to the stream that actually writes the pings.
+    fn ping_interval(&self) -> Duration {
+        DEFAULT_PING_INTERVAL
+    }
+
     // Write a ping message to the stream, if supported.
     // This should end up calling the highest level stream abstraction that supports
     // pings, and should only result in a single message.
     fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>>;
 }
 
+/// Returns the interval at which pings should be written between `a` and `b`, or None if neither
+/// stream supports pings.
+pub fn shortest_ping_interval<A, B>(a: &A, b: &B) -> Option<Duration>
+where
+    A: AsyncPing + ?Sized,
+    B: AsyncPing + ?Sized,
+{
+    match (a.supports_ping(), b.supports_ping()) {
+        (true, true) => Some(std::cmp::min(a.ping_interval(), b.ping_interval())),
+        (true, false) => Some(a.ping_interval()),
+        (false, true) => Some(b.ping_interval()),
+        (false, false) => None,
+    }
+}
+
 pub trait AsyncReadMessage {
     fn poll_read_message(
         self: Pin<&mut Self>,
@@ -119,7 +144,21 @@ impl AsyncShutdownMessage for UdpSocket {
     }
 }
 
-pub trait AsyncStream: AsyncRead + AsyncWrite + AsyncPing + Unpin + Send {}
+pub trait AsyncStream: AsyncRead + AsyncWrite + AsyncPing + Unpin + Send {
+    // Whether poll_shutdown only closes the write direction, so that the stream can still be
+    // read from afterwards. Wrapping streams should forward this to the stream that they shut
+    // down.
+    fn supports_half_close(&self) -> bool {
+        false
+    }
+
+    // The stream beneath a TLS stream that was set up over a TlsRecordStream, which can be used
+    // directly once the TLS layer is flushed. Only TLS streams forward this, since anything in
+    // between would be bypassed.
+    fn tls_record_stream(&mut self) -> Option<&mut TlsRecordStream> {
+        None
+    }
+}
 
 pub trait AsyncMessageStream:
     AsyncReadMessage
@@ -168,7 +207,11 @@ impl AsyncPing for TcpStream {
     }
 }
 
-impl AsyncStream for TcpStream {}
+impl AsyncStream for TcpStream {
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
 
 #[cfg(target_family = "unix")]
 impl AsyncPing for UnixStream {
@@ -182,7 +225,29 @@ impl AsyncPing for UnixStream {
 }
 
 #[cfg(target_family = "unix")]
-impl AsyncStream for UnixStream {}
+impl AsyncStream for UnixStream {
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
+
+// In-memory streams from tokio::io::duplex, which are useful for running connections without
+// sockets.
+impl AsyncPing for DuplexStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for DuplexStream {
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
 
 impl AsyncPing for UdpSocket {
     fn supports_ping(&self) -> bool {
@@ -204,13 +269,28 @@ where
         self.get_ref().0.supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        self.get_ref().0.ping_interval()
+    }
+
     fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
         let this = self.get_mut();
         Pin::new(this.get_mut().0).poll_write_ping(cx)
     }
 }
 
-impl<AS> AsyncStream for tokio_rustls::client::TlsStream<AS> where AS: AsyncStream {}
+impl<AS> AsyncStream for tokio_rustls::client::TlsStream<AS>
+where
+    AS: AsyncStream,
+{
+    fn supports_half_close(&self) -> bool {
+        self.get_ref().0.supports_half_close()
+    }
+
+    fn tls_record_stream(&mut self) -> Option<&mut TlsRecordStream> {
+        self.get_mut().0.tls_record_stream()
+    }
+}
 
 impl<AS> AsyncPing for tokio_rustls::server::TlsStream<AS>
 where
@@ -220,13 +300,28 @@ where
         self.get_ref().0.supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        self.get_ref().0.ping_interval()
+    }
+
     fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
         let this = self.get_mut();
         Pin::new(this.get_mut().0).poll_write_ping(cx)
     }
 }
 
-impl<AS> AsyncStream for tokio_rustls::server::TlsStream<AS> where AS: AsyncStream {}
+impl<AS> AsyncStream for tokio_rustls::server::TlsStream<AS>
+where
+    AS: AsyncStream,
+{
+    fn supports_half_close(&self) -> bool {
+        self.get_ref().0.supports_half_close()
+    }
+
+    fn tls_record_stream(&mut self) -> Option<&mut TlsRecordStream> {
+        self.get_mut().0.tls_record_stream()
+    }
+}
 
 // pattern copied from deref_async_read macro: https://docs.rs/tokio/latest/src/tokio/io/async_read.rs.html#60
 impl<T: ?Sized + AsyncPing + Unpin> AsyncPing for Box<T> {
@@ -234,6 +329,10 @@ impl<T: ?Sized + AsyncPing + Unpin> AsyncPing for Box<T> {
         (&**self).supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        (**self).ping_interval()
+    }
+
     fn poll_write_ping(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -247,6 +346,10 @@ impl<T: ?Sized + AsyncPing + Unpin> AsyncPing for &mut T {
         (&**self).supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        (**self).ping_interval()
+    }
+
     fn poll_write_ping(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -415,8 +518,25 @@ impl<T: ?Sized + AsyncWriteSourcedMessage + Unpin> AsyncWriteSourcedMessage for
     }
 }
 
-impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for Box<T> {}
-impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for &mut T {}
+impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for Box<T> {
+    fn supports_half_close(&self) -> bool {
+        (**self).supports_half_close()
+    }
+
+    fn tls_record_stream(&mut self) -> Option<&mut TlsRecordStream> {
+        (**self).tls_record_stream()
+    }
+}
+
+impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for &mut T {
+    fn supports_half_close(&self) -> bool {
+        (**self).supports_half_close()
+    }
+
+    fn tls_record_stream(&mut self) -> Option<&mut TlsRecordStream> {
+        (**self).tls_record_stream()
+    }
+}
 
 impl<T: ?Sized + AsyncMessageStream + Unpin> AsyncMessageStream for Box<T> {}
 impl<T: ?Sized + AsyncMessageStream + Unpin> AsyncMessageStream for &mut T {}
@@ -0,0 +1,140 @@
+// Counts failed authentications from each source IP, and bans sources with too many failures so
+// that their connections are dropped before the handshake runs.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+use crate::config::AuthBanConfig;
+use crate::tcp_handler::{is_auth_failure, TcpServerSetupResult};
+
+#[derive(Debug)]
+struct SourceState {
+    // failures since the window started.
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+pub struct AuthBanTable {
+    server_label: String,
+    config: AuthBanConfig,
+    sources: Mutex<HashMap<IpAddr, SourceState>>,
+}
+
+impl AuthBanTable {
+    pub fn new(server_label: String, config: AuthBanConfig) -> Self {
+        Self {
+            server_label,
+            config,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.sources
+            .lock()
+            .get(&ip)
+            .and_then(|state| state.banned_until)
+            .is_some_and(|banned_until| banned_until > Instant::now())
+    }
+
+    pub fn record_failure(self: &Arc<Self>, ip: IpAddr) {
+        let window = Duration::from_secs(self.config.window_secs);
+        let ban_duration = Duration::from_secs(self.config.ban_duration_secs);
+        let now = Instant::now();
+
+        let mut sources = self.sources.lock();
+        // Forget sources whose failures are too old to count.
+        sources.retain(|_, state| {
+            state.banned_until.is_some() || now.duration_since(state.window_start) < window
+        });
+
+        let state = sources.entry(ip).or_insert_with(|| SourceState {
+            failures: 0,
+            window_start: now,
+            banned_until: None,
+        });
+        if state.banned_until.is_some() {
+            return;
+        }
+        if now.duration_since(state.window_start) >= window {
+            state.failures = 0;
+            state.window_start = now;
+        }
+        state.failures += 1;
+        debug!(
+            "[{}] Authentication failure {} from {}",
+            self.server_label, state.failures, ip
+        );
+        if state.failures < self.config.max_auth_failures {
+            return;
+        }
+
+        state.banned_until = Some(now + ban_duration);
+        warn!(
+            "[{}] Banned {} for {} seconds after {} authentication failures",
+            self.server_label, ip, self.config.ban_duration_secs, state.failures
+        );
+        drop(sources);
+
+        let table = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ban_duration).await;
+            table.sources.lock().remove(&ip);
+            warn!("[{}] Ban on {} expired", table.server_label, ip);
+        });
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        let mut sources = self.sources.lock();
+        if sources
+            .get(&ip)
+            .is_some_and(|state| state.banned_until.is_none())
+        {
+            sources.remove(&ip);
+        }
+    }
+
+    // Returns the banned sources, and how long until each ban expires.
+    pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        self.sources
+            .lock()
+            .iter()
+            .filter_map(|(ip, state)| {
+                state
+                    .banned_until
+                    .filter(|banned_until| *banned_until > now)
+                    .map(|banned_until| (*ip, banned_until - now))
+            })
+            .collect()
+    }
+}
+
+// The source of a connection, which records the result of its authentication.
+pub struct AuthSource {
+    table: Arc<AuthBanTable>,
+    ip: IpAddr,
+}
+
+impl AuthSource {
+    pub fn new(table: Arc<AuthBanTable>, ip: IpAddr) -> Self {
+        Self { table, ip }
+    }
+
+    // Records a successful setup or a failed authentication. Fallbacks are neither, since they
+    // can also be normal visitors to the fallback site.
+    pub fn record_setup_result(&self, result: &std::io::Result<TcpServerSetupResult>) {
+        match result {
+            Ok(TcpServerSetupResult::Fallback { .. }) => (),
+            Ok(_) => self.table.record_success(self.ip),
+            Err(e) if is_auth_failure(e) => self.table.record_failure(self.ip),
+            Err(_) => (),
+        }
+    }
+}
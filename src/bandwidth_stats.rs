@@ -0,0 +1,169 @@
+// Totals of the bytes relayed through each rule and client proxy, which are saved to a file so
+// that they're kept across restarts. Bytes are added when a connection finishes, so connections
+// that are still open aren't counted yet.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::config::StatsConfig;
+use crate::connection_registry::ConnectionInfo;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ByteTotals {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ByteTotals {
+    fn add(&mut self, info: &ConnectionInfo) {
+        self.bytes_sent = self.bytes_sent.saturating_add(info.bytes_sent());
+        self.bytes_received = self.bytes_received.saturating_add(info.bytes_received());
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsState {
+    // seconds since the unix epoch when the totals were last reset.
+    pub since: u64,
+    // The totals of each rule by its label, for each server, since rules are only labeled by
+    // their position when they don't have a name.
+    pub rules: BTreeMap<String, BTreeMap<String, ByteTotals>>,
+    pub client_proxies: BTreeMap<String, ByteTotals>,
+}
+
+impl StatsState {
+    fn new() -> Self {
+        Self {
+            since: unix_time_secs(),
+            rules: BTreeMap::new(),
+            client_proxies: BTreeMap::new(),
+        }
+    }
+}
+
+pub struct BandwidthStats {
+    // None until the stats store is started, so that nothing is counted without a stats file.
+    state: Mutex<Option<StatsState>>,
+}
+
+pub fn bandwidth_stats() -> &'static BandwidthStats {
+    static INSTANCE: OnceLock<BandwidthStats> = OnceLock::new();
+    INSTANCE.get_or_init(|| BandwidthStats {
+        state: Mutex::new(None),
+    })
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl BandwidthStats {
+    // Adds the bytes of a finished connection to the totals of its rule and client proxy.
+    pub fn record(&self, info: &ConnectionInfo) {
+        if info.bytes_sent() == 0 && info.bytes_received() == 0 {
+            return;
+        }
+        let mut state = self.state.lock();
+        let state = match state.as_mut() {
+            Some(state) => state,
+            None => return,
+        };
+        if let Some(rule_label) = info.rule_label() {
+            state
+                .rules
+                .entry(info.server.clone())
+                .or_default()
+                .entry(rule_label)
+                .or_default()
+                .add(info);
+        }
+        if let Some(client_proxy) = info.client_proxy() {
+            state
+                .client_proxies
+                .entry(client_proxy)
+                .or_default()
+                .add(info);
+        }
+    }
+
+    // None when there is no stats store.
+    pub fn totals(&self) -> Option<StatsState> {
+        self.state.lock().clone()
+    }
+
+    // Returns false when there is no stats store.
+    pub fn reset(&self) -> bool {
+        match self.state.lock().as_mut() {
+            Some(state) => {
+                *state = StatsState::new();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn load(&self, stats_file: &str) -> std::io::Result<()> {
+        let state = match std::fs::read(stats_file) {
+            Ok(state_bytes) => serde_json::from_slice(&state_bytes).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("failed to parse stats file {}: {}", stats_file, e),
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StatsState::new(),
+            Err(e) => return Err(e),
+        };
+        self.state.lock().replace(state);
+        Ok(())
+    }
+
+    async fn save(&self, stats_file: &str) -> std::io::Result<()> {
+        let state_bytes = match self.totals() {
+            Some(state) => serde_json::to_vec_pretty(&state)?,
+            None => return Ok(()),
+        };
+
+        // Write to a temporary file first so that a partially written file is never loaded, and
+        // sync it so that the rename doesn't replace the previous totals with an empty file after
+        // a crash.
+        let tmp_path = format!("{}.tmp", stats_file);
+        let mut tmp_file = tokio::fs::File::create(&tmp_path).await?;
+        tmp_file.write_all(&state_bytes).await?;
+        tmp_file.sync_all().await?;
+        drop(tmp_file);
+        tokio::fs::rename(&tmp_path, stats_file).await
+    }
+}
+
+// Loads the saved totals, and saves them periodically.
+pub async fn start_stats_store(config: StatsConfig) -> std::io::Result<()> {
+    let StatsConfig {
+        stats_file,
+        save_interval_secs,
+    } = config;
+
+    let stats = bandwidth_stats();
+    stats.load(&stats_file)?;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(save_interval_secs));
+        // The first tick completes immediately.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = stats.save(&stats_file).await {
+                error!("Failed to save stats to {}: {}", stats_file, e);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,545 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use shoes_shuttle::address::NetLocation;
+use shoes_shuttle::clash_import::import_clash_config;
+use shoes_shuttle::client_export::export_client_config;
+use shoes_shuttle::client_proxy_selector::{ConnectAction, ConnectDecision, ProxyPool};
+use shoes_shuttle::config::{
+    check_configs, load_configs, BalanceMode, ClientConfig, ConfigSelection, ServerConfig,
+    StickyMode, Transport,
+};
+use shoes_shuttle::config_schema::json_schema;
+use shoes_shuttle::resolver::{create_resolver, Resolver};
+use shoes_shuttle::share_link::{share_links, ShareLink};
+use shoes_shuttle::tcp_handler_util::create_client_proxy_selector;
+
+const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+
+// Used for --no-resolve, so that only rules that can be matched without DNS are checked.
+// IP destinations are returned as-is, only hostnames fail to resolve.
+struct NoResolveResolver;
+
+impl Resolver for NoResolveResolver {
+    fn resolve_location(
+        &self,
+        location: &NetLocation,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+        let result = match location.to_socket_addr_nonblocking() {
+            Some(socket_addr) => Ok(vec![socket_addr]),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("not resolving {}", location),
+            )),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+fn print_usage_and_exit(arg0: String) -> ! {
+    eprintln!("{} [OPTIONS] [config.yaml...]", arg0);
+    eprintln!();
+    eprintln!(
+        "Config files default to {}. Use - to read a config from stdin, or pass a http(s) URL.",
+        DEFAULT_CONFIG_PATH
+    );
+    eprintln!();
+    eprintln!("OPTIONS:");
+    eprintln!("    --check                Report all config errors and warnings, and exit with a");
+    eprintln!("                           non-zero status if there are any errors");
+    eprintln!("    --client-config <address>");
+    eprintln!("                           Print a client proxy config for each server, connecting");
+    eprintln!("                           to the given address, which defaults to the server port");
+    eprintln!("    --explain <host:port>  Show which rule each server matches for a destination");
+    eprintln!("    --import-clash <clash.yaml>");
+    eprintln!("                           Print a config converted from a Clash config, with");
+    eprintln!("                           warnings for anything that couldn't be converted");
+    eprintln!(
+        "    --json                 With --check, print the errors and warnings as JSON, with"
+    );
+    eprintln!("                           the file, line, column and path of each where known");
+    eprintln!("    --no-resolve           Don't resolve hostname destinations when explaining, so");
+    eprintln!("                           rules that need DNS are skipped and later matches are");
+    eprintln!("                           reported as undetermined");
+    eprintln!("    --print-schema         Print a JSON Schema for config files");
+    eprintln!("    --share-links <host>   Print share links for each server, for clients that");
+    eprintln!("                           connect to the given public host");
+    std::process::exit(1);
+}
+
+fn describe_client_config(client_config: &ClientConfig) -> String {
+    let mut description = if client_config.protocol.is_direct() {
+        client_config.protocol.to_string()
+    } else {
+        format!("{} at {}", client_config.protocol, client_config.address)
+    };
+    if client_config.transport != Transport::Tcp {
+        description.push_str(&format!(" ({:?})", client_config.transport).to_lowercase());
+    }
+    if client_config.mux_settings.is_some() {
+        description.push_str(" with mux");
+    }
+    description
+}
+
+async fn explain_server(
+    index: usize,
+    server_config: ServerConfig,
+    location: &NetLocation,
+    resolver: &Arc<dyn Resolver>,
+    no_resolve: bool,
+) {
+    let label = match server_config.name {
+        Some(ref name) => format!("Server {} ({})", index + 1, name),
+        None => format!("Server {}", index + 1),
+    };
+    let ServerConfig {
+        bind_locations,
+        protocol,
+        transports,
+        rules,
+        ..
+    } = server_config;
+
+    let transports = transports
+        .iter()
+        .map(|transport| format!("{:?}", transport))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!(
+        "{}: {} ({}) at {}",
+        label, protocol, transports, bind_locations
+    );
+
+    let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
+    let selector = create_client_proxy_selector(rules, |client_config| client_config);
+
+    let mut matched_rule = None;
+    let mut failed = false;
+    // Labels of rules skipped because they need DNS, any later match only
+    // applies if none of these match once the destination is resolved.
+    let mut skipped_labels = vec![];
+    for rule in selector.rules().iter() {
+        let label = rule.label();
+        if matched_rule.is_some() || failed {
+            println!("  {}: {} -> not checked", label, rule);
+            continue;
+        }
+        if !rule.is_active() {
+            println!("  {}: {} -> skipped, outside its schedule", label, rule);
+            continue;
+        }
+        match rule.matching_mask(location, resolver).await {
+            Ok(Some(mask)) => {
+                if skipped_labels.is_empty() {
+                    println!("  {}: {} -> MATCHED by {}", label, rule, mask);
+                } else {
+                    println!(
+                        "  {}: {} -> would match by {} only if {} doesn't",
+                        label,
+                        rule,
+                        mask,
+                        skipped_labels.join(" or ")
+                    );
+                }
+                matched_rule = Some(rule);
+            }
+            Ok(None) => {
+                println!("  {}: {} -> no match", label, rule);
+            }
+            Err(_) if no_resolve => {
+                println!(
+                    "  {}: {} -> skipped, needs the destination to be resolved",
+                    label, rule
+                );
+                skipped_labels.push(label);
+            }
+            Err(e) => {
+                println!("  {}: {} -> error: {}", label, rule, e);
+                failed = true;
+            }
+        }
+    }
+
+    let result_label = if skipped_labels.is_empty() {
+        "Result".to_string()
+    } else {
+        println!(
+            "  Result: undetermined, depends on {} which needs the destination to be resolved",
+            skipped_labels.join(" and ")
+        );
+        format!("If {} doesn't match", skipped_labels.join(" or "))
+    };
+
+    let rule = match matched_rule {
+        Some(rule) => rule,
+        None => {
+            if failed {
+                println!("  Result: connection fails because rule matching failed");
+            } else {
+                println!("  {}: block, no rule matched", result_label);
+            }
+            return;
+        }
+    };
+
+    match rule.action.to_decision(location.clone(), None, None) {
+        ConnectDecision::Allow {
+            remote_location, ..
+        } => {
+            let (client_proxies, sticky, balance, select_by) = match rule.action {
+                ConnectAction::Allow {
+                    ref client_proxies,
+                    sticky,
+                    balance,
+                    ref select_by,
+                    ..
+                } => (client_proxies, sticky, balance, select_by),
+                ConnectAction::Block => unreachable!(),
+            };
+            println!(
+                "  {}: allow, connecting to {}",
+                result_label, remote_location
+            );
+            match select_by {
+                None => print_client_proxies("    ", client_proxies, sticky, balance),
+                Some(select_by) => {
+                    println!("    with client proxies picked by {}:", select_by.key());
+                    for (value, group_proxies) in select_by.groups() {
+                        println!("      {}:", value);
+                        print_client_proxies("        ", group_proxies, sticky, balance);
+                    }
+                    println!("      otherwise:");
+                    print_client_proxies("        ", client_proxies, sticky, balance);
+                }
+            }
+        }
+        ConnectDecision::Block => {
+            println!("  {}: block", result_label);
+        }
+    }
+}
+
+fn print_client_proxies(
+    indent: &str,
+    client_proxies: &ProxyPool<ClientConfig>,
+    sticky: StickyMode,
+    balance: BalanceMode,
+) {
+    let client_proxies = client_proxies.client_proxies().iter().collect::<Vec<_>>();
+    if client_proxies.len() == 1 {
+        println!(
+            "{}via {}",
+            indent,
+            describe_client_config(client_proxies[0])
+        );
+    } else {
+        let order = match (sticky, balance) {
+            (StickyMode::SourceIp, _) => "chosen by source IP",
+            (StickyMode::None, BalanceMode::RoundRobin) => "in round robin order",
+            (StickyMode::None, BalanceMode::LeastConn) => "with the fewest active connections",
+        };
+        println!("{}via one of, {}:", indent, order);
+        for client_proxy in client_proxies {
+            println!("{}  {}", indent, describe_client_config(client_proxy));
+        }
+    }
+}
+
+fn print_share_links(index: usize, server_config: &ServerConfig, host: &str) {
+    let label = match server_config.name {
+        Some(ref name) => format!("Server {} ({})", index + 1, name),
+        None => format!("Server {}", index + 1),
+    };
+    println!(
+        "{}: {} at {}",
+        label, server_config.protocol, server_config.bind_locations
+    );
+    for link in share_links(server_config, host) {
+        match link {
+            ShareLink::Url(url) => println!("  {}", url),
+            ShareLink::Unsupported(reason) => println!("  skipped: {}", reason),
+        }
+    }
+}
+
+fn print_client_config(index: usize, server_config: &ServerConfig, address: &str) {
+    let label = match server_config.name {
+        Some(ref name) => format!("Server {} ({})", index + 1, name),
+        None => format!("Server {}", index + 1),
+    };
+    println!(
+        "# {}: {} at {}",
+        label, server_config.protocol, server_config.bind_locations
+    );
+    match export_client_config(server_config, address) {
+        Ok(exported) => {
+            for note in exported.notes.iter() {
+                println!("# note: {}", note);
+            }
+            print!("{}", exported.yaml);
+        }
+        Err(e) => println!("# unsupported: {}", e),
+    }
+}
+
+async fn import_clash(path: &str) {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    match import_clash_config(&contents) {
+        Ok(imported) => {
+            for warning in imported.warnings.iter() {
+                println!("# warning: {}", warning);
+            }
+            print!("{}", imported.yaml);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn check(config_paths: &[String], json_output: bool) {
+    let report = check_configs(config_paths).await;
+    if json_output {
+        let output = serde_json::json!({
+            "errors": report.errors,
+            "warnings": report.warnings,
+        });
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        if !report.errors.is_empty() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    for warning in report.warnings.iter() {
+        println!("warning: {}", warning);
+    }
+    for error in report.errors.iter() {
+        println!("error: {}", error);
+    }
+    println!(
+        "{} error(s), {} warning(s)",
+        report.errors.len(),
+        report.warnings.len()
+    );
+    if !report.errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let arg0 = args.remove(0);
+
+    let mut check_only = false;
+    let mut json_output = false;
+    let mut print_schema = false;
+    let mut explain_location: Option<NetLocation> = None;
+    let mut no_resolve = false;
+    let mut share_host: Option<String> = None;
+    let mut client_address: Option<String> = None;
+    let mut clash_path: Option<String> = None;
+    let mut config_paths = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--explain" => {
+                let location_str = match args.next() {
+                    Some(s) => s,
+                    None => {
+                        eprintln!("Missing location for --explain");
+                        print_usage_and_exit(arg0);
+                    }
+                };
+                match NetLocation::from_str(&location_str, None) {
+                    Ok(location) => {
+                        explain_location = Some(location);
+                    }
+                    Err(e) => {
+                        eprintln!("Invalid location {}: {}", location_str, e);
+                        print_usage_and_exit(arg0);
+                    }
+                }
+            }
+            "--client-config" => match args.next() {
+                Some(address) => {
+                    client_address = Some(address);
+                }
+                None => {
+                    eprintln!("Missing address for --client-config");
+                    print_usage_and_exit(arg0);
+                }
+            },
+            "--import-clash" => match args.next() {
+                Some(path) => {
+                    clash_path = Some(path);
+                }
+                None => {
+                    eprintln!("Missing path for --import-clash");
+                    print_usage_and_exit(arg0);
+                }
+            },
+            "--share-links" => match args.next() {
+                Some(host) => {
+                    share_host = Some(host);
+                }
+                None => {
+                    eprintln!("Missing host for --share-links");
+                    print_usage_and_exit(arg0);
+                }
+            },
+            "--check" => {
+                check_only = true;
+            }
+            "--json" => {
+                json_output = true;
+            }
+            "--print-schema" => {
+                print_schema = true;
+            }
+            "--no-resolve" => {
+                no_resolve = true;
+            }
+            "-h" | "--help" => {
+                print_usage_and_exit(arg0);
+            }
+            _ => {
+                if arg.starts_with("--") {
+                    eprintln!("Invalid option: {}", arg);
+                    print_usage_and_exit(arg0);
+                }
+                config_paths.push(arg);
+            }
+        }
+    }
+
+    if json_output && !check_only {
+        eprintln!("--json can only be used with --check.");
+        print_usage_and_exit(arg0);
+    }
+
+    if print_schema {
+        if check_only
+            || explain_location.is_some()
+            || share_host.is_some()
+            || client_address.is_some()
+            || clash_path.is_some()
+            || !config_paths.is_empty()
+        {
+            eprintln!("--print-schema can't be used with other options or config files.");
+            print_usage_and_exit(arg0);
+        }
+        println!("{}", serde_json::to_string_pretty(&json_schema()).unwrap());
+        return;
+    }
+
+    if let Some(path) = clash_path {
+        if check_only
+            || explain_location.is_some()
+            || share_host.is_some()
+            || client_address.is_some()
+            || !config_paths.is_empty()
+        {
+            eprintln!("--import-clash can't be used with other options or config files.");
+            print_usage_and_exit(arg0);
+        }
+        import_clash(&path).await;
+        return;
+    }
+
+    if config_paths.is_empty() {
+        config_paths.push(DEFAULT_CONFIG_PATH.to_string());
+    }
+
+    if let Some(address) = client_address {
+        if check_only || explain_location.is_some() || share_host.is_some() {
+            eprintln!("--client-config can't be used with other commands.");
+            print_usage_and_exit(arg0);
+        }
+        let server_configs = match load_configs(&config_paths).await {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Failed to load config: {}", e);
+                std::process::exit(1);
+            }
+        };
+        for (i, server_config) in server_configs.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            print_client_config(i, server_config, &address);
+        }
+        return;
+    }
+
+    if let Some(host) = share_host {
+        if check_only || explain_location.is_some() {
+            eprintln!("--share-links can't be used with --check or --explain.");
+            print_usage_and_exit(arg0);
+        }
+        let server_configs = match load_configs(&config_paths).await {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("Failed to load config: {}", e);
+                std::process::exit(1);
+            }
+        };
+        for (i, server_config) in server_configs.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            print_share_links(i, server_config, &host);
+        }
+        return;
+    }
+
+    let location = match (check_only, explain_location) {
+        (true, None) => {
+            check(&config_paths, json_output).await;
+            return;
+        }
+        (false, Some(l)) => l,
+        (true, Some(_)) => {
+            eprintln!("--check and --explain can't be used together.");
+            print_usage_and_exit(arg0);
+        }
+        (false, None) => {
+            eprintln!("No command specified.");
+            print_usage_and_exit(arg0);
+        }
+    };
+
+    let server_configs = match load_configs(&config_paths).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let resolver: Arc<dyn Resolver> = if no_resolve {
+        Arc::new(NoResolveResolver)
+    } else {
+        create_resolver(None)
+    };
+
+    println!(
+        "Explaining {}, rules are checked in order and the first match wins.",
+        location
+    );
+    for (i, server_config) in server_configs.into_iter().enumerate() {
+        println!();
+        explain_server(i, server_config, &location, &resolver, no_resolve).await;
+    }
+}
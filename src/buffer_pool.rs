@@ -0,0 +1,59 @@
+use std::sync::OnceLock;
+
+use parking_lot::Mutex;
+
+use crate::util::allocate_vec;
+
+// The size of the buffers used to copy between streams, in each direction.
+pub const COPY_BUFFER_SIZE: usize = 16384;
+
+// Only this much memory is kept for reuse, so that a burst of connections doesn't keep its
+// buffers allocated after the connections close.
+const MAX_RETAINED_BYTES: usize = 4 * 1024 * 1024;
+
+// Reuses buffers between connections, to avoid an allocation for each connection. When the pool
+// is empty, a new buffer is allocated instead of waiting for one to be returned.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffer_size: usize,
+    max_buffers: usize,
+    buffers: Mutex<Vec<Box<[u8]>>>,
+}
+
+pub fn copy_buffer_pool() -> &'static BufferPool {
+    static INSTANCE: OnceLock<BufferPool> = OnceLock::new();
+    INSTANCE.get_or_init(|| BufferPool::new(COPY_BUFFER_SIZE, MAX_RETAINED_BYTES))
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_retained_bytes: usize) -> Self {
+        Self {
+            buffer_size,
+            max_buffers: max_retained_bytes / buffer_size,
+            buffers: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    // Buffers aren't cleared when they're returned, like the uninitialized buffers from
+    // allocate_vec, so callers must only write out bytes that they've read into the buffer.
+    pub fn take(&self) -> Box<[u8]> {
+        match self.buffers.lock().pop() {
+            Some(buf) => buf,
+            None => allocate_vec(self.buffer_size).into_boxed_slice(),
+        }
+    }
+
+    pub fn put(&self, buf: Box<[u8]>) {
+        if buf.len() != self.buffer_size {
+            return;
+        }
+        let mut buffers = self.buffers.lock();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
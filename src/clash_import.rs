@@ -0,0 +1,698 @@
+use std::collections::{HashMap, HashSet};
+
+use serde_yaml::{Mapping, Value};
+
+// A config converted from a Clash config, as YAML.
+pub struct ImportedConfig {
+    pub yaml: String,
+    // Clash features that couldn't be converted, or were converted with different behavior.
+    pub warnings: Vec<String>,
+}
+
+const RULE_GROUP_NAME: &str = "clash-rules";
+
+// Groups that contain other groups are flattened, up to this depth.
+const MAX_GROUP_DEPTH: usize = 8;
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn mapping(entries: Vec<(&str, Value)>) -> Value {
+    let mut mapping = Mapping::new();
+    for (key, value) in entries {
+        mapping.insert(Value::String(key.to_string()), value);
+    }
+    Value::Mapping(mapping)
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+// Clash configs often have numbers or booleans where strings are expected, eg. for passwords.
+fn get_string(value: &Value, key: &str) -> Option<String> {
+    match value.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn get_bool(value: &Value, key: &str) -> bool {
+    match value.get(key) {
+        Some(Value::Bool(b)) => *b,
+        Some(Value::String(s)) => s == "true",
+        _ => false,
+    }
+}
+
+fn get_strings(value: &Value, key: &str) -> Vec<String> {
+    match value.get(key) {
+        Some(Value::Sequence(values)) => values
+            .iter()
+            .filter_map(|value| match value {
+                Value::String(s) => Some(s.clone()),
+                Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            })
+            .collect(),
+        Some(Value::String(s)) => vec![s.clone()],
+        _ => vec![],
+    }
+}
+
+fn require_string(value: &Value, key: &str, name: &str) -> Result<String, String> {
+    get_string(value, key).ok_or_else(|| format!("proxy {} is missing {}", name, key))
+}
+
+// Converts a Clash config to client groups for its proxies and proxy groups, a rule group for
+// its rules, and servers for its listening ports.
+pub fn import_clash_config(contents: &str) -> std::io::Result<ImportedConfig> {
+    let clash_config: Value = serde_yaml::from_str(contents)
+        .map_err(|e| invalid_data(format!("Failed to parse Clash config: {}", e)))?;
+    if !clash_config.is_mapping() {
+        return Err(invalid_data(
+            "Clash config should be a mapping with proxies and rules".to_string(),
+        ));
+    }
+
+    let mut warnings = vec![];
+    let mut configs = vec![];
+
+    // Each proxy becomes a client group with the same name, so that rules can refer to it.
+    let mut proxies: HashMap<String, Value> = HashMap::new();
+    if let Some(Value::Sequence(clash_proxies)) = clash_config.get("proxies") {
+        for clash_proxy in clash_proxies.iter() {
+            let name = match get_string(clash_proxy, "name") {
+                Some(name) => name,
+                None => {
+                    warnings.push("skipped a proxy without a name".to_string());
+                    continue;
+                }
+            };
+            match convert_proxy(clash_proxy, &name, &mut warnings) {
+                Ok(client_config) => {
+                    configs.push(mapping(vec![
+                        ("client_group", string(&name)),
+                        ("client_proxy", client_config.clone()),
+                    ]));
+                    proxies.insert(name, client_config);
+                }
+                Err(reason) => {
+                    warnings.push(format!("skipped proxy {}: {}", name, reason));
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<String, Value> = HashMap::new();
+    if let Some(Value::Sequence(clash_groups)) = clash_config.get("proxy-groups") {
+        for clash_group in clash_groups.iter() {
+            if let Some(name) = get_string(clash_group, "name") {
+                groups.insert(name, clash_group.clone());
+            }
+        }
+        for clash_group in clash_groups.iter() {
+            let name = match get_string(clash_group, "name") {
+                Some(name) => name,
+                None => {
+                    warnings.push("skipped a proxy group without a name".to_string());
+                    continue;
+                }
+            };
+            let client_proxies =
+                convert_group(clash_group, &name, &proxies, &groups, &mut warnings);
+            if client_proxies.is_empty() {
+                warnings.push(format!(
+                    "skipped proxy group {}: none of its proxies could be converted",
+                    name
+                ));
+                continue;
+            }
+            configs.push(mapping(vec![
+                ("client_group", string(&name)),
+                ("client_proxies", Value::Sequence(client_proxies)),
+            ]));
+        }
+    }
+
+    // Proxy groups that were skipped can't be used by rules.
+    let mut targets: HashSet<String> = proxies.keys().cloned().collect();
+    for config in configs.iter() {
+        if let Some(name) = get_string(config, "client_group") {
+            targets.insert(name);
+        }
+    }
+
+    let rules = match clash_config.get("rules") {
+        Some(Value::Sequence(clash_rules)) => convert_rules(clash_rules, &targets, &mut warnings),
+        _ => {
+            warnings
+                .push("the Clash config has no rules, so all connections are direct".to_string());
+            vec![allow_rule(vec![string("0.0.0.0/0")], "direct")]
+        }
+    };
+    configs.push(mapping(vec![
+        ("rule_group", string(RULE_GROUP_NAME)),
+        ("rules", Value::Sequence(rules)),
+    ]));
+
+    let servers = convert_servers(&clash_config, &mut warnings);
+    if servers.is_empty() {
+        warnings.push(
+            "the Clash config has no port, socks-port or mixed-port, add a server that uses \
+             the clash-rules rule group"
+                .to_string(),
+        );
+    }
+    configs.extend(servers);
+
+    for key in ["dns", "tun", "hosts", "rule-providers", "proxy-providers"] {
+        if clash_config.get(key).is_some() {
+            warnings.push(format!("{} settings are not supported", key));
+        }
+    }
+
+    let yaml = serde_yaml::to_string(&Value::Sequence(configs))
+        .map_err(|e| invalid_data(format!("Failed to write config: {}", e)))?;
+    Ok(ImportedConfig { yaml, warnings })
+}
+
+fn convert_proxy(
+    clash_proxy: &Value,
+    name: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Value, String> {
+    let proxy_type = require_string(clash_proxy, "type", name)?;
+    let server = require_string(clash_proxy, "server", name)?;
+    let port = require_string(clash_proxy, "port", name)?;
+    // IPv6 addresses need brackets before the port.
+    let address = if server.contains(':') {
+        format!("[{}]:{}", server, port)
+    } else {
+        format!("{}:{}", server, port)
+    };
+
+    let protocol = match proxy_type.as_str() {
+        "ss" => convert_shadowsocks(clash_proxy, name, warnings)?,
+        "vmess" => {
+            let user_id = require_string(clash_proxy, "uuid", name)?;
+            let cipher = match get_string(clash_proxy, "cipher").as_deref() {
+                None | Some("auto") => "any".to_string(),
+                Some(cipher) => cipher.to_string(),
+            };
+            let alter_id = get_string(clash_proxy, "alterId").unwrap_or_default();
+            let mut entries = vec![
+                ("type", string("vmess")),
+                ("cipher", string(&cipher)),
+                ("user_id", string(&user_id)),
+            ];
+            if !alter_id.is_empty() && alter_id != "0" {
+                entries.push(("aead", Value::Bool(false)));
+            }
+            let protocol = convert_network(clash_proxy, name, mapping(entries), warnings)?;
+            if get_bool(clash_proxy, "tls") {
+                convert_tls(clash_proxy, protocol)
+            } else {
+                protocol
+            }
+        }
+        "trojan" => {
+            let password = require_string(clash_proxy, "password", name)?;
+            let protocol = mapping(vec![
+                ("type", string("trojan")),
+                ("password", string(&password)),
+            ]);
+            let protocol = convert_network(clash_proxy, name, protocol, warnings)?;
+            // Trojan always uses TLS.
+            convert_tls(clash_proxy, protocol)
+        }
+        "vless" => {
+            let user_id = require_string(clash_proxy, "uuid", name)?;
+            let mut entries = vec![("type", string("vless")), ("user_id", string(&user_id))];
+            // The Vision flow is only supported directly inside TLS.
+            let is_tls_over_tcp = get_bool(clash_proxy, "tls")
+                && matches!(
+                    get_string(clash_proxy, "network").as_deref(),
+                    None | Some("tcp")
+                );
+            match get_string(clash_proxy, "flow").as_deref() {
+                None | Some("") => (),
+                Some("xtls-rprx-vision") if is_tls_over_tcp => {
+                    entries.push(("flow", string("xtls-rprx-vision")));
+                }
+                Some(flow) => warnings.push(format!(
+                    "proxy {}: flow {} is not supported, plain vless is used",
+                    name, flow
+                )),
+            }
+            let protocol = mapping(entries);
+            let protocol = convert_network(clash_proxy, name, protocol, warnings)?;
+            if get_bool(clash_proxy, "tls") {
+                convert_tls(clash_proxy, protocol)
+            } else {
+                protocol
+            }
+        }
+        "socks5" | "http" => {
+            let protocol_type = if proxy_type == "socks5" {
+                "socks"
+            } else {
+                "http"
+            };
+            let mut entries = vec![("type", string(protocol_type))];
+            if let Some(username) = get_string(clash_proxy, "username") {
+                entries.push(("username", string(&username)));
+            }
+            if let Some(password) = get_string(clash_proxy, "password") {
+                entries.push(("password", string(&password)));
+            }
+            if get_bool(clash_proxy, "tls") {
+                convert_tls(clash_proxy, mapping(entries))
+            } else {
+                mapping(entries)
+            }
+        }
+        _ => {
+            return Err(format!("{} proxies are not supported", proxy_type));
+        }
+    };
+
+    Ok(mapping(vec![
+        ("address", string(&address)),
+        ("protocol", protocol),
+    ]))
+}
+
+fn convert_shadowsocks(
+    clash_proxy: &Value,
+    name: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Value, String> {
+    let cipher = require_string(clash_proxy, "cipher", name)?;
+    let password = require_string(clash_proxy, "password", name)?;
+    let mut entries = vec![
+        ("type", string("shadowsocks")),
+        ("cipher", string(&cipher)),
+        ("password", string(&password)),
+    ];
+    let plugin_opts = clash_proxy.get("plugin-opts");
+    let plugin_opt = |key: &str| plugin_opts.and_then(|opts| get_string(opts, key));
+    match get_string(clash_proxy, "plugin").as_deref() {
+        None => (),
+        Some("obfs") => {
+            let mut opts = vec![format!(
+                "obfs={}",
+                plugin_opt("mode").unwrap_or_else(|| "http".to_string())
+            )];
+            if let Some(host) = plugin_opt("host") {
+                opts.push(format!("obfs-host={}", host));
+            }
+            entries.push(("plugin", string("obfs-local")));
+            entries.push(("plugin_opts", string(&opts.join(";"))));
+        }
+        Some("v2ray-plugin") => {
+            let mut opts = vec![];
+            if let Some(mode) = plugin_opt("mode") {
+                opts.push(format!("mode={}", mode));
+            }
+            if plugin_opts.is_some_and(|opts| get_bool(opts, "tls")) {
+                opts.push("tls".to_string());
+            }
+            if let Some(host) = plugin_opt("host") {
+                opts.push(format!("host={}", host));
+            }
+            if let Some(path) = plugin_opt("path") {
+                opts.push(format!("path={}", path));
+            }
+            entries.push(("plugin", string("v2ray-plugin")));
+            if !opts.is_empty() {
+                entries.push(("plugin_opts", string(&opts.join(";"))));
+            }
+        }
+        Some(plugin) => {
+            return Err(format!("the {} plugin is not supported", plugin));
+        }
+    }
+    if get_bool(clash_proxy, "udp-over-tcp") {
+        if get_string(clash_proxy, "udp-over-tcp-version").as_deref() != Some("2") {
+            warnings.push(format!(
+                "proxy {}: UDP-over-TCP version 2 is used, which the server needs to support",
+                name
+            ));
+        }
+        entries.push(("udp_over_tcp", Value::Bool(true)));
+    }
+    if entries.iter().any(|(key, _)| *key == "plugin") {
+        warnings.push(format!(
+            "proxy {}: the plugin executable needs to be installed",
+            name
+        ));
+    }
+    Ok(mapping(entries))
+}
+
+// Wraps the protocol in websocket or gRPC, for the ws and grpc networks.
+fn convert_network(
+    clash_proxy: &Value,
+    name: &str,
+    protocol: Value,
+    warnings: &mut Vec<String>,
+) -> Result<Value, String> {
+    match get_string(clash_proxy, "network").as_deref() {
+        None | Some("tcp") => Ok(protocol),
+        Some("ws") => {
+            let mut entries = vec![("type", string("websocket"))];
+            let ws_opts = clash_proxy.get("ws-opts");
+            // Older Clash configs put the options at the top level.
+            let path = ws_opts
+                .and_then(|opts| get_string(opts, "path"))
+                .or_else(|| get_string(clash_proxy, "ws-path"));
+            if let Some(path) = path {
+                entries.push(("matching_path", string(&path)));
+            }
+            let headers = ws_opts
+                .and_then(|opts| opts.get("headers"))
+                .or_else(|| clash_proxy.get("ws-headers"));
+            if let Some(Value::Mapping(headers)) = headers {
+                entries.push(("matching_headers", Value::Mapping(headers.clone())));
+            }
+            if ws_opts.is_some_and(|opts| opts.get("max-early-data").is_some()) {
+                warnings.push(format!(
+                    "proxy {}: websocket early data is not supported",
+                    name
+                ));
+            }
+            entries.push(("protocol", protocol));
+            Ok(mapping(entries))
+        }
+        Some("grpc") => {
+            let mut entries = vec![("type", string("grpc"))];
+            let service_name = clash_proxy
+                .get("grpc-opts")
+                .and_then(|opts| get_string(opts, "grpc-service-name"));
+            if let Some(service_name) = service_name {
+                entries.push(("service_name", string(&service_name)));
+            }
+            entries.push(("protocol", protocol));
+            Ok(mapping(entries))
+        }
+        Some(network) => Err(format!("the {} network is not supported", network)),
+    }
+}
+
+fn convert_tls(clash_proxy: &Value, protocol: Value) -> Value {
+    let mut entries = vec![("type", string("tls"))];
+    let sni_hostname =
+        get_string(clash_proxy, "servername").or_else(|| get_string(clash_proxy, "sni"));
+    if let Some(sni_hostname) = sni_hostname {
+        entries.push(("sni_hostname", string(&sni_hostname)));
+    }
+    if get_bool(clash_proxy, "skip-cert-verify") {
+        entries.push(("verify", Value::Bool(false)));
+    }
+    let alpn_protocols = get_strings(clash_proxy, "alpn");
+    if !alpn_protocols.is_empty() {
+        entries.push((
+            "alpn_protocols",
+            Value::Sequence(alpn_protocols.iter().map(|s| string(s)).collect()),
+        ));
+    }
+    entries.push(("protocol", protocol));
+    mapping(entries)
+}
+
+// Client groups can't contain other groups, so the proxies of nested groups are copied in.
+fn convert_group(
+    clash_group: &Value,
+    name: &str,
+    proxies: &HashMap<String, Value>,
+    groups: &HashMap<String, Value>,
+    warnings: &mut Vec<String>,
+) -> Vec<Value> {
+    let group_type = get_string(clash_group, "type").unwrap_or_default();
+    let members = get_strings(clash_group, "proxies");
+    if clash_group.get("use").is_some() {
+        warnings.push(format!(
+            "proxy group {}: proxy providers are not supported",
+            name
+        ));
+    }
+
+    let members = match group_type.as_str() {
+        "select" => {
+            if members.len() > 1 {
+                warnings.push(format!(
+                    "proxy group {}: select groups can't be switched, the first proxy {} is used",
+                    name, members[0]
+                ));
+            }
+            members.into_iter().take(1).collect()
+        }
+        "url-test" | "fallback" => {
+            warnings.push(format!(
+                "proxy group {}: {} is not supported, proxies are used in round robin order",
+                name, group_type
+            ));
+            members
+        }
+        "load-balance" => members,
+        _ => {
+            warnings.push(format!(
+                "proxy group {}: {} groups are not supported, proxies are used in round robin \
+                 order",
+                name, group_type
+            ));
+            members
+        }
+    };
+
+    let mut client_proxies = vec![];
+    for member in members {
+        collect_group_member(
+            &member,
+            name,
+            proxies,
+            groups,
+            0,
+            &mut client_proxies,
+            warnings,
+        );
+    }
+    client_proxies
+}
+
+fn collect_group_member(
+    member: &str,
+    group_name: &str,
+    proxies: &HashMap<String, Value>,
+    groups: &HashMap<String, Value>,
+    depth: usize,
+    client_proxies: &mut Vec<Value>,
+    warnings: &mut Vec<String>,
+) {
+    if member == "DIRECT" {
+        client_proxies.push(mapping(vec![(
+            "protocol",
+            mapping(vec![("type", string("direct"))]),
+        )]));
+    } else if member == "REJECT" {
+        warnings.push(format!(
+            "proxy group {}: REJECT can't be part of a group, it was left out",
+            group_name
+        ));
+    } else if let Some(client_config) = proxies.get(member) {
+        client_proxies.push(client_config.clone());
+    } else if let Some(clash_group) = groups.get(member) {
+        if depth >= MAX_GROUP_DEPTH {
+            warnings.push(format!(
+                "proxy group {}: groups are nested too deeply at {}",
+                group_name, member
+            ));
+            return;
+        }
+        // Nested groups are used with all their proxies, whatever their type.
+        for nested_member in get_strings(clash_group, "proxies") {
+            collect_group_member(
+                &nested_member,
+                group_name,
+                proxies,
+                groups,
+                depth + 1,
+                client_proxies,
+                warnings,
+            );
+        }
+    } else {
+        warnings.push(format!(
+            "proxy group {}: {} was not converted, it was left out",
+            group_name, member
+        ));
+    }
+}
+
+fn allow_rule(masks: Vec<Value>, client_group: &str) -> Value {
+    mapping(vec![
+        ("masks", Value::Sequence(masks)),
+        ("action", string("allow")),
+        ("client_proxy", string(client_group)),
+    ])
+}
+
+fn block_rule(masks: Vec<Value>) -> Value {
+    mapping(vec![
+        ("masks", Value::Sequence(masks)),
+        ("action", string("block")),
+    ])
+}
+
+fn target_rule(target: String, masks: Vec<Value>) -> Value {
+    match target.as_str() {
+        "DIRECT" => allow_rule(masks, "direct"),
+        "REJECT" | "REJECT-DROP" => block_rule(masks),
+        _ => allow_rule(masks, &target),
+    }
+}
+
+// Converts rules in order, merging consecutive rules with the same target.
+fn convert_rules(
+    clash_rules: &[Value],
+    targets: &HashSet<String>,
+    warnings: &mut Vec<String>,
+) -> Vec<Value> {
+    let mut rules = vec![];
+    let mut current: Option<(String, Vec<Value>)> = None;
+    let mut has_match = false;
+    let mut warned_domain = false;
+
+    for clash_rule in clash_rules.iter() {
+        let clash_rule = match clash_rule {
+            Value::String(s) => s.as_str(),
+            _ => {
+                warnings.push("skipped a rule that isn't a string".to_string());
+                continue;
+            }
+        };
+        let parts = clash_rule.split(',').map(str::trim).collect::<Vec<_>>();
+        let (mask, target) = match parts.as_slice() {
+            ["MATCH", target, ..] | ["FINAL", target, ..] => ("0.0.0.0/0".to_string(), *target),
+            ["DOMAIN-SUFFIX", domain, target, ..] => (domain.to_string(), *target),
+            ["DOMAIN", domain, target, ..] => {
+                if !warned_domain {
+                    warnings
+                        .push("DOMAIN rules also match subdomains, like DOMAIN-SUFFIX".to_string());
+                    warned_domain = true;
+                }
+                (domain.to_string(), *target)
+            }
+            ["IP-CIDR", cidr, target, ..] | ["IP-CIDR6", cidr, target, ..] => {
+                (cidr.to_string(), *target)
+            }
+            _ => {
+                warnings.push(format!("skipped rule {}: not supported", clash_rule));
+                continue;
+            }
+        };
+
+        let target = target.to_string();
+        if !matches!(target.as_str(), "DIRECT" | "REJECT" | "REJECT-DROP")
+            && !targets.contains(&target)
+        {
+            warnings.push(format!(
+                "skipped rule {}: proxy {} was not converted",
+                clash_rule, target
+            ));
+            continue;
+        }
+        has_match = matches!(parts[0], "MATCH" | "FINAL");
+
+        match current {
+            Some((ref current_target, ref mut masks)) if *current_target == target => {
+                masks.push(string(&mask));
+            }
+            _ => {
+                if let Some((current_target, masks)) = current.take() {
+                    rules.push(target_rule(current_target, masks));
+                }
+                current = Some((target, vec![string(&mask)]));
+            }
+        }
+
+        if has_match {
+            break;
+        }
+    }
+    if let Some((current_target, masks)) = current.take() {
+        rules.push(target_rule(current_target, masks));
+    }
+
+    // Clash connects directly when no rule matches.
+    if !has_match {
+        rules.push(allow_rule(vec![string("0.0.0.0/0")], "direct"));
+    }
+    rules
+}
+
+fn convert_servers(clash_config: &Value, warnings: &mut Vec<String>) -> Vec<Value> {
+    let bind_address = if get_bool(clash_config, "allow-lan") {
+        get_string(clash_config, "bind-address")
+            .filter(|address| address != "*")
+            .unwrap_or_else(|| "0.0.0.0".to_string())
+    } else {
+        "127.0.0.1".to_string()
+    };
+    let bind_address = if bind_address.contains(':') {
+        format!("[{}]", bind_address)
+    } else {
+        bind_address
+    };
+
+    // Clash authentication entries are user:password.
+    let users = get_strings(clash_config, "authentication")
+        .into_iter()
+        .filter_map(|entry| {
+            let (username, password) = entry.split_once(':')?;
+            Some(mapping(vec![
+                ("username", string(username)),
+                ("password", string(password)),
+            ]))
+        })
+        .collect::<Vec<_>>();
+
+    let mut ports = vec![];
+    if let Some(port) = get_string(clash_config, "port") {
+        ports.push(("http", port));
+    }
+    if let Some(port) = get_string(clash_config, "socks-port") {
+        ports.push(("socks", port));
+    }
+    if let Some(port) = get_string(clash_config, "mixed-port") {
+        warnings.push(format!(
+            "mixed-port {} only accepts SOCKS, HTTP proxy clients need a separate port",
+            port
+        ));
+        ports.push(("socks", port));
+    }
+    for key in ["redir-port", "tproxy-port"] {
+        if let Some(port) = get_string(clash_config, key) {
+            warnings.push(format!("{} {} is not supported", key, port));
+        }
+    }
+
+    ports
+        .into_iter()
+        .map(|(protocol_type, port)| {
+            let mut protocol = vec![("type", string(protocol_type))];
+            if !users.is_empty() {
+                protocol.push(("users", Value::Sequence(users.clone())));
+            }
+            mapping(vec![
+                ("address", string(&format!("{}:{}", bind_address, port))),
+                ("protocol", mapping(protocol)),
+                ("rules", string(RULE_GROUP_NAME)),
+            ])
+        })
+        .collect()
+}
@@ -0,0 +1,307 @@
+use serde_yaml::{Mapping, Value};
+
+use crate::address::NetLocation;
+use crate::config::{
+    BindLocation, ServerConfig, ServerProxyConfig, ShadowsocksConfig, Transport,
+    WebsocketServerConfig,
+};
+
+// A client proxy config that connects to a server, as YAML.
+pub struct ExportedClientConfig {
+    pub yaml: String,
+    // Settings that couldn't be carried over, which need to be checked by hand.
+    pub notes: Vec<String>,
+}
+
+fn unsupported(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, message)
+}
+
+fn mapping(entries: Vec<(&str, Value)>) -> Value {
+    let mut mapping = Mapping::new();
+    for (key, value) in entries {
+        mapping.insert(Value::String(key.to_string()), value);
+    }
+    Value::Mapping(mapping)
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn strings<'a>(values: impl Iterator<Item = &'a String>) -> Value {
+    Value::Sequence(values.map(|s| string(s)).collect())
+}
+
+// Creates the client config for connecting to the server at the given address. The port
+// defaults to the server's port.
+pub fn export_client_config(
+    server_config: &ServerConfig,
+    address: &str,
+) -> std::io::Result<ExportedClientConfig> {
+    // Clients connect to the port of the first address the server listens on.
+    let bind_port =
+        server_config
+            .bind_locations
+            .iter()
+            .find_map(|bind_location| match bind_location {
+                BindLocation::Address(ref a) => Some(a.port()),
+                BindLocation::Path(_) => None,
+            });
+    let address = NetLocation::from_str(address, bind_port)?;
+
+    let mut notes = vec![];
+    let protocol = export_protocol(&server_config.protocol, &mut notes)?;
+
+    let mut entries = vec![
+        ("address", Value::String(address.to_string())),
+        ("protocol", protocol),
+    ];
+    // Clients use the first transport, like QUIC when it's listed before TCP.
+    let mut transports = server_config.transports.iter();
+    let transport = transports.next().unwrap();
+    for other_transport in transports {
+        notes.push(format!(
+            "the server also accepts {} transport connections",
+            format!("{:?}", other_transport).to_lowercase()
+        ));
+    }
+    match transport {
+        Transport::Tcp => (),
+        Transport::Quic => {
+            entries.push(("transport", string("quic")));
+            if let Some(ref quic_config) = server_config.quic_settings {
+                let alpn_protocols = quic_config.alpn_protocols.iter().collect::<Vec<_>>();
+                if !alpn_protocols.is_empty() {
+                    entries.push((
+                        "quic_settings",
+                        mapping(vec![(
+                            "alpn_protocols",
+                            strings(alpn_protocols.into_iter()),
+                        )]),
+                    ));
+                }
+            }
+            notes.push(
+                "the QUIC certificate is verified, set quic_settings.ca_cert or verify for \
+                 self-signed certificates"
+                    .to_string(),
+            );
+        }
+        Transport::Udp => {
+            return Err(unsupported(
+                "UDP transport servers only relay shadowsocks datagrams, and have no client"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let yaml = serde_yaml::to_string(&mapping(entries)).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Failed to write client config: {}", e),
+        )
+    })?;
+    Ok(ExportedClientConfig { yaml, notes })
+}
+
+fn export_protocol(
+    protocol: &ServerProxyConfig,
+    notes: &mut Vec<String>,
+) -> std::io::Result<Value> {
+    let value = match protocol {
+        ServerProxyConfig::Http {
+            username,
+            password,
+            users,
+        }
+        | ServerProxyConfig::Socks {
+            username,
+            password,
+            users,
+        } => {
+            let protocol_type = match protocol {
+                ServerProxyConfig::Http { .. } => "http",
+                _ => "socks",
+            };
+            let mut entries = vec![("type", string(protocol_type))];
+            // Use the main credentials, or the first additional user.
+            let credentials = match (username, password) {
+                (Some(username), Some(password)) => Some((username, password)),
+                _ => users
+                    .iter()
+                    .next()
+                    .map(|user| (&user.username, &user.password)),
+            };
+            if let Some((username, password)) = credentials {
+                entries.push(("username", string(username)));
+                entries.push(("password", string(password)));
+            }
+            mapping(entries)
+        }
+        ServerProxyConfig::Shadowsocks(shadowsocks_config) => {
+            export_shadowsocks(Some("shadowsocks"), shadowsocks_config, notes)
+        }
+        ServerProxyConfig::Snell(shadowsocks_config) => {
+            export_shadowsocks(Some("snell"), shadowsocks_config, notes)
+        }
+        ServerProxyConfig::Vless { user_id, flow, .. } => {
+            let mut entries = vec![("type", string("vless")), ("user_id", string(user_id))];
+            if let Some(flow) = flow {
+                entries.push(("flow", string(flow.name())));
+            }
+            mapping(entries)
+        }
+        ServerProxyConfig::Trojan {
+            password,
+            shadowsocks,
+            udp_over_tcp,
+            ..
+        } => {
+            let mut entries = vec![("type", string("trojan")), ("password", string(password))];
+            if let Some(shadowsocks_config) = shadowsocks {
+                // Trojan's shadowsocks settings don't have a type.
+                entries.push((
+                    "shadowsocks",
+                    export_shadowsocks(None, shadowsocks_config, notes),
+                ));
+            }
+            if *udp_over_tcp {
+                entries.push(("udp_over_tcp", Value::Bool(true)));
+            }
+            mapping(entries)
+        }
+        ServerProxyConfig::Vmess {
+            cipher, user_id, ..
+        } => mapping(vec![
+            ("type", string("vmess")),
+            ("cipher", string(cipher)),
+            ("user_id", string(user_id)),
+        ]),
+        ServerProxyConfig::Tls {
+            sni_targets,
+            default_target,
+            ..
+        } => {
+            // Only a server with a single target can be connected to without choosing one.
+            let (sni_hostname, tls_config) = match (sni_targets.len(), default_target) {
+                (0, Some(default_target)) => (None, default_target.as_ref()),
+                (1, None) => {
+                    let (sni_hostname, tls_config) = sni_targets.iter().next().unwrap();
+                    if sni_hostname.starts_with("*.") {
+                        return Err(unsupported(format!(
+                            "wildcard SNI target {} has no single hostname for clients to send",
+                            sni_hostname
+                        )));
+                    }
+                    (Some(sni_hostname), tls_config)
+                }
+                _ => {
+                    return Err(unsupported(
+                        "TLS servers that route several SNI hostnames have no single matching \
+                         client"
+                            .to_string(),
+                    ));
+                }
+            };
+            let mut entries = vec![("type", string("tls"))];
+            if let Some(sni_hostname) = sni_hostname {
+                entries.push(("sni_hostname", string(sni_hostname)));
+            }
+            let alpn_protocols = tls_config.alpn_protocols.iter().collect::<Vec<_>>();
+            if !alpn_protocols.is_empty() {
+                entries.push(("alpn_protocols", strings(alpn_protocols.into_iter())));
+            }
+            entries.push(("protocol", export_protocol(&tls_config.protocol, notes)?));
+            notes.push(
+                "the TLS certificate is verified, set ca_cert or verify for self-signed \
+                 certificates"
+                    .to_string(),
+            );
+            mapping(entries)
+        }
+        ServerProxyConfig::Websocket { targets } => {
+            let targets = targets.iter().collect::<Vec<_>>();
+            if targets.len() != 1 {
+                return Err(unsupported(
+                    "websocket servers with several targets have no single matching client"
+                        .to_string(),
+                ));
+            }
+            export_websocket(targets[0], notes)?
+        }
+        ServerProxyConfig::Grpc(grpc_config) => mapping(vec![
+            ("type", string("grpc")),
+            ("service_name", string(&grpc_config.service_name)),
+            ("protocol", export_protocol(&grpc_config.protocol, notes)?),
+        ]),
+        ServerProxyConfig::PortForward { .. } => {
+            return Err(unsupported(
+                "port forwarding servers have no matching client".to_string(),
+            ));
+        }
+    };
+    Ok(value)
+}
+
+fn export_shadowsocks(
+    protocol_type: Option<&str>,
+    shadowsocks_config: &ShadowsocksConfig,
+    notes: &mut Vec<String>,
+) -> Value {
+    let ShadowsocksConfig {
+        cipher,
+        password,
+        udp_cipher,
+        plugin,
+        udp_over_tcp,
+        ..
+    } = shadowsocks_config;
+    let mut entries = vec![];
+    if let Some(protocol_type) = protocol_type {
+        entries.push(("type", string(protocol_type)));
+    }
+    entries.push(("cipher", string(cipher)));
+    entries.push(("password", string(password)));
+    if let Some(udp_cipher) = udp_cipher {
+        entries.push(("udp_cipher", string(udp_cipher)));
+    }
+    if *udp_over_tcp {
+        entries.push(("udp_over_tcp", Value::Bool(true)));
+    }
+    if let Some(plugin) = plugin {
+        // Plugins take different options on each side, so they can't be copied.
+        notes.push(format!(
+            "the server uses the {} plugin, add plugin and plugin_opts for its client side",
+            plugin
+        ));
+    }
+    mapping(entries)
+}
+
+fn export_websocket(
+    websocket_config: &WebsocketServerConfig,
+    notes: &mut Vec<String>,
+) -> std::io::Result<Value> {
+    let mut entries = vec![("type", string("websocket"))];
+    if let Some(ref matching_path) = websocket_config.matching_path {
+        entries.push(("matching_path", string(matching_path)));
+    }
+    if let Some(ref matching_headers) = websocket_config.matching_headers {
+        let mut headers = matching_headers.iter().collect::<Vec<_>>();
+        headers.sort();
+        let mut mapping = Mapping::new();
+        for (name, value) in headers {
+            mapping.insert(string(name), string(value));
+        }
+        entries.push(("matching_headers", Value::Mapping(mapping)));
+    }
+    if websocket_config.compression.is_some() {
+        notes.push("the server accepts compression, add compression to use it".to_string());
+    }
+    entries.push((
+        "protocol",
+        export_protocol(&websocket_config.protocol, notes)?,
+    ));
+    Ok(mapping(entries))
+}
@@ -1,12 +1,15 @@
 use log::{debug, error};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use crate::address::{Address, NetLocation};
 use crate::address::{AddressMask, NetLocationMask};
+use crate::config::{BalanceMode, MaskMode, SelectKey, StickyMode};
+use crate::ip_rule_index::IpRuleIndex;
 use crate::option_util::OneOrSome;
 use crate::resolver::{resolve_single_address, Resolver};
+use crate::rule_schedule::RuleSchedule;
 
 // If a hostname is provided in a rule, it won't be resolved,
 // and the netmask field will be ignored.
@@ -21,13 +24,281 @@ const DONT_RESOLVE_RULE_HOSTNAMES: bool = true;
 
 #[derive(Debug)]
 pub struct ConnectRule<T> {
+    pub name: Option<String>,
+    // The position of the rule in its selector, set by ClientProxySelector::new.
+    index: usize,
     pub masks: Vec<NetLocationMask>,
+    pub mask_mode: MaskMode,
+    pub schedule: Option<RuleSchedule>,
     pub action: ConnectAction<T>,
+    hit_count: AtomicU64,
 }
 
 impl<T> ConnectRule<T> {
-    pub fn new(masks: Vec<NetLocationMask>, action: ConnectAction<T>) -> Self {
-        Self { masks, action }
+    pub fn new(
+        name: Option<String>,
+        masks: Vec<NetLocationMask>,
+        mask_mode: MaskMode,
+        schedule: Option<RuleSchedule>,
+        action: ConnectAction<T>,
+    ) -> Self {
+        Self {
+            name,
+            index: 0,
+            masks,
+            mask_mode,
+            schedule,
+            action,
+            hit_count: AtomicU64::new(0),
+        }
+    }
+
+    // The rule's name, or its 1-based position in the rule list if it doesn't have one.
+    pub fn label(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("rule {}", self.index + 1),
+        }
+    }
+
+    // The rule's 0-based position in its selector.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn hit_count(&self) -> u64 {
+        self.hit_count.load(Ordering::Relaxed)
+    }
+
+    // Returns the first mask that matches the location, using the same logic as
+    // ClientProxySelector::judge_with_rule. Useful to check rules one at a time.
+    pub async fn matching_mask(
+        &self,
+        location: &NetLocation,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<Option<&NetLocationMask>> {
+        let mut resolved_ip: Option<u128> = None;
+        match_masks(
+            &self.masks,
+            self.mask_mode,
+            location,
+            &mut resolved_ip,
+            resolver,
+        )
+        .await
+    }
+
+    // Whether the rule's schedule, if any, includes the current time. Rules that aren't active are
+    // skipped.
+    pub fn is_active(&self) -> bool {
+        match self.schedule {
+            Some(ref schedule) => schedule.is_active(),
+            None => true,
+        }
+    }
+
+    // Whether the rule matches every location, so that it can be used as the default rule.
+    fn matches_everything(&self) -> bool {
+        if self.schedule.is_some() || self.masks.iter().any(|mask| mask.negated) {
+            return false;
+        }
+        match self.mask_mode {
+            MaskMode::Any => self.masks.iter().any(|mask| mask.address_mask.netmask == 0),
+            MaskMode::All => self.masks.iter().all(|mask| mask.address_mask.netmask == 0),
+        }
+    }
+}
+
+impl<T> std::fmt::Display for ConnectRule<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let separator = match self.mask_mode {
+            MaskMode::Any => ", ",
+            MaskMode::All => " and ",
+        };
+        let masks = self
+            .masks
+            .iter()
+            .map(|mask| mask.to_string())
+            .collect::<Vec<_>>()
+            .join(separator);
+        match self.action {
+            ConnectAction::Allow {
+                override_address: Some(ref override_address),
+                ..
+            } => write!(f, "allow {} -> {}", masks, override_address),
+            ConnectAction::Allow { .. } => write!(f, "allow {}", masks),
+            ConnectAction::Block => write!(f, "block {}", masks),
+        }?;
+        if let ConnectAction::Allow {
+            select_by: Some(ref select_by),
+            ..
+        } = self.action
+        {
+            write!(f, " by {}", select_by.key())?;
+        }
+        match self.schedule {
+            Some(ref schedule) => write!(f, " during {}", schedule),
+            None => Ok(()),
+        }
+    }
+}
+
+// A list of client proxies and how many connections are active through each of them.
+#[derive(Debug)]
+pub struct ProxyPool<T> {
+    client_proxies: OneOrSome<T>,
+    next_proxy_index: AtomicU32,
+    // The number of active connections through each client proxy, in the same order.
+    active_connections: Vec<Arc<AtomicUsize>>,
+}
+
+impl<T> ProxyPool<T> {
+    fn new(client_proxies: OneOrSome<T>) -> Self {
+        let active_connections = (0..client_proxies.len())
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect();
+        Self {
+            client_proxies,
+            next_proxy_index: AtomicU32::new(0),
+            active_connections,
+        }
+    }
+
+    pub fn client_proxies(&self) -> &OneOrSome<T> {
+        &self.client_proxies
+    }
+
+    pub fn active_connections(&self) -> Vec<usize> {
+        self.active_connections
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    fn select(
+        &self,
+        sticky: StickyMode,
+        balance: BalanceMode,
+        source_ip: Option<IpAddr>,
+    ) -> (&T, ActiveConnection) {
+        let (client_proxy, proxy_index) = match self.client_proxies {
+            OneOrSome::One(ref item) => (item, 0),
+            OneOrSome::Some(ref v) => {
+                let i = match (sticky, source_ip, balance) {
+                    (StickyMode::SourceIp, Some(source_ip), _) => {
+                        select_sticky_proxy(v.len(), source_ip)
+                    }
+                    (_, _, BalanceMode::RoundRobin) => {
+                        select_proxy(v.len(), &self.next_proxy_index)
+                    }
+                    (_, _, BalanceMode::LeastConn) => {
+                        select_least_conn_proxy(&self.active_connections, &self.next_proxy_index)
+                    }
+                };
+                (&v[i], i)
+            }
+        };
+        (
+            client_proxy,
+            ActiveConnection::new(self.active_connections[proxy_index].clone()),
+        )
+    }
+}
+
+// Parses a select_by key. User keys are kept as they are, so None is returned for them.
+pub fn parse_select_mask(key: SelectKey, value: &str) -> std::io::Result<Option<AddressMask>> {
+    let mask = match key {
+        SelectKey::User => return Ok(None),
+        SelectKey::Sni | SelectKey::Source => AddressMask::from(value)?,
+    };
+    let is_hostname = mask.address.is_hostname();
+    if key == SelectKey::Sni && !is_hostname {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("sni key {} is not a hostname", value),
+        ));
+    }
+    if key == SelectKey::Source && is_hostname {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("source key {} is not an IP address or range", value),
+        ));
+    }
+    Ok(Some(mask))
+}
+
+// Picks the client proxies of an allow rule by an attribute of the connection.
+#[derive(Debug)]
+pub struct ProxyGroupSelector<T> {
+    key: SelectKey,
+    // Sorted so that the most specific key comes first.
+    groups: Vec<(String, Option<AddressMask>, ProxyPool<T>)>,
+}
+
+impl<T> ProxyGroupSelector<T> {
+    pub fn new(key: SelectKey, groups: Vec<(String, OneOrSome<T>)>) -> Self {
+        let mut groups = groups
+            .into_iter()
+            .map(|(value, client_proxies)| {
+                let mask = parse_select_mask(key, &value).unwrap();
+                (value, mask, ProxyPool::new(client_proxies))
+            })
+            .collect::<Vec<_>>();
+        // Longer prefixes come first, and longer hostnames are more specific since only parent
+        // domains can match the same hostname. Keys that are as specific are sorted by name.
+        groups.sort_by(|(value, mask, _), (other_value, other_mask, _)| {
+            let specificity = mask.as_ref().map(|mask| (mask.netmask, value.len()));
+            let other_specificity = other_mask
+                .as_ref()
+                .map(|mask| (mask.netmask, other_value.len()));
+            other_specificity
+                .cmp(&specificity)
+                .then_with(|| value.cmp(other_value))
+        });
+        Self { key, groups }
+    }
+
+    pub fn key(&self) -> SelectKey {
+        self.key
+    }
+
+    // The key and client proxies of each group, most specific first.
+    pub fn groups(&self) -> impl Iterator<Item = (&str, &ProxyPool<T>)> {
+        self.groups
+            .iter()
+            .map(|(value, _, pool)| (value.as_str(), pool))
+    }
+
+    fn find_group(
+        &self,
+        target_location: &NetLocation,
+        source_ip: Option<IpAddr>,
+        user: Option<&str>,
+    ) -> Option<(&str, &ProxyPool<T>)> {
+        let attribute_mask = match self.key {
+            SelectKey::User => {
+                let user = user?;
+                return self.groups().find(|(value, _)| *value == user);
+            }
+            SelectKey::Sni => AddressMask {
+                address: Address::Hostname(target_location.address().hostname()?.to_string()),
+                netmask: u128::MAX,
+            },
+            SelectKey::Source => AddressMask {
+                address: match source_ip? {
+                    IpAddr::V4(ip) => Address::Ipv4(ip),
+                    IpAddr::V6(ip) => Address::Ipv6(ip),
+                },
+                netmask: u128::MAX,
+            },
+        };
+        self.groups
+            .iter()
+            .find(|(_, mask, _)| {
+                mask.as_ref()
+                    .is_some_and(|mask| mask.contains(&attribute_mask))
+            })
+            .map(|(value, _, pool)| (value.as_str(), pool))
     }
 }
 
@@ -35,18 +306,29 @@ impl<T> ConnectRule<T> {
 pub enum ConnectAction<T> {
     Allow {
         override_address: Option<NetLocation>,
-        client_proxies: OneOrSome<T>,
-        next_proxy_index: AtomicU32,
+        // Used when there's no select_by, or none of its groups match.
+        client_proxies: ProxyPool<T>,
+        sticky: StickyMode,
+        balance: BalanceMode,
+        select_by: Option<ProxyGroupSelector<T>>,
     },
     Block,
 }
 
 impl<T> ConnectAction<T> {
-    pub fn new_allow(override_address: Option<NetLocation>, client_proxies: OneOrSome<T>) -> Self {
+    pub fn new_allow(
+        override_address: Option<NetLocation>,
+        client_proxies: OneOrSome<T>,
+        sticky: StickyMode,
+        balance: BalanceMode,
+        select_by: Option<ProxyGroupSelector<T>>,
+    ) -> Self {
         ConnectAction::Allow {
             override_address,
-            client_proxies,
-            next_proxy_index: AtomicU32::new(0),
+            client_proxies: ProxyPool::new(client_proxies),
+            sticky,
+            balance,
+            select_by,
         }
     }
 
@@ -54,32 +336,57 @@ impl<T> ConnectAction<T> {
         ConnectAction::Block
     }
 
-    pub fn to_decision(&self, target_location: NetLocation) -> ConnectDecision<T> {
+    // The number of active connections through each client proxy, or None for block actions.
+    pub fn active_connections(&self) -> Option<Vec<usize>> {
+        match self {
+            ConnectAction::Allow { client_proxies, .. } => {
+                Some(client_proxies.active_connections())
+            }
+            ConnectAction::Block => None,
+        }
+    }
+
+    // The source IP is used to pick a client proxy for sticky rules. Without it, proxies are
+    // balanced as configured. The source IP and user are also what select_by picks the proxies
+    // by, and the rule's own proxies are used when they're unknown. The chosen proxy counts as
+    // having one more active connection until the decision's ActiveConnection is dropped.
+    pub fn to_decision(
+        &self,
+        target_location: NetLocation,
+        source_ip: Option<IpAddr>,
+        user: Option<&str>,
+    ) -> ConnectDecision<'_, T> {
         match self {
             ConnectAction::Allow {
                 override_address,
                 client_proxies,
-                next_proxy_index,
+                sticky,
+                balance,
+                select_by,
             } => {
-                let client_proxy = match client_proxies {
-                    OneOrSome::One(item) => item,
-                    OneOrSome::Some(v) => select_proxy(&v, &next_proxy_index),
-                };
+                let mut pool = client_proxies;
+                if let Some(select_by) = select_by {
+                    if let Some((value, group_pool)) =
+                        select_by.find_group(&target_location, source_ip, user)
+                    {
+                        debug!(
+                            "Using the client proxies of {} {} for {}",
+                            select_by.key(),
+                            value,
+                            target_location
+                        );
+                        pool = group_pool;
+                    }
+                }
+                let (client_proxy, active_connection) = pool.select(*sticky, *balance, source_ip);
 
                 ConnectDecision::Allow {
                     client_proxy,
                     remote_location: match override_address {
-                        Some(l) => {
-                            if l.port() > 0 {
-                                l.clone()
-                            } else {
-                                // If port of 0 is specified for the replacement location,
-                                // take the requested port.
-                                NetLocation::new(l.address().clone(), target_location.port())
-                            }
-                        }
+                        Some(l) => override_location(l, target_location),
                         None => target_location,
                     },
+                    active_connection,
                 }
             }
             ConnectAction::Block => ConnectDecision::Block,
@@ -87,10 +394,30 @@ impl<T> ConnectAction<T> {
     }
 }
 
+// Only the parts of the override that are specified replace the requested location: a port of 0
+// keeps the requested port, and an unspecified host (0.0.0.0 or ::) keeps the requested host.
+fn override_location(override_address: &NetLocation, target_location: NetLocation) -> NetLocation {
+    let port = match override_address.port() {
+        0 => target_location.port(),
+        port => port,
+    };
+    if override_address.address().is_unspecified() {
+        let (address, _) = target_location.unwrap_components();
+        NetLocation::new(address, port)
+    } else {
+        NetLocation::new(override_address.address().clone(), port)
+    }
+}
+
 #[derive(Debug)]
 pub struct ClientProxySelector<T> {
     rules: Vec<ConnectRule<T>>,
+    ip_index: IpRuleIndex,
+    // The rules that aren't in ip_index, in order.
+    unindexed_rules: Vec<usize>,
     default_rule_index: Option<usize>,
+    // The number of locations that didn't match any rule, and were blocked.
+    fallthrough_count: AtomicU64,
 }
 
 unsafe impl<T: Send> Send for ClientProxySelector<T> {}
@@ -100,12 +427,37 @@ pub enum ConnectDecision<'a, T> {
     Allow {
         client_proxy: &'a T,
         remote_location: NetLocation,
+        // Should be kept until the connection through the client proxy is closed.
+        active_connection: ActiveConnection,
     },
     Block,
 }
 
+// Counts as an active connection through a client proxy until it's dropped, which is how
+// least-conn balancing knows when connections finish, including ones that fail.
+#[derive(Debug)]
+pub struct ActiveConnection {
+    count: Arc<AtomicUsize>,
+}
+
+impl ActiveConnection {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self { count }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 impl<T> ClientProxySelector<T> {
-    pub fn new(rules: Vec<ConnectRule<T>>) -> Self {
+    pub fn new(mut rules: Vec<ConnectRule<T>>) -> Self {
+        for (i, rule) in rules.iter_mut().enumerate() {
+            rule.index = i;
+        }
         let mut default_rule_index: Option<usize> = None;
         // find a default rule which we'll use for multidirectional forwarding..
         // TODO: ideally, we'd check the rule for each target during multidirectional forwarding
@@ -124,19 +476,21 @@ impl<T> ClientProxySelector<T> {
                 }
                 _ => (),
             }
-            let is_cover_rule = rule
-                .masks
-                .iter()
-                .find(|&mask| mask.address_mask.netmask == 0)
-                .is_some();
-            if is_cover_rule {
+            if rule.matches_everything() {
                 default_rule_index = Some(i);
                 break;
             }
         }
+        let ip_index = IpRuleIndex::new(&rules);
+        let unindexed_rules = (0..rules.len())
+            .filter(|i| !ip_index.is_indexed(*i))
+            .collect();
         Self {
             rules,
+            ip_index,
+            unindexed_rules,
             default_rule_index,
+            fallthrough_count: AtomicU64::new(0),
         }
     }
 
@@ -146,38 +500,150 @@ impl<T> ClientProxySelector<T> {
                 let rule = &self.rules[i];
                 // the remote location is unused because we don't choose a default rule with
                 // an override_address, so just pass a port of 0.
-                rule.action.to_decision(NetLocation::UNSPECIFIED)
+                rule.action
+                    .to_decision(NetLocation::UNSPECIFIED, None, None)
             }
             None => ConnectDecision::Block,
         }
     }
 
-    pub async fn judge<'a>(
+    pub fn rules(&self) -> &[ConnectRule<T>] {
+        &self.rules
+    }
+
+    pub fn fallthrough_count(&self) -> u64 {
+        self.fallthrough_count.load(Ordering::Relaxed)
+    }
+
+    // Rules in the IP index are matched with a single lookup, which is only done, resolving the
+    // location if needed, once the rules before the next unindexed rule include an indexed one.
+    // Unindexed rules are checked one by one in between, so the first matching rule still wins.
+    async fn match_rule(
+        &self,
+        location: &NetLocation,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<Option<&ConnectRule<T>>> {
+        // We only resolve when necessary.
+        let mut resolved_ip: Option<u128> = None;
+        let mut indexed_match: Option<Option<usize>> = None;
+
+        for &i in self
+            .unindexed_rules
+            .iter()
+            .chain(std::iter::once(&self.rules.len()))
+        {
+            if indexed_match.is_none() && self.ip_index.has_indexed_rule_before(i) {
+                let ip = resolve_location_ip(location, &mut resolved_ip, resolver)
+                    .await
+                    .map_err(|e| {
+                        std::io::Error::new(
+                            e.kind(),
+                            format!("fatal error while matching rules for {}: {}", location, e),
+                        )
+                    })?;
+                indexed_match = Some(self.ip_index.earliest_rule(ip));
+            }
+            if let Some(Some(indexed_rule)) = indexed_match {
+                if indexed_rule < i {
+                    debug!(
+                        "Found matching rule for {} in the IP index -> rule {}",
+                        location,
+                        indexed_rule + 1
+                    );
+                    return Ok(Some(&self.rules[indexed_rule]));
+                }
+            }
+
+            let rule = match self.rules.get(i) {
+                Some(rule) => rule,
+                None => break,
+            };
+            if !rule.is_active() {
+                continue;
+            }
+            if match_masks(
+                &rule.masks,
+                rule.mask_mode,
+                location,
+                &mut resolved_ip,
+                resolver,
+            )
+            .await?
+            .is_some()
+            {
+                return Ok(Some(rule));
+            }
+        }
+        Ok(None)
+    }
+
+    // Also returns the rule that was matched, if any. The source IP and the authenticated user
+    // are only used to pick the client proxy.
+    pub async fn judge_with_rule<'a>(
         &'a self,
         location: NetLocation,
+        source_ip: Option<IpAddr>,
+        user: Option<&str>,
         resolver: &Arc<dyn Resolver>,
-    ) -> std::io::Result<ConnectDecision<'a, T>> {
-        match match_rule(&self.rules, &location, resolver).await? {
-            Some(rule) => Ok(rule.action.to_decision(location)),
-            None => Ok(ConnectDecision::Block),
+    ) -> std::io::Result<(ConnectDecision<'a, T>, Option<&'a ConnectRule<T>>)> {
+        match self.match_rule(&location, resolver).await? {
+            Some(rule) => {
+                rule.hit_count.fetch_add(1, Ordering::Relaxed);
+                Ok((
+                    rule.action.to_decision(location, source_ip, user),
+                    Some(rule),
+                ))
+            }
+            None => {
+                self.fallthrough_count.fetch_add(1, Ordering::Relaxed);
+                Ok((ConnectDecision::Block, None))
+            }
         }
     }
 }
 
 #[inline]
-fn select_proxy<'a, T>(proxy_list: &'a Vec<T>, index: &'a AtomicU32) -> &'a T {
-    match proxy_list.len() {
+fn select_proxy(proxy_count: usize, index: &AtomicU32) -> usize {
+    match proxy_count {
         0 => {
             panic!("Empty proxy list");
         }
-        1 => &proxy_list[0],
-        _ => {
-            let proxy_index = index.fetch_add(1, Ordering::Relaxed) as usize;
-            &proxy_list[proxy_index % proxy_list.len()]
-        }
+        1 => 0,
+        _ => index.fetch_add(1, Ordering::Relaxed) as usize % proxy_count,
     }
 }
 
+// Picks the proxy with the fewest active connections. The search starts at the next round robin
+// position, so that proxies with the same count take turns.
+fn select_least_conn_proxy(active_connections: &[Arc<AtomicUsize>], index: &AtomicU32) -> usize {
+    let start = select_proxy(active_connections.len(), index);
+    (0..active_connections.len())
+        .map(|offset| (start + offset) % active_connections.len())
+        .min_by_key(|&i| active_connections[i].load(Ordering::Relaxed))
+        .unwrap()
+}
+
+// Picks a proxy with rendezvous hashing: each proxy gets a score from the source and its position,
+// and the highest score wins. A source keeps using the same proxy, different sources are spread
+// evenly, and if a proxy is removed only its sources move. The hash doesn't depend on the process,
+// so sources keep their proxies across restarts.
+fn select_sticky_proxy(proxy_count: usize, source_ip: IpAddr) -> usize {
+    let source = ip_to_u128(source_ip);
+    let source_hash = mix64(mix64(source as u64) ^ (source >> 64) as u64);
+    (0..proxy_count)
+        .max_by_key(|i| mix64(source_hash ^ mix64(*i as u64)))
+        .unwrap()
+}
+
+// The splitmix64 finalizer.
+#[inline]
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
 #[inline]
 fn ip_to_u128(ip: IpAddr) -> u128 {
     match ip {
@@ -212,40 +678,99 @@ fn matches_domain(base_domain: &str, hostname: &str) -> bool {
     }
 }
 
+// Negated masks are checked after the others, so that a location is only resolved for them when
+// the rest of the rule matches. Returns the first mask that isn't negated when the rule matches,
+// or the first negated mask if there are only negated masks.
 #[inline]
-async fn match_rule<'a, T>(
-    rules: &'a Vec<ConnectRule<T>>,
+async fn match_masks<'a>(
+    masks: &'a [NetLocationMask],
+    mask_mode: MaskMode,
     location: &NetLocation,
+    resolved_ip: &mut Option<u128>,
     resolver: &Arc<dyn Resolver>,
-) -> std::io::Result<Option<&'a ConnectRule<T>>> {
-    // We only resolve when necessary.
-    let mut resolved_ip: Option<u128> = None;
-
-    for rule in rules.iter() {
-        for mask in rule.masks.iter() {
-            match match_mask(mask, location, &mut resolved_ip, resolver).await {
-                Ok(is_match) => {
-                    if is_match {
-                        debug!("Found matching mask for {} -> {:?}", location, mask);
-                        return Ok(Some(rule));
-                    }
-                }
-                Err(MatchMaskError::Fatal(e)) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("fatal error while matching mask for {}: {}", location, e),
-                    ));
-                }
-                Err(MatchMaskError::NonFatal(e)) => {
-                    error!(
-                        "Non-fatal error while trying to match mask for {}: {}",
-                        location, e
-                    );
-                }
+) -> std::io::Result<Option<&'a NetLocationMask>> {
+    let mut matching_mask = None;
+    let mut has_positive_mask = false;
+    for mask in masks.iter().filter(|mask| !mask.negated) {
+        has_positive_mask = true;
+        let is_match = match_mask_or_log(mask, location, resolved_ip, resolver).await?;
+        match mask_mode {
+            MaskMode::Any if is_match => {
+                matching_mask = Some(mask);
+                break;
             }
+            MaskMode::Any => (),
+            MaskMode::All if is_match => {
+                matching_mask = matching_mask.or(Some(mask));
+            }
+            MaskMode::All => return Ok(None),
+        }
+    }
+    if has_positive_mask && matching_mask.is_none() {
+        return Ok(None);
+    }
+
+    // Hostname masks come first since they never need the location to be resolved.
+    let negated_masks = masks
+        .iter()
+        .filter(|mask| mask.negated && mask.address_mask.address.is_hostname())
+        .chain(
+            masks
+                .iter()
+                .filter(|mask| mask.negated && !mask.address_mask.address.is_hostname()),
+        );
+    for mask in negated_masks {
+        if match_mask_or_log(mask, location, resolved_ip, resolver).await? {
+            debug!("Location {} is excluded by mask {:?}", location, mask);
+            return Ok(None);
+        }
+        matching_mask = matching_mask.or(Some(mask));
+    }
+
+    if let Some(mask) = matching_mask {
+        debug!("Found matching mask for {} -> {:?}", location, mask);
+    }
+    Ok(matching_mask)
+}
+
+// Matches the mask without its negation. Errors that only affect this mask are logged and
+// treated as not matching.
+#[inline]
+async fn match_mask_or_log(
+    mask: &NetLocationMask,
+    location: &NetLocation,
+    resolved_ip: &mut Option<u128>,
+    resolver: &Arc<dyn Resolver>,
+) -> std::io::Result<bool> {
+    match match_mask(mask, location, resolved_ip, resolver).await {
+        Ok(is_match) => Ok(is_match),
+        Err(MatchMaskError::Fatal(e)) => Err(std::io::Error::other(format!(
+            "fatal error while matching mask for {}: {}",
+            location, e
+        ))),
+        Err(MatchMaskError::NonFatal(e)) => {
+            error!(
+                "Non-fatal error while trying to match mask for {}: {}",
+                location, e
+            );
+            Ok(false)
         }
     }
-    Ok(None)
+}
+
+// Resolves the location the first time it's needed, as an IPv4-mapped address for IPv4.
+async fn resolve_location_ip(
+    location: &NetLocation,
+    resolved_ip: &mut Option<u128>,
+    resolver: &Arc<dyn Resolver>,
+) -> std::io::Result<u128> {
+    if let Some(ip) = resolved_ip {
+        return Ok(*ip);
+    }
+    let socket_addr = resolve_single_address(resolver, location).await?;
+    let ip = ip_to_u128(socket_addr.ip());
+    resolved_ip.replace(ip);
+    Ok(ip)
 }
 
 enum MatchMaskError {
@@ -263,8 +788,14 @@ async fn match_mask(
     let NetLocationMask {
         address_mask: AddressMask { address, netmask },
         port,
+        domain_set,
+        ..
     } = location_mask;
 
+    if let Some(domain_set) = domain_set {
+        return Ok(domain_set.contains(location.address()));
+    }
+
     let netmask = *netmask;
     let port = *port;
 
@@ -289,18 +820,11 @@ async fn match_mask(
         }
     }
 
-    let masked_ip = match resolved_ip {
-        Some(ip) => *ip,
-        None => {
-            // fatal error if the destination we are trying to get to cannot be resolved.
-            let socket_addr = resolve_single_address(resolver, location)
-                .await
-                .map_err(MatchMaskError::Fatal)?;
-            let ip = ip_to_u128(socket_addr.ip());
-            resolved_ip.replace(ip);
-            ip
-        }
-    } & netmask;
+    // fatal error if the destination we are trying to get to cannot be resolved.
+    let masked_ip = resolve_location_ip(location, resolved_ip, resolver)
+        .await
+        .map_err(MatchMaskError::Fatal)?
+        & netmask;
 
     match address {
         Address::Ipv4(ip_addr) => {
@@ -333,3 +857,190 @@ async fn match_mask(
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+
+    // Resolves every hostname to the same IP, and counts the lookups.
+    struct StaticResolver {
+        ip: IpAddr,
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for StaticResolver {
+        fn resolve_location(
+            &self,
+            location: &NetLocation,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let addr = match location.address() {
+                Address::Ipv4(ip) => SocketAddr::new(IpAddr::V4(*ip), location.port()),
+                Address::Ipv6(ip) => SocketAddr::new(IpAddr::V6(*ip), location.port()),
+                Address::Hostname(_) => SocketAddr::new(self.ip, location.port()),
+            };
+            Box::pin(async move { Ok(vec![addr]) })
+        }
+    }
+
+    fn static_resolver(ip: &str) -> Arc<StaticResolver> {
+        Arc::new(StaticResolver {
+            ip: ip.parse().unwrap(),
+            lookups: AtomicUsize::new(0),
+        })
+    }
+
+    // Returns the matching mask as it's displayed, or None when the masks don't match.
+    async fn find_mask(
+        masks: &[&str],
+        mask_mode: MaskMode,
+        location: &str,
+        resolver: &Arc<StaticResolver>,
+    ) -> Option<String> {
+        let masks = masks
+            .iter()
+            .map(|mask| NetLocationMask::from(mask).unwrap())
+            .collect::<Vec<_>>();
+        let location = NetLocation::from_str(location, None).unwrap();
+        let resolver: Arc<dyn Resolver> = resolver.clone();
+        match_masks(&masks, mask_mode, &location, &mut None, &resolver)
+            .await
+            .unwrap()
+            .map(|mask| mask.to_string())
+    }
+
+    #[tokio::test]
+    async fn only_negated_masks_match_everything_else() {
+        let resolver = static_resolver("10.0.0.1");
+        for mask_mode in [MaskMode::Any, MaskMode::All] {
+            let masks = ["!10.0.0.0/8", "!192.168.0.0/16"];
+            assert_eq!(
+                find_mask(&masks, mask_mode, "1.1.1.1:443", &resolver).await,
+                Some("!10.0.0.0/8".to_string())
+            );
+            assert_eq!(
+                find_mask(&masks, mask_mode, "10.1.2.3:443", &resolver).await,
+                None
+            );
+            assert_eq!(
+                find_mask(&masks, mask_mode, "192.168.1.1:443", &resolver).await,
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn negated_mask_overrides_positive_mask() {
+        let resolver = static_resolver("10.0.0.1");
+        // The order of the masks doesn't matter.
+        for masks in [
+            ["192.168.0.0/16", "!192.168.1.0/24"],
+            ["!192.168.1.0/24", "192.168.0.0/16"],
+        ] {
+            assert_eq!(
+                find_mask(&masks, MaskMode::Any, "192.168.2.1:80", &resolver).await,
+                Some("192.168.0.0/16".to_string())
+            );
+            assert_eq!(
+                find_mask(&masks, MaskMode::Any, "192.168.1.5:80", &resolver).await,
+                None
+            );
+            assert_eq!(
+                find_mask(&masks, MaskMode::Any, "172.16.0.1:80", &resolver).await,
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn all_mode_with_negated_mask() {
+        let resolver = static_resolver("10.0.0.1");
+        let masks = ["192.168.0.0/16", "192.168.1.0/24", "!192.168.1.128/25"];
+        assert_eq!(
+            find_mask(&masks, MaskMode::All, "192.168.1.5:80", &resolver).await,
+            Some("192.168.0.0/16".to_string())
+        );
+        assert_eq!(
+            find_mask(&masks, MaskMode::All, "192.168.1.200:80", &resolver).await,
+            None
+        );
+        // Only matches one of the positive masks.
+        assert_eq!(
+            find_mask(&masks, MaskMode::All, "192.168.2.1:80", &resolver).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn negated_hostname_masks_are_checked_before_ip_masks() {
+        let resolver = static_resolver("10.0.0.1");
+        let masks = ["!10.0.0.0/8", "!ads.example.com"];
+
+        // Excluded by the hostname mask without resolving the location.
+        assert_eq!(
+            find_mask(
+                &masks,
+                MaskMode::Any,
+                "tracker.ads.example.com:443",
+                &resolver
+            )
+            .await,
+            None
+        );
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 0);
+
+        // Only the IP mask needs the location to be resolved.
+        assert_eq!(
+            find_mask(&masks, MaskMode::Any, "www.example.com:443", &resolver).await,
+            None
+        );
+        assert_eq!(resolver.lookups.load(Ordering::Relaxed), 1);
+
+        let resolver = static_resolver("1.1.1.1");
+        assert_eq!(
+            find_mask(&masks, MaskMode::Any, "www.example.com:443", &resolver).await,
+            Some("!ads.example.com".to_string())
+        );
+    }
+
+    fn assert_overridden(override_address: &str, target_location: &str, expected: &str) {
+        let override_address = NetLocation::from_str(override_address, None).unwrap();
+        let target_location = NetLocation::from_str(target_location, None).unwrap();
+        let expected = NetLocation::from_str(expected, None).unwrap();
+        assert_eq!(
+            override_location(&override_address, target_location),
+            expected
+        );
+    }
+
+    #[test]
+    fn override_replaces_host_and_port() {
+        assert_overridden("10.0.0.1:8080", "example.com:443", "10.0.0.1:8080");
+        assert_overridden("proxy.internal:3128", "1.1.1.1:443", "proxy.internal:3128");
+    }
+
+    #[test]
+    fn port_only_override_keeps_requested_host() {
+        assert_overridden("0.0.0.0:8443", "example.com:443", "example.com:8443");
+        assert_overridden("[::]:8443", "1.1.1.1:443", "1.1.1.1:8443");
+    }
+
+    #[test]
+    fn host_only_override_keeps_requested_port() {
+        assert_overridden("10.0.0.1:0", "example.com:443", "10.0.0.1:443");
+        assert_overridden(
+            "mirror.example.com:0",
+            "example.com:80",
+            "mirror.example.com:80",
+        );
+    }
+
+    #[test]
+    fn unspecified_override_keeps_requested_location() {
+        assert_overridden("0.0.0.0:0", "example.com:443", "example.com:443");
+        assert_overridden("[::]:0", "[2001:db8::1]:443", "[2001:db8::1]:443");
+    }
+}
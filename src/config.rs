@@ -1,15 +1,57 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::time::Duration;
 
-use serde::Deserialize;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
 
-use crate::address::{NetLocation, NetLocationMask};
+use crate::address::{Address, NetLocation, NetLocationMask};
+use crate::buffer_pool::COPY_BUFFER_SIZE;
+use crate::client_proxy_selector::parse_select_mask;
+use crate::config_decrypt::{decrypt_config, is_encrypted_config};
+use crate::config_fetch::{cache_config, fetch_config, is_config_url};
+use crate::config_schema::{
+    apply_defaults, config_file_schema, find_unknown_fields, Schema, UnknownField,
+};
+use crate::copy_multidirectional_message::DEFAULT_ASSOCIATION_TIMEOUT_SECS;
+use crate::domain_set::load_domain_set;
 use crate::option_util::{NoneOrOne, NoneOrSome, OneOrSome};
+#[cfg(target_family = "unix")]
+use crate::privilege_util::resolve_run_as;
+use crate::rule_schedule::{ScheduleTimezone, TimeRange};
+use crate::rustls_util::{create_tls_policy, load_ca_certs, parse_spki_hash};
+use crate::shadowsocks::{is_none_cipher, ShadowsocksUdpCipher};
+use crate::util::Redacted;
 
 fn default_true() -> bool {
     true
 }
 
+fn default_write_coalescing_max_bytes() -> usize {
+    8192
+}
+
+fn default_write_coalescing_max_delay_ms() -> u64 {
+    5
+}
+
+fn default_ping_interval_secs() -> u64 {
+    60
+}
+
+fn default_max_window_bits() -> u8 {
+    15
+}
+
+fn default_max_decompressed_size() -> usize {
+    1024 * 1024
+}
+
+fn default_fragment_size() -> usize {
+    100
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BindLocation {
@@ -44,20 +86,447 @@ impl Default for Transport {
 pub struct TcpConfig {
     #[serde(default = "default_true")]
     pub no_delay: bool,
+    // Servers reset connections that are closed before they're set up, instead of closing them
+    // gracefully.
+    #[serde(default)]
+    pub reset_on_failure: bool,
+    // Clients race connections to the addresses of a location, starting the next attempt when
+    // one takes too long, instead of waiting for each to fail in turn.
+    #[serde(default)]
+    pub happy_eyeballs: bool,
+    // Servers hold back small writes when relaying, so that they're sent in fewer packets.
+    #[serde(default)]
+    pub write_coalescing: Option<WriteCoalescingConfig>,
 }
 
 impl Default for TcpConfig {
     fn default() -> Self {
-        TcpConfig { no_delay: true }
+        TcpConfig {
+            no_delay: true,
+            reset_on_failure: false,
+            happy_eyeballs: false,
+            write_coalescing: None,
+        }
+    }
+}
+
+// Relayed data is only written once max_bytes are buffered, or once the first buffered byte has
+// waited max_delay_ms, so no write is delayed by more than max_delay_ms. Unlike no_delay, which
+// controls Nagle's algorithm in the kernel, the data is buffered before it's written.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct WriteCoalescingConfig {
+    #[serde(default = "default_write_coalescing_max_bytes")]
+    pub max_bytes: usize,
+    #[serde(default = "default_write_coalescing_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl WriteCoalescingConfig {
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+}
+
+fn default_mux_max_streams() -> u32 {
+    8
+}
+
+fn default_mux_idle_timeout_secs() -> u64 {
+    60
+}
+
+// Multiplexes streams over a single connection using smux framing. On a client, max_streams
+// limits the streams opened per connection before another connection is made. On a server, it
+// limits the streams accepted per connection. Mux is negotiated like sing-mux, so the other end
+// can be shoes, or sing-box with the smux protocol and padding disabled.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MuxConfig {
+    #[serde(default = "default_mux_max_streams")]
+    pub max_streams: u32,
+    // how long a connection is kept open without any streams.
+    #[serde(default = "default_mux_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+// How UDP datagrams from clients are relayed. fullcone uses a single socket per client session for
+// every destination, and delivers datagrams from any source back to the client, which some P2P
+// apps need. symmetric uses a socket per destination, and only delivers replies from that
+// destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NatType {
+    #[default]
+    #[serde(alias = "full-cone", alias = "full_cone")]
+    FullCone,
+    Symmetric,
+}
+
+fn default_udp_idle_timeout_secs() -> u64 {
+    DEFAULT_ASSOCIATION_TIMEOUT_SECS.into()
+}
+
+fn default_udp_max_sessions() -> usize {
+    4096
+}
+
+fn default_udp_batch_size() -> usize {
+    1
+}
+
+// The most datagrams that are received or sent with a single syscall.
+pub const MAX_UDP_BATCH_SIZE: usize = 64;
+
+fn default_max_auth_failures() -> u32 {
+    5
+}
+
+fn default_auth_failure_window_secs() -> u64 {
+    60
+}
+
+fn default_auth_ban_duration_secs() -> u64 {
+    600
+}
+
+// Sources with max_auth_failures failed authentications within window_secs are banned for
+// ban_duration_secs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthBanConfig {
+    #[serde(default = "default_max_auth_failures")]
+    pub max_auth_failures: u32,
+    #[serde(alias = "window", default = "default_auth_failure_window_secs")]
+    pub window_secs: u64,
+    #[serde(alias = "ban_duration", default = "default_auth_ban_duration_secs")]
+    pub ban_duration_secs: u64,
+}
+
+fn default_quota_save_interval_secs() -> u64 {
+    60
+}
+
+fn default_stats_save_interval_secs() -> u64 {
+    60
+}
+
+fn default_reaper_interval_secs() -> u64 {
+    30
+}
+
+fn default_health_upstream_timeout_secs() -> u64 {
+    5
+}
+
+fn default_telemetry_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_telemetry_service_name() -> String {
+    "shoes".to_string()
+}
+
+fn default_acme_directory_url() -> String {
+    String::from("https://acme-v02.api.letsencrypt.org/directory")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuotaConfig {
+    // where user data usage is saved, so that it's kept across restarts.
+    #[serde(default)]
+    pub state_file: Option<String>,
+    #[serde(default = "default_quota_save_interval_secs")]
+    pub save_interval_secs: u64,
+    // how many days a user's usage is counted for before it's reset. When unset, usage is only
+    // reset from the admin server.
+    #[serde(default)]
+    pub period_days: Option<u32>,
+}
+
+// Totals of the bytes relayed through each rule and client proxy, which are kept across restarts
+// for reporting, unlike the metrics server's histograms.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsConfig {
+    pub stats_file: String,
+    #[serde(default = "default_stats_save_interval_secs")]
+    pub save_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UdpConfig {
+    #[serde(default)]
+    pub nat: NatType,
+    // how long a UDP session is kept without any datagrams, before its sockets are closed.
+    #[serde(default = "default_udp_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    // the most UDP sessions a server relays at once. When there are too many, the oldest
+    // session is closed.
+    #[serde(default = "default_udp_max_sessions")]
+    pub max_sessions: usize,
+    // how many datagrams are received or sent at once with recvmmsg and sendmmsg, for full cone
+    // direct relays on Linux. Each session then keeps a 64KiB receive buffer per datagram, so
+    // this defaults to 1, which relays one datagram at a time like other platforms.
+    #[serde(default = "default_udp_batch_size")]
+    pub batch_size: usize,
+}
+
+impl UdpConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            nat: NatType::default(),
+            idle_timeout_secs: default_udp_idle_timeout_secs(),
+            max_sessions: default_udp_max_sessions(),
+            batch_size: default_udp_batch_size(),
+        }
     }
 }
 
+fn default_resolver_timeout_secs() -> u64 {
+    5
+}
+
+fn default_resolver_attempts() -> u32 {
+    2
+}
+
+// Bounds how long a hostname lookup can take, so that connections fail fast when DNS is down.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResolverConfig {
+    // how long each attempt is given.
+    #[serde(default = "default_resolver_timeout_secs")]
+    pub timeout_secs: u64,
+    // how many times a lookup is tried, when the previous attempt timed out.
+    #[serde(default = "default_resolver_attempts")]
+    pub attempts: u32,
+    // DNS over TLS servers to resolve with instead of the OS. They're tried in order, with
+    // timeout_secs applying to each server, until one answers.
+    #[serde(alias = "dot_server", default)]
+    pub dot_servers: NoneOrSome<DotServerConfig>,
+}
+
+fn deserialize_dot_address<'de, D>(deserializer: D) -> Result<NetLocation, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    deserialize_net_location(deserializer, Some(853))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DotServerConfig {
+    // the server, which defaults to port 853. A hostname is resolved by the OS.
+    #[serde(deserialize_with = "deserialize_dot_address")]
+    pub address: NetLocation,
+    // the name that the server's certificate is verified against, which defaults to the
+    // address.
+    #[serde(default)]
+    pub sni_hostname: Option<String>,
+    // a PEM file path or inline PEM with CA certificates to trust, in addition to the bundled
+    // root certificates.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    // base64 SHA-256 hashes of the server certificate's SubjectPublicKeyInfo, one of which must
+    // match. The certificate is still verified.
+    #[serde(default)]
+    pub pin_sha256: NoneOrSome<String>,
+}
+
+// A local control socket for listing live connections and rule hits, and reloading the config.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConfig {
+    #[serde(flatten)]
+    pub bind_location: BindLocation,
+}
+
+// An HTTP endpoint that serves connection metrics at /metrics, in the Prometheus text format.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    pub address: NetLocation,
+}
+
+// Exports a span for each connection to an OpenTelemetry collector, as OTLP over HTTP. Needs the
+// otel feature.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelemetryConfig {
+    // the collector's traces URL, eg. http://localhost:4318/v1/traces.
+    pub endpoint: String,
+    // the fraction of connections that are traced.
+    #[serde(default = "default_telemetry_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_telemetry_service_name")]
+    pub service_name: String,
+}
+
+// A plain HTTP endpoint for load balancer checks. /live always returns 200, and /ready returns 200
+// once a listener is accepting connections and the upstream, if any, accepts a TCP connection.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthConfig {
+    pub address: NetLocation,
+    #[serde(default)]
+    pub upstream: Option<NetLocation>,
+    #[serde(default = "default_health_upstream_timeout_secs")]
+    pub upstream_timeout_secs: u64,
+}
+
+// The user and group that the process switches to once its listeners are bound, so that it can
+// be started as root to bind privileged ports without keeping root. Connections are only accepted
+// after the switch.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RunAsConfig {
+    pub user: IdOrName,
+    // defaults to the user's primary group.
+    #[serde(default)]
+    pub group: Option<IdOrName>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum IdOrName {
+    Id(u32),
+    Name(String),
+}
+
+// The Tokio runtime that the server runs on. It's built when the process starts, so changes only
+// take effect after a restart.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RuntimeConfig {
+    #[serde(default)]
+    pub flavor: RuntimeFlavor,
+    // defaults to the number of CPUs.
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    // the maximum number of threads for blocking operations like file IO and DNS lookups.
+    #[serde(default)]
+    pub max_blocking_threads: Option<usize>,
+    #[serde(default)]
+    pub thread_name: Option<String>,
+    #[serde(default)]
+    pub thread_stack_size: Option<usize>,
+}
+
+// current_thread runs everything on the thread that started the server, which makes the order
+// of events deterministic when debugging.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeFlavor {
+    #[default]
+    #[serde(alias = "multi-thread")]
+    MultiThread,
+    #[serde(alias = "current-thread")]
+    CurrentThread,
+}
+
+// Periodically closes connections that have been open or idle for too long, for any protocol.
+// Connections are idle when no bytes have been relayed.
 #[derive(Debug, Clone, Deserialize)]
+pub struct ReaperConfig {
+    // how often the open connections are checked.
+    #[serde(default = "default_reaper_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub max_lifetime_secs: Option<u64>,
+    #[serde(default)]
+    pub max_idle_secs: Option<u64>,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct ServerQuicConfig {
     pub cert: String,
     pub key: String,
     #[serde(alias = "alpn_protocol", default)]
     pub alpn_protocols: NoneOrSome<String>,
+    #[serde(default, deserialize_with = "deserialize_tls_version")]
+    pub min_tls_version: Option<String>,
+    #[serde(alias = "cipher_suite", default)]
+    pub cipher_suites: NoneOrSome<String>,
+    // Verifies client certificates like the TLS server's client_ca and require_client_cert.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    #[serde(default)]
+    pub require_client_cert: bool,
+    // Whether UDP sessions can send their datagrams as QUIC datagrams.
+    #[serde(default = "default_true")]
+    pub udp_datagrams: bool,
+    #[serde(default)]
+    pub oversized_datagrams: OversizedDatagramPolicy,
+}
+
+impl std::fmt::Debug for ServerQuicConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerQuicConfig")
+            .field("cert", &self.cert)
+            .field("key", &Redacted)
+            .field("alpn_protocols", &self.alpn_protocols)
+            .field("min_tls_version", &self.min_tls_version)
+            .field("cipher_suites", &self.cipher_suites)
+            .field("client_ca", &self.client_ca)
+            .field("require_client_cert", &self.require_client_cert)
+            .field("udp_datagrams", &self.udp_datagrams)
+            .field("oversized_datagrams", &self.oversized_datagrams)
+            .finish()
+    }
+}
+
+// What happens to a UDP message that doesn't fit in a QUIC datagram: stream sends it on the
+// session's stream like when datagrams aren't supported, and drop drops it, like a network would
+// drop a packet that is too large.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedDatagramPolicy {
+    #[default]
+    Stream,
+    Drop,
+}
+
+// The oldest TLS version a server accepts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    // Parses a version from a config, where no version means the default.
+    pub fn from_config(value: Option<&str>) -> std::io::Result<Self> {
+        let value = match value {
+            Some(v) => v,
+            None => return Ok(TlsVersion::default()),
+        };
+        let lowercase_value = value.to_ascii_lowercase();
+        let version = lowercase_value
+            .strip_prefix("tlsv")
+            .or_else(|| lowercase_value.strip_prefix("tls"))
+            .unwrap_or(&lowercase_value);
+        match version {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid TLS version {}, expected one of: 1.2, 1.3", value),
+            )),
+        }
+    }
+}
+
+// Versions are checked when the config is validated, so that the error lists the allowed
+// versions.
+fn deserialize_tls_version<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    // Unquoted versions are parsed as numbers.
+    match serde_yaml::Value::deserialize(deserializer)? {
+        serde_yaml::Value::String(s) => Ok(Some(s)),
+        serde_yaml::Value::Number(n) => Ok(Some(n.to_string())),
+        _ => Err(serde::de::Error::invalid_type(
+            serde::de::Unexpected::Other("non-string TLS version"),
+            &"a TLS version",
+        )),
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -66,82 +535,388 @@ pub enum Config {
     ServerConfig(ServerConfig),
     ClientConfigGroup {
         client_group: String,
-        // TODO: do a topological sort and allow this to be OneOrSome<ConfigSelection>
+        // Can reference other client groups, which are expanded when the configs are loaded.
         #[serde(alias = "client_proxy")]
-        client_proxies: OneOrSome<ClientConfig>,
+        client_proxies: OneOrSome<ConfigSelection<ClientConfig>>,
     },
     RuleConfigGroup {
         rule_group: String,
+        // Can reference other rule groups, which are expanded when the configs are loaded.
         #[serde(alias = "rule")]
-        rules: OneOrSome<RuleConfig>,
+        rules: OneOrSome<ConfigSelection<RuleConfig>>,
+    },
+    Defaults {
+        defaults: ConfigDefaults,
     },
 }
 
+// Settings that are merged into every server and client config that doesn't set them, after all
+// the configs are read. A setting in a config takes precedence over the same setting here, which
+// takes precedence over the built-in default. Object settings like tcp_settings are merged field
+// by field.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConfigDefaults {
+    #[serde(default)]
+    pub server: Option<serde_yaml::Value>,
+    #[serde(default)]
+    pub client: Option<serde_yaml::Value>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig {
-    #[serde(flatten)]
-    pub bind_location: BindLocation,
+    // Used to identify the server in logs and the admin socket.
+    #[serde(default)]
+    pub name: Option<String>,
+    // The server listens on every address and path, where each can also be a list.
+    #[serde(flatten, deserialize_with = "deserialize_bind_locations")]
+    pub bind_locations: OneOrSome<BindLocation>,
     pub protocol: ServerProxyConfig,
-    #[serde(alias = "transport", default)]
-    pub transport: Transport,
+    // With both tcp and quic, the QUIC listeners share the TCP server's protocol and rules.
+    #[serde(
+        rename = "transport",
+        alias = "transports",
+        default = "default_transports"
+    )]
+    pub transports: OneOrSome<Transport>,
     #[serde(default)]
     pub tcp_settings: Option<TcpConfig>,
     #[serde(default)]
     pub quic_settings: Option<ServerQuicConfig>,
+    #[serde(default)]
+    pub mux_settings: Option<MuxConfig>,
+    #[serde(default)]
+    pub udp_settings: Option<UdpConfig>,
+    #[serde(default)]
+    pub resolver_settings: Option<ResolverConfig>,
+    #[serde(default)]
+    pub admin_settings: Option<AdminConfig>,
+    #[serde(default)]
+    pub quota_settings: Option<QuotaConfig>,
+    #[serde(default)]
+    pub stats_settings: Option<StatsConfig>,
+    #[serde(default)]
+    pub auth_ban_settings: Option<AuthBanConfig>,
+    #[serde(default)]
+    pub reaper_settings: Option<ReaperConfig>,
+    #[serde(default)]
+    pub metrics_settings: Option<MetricsConfig>,
+    #[serde(default)]
+    pub telemetry_settings: Option<TelemetryConfig>,
+    #[serde(default)]
+    pub health_settings: Option<HealthConfig>,
+    #[serde(default)]
+    pub runtime_settings: Option<RuntimeConfig>,
+    #[serde(default)]
+    pub run_as: Option<RunAsConfig>,
+    // Source addresses that are accepted, where empty means any. Denied sources take precedence.
+    // These aren't used for unix domain sockets.
+    #[serde(alias = "allow_source", default)]
+    pub allow_sources: NoneOrSome<NetLocationMask>,
+    #[serde(alias = "deny_source", default)]
+    pub deny_sources: NoneOrSome<NetLocationMask>,
+    // The DSCP class that packets sent to clients are marked with.
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    // How many connections the kernel queues before they're accepted, 1024 by default. Linux
+    // caps it at net.core.somaxconn.
+    #[serde(default)]
+    pub backlog: Option<u32>,
     #[serde(alias = "rule", default = "direct_allow_rule")]
     pub rules: NoneOrSome<ConfigSelection<RuleConfig>>,
 }
 
+fn default_transports() -> OneOrSome<Transport> {
+    OneOrSome::One(Transport::default())
+}
+
+impl ServerConfig {
+    pub fn has_transport(&self, transport: Transport) -> bool {
+        self.transports.contains(&transport)
+    }
+
+    // The server's name, or its bind location if it doesn't have one.
+    pub fn label(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => self.bind_locations.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BindLocationsConfig {
+    #[serde(default)]
+    address: NoneOrSome<NetLocation>,
+    #[serde(default)]
+    path: NoneOrSome<PathBuf>,
+}
+
+fn deserialize_bind_locations<'de, D>(deserializer: D) -> Result<OneOrSome<BindLocation>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    let BindLocationsConfig { address, path } = BindLocationsConfig::deserialize(deserializer)?;
+    let mut bind_locations = address
+        .into_iter()
+        .map(BindLocation::Address)
+        .chain(path.into_iter().map(BindLocation::Path))
+        .collect::<Vec<_>>();
+    match bind_locations.len() {
+        0 => Err(serde::de::Error::custom(
+            "server needs at least one address or path",
+        )),
+        1 => Ok(OneOrSome::One(bind_locations.pop().unwrap())),
+        _ => Ok(OneOrSome::Some(bind_locations)),
+    }
+}
+
 fn direct_allow_rule() -> NoneOrSome<ConfigSelection<RuleConfig>> {
     NoneOrSome::One(ConfigSelection::Config(RuleConfig::default()))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct ShadowsocksConfig {
     pub cipher: String,
     pub password: String,
+    // The cipher for UDP relay, which defaults to the TCP cipher.
+    #[serde(default)]
+    pub udp_cipher: Option<String>,
+    // A SIP003 plugin executable that TCP connections are tunneled through.
+    #[serde(default)]
+    pub plugin: Option<String>,
+    #[serde(default)]
+    pub plugin_opts: Option<String>,
+    // Relays UDP over the TCP connection with UDP-over-TCP v2, instead of in UDP packets.
+    #[serde(default)]
+    pub udp_over_tcp: bool,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl std::fmt::Debug for ShadowsocksConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShadowsocksConfig")
+            .field("cipher", &self.cipher)
+            .field("password", &Redacted)
+            .field("udp_cipher", &self.udp_cipher)
+            .field("plugin", &self.plugin)
+            .field("plugin_opts", &self.plugin_opts)
+            .field("udp_over_tcp", &self.udp_over_tcp)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
 pub struct TlsServerConfig {
-    pub cert: String,
-    pub key: String,
+    // PEM certificate chain and private key paths. Either these or acme must be set.
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    pub ocsp_stapling: Option<OcspStaplingConfig>,
+    // CA certificates that client certificates are verified against, as a PEM file path or
+    // inline PEM.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    // Rejects clients without a valid certificate. Otherwise, they're accepted without an
+    // identity.
+    #[serde(default)]
+    pub require_client_cert: bool,
+    // Rules for clients whose certificate has the identity, in place of the override rules.
+    #[serde(alias = "client_identity", default)]
+    pub client_identities: NoneOrSome<ClientIdentityConfig>,
     #[serde(alias = "alpn_protocol", default)]
     pub alpn_protocols: NoneOrSome<String>,
+    // What happens when a client offers none of alpn_protocols. By default, clients that offer
+    // other protocols are rejected, and clients that don't offer any are accepted without one.
+    #[serde(default)]
+    pub unknown_alpn: Option<UnknownAlpnConfig>,
+    #[serde(default, deserialize_with = "deserialize_tls_version")]
+    pub min_tls_version: Option<String>,
+    #[serde(alias = "cipher_suite", default)]
+    pub cipher_suites: NoneOrSome<String>,
+    // Replaces the inner protocol's fallback location depending on the negotiated ALPN
+    // protocol.
+    #[serde(alias = "alpn_fallback", default)]
+    pub alpn_fallbacks: HashMap<String, NetLocation>,
+    // Rules for connections depending on the negotiated ALPN protocol, in place of the override
+    // rules.
+    #[serde(default)]
+    pub alpn_override_rules: HashMap<String, NoneOrSome<ConfigSelection<RuleConfig>>>,
     pub protocol: ServerProxyConfig,
 
     #[serde(alias = "override_rule", default)]
     pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
 }
 
+// Obtains and renews a certificate from an ACME server such as Let's Encrypt, which validates
+// the domains with TLS-ALPN-01 challenges on the same listener.
 #[derive(Debug, Clone, Deserialize)]
-pub struct WebsocketServerConfig {
-    #[serde(default)]
-    pub matching_path: Option<String>,
-    #[serde(default)]
-    pub matching_headers: Option<HashMap<String, String>>,
-    pub protocol: ServerProxyConfig,
+pub struct AcmeConfig {
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    // the account contact, which the ACME server may send expiry notices to.
     #[serde(default)]
-    pub ping_type: WebsocketPingType,
+    pub email: Option<String>,
+    #[serde(alias = "domain")]
+    pub domains: OneOrSome<String>,
+    // where the account key, certificate and certificate key are kept, so that they're reused
+    // across restarts.
+    pub cache_dir: String,
+}
 
-    #[serde(alias = "override_rule", default)]
-    pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+// reject closes connections with a no_application_protocol alert, and a protocol name accepts
+// them without ALPN and handles them as if they negotiated that protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnknownAlpnConfig {
+    Reject,
+    Protocol(String),
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub enum WebsocketPingType {
-    Disabled,
-    #[serde(alias = "ping", alias = "ping-frame")]
-    PingFrame,
-    #[serde(alias = "empty", alias = "empty-frame")]
-    EmptyFrame,
+impl<'de> Deserialize<'de> for UnknownAlpnConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        Ok(match value.as_str() {
+            "reject" => UnknownAlpnConfig::Reject,
+            _ => UnknownAlpnConfig::Protocol(value),
+        })
+    }
 }
 
-impl Default for WebsocketPingType {
-    fn default() -> Self {
-        // Ping frames are better if the websocket (or a proxy) requires it to stop from timing
-        // out because it causes the remote end to write a pong, which could prevent the connection
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientIdentityConfig {
+    // the subject common name, or the first subject alternative name when there's no common name.
+    pub identity: String,
+    #[serde(alias = "override_rule", default)]
+    pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+}
+
+// Staples OCSP responses for the certificate to the handshake. The certificate is sent without
+// a response while no unexpired response is available.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OcspStaplingConfig {
+    // a DER-encoded OCSP response, which is reread when it's changed. when responses are
+    // fetched, they're also saved here.
+    #[serde(default)]
+    pub response_file: Option<String>,
+    // fetches responses from the OCSP responder in the certificate, which needs the issuer
+    // certificate in the chain.
+    #[serde(default)]
+    pub fetch: bool,
+}
+
+impl std::fmt::Debug for TlsServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsServerConfig")
+            .field("cert", &self.cert)
+            .field("key", &Redacted)
+            .field("acme", &self.acme)
+            .field("ocsp_stapling", &self.ocsp_stapling)
+            .field("client_ca", &self.client_ca)
+            .field("require_client_cert", &self.require_client_cert)
+            .field("client_identities", &self.client_identities)
+            .field("alpn_protocols", &self.alpn_protocols)
+            .field("unknown_alpn", &self.unknown_alpn)
+            .field("min_tls_version", &self.min_tls_version)
+            .field("cipher_suites", &self.cipher_suites)
+            .field("alpn_fallbacks", &self.alpn_fallbacks)
+            .field("alpn_override_rules", &self.alpn_override_rules)
+            .field("protocol", &self.protocol)
+            .field("override_rules", &self.override_rules)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebsocketServerConfig {
+    #[serde(default)]
+    pub matching_path: Option<String>,
+    #[serde(default)]
+    pub matching_headers: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub response_headers: HashMap<String, String>,
+    pub protocol: ServerProxyConfig,
+    #[serde(default)]
+    pub ping_type: WebsocketPingType,
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    #[serde(default)]
+    pub max_missed_pongs: u32,
+    #[serde(default)]
+    pub compression: Option<WebsocketCompressionConfig>,
+
+    #[serde(alias = "override_rule", default)]
+    pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+}
+
+// permessage-deflate settings. The server_* fields apply to data sent by the server and the
+// client_* fields to data sent by the client, regardless of which side is configured.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebsocketCompressionConfig {
+    #[serde(default)]
+    pub server_no_context_takeover: bool,
+    #[serde(default)]
+    pub client_no_context_takeover: bool,
+    #[serde(default = "default_max_window_bits")]
+    pub server_max_window_bits: u8,
+    #[serde(default = "default_max_window_bits")]
+    pub client_max_window_bits: u8,
+    #[serde(default = "default_max_decompressed_size")]
+    pub max_decompressed_size: usize,
+}
+
+// A gRPC service with the Tun and TunMulti calls of V2Ray and Xray, where each call carries a
+// stream of the protocol. Calls share the HTTP/2 connection, so it's usually wrapped in TLS with
+// the h2 ALPN protocol.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrpcServerConfig {
+    #[serde(default = "default_grpc_service_name")]
+    pub service_name: String,
+    pub protocol: ServerProxyConfig,
+
+    #[serde(alias = "override_rule", default)]
+    pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+}
+
+fn default_grpc_service_name() -> String {
+    "GunService".to_string()
+}
+
+// The flow control of VLESS streams, which is only supported directly inside TLS.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum VlessFlow {
+    // XTLS Vision pads the handshake of proxied TLS connections, and then copies their
+    // application data without encrypting it again.
+    #[serde(rename = "xtls-rprx-vision")]
+    XtlsRprxVision,
+}
+
+impl VlessFlow {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::XtlsRprxVision => "xtls-rprx-vision",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebsocketPingType {
+    Disabled,
+    #[serde(alias = "ping", alias = "ping-frame")]
+    PingFrame,
+    #[serde(alias = "empty", alias = "empty-frame")]
+    EmptyFrame,
+}
+
+impl Default for WebsocketPingType {
+    fn default() -> Self {
+        // Ping frames are better if the websocket (or a proxy) requires it to stop from timing
+        // out because it causes the remote end to write a pong, which could prevent the connection
         // from timing out.
         // However some clients (eg. Quantumult-X) can't handle ping frames and disconnects when
         // one is received, so empty frames can be better for compatibility.
@@ -149,40 +924,89 @@ impl Default for WebsocketPingType {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Clone, Deserialize)]
+pub struct ProxyUserConfig {
+    pub username: String,
+    pub password: String,
+    // The most bytes the user can send and receive in a quota period, after which new
+    // connections are rejected.
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    // Rules for connections from this user, in place of the server's rules.
+    #[serde(alias = "override_rule", default)]
+    pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+}
+
+impl std::fmt::Debug for ProxyUserConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyUserConfig")
+            .field("username", &self.username)
+            .field("password", &Redacted)
+            .field("quota_bytes", &self.quota_bytes)
+            .field("override_rules", &self.override_rules)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ServerProxyConfig {
     Http {
         username: Option<String>,
         password: Option<String>,
+        // Additional credentials, any of which are accepted.
+        #[serde(alias = "user", default)]
+        users: Box<NoneOrSome<ProxyUserConfig>>,
     },
     #[serde(alias = "socks5")]
     Socks {
         username: Option<String>,
         password: Option<String>,
+        // Additional credentials, any of which are accepted.
+        #[serde(alias = "user", default)]
+        users: Box<NoneOrSome<ProxyUserConfig>>,
     },
     #[serde(alias = "ss")]
     Shadowsocks(ShadowsocksConfig),
     Snell(ShadowsocksConfig),
     Vless {
         user_id: String,
+        // Where connections with an unknown user id are forwarded to.
+        #[serde(default)]
+        fallback: Option<NetLocation>,
+        // Clients have to use the flow when it's set, and can't use one otherwise.
+        #[serde(default)]
+        flow: Option<VlessFlow>,
     },
     Trojan {
         password: String,
         #[serde(default)]
         shadowsocks: Option<ShadowsocksConfig>,
+        // Where connections with a wrong password are forwarded to, so that they see a normal
+        // website.
+        #[serde(default)]
+        fallback: Option<NetLocation>,
+        // Accepts UDP associate requests and UDP-over-TCP v2 streams.
+        #[serde(default)]
+        udp_over_tcp: bool,
     },
     Tls {
         #[serde(default)]
         sni_targets: HashMap<String, TlsServerConfig>,
         #[serde(default)]
         default_target: Option<Box<TlsServerConfig>>,
+        #[serde(default)]
+        unknown_sni: UnknownSniConfig,
     },
     Vmess {
         cipher: String,
         user_id: String,
         #[serde(default = "default_true")]
         force_aead: bool,
+        // The number of alter ids derived from user_id that legacy clients can authenticate
+        // with. Only used when force_aead is false.
+        #[serde(default)]
+        alter_id: u16,
         #[serde(default = "default_true")]
         udp_enabled: bool,
     },
@@ -191,6 +1015,7 @@ pub enum ServerProxyConfig {
         #[serde(alias = "target")]
         targets: Box<OneOrSome<WebsocketServerConfig>>,
     },
+    Grpc(Box<GrpcServerConfig>),
     #[serde(alias = "forward")]
     PortForward {
         #[serde(alias = "target")]
@@ -198,6 +1023,86 @@ pub enum ServerProxyConfig {
     },
 }
 
+impl std::fmt::Debug for ServerProxyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http {
+                username,
+                password,
+                users,
+            } => f
+                .debug_struct("Http")
+                .field("username", username)
+                .field("password", &password.as_ref().map(|_| Redacted))
+                .field("users", users)
+                .finish(),
+            Self::Socks {
+                username,
+                password,
+                users,
+            } => f
+                .debug_struct("Socks")
+                .field("username", username)
+                .field("password", &password.as_ref().map(|_| Redacted))
+                .field("users", users)
+                .finish(),
+            Self::Shadowsocks(config) => f.debug_tuple("Shadowsocks").field(config).finish(),
+            Self::Snell(config) => f.debug_tuple("Snell").field(config).finish(),
+            Self::Vless { fallback, flow, .. } => f
+                .debug_struct("Vless")
+                .field("user_id", &Redacted)
+                .field("fallback", fallback)
+                .field("flow", flow)
+                .finish(),
+            Self::Trojan {
+                shadowsocks,
+                fallback,
+                udp_over_tcp,
+                ..
+            } => f
+                .debug_struct("Trojan")
+                .field("password", &Redacted)
+                .field("shadowsocks", shadowsocks)
+                .field("fallback", fallback)
+                .field("udp_over_tcp", udp_over_tcp)
+                .finish(),
+            Self::Tls {
+                sni_targets,
+                default_target,
+                unknown_sni,
+            } => f
+                .debug_struct("Tls")
+                .field("sni_targets", sni_targets)
+                .field("default_target", default_target)
+                .field("unknown_sni", unknown_sni)
+                .finish(),
+            Self::Vmess {
+                cipher,
+                force_aead,
+                alter_id,
+                udp_enabled,
+                ..
+            } => f
+                .debug_struct("Vmess")
+                .field("cipher", cipher)
+                .field("user_id", &Redacted)
+                .field("force_aead", force_aead)
+                .field("alter_id", alter_id)
+                .field("udp_enabled", udp_enabled)
+                .finish(),
+            Self::Websocket { targets } => f
+                .debug_struct("Websocket")
+                .field("targets", targets)
+                .finish(),
+            Self::Grpc(config) => f.debug_tuple("Grpc").field(config).finish(),
+            Self::PortForward { targets } => f
+                .debug_struct("PortForward")
+                .field("targets", targets)
+                .finish(),
+        }
+    }
+}
+
 impl std::fmt::Display for ServerProxyConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -213,12 +1118,26 @@ impl std::fmt::Display for ServerProxyConfig {
                 Self::Tls { .. } => "Tls",
                 Self::Vmess { .. } => "Vmess",
                 Self::Websocket { .. } => "Websocket",
+                Self::Grpc(_) => "gRPC",
                 Self::PortForward { .. } => "Portforward",
             }
         )
     }
 }
 
+// What a TLS server does with a ClientHello whose SNI hostname doesn't match any of sni_targets.
+// default uses default_target, reject closes the connection with an unrecognized_name alert, and
+// fallback forwards the raw connection to a decoy location, so that the server looks like it
+// doesn't host the hostname. A ClientHello without an SNI hostname always uses default_target.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownSniConfig {
+    #[default]
+    Default,
+    Reject,
+    Fallback(NetLocation),
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum ConfigSelection<T> {
@@ -250,7 +1169,7 @@ impl<T> ConfigSelection<T> {
         client_groups: &HashMap<String, Vec<U>>,
     ) -> std::io::Result<Vec<ConfigSelection<U>>>
     where
-        U: Clone,
+        U: Clone + GroupKind,
     {
         let mut ret = vec![];
         for selection in iter {
@@ -266,7 +1185,7 @@ impl<T> ConfigSelection<T> {
                         None => {
                             return Err(std::io::Error::new(
                                 std::io::ErrorKind::InvalidInput,
-                                format!("No such client group: {}", client_group),
+                                format!("No such {} group: {}", U::GROUP_KIND, client_group),
                             ));
                         }
                     }
@@ -281,7 +1200,7 @@ impl<T> ConfigSelection<T> {
         client_groups: &HashMap<String, Vec<T>>,
     ) -> std::io::Result<()>
     where
-        T: Clone,
+        T: Clone + GroupKind,
     {
         if selections.is_empty() {
             return Ok(());
@@ -297,7 +1216,7 @@ impl<T> ConfigSelection<T> {
         client_groups: &HashMap<String, Vec<T>>,
     ) -> std::io::Result<()>
     where
-        T: Clone,
+        T: Clone + GroupKind,
     {
         let ret = Self::replace(selections.iter(), client_groups)?;
         let _ = std::mem::replace(selections, OneOrSome::Some(ret));
@@ -305,6 +1224,115 @@ impl<T> ConfigSelection<T> {
     }
 }
 
+// The kind of group that a ConfigSelection::GroupName refers to, used in error messages.
+pub trait GroupKind {
+    const GROUP_KIND: &'static str;
+}
+
+impl GroupKind for ClientConfig {
+    const GROUP_KIND: &'static str = "client";
+}
+
+impl GroupKind for RuleConfig {
+    const GROUP_KIND: &'static str = "rule";
+}
+
+// Expands the group references in group definitions, so that every group only contains configs.
+// Groups are resolved depth first, and a group that is reached again while it's being resolved is
+// a reference cycle.
+struct GroupResolver<'a, T> {
+    definitions: &'a HashMap<String, Vec<ConfigSelection<T>>>,
+    resolved: &'a mut HashMap<String, Vec<T>>,
+    chain: Vec<String>,
+}
+
+impl<'a, T: Clone + GroupKind> GroupResolver<'a, T> {
+    fn new(
+        definitions: &'a HashMap<String, Vec<ConfigSelection<T>>>,
+        resolved: &'a mut HashMap<String, Vec<T>>,
+    ) -> Self {
+        Self {
+            definitions,
+            resolved,
+            chain: vec![],
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> std::io::Result<()> {
+        if self.resolved.contains_key(name) {
+            return Ok(());
+        }
+        if self.chain.iter().any(|n| n == name) {
+            let mut chain = self.chain.clone();
+            chain.push(name.to_string());
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} group reference cycle: {}",
+                    T::GROUP_KIND,
+                    chain.join(" -> ")
+                ),
+            ));
+        }
+        let selections = match self.definitions.get(name) {
+            Some(selections) => selections,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "No such {} group: {} (referenced by {})",
+                        T::GROUP_KIND,
+                        name,
+                        self.chain.join(" -> ")
+                    ),
+                ));
+            }
+        };
+
+        self.chain.push(name.to_string());
+        let mut configs = vec![];
+        for selection in selections.iter() {
+            match selection {
+                ConfigSelection::Config(config) => configs.push(config.clone()),
+                ConfigSelection::GroupName(group) => {
+                    self.resolve(group)?;
+                    configs.extend(self.resolved[group].iter().cloned());
+                }
+            }
+        }
+        self.chain.pop();
+
+        self.resolved.insert(name.to_string(), configs);
+        Ok(())
+    }
+}
+
+// Resolves every group definition, and returns an error for each group that couldn't be
+// resolved. Groups that were resolved are added to `resolved`.
+fn resolve_groups<T: Clone + GroupKind>(
+    definitions: &HashMap<String, Vec<ConfigSelection<T>>>,
+    resolved: &mut HashMap<String, Vec<T>>,
+) -> Vec<std::io::Error> {
+    // Sorted so that errors are reported in the same order every time.
+    let mut names: Vec<&String> = definitions.keys().collect();
+    names.sort();
+
+    let mut resolver = GroupResolver::new(definitions, resolved);
+    let mut failed = HashSet::new();
+    let mut errors = vec![];
+    for name in names {
+        // Groups in the chain of an earlier error would only report it again.
+        if failed.contains(name) {
+            continue;
+        }
+        if let Err(e) = resolver.resolve(name) {
+            failed.extend(resolver.chain.drain(..));
+            errors.push(e);
+        }
+    }
+    errors
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClientConfig {
     #[serde(default)]
@@ -318,6 +1346,63 @@ pub struct ClientConfig {
     pub tcp_settings: Option<TcpConfig>,
     #[serde(default)]
     pub quic_settings: Option<ClientQuicConfig>,
+    #[serde(default)]
+    pub mux_settings: Option<MuxConfig>,
+    #[serde(default)]
+    pub ip_preference: IpPreference,
+    // The local port that UDP sockets for relayed datagrams are bound to, instead of an
+    // ephemeral port. reuse_port allows more than one socket to bind it, eg. for concurrent
+    // sessions. Symmetric NAT sessions still bind an ephemeral port for each destination.
+    #[serde(default)]
+    pub bind_port: Option<u16>,
+    #[serde(default)]
+    pub reuse_port: bool,
+    // Where hostnames are resolved when connecting through a proxy.
+    #[serde(default)]
+    pub resolve: ResolveMode,
+    // The DSCP class that outbound packets are marked with.
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    // How the proxy's own address is resolved, when it's a hostname. Destinations are still
+    // resolved with the server's resolver, so the proxy can be found with a bootstrap resolver
+    // without it also being used for everything sent through the proxy.
+    #[serde(default)]
+    pub address_resolver_settings: Option<ResolverConfig>,
+}
+
+// Where the hostname of a destination is resolved. Rules always match against the hostname,
+// before it is resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolveMode {
+    // Resolve here, and send the address to the proxy.
+    Local,
+    // Send the hostname to the proxy, which resolves it. This avoids poisoned local DNS.
+    #[default]
+    Remote,
+}
+
+// Which resolved addresses are connected to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpPreference {
+    // Only connect to IPv4 addresses.
+    Ipv4,
+    // Only connect to IPv6 addresses.
+    Ipv6,
+    // Connect to addresses in the order that they were resolved.
+    #[default]
+    Dual,
+}
+
+impl std::fmt::Display for IpPreference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Ipv4 => write!(f, "ipv4"),
+            Self::Ipv6 => write!(f, "ipv6"),
+            Self::Dual => write!(f, "dual"),
+        }
+    }
 }
 
 fn unspecified_address() -> NetLocation {
@@ -333,6 +1418,13 @@ impl Default for ClientConfig {
             transport: Transport::default(),
             tcp_settings: None,
             quic_settings: None,
+            mux_settings: None,
+            ip_preference: IpPreference::default(),
+            bind_port: None,
+            reuse_port: false,
+            resolve: ResolveMode::default(),
+            dscp: None,
+            address_resolver_settings: None,
         }
     }
 }
@@ -342,44 +1434,67 @@ pub struct ClientQuicConfig {
     #[serde(default = "default_true")]
     pub verify: bool,
     #[serde(default)]
+    pub ca_cert: Option<String>,
+    #[serde(default = "default_true")]
+    pub system_roots: bool,
+    #[serde(default)]
     pub sni_hostname: NoneOrOne<String>,
     #[serde(alias = "alpn_protocol", default)]
     pub alpn_protocols: NoneOrSome<String>,
+    #[serde(default = "default_true")]
+    pub udp_datagrams: bool,
+    #[serde(default)]
+    pub oversized_datagrams: OversizedDatagramPolicy,
 }
 
 impl Default for ClientQuicConfig {
     fn default() -> Self {
         Self {
             verify: true,
+            ca_cert: None,
+            system_roots: true,
             sni_hostname: NoneOrOne::Unspecified,
             alpn_protocols: NoneOrSome::Unspecified,
+            udp_datagrams: true,
+            oversized_datagrams: OversizedDatagramPolicy::Stream,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ClientProxyConfig {
     Direct,
     Http {
         username: Option<String>,
         password: Option<String>,
+        // Sends the connection's trace ID in an X-Request-Id header of the CONNECT request.
+        #[serde(default)]
+        request_id_header: bool,
     },
     #[serde(alias = "socks5")]
     Socks {
         username: Option<String>,
         password: Option<String>,
+        // Relays UDP through the proxy with the UDP associate command.
+        #[serde(default)]
+        udp_associate: bool,
     },
     #[serde(alias = "ss")]
     Shadowsocks(ShadowsocksConfig),
     Snell(ShadowsocksConfig),
     Vless {
         user_id: String,
+        #[serde(default)]
+        flow: Option<VlessFlow>,
     },
     Trojan {
         password: String,
         #[serde(default)]
         shadowsocks: Option<ShadowsocksConfig>,
+        // Relays UDP over the connection with UDP-over-TCP v2.
+        #[serde(default)]
+        udp_over_tcp: bool,
     },
     Tls(TlsClientConfig),
     Vmess {
@@ -390,6 +1505,7 @@ pub enum ClientProxyConfig {
     },
     #[serde(alias = "ws")]
     Websocket(WebsocketClientConfig),
+    Grpc(GrpcClientConfig),
 }
 
 impl ClientProxyConfig {
@@ -399,34 +1515,224 @@ impl ClientProxyConfig {
             _ => false,
         }
     }
+
+    // Whether UDP is relayed over the connection to the proxy, including through TLS, websocket
+    // and gRPC layers.
+    pub fn udp_over_tcp(&self) -> bool {
+        match self {
+            ClientProxyConfig::Shadowsocks(shadowsocks_config) => shadowsocks_config.udp_over_tcp,
+            ClientProxyConfig::Trojan { udp_over_tcp, .. } => *udp_over_tcp,
+            ClientProxyConfig::Tls(TlsClientConfig { protocol, .. })
+            | ClientProxyConfig::Websocket(WebsocketClientConfig { protocol, .. })
+            | ClientProxyConfig::Grpc(GrpcClientConfig { protocol, .. }) => protocol.udp_over_tcp(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for ClientProxyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Direct => write!(f, "Direct"),
+            Self::Http {
+                username,
+                password,
+                request_id_header,
+            } => f
+                .debug_struct("Http")
+                .field("username", username)
+                .field("password", &password.as_ref().map(|_| Redacted))
+                .field("request_id_header", request_id_header)
+                .finish(),
+            Self::Socks {
+                username,
+                password,
+                udp_associate,
+            } => f
+                .debug_struct("Socks")
+                .field("username", username)
+                .field("password", &password.as_ref().map(|_| Redacted))
+                .field("udp_associate", udp_associate)
+                .finish(),
+            Self::Shadowsocks(config) => f.debug_tuple("Shadowsocks").field(config).finish(),
+            Self::Snell(config) => f.debug_tuple("Snell").field(config).finish(),
+            Self::Vless { flow, .. } => f
+                .debug_struct("Vless")
+                .field("user_id", &Redacted)
+                .field("flow", flow)
+                .finish(),
+            Self::Trojan {
+                shadowsocks,
+                udp_over_tcp,
+                ..
+            } => f
+                .debug_struct("Trojan")
+                .field("password", &Redacted)
+                .field("shadowsocks", shadowsocks)
+                .field("udp_over_tcp", udp_over_tcp)
+                .finish(),
+            Self::Tls(config) => f.debug_tuple("Tls").field(config).finish(),
+            Self::Vmess { cipher, aead, .. } => f
+                .debug_struct("Vmess")
+                .field("cipher", cipher)
+                .field("user_id", &Redacted)
+                .field("aead", aead)
+                .finish(),
+            Self::Websocket(config) => f.debug_tuple("Websocket").field(config).finish(),
+            Self::Grpc(config) => f.debug_tuple("Grpc").field(config).finish(),
+        }
+    }
+}
+
+impl std::fmt::Display for ClientProxyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Direct => write!(f, "Direct"),
+            Self::Http { .. } => write!(f, "HTTP"),
+            Self::Socks { .. } => write!(f, "SOCKS"),
+            Self::Shadowsocks(_) => write!(f, "Shadowsocks"),
+            Self::Snell(_) => write!(f, "Snell"),
+            Self::Vless { .. } => write!(f, "Vless"),
+            Self::Trojan { .. } => write!(f, "Trojan"),
+            Self::Tls(TlsClientConfig { protocol, .. }) => write!(f, "Tls -> {}", protocol),
+            Self::Vmess { .. } => write!(f, "Vmess"),
+            Self::Websocket(WebsocketClientConfig { protocol, .. }) => {
+                write!(f, "Websocket -> {}", protocol)
+            }
+            Self::Grpc(GrpcClientConfig { protocol, .. }) => write!(f, "gRPC -> {}", protocol),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TlsClientConfig {
     #[serde(default = "default_true")]
     pub verify: bool,
+    // a PEM file path or inline PEM with CA certificates to trust, in addition to the bundled
+    // root certificates unless system_roots is false.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+    #[serde(default = "default_true")]
+    pub system_roots: bool,
+    // base64 SHA-256 hashes of the server certificate's SubjectPublicKeyInfo, one of which must
+    // match. The certificate is still verified unless verify is false.
+    #[serde(default)]
+    pub pin_sha256: NoneOrSome<String>,
     #[serde(default)]
     pub sni_hostname: NoneOrOne<String>,
     #[serde(alias = "alpn_protocol", default)]
     pub alpn_protocols: NoneOrSome<String>,
+    // Splits the ClientHello into several TLS records, so that DPI which matches the SNI
+    // hostname within a single record doesn't see it.
+    #[serde(default)]
+    pub fragment: Option<TlsFragmentConfig>,
     pub protocol: Box<ClientProxyConfig>,
 }
 
+// Only the ClientHello is fragmented, the rest of the stream is written as is. Each record is
+// written separately, so it's also sent in its own TCP segment when there's a delay.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsFragmentConfig {
+    // The handshake bytes in each record. Servers must accept fragmented handshake messages,
+    // but some middleboxes drop very small records, so this isn't too small by default.
+    #[serde(default = "default_fragment_size")]
+    pub size: usize,
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct WebsocketClientConfig {
     #[serde(default)]
     pub matching_path: Option<String>,
     #[serde(default)]
     pub matching_headers: Option<HashMap<String, String>>,
+    // The Host header of the upgrade request, which can differ from the connected address and
+    // the TLS SNI hostname for domain fronting. Many CDNs reject requests where the two don't
+    // match, so this only works with those that still allow fronting.
+    #[serde(default)]
+    pub host_header: Option<String>,
     #[serde(default)]
     pub ping_type: WebsocketPingType,
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64,
+    #[serde(default)]
+    pub max_missed_pongs: u32,
+    #[serde(default)]
+    pub compression: Option<WebsocketCompressionConfig>,
+    pub protocol: Box<ClientProxyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrpcClientConfig {
+    #[serde(default = "default_grpc_service_name")]
+    pub service_name: String,
+    // Uses the TunMulti call, for servers that only accept it.
+    #[serde(default)]
+    pub multi_mode: bool,
+    // The :authority of the calls, which defaults to the TLS SNI hostname.
+    #[serde(default)]
+    pub authority: Option<String>,
     pub protocol: Box<ClientProxyConfig>,
 }
 
+// How the masks of a rule that aren't negated are combined. A location that matches any negated
+// mask never matches the rule, whatever the mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskMode {
+    // The location must match one of the masks.
+    #[default]
+    Any,
+    // The location must match all of the masks.
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleDay {
+    #[serde(alias = "monday")]
+    Mon,
+    #[serde(alias = "tuesday")]
+    Tue,
+    #[serde(alias = "wednesday")]
+    Wed,
+    #[serde(alias = "thursday")]
+    Thu,
+    #[serde(alias = "friday")]
+    Fri,
+    #[serde(alias = "saturday")]
+    Sat,
+    #[serde(alias = "sunday")]
+    Sun,
+}
+
+// Limits a rule to a time window. Outside the window, the rule is skipped and matching falls
+// through to the next rule.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleConfig {
+    // The days that the window starts on, defaulting to every day.
+    #[serde(default)]
+    pub days: NoneOrSome<ScheduleDay>,
+    // A range like 09:00-17:00, defaulting to the whole day. The range spans midnight when the
+    // end is earlier than the start.
+    #[serde(default)]
+    pub time: Option<TimeRange>,
+    #[serde(default)]
+    pub timezone: ScheduleTimezone,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct RuleConfig {
+    // Used to identify the rule in logs and the admin socket.
+    #[serde(default)]
+    pub name: Option<String>,
     #[serde(alias = "mask")]
     pub masks: OneOrSome<NetLocationMask>,
+    #[serde(default)]
+    pub mask_mode: MaskMode,
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
     #[serde(flatten)]
     pub action: RuleActionConfig,
 }
@@ -434,27 +1740,97 @@ pub struct RuleConfig {
 impl Default for RuleConfig {
     fn default() -> Self {
         Self {
+            name: None,
             masks: OneOrSome::One(NetLocationMask::ANY),
+            mask_mode: MaskMode::Any,
+            schedule: None,
             action: RuleActionConfig::Allow {
                 override_address: None,
                 client_proxies: OneOrSome::One(ConfigSelection::Config(ClientConfig::default())),
+                sticky: StickyMode::None,
+                balance: BalanceMode::RoundRobin,
+                select_by: None,
             },
         }
     }
 }
 
+// How an allow rule with several client proxies picks one for each connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum StickyMode {
+    // Proxies are used in round robin order.
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    // Connections from the same source IP always use the same proxy.
+    #[serde(rename = "source-ip", alias = "source_ip")]
+    SourceIp,
+}
+
+// How an allow rule with several client proxies spreads connections that aren't sticky.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum BalanceMode {
+    #[default]
+    #[serde(rename = "round-robin", alias = "round_robin")]
+    RoundRobin,
+    // The proxy with the fewest active connections is used, and ties are broken in round robin
+    // order.
+    #[serde(rename = "least-conn", alias = "least_conn")]
+    LeastConn,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "action", rename_all = "lowercase")]
 pub enum RuleActionConfig {
     Allow {
+        // A port of 0 keeps the requested port, and an unspecified host (0.0.0.0 or ::) keeps the
+        // requested host.
         #[serde(default, deserialize_with = "deserialize_override_address")]
         override_address: Option<NetLocation>,
         #[serde(alias = "client_proxy")]
         client_proxies: OneOrSome<ConfigSelection<ClientConfig>>,
+        #[serde(default)]
+        sticky: StickyMode,
+        #[serde(default)]
+        balance: BalanceMode,
+        // Replaces client_proxies for connections that match one of its keys.
+        #[serde(default)]
+        select_by: Option<SelectByConfig>,
     },
     Block,
 }
 
+// The attribute of a connection that an allow rule's select_by picks client proxies by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SelectKey {
+    // The authenticated user, matched exactly.
+    User,
+    // The requested hostname, which matches keys that are the same domain or a parent domain.
+    // Connections to IP addresses never match.
+    Sni,
+    // The source IP, which matches keys that are IP addresses or ranges.
+    Source,
+}
+
+impl std::fmt::Display for SelectKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectKey::User => write!(f, "user"),
+            SelectKey::Sni => write!(f, "sni"),
+            SelectKey::Source => write!(f, "source"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SelectByConfig {
+    pub key: SelectKey,
+    // The client proxies for each key, usually the name of a client group. When several sni or
+    // source keys match, the most specific one is used.
+    pub groups: HashMap<String, OneOrSome<ConfigSelection<ClientConfig>>>,
+}
+
 fn deserialize_net_location<'de, D>(
     deserializer: D,
     default_port: Option<u16>,
@@ -489,64 +1865,427 @@ where
     Ok(Some(deserialize_net_location(deserializer, Some(0))?))
 }
 
+impl<'de> serde::de::Deserialize<'de> for TimeRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        TimeRange::from(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for ScheduleTimezone {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        ScheduleTimezone::from(&value).map_err(serde::de::Error::custom)
+    }
+}
+
 impl<'de> serde::de::Deserialize<'de> for NetLocationMask {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::de::Deserializer<'de>,
     {
         let value = String::deserialize(deserializer)?;
-        let net_location_mask = NetLocationMask::from(&value).map_err(|_| {
-            serde::de::Error::invalid_value(
-                serde::de::Unexpected::Other("invalid net location mask"),
-                &"invalid net location mask",
-            )
+        let net_location_mask = NetLocationMask::from(&value).map_err(|e| {
+            serde::de::Error::custom(format!("invalid net location mask {}: {}", value, e))
         })?;
 
         Ok(net_location_mask)
     }
 }
 
-pub async fn load_configs(args: &[String]) -> std::io::Result<Vec<ServerConfig>> {
-    let mut all_configs = vec![];
-    for config_filename in args {
-        let config_bytes = match tokio::fs::read(config_filename).await {
-            Ok(b) => b,
-            Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    format!("Could not read config file {}: {}", config_filename, e),
-                ));
-            }
-        };
-
-        let config_str = match String::from_utf8(config_bytes) {
-            Ok(s) => s,
-            Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    format!(
-                        "Could not parse config file {} as UTF8: {}",
-                        config_filename, e
-                    ),
-                ));
+fn check_unknown_fields(
+    config_filename: &str,
+    schema: &Schema,
+    value: &serde_yaml::Value,
+) -> std::io::Result<()> {
+    let unknown_fields = find_unknown_fields(schema, value);
+    if unknown_fields.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "Config file {} has unknown fields:\n  {}",
+        config_filename,
+        unknown_fields
+            .iter()
+            .map(|field| field.description.as_str())
+            .collect::<Vec<_>>()
+            .join("\n  ")
+    );
+    let issues = unknown_fields
+        .into_iter()
+        .map(|UnknownField { path, description }| ConfigIssue {
+            file: Some(config_filename.to_string()),
+            path: Some(path),
+            ..ConfigIssue::new(format!(
+                "Config file {} has an {}",
+                config_filename, description
+            ))
+        })
+        .collect();
+    Err(config_file_error(message, issues))
+}
+
+fn parse_yaml_value(config_filename: &str, config_str: &str) -> std::io::Result<serde_yaml::Value> {
+    serde_yaml::from_str::<serde_yaml::Value>(config_str).map_err(|e| {
+        yaml_error(
+            config_filename,
+            format!(
+                "Could not parse config file {} as YAML: {}",
+                config_filename, e
+            ),
+            &e,
+        )
+    })
+}
+
+// A config error or warning, with where it is as far as that's known.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigIssue {
+    pub file: Option<String>,
+    // The line and column start at 1, and are only known for YAML parse errors.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    // Where in the file's YAML the issue is, like [0].protocol.cipher.
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(message: String) -> Self {
+        Self {
+            file: None,
+            line: None,
+            column: None,
+            path: None,
+            message,
+        }
+    }
+
+    fn in_config(file: &str, path: &str, message: String) -> Self {
+        Self {
+            file: Some(file.to_string()),
+            path: (!path.is_empty()).then(|| path.to_string()),
+            ..Self::new(message)
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// The inner error of a config file's io::Error, so that check_configs can report where each
+// issue is rather than only the message.
+#[derive(Debug)]
+struct ConfigFileError {
+    message: String,
+    issues: Vec<ConfigIssue>,
+}
+
+impl std::fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigFileError {}
+
+fn config_file_error(message: String, issues: Vec<ConfigIssue>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        ConfigFileError { message, issues },
+    )
+}
+
+fn yaml_error(config_filename: &str, message: String, error: &serde_yaml::Error) -> std::io::Error {
+    let location = error.location();
+    let issue = ConfigIssue {
+        file: Some(config_filename.to_string()),
+        line: location.as_ref().map(|location| location.line()),
+        column: location.as_ref().map(|location| location.column()),
+        ..ConfigIssue::new(message.clone())
+    };
+    config_file_error(message, vec![issue])
+}
+
+fn config_issues(error: std::io::Error) -> Vec<ConfigIssue> {
+    match error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<ConfigFileError>())
+    {
+        Some(config_file_error) => config_file_error.issues.clone(),
+        None => vec![ConfigIssue::new(error.to_string())],
+    }
+}
+
+// A config read from a file, along with its YAML so that defaults can be merged into it.
+struct ConfigEntry {
+    config: Config,
+    value: serde_yaml::Value,
+    // Where the config is in the file, which is empty for a file with a single server config.
+    path: String,
+}
+
+fn parse_config_str(config_filename: &str, config_str: &str) -> std::io::Result<Vec<ConfigEntry>> {
+    let value = parse_yaml_value(config_filename, config_str)?;
+    check_unknown_fields(config_filename, &config_file_schema(), &value)?;
+
+    // A file can either have a list of configs, or a single server config. The configs are
+    // parsed from the string rather than the value, so that errors have line numbers.
+    let parse_result = match value {
+        serde_yaml::Value::Sequence(values) => {
+            serde_yaml::from_str::<Vec<Config>>(config_str).map(|configs| {
+                configs
+                    .into_iter()
+                    .zip(values)
+                    .enumerate()
+                    .map(|(i, (config, value))| ConfigEntry {
+                        config,
+                        value,
+                        path: format!("[{}]", i),
+                    })
+                    .collect()
+            })
+        }
+        value => serde_yaml::from_str::<ServerConfig>(config_str).map(|server_config| {
+            vec![ConfigEntry {
+                config: Config::ServerConfig(server_config),
+                value,
+                path: String::new(),
+            }]
+        }),
+    };
+    parse_result.map_err(|e| {
+        yaml_error(
+            config_filename,
+            format!(
+                "Could not parse config file {} as config YAML: {}",
+                config_filename, e
+            ),
+            &e,
+        )
+    })
+}
+
+// Parses a file that has a single server config, rejecting unknown fields.
+pub fn parse_server_config(
+    config_filename: &str,
+    config_str: &str,
+) -> std::io::Result<ServerConfig> {
+    let value = parse_yaml_value(config_filename, config_str)?;
+    check_unknown_fields(config_filename, &Schema::Ref("ServerConfig"), &value)?;
+    serde_yaml::from_str::<ServerConfig>(config_str).map_err(|e| {
+        yaml_error(
+            config_filename,
+            format!(
+                "Could not parse config file {} as config YAML: {}",
+                config_filename, e
+            ),
+            &e,
+        )
+    })
+}
+
+// A config filename of "-" reads the config from stdin, and http:// and https:// URLs are
+// fetched.
+const STDIN_CONFIG_FILENAME: &str = "-";
+
+fn config_source_name(config_filename: &str) -> &str {
+    if config_filename == STDIN_CONFIG_FILENAME {
+        "<stdin>"
+    } else {
+        config_filename
+    }
+}
+
+fn check_stdin_used_once(args: &[String]) -> std::io::Result<()> {
+    if args
+        .iter()
+        .filter(|arg| arg.as_str() == STDIN_CONFIG_FILENAME)
+        .count()
+        > 1
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "stdin (-) can only be used as a config source once",
+        ));
+    }
+    Ok(())
+}
+
+async fn read_config_file(config_filename: &str) -> std::io::Result<Vec<ConfigEntry>> {
+    let source_name = config_source_name(config_filename);
+    let read_result = if config_filename == STDIN_CONFIG_FILENAME {
+        let mut b = vec![];
+        tokio::io::stdin().read_to_end(&mut b).await.map(|_| b)
+    } else if is_config_url(config_filename) {
+        let config_bytes = fetch_config(config_filename).await?;
+        let configs = parse_config_bytes(source_name, config_bytes.clone())?;
+        cache_config(config_filename, &config_bytes).await;
+        return Ok(configs);
+    } else {
+        tokio::fs::read(config_filename).await
+    };
+    let config_bytes = match read_result {
+        Ok(b) => b,
+        Err(e) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Could not read config file {}: {}", source_name, e),
+            ));
+        }
+    };
+
+    parse_config_bytes(source_name, config_bytes)
+}
+
+fn parse_config_bytes(
+    source_name: &str,
+    config_bytes: Vec<u8>,
+) -> std::io::Result<Vec<ConfigEntry>> {
+    // Encrypted configs are decrypted here rather than when they're read, so that fetched configs
+    // are cached encrypted.
+    let config_bytes = if is_encrypted_config(&config_bytes) {
+        decrypt_config(&config_bytes).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Could not decrypt config file {}: {}", source_name, e),
+            )
+        })?
+    } else {
+        config_bytes
+    };
+    let config_str = match String::from_utf8(config_bytes) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Could not parse config file {} as UTF8: {}", source_name, e),
+            ));
+        }
+    };
+
+    parse_config_str(source_name, &config_str)
+}
+
+// Where each client and rule group was defined, so that a duplicate group error can name both
+// sources.
+#[derive(Default)]
+struct GroupSources {
+    client_groups: HashMap<String, String>,
+    rule_groups: HashMap<String, String>,
+}
+
+fn duplicate_group_error(
+    kind: &str,
+    name: &str,
+    source_name: &str,
+    sources: &HashMap<String, String>,
+) -> String {
+    match sources.get(name) {
+        Some(existing_source) => format!(
+            "{} group already exists: {} (defined in {}, and again in {})",
+            kind, name, existing_source, source_name
+        ),
+        None => format!(
+            "{} group already exists: {} (a built-in group, redefined in {})",
+            kind, name, source_name
+        ),
+    }
+}
+
+// Merges the defaults, if any were set, into the other configs. Configs that the defaults are merged
+// into are parsed again, and an error is returned for each one that can't be.
+fn apply_config_defaults(
+    entries: Vec<(&str, ConfigEntry)>,
+) -> (Vec<(&str, String, Config)>, Vec<std::io::Error>) {
+    let mut errors = vec![];
+    let mut defaults: Option<(&str, ConfigDefaults)> = None;
+    for (source_name, entry) in entries.iter() {
+        if let Config::Defaults { defaults: ref d } = entry.config {
+            match defaults {
+                Some((existing_source, _)) => {
+                    let message = format!(
+                        "defaults can only be set once (set in {}, and again in {})",
+                        existing_source, source_name
+                    );
+                    errors.push(config_file_error(
+                        message.clone(),
+                        vec![ConfigIssue::in_config(source_name, &entry.path, message)],
+                    ));
+                }
+                None => defaults = Some((source_name, d.clone())),
             }
-        };
+        }
+    }
+
+    let defaults = match defaults {
+        Some((source_name, defaults)) => {
+            for (kind, value) in [("server", &defaults.server), ("client", &defaults.client)] {
+                if value.as_ref().is_some_and(|v| !v.is_mapping()) {
+                    let message = format!(
+                        "{} defaults in {} must be a mapping of settings",
+                        kind, source_name
+                    );
+                    errors.push(config_file_error(
+                        message.clone(),
+                        vec![ConfigIssue::in_config(source_name, "", message)],
+                    ));
+                }
+            }
+            defaults
+        }
+        None => {
+            let configs = entries
+                .into_iter()
+                .map(|(source_name, entry)| (source_name, entry.path, entry.config))
+                .collect();
+            return (configs, errors);
+        }
+    };
 
-        let mut configs = match serde_yaml::from_str::<Vec<Config>>(&config_str) {
-            Ok(c) => c,
+    let mut configs = vec![];
+    for (source_name, entry) in entries.into_iter() {
+        let mut value = match entry.config {
+            Config::Defaults { .. } => continue,
+            _ => entry.value,
+        };
+        let schema = Schema::Ref("Config");
+        if let Some(ref server_defaults) = defaults.server {
+            apply_defaults(&schema, &mut value, "ServerConfig", server_defaults);
+        }
+        if let Some(ref client_defaults) = defaults.client {
+            apply_defaults(&schema, &mut value, "ClientConfig", client_defaults);
+        }
+        match serde_yaml::from_value::<Config>(value) {
+            Ok(config) => configs.push((source_name, entry.path, config)),
             Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    format!(
-                        "Could not parse config file {} as config YAML: {}",
-                        config_filename, e
-                    ),
+                let message = format!(
+                    "Could not parse config in {} after applying defaults: {}",
+                    source_name, e
+                );
+                errors.push(config_file_error(
+                    message.clone(),
+                    vec![ConfigIssue::in_config(source_name, &entry.path, message)],
                 ));
             }
-        };
-        all_configs.append(&mut configs)
+        }
     }
+    (configs, errors)
+}
 
+fn builtin_groups() -> (
+    HashMap<String, Vec<ClientConfig>>,
+    HashMap<String, Vec<RuleConfig>>,
+) {
     let mut client_groups: HashMap<String, Vec<ClientConfig>> = HashMap::new();
     client_groups.insert(String::from("direct"), vec![ClientConfig::default()]);
 
@@ -554,146 +2293,2138 @@ pub async fn load_configs(args: &[String]) -> std::io::Result<Vec<ServerConfig>>
     rule_groups.insert(
         String::from("allow-all-direct"),
         vec![RuleConfig {
+            name: None,
             masks: OneOrSome::One(NetLocationMask::ANY),
+            mask_mode: MaskMode::Any,
+            schedule: None,
             action: RuleActionConfig::Allow {
                 override_address: None,
                 client_proxies: OneOrSome::One(ConfigSelection::Config(ClientConfig::default())),
+                sticky: StickyMode::None,
+                balance: BalanceMode::RoundRobin,
+                select_by: None,
             },
         }],
     );
     rule_groups.insert(
         String::from("block-all"),
         vec![RuleConfig {
+            name: None,
             masks: OneOrSome::One(NetLocationMask::ANY),
+            mask_mode: MaskMode::Any,
+            schedule: None,
             action: RuleActionConfig::Block,
         }],
     );
 
-    let mut server_configs: Vec<ServerConfig> = vec![];
+    (client_groups, rule_groups)
+}
+
+// Configs are read from each source in order, where "-" is stdin.
+pub async fn load_configs(args: &[String]) -> std::io::Result<Vec<ServerConfig>> {
+    check_stdin_used_once(args)?;
+
+    let mut all_entries = vec![];
+    for config_filename in args {
+        let entries = read_config_file(config_filename).await?;
+        let source_name = config_source_name(config_filename);
+        all_entries.extend(entries.into_iter().map(|entry| (source_name, entry)));
+    }
+
+    let (all_configs, errors) = apply_config_defaults(all_entries);
+    if let Some(e) = errors.into_iter().next() {
+        return Err(e);
+    }
+
+    let (mut client_groups, mut rule_groups) = builtin_groups();
+    let mut client_group_definitions = HashMap::new();
+    let mut rule_group_definitions = HashMap::new();
+    let mut group_sources = GroupSources::default();
+    let mut user_client_groups = vec![];
+    let mut user_rule_groups = vec![];
+    let mut references = GroupReferences::default();
+
+    let mut server_configs: Vec<ServerConfig> = vec![];
+
+    for (source_name, _, config) in all_configs.into_iter() {
+        match config {
+            Config::ClientConfigGroup {
+                client_group,
+                client_proxies,
+            } => {
+                if client_groups.contains_key(&client_group)
+                    || client_group_definitions.contains_key(&client_group)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        duplicate_group_error(
+                            "client",
+                            &client_group,
+                            source_name,
+                            &group_sources.client_groups,
+                        ),
+                    ));
+                }
+                references.add_client_selections(client_proxies.iter());
+                client_group_definitions.insert(client_group.clone(), client_proxies.into_vec());
+                group_sources
+                    .client_groups
+                    .insert(client_group.clone(), source_name.to_string());
+                user_client_groups.push(client_group);
+            }
+            Config::RuleConfigGroup { rule_group, rules } => {
+                if rule_groups.contains_key(&rule_group)
+                    || rule_group_definitions.contains_key(&rule_group)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        duplicate_group_error(
+                            "rule",
+                            &rule_group,
+                            source_name,
+                            &group_sources.rule_groups,
+                        ),
+                    ));
+                }
+                references.add_rule_selections(rules.iter());
+                rule_group_definitions.insert(rule_group.clone(), rules.into_vec());
+                group_sources
+                    .rule_groups
+                    .insert(rule_group.clone(), source_name.to_string());
+                user_rule_groups.push(rule_group);
+            }
+            Config::ServerConfig(server_config) => {
+                references.add_server_config(&server_config);
+                server_configs.push(server_config);
+            }
+            Config::Defaults { .. } => unreachable!("defaults were already applied"),
+        }
+    }
+
+    if let Some(e) = references
+        .mismatched_kind_errors(
+            &client_groups,
+            &client_group_definitions,
+            &rule_groups,
+            &rule_group_definitions,
+        )
+        .into_iter()
+        .next()
+    {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+    }
+
+    let mut group_errors = resolve_groups(&client_group_definitions, &mut client_groups);
+    group_errors.extend(resolve_groups(&rule_group_definitions, &mut rule_groups));
+    if let Some(e) = group_errors.into_iter().next() {
+        return Err(e);
+    }
+
+    for config in server_configs.iter_mut() {
+        validate_server_config(config, &client_groups, &rule_groups)?;
+    }
+
+    for config in server_configs.iter() {
+        for warning in find_shadowed_server_rules(config) {
+            warn!("[{}] {}", config.label(), warning);
+        }
+        for warning in find_insecure_settings(config) {
+            warn!("[{}] {}", config.label(), warning);
+        }
+        for warning in find_ignored_client_addresses(config) {
+            warn!("[{}] {}", config.label(), warning);
+        }
+    }
+
+    // Unused groups are allowed, but are often a misspelled reference.
+    for warning in references.unused_group_warnings(&user_client_groups, &user_rule_groups) {
+        warn!("{}", warning);
+    }
+
+    Ok(server_configs)
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigCheckReport {
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>,
+}
+
+// Like load_configs, but keeps going after an error so that every error can be reported, and
+// also warns about config that is probably a mistake.
+pub async fn check_configs(args: &[String]) -> ConfigCheckReport {
+    let mut report = ConfigCheckReport::default();
+
+    if let Err(e) = check_stdin_used_once(args) {
+        report.errors.extend(config_issues(e));
+        return report;
+    }
+
+    let mut all_entries = vec![];
+    for config_filename in args {
+        match read_config_file(config_filename).await {
+            Ok(entries) => {
+                let source_name = config_source_name(config_filename);
+                all_entries.extend(entries.into_iter().map(|entry| (source_name, entry)));
+            }
+            Err(e) => report.errors.extend(config_issues(e)),
+        }
+    }
+
+    let (all_configs, errors) = apply_config_defaults(all_entries);
+    report
+        .errors
+        .extend(errors.into_iter().flat_map(config_issues));
+
+    let (mut client_groups, mut rule_groups) = builtin_groups();
+    let mut client_group_definitions = HashMap::new();
+    let mut rule_group_definitions = HashMap::new();
+    let mut group_sources = GroupSources::default();
+    let mut user_client_groups = vec![];
+    let mut user_rule_groups = vec![];
+    let mut references = GroupReferences::default();
+
+    // Each server config is kept with where it was defined.
+    let mut server_configs: Vec<(&str, String, ServerConfig)> = vec![];
+
+    for (source_name, path, config) in all_configs.into_iter() {
+        match config {
+            Config::ClientConfigGroup {
+                client_group,
+                client_proxies,
+            } => {
+                if client_groups.contains_key(&client_group)
+                    || client_group_definitions.contains_key(&client_group)
+                {
+                    report.errors.push(ConfigIssue::in_config(
+                        source_name,
+                        &path,
+                        duplicate_group_error(
+                            "client",
+                            &client_group,
+                            source_name,
+                            &group_sources.client_groups,
+                        ),
+                    ));
+                    continue;
+                }
+                references.add_client_selections(client_proxies.iter());
+                client_group_definitions.insert(client_group.clone(), client_proxies.into_vec());
+                group_sources
+                    .client_groups
+                    .insert(client_group.clone(), source_name.to_string());
+                user_client_groups.push(client_group);
+            }
+            Config::RuleConfigGroup { rule_group, rules } => {
+                if rule_groups.contains_key(&rule_group)
+                    || rule_group_definitions.contains_key(&rule_group)
+                {
+                    report.errors.push(ConfigIssue::in_config(
+                        source_name,
+                        &path,
+                        duplicate_group_error(
+                            "rule",
+                            &rule_group,
+                            source_name,
+                            &group_sources.rule_groups,
+                        ),
+                    ));
+                    continue;
+                }
+                references.add_rule_selections(rules.iter());
+                rule_group_definitions.insert(rule_group.clone(), rules.into_vec());
+                group_sources
+                    .rule_groups
+                    .insert(rule_group.clone(), source_name.to_string());
+                user_rule_groups.push(rule_group);
+            }
+            Config::ServerConfig(server_config) => {
+                references.add_server_config(&server_config);
+                server_configs.push((source_name, path, server_config));
+            }
+            Config::Defaults { .. } => unreachable!("defaults were already applied"),
+        }
+    }
+
+    // A group used as the wrong kind would also be reported as missing everywhere it's used.
+    let mismatched_kind_errors = references.mismatched_kind_errors(
+        &client_groups,
+        &client_group_definitions,
+        &rule_groups,
+        &rule_group_definitions,
+    );
+    if !mismatched_kind_errors.is_empty() {
+        report
+            .errors
+            .extend(mismatched_kind_errors.into_iter().map(ConfigIssue::new));
+        return report;
+    }
+
+    for e in resolve_groups(&client_group_definitions, &mut client_groups)
+        .into_iter()
+        .chain(resolve_groups(&rule_group_definitions, &mut rule_groups))
+    {
+        report.errors.extend(config_issues(e));
+    }
+
+    for (i, (source_name, path, server_config)) in server_configs.iter_mut().enumerate() {
+        let label = match server_config.name {
+            Some(ref name) => format!(
+                "server {} ({}) at {}",
+                i + 1,
+                name,
+                server_config.bind_locations
+            ),
+            None => format!("server {} at {}", i + 1, server_config.bind_locations),
+        };
+        for e in collect_server_config_errors(server_config, &client_groups, &rule_groups) {
+            report.errors.push(ConfigIssue::in_config(
+                source_name,
+                path,
+                format!("{}: {}", label, e),
+            ));
+        }
+        for warning in find_shadowed_server_rules(server_config)
+            .into_iter()
+            .chain(find_insecure_settings(server_config))
+            .chain(find_ignored_client_addresses(server_config))
+        {
+            report.warnings.push(ConfigIssue::in_config(
+                source_name,
+                path,
+                format!("{}: {}", label, warning),
+            ));
+        }
+    }
+
+    report.warnings.extend(
+        references
+            .unused_group_warnings(&user_client_groups, &user_rule_groups)
+            .into_iter()
+            .map(ConfigIssue::new),
+    );
+
+    report
+}
+
+#[derive(Default)]
+struct GroupReferences {
+    client_groups: HashSet<String>,
+    rule_groups: HashSet<String>,
+}
+
+impl GroupReferences {
+    fn unused_group_warnings(
+        &self,
+        user_client_groups: &[String],
+        user_rule_groups: &[String],
+    ) -> Vec<String> {
+        let mut warnings = vec![];
+        for client_group in user_client_groups {
+            if !self.client_groups.contains(client_group) {
+                warnings.push(format!("client group is never used: {}", client_group));
+            }
+        }
+        for rule_group in user_rule_groups {
+            if !self.rule_groups.contains(rule_group) {
+                warnings.push(format!("rule group is never used: {}", rule_group));
+            }
+        }
+        warnings
+    }
+
+    fn add_server_config(&mut self, server_config: &ServerConfig) {
+        self.add_rule_selections(server_config.rules.iter());
+        self.add_server_proxy_config(&server_config.protocol);
+    }
+
+    fn add_server_proxy_config(&mut self, server_proxy_config: &ServerProxyConfig) {
+        match server_proxy_config {
+            ServerProxyConfig::Tls {
+                sni_targets,
+                default_target,
+                ..
+            } => {
+                for tls_server_config in sni_targets.values().chain(default_target.as_deref()) {
+                    self.add_rule_selections(tls_server_config.override_rules.iter());
+                    for client_identity in tls_server_config.client_identities.iter() {
+                        self.add_rule_selections(client_identity.override_rules.iter());
+                    }
+                    for alpn_override_rules in tls_server_config.alpn_override_rules.values() {
+                        self.add_rule_selections(alpn_override_rules.iter());
+                    }
+                    self.add_server_proxy_config(&tls_server_config.protocol);
+                }
+            }
+            ServerProxyConfig::Websocket { targets } => {
+                for websocket_server_config in targets.iter() {
+                    self.add_rule_selections(websocket_server_config.override_rules.iter());
+                    self.add_server_proxy_config(&websocket_server_config.protocol);
+                }
+            }
+            ServerProxyConfig::Grpc(grpc_server_config) => {
+                self.add_rule_selections(grpc_server_config.override_rules.iter());
+                self.add_server_proxy_config(&grpc_server_config.protocol);
+            }
+            ServerProxyConfig::Http { users, .. } | ServerProxyConfig::Socks { users, .. } => {
+                for user in users.iter() {
+                    self.add_rule_selections(user.override_rules.iter());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Returns an error for each group that is only defined as the other kind of group.
+    fn mismatched_kind_errors<T, U, V, W>(
+        &self,
+        client_groups: &HashMap<String, T>,
+        client_group_definitions: &HashMap<String, U>,
+        rule_groups: &HashMap<String, V>,
+        rule_group_definitions: &HashMap<String, W>,
+    ) -> Vec<String> {
+        let is_client_group = |name: &str| {
+            client_groups.contains_key(name) || client_group_definitions.contains_key(name)
+        };
+        let is_rule_group = |name: &str| {
+            rule_groups.contains_key(name) || rule_group_definitions.contains_key(name)
+        };
+
+        let mut errors = vec![];
+        let mut client_references: Vec<&String> = self.client_groups.iter().collect();
+        client_references.sort();
+        for name in client_references {
+            if !is_client_group(name) && is_rule_group(name) {
+                errors.push(format!(
+                    "{} is a rule group, but is used where a client group is expected",
+                    name
+                ));
+            }
+        }
+        let mut rule_references: Vec<&String> = self.rule_groups.iter().collect();
+        rule_references.sort();
+        for name in rule_references {
+            if !is_rule_group(name) && is_client_group(name) {
+                errors.push(format!(
+                    "{} is a client group, but is used where a rule group is expected",
+                    name
+                ));
+            }
+        }
+        errors
+    }
+
+    fn add_client_selections<'a>(
+        &mut self,
+        client_selections: impl Iterator<Item = &'a ConfigSelection<ClientConfig>>,
+    ) {
+        for client_selection in client_selections {
+            if let ConfigSelection::GroupName(client_group) = client_selection {
+                self.client_groups.insert(client_group.clone());
+            }
+        }
+    }
+
+    fn add_rule_selections<'a>(
+        &mut self,
+        rule_selections: impl Iterator<Item = &'a ConfigSelection<RuleConfig>>,
+    ) {
+        for rule_selection in rule_selections {
+            match rule_selection {
+                ConfigSelection::Config(rule) => self.add_rule(rule),
+                ConfigSelection::GroupName(rule_group) => {
+                    self.rule_groups.insert(rule_group.clone());
+                }
+            }
+        }
+    }
+
+    fn add_rule(&mut self, rule: &RuleConfig) {
+        if let RuleActionConfig::Allow {
+            ref client_proxies,
+            ref select_by,
+            ..
+        } = rule.action
+        {
+            self.add_client_selections(client_proxies.iter());
+            if let Some(select_by) = select_by {
+                for group_proxies in select_by.groups.values() {
+                    self.add_client_selections(group_proxies.iter());
+                }
+            }
+        }
+    }
+}
+
+// Finds shadowed rules in the server's rules and in every list of override rules.
+fn find_shadowed_server_rules(server_config: &ServerConfig) -> Vec<String> {
+    let mut warnings = vec![];
+    add_shadowed_rule_warnings(None, server_config.rules.iter(), &mut warnings);
+    add_shadowed_proxy_rule_warnings(&server_config.protocol, &mut warnings);
+    warnings
+}
+
+fn add_shadowed_proxy_rule_warnings(
+    server_proxy_config: &ServerProxyConfig,
+    warnings: &mut Vec<String>,
+) {
+    match server_proxy_config {
+        ServerProxyConfig::Tls {
+            sni_targets,
+            default_target,
+            ..
+        } => {
+            let targets = sni_targets
+                .iter()
+                .map(|(sni_hostname, tls_server_config)| {
+                    (format!("SNI target {}", sni_hostname), tls_server_config)
+                })
+                .chain(default_target.as_deref().map(|tls_server_config| {
+                    ("the default TLS target".to_string(), tls_server_config)
+                }));
+            for (target, tls_server_config) in targets {
+                add_shadowed_rule_warnings(
+                    Some(&format!("override rules for {}", target)),
+                    tls_server_config.override_rules.iter(),
+                    warnings,
+                );
+                for client_identity in tls_server_config.client_identities.iter() {
+                    add_shadowed_rule_warnings(
+                        Some(&format!(
+                            "override rules for client identity {} in {}",
+                            client_identity.identity, target
+                        )),
+                        client_identity.override_rules.iter(),
+                        warnings,
+                    );
+                }
+                let mut alpn_protocols = tls_server_config
+                    .alpn_override_rules
+                    .keys()
+                    .collect::<Vec<_>>();
+                alpn_protocols.sort();
+                for alpn_protocol in alpn_protocols {
+                    add_shadowed_rule_warnings(
+                        Some(&format!(
+                            "override rules for ALPN protocol {} in {}",
+                            alpn_protocol, target
+                        )),
+                        tls_server_config.alpn_override_rules[alpn_protocol].iter(),
+                        warnings,
+                    );
+                }
+                add_shadowed_proxy_rule_warnings(&tls_server_config.protocol, warnings);
+            }
+        }
+        ServerProxyConfig::Websocket { targets } => {
+            for (i, websocket_server_config) in targets.iter().enumerate() {
+                let target = match websocket_server_config.matching_path {
+                    Some(ref matching_path) => format!("websocket path {}", matching_path),
+                    None => format!("websocket target {}", i + 1),
+                };
+                add_shadowed_rule_warnings(
+                    Some(&format!("override rules for {}", target)),
+                    websocket_server_config.override_rules.iter(),
+                    warnings,
+                );
+                add_shadowed_proxy_rule_warnings(&websocket_server_config.protocol, warnings);
+            }
+        }
+        ServerProxyConfig::Grpc(grpc_server_config) => {
+            add_shadowed_rule_warnings(
+                Some(&format!(
+                    "override rules for gRPC service {}",
+                    grpc_server_config.service_name
+                )),
+                grpc_server_config.override_rules.iter(),
+                warnings,
+            );
+            add_shadowed_proxy_rule_warnings(&grpc_server_config.protocol, warnings);
+        }
+        ServerProxyConfig::Http { users, .. } | ServerProxyConfig::Socks { users, .. } => {
+            for user in users.iter() {
+                add_shadowed_rule_warnings(
+                    Some(&format!("override rules for user {}", user.username)),
+                    user.override_rules.iter(),
+                    warnings,
+                );
+            }
+        }
+        _ => (),
+    }
+}
+
+// Finds shadowsocks layers that use the none cipher without a plugin, TLS or QUIC around them, so
+// that their traffic isn't encrypted.
+fn find_insecure_settings(server_config: &ServerConfig) -> Vec<String> {
+    let mut warnings = vec![];
+    if server_config
+        .transports
+        .iter()
+        .any(|transport| *transport != Transport::Quic)
+        && has_plain_server_layer(&server_config.protocol)
+    {
+        warnings.push(
+            "INSECURE: shadowsocks uses the none cipher without an outer TLS layer or plugin, so connections are not encrypted".to_string(),
+        );
+    }
+
+    let mut client_addresses = vec![];
+    for client_config in server_client_configs(server_config) {
+        if client_config.transport != Transport::Quic
+            && has_plain_client_layer(&client_config.protocol)
+            && !client_addresses.contains(&&client_config.address)
+        {
+            client_addresses.push(&client_config.address);
+        }
+    }
+    for address in client_addresses {
+        warnings.push(format!(
+            "INSECURE: client proxy {} uses the shadowsocks none cipher without an outer TLS layer or plugin, so connections are not encrypted",
+            address
+        ));
+    }
+    warnings
+}
+
+// Direct clients connect to the destination, so an address set on one was probably meant for
+// another client proxy.
+fn find_ignored_client_addresses(server_config: &ServerConfig) -> Vec<String> {
+    let mut client_addresses = vec![];
+    for client_config in server_client_configs(server_config) {
+        if client_config.protocol.is_direct()
+            && !client_config.address.is_unspecified()
+            && !client_addresses.contains(&&client_config.address)
+        {
+            client_addresses.push(&client_config.address);
+        }
+    }
+    client_addresses
+        .into_iter()
+        .map(|address| {
+            format!(
+                "direct client proxy has an address ({}), which is ignored",
+                address
+            )
+        })
+        .collect()
+}
+
+// The client proxies of the server's rules, including the rules that its protocol overrides
+// them with.
+fn server_client_configs(server_config: &ServerConfig) -> Vec<&ClientConfig> {
+    let mut rule_lists = vec![&server_config.rules];
+    collect_override_rules(&server_config.protocol, &mut rule_lists);
+    let mut client_configs = vec![];
+    for rule_selection in rule_lists.into_iter().flat_map(|rules| rules.iter()) {
+        let (client_proxies, select_by) = match rule_selection {
+            ConfigSelection::Config(RuleConfig {
+                action:
+                    RuleActionConfig::Allow {
+                        client_proxies,
+                        select_by,
+                        ..
+                    },
+                ..
+            }) => (client_proxies, select_by),
+            _ => continue,
+        };
+        let group_proxies = select_by
+            .iter()
+            .flat_map(|select_by| select_by.groups.values());
+        for client_selection in std::iter::once(client_proxies)
+            .chain(group_proxies)
+            .flat_map(|client_proxies| client_proxies.iter())
+        {
+            if let ConfigSelection::Config(client_config) = client_selection {
+                client_configs.push(client_config);
+            }
+        }
+    }
+    client_configs
+}
+
+fn has_plain_server_layer(protocol: &ServerProxyConfig) -> bool {
+    match protocol {
+        ServerProxyConfig::Shadowsocks(shadowsocks_config) => {
+            is_none_cipher(&shadowsocks_config.cipher) && shadowsocks_config.plugin.is_none()
+        }
+        ServerProxyConfig::Websocket { targets } => targets
+            .iter()
+            .any(|target| has_plain_server_layer(&target.protocol)),
+        ServerProxyConfig::Grpc(grpc_server_config) => {
+            has_plain_server_layer(&grpc_server_config.protocol)
+        }
+        _ => false,
+    }
+}
+
+fn has_plain_client_layer(protocol: &ClientProxyConfig) -> bool {
+    match protocol {
+        ClientProxyConfig::Shadowsocks(shadowsocks_config) => {
+            is_none_cipher(&shadowsocks_config.cipher) && shadowsocks_config.plugin.is_none()
+        }
+        ClientProxyConfig::Websocket(websocket_config) => {
+            has_plain_client_layer(&websocket_config.protocol)
+        }
+        ClientProxyConfig::Grpc(grpc_config) => has_plain_client_layer(&grpc_config.protocol),
+        _ => false,
+    }
+}
+
+fn collect_override_rules<'a>(
+    server_proxy_config: &'a ServerProxyConfig,
+    rule_lists: &mut Vec<&'a NoneOrSome<ConfigSelection<RuleConfig>>>,
+) {
+    match server_proxy_config {
+        ServerProxyConfig::Tls {
+            sni_targets,
+            default_target,
+            ..
+        } => {
+            for tls_server_config in sni_targets.values().chain(default_target.as_deref()) {
+                rule_lists.push(&tls_server_config.override_rules);
+                for client_identity in tls_server_config.client_identities.iter() {
+                    rule_lists.push(&client_identity.override_rules);
+                }
+                rule_lists.extend(tls_server_config.alpn_override_rules.values());
+                collect_override_rules(&tls_server_config.protocol, rule_lists);
+            }
+        }
+        ServerProxyConfig::Websocket { targets } => {
+            for websocket_server_config in targets.iter() {
+                rule_lists.push(&websocket_server_config.override_rules);
+                collect_override_rules(&websocket_server_config.protocol, rule_lists);
+            }
+        }
+        ServerProxyConfig::Grpc(grpc_server_config) => {
+            rule_lists.push(&grpc_server_config.override_rules);
+            collect_override_rules(&grpc_server_config.protocol, rule_lists);
+        }
+        ServerProxyConfig::Http { users, .. } | ServerProxyConfig::Socks { users, .. } => {
+            for user in users.iter() {
+                rule_lists.push(&user.override_rules);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn add_shadowed_rule_warnings<'a>(
+    rule_list: Option<&str>,
+    rule_selections: impl Iterator<Item = &'a ConfigSelection<RuleConfig>>,
+    warnings: &mut Vec<String>,
+) {
+    // Rule groups are only replaced when they all exist.
+    let mut rules = vec![];
+    for rule_selection in rule_selections {
+        match rule_selection {
+            ConfigSelection::Config(rule) => rules.push(rule),
+            ConfigSelection::GroupName(_) => return,
+        }
+    }
+    for warning in find_shadowed_rules(&rules) {
+        match rule_list {
+            Some(rule_list) => warnings.push(format!("{}: {}", rule_list, warning)),
+            None => warnings.push(warning),
+        }
+    }
+}
+
+// Finds rules that can never match because earlier rules already match everything they do.
+fn find_shadowed_rules(rules: &[&RuleConfig]) -> Vec<String> {
+    let mut warnings = vec![];
+    // Rules with negated masks or a schedule, or that need all of their masks to match, match less
+    // than their masks do, so they're only checked for being shadowed and never shadow other rules.
+    let matches_all_masks = |rule: &RuleConfig| {
+        rule.mask_mode == MaskMode::Any
+            && rule.masks.iter().all(|mask| !mask.negated)
+            && rule.schedule.is_none()
+    };
+    for (i, rule) in rules.iter().enumerate() {
+        let mut shadowing_rules = vec![];
+        let mut masks = rule.masks.iter().filter(|mask| !mask.negated).peekable();
+        if masks.peek().is_none() {
+            continue;
+        }
+        let is_shadowed = masks.all(|mask| {
+            match rules[0..i].iter().position(|earlier_rule| {
+                matches_all_masks(earlier_rule)
+                    && earlier_rule.masks.iter().any(|m| m.contains(mask))
+            }) {
+                Some(j) => {
+                    if !shadowing_rules.contains(&(j + 1)) {
+                        shadowing_rules.push(j + 1);
+                    }
+                    true
+                }
+                None => false,
+            }
+        });
+        if is_shadowed {
+            let masks = rule
+                .masks
+                .iter()
+                .map(|mask| mask.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let shadowing_rules = shadowing_rules
+                .iter()
+                .map(|j| j.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            warnings.push(format!(
+                "rule {} ({}) is never matched, because earlier rules ({}) match all of its masks",
+                i + 1,
+                masks,
+                shadowing_rules
+            ));
+        }
+    }
+    warnings
+}
+
+pub fn update_config(config: &mut ServerConfig) -> std::io::Result<()> {
+    let client_groups = HashMap::from([("direct".to_owned(), vec![ClientConfig::default()])]);
+    let rule_groups = HashMap::new();
+    validate_server_config(config, &client_groups, &rule_groups)
+}
+
+fn validate_server_config(
+    server_config: &mut ServerConfig,
+    client_groups: &HashMap<String, Vec<ClientConfig>>,
+    rule_groups: &HashMap<String, Vec<RuleConfig>>,
+) -> std::io::Result<()> {
+    let mut errors = collect_server_config_errors(server_config, client_groups, rule_groups);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+// Validates each part of the server config separately, so that all errors can be reported at
+// once.
+fn collect_server_config_errors(
+    server_config: &mut ServerConfig,
+    client_groups: &HashMap<String, Vec<ClientConfig>>,
+    rule_groups: &HashMap<String, Vec<RuleConfig>>,
+) -> Vec<std::io::Error> {
+    let mut errors = vec![];
+
+    if let Err(e) = validate_server_transport(server_config) {
+        errors.push(e);
+    }
+
+    if let Err(e) = validate_dscp(server_config.dscp) {
+        errors.push(e);
+    }
+    if server_config.dscp.is_some() && has_path_bind_location(server_config) {
+        errors.push(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "dscp is not supported for unix domain sockets",
+        ));
+    }
+
+    if let Err(e) = validate_backlog(server_config) {
+        errors.push(e);
+    }
+
+    if let Some(ref admin_config) = server_config.admin_settings {
+        if let Err(e) = validate_admin_config(admin_config) {
+            errors.push(e);
+        }
+    }
+
+    match ConfigSelection::replace_none_or_some_groups(&mut server_config.rules, rule_groups) {
+        Ok(()) => {
+            if server_config.rules.is_empty() {
+                server_config.rules = direct_allow_rule();
+            }
+            for rule_config_selection in server_config.rules.iter_mut() {
+                if let Err(e) =
+                    validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)
+                {
+                    errors.push(e);
+                }
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+
+    if let Err(e) =
+        validate_server_proxy_config(&mut server_config.protocol, client_groups, rule_groups)
+    {
+        errors.push(e);
+    }
+
+    errors
+}
+
+fn validate_dscp(dscp: Option<u8>) -> std::io::Result<()> {
+    if let Some(dscp) = dscp {
+        if dscp > 63 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid dscp {}, must be between 0 and 63", dscp),
+            ));
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )))]
+    if dscp.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "dscp is not supported on this platform",
+        ));
+    }
+
+    Ok(())
+}
+
+// The largest backlog that's accepted, which is the most that Linux allows with somaxconn.
+const MAX_BACKLOG: u32 = 65535;
+
+fn validate_backlog(server_config: &ServerConfig) -> std::io::Result<()> {
+    let backlog = match server_config.backlog {
+        Some(backlog) => backlog,
+        None => return Ok(()),
+    };
+    if backlog == 0 || backlog > MAX_BACKLOG {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid backlog {}, must be between 1 and {}",
+                backlog, MAX_BACKLOG
+            ),
+        ));
+    }
+    if !server_config.has_transport(Transport::Tcp) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "backlog is only supported for TCP transport",
+        ));
+    }
+    if let ServerProxyConfig::Shadowsocks(ShadowsocksConfig {
+        plugin: Some(_), ..
+    }) = server_config.protocol
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "backlog is not supported with Shadowsocks plugins, which own the listener",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_server_transport(server_config: &ServerConfig) -> std::io::Result<()> {
+    let transports = server_config.transports.iter().collect::<Vec<_>>();
+    for (i, transport) in transports.iter().enumerate() {
+        if transports[..i].contains(transport) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} transport is listed more than once",
+                    format!("{:?}", transport).to_lowercase()
+                ),
+            ));
+        }
+    }
+    // Only the TCP server can share its protocol and rules with other listeners.
+    if transports.len() > 1 && server_config.has_transport(Transport::Udp) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "UDP transport can't be combined with other transports",
+        ));
+    }
+
+    if !server_config.has_transport(Transport::Tcp) && server_config.tcp_settings.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "TCP transport is not selected but TCP settings specified",
+        ));
+    }
+
+    if let Some(ref tcp_config) = server_config.tcp_settings {
+        if tcp_config.happy_eyeballs {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "happy_eyeballs is only supported in client TCP settings",
+            ));
+        }
+        if let Some(ref write_coalescing) = tcp_config.write_coalescing {
+            validate_write_coalescing_config(write_coalescing)?;
+        }
+    }
+
+    if server_config.has_transport(Transport::Quic) {
+        match server_config.quic_settings {
+            Some(ref quic_config) => {
+                // QUIC only supports TLS 1.3, so the minimum version is only checked.
+                TlsVersion::from_config(quic_config.min_tls_version.as_deref())?;
+                let cipher_suites = quic_config.cipher_suites.clone().into_vec();
+                create_tls_policy(TlsVersion::Tls13, &cipher_suites)?;
+                validate_client_auth(
+                    quic_config.client_ca.as_deref(),
+                    quic_config.require_client_cert,
+                )?;
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "QUIC transport is selected but QUIC settings not specified",
+                ));
+            }
+        }
+    } else {
+        if server_config.quic_settings.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "QUIC transport is not selected but QUIC settings specified",
+            ));
+        }
+    }
+
+    // Each QUIC stream is already separate, so there's no HTTP/2 connection to carry the calls.
+    if server_config.has_transport(Transport::Quic) {
+        if let ServerProxyConfig::Grpc(_) = server_config.protocol {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "gRPC is not supported for QUIC transport",
+            ));
+        }
+    }
+
+    validate_server_plugin(&server_config.protocol, true)?;
+    validate_server_vless_flow(&server_config.protocol, false)?;
+    if let ServerProxyConfig::Shadowsocks(ShadowsocksConfig {
+        plugin: Some(_), ..
+    }) = server_config.protocol
+    {
+        if transports != [&Transport::Tcp] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Shadowsocks plugins are only supported for TCP transport",
+            ));
+        }
+        if has_path_bind_location(server_config) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Shadowsocks plugins can't listen on unix domain sockets",
+            ));
+        }
+        if server_config.dscp.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "dscp is not supported with Shadowsocks plugins, which own the listener",
+            ));
+        }
+    }
+
+    if server_config.has_transport(Transport::Udp) {
+        match server_config.protocol {
+            ServerProxyConfig::Shadowsocks(ref shadowsocks_config) => {
+                ShadowsocksUdpCipher::from_config(shadowsocks_config)?;
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "UDP transport is only supported for shadowsocks",
+                ));
+            }
+        }
+    }
+
+    if let Some(ref mux_config) = server_config.mux_settings {
+        if !server_config.has_transport(Transport::Tcp) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Mux settings are only supported for TCP transport",
+            ));
+        }
+        validate_mux_config(mux_config)?;
+    }
+
+    if let Some(ref udp_config) = server_config.udp_settings {
+        validate_udp_config(udp_config)?;
+    }
+
+    if let Some(ref resolver_config) = server_config.resolver_settings {
+        validate_resolver_config(resolver_config)?;
+    }
+
+    if let Some(ref quota_config) = server_config.quota_settings {
+        validate_quota_config(quota_config)?;
+    }
+
+    if let Some(ref stats_config) = server_config.stats_settings {
+        validate_stats_config(stats_config)?;
+    }
+
+    if let Some(ref auth_ban_config) = server_config.auth_ban_settings {
+        validate_auth_ban_config(auth_ban_config)?;
+    }
+
+    if let Some(ref reaper_config) = server_config.reaper_settings {
+        validate_reaper_config(reaper_config)?;
+    }
+
+    if let Some(ref runtime_config) = server_config.runtime_settings {
+        validate_runtime_config(runtime_config)?;
+    }
+
+    if let Some(ref telemetry_config) = server_config.telemetry_settings {
+        validate_telemetry_config(telemetry_config)?;
+    }
+
+    if let Some(ref health_config) = server_config.health_settings {
+        for bind_location in server_config.bind_locations.iter() {
+            validate_health_config(health_config, bind_location)?;
+        }
+    }
+
+    if let Some(ref _run_as) = server_config.run_as {
+        #[cfg(target_family = "unix")]
+        resolve_run_as(_run_as)?;
+        #[cfg(not(target_family = "unix"))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "run_as is only supported on unix",
+        ));
+    }
+
+    for source_mask in server_config
+        .allow_sources
+        .iter()
+        .chain(server_config.deny_sources.iter())
+    {
+        validate_source_mask(source_mask)?;
+    }
+
+    if has_path_bind_location(server_config) && transports != [&Transport::Tcp] {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Unix domain socket support only available for TCP transport",
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_client_config(client_config: &mut ClientConfig) -> std::io::Result<()> {
+    // The address defaults to unspecified, which is only valid for direct clients.
+    if !client_config.protocol.is_direct() && client_config.address.is_unspecified() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} client proxy is missing its address",
+                client_config.protocol
+            ),
+        ));
+    }
+
+    if client_config.transport != Transport::Tcp && client_config.tcp_settings.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "TCP transport is not selected but TCP settings specified",
+        ));
+    }
+
+    if let Some(ref tcp_config) = client_config.tcp_settings {
+        if tcp_config.reset_on_failure {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "reset_on_failure is only supported in server TCP settings",
+            ));
+        }
+        if tcp_config.write_coalescing.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "write_coalescing is only supported in server TCP settings",
+            ));
+        }
+    }
+
+    if client_config.transport != Transport::Quic && client_config.quic_settings.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "QUIC transport is not selected but QUIC settings specified",
+        ));
+    }
+
+    if let Some(ref quic_config) = client_config.quic_settings {
+        validate_client_roots(
+            quic_config.verify,
+            &quic_config.ca_cert,
+            quic_config.system_roots,
+        )?;
+    }
+
+    if let Some(ref mux_config) = client_config.mux_settings {
+        if client_config.protocol.is_direct() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Mux settings specified for a direct client",
+            ));
+        }
+        validate_mux_config(mux_config)?;
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    if client_config.bind_interface.is_one() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "bind_interface is only available on Android, Fuchsia, or Linux.",
+        ));
+    }
+
+    if client_config.bind_port == Some(0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "bind_port must be greater than 0",
+        ));
+    }
+    if client_config.reuse_port && client_config.bind_port.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "reuse_port requires bind_port",
+        ));
+    }
+
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    if client_config.reuse_port {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "reuse_port is not supported on this platform",
+        ));
+    }
+
+    validate_dscp(client_config.dscp)?;
+
+    if let Some(ref resolver_config) = client_config.address_resolver_settings {
+        if client_config.protocol.is_direct() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Address resolver settings specified for a direct client",
+            ));
+        }
+        validate_resolver_config(resolver_config)?;
+    }
+
+    validate_client_proxy_config(&client_config.protocol)?;
+    validate_client_plugin(&client_config.protocol, true)?;
+    validate_client_vless_flow(&client_config.protocol, false)?;
+    if let ClientProxyConfig::Socks {
+        udp_associate: true,
+        ..
+    } = client_config.protocol
+    {
+        if client_config.transport != Transport::Tcp {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "SOCKS UDP associate is only supported for TCP transport",
+            ));
+        }
+    }
+    if let ClientProxyConfig::Shadowsocks(ShadowsocksConfig {
+        plugin: Some(_), ..
+    }) = client_config.protocol
+    {
+        if client_config.transport != Transport::Tcp {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Shadowsocks plugins are only supported for TCP transport",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Plugins take over the listener or the connection to the proxy, so they can only be used when
+// shadowsocks is the outer protocol.
+fn nested_plugin_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "Shadowsocks plugins are only supported when shadowsocks is the outer protocol",
+    )
+}
+
+// Snell and trojan's shadowsocks layer don't carry the UDP-over-TCP request themselves, and always
+// encrypt.
+fn validate_nested_shadowsocks(shadowsocks_config: &ShadowsocksConfig) -> std::io::Result<()> {
+    if shadowsocks_config.udp_over_tcp {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "udp_over_tcp is only supported for shadowsocks and trojan, not nested shadowsocks settings",
+        ));
+    }
+    if is_none_cipher(&shadowsocks_config.cipher) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "the {} cipher is only supported for shadowsocks, not snell or nested shadowsocks settings",
+                shadowsocks_config.cipher
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_server_plugin(protocol: &ServerProxyConfig, is_outer: bool) -> std::io::Result<()> {
+    let has_plugin = match protocol {
+        ServerProxyConfig::Shadowsocks(shadowsocks_config) => {
+            !is_outer && shadowsocks_config.plugin.is_some()
+        }
+        ServerProxyConfig::Snell(shadowsocks_config)
+        | ServerProxyConfig::Trojan {
+            shadowsocks: Some(shadowsocks_config),
+            ..
+        } => {
+            validate_nested_shadowsocks(shadowsocks_config)?;
+            shadowsocks_config.plugin.is_some()
+        }
+        _ => false,
+    };
+    if has_plugin {
+        return Err(nested_plugin_error());
+    }
+    Ok(())
+}
+
+fn validate_client_plugin(protocol: &ClientProxyConfig, is_outer: bool) -> std::io::Result<()> {
+    let has_plugin = match protocol {
+        ClientProxyConfig::Shadowsocks(shadowsocks_config) => {
+            !is_outer && shadowsocks_config.plugin.is_some()
+        }
+        ClientProxyConfig::Snell(shadowsocks_config)
+        | ClientProxyConfig::Trojan {
+            shadowsocks: Some(shadowsocks_config),
+            ..
+        } => {
+            validate_nested_shadowsocks(shadowsocks_config)?;
+            shadowsocks_config.plugin.is_some()
+        }
+        _ => false,
+    };
+    if has_plugin {
+        return Err(nested_plugin_error());
+    }
+    Ok(())
+}
+
+// The relay is reached over plain UDP next to the control connection, which can't be carried by
+// other layers.
+fn validate_client_udp_associate(protocol: &ClientProxyConfig) -> std::io::Result<()> {
+    match protocol {
+        ClientProxyConfig::Socks {
+            udp_associate: true,
+            ..
+        } => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "SOCKS UDP associate is only supported when socks is the outer protocol",
+        )),
+        _ => Ok(()),
+    }
+}
+
+// The Vision flow reads and writes beneath the TLS layer, so VLESS has to be directly inside it.
+fn vless_flow_error(flow: VlessFlow) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "VLESS flow {} is only supported directly inside TLS",
+            flow.name()
+        ),
+    )
+}
+
+fn validate_server_vless_flow(
+    protocol: &ServerProxyConfig,
+    is_in_tls: bool,
+) -> std::io::Result<()> {
+    match protocol {
+        ServerProxyConfig::Vless {
+            flow: Some(flow), ..
+        } if !is_in_tls => Err(vless_flow_error(*flow)),
+        _ => Ok(()),
+    }
+}
+
+fn validate_client_vless_flow(
+    protocol: &ClientProxyConfig,
+    is_in_tls: bool,
+) -> std::io::Result<()> {
+    match protocol {
+        ClientProxyConfig::Vless {
+            flow: Some(flow), ..
+        } if !is_in_tls => Err(vless_flow_error(*flow)),
+        _ => Ok(()),
+    }
+}
+
+fn validate_resolver_config(resolver_config: &ResolverConfig) -> std::io::Result<()> {
+    if resolver_config.timeout_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "resolver timeout_secs must be greater than zero",
+        ));
+    }
+    if resolver_config.attempts == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "resolver attempts must be greater than zero",
+        ));
+    }
+    for dot_server in resolver_config.dot_servers.iter() {
+        let server_name = match dot_server.sni_hostname {
+            Some(ref sni_hostname) => sni_hostname.clone(),
+            None => dot_server.address.address().to_string(),
+        };
+        if rustls::ServerName::try_from(server_name.as_str()).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid DNS over TLS server name: {}", server_name),
+            ));
+        }
+        if let Some(ref ca_cert) = dot_server.ca_cert {
+            load_ca_certs(ca_cert)?;
+        }
+        for pin in dot_server.pin_sha256.iter() {
+            parse_spki_hash(pin)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_udp_config(udp_config: &UdpConfig) -> std::io::Result<()> {
+    if udp_config.max_sessions == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "udp max_sessions must be greater than zero",
+        ));
+    }
+    if udp_config.idle_timeout_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "udp idle_timeout_secs must be greater than zero",
+        ));
+    }
+    if udp_config.batch_size == 0 || udp_config.batch_size > MAX_UDP_BATCH_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "udp batch_size must be between 1 and {}",
+                MAX_UDP_BATCH_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_auth_ban_config(auth_ban_config: &AuthBanConfig) -> std::io::Result<()> {
+    if auth_ban_config.max_auth_failures == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "auth ban max_auth_failures must be greater than zero",
+        ));
+    }
+    if auth_ban_config.window_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "auth ban window_secs must be greater than zero",
+        ));
+    }
+    if auth_ban_config.ban_duration_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "auth ban ban_duration_secs must be greater than zero",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_source_mask(source_mask: &NetLocationMask) -> std::io::Result<()> {
+    if source_mask.negated {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "source masks can't be negated, use deny_sources instead: {}",
+                source_mask
+            ),
+        ));
+    }
+    if source_mask.port != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("source masks can't have a port: {}", source_mask),
+        ));
+    }
+    if source_mask.address_mask.address.is_hostname() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "source masks must be IP addresses or CIDR ranges: {}",
+                source_mask
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// The longest that write coalescing may hold data, so that interactive connections stay usable if
+// it's enabled by mistake.
+const MAX_WRITE_COALESCING_DELAY_MS: u64 = 50;
+
+fn validate_write_coalescing_config(
+    write_coalescing: &WriteCoalescingConfig,
+) -> std::io::Result<()> {
+    if write_coalescing.max_bytes == 0 || write_coalescing.max_bytes > COPY_BUFFER_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "write_coalescing max_bytes must be between 1 and {}",
+                COPY_BUFFER_SIZE
+            ),
+        ));
+    }
+    if write_coalescing.max_delay_ms == 0
+        || write_coalescing.max_delay_ms > MAX_WRITE_COALESCING_DELAY_MS
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "write_coalescing max_delay_ms must be between 1 and {}",
+                MAX_WRITE_COALESCING_DELAY_MS
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_reaper_config(reaper_config: &ReaperConfig) -> std::io::Result<()> {
+    if reaper_config.interval_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "reaper interval_secs must be greater than zero",
+        ));
+    }
+    if reaper_config.max_lifetime_secs.is_none() && reaper_config.max_idle_secs.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "reaper needs max_lifetime_secs or max_idle_secs",
+        ));
+    }
+    if reaper_config.max_lifetime_secs == Some(0) || reaper_config.max_idle_secs == Some(0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "reaper max_lifetime_secs and max_idle_secs must be greater than zero",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_telemetry_config(telemetry_config: &TelemetryConfig) -> std::io::Result<()> {
+    if !cfg!(feature = "otel") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "telemetry_settings needs shoes to be built with the otel feature",
+        ));
+    }
+    if !telemetry_config.endpoint.starts_with("http://")
+        && !telemetry_config.endpoint.starts_with("https://")
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "telemetry endpoint must be a http(s) URL: {}",
+                telemetry_config.endpoint
+            ),
+        ));
+    }
+    if !(0.0..=1.0).contains(&telemetry_config.sample_rate) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "telemetry sample_rate must be between 0 and 1",
+        ));
+    }
+    Ok(())
+}
+
+fn has_path_bind_location(server_config: &ServerConfig) -> bool {
+    server_config
+        .bind_locations
+        .iter()
+        .any(|bind_location| matches!(bind_location, BindLocation::Path(_)))
+}
+
+fn validate_health_config(
+    health_config: &HealthConfig,
+    bind_location: &BindLocation,
+) -> std::io::Result<()> {
+    if health_config.upstream_timeout_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "health upstream_timeout_secs must be greater than zero",
+        ));
+    }
+    // Proxy clients shouldn't be able to reach the health endpoint.
+    if let BindLocation::Address(ref location) = bind_location {
+        if location.port() == health_config.address.port() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "health server must not share the server's port: {}",
+                    health_config.address
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Smaller stacks overflow while setting up connections.
+const MIN_THREAD_STACK_SIZE: usize = 64 * 1024;
+
+fn validate_runtime_config(runtime_config: &RuntimeConfig) -> std::io::Result<()> {
+    if runtime_config.flavor == RuntimeFlavor::CurrentThread
+        && runtime_config.worker_threads.is_some()
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "worker_threads is not supported with the current_thread runtime",
+        ));
+    }
+    if runtime_config.worker_threads == Some(0) || runtime_config.max_blocking_threads == Some(0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "runtime worker_threads and max_blocking_threads must be greater than zero",
+        ));
+    }
+    if runtime_config
+        .thread_stack_size
+        .is_some_and(|thread_stack_size| thread_stack_size < MIN_THREAD_STACK_SIZE)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "runtime thread_stack_size must be at least {} bytes",
+                MIN_THREAD_STACK_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_quota_config(quota_config: &QuotaConfig) -> std::io::Result<()> {
+    if quota_config.save_interval_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "quota save_interval_secs must be greater than zero",
+        ));
+    }
+    if quota_config.period_days == Some(0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "quota period_days must be greater than zero",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_stats_config(stats_config: &StatsConfig) -> std::io::Result<()> {
+    if stats_config.stats_file.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "stats_file must not be empty",
+        ));
+    }
+    if stats_config.save_interval_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "stats save_interval_secs must be greater than zero",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_mux_config(mux_config: &MuxConfig) -> std::io::Result<()> {
+    if mux_config.max_streams == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "mux max_streams must be greater than zero",
+        ));
+    }
+    if mux_config.idle_timeout_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "mux idle_timeout_secs must be greater than zero",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_tls_server_certificate(tls_server_config: &TlsServerConfig) -> std::io::Result<()> {
+    if let Some(ref ocsp_stapling_config) = tls_server_config.ocsp_stapling {
+        validate_ocsp_stapling_config(ocsp_stapling_config)?;
+    }
+    match (
+        &tls_server_config.cert,
+        &tls_server_config.key,
+        &tls_server_config.acme,
+    ) {
+        (Some(_), Some(_), None) => Ok(()),
+        (None, None, Some(acme_config)) => validate_acme_config(acme_config),
+        (_, _, Some(_)) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "TLS server has both acme settings and a cert or key",
+        )),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "TLS server needs both cert and key, or acme settings",
+        )),
+    }
+}
+
+fn validate_ocsp_stapling_config(ocsp_stapling_config: &OcspStaplingConfig) -> std::io::Result<()> {
+    if ocsp_stapling_config.response_file.is_none() && !ocsp_stapling_config.fetch {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "OCSP stapling needs a response_file, or fetch enabled",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_acme_config(acme_config: &AcmeConfig) -> std::io::Result<()> {
+    if !acme_config.directory_url.starts_with("https://")
+        && !acme_config.directory_url.starts_with("http://")
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "ACME directory_url must be an http(s) URL: {}",
+                acme_config.directory_url
+            ),
+        ));
+    }
+    for domain in acme_config.domains.iter() {
+        let is_hostname = matches!(Address::from(domain), Ok(Address::Hostname(_)));
+        if !is_hostname || rustls::ServerName::try_from(domain.as_str()).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid ACME domain: {}", domain),
+            ));
+        }
+    }
+    if acme_config.cache_dir.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "ACME cache_dir must not be empty",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_admin_config(admin_config: &AdminConfig) -> std::io::Result<()> {
+    if let BindLocation::Address(ref location) = admin_config.bind_location {
+        let is_loopback = match location.address() {
+            Address::Ipv4(ip) => ip.is_loopback(),
+            Address::Ipv6(ip) => ip.is_loopback(),
+            Address::Hostname(hostname) => hostname == "localhost",
+        };
+        if !is_loopback {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "admin server must bind to a loopback address or a unix socket: {}",
+                    location
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
 
-    for config in all_configs.into_iter() {
-        match config {
-            Config::ClientConfigGroup {
-                client_group,
-                client_proxies,
-            } => {
-                if client_groups
-                    .insert(client_group.clone(), client_proxies.into_vec())
-                    .is_some()
-                {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        format!("client group already exists: {}", client_group),
-                    ));
-                }
+fn validate_client_proxy_config(client_proxy_config: &ClientProxyConfig) -> std::io::Result<()> {
+    match client_proxy_config {
+        ClientProxyConfig::Tls(TlsClientConfig {
+            verify,
+            ca_cert,
+            system_roots,
+            pin_sha256,
+            fragment,
+            protocol,
+            ..
+        }) => {
+            validate_client_roots(*verify, ca_cert, *system_roots)?;
+            for pin in pin_sha256.iter() {
+                parse_spki_hash(pin)?;
             }
-            Config::RuleConfigGroup { rule_group, rules } => {
-                if rule_groups
-                    .insert(rule_group.clone(), rules.into_vec())
-                    .is_some()
-                {
+            if let Some(fragment) = fragment {
+                validate_tls_fragment(fragment)?;
+            }
+            validate_client_proxy_config(protocol)?;
+            validate_client_plugin(protocol, false)?;
+            validate_client_udp_associate(protocol)?;
+            validate_client_vless_flow(protocol, true)?;
+        }
+        ClientProxyConfig::Websocket(WebsocketClientConfig {
+            matching_headers,
+            host_header,
+            ping_type,
+            ping_interval_secs,
+            compression,
+            protocol,
+            ..
+        }) => {
+            if let Some(host_header) = host_header {
+                validate_websocket_host_header(host_header, matching_headers)?;
+            }
+            validate_websocket_ping(ping_type, *ping_interval_secs)?;
+            if let Some(compression) = compression {
+                validate_websocket_compression(compression)?;
+            }
+            validate_client_proxy_config(protocol)?;
+            validate_client_plugin(protocol, false)?;
+            validate_client_udp_associate(protocol)?;
+            validate_client_vless_flow(protocol, false)?;
+        }
+        ClientProxyConfig::Grpc(GrpcClientConfig {
+            service_name,
+            authority,
+            protocol,
+            ..
+        }) => {
+            validate_grpc_service_name(service_name)?;
+            if let Some(authority) = authority {
+                if authority.parse::<http::uri::Authority>().is_err() {
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidInput,
-                        format!("rule group already exists: {}", rule_group),
+                        format!("invalid gRPC authority: {:?}", authority),
                     ));
                 }
             }
-            Config::ServerConfig(server_config) => {
-                server_configs.push(server_config);
-            }
+            validate_client_proxy_config(protocol)?;
+            validate_client_plugin(protocol, false)?;
+            validate_client_udp_associate(protocol)?;
+            validate_client_vless_flow(protocol, false)?;
         }
+        // UDP relay uses the TCP cipher by default, which is checked when it's used, since
+        // not every cipher supports UDP.
+        ClientProxyConfig::Shadowsocks(shadowsocks_config)
+            if shadowsocks_config.udp_cipher.is_some() =>
+        {
+            ShadowsocksUdpCipher::from_config(shadowsocks_config)?;
+        }
+        _ => (),
     }
+    Ok(())
+}
 
-    for config in server_configs.iter_mut() {
-        validate_server_config(config, &client_groups, &rule_groups)?;
+fn validate_client_roots(
+    verify: bool,
+    ca_cert: &Option<String>,
+    system_roots: bool,
+) -> std::io::Result<()> {
+    if let Some(ca_cert) = ca_cert {
+        load_ca_certs(ca_cert)?;
+    }
+    if verify && ca_cert.is_none() && !system_roots {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "system_roots is false but no ca_cert is specified, so no certificate can be verified",
+        ));
     }
+    Ok(())
+}
 
-    Ok(server_configs)
+fn validate_websocket_ping(
+    ping_type: &WebsocketPingType,
+    ping_interval_secs: u64,
+) -> std::io::Result<()> {
+    // The interval is ignored when pings are disabled.
+    if *ping_type != WebsocketPingType::Disabled && ping_interval_secs == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "websocket ping_interval_secs must be greater than zero",
+        ));
+    }
+    Ok(())
 }
 
-pub fn update_config(config: &mut ServerConfig) -> std::io::Result<()> {
-    let client_groups = HashMap::from([("direct".to_owned(), vec![ClientConfig::default()])]);
-    let rule_groups = HashMap::new();
-    validate_server_config(config, &client_groups, &rule_groups)
+// The service name is the first segment of the call path.
+fn validate_grpc_service_name(service_name: &str) -> std::io::Result<()> {
+    if service_name.is_empty()
+        || !service_name
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !matches!(c, '/' | '?' | '#' | '%'))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid gRPC service_name: {:?}", service_name),
+        ));
+    }
+    Ok(())
 }
 
-fn validate_server_config(
-    server_config: &mut ServerConfig,
-    client_groups: &HashMap<String, Vec<ClientConfig>>,
-    rule_groups: &HashMap<String, Vec<RuleConfig>>,
+fn validate_tls_fragment(config: &TlsFragmentConfig) -> std::io::Result<()> {
+    // TLS records can't be empty, or hold more than 16 KiB.
+    if !(1..=16384).contains(&config.size) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "TLS fragment size must be between 1 and 16384, got {}",
+                config.size
+            ),
+        ));
+    }
+    if config.delay_ms > 1000 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "TLS fragment delay_ms must be at most 1000, got {}",
+                config.delay_ms
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_websocket_host_header(
+    host_header: &str,
+    matching_headers: &Option<HashMap<String, String>>,
 ) -> std::io::Result<()> {
-    if server_config.transport != Transport::Tcp {
-        if server_config.tcp_settings.is_some() {
+    if host_header.is_empty() || host_header.contains(['\r', '\n']) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid websocket host_header: {:?}", host_header),
+        ));
+    }
+    let has_host = matching_headers
+        .as_ref()
+        .is_some_and(|headers| headers.keys().any(|name| name.eq_ignore_ascii_case("host")));
+    if has_host {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "websocket host_header can't be used with a Host header in matching_headers",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_websocket_compression(config: &WebsocketCompressionConfig) -> std::io::Result<()> {
+    // zlib doesn't support raw deflate with a window size of 8 bits.
+    for window_bits in [config.server_max_window_bits, config.client_max_window_bits] {
+        if !(9..=15).contains(&window_bits) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "TCP transport is not selected but TCP settings specified",
+                format!(
+                    "websocket compression window bits must be between 9 and 15, got {}",
+                    window_bits
+                ),
             ));
         }
     }
+    if config.max_decompressed_size == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "websocket compression max_decompressed_size must be greater than zero",
+        ));
+    }
+    Ok(())
+}
 
-    if server_config.transport == Transport::Quic {
-        if server_config.quic_settings.is_none() {
+fn validate_websocket_response_headers(headers: &HashMap<String, String>) -> std::io::Result<()> {
+    let mut seen_names: Vec<String> = vec![];
+    for (name, value) in headers.iter() {
+        let is_valid_name = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !is_valid_name {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "QUIC transport is selected but QUIC settings not specified",
+                format!("invalid websocket response header name: {}", name),
             ));
         }
-    } else {
-        if server_config.quic_settings.is_some() {
+        if value.contains(['\r', '\n']) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "QUIC transport is not selected but QUIC settings specified",
+                format!("invalid websocket response header value for {}", name),
             ));
         }
-    }
-
-    if let BindLocation::Path(_) = server_config.bind_location {
-        if server_config.transport != Transport::Tcp {
+        // Header names are case-insensitive, so `Server` and `server` are duplicates.
+        let lowercase_name = name.to_ascii_lowercase();
+        if seen_names.contains(&lowercase_name) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                "Unix domain socket support only available for TCP transport",
+                format!("duplicate websocket response header: {}", name),
             ));
         }
+        seen_names.push(lowercase_name);
     }
+    Ok(())
+}
 
-    ConfigSelection::replace_none_or_some_groups(&mut server_config.rules, rule_groups)?;
+fn validate_tls_server_config(
+    tls_server_config: &mut TlsServerConfig,
+    client_groups: &HashMap<String, Vec<ClientConfig>>,
+    rule_groups: &HashMap<String, Vec<RuleConfig>>,
+) -> std::io::Result<()> {
+    validate_tls_server_certificate(tls_server_config)?;
+    validate_client_auth(
+        tls_server_config.client_ca.as_deref(),
+        tls_server_config.require_client_cert,
+    )?;
+    let TlsServerConfig {
+        ref mut protocol,
+        ref mut override_rules,
+        ref mut client_identities,
+        ref client_ca,
+        ref alpn_protocols,
+        ref unknown_alpn,
+        ref mut alpn_override_rules,
+        ref min_tls_version,
+        ref cipher_suites,
+        ..
+    } = *tls_server_config;
+    create_tls_policy(
+        TlsVersion::from_config(min_tls_version.as_deref())?,
+        &cipher_suites.clone().into_vec(),
+    )?;
+    validate_server_proxy_config(protocol, client_groups, rule_groups)?;
+    validate_server_plugin(protocol, false)?;
+    validate_server_vless_flow(protocol, true)?;
 
-    if server_config.rules.is_empty() {
-        server_config.rules = direct_allow_rule();
-    }
+    ConfigSelection::replace_none_or_some_groups(override_rules, rule_groups)?;
 
-    for rule_config_selection in server_config.rules.iter_mut() {
+    for rule_config_selection in override_rules.iter_mut() {
         validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
     }
 
-    validate_server_proxy_config(&mut server_config.protocol, client_groups, rule_groups)?;
-
-    Ok(())
-}
-
-fn validate_client_config(client_config: &mut ClientConfig) -> std::io::Result<()> {
-    if client_config.transport != Transport::Tcp && client_config.tcp_settings.is_some() {
+    if !client_identities.is_empty() && client_ca.is_none() {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
-            "TCP transport is not selected but TCP settings specified",
+            "client_identities need a client_ca to verify client certificates",
         ));
     }
+    let mut seen_identities = vec![];
+    for client_identity in client_identities.iter_mut() {
+        if seen_identities.contains(&client_identity.identity) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("duplicate client identity: {}", client_identity.identity),
+            ));
+        }
+        seen_identities.push(client_identity.identity.clone());
 
-    if client_config.transport != Transport::Quic && client_config.quic_settings.is_some() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "QUIC transport is not selected but QUIC settings specified",
-        ));
+        ConfigSelection::replace_none_or_some_groups(
+            &mut client_identity.override_rules,
+            rule_groups,
+        )?;
+
+        for rule_config_selection in client_identity.override_rules.iter_mut() {
+            validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
+        }
     }
 
-    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-    if client_config.bind_interface.is_one() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "bind_interface is only available on Android, Fuchsia, or Linux.",
-        ));
+    let is_alpn_protocol =
+        |alpn_protocol: &String| alpn_protocols.iter().any(|p| p == alpn_protocol);
+    match unknown_alpn {
+        Some(_) if alpn_protocols.is_empty() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "unknown_alpn needs alpn_protocols to be set",
+            ));
+        }
+        Some(UnknownAlpnConfig::Protocol(alpn_protocol)) if !is_alpn_protocol(alpn_protocol) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown_alpn protocol {} is not one of alpn_protocols",
+                    alpn_protocol
+                ),
+            ));
+        }
+        _ => (),
+    }
+    for (alpn_protocol, override_rules) in alpn_override_rules.iter_mut() {
+        if !is_alpn_protocol(alpn_protocol) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "alpn_override_rules protocol {} is not one of alpn_protocols",
+                    alpn_protocol
+                ),
+            ));
+        }
+
+        ConfigSelection::replace_none_or_some_groups(override_rules, rule_groups)?;
+
+        for rule_config_selection in override_rules.iter_mut() {
+            validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_sni_hostnames<'a>(
+    sni_hostnames: impl Iterator<Item = &'a String>,
+) -> std::io::Result<()> {
+    let mut seen_hostnames: HashMap<String, &String> = HashMap::new();
+    for sni_hostname in sni_hostnames {
+        // A wildcard can only be the whole leftmost label, as in certificates.
+        let is_valid = match sni_hostname.strip_prefix("*.") {
+            Some(parent) => {
+                !parent.contains('*')
+                    && parent.contains('.')
+                    && matches!(Address::from(parent), Ok(Address::Hostname(_)))
+            }
+            None => !sni_hostname.is_empty() && !sni_hostname.contains('*'),
+        };
+        if !is_valid {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid SNI hostname: {}", sni_hostname),
+            ));
+        }
+        if let Some(previous) =
+            seen_hostnames.insert(sni_hostname.to_ascii_lowercase(), sni_hostname)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "SNI hostnames {} and {} overlap, hostnames are matched case-insensitively",
+                    previous, sni_hostname
+                ),
+            ));
+        }
     }
+    Ok(())
+}
 
+fn validate_client_auth(client_ca: Option<&str>, require_client_cert: bool) -> std::io::Result<()> {
+    match client_ca {
+        Some(client_ca) => {
+            load_ca_certs(client_ca)?;
+        }
+        None if require_client_cert => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "require_client_cert needs a client_ca to verify client certificates",
+            ));
+        }
+        None => (),
+    }
     Ok(())
 }
 
@@ -703,17 +4434,64 @@ fn validate_server_proxy_config(
     rule_groups: &HashMap<String, Vec<RuleConfig>>,
 ) -> std::io::Result<()> {
     match server_proxy_config {
+        ServerProxyConfig::Shadowsocks(shadowsocks_config)
+            if shadowsocks_config.udp_cipher.is_some() =>
+        {
+            ShadowsocksUdpCipher::from_config(shadowsocks_config)?;
+        }
         ServerProxyConfig::Tls {
             sni_targets,
             default_target,
+            ..
+        } => {
+            validate_sni_hostnames(sni_targets.keys())?;
+            for tls_server_config in sni_targets
+                .values_mut()
+                .chain(default_target.as_deref_mut())
+            {
+                validate_tls_server_config(tls_server_config, client_groups, rule_groups)?;
+            }
+        }
+        ServerProxyConfig::Trojan {
+            shadowsocks: Some(_),
+            fallback: Some(_),
+            ..
         } => {
-            for (_, tls_server_config) in sni_targets.iter_mut() {
-                let TlsServerConfig {
+            // The received bytes can't be replayed once they have been decrypted.
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Trojan fallback is not supported with shadowsocks",
+            ));
+        }
+        ServerProxyConfig::Vmess {
+            force_aead: true,
+            alter_id,
+            ..
+        } if *alter_id > 0 => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "VMess alter_id requires force_aead to be false, since only legacy clients use it",
+            ));
+        }
+        ServerProxyConfig::Websocket { targets } => {
+            for websocket_server_config in targets.iter_mut() {
+                let WebsocketServerConfig {
                     ref mut protocol,
                     ref mut override_rules,
+                    ref ping_type,
+                    ping_interval_secs,
+                    ref compression,
+                    ref response_headers,
                     ..
-                } = *tls_server_config;
+                } = websocket_server_config;
+                validate_websocket_ping(ping_type, *ping_interval_secs)?;
+                validate_websocket_response_headers(response_headers)?;
+                if let Some(compression) = compression {
+                    validate_websocket_compression(compression)?;
+                }
                 validate_server_proxy_config(protocol, client_groups, rule_groups)?;
+                validate_server_plugin(protocol, false)?;
+                validate_server_vless_flow(protocol, false)?;
 
                 ConfigSelection::replace_none_or_some_groups(override_rules, rule_groups)?;
 
@@ -721,33 +4499,53 @@ fn validate_server_proxy_config(
                     validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
                 }
             }
-            if let Some(tls_server_config) = default_target {
-                let TlsServerConfig {
-                    ref mut protocol,
-                    ref mut override_rules,
-                    ..
-                } = **tls_server_config;
-                validate_server_proxy_config(protocol, client_groups, rule_groups)?;
+        }
+        ServerProxyConfig::Grpc(grpc_server_config) => {
+            let GrpcServerConfig {
+                ref service_name,
+                ref mut protocol,
+                ref mut override_rules,
+            } = **grpc_server_config;
+            validate_grpc_service_name(service_name)?;
+            validate_server_proxy_config(protocol, client_groups, rule_groups)?;
+            validate_server_plugin(protocol, false)?;
+            validate_server_vless_flow(protocol, false)?;
 
-                ConfigSelection::replace_none_or_some_groups(override_rules, rule_groups)?;
+            ConfigSelection::replace_none_or_some_groups(override_rules, rule_groups)?;
 
-                for rule_config_selection in override_rules.iter_mut() {
-                    validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
-                }
+            for rule_config_selection in override_rules.iter_mut() {
+                validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
             }
         }
-        ServerProxyConfig::Websocket { targets } => {
-            for websocket_server_config in targets.iter_mut() {
-                let WebsocketServerConfig {
-                    ref mut protocol,
-                    ref mut override_rules,
-                    ..
-                } = websocket_server_config;
-                validate_server_proxy_config(protocol, client_groups, rule_groups)?;
+        ServerProxyConfig::Http {
+            username,
+            password,
+            users,
+        }
+        | ServerProxyConfig::Socks {
+            username,
+            password,
+            users,
+        } => {
+            let mut seen_usernames = vec![];
+            if username.is_some() || password.is_some() {
+                seen_usernames.push(username.clone().unwrap_or_default());
+            }
+            for user in users.iter_mut() {
+                if seen_usernames.contains(&user.username) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("duplicate username: {}", user.username),
+                    ));
+                }
+                seen_usernames.push(user.username.clone());
 
-                ConfigSelection::replace_none_or_some_groups(override_rules, rule_groups)?;
+                ConfigSelection::replace_none_or_some_groups(
+                    &mut user.override_rules,
+                    rule_groups,
+                )?;
 
-                for rule_config_selection in override_rules.iter_mut() {
+                for rule_config_selection in user.override_rules.iter_mut() {
                     validate_rule_config(rule_config_selection.unwrap_config_mut(), client_groups)?;
                 }
             }
@@ -761,17 +4559,174 @@ fn validate_rule_config(
     rule_config: &mut RuleConfig,
     client_groups: &HashMap<String, Vec<ClientConfig>>,
 ) -> std::io::Result<()> {
+    for mask in rule_config.masks.iter_mut() {
+        if let Some(ref mut domain_set) = mask.domain_set {
+            domain_set.domains = Some(load_domain_set(&domain_set.path)?);
+        }
+    }
     match rule_config.action {
         RuleActionConfig::Allow {
+            ref override_address,
             ref mut client_proxies,
-            ..
+            sticky,
+            balance,
+            ref mut select_by,
         } => {
+            if sticky != StickyMode::None && balance != BalanceMode::RoundRobin {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "sticky can only be used with round-robin balancing",
+                ));
+            }
+            if let Some(override_address) = override_address {
+                if override_address.address().is_unspecified() && override_address.port() == 0 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "override_address needs a host or a port to override",
+                    ));
+                }
+            }
             ConfigSelection::replace_one_or_some_groups(client_proxies, client_groups)?;
             for client_config_selection in client_proxies.iter_mut() {
                 validate_client_config(client_config_selection.unwrap_config_mut())?
             }
+            if let Some(select_by) = select_by {
+                validate_select_by_config(select_by, client_groups)?;
+            }
         }
         _ => (),
     }
     Ok(())
 }
+
+fn validate_select_by_config(
+    select_by: &mut SelectByConfig,
+    client_groups: &HashMap<String, Vec<ClientConfig>>,
+) -> std::io::Result<()> {
+    if select_by.groups.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "select_by needs at least one group",
+        ));
+    }
+    for (value, client_proxies) in select_by.groups.iter_mut() {
+        parse_select_mask(select_by.key, value)?;
+        ConfigSelection::replace_one_or_some_groups(client_proxies, client_groups)?;
+        for client_config_selection in client_proxies.iter_mut() {
+            validate_client_config(client_config_selection.unwrap_config_mut())?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test writes its config to a file of its own, since tests run in parallel.
+    async fn load_error(name: &str, config: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "shoes-config-test-{}-{}.yaml",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, config).unwrap();
+        let result = load_configs(&[path.to_string_lossy().into_owned()]).await;
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Ok(_) => panic!("config {} was accepted", name),
+            Err(e) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+                e.to_string()
+            }
+        }
+    }
+
+    const SERVER: &str = "
+- address: 127.0.0.1:0
+  protocol:
+    type: socks
+";
+
+    #[tokio::test]
+    async fn client_group_referencing_itself_is_rejected() {
+        let config = format!(
+            "
+- client_group: loop
+  client_proxies:
+    - loop
+{SERVER}
+  rules:
+    - mask: 0.0.0.0/0
+      action: allow
+      client_proxy: loop
+"
+        );
+        assert_eq!(
+            load_error("client-self-reference", &config).await,
+            "client group reference cycle: loop -> loop"
+        );
+    }
+
+    #[tokio::test]
+    async fn rule_group_cycle_is_rejected() {
+        let config = format!(
+            "
+- rule_group: a
+  rules:
+    - b
+- rule_group: b
+  rules:
+    - mask: 10.0.0.0/8
+      action: block
+    - a
+{SERVER}
+  rules: a
+"
+        );
+        assert_eq!(
+            load_error("rule-cycle", &config).await,
+            "rule group reference cycle: a -> b -> a"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_group_used_as_rule_group_is_rejected() {
+        let config = format!(
+            "
+- client_group: upstream
+  client_proxies:
+    - address: 127.0.0.1:1080
+      protocol:
+        type: socks
+{SERVER}
+  rules: upstream
+"
+        );
+        assert_eq!(
+            load_error("client-group-as-rule-group", &config).await,
+            "upstream is a client group, but is used where a rule group is expected"
+        );
+    }
+
+    #[tokio::test]
+    async fn rule_group_used_as_client_group_is_rejected() {
+        let config = format!(
+            "
+- rule_group: blocked
+  rules:
+    - mask: 10.0.0.0/8
+      action: block
+{SERVER}
+  rules:
+    - mask: 0.0.0.0/0
+      action: allow
+      client_proxy: blocked
+"
+        );
+        assert_eq!(
+            load_error("rule-group-as-client-group", &config).await,
+            "blocked is a rule group, but is used where a client group is expected"
+        );
+    }
+}
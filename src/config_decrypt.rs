@@ -0,0 +1,511 @@
+// Decrypts configs that were encrypted with age (https://age-encryption.org/v1), so that configs
+// with secrets can be stored encrypted and used without a separate decryption step. Files for
+// X25519 recipients are supported, in binary or armored form.
+//
+// Errors describe what failed without including any of the decrypted content.
+
+use base64::engine::general_purpose::{STANDARD as BASE64, STANDARD_NO_PAD as BASE64_NO_PAD};
+use base64::Engine as _;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+
+// One or more age identities, eg. AGE-SECRET-KEY-1..., separated by whitespace.
+const IDENTITY_ENV_VAR: &str = "SHOES_AGE_IDENTITY";
+
+// The path of an age identity file, with one identity per line and # comments.
+const IDENTITY_FILE_ENV_VAR: &str = "SHOES_AGE_IDENTITY_FILE";
+
+const VERSION_LINE: &[u8] = b"age-encryption.org/v1\n";
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+
+const SECRET_KEY_HRP: &str = "age-secret-key-";
+const X25519_LABEL: &[u8] = b"age-encryption.org/v1/X25519";
+
+const FILE_KEY_LEN: usize = 16;
+const PAYLOAD_NONCE_LEN: usize = 16;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+pub fn is_encrypted_config(config_bytes: &[u8]) -> bool {
+    let trimmed = trim_start(config_bytes);
+    trimmed.starts_with(VERSION_LINE) || trimmed.starts_with(ARMOR_BEGIN.as_bytes())
+}
+
+// Decrypts the config with the identities from SHOES_AGE_IDENTITY or SHOES_AGE_IDENTITY_FILE.
+pub fn decrypt_config(config_bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let identities = load_identities()?;
+    let dearmored;
+    let mut file = trim_start(config_bytes);
+    if file.starts_with(ARMOR_BEGIN.as_bytes()) {
+        dearmored = dearmor(file)?;
+        file = &dearmored;
+    }
+
+    let header = parse_header(file)?;
+    let file_key = header
+        .x25519_stanzas
+        .iter()
+        .find_map(|stanza| {
+            identities
+                .iter()
+                .find_map(|identity| unwrap_file_key(identity, stanza))
+        })
+        .ok_or_else(|| {
+            invalid_data(if header.x25519_stanzas.is_empty() {
+                "config has no X25519 recipients, which are the only supported age recipients"
+            } else {
+                "none of the age identities can decrypt the config"
+            })
+        })?;
+
+    let mac_key = hkdf_sha256(&[], &file_key, b"header");
+    let mac_key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &mac_key);
+    ring::hmac::verify(&mac_key, &file[..header.mac_input_len], &header.mac)
+        .map_err(|_| invalid_data("age header MAC doesn't match"))?;
+
+    decrypt_payload(&file_key, &file[header.len..])
+}
+
+fn load_identities() -> std::io::Result<Vec<[u8; 32]>> {
+    let identities = match (
+        std::env::var(IDENTITY_ENV_VAR),
+        std::env::var_os(IDENTITY_FILE_ENV_VAR),
+    ) {
+        (Ok(identities), _) => identities,
+        (Err(_), Some(path)) => std::fs::read_to_string(&path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "could not read {} {}: {}",
+                    IDENTITY_FILE_ENV_VAR,
+                    path.to_string_lossy(),
+                    e
+                ),
+            )
+        })?,
+        (Err(_), None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "config is encrypted, but neither {} nor {} is set",
+                    IDENTITY_ENV_VAR, IDENTITY_FILE_ENV_VAR
+                ),
+            ));
+        }
+    };
+
+    let mut ret = vec![];
+    for line in identities.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        for identity in line.split_whitespace() {
+            // The identity itself isn't included in the error, since it's a secret.
+            ret.push(parse_identity(identity).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "invalid age identity, expected AGE-SECRET-KEY-1...",
+                )
+            })?);
+        }
+    }
+    if ret.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no age identities were found",
+        ));
+    }
+    Ok(ret)
+}
+
+fn parse_identity(identity: &str) -> Option<[u8; 32]> {
+    let identity = identity.to_ascii_lowercase();
+    let (hrp, data) = bech32_decode(&identity)?;
+    if hrp != SECRET_KEY_HRP {
+        return None;
+    }
+    data.try_into().ok()
+}
+
+fn bech32_decode(s: &str) -> Option<(&str, Vec<u8>)> {
+    const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATORS: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+    let separator = s.rfind('1')?;
+    let (hrp, data) = (&s[..separator], &s[separator + 1..]);
+    if hrp.is_empty() || data.len() < 6 {
+        return None;
+    }
+    let values = data
+        .bytes()
+        .map(|c| CHARSET.iter().position(|x| *x == c).map(|p| p as u8))
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut checksum: u32 = 1;
+    let hrp_values = hrp
+        .bytes()
+        .map(|b| b >> 5)
+        .chain(std::iter::once(0))
+        .chain(hrp.bytes().map(|b| b & 31));
+    for value in hrp_values.chain(values.iter().copied()) {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    if checksum != 1 {
+        return None;
+    }
+
+    // Converts the 5-bit groups to bytes, without the checksum.
+    let mut bytes = vec![];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for value in values[..values.len() - 6].iter() {
+        acc = (acc << 5) | *value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some((hrp, bytes))
+}
+
+fn dearmor(file: &[u8]) -> std::io::Result<Vec<u8>> {
+    let text = std::str::from_utf8(file).map_err(|_| invalid_data("armored config isn't UTF8"))?;
+    let body = text
+        .trim()
+        .strip_prefix(ARMOR_BEGIN)
+        .and_then(|s| s.strip_suffix(ARMOR_END))
+        .ok_or_else(|| invalid_data("armored config is missing its end line"))?;
+    let encoded = body.split_whitespace().collect::<String>();
+    BASE64
+        .decode(encoded)
+        .map_err(|_| invalid_data("armored config isn't valid base64"))
+}
+
+struct Header {
+    x25519_stanzas: Vec<X25519Stanza>,
+    mac: Vec<u8>,
+    // The length of the header up to and including "---", which the MAC covers.
+    mac_input_len: usize,
+    // The length of the header including the MAC line.
+    len: usize,
+}
+
+struct X25519Stanza {
+    ephemeral_share: [u8; 32],
+    wrapped_file_key: Vec<u8>,
+}
+
+fn parse_header(file: &[u8]) -> std::io::Result<Header> {
+    let mut pos = VERSION_LINE.len();
+    if !file.starts_with(VERSION_LINE) {
+        return Err(invalid_data("unsupported age version"));
+    }
+
+    let mut x25519_stanzas = vec![];
+    loop {
+        let line = next_line(file, &mut pos)?;
+        if let Some(mac) = line.strip_prefix(b"--- ") {
+            let mac = BASE64_NO_PAD
+                .decode(mac)
+                .map_err(|_| invalid_data("invalid age header MAC"))?;
+            return Ok(Header {
+                x25519_stanzas,
+                mac,
+                mac_input_len: pos - line.len() - 1 + 3,
+                len: pos,
+            });
+        }
+        let args = line
+            .strip_prefix(b"-> ")
+            .ok_or_else(|| invalid_data("invalid age header"))?
+            .split(|b| *b == b' ')
+            .collect::<Vec<_>>();
+
+        // The body is base64 in lines of 64 characters, ending with a shorter line.
+        let mut body = vec![];
+        loop {
+            let body_line = next_line(file, &mut pos)?;
+            body.extend_from_slice(body_line);
+            if body_line.len() < 64 {
+                break;
+            }
+        }
+
+        if args.len() == 2 && args[0] == b"X25519" {
+            let ephemeral_share = BASE64_NO_PAD
+                .decode(args[1])
+                .ok()
+                .and_then(|share| <[u8; 32]>::try_from(share).ok())
+                .ok_or_else(|| invalid_data("invalid X25519 recipient stanza"))?;
+            let wrapped_file_key = BASE64_NO_PAD
+                .decode(&body)
+                .map_err(|_| invalid_data("invalid X25519 recipient stanza"))?;
+            x25519_stanzas.push(X25519Stanza {
+                ephemeral_share,
+                wrapped_file_key,
+            });
+        }
+    }
+}
+
+fn next_line<'a>(file: &'a [u8], pos: &mut usize) -> std::io::Result<&'a [u8]> {
+    let rest = &file[*pos..];
+    let len = rest
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| invalid_data("age header ended early"))?;
+    *pos += len + 1;
+    Ok(&rest[..len])
+}
+
+fn unwrap_file_key(identity: &[u8; 32], stanza: &X25519Stanza) -> Option<[u8; FILE_KEY_LEN]> {
+    let recipient = x25519(identity, &X25519_BASEPOINT);
+    let shared_secret = x25519(identity, &stanza.ephemeral_share);
+    if shared_secret == [0u8; 32] {
+        return None;
+    }
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(&stanza.ephemeral_share);
+    salt[32..].copy_from_slice(&recipient);
+    let wrap_key = hkdf_sha256(&salt, &shared_secret, X25519_LABEL);
+
+    let mut wrapped_file_key = stanza.wrapped_file_key.clone();
+    let file_key = open(&wrap_key, [0u8; NONCE_LEN], &mut wrapped_file_key).ok()?;
+    file_key.try_into().ok()
+}
+
+fn decrypt_payload(file_key: &[u8], payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    if payload.len() < PAYLOAD_NONCE_LEN + TAG_LEN {
+        return Err(invalid_data("age payload is too short"));
+    }
+    let (payload_nonce, mut ciphertext) = payload.split_at(PAYLOAD_NONCE_LEN);
+    let payload_key = hkdf_sha256(payload_nonce, file_key, b"payload");
+
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    let mut counter: u64 = 0;
+    loop {
+        let chunk_len = std::cmp::min(ciphertext.len(), CHUNK_LEN + TAG_LEN);
+        let is_last = chunk_len == ciphertext.len();
+        // The nonce is an 11 byte big endian counter, followed by a flag for the last chunk.
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+        nonce[11] = is_last as u8;
+
+        let mut chunk = ciphertext[..chunk_len].to_vec();
+        plaintext.extend_from_slice(open(&payload_key, nonce, &mut chunk)?);
+        ciphertext = &ciphertext[chunk_len..];
+        if is_last {
+            return Ok(plaintext);
+        }
+        counter += 1;
+    }
+}
+
+fn open<'a>(
+    key: &[u8],
+    nonce: [u8; NONCE_LEN],
+    data: &'a mut [u8],
+) -> std::io::Result<&'a mut [u8]> {
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap());
+    key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), data)
+        .map_err(|_| invalid_data("failed to decrypt, the config may be corrupted"))
+}
+
+struct KeyLen(usize);
+
+impl ring::hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, salt)
+        .extract(ikm)
+        .expand(&[info], KeyLen(key.len()))
+        .unwrap()
+        .fill(&mut key)
+        .unwrap();
+    key
+}
+
+fn trim_start(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+// X25519 (RFC 7748), which ring only provides for ephemeral keys. This follows TweetNaCl, with
+// field elements as 16 limbs of 16 bits.
+
+type FieldElement = [i64; 16];
+
+const X25519_BASEPOINT: [u8; 32] = {
+    let mut basepoint = [0u8; 32];
+    basepoint[0] = 9;
+    basepoint
+};
+
+const A24: FieldElement = [0xdb41, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+fn x25519(scalar: &[u8; 32], point: &[u8; 32]) -> [u8; 32] {
+    let mut z = *scalar;
+    z[31] = (z[31] & 127) | 64;
+    z[0] &= 248;
+
+    let x = unpack(point);
+    let mut a: FieldElement = [0; 16];
+    let mut b = x;
+    let mut c: FieldElement = [0; 16];
+    let mut d: FieldElement = [0; 16];
+    a[0] = 1;
+    d[0] = 1;
+
+    for i in (0..255).rev() {
+        let bit = ((z[i >> 3] >> (i & 7)) & 1) as i64;
+        swap(&mut a, &mut b, bit);
+        swap(&mut c, &mut d, bit);
+        let mut e = add(&a, &c);
+        a = sub(&a, &c);
+        c = add(&b, &d);
+        b = sub(&b, &d);
+        d = mul(&e, &e);
+        let f = mul(&a, &a);
+        a = mul(&c, &a);
+        c = mul(&b, &e);
+        e = add(&a, &c);
+        a = sub(&a, &c);
+        b = mul(&a, &a);
+        c = sub(&d, &f);
+        a = mul(&c, &A24);
+        a = add(&a, &d);
+        c = mul(&c, &a);
+        a = mul(&d, &f);
+        d = mul(&b, &x);
+        b = mul(&e, &e);
+        swap(&mut a, &mut b, bit);
+        swap(&mut c, &mut d, bit);
+    }
+
+    pack(&mul(&a, &invert(&c)))
+}
+
+fn unpack(bytes: &[u8; 32]) -> FieldElement {
+    let mut o: FieldElement = [0; 16];
+    for i in 0..16 {
+        o[i] = bytes[2 * i] as i64 + ((bytes[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn pack(n: &FieldElement) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        let mut m: FieldElement = [0; 16];
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        swap(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0u8; 32];
+    for i in 0..16 {
+        o[2 * i] = (t[i] & 0xff) as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn carry(o: &mut FieldElement) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+// Swaps p and q when bit is 1, without branching on it.
+fn swap(p: &mut FieldElement, q: &mut FieldElement, bit: i64) {
+    let mask = !(bit - 1);
+    for i in 0..16 {
+        let t = mask & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn add(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut o: FieldElement = [0; 16];
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn sub(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut o: FieldElement = [0; 16];
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul(a: &FieldElement, b: &FieldElement) -> FieldElement {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o: FieldElement = [0; 16];
+    o.copy_from_slice(&t[..16]);
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+// Raises to the power p - 2.
+fn invert(i: &FieldElement) -> FieldElement {
+    let mut c = *i;
+    for a in (0..254).rev() {
+        c = mul(&c, &c);
+        if a != 2 && a != 4 {
+            c = mul(&c, i);
+        }
+    }
+    c
+}
@@ -0,0 +1,110 @@
+// Fetches configs from http:// and https:// URLs, so that they can be read from a central
+// service.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::http_client::http_request;
+
+// If set, sent as a bearer token in the Authorization header.
+const BEARER_TOKEN_ENV_VAR: &str = "SHOES_CONFIG_TOKEN";
+
+// If set, every successfully fetched config is saved in this directory, and used when fetching
+// fails.
+const CACHE_DIR_ENV_VAR: &str = "SHOES_CONFIG_CACHE_DIR";
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn is_config_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+async fn fetch(url: &str) -> std::io::Result<Vec<u8>> {
+    let token = std::env::var(BEARER_TOKEN_ENV_VAR)
+        .ok()
+        .map(|token| format!("Bearer {}", token.trim()));
+    let mut headers = vec![("Accept", "application/yaml, text/yaml, */*")];
+    if let Some(ref token) = token {
+        headers.push(("Authorization", token));
+    }
+
+    let response = http_request("GET", url, &headers, &[]).await?;
+    if !response.is_success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unexpected response status: {}", response.status_line),
+        ));
+    }
+    Ok(response.body)
+}
+
+fn cache_path(url: &str) -> Option<PathBuf> {
+    let cache_dir = std::env::var_os(CACHE_DIR_ENV_VAR)?;
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    Some(PathBuf::from(cache_dir).join(format!("{}.yaml", hash)))
+}
+
+// Fetches the config at the URL, falling back to the last cached copy if fetching fails.
+pub async fn fetch_config(url: &str) -> std::io::Result<Vec<u8>> {
+    let fetch_result = match tokio::time::timeout(FETCH_TIMEOUT, fetch(url)).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("timed out after {} seconds", FETCH_TIMEOUT.as_secs()),
+        )),
+    };
+    let e = match fetch_result {
+        Ok(body) => return Ok(body),
+        Err(e) => e,
+    };
+
+    if let Some(path) = cache_path(url) {
+        if let Ok(body) = tokio::fs::read(&path).await {
+            println!(
+                "WARNING: Failed to fetch config from {} ({}), using cached copy at {}",
+                url,
+                e,
+                path.display()
+            );
+            return Ok(body);
+        }
+    }
+
+    Err(std::io::Error::new(
+        e.kind(),
+        format!("Failed to fetch config from {}: {}", url, e),
+    ))
+}
+
+// Saves a fetched config that was parsed successfully, to be used if a later fetch fails.
+pub async fn cache_config(url: &str, config_bytes: &[u8]) {
+    let path = match cache_path(url) {
+        Some(p) => p,
+        None => return,
+    };
+    if let Some(parent) = path.parent() {
+        let _ = tokio::fs::create_dir_all(parent).await;
+    }
+    // Write to a temporary file first so that a partially written file is never used.
+    let tmp_path = path.with_extension("yaml.tmp");
+    let result = match tokio::fs::write(&tmp_path, config_bytes).await {
+        Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        println!(
+            "WARNING: Failed to cache config from {} at {}: {}",
+            url,
+            path.display(),
+            e
+        );
+    }
+}
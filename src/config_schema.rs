@@ -0,0 +1,1191 @@
+// A description of the config format, used to find unknown keys in config files and to generate
+// a JSON Schema. serde ignores unknown keys, and deny_unknown_fields can't be used since the
+// configs rely on untagged enums and flattened fields.
+//
+// This needs to be kept in sync with the config types in config.rs.
+
+use std::collections::HashMap;
+
+use serde_json::json;
+use serde_yaml::Value;
+
+pub enum Schema {
+    String,
+    Integer,
+    // An integer or a fraction.
+    Number,
+    Boolean,
+    // A string with one of these values.
+    Enum(&'static [&'static str]),
+    Object(Vec<Field>),
+    // An object with any keys.
+    Map(Box<Schema>),
+    List(Box<Schema>),
+    // A single item or a list of items.
+    OneOrSome(Box<Schema>),
+    // An object whose fields depend on the value of the tag field.
+    Tagged {
+        tag: &'static str,
+        variants: Vec<Variant>,
+    },
+    AnyOf(Vec<Schema>),
+    // A schema from definitions().
+    Ref(&'static str),
+    // The fields of an object schema from definitions(), none of which are required.
+    Partial(&'static str),
+}
+
+pub struct Field {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub required: bool,
+    pub schema: Schema,
+}
+
+impl Field {
+    fn new(name: &'static str, schema: Schema) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            required: false,
+            schema,
+        }
+    }
+
+    fn required(name: &'static str, schema: Schema) -> Self {
+        Self {
+            required: true,
+            ..Self::new(name, schema)
+        }
+    }
+
+    fn alias(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.name == key || self.aliases.contains(&key)
+    }
+}
+
+pub struct Variant {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub fields: Vec<Field>,
+}
+
+impl Variant {
+    fn new(name: &'static str, fields: Vec<Field>) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            fields,
+        }
+    }
+
+    fn alias(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    fn matches(&self, tag_value: &str) -> bool {
+        self.name == tag_value || self.aliases.contains(&tag_value)
+    }
+}
+
+fn reference(name: &'static str) -> Schema {
+    Schema::Ref(name)
+}
+
+fn one_or_some(schema: Schema) -> Schema {
+    Schema::OneOrSome(Box::new(schema))
+}
+
+fn string_map() -> Schema {
+    Schema::Map(Box::new(Schema::String))
+}
+
+const TRANSPORTS: &[&str] = &["tcp", "quic", "udp"];
+
+const NAT_TYPES: &[&str] = &["fullcone", "full-cone", "full_cone", "symmetric"];
+
+const OVERSIZED_DATAGRAMS: &[&str] = &["stream", "drop"];
+
+const MASK_MODES: &[&str] = &["any", "all"];
+
+const STICKY_MODES: &[&str] = &["none", "source-ip", "source_ip"];
+
+const BALANCE_MODES: &[&str] = &["round-robin", "round_robin", "least-conn", "least_conn"];
+
+const SELECT_KEYS: &[&str] = &["user", "sni", "source"];
+
+const SCHEDULE_DAYS: &[&str] = &[
+    "mon",
+    "monday",
+    "tue",
+    "tuesday",
+    "wed",
+    "wednesday",
+    "thu",
+    "thursday",
+    "fri",
+    "friday",
+    "sat",
+    "saturday",
+    "sun",
+    "sunday",
+];
+
+const RUNTIME_FLAVORS: &[&str] = &[
+    "multi_thread",
+    "multi-thread",
+    "current_thread",
+    "current-thread",
+];
+
+const IP_PREFERENCES: &[&str] = &["ipv4", "ipv6", "dual"];
+
+const RESOLVE_MODES: &[&str] = &["local", "remote"];
+
+const VLESS_FLOWS: &[&str] = &["xtls-rprx-vision"];
+
+const WEBSOCKET_PING_TYPES: &[&str] = &[
+    "disabled",
+    "pingframe",
+    "ping",
+    "ping-frame",
+    "emptyframe",
+    "empty",
+    "empty-frame",
+];
+
+fn shadowsocks_fields() -> Vec<Field> {
+    vec![
+        Field::required("cipher", Schema::String),
+        Field::required("password", Schema::String),
+        Field::new("udp_cipher", Schema::String),
+        Field::new("plugin", Schema::String),
+        Field::new("plugin_opts", Schema::String),
+        Field::new("udp_over_tcp", Schema::Boolean),
+    ]
+}
+
+fn credential_fields() -> Vec<Field> {
+    vec![
+        Field::new("username", Schema::String),
+        Field::new("password", Schema::String),
+    ]
+}
+
+fn alpn_protocols_field() -> Field {
+    Field::new("alpn_protocols", one_or_some(Schema::String)).alias(&["alpn_protocol"])
+}
+
+fn override_rules_field() -> Field {
+    Field::new("override_rules", one_or_some(reference("RuleSelection"))).alias(&["override_rule"])
+}
+
+fn websocket_common_fields() -> Vec<Field> {
+    vec![
+        Field::new("matching_path", Schema::String),
+        Field::new("matching_headers", string_map()),
+        Field::new("ping_type", Schema::Enum(WEBSOCKET_PING_TYPES)),
+        Field::new("ping_interval_secs", Schema::Integer),
+        Field::new("max_missed_pongs", Schema::Integer),
+        Field::new("compression", reference("WebsocketCompressionConfig")),
+    ]
+}
+
+fn http_client_fields() -> Vec<Field> {
+    let mut fields = credential_fields();
+    fields.push(Field::new("request_id_header", Schema::Boolean));
+    fields
+}
+
+fn socks_client_fields() -> Vec<Field> {
+    let mut fields = credential_fields();
+    fields.push(Field::new("udp_associate", Schema::Boolean));
+    fields
+}
+
+fn server_credential_fields() -> Vec<Field> {
+    let mut fields = credential_fields();
+    fields.push(
+        Field::new(
+            "users",
+            one_or_some(Schema::Object(vec![
+                Field::required("username", Schema::String),
+                Field::required("password", Schema::String),
+                Field::new("quota_bytes", Schema::Integer),
+                override_rules_field(),
+            ])),
+        )
+        .alias(&["user"]),
+    );
+    fields
+}
+
+fn server_proxy_config() -> Schema {
+    Schema::Tagged {
+        tag: "type",
+        variants: vec![
+            Variant::new("http", server_credential_fields()),
+            Variant::new("socks", server_credential_fields()).alias(&["socks5"]),
+            Variant::new("shadowsocks", shadowsocks_fields()).alias(&["ss"]),
+            Variant::new("snell", shadowsocks_fields()),
+            Variant::new(
+                "vless",
+                vec![
+                    Field::required("user_id", Schema::String),
+                    Field::new("fallback", Schema::String),
+                    Field::new("flow", Schema::Enum(VLESS_FLOWS)),
+                ],
+            ),
+            Variant::new(
+                "trojan",
+                vec![
+                    Field::required("password", Schema::String),
+                    Field::new("shadowsocks", reference("ShadowsocksConfig")),
+                    Field::new("fallback", Schema::String),
+                    Field::new("udp_over_tcp", Schema::Boolean),
+                ],
+            ),
+            Variant::new(
+                "tls",
+                vec![
+                    Field::new(
+                        "sni_targets",
+                        Schema::Map(Box::new(reference("TlsServerConfig"))),
+                    ),
+                    Field::new("default_target", reference("TlsServerConfig")),
+                    Field::new(
+                        "unknown_sni",
+                        Schema::AnyOf(vec![
+                            Schema::Enum(&["default", "reject"]),
+                            Schema::Object(vec![Field::required("fallback", Schema::String)]),
+                        ]),
+                    ),
+                ],
+            ),
+            Variant::new(
+                "vmess",
+                vec![
+                    Field::required("cipher", Schema::String),
+                    Field::required("user_id", Schema::String),
+                    Field::new("force_aead", Schema::Boolean),
+                    Field::new("alter_id", Schema::Integer),
+                    Field::new("udp_enabled", Schema::Boolean),
+                ],
+            ),
+            Variant::new(
+                "websocket",
+                vec![
+                    Field::required("targets", one_or_some(reference("WebsocketServerConfig")))
+                        .alias(&["target"]),
+                ],
+            )
+            .alias(&["ws"]),
+            Variant::new(
+                "grpc",
+                vec![
+                    Field::new("service_name", Schema::String),
+                    Field::required("protocol", reference("ServerProxyConfig")),
+                    override_rules_field(),
+                ],
+            ),
+            Variant::new(
+                "portforward",
+                vec![Field::required("targets", one_or_some(Schema::String)).alias(&["target"])],
+            )
+            .alias(&["forward"]),
+        ],
+    }
+}
+
+fn client_proxy_config() -> Schema {
+    let mut websocket_fields = websocket_common_fields();
+    websocket_fields.extend([
+        Field::new("host_header", Schema::String),
+        Field::required("protocol", reference("ClientProxyConfig")),
+    ]);
+
+    Schema::Tagged {
+        tag: "type",
+        variants: vec![
+            Variant::new("direct", vec![]),
+            Variant::new("http", http_client_fields()),
+            Variant::new("socks", socks_client_fields()).alias(&["socks5"]),
+            Variant::new("shadowsocks", shadowsocks_fields()).alias(&["ss"]),
+            Variant::new("snell", shadowsocks_fields()),
+            Variant::new(
+                "vless",
+                vec![
+                    Field::required("user_id", Schema::String),
+                    Field::new("flow", Schema::Enum(VLESS_FLOWS)),
+                ],
+            ),
+            Variant::new(
+                "trojan",
+                vec![
+                    Field::required("password", Schema::String),
+                    Field::new("shadowsocks", reference("ShadowsocksConfig")),
+                    Field::new("udp_over_tcp", Schema::Boolean),
+                ],
+            ),
+            Variant::new(
+                "tls",
+                vec![
+                    Field::new("verify", Schema::Boolean),
+                    Field::new("ca_cert", Schema::String),
+                    Field::new("system_roots", Schema::Boolean),
+                    Field::new("pin_sha256", one_or_some(Schema::String)),
+                    Field::new("sni_hostname", Schema::String),
+                    alpn_protocols_field(),
+                    Field::new(
+                        "fragment",
+                        Schema::Object(vec![
+                            Field::new("size", Schema::Integer),
+                            Field::new("delay_ms", Schema::Integer),
+                        ]),
+                    ),
+                    Field::required("protocol", reference("ClientProxyConfig")),
+                ],
+            ),
+            Variant::new(
+                "vmess",
+                vec![
+                    Field::required("cipher", Schema::String),
+                    Field::required("user_id", Schema::String),
+                    Field::new("aead", Schema::Boolean),
+                ],
+            ),
+            Variant::new("websocket", websocket_fields).alias(&["ws"]),
+            Variant::new(
+                "grpc",
+                vec![
+                    Field::new("service_name", Schema::String),
+                    Field::new("multi_mode", Schema::Boolean),
+                    Field::new("authority", Schema::String),
+                    Field::required("protocol", reference("ClientProxyConfig")),
+                ],
+            ),
+        ],
+    }
+}
+
+fn rule_config() -> Schema {
+    let schedule_field = || {
+        Field::new(
+            "schedule",
+            Schema::Object(vec![
+                Field::new("days", one_or_some(Schema::Enum(SCHEDULE_DAYS))),
+                Field::new("time", Schema::String),
+                Field::new("timezone", Schema::String),
+            ]),
+        )
+    };
+    let masks_field =
+        || Field::required("masks", one_or_some(reference("NetLocationMask"))).alias(&["mask"]);
+    Schema::Tagged {
+        tag: "action",
+        variants: vec![
+            Variant::new(
+                "allow",
+                vec![
+                    Field::new("name", Schema::String),
+                    masks_field(),
+                    Field::new("mask_mode", Schema::Enum(MASK_MODES)),
+                    schedule_field(),
+                    Field::new("override_address", Schema::String),
+                    Field::required("client_proxies", one_or_some(reference("ClientSelection")))
+                        .alias(&["client_proxy"]),
+                    Field::new("sticky", Schema::Enum(STICKY_MODES)),
+                    Field::new("balance", Schema::Enum(BALANCE_MODES)),
+                    Field::new(
+                        "select_by",
+                        Schema::Object(vec![
+                            Field::required("key", Schema::Enum(SELECT_KEYS)),
+                            Field::required(
+                                "groups",
+                                Schema::Map(Box::new(one_or_some(reference("ClientSelection")))),
+                            ),
+                        ]),
+                    ),
+                ],
+            ),
+            Variant::new(
+                "block",
+                vec![
+                    Field::new("name", Schema::String),
+                    masks_field(),
+                    Field::new("mask_mode", Schema::Enum(MASK_MODES)),
+                    schedule_field(),
+                ],
+            ),
+        ],
+    }
+}
+
+pub fn definitions() -> HashMap<&'static str, Schema> {
+    let mut websocket_server_fields = websocket_common_fields();
+    websocket_server_fields.extend([
+        Field::new("response_headers", string_map()),
+        Field::required("protocol", reference("ServerProxyConfig")),
+        override_rules_field(),
+    ]);
+
+    HashMap::from([
+        (
+            "Config",
+            Schema::AnyOf(vec![
+                reference("ServerConfig"),
+                Schema::Object(vec![
+                    Field::required("client_group", Schema::String),
+                    Field::required("client_proxies", one_or_some(reference("ClientSelection")))
+                        .alias(&["client_proxy"]),
+                ]),
+                Schema::Object(vec![
+                    Field::required("rule_group", Schema::String),
+                    Field::required("rules", one_or_some(reference("RuleSelection")))
+                        .alias(&["rule"]),
+                ]),
+                Schema::Object(vec![Field::required(
+                    "defaults",
+                    Schema::Object(vec![
+                        Field::new("server", Schema::Partial("ServerConfig")),
+                        Field::new("client", Schema::Partial("ClientConfig")),
+                    ]),
+                )]),
+            ]),
+        ),
+        (
+            "ServerConfig",
+            Schema::Object(vec![
+                Field::new("name", Schema::String),
+                Field::new("address", one_or_some(Schema::String)),
+                Field::new("path", one_or_some(Schema::String)),
+                Field::required("protocol", reference("ServerProxyConfig")),
+                Field::new("transport", one_or_some(Schema::Enum(TRANSPORTS)))
+                    .alias(&["transports"]),
+                Field::new("tcp_settings", reference("TcpConfig")),
+                Field::new(
+                    "quic_settings",
+                    Schema::Object(vec![
+                        Field::required("cert", Schema::String),
+                        Field::required("key", Schema::String),
+                        alpn_protocols_field(),
+                        Field::new("min_tls_version", Schema::String),
+                        Field::new("cipher_suites", one_or_some(Schema::String))
+                            .alias(&["cipher_suite"]),
+                        Field::new("client_ca", Schema::String),
+                        Field::new("require_client_cert", Schema::Boolean),
+                        Field::new("udp_datagrams", Schema::Boolean),
+                        Field::new("oversized_datagrams", Schema::Enum(OVERSIZED_DATAGRAMS)),
+                    ]),
+                ),
+                Field::new("mux_settings", reference("MuxConfig")),
+                Field::new(
+                    "udp_settings",
+                    Schema::Object(vec![
+                        Field::new("nat", Schema::Enum(NAT_TYPES)),
+                        Field::new("idle_timeout_secs", Schema::Integer),
+                        Field::new("max_sessions", Schema::Integer),
+                        Field::new("batch_size", Schema::Integer),
+                    ]),
+                ),
+                Field::new("resolver_settings", reference("ResolverConfig")),
+                Field::new(
+                    "admin_settings",
+                    Schema::Object(vec![
+                        Field::new("address", Schema::String),
+                        Field::new("path", Schema::String),
+                    ]),
+                ),
+                Field::new(
+                    "auth_ban_settings",
+                    Schema::Object(vec![
+                        Field::new("max_auth_failures", Schema::Integer),
+                        Field::new("window_secs", Schema::Integer).alias(&["window"]),
+                        Field::new("ban_duration_secs", Schema::Integer).alias(&["ban_duration"]),
+                    ]),
+                ),
+                Field::new("allow_sources", one_or_some(Schema::String)).alias(&["allow_source"]),
+                Field::new("deny_sources", one_or_some(Schema::String)).alias(&["deny_source"]),
+                Field::new("dscp", Schema::Integer),
+                Field::new("backlog", Schema::Integer),
+                Field::new(
+                    "quota_settings",
+                    Schema::Object(vec![
+                        Field::new("state_file", Schema::String),
+                        Field::new("save_interval_secs", Schema::Integer),
+                        Field::new("period_days", Schema::Integer),
+                    ]),
+                ),
+                Field::new(
+                    "stats_settings",
+                    Schema::Object(vec![
+                        Field::required("stats_file", Schema::String),
+                        Field::new("save_interval_secs", Schema::Integer),
+                    ]),
+                ),
+                Field::new(
+                    "reaper_settings",
+                    Schema::Object(vec![
+                        Field::new("interval_secs", Schema::Integer),
+                        Field::new("max_lifetime_secs", Schema::Integer),
+                        Field::new("max_idle_secs", Schema::Integer),
+                    ]),
+                ),
+                Field::new(
+                    "metrics_settings",
+                    Schema::Object(vec![Field::new("address", Schema::String)]),
+                ),
+                Field::new(
+                    "telemetry_settings",
+                    Schema::Object(vec![
+                        Field::required("endpoint", Schema::String),
+                        Field::new("sample_rate", Schema::Number),
+                        Field::new("service_name", Schema::String),
+                    ]),
+                ),
+                Field::new(
+                    "health_settings",
+                    Schema::Object(vec![
+                        Field::required("address", Schema::String),
+                        Field::new("upstream", Schema::String),
+                        Field::new("upstream_timeout_secs", Schema::Integer),
+                    ]),
+                ),
+                Field::new(
+                    "runtime_settings",
+                    Schema::Object(vec![
+                        Field::new("flavor", Schema::Enum(RUNTIME_FLAVORS)),
+                        Field::new("worker_threads", Schema::Integer),
+                        Field::new("max_blocking_threads", Schema::Integer),
+                        Field::new("thread_name", Schema::String),
+                        Field::new("thread_stack_size", Schema::Integer),
+                    ]),
+                ),
+                Field::new(
+                    "run_as",
+                    Schema::Object(vec![
+                        Field::required(
+                            "user",
+                            Schema::AnyOf(vec![Schema::Integer, Schema::String]),
+                        ),
+                        Field::new(
+                            "group",
+                            Schema::AnyOf(vec![Schema::Integer, Schema::String]),
+                        ),
+                    ]),
+                ),
+                Field::new("rules", one_or_some(reference("RuleSelection"))).alias(&["rule"]),
+            ]),
+        ),
+        (
+            "TcpConfig",
+            Schema::Object(vec![
+                Field::new("no_delay", Schema::Boolean),
+                Field::new("reset_on_failure", Schema::Boolean),
+                Field::new("happy_eyeballs", Schema::Boolean),
+                Field::new(
+                    "write_coalescing",
+                    Schema::Object(vec![
+                        Field::new("max_bytes", Schema::Integer),
+                        Field::new("max_delay_ms", Schema::Integer),
+                    ]),
+                ),
+            ]),
+        ),
+        (
+            "ResolverConfig",
+            Schema::Object(vec![
+                Field::new("timeout_secs", Schema::Integer),
+                Field::new("attempts", Schema::Integer),
+                Field::new(
+                    "dot_servers",
+                    one_or_some(Schema::Object(vec![
+                        Field::new("address", Schema::String),
+                        Field::new("sni_hostname", Schema::String),
+                        Field::new("ca_cert", Schema::String),
+                        Field::new("pin_sha256", one_or_some(Schema::String)),
+                    ])),
+                )
+                .alias(&["dot_server"]),
+            ]),
+        ),
+        (
+            "MuxConfig",
+            Schema::Object(vec![
+                Field::new("max_streams", Schema::Integer),
+                Field::new("idle_timeout_secs", Schema::Integer),
+            ]),
+        ),
+        ("ShadowsocksConfig", Schema::Object(shadowsocks_fields())),
+        (
+            "TlsServerConfig",
+            Schema::Object(vec![
+                Field::new("cert", Schema::String),
+                Field::new("key", Schema::String),
+                Field::new(
+                    "acme",
+                    Schema::Object(vec![
+                        Field::new("directory_url", Schema::String),
+                        Field::new("email", Schema::String),
+                        Field::required("domains", one_or_some(Schema::String)).alias(&["domain"]),
+                        Field::required("cache_dir", Schema::String),
+                    ]),
+                ),
+                Field::new(
+                    "ocsp_stapling",
+                    Schema::Object(vec![
+                        Field::new("response_file", Schema::String),
+                        Field::new("fetch", Schema::Boolean),
+                    ]),
+                ),
+                Field::new("client_ca", Schema::String),
+                Field::new("require_client_cert", Schema::Boolean),
+                Field::new(
+                    "client_identities",
+                    one_or_some(Schema::Object(vec![
+                        Field::required("identity", Schema::String),
+                        override_rules_field(),
+                    ])),
+                )
+                .alias(&["client_identity"]),
+                alpn_protocols_field(),
+                Field::new("unknown_alpn", Schema::String),
+                Field::new("min_tls_version", Schema::String),
+                Field::new("cipher_suites", one_or_some(Schema::String)).alias(&["cipher_suite"]),
+                Field::new("alpn_fallbacks", Schema::Map(Box::new(Schema::String)))
+                    .alias(&["alpn_fallback"]),
+                Field::new(
+                    "alpn_override_rules",
+                    Schema::Map(Box::new(one_or_some(reference("RuleSelection")))),
+                ),
+                Field::required("protocol", reference("ServerProxyConfig")),
+                override_rules_field(),
+            ]),
+        ),
+        (
+            "WebsocketServerConfig",
+            Schema::Object(websocket_server_fields),
+        ),
+        (
+            "WebsocketCompressionConfig",
+            Schema::Object(vec![
+                Field::new("server_no_context_takeover", Schema::Boolean),
+                Field::new("client_no_context_takeover", Schema::Boolean),
+                Field::new("server_max_window_bits", Schema::Integer),
+                Field::new("client_max_window_bits", Schema::Integer),
+                Field::new("max_decompressed_size", Schema::Integer),
+            ]),
+        ),
+        ("ServerProxyConfig", server_proxy_config()),
+        (
+            "ClientConfig",
+            Schema::Object(vec![
+                Field::new("bind_interface", Schema::String),
+                Field::new("address", Schema::String),
+                Field::required("protocol", reference("ClientProxyConfig")),
+                Field::new("transport", Schema::Enum(TRANSPORTS)),
+                Field::new("tcp_settings", reference("TcpConfig")),
+                Field::new(
+                    "quic_settings",
+                    Schema::Object(vec![
+                        Field::new("verify", Schema::Boolean),
+                        Field::new("ca_cert", Schema::String),
+                        Field::new("system_roots", Schema::Boolean),
+                        Field::new("sni_hostname", Schema::String),
+                        alpn_protocols_field(),
+                        Field::new("udp_datagrams", Schema::Boolean),
+                        Field::new("oversized_datagrams", Schema::Enum(OVERSIZED_DATAGRAMS)),
+                    ]),
+                ),
+                Field::new("mux_settings", reference("MuxConfig")),
+                Field::new("ip_preference", Schema::Enum(IP_PREFERENCES)),
+                Field::new("bind_port", Schema::Integer),
+                Field::new("reuse_port", Schema::Boolean),
+                Field::new("resolve", Schema::Enum(RESOLVE_MODES)),
+                Field::new("dscp", Schema::Integer),
+                Field::new("address_resolver_settings", reference("ResolverConfig")),
+            ]),
+        ),
+        (
+            "ClientSelection",
+            Schema::AnyOf(vec![Schema::String, reference("ClientConfig")]),
+        ),
+        ("ClientProxyConfig", client_proxy_config()),
+        (
+            "RuleSelection",
+            Schema::AnyOf(vec![Schema::String, reference("RuleConfig")]),
+        ),
+        ("RuleConfig", rule_config()),
+        // An IP with an optional netmask or a hostname, and an optional port, eg. 10.0.0.0/8:443.
+        ("NetLocationMask", Schema::String),
+    ])
+}
+
+// A config file is either a list of configs, or a single server config.
+pub fn config_file_schema() -> Schema {
+    Schema::AnyOf(vec![
+        Schema::List(Box::new(reference("Config"))),
+        reference("ServerConfig"),
+    ])
+}
+
+// A key in a value that isn't part of the schema.
+pub struct UnknownField {
+    // The key's path in the value, like [0].protocol.typo.
+    pub path: String,
+    pub description: String,
+}
+
+// Returns each key in the value that isn't part of the schema.
+pub fn find_unknown_fields(schema: &Schema, value: &Value) -> Vec<UnknownField> {
+    let definitions = definitions();
+    let mut unknown_fields = vec![];
+    find_unknown_fields_inner(schema, value, "", &definitions, &mut unknown_fields);
+    unknown_fields
+}
+
+fn find_unknown_fields_inner(
+    schema: &Schema,
+    value: &Value,
+    path: &str,
+    definitions: &HashMap<&'static str, Schema>,
+    unknown_fields: &mut Vec<UnknownField>,
+) {
+    match schema {
+        Schema::String | Schema::Integer | Schema::Number | Schema::Boolean | Schema::Enum(_) => (),
+        Schema::Object(fields) => {
+            check_fields(fields, None, value, path, definitions, unknown_fields);
+        }
+        Schema::Map(value_schema) => {
+            if let Value::Mapping(mapping) = value {
+                for (key, item) in mapping.iter() {
+                    let item_path = join_path(path, &key_to_string(key));
+                    find_unknown_fields_inner(
+                        value_schema,
+                        item,
+                        &item_path,
+                        definitions,
+                        unknown_fields,
+                    );
+                }
+            }
+        }
+        Schema::List(item_schema) | Schema::OneOrSome(item_schema) => match value {
+            Value::Sequence(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, i);
+                    find_unknown_fields_inner(
+                        item_schema,
+                        item,
+                        &item_path,
+                        definitions,
+                        unknown_fields,
+                    );
+                }
+            }
+            _ => {
+                if let Schema::OneOrSome(_) = schema {
+                    find_unknown_fields_inner(
+                        item_schema,
+                        value,
+                        path,
+                        definitions,
+                        unknown_fields,
+                    );
+                }
+            }
+        },
+        Schema::Tagged { tag, variants } => {
+            let tag_value = match value.get(*tag).and_then(Value::as_str) {
+                Some(s) => s,
+                // serde will report the missing tag.
+                None => return,
+            };
+            if let Some(variant) = variants.iter().find(|v| v.matches(tag_value)) {
+                check_fields(
+                    &variant.fields,
+                    Some(tag),
+                    value,
+                    path,
+                    definitions,
+                    unknown_fields,
+                );
+            }
+        }
+        Schema::AnyOf(schemas) => {
+            if let Some(schema) = best_alternative(schemas, value, definitions) {
+                find_unknown_fields_inner(schema, value, path, definitions, unknown_fields);
+            }
+        }
+        Schema::Ref(name) | Schema::Partial(name) => {
+            find_unknown_fields_inner(&definitions[name], value, path, definitions, unknown_fields);
+        }
+    }
+}
+
+// Returns the alternative that fits the value best, so that a typo in a required field doesn't
+// cause every field to be reported.
+fn best_alternative<'a>(
+    schemas: &'a [Schema],
+    value: &Value,
+    definitions: &'a HashMap<&'static str, Schema>,
+) -> Option<&'a Schema> {
+    let mut best: Option<(usize, &Schema)> = None;
+    for schema in schemas.iter() {
+        let resolved = resolve(schema, definitions);
+        if !is_compatible(resolved, value, definitions) {
+            continue;
+        }
+        let mut candidate_fields = vec![];
+        find_unknown_fields_inner(resolved, value, "", definitions, &mut candidate_fields);
+        let score = candidate_fields.len() + count_missing_fields(resolved, value, definitions);
+        if best.as_ref().is_none_or(|(s, _)| score < *s) {
+            best = Some((score, schema));
+        }
+    }
+    best.map(|(_, schema)| schema)
+}
+
+// Adds the fields of `defaults` to each part of the value that the schema describes as the
+// definition `name`, when that part doesn't set them. When both set a field to an object, the
+// objects are merged the same way, and otherwise the value's own setting is kept.
+pub fn apply_defaults(schema: &Schema, value: &mut Value, name: &'static str, defaults: &Value) {
+    let definitions = definitions();
+    apply_defaults_inner(schema, value, name, defaults, &definitions);
+}
+
+fn apply_defaults_inner(
+    schema: &Schema,
+    value: &mut Value,
+    name: &'static str,
+    defaults: &Value,
+    definitions: &HashMap<&'static str, Schema>,
+) {
+    match schema {
+        // Defaults aren't applied to other defaults.
+        Schema::String
+        | Schema::Integer
+        | Schema::Number
+        | Schema::Boolean
+        | Schema::Enum(_)
+        | Schema::Partial(_) => (),
+        Schema::Object(fields) => {
+            apply_defaults_to_fields(fields, value, name, defaults, definitions);
+        }
+        Schema::Map(value_schema) => {
+            if let Value::Mapping(mapping) = value {
+                for (_, item) in mapping.iter_mut() {
+                    apply_defaults_inner(value_schema, item, name, defaults, definitions);
+                }
+            }
+        }
+        Schema::List(item_schema) | Schema::OneOrSome(item_schema) => match value {
+            Value::Sequence(items) => {
+                for item in items.iter_mut() {
+                    apply_defaults_inner(item_schema, item, name, defaults, definitions);
+                }
+            }
+            _ => {
+                if let Schema::OneOrSome(_) = schema {
+                    apply_defaults_inner(item_schema, value, name, defaults, definitions);
+                }
+            }
+        },
+        Schema::Tagged { tag, variants } => {
+            let variant = match value.get(*tag).and_then(Value::as_str) {
+                Some(tag_value) => variants.iter().find(|v| v.matches(tag_value)),
+                None => None,
+            };
+            if let Some(variant) = variant {
+                apply_defaults_to_fields(&variant.fields, value, name, defaults, definitions);
+            }
+        }
+        Schema::AnyOf(schemas) => {
+            if let Some(schema) = best_alternative(schemas, value, definitions) {
+                apply_defaults_inner(schema, value, name, defaults, definitions);
+            }
+        }
+        Schema::Ref(ref_name) => {
+            let schema = &definitions[ref_name];
+            if *ref_name == name {
+                merge_defaults(schema, value, defaults, definitions);
+            }
+            apply_defaults_inner(schema, value, name, defaults, definitions);
+        }
+    }
+}
+
+fn apply_defaults_to_fields(
+    fields: &[Field],
+    value: &mut Value,
+    name: &'static str,
+    defaults: &Value,
+    definitions: &HashMap<&'static str, Schema>,
+) {
+    if let Value::Mapping(mapping) = value {
+        for (key, item) in mapping.iter_mut() {
+            let key = key_to_string(key);
+            if let Some(field) = fields.iter().find(|field| field.matches(&key)) {
+                apply_defaults_inner(&field.schema, item, name, defaults, definitions);
+            }
+        }
+    }
+}
+
+fn merge_defaults(
+    schema: &Schema,
+    value: &mut Value,
+    defaults: &Value,
+    definitions: &HashMap<&'static str, Schema>,
+) {
+    let fields = match resolve(schema, definitions) {
+        Schema::Object(fields) => fields,
+        // Only objects are merged, so eg. a protocol is always taken as a whole.
+        _ => return,
+    };
+    let (mapping, default_mapping) = match (value, defaults) {
+        (Value::Mapping(mapping), Value::Mapping(default_mapping)) => (mapping, default_mapping),
+        _ => return,
+    };
+    for (default_key, default_item) in default_mapping.iter() {
+        let field = fields
+            .iter()
+            .find(|field| field.matches(&key_to_string(default_key)));
+        // A field can be set using any of its aliases.
+        let item = mapping
+            .iter_mut()
+            .find(|(key, _)| match field {
+                Some(field) => field.matches(&key_to_string(key)),
+                None => *key == default_key,
+            })
+            .map(|(_, item)| item);
+        match (item, field) {
+            (Some(item), Some(field)) => {
+                merge_defaults(&field.schema, item, default_item, definitions);
+            }
+            (Some(_), None) => (),
+            (None, _) => {
+                mapping.insert(default_key.clone(), default_item.clone());
+            }
+        }
+    }
+}
+
+fn check_fields(
+    fields: &[Field],
+    tag: Option<&str>,
+    value: &Value,
+    path: &str,
+    definitions: &HashMap<&'static str, Schema>,
+    unknown_fields: &mut Vec<UnknownField>,
+) {
+    let mapping = match value {
+        Value::Mapping(m) => m,
+        _ => return,
+    };
+    for (key, item) in mapping.iter() {
+        let key = key_to_string(key);
+        if tag == Some(key.as_str()) {
+            continue;
+        }
+        match fields.iter().find(|field| field.matches(&key)) {
+            Some(field) => {
+                find_unknown_fields_inner(
+                    &field.schema,
+                    item,
+                    &join_path(path, &key),
+                    definitions,
+                    unknown_fields,
+                );
+            }
+            None => {
+                let location = if path.is_empty() { "top level" } else { path };
+                unknown_fields.push(UnknownField {
+                    path: join_path(path, &key),
+                    description: format!("unknown field `{}` in {}", key, location),
+                });
+            }
+        }
+    }
+}
+
+fn resolve<'a>(schema: &'a Schema, definitions: &'a HashMap<&'static str, Schema>) -> &'a Schema {
+    match schema {
+        Schema::Ref(name) => resolve(&definitions[name], definitions),
+        _ => schema,
+    }
+}
+
+fn is_compatible(
+    schema: &Schema,
+    value: &Value,
+    definitions: &HashMap<&'static str, Schema>,
+) -> bool {
+    match resolve(schema, definitions) {
+        Schema::Object(_) | Schema::Map(_) | Schema::Tagged { .. } | Schema::Partial(_) => {
+            value.is_mapping()
+        }
+        Schema::List(_) => value.is_sequence(),
+        Schema::String | Schema::Integer | Schema::Number | Schema::Boolean | Schema::Enum(_) => {
+            !value.is_mapping() && !value.is_sequence()
+        }
+        Schema::OneOrSome(_) | Schema::AnyOf(_) | Schema::Ref(_) => true,
+    }
+}
+
+fn count_missing_fields(
+    schema: &Schema,
+    value: &Value,
+    definitions: &HashMap<&'static str, Schema>,
+) -> usize {
+    let fields = match resolve(schema, definitions) {
+        Schema::Object(fields) => fields,
+        _ => return 0,
+    };
+    fields
+        .iter()
+        .filter(|field| {
+            field.required
+                && !std::iter::once(&field.name)
+                    .chain(field.aliases.iter())
+                    .any(|name| value.get(*name).is_some())
+        })
+        .count()
+}
+
+fn key_to_string(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        _ => serde_yaml::to_string(key)
+            .map(|s| s.trim_end().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+// Returns a JSON Schema for config files, for editors and linters.
+pub fn json_schema() -> serde_json::Value {
+    let definitions = definitions()
+        .iter()
+        .map(|(name, schema)| (name.to_string(), to_json_schema(schema)))
+        .collect::<serde_json::Map<_, _>>();
+    let mut root = match to_json_schema(&config_file_schema()) {
+        serde_json::Value::Object(m) => m,
+        _ => unreachable!(),
+    };
+    root.insert(
+        "$schema".to_string(),
+        json!("http://json-schema.org/draft-07/schema#"),
+    );
+    root.insert("title".to_string(), json!("shoes config"));
+    root.insert(
+        "definitions".to_string(),
+        serde_json::Value::Object(definitions),
+    );
+    serde_json::Value::Object(root)
+}
+
+fn fields_to_json_schema(fields: &[Field], tag: Option<(&str, &Variant)>) -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    let mut required = vec![];
+    let mut alternatives = vec![];
+
+    if let Some((tag, variant)) = tag {
+        let tag_values = std::iter::once(&variant.name)
+            .chain(variant.aliases.iter())
+            .collect::<Vec<_>>();
+        properties.insert(tag.to_string(), json!({ "enum": tag_values }));
+        required.push(tag.to_string());
+    }
+
+    for field in fields.iter() {
+        let schema = to_json_schema(&field.schema);
+        for alias in field.aliases.iter() {
+            let mut alias_schema = schema.clone();
+            if let serde_json::Value::Object(ref mut m) = alias_schema {
+                m.insert(
+                    "description".to_string(),
+                    json!(format!("Alias of {}", field.name)),
+                );
+            }
+            properties.insert(alias.to_string(), alias_schema);
+        }
+        properties.insert(field.name.to_string(), schema);
+
+        if field.required {
+            if field.aliases.is_empty() {
+                required.push(field.name.to_string());
+            } else {
+                let names = std::iter::once(&field.name).chain(field.aliases.iter());
+                let any_of = names
+                    .map(|name| json!({ "required": [name] }))
+                    .collect::<Vec<_>>();
+                alternatives.push(json!({ "anyOf": any_of }));
+            }
+        }
+    }
+
+    let mut schema = json!({
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    });
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    if !alternatives.is_empty() {
+        schema["allOf"] = json!(alternatives);
+    }
+    schema
+}
+
+fn to_json_schema(schema: &Schema) -> serde_json::Value {
+    match schema {
+        Schema::String => json!({ "type": "string" }),
+        Schema::Integer => json!({ "type": "integer", "minimum": 0 }),
+        Schema::Number => json!({ "type": "number", "minimum": 0 }),
+        Schema::Boolean => json!({ "type": "boolean" }),
+        Schema::Enum(values) => json!({ "type": "string", "enum": values }),
+        Schema::Object(fields) => fields_to_json_schema(fields, None),
+        Schema::Map(value_schema) => json!({
+            "type": "object",
+            "additionalProperties": to_json_schema(value_schema),
+        }),
+        Schema::List(item_schema) => json!({
+            "type": "array",
+            "items": to_json_schema(item_schema),
+        }),
+        Schema::OneOrSome(item_schema) => {
+            let item_schema = to_json_schema(item_schema);
+            json!({
+                "anyOf": [item_schema, { "type": "array", "items": item_schema }],
+            })
+        }
+        Schema::Tagged { tag, variants } => {
+            let variants = variants
+                .iter()
+                .map(|variant| fields_to_json_schema(&variant.fields, Some((tag, variant))))
+                .collect::<Vec<_>>();
+            json!({ "oneOf": variants })
+        }
+        Schema::AnyOf(schemas) => json!({
+            "anyOf": schemas.iter().map(to_json_schema).collect::<Vec<_>>(),
+        }),
+        Schema::Ref(name) => json!({ "$ref": format!("#/definitions/{}", name) }),
+        // JSON Schema can't make the required fields of a definition optional.
+        Schema::Partial(_) => json!({ "type": "object" }),
+    }
+}
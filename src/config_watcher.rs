@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+
+use crate::connection_registry::connection_registry;
+use crate::domain_set::domain_set_paths;
+
+// Editors often write a file in several steps, so changes are applied once they stop for this
+// long.
+const SETTLE_DURATION: Duration = Duration::from_millis(500);
+
+fn watch_error(e: notify::Error) -> std::io::Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        _ => std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("failed to watch config files: {}", e),
+        ),
+    }
+}
+
+// The directory and absolute path of the file. The directory is watched rather than the file,
+// since editors and deployment tools often replace the file instead of writing to it.
+fn watch_location(path: &Path) -> std::io::Result<(PathBuf, PathBuf)> {
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("not a config file path: {}", path.display()),
+        )
+    })?;
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = std::fs::canonicalize(dir)?;
+    let path = dir.join(file_name);
+    Ok((dir, path))
+}
+
+// Starts watching the file's directory if it isn't watched yet.
+fn watch_file(
+    watcher: &mut impl Watcher,
+    dirs: &mut HashSet<PathBuf>,
+    watched_paths: &Mutex<HashSet<PathBuf>>,
+    path: &Path,
+) -> std::io::Result<()> {
+    let (dir, path) = watch_location(path)?;
+    if !dirs.contains(&dir) {
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+        dirs.insert(dir);
+    }
+    watched_paths.lock().insert(path);
+    Ok(())
+}
+
+// Domain set files are loaded with the config, so they're watched once the config has loaded
+// them.
+fn watch_domain_sets(
+    watcher: &mut impl Watcher,
+    dirs: &mut HashSet<PathBuf>,
+    watched_paths: &Mutex<HashSet<PathBuf>>,
+) {
+    for path in domain_set_paths() {
+        if watched_paths.lock().contains(&path) {
+            continue;
+        }
+        if let Err(e) = watch_file(watcher, dirs, watched_paths, &path) {
+            warn!("Failed to watch domain set {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Reloads the config when any of the files, or the domain set files that it uses, change. A
+// config that fails to load is logged and the running config is kept.
+pub fn start_config_watcher(paths: &[PathBuf]) -> std::io::Result<()> {
+    let mut dirs = HashSet::new();
+    let watched_paths = Arc::new(Mutex::new(HashSet::new()));
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let event_watched_paths = watched_paths.clone();
+    let mut watcher =
+        notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+            Ok(event) => {
+                let is_change = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+                let watched_paths = event_watched_paths.lock();
+                if is_change && event.paths.iter().any(|p| watched_paths.contains(p)) {
+                    let _ = tx.send(());
+                }
+            }
+            Err(e) => warn!("Error while watching config files: {}", e),
+        })
+        .map_err(watch_error)?;
+    for path in paths {
+        watch_file(&mut watcher, &mut dirs, &watched_paths, path)?;
+    }
+    watch_domain_sets(&mut watcher, &mut dirs, &watched_paths);
+
+    tokio::spawn(async move {
+        // The watcher stops when it's dropped.
+        let mut watcher = watcher;
+        while rx.recv().await.is_some() {
+            loop {
+                tokio::time::sleep(SETTLE_DURATION).await;
+                let mut changed = false;
+                while rx.try_recv().is_ok() {
+                    changed = true;
+                }
+                if !changed {
+                    break;
+                }
+            }
+
+            let result = tokio::task::spawn_blocking(|| connection_registry().reload()).await;
+            match result {
+                Ok(Ok(())) => {
+                    info!("Reloaded config after a config file changed");
+                    watch_domain_sets(&mut watcher, &mut dirs, &watched_paths);
+                }
+                Ok(Err(e)) => error!(
+                    "Failed to reload changed config, keeping the running config: {}",
+                    e
+                ),
+                Err(e) => error!(
+                    "Failed to reload changed config, keeping the running config: {}",
+                    e
+                ),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,759 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::IoSlice;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::{ready, FutureExt};
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::address::NetLocation;
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::auth_ban_table::AuthBanTable;
+use crate::bandwidth_stats::bandwidth_stats;
+use crate::client_proxy_selector::{
+    ActiveConnection, ClientProxySelector, ConnectDecision, ConnectRule,
+};
+use crate::config::{ReaperConfig, ServerConfig};
+use crate::metrics::connection_metrics;
+use crate::tcp_client_connector::{EgressAddresses, TcpClientConnector};
+use crate::telemetry::{with_span, ConnectionSpan};
+use crate::udp_session_table::UdpSessionTable;
+use crate::user_quota::user_quotas;
+
+type ConfigLoader = Box<dyn Fn() -> std::io::Result<ServerConfig> + Send + Sync>;
+type ServerReloader = Box<dyn Fn(ServerConfig) -> std::io::Result<()> + Send + Sync>;
+
+// Live state of the running servers, read by the admin server.
+pub struct ConnectionRegistry {
+    next_connection_id: AtomicU64,
+    // Starts the trace ID of every connection, so that the IDs from different processes, eg. each
+    // hop of a proxy chain, don't collide.
+    trace_id_prefix: u32,
+    connections: Mutex<BTreeMap<u64, Arc<ConnectionInfo>>>,
+    // How many connection tasks have panicked.
+    panic_count: AtomicU64,
+    selectors: Mutex<Vec<(String, Arc<ClientProxySelector<TcpClientConnector>>)>>,
+    udp_session_tables: Mutex<Vec<(String, Arc<UdpSessionTable>)>>,
+    auth_ban_tables: Mutex<Vec<(String, Arc<AuthBanTable>)>>,
+    config_loader: Mutex<Option<ConfigLoader>>,
+    server_reloaders: Mutex<Vec<ServerReloader>>,
+}
+
+pub fn connection_registry() -> &'static ConnectionRegistry {
+    static INSTANCE: OnceLock<ConnectionRegistry> = OnceLock::new();
+    INSTANCE.get_or_init(|| {
+        install_panic_hook();
+        ConnectionRegistry {
+            next_connection_id: AtomicU64::new(1),
+            trace_id_prefix: rand::random::<u32>() & 0xffffff,
+            connections: Mutex::new(BTreeMap::new()),
+            panic_count: AtomicU64::new(0),
+            selectors: Mutex::new(vec![]),
+            udp_session_tables: Mutex::new(vec![]),
+            auth_ban_tables: Mutex::new(vec![]),
+            config_loader: Mutex::new(None),
+            server_reloaders: Mutex::new(vec![]),
+        }
+    })
+}
+
+tokio::task_local! {
+    // The trace ID of the connection that the task is running, for handlers that aren't given
+    // its info.
+    static CURRENT_TRACE_ID: String;
+}
+
+pub fn current_trace_id() -> Option<String> {
+    CURRENT_TRACE_ID.try_with(Clone::clone).ok()
+}
+
+thread_local! {
+    // The backtrace of the last panic on this thread, which is logged when a connection task
+    // panicked.
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+// Panics still go to the previous hook, which prints them.
+fn install_panic_hook() {
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        PANIC_BACKTRACE.with(|backtrace| backtrace.replace(Some(Backtrace::force_capture())));
+        previous_hook(panic_info);
+    }));
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn register(
+        &self,
+        server: String,
+        source: String,
+        source_ip: Option<IpAddr>,
+        protocol: String,
+    ) -> ConnectionHandle {
+        self.create_connection(server, source, source_ip, protocol, true)
+    }
+
+    // Creates a connection that is only added to the registry when registered is set. Unregistered
+    // connections aren't listed, and aren't counted in the user quotas, metrics and bandwidth
+    // stats when they close.
+    pub fn create_connection(
+        &self,
+        server: String,
+        source: String,
+        source_ip: Option<IpAddr>,
+        protocol: String,
+        registered: bool,
+    ) -> ConnectionHandle {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let info = Arc::new(ConnectionInfo {
+            id,
+            trace_id: format!("{:06x}-{}", self.trace_id_prefix, id),
+            server,
+            source,
+            source_ip,
+            protocol,
+            start_time: Instant::now(),
+            destination: Mutex::new(None),
+            user: Mutex::new(None),
+            matched_rule: Mutex::new(None),
+            rule_label: Mutex::new(None),
+            client_proxy: Mutex::new(None),
+            egress: Mutex::new(None),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            sent_throughput: ThroughputCounter::new(),
+            received_throughput: ThroughputCounter::new(),
+            last_active_millis: AtomicU64::new(0),
+            closing: AtomicBool::new(false),
+            close_notify: Notify::new(),
+            setup_duration: OnceLock::new(),
+            span: ConnectionSpan::start(),
+            registered,
+        });
+        if registered {
+            self.connections.lock().insert(id, info.clone());
+        }
+        ConnectionHandle { info }
+    }
+
+    pub fn panic_count(&self) -> u64 {
+        self.panic_count.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> Vec<Arc<ConnectionInfo>> {
+        self.connections.lock().values().cloned().collect()
+    }
+
+    // The bytes sent and received by the open connections of a user.
+    pub fn user_bytes(&self, user: &str) -> u64 {
+        self.connections
+            .lock()
+            .values()
+            .filter(|info| info.user.lock().as_deref() == Some(user))
+            .map(|info| info.bytes_sent() + info.bytes_received())
+            .sum()
+    }
+
+    // Replaces any selector previously set with the same label.
+    pub fn set_selector(
+        &self,
+        label: String,
+        selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    ) {
+        let mut selectors = self.selectors.lock();
+        match selectors.iter_mut().find(|(l, _)| *l == label) {
+            Some(entry) => entry.1 = selector,
+            None => selectors.push((label, selector)),
+        }
+    }
+
+    pub fn selectors(&self) -> Vec<(String, Arc<ClientProxySelector<TcpClientConnector>>)> {
+        self.selectors.lock().clone()
+    }
+
+    // Replaces any table previously set with the same label.
+    pub fn set_udp_session_table(&self, label: String, table: Arc<UdpSessionTable>) {
+        let mut tables = self.udp_session_tables.lock();
+        match tables.iter_mut().find(|(l, _)| *l == label) {
+            Some(entry) => entry.1 = table,
+            None => tables.push((label, table)),
+        }
+    }
+
+    pub fn udp_session_tables(&self) -> Vec<(String, Arc<UdpSessionTable>)> {
+        self.udp_session_tables.lock().clone()
+    }
+
+    // Replaces any table previously set with the same label.
+    pub fn set_auth_ban_table(&self, label: String, table: Arc<AuthBanTable>) {
+        let mut tables = self.auth_ban_tables.lock();
+        match tables.iter_mut().find(|(l, _)| *l == label) {
+            Some(entry) => entry.1 = table,
+            None => tables.push((label, table)),
+        }
+    }
+
+    pub fn auth_ban_tables(&self) -> Vec<(String, Arc<AuthBanTable>)> {
+        self.auth_ban_tables.lock().clone()
+    }
+
+    pub fn set_config_loader(&self, loader: ConfigLoader) {
+        self.config_loader.lock().replace(loader);
+    }
+
+    pub fn add_server_reloader(&self, reloader: ServerReloader) {
+        self.server_reloaders.lock().push(reloader);
+    }
+
+    // Loads the config again and applies it to the running servers, which also reads
+    // certificates again.
+    pub fn reload(&self) -> std::io::Result<()> {
+        let config = match *self.config_loader.lock() {
+            Some(ref loader) => loader()?,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "no config source to reload from",
+                ));
+            }
+        };
+
+        let server_reloaders = self.server_reloaders.lock();
+        if server_reloaders.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "no running servers support reloading",
+            ));
+        }
+        for reloader in server_reloaders.iter() {
+            reloader(config.clone())?;
+        }
+        Ok(())
+    }
+}
+
+// How many seconds the throughput of a connection is averaged over.
+const THROUGHPUT_WINDOW_SECS: u64 = 5;
+
+// The bytes relayed in each of the last seconds, for the throughput over a rolling window.
+// Bytes that are added while another thread starts the next second can be lost, which is fine
+// for diagnostics.
+#[derive(Debug)]
+struct ThroughputCounter {
+    // The second after the connection started that each bucket counts.
+    seconds: [AtomicU64; THROUGHPUT_WINDOW_SECS as usize],
+    bytes: [AtomicU64; THROUGHPUT_WINDOW_SECS as usize],
+}
+
+impl ThroughputCounter {
+    fn new() -> Self {
+        Self {
+            seconds: Default::default(),
+            bytes: Default::default(),
+        }
+    }
+
+    fn add(&self, second: u64, amount: u64) {
+        let index = (second % THROUGHPUT_WINDOW_SECS) as usize;
+        if self.seconds[index].load(Ordering::Relaxed) != second
+            && self.seconds[index].swap(second, Ordering::Relaxed) != second
+        {
+            self.bytes[index].store(0, Ordering::Relaxed);
+        }
+        self.bytes[index].fetch_add(amount, Ordering::Relaxed);
+    }
+
+    // The bytes per second since the start of the window, which ends in the current second and
+    // doesn't start before the connection.
+    fn rate(&self, elapsed: Duration) -> u64 {
+        let current_second = elapsed.as_secs();
+        let total = (0..THROUGHPUT_WINDOW_SECS as usize)
+            .filter(|&i| {
+                let second = self.seconds[i].load(Ordering::Relaxed);
+                second <= current_second && second + THROUGHPUT_WINDOW_SECS > current_second
+            })
+            .map(|i| self.bytes[i].load(Ordering::Relaxed))
+            .sum::<u64>();
+        let window_secs = std::cmp::min(
+            elapsed,
+            Duration::from_secs(THROUGHPUT_WINDOW_SECS - 1)
+                + Duration::from_nanos(elapsed.subsec_nanos() as u64),
+        )
+        .as_secs_f64();
+        if window_secs <= 0.0 {
+            return 0;
+        }
+        (total as f64 / window_secs) as u64
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    // Identifies the connection in logs, and to the next hop when the client protocol can send
+    // it. Unlike id, it's unique across processes.
+    pub trace_id: String,
+    // The name of the server that accepted the connection, or its bind location.
+    pub server: String,
+    pub source: String,
+    // None for connections on unix sockets.
+    pub source_ip: Option<IpAddr>,
+    pub protocol: String,
+    pub start_time: Instant,
+    destination: Mutex<Option<NetLocation>>,
+    // The user that the client authenticated as.
+    user: Mutex<Option<String>>,
+    matched_rule: Mutex<Option<String>>,
+    rule_label: Mutex<Option<String>>,
+    // The label of the client proxy that the connection goes through.
+    client_proxy: Mutex<Option<String>>,
+    // The addresses of the last connection to the destination or client proxy.
+    egress: Mutex<Option<EgressAddresses>>,
+    // bytes sent to and received from the remote location.
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    sent_throughput: ThroughputCounter,
+    received_throughput: ThroughputCounter,
+    // when bytes were last relayed, as milliseconds after start_time.
+    last_active_millis: AtomicU64,
+    closing: AtomicBool,
+    close_notify: Notify,
+    // how long it took until the first stream to the destination was set up.
+    setup_duration: OnceLock<Duration>,
+    // Set when the connection is traced.
+    span: Option<Arc<ConnectionSpan>>,
+    registered: bool,
+}
+
+impl ConnectionInfo {
+    // The source along with the trace ID, which log lines about the connection start with.
+    pub fn log_label(&self) -> String {
+        format!("{} #{}", self.source, self.trace_id)
+    }
+
+    // Whether the connection is listed in the registry, which the streams of a multiplexed
+    // connection follow.
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
+    pub fn set_destination(&self, destination: NetLocation) {
+        self.destination.lock().replace(destination);
+    }
+
+    pub fn destination(&self) -> Option<NetLocation> {
+        self.destination.lock().clone()
+    }
+
+    pub fn set_user(&self, user: String) {
+        debug!(
+            "[{}] {} authenticated as {}",
+            self.server,
+            self.log_label(),
+            user
+        );
+        self.user.lock().replace(user);
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().clone()
+    }
+
+    pub fn set_matched_rule(&self, matched_rule: String) {
+        self.matched_rule.lock().replace(matched_rule);
+    }
+
+    // Records the rule that a location matched, where None means no rule matched and the
+    // connection is blocked.
+    pub fn record_judgement(
+        &self,
+        location: &NetLocation,
+        rule: Option<&ConnectRule<TcpClientConnector>>,
+        decision: &ConnectDecision<TcpClientConnector>,
+    ) {
+        if let ConnectDecision::Allow { client_proxy, .. } = decision {
+            self.set_client_proxy(client_proxy.label().to_string());
+        }
+        match rule {
+            Some(rule) => {
+                match decision {
+                    ConnectDecision::Allow {
+                        remote_location, ..
+                    } if remote_location != location => {
+                        debug!(
+                            "[{}] {} -> {} matched {}, connecting to {}",
+                            self.server,
+                            self.log_label(),
+                            location,
+                            rule.label(),
+                            remote_location
+                        );
+                    }
+                    ConnectDecision::Allow { .. } => {
+                        debug!(
+                            "[{}] {} -> {} matched {}",
+                            self.server,
+                            self.log_label(),
+                            location,
+                            rule.label()
+                        );
+                    }
+                    ConnectDecision::Block => {
+                        debug!(
+                            "[{}] {} -> {} matched {}, blocking",
+                            self.server,
+                            self.log_label(),
+                            location,
+                            rule.label()
+                        );
+                    }
+                }
+                self.set_matched_rule(format!("{}: {}", rule.label(), rule));
+                self.rule_label.lock().replace(rule.label());
+            }
+            None => {
+                debug!(
+                    "[{}] {} -> {} didn't match any rule, blocking",
+                    self.server,
+                    self.log_label(),
+                    location
+                );
+            }
+        }
+    }
+
+    pub fn matched_rule(&self) -> Option<String> {
+        self.matched_rule.lock().clone()
+    }
+
+    pub fn rule_label(&self) -> Option<String> {
+        self.rule_label.lock().clone()
+    }
+
+    pub fn set_client_proxy(&self, label: String) {
+        self.client_proxy.lock().replace(label);
+    }
+
+    pub fn client_proxy(&self) -> Option<String> {
+        self.client_proxy.lock().clone()
+    }
+
+    pub fn set_egress(&self, egress: EgressAddresses) {
+        self.egress.lock().replace(egress);
+    }
+
+    pub fn egress(&self) -> Option<EgressAddresses> {
+        *self.egress.lock()
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    // The bytes per second sent over the last few seconds.
+    pub fn send_rate(&self) -> u64 {
+        self.sent_throughput.rate(self.start_time.elapsed())
+    }
+
+    // The bytes per second received over the last few seconds.
+    pub fn receive_rate(&self) -> u64 {
+        self.received_throughput.rate(self.start_time.elapsed())
+    }
+
+    fn add_bytes_sent(&self, amount: u64) {
+        self.bytes_sent.fetch_add(amount, Ordering::Relaxed);
+        let elapsed = self.start_time.elapsed();
+        self.sent_throughput.add(elapsed.as_secs(), amount);
+        self.last_active_millis
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn add_bytes_received(&self, amount: u64) {
+        self.bytes_received.fetch_add(amount, Ordering::Relaxed);
+        let elapsed = self.start_time.elapsed();
+        self.received_throughput.add(elapsed.as_secs(), amount);
+        self.last_active_millis
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    // Only the first setup is kept, since a connection can set up more than one stream, eg.
+    // for each forwarded HTTP request.
+    pub fn mark_setup_complete(&self) {
+        let _ = self.setup_duration.set(self.start_time.elapsed());
+    }
+
+    pub fn setup_duration(&self) -> Option<Duration> {
+        self.setup_duration.get().copied()
+    }
+
+    pub fn mark_active(&self) {
+        self.last_active_millis.store(
+            self.start_time.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    // How long it's been since bytes were relayed, or since the connection was accepted.
+    pub fn idle_duration(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active_millis.load(Ordering::Relaxed));
+        self.start_time.elapsed().saturating_sub(last_active)
+    }
+
+    // Closes the connection, returning false if it was already being closed.
+    pub fn close(&self) -> bool {
+        if self.closing.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        // notify_one stores a permit, so the connection is closed even if it isn't waiting yet.
+        self.close_notify.notify_one();
+        true
+    }
+
+    // Runs the connection until it finishes, is closed, or panics. The streams used by the
+    // future are dropped, and so closed, when it's closed or panics. Connection futures are
+    // large, so callers should box them to keep them off the stack. The connection's span, if
+    // it's traced, ends here.
+    pub async fn run_until_closed<F>(&self, future: F) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        // The future is dropped after it panics, so its state is never seen again.
+        let future = CURRENT_TRACE_ID.scope(
+            self.trace_id.clone(),
+            with_span(self.span.clone(), AssertUnwindSafe(future).catch_unwind()),
+        );
+        let result = tokio::select! {
+            result = future => match result {
+                Ok(result) => result,
+                Err(payload) => {
+                    connection_registry()
+                        .panic_count
+                        .fetch_add(1, Ordering::Relaxed);
+                    let backtrace = PANIC_BACKTRACE.with(|backtrace| backtrace.take());
+                    error!(
+                        "[{}] {} connection {} from {} panicked: {}\n{}",
+                        self.server,
+                        self.protocol,
+                        self.id,
+                        self.log_label(),
+                        panic_message(payload.as_ref()),
+                        backtrace.map_or_else(|| "no backtrace".to_string(), |b| b.to_string())
+                    );
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        "connection task panicked",
+                    ))
+                }
+            },
+            _ = self.close_notify.notified() => {
+                debug!("[{}] {} closed", self.server, self.log_label());
+                Ok(())
+            }
+        };
+        if let Some(ref span) = self.span {
+            span.finish(self, result.as_ref().err());
+        }
+        result
+    }
+}
+
+// Closes any connection that has been open for longer than max_lifetime_secs or idle for longer
+// than max_idle_secs, as a safety net for handlers that don't time out.
+pub fn start_connection_reaper(config: ReaperConfig) {
+    let ReaperConfig {
+        interval_secs,
+        max_lifetime_secs,
+        max_idle_secs,
+    } = config;
+    let max_lifetime = max_lifetime_secs.map(Duration::from_secs);
+    let max_idle = max_idle_secs.map(Duration::from_secs);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            for info in connection_registry().connections() {
+                let duration = info.duration();
+                let idle_duration = info.idle_duration();
+                let reason = if max_lifetime.is_some_and(|max| duration >= max) {
+                    "open"
+                } else if max_idle.is_some_and(|max| idle_duration >= max) {
+                    "idle"
+                } else {
+                    continue;
+                };
+                if info.close() {
+                    warn!(
+                        "[{}] Reaping {} connection {} from {} to {}, {} for too long (open for {:?}, idle for {:?})",
+                        info.server,
+                        info.protocol,
+                        info.id,
+                        info.log_label(),
+                        info.destination()
+                            .map_or_else(|| "unknown".to_string(), |d| d.to_string()),
+                        reason,
+                        duration,
+                        idle_duration
+                    );
+                }
+            }
+        }
+    });
+}
+
+// Unregisters the connection when dropped.
+pub struct ConnectionHandle {
+    info: Arc<ConnectionInfo>,
+}
+
+impl ConnectionHandle {
+    pub fn info(&self) -> &Arc<ConnectionInfo> {
+        &self.info
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        if !self.info.registered {
+            return;
+        }
+        connection_registry()
+            .connections
+            .lock()
+            .remove(&self.info.id);
+        if let Some(user) = self.info.user() {
+            user_quotas().add_usage(&user, self.info.bytes_sent() + self.info.bytes_received());
+        }
+        connection_metrics().record(&self.info);
+        bandwidth_stats().record(&self.info);
+    }
+}
+
+// Counts the bytes going through a client stream, which counts as an active connection through
+// its client proxy until it's dropped.
+pub struct CountingStream {
+    stream: Box<dyn AsyncStream>,
+    info: Arc<ConnectionInfo>,
+    _active_connection: ActiveConnection,
+}
+
+impl CountingStream {
+    pub fn new(
+        stream: Box<dyn AsyncStream>,
+        info: Arc<ConnectionInfo>,
+        active_connection: ActiveConnection,
+    ) -> Self {
+        Self {
+            stream,
+            info,
+            _active_connection: active_connection,
+        }
+    }
+}
+
+impl AsyncRead for CountingStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        let read_amount = buf.filled().len() - filled_before;
+        if read_amount > 0 {
+            this.info.add_bytes_received(read_amount as u64);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        if written > 0 {
+            this.info.add_bytes_sent(written as u64);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.stream).poll_write_vectored(cx, bufs))?;
+        if written > 0 {
+            this.info.add_bytes_sent(written as u64);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for CountingStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncStream for CountingStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+}
@@ -3,42 +3,118 @@
 // Changes:
 // - Customizable buffer size
 // - Don't bother initializing buffer
+// - Buffers are reused from a pool
 // - Read and write whenever there's a space
 // - Circular buffer
+// - Only keep copying after one direction finishes when the shut down stream supports half-close
+// - Optionally hold back small writes until more data is read or a timer fires
 
 use futures::ready;
 use tokio::io::ReadBuf;
 
 use std::future::Future;
-use std::io;
+use std::io::{self, IoSlice};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use crate::async_stream::AsyncStream;
-use crate::util::allocate_vec;
+use crate::async_stream::{shortest_ping_interval, AsyncStream};
+use crate::buffer_pool::{copy_buffer_pool, BufferPool};
+use crate::config::WriteCoalescingConfig;
+
+#[derive(Debug, PartialEq, Eq)]
+enum CoalescerState {
+    // Nothing is buffered.
+    Idle,
+    // Buffered data is held until the flush timer fires.
+    Holding,
+    // Buffered data is written until the buffer is empty.
+    Writing,
+}
+
+// Holds back writes until max_bytes are buffered or the first buffered byte has waited
+// max_delay, so that many small reads are written together.
+#[derive(Debug)]
+struct WriteCoalescer {
+    max_bytes: usize,
+    max_delay: Duration,
+    state: CoalescerState,
+    flush_timer: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl WriteCoalescer {
+    fn new(config: &WriteCoalescingConfig, buffer_size: usize) -> Self {
+        Self {
+            // The buffer can't hold more, so waiting for more would never end.
+            max_bytes: std::cmp::min(config.max_bytes, buffer_size),
+            max_delay: config.max_delay(),
+            state: CoalescerState::Idle,
+            flush_timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        }
+    }
+
+    // Returns true if the buffered data should not be written yet. The timer is registered with
+    // the context while data is held, so the copy is woken up to write it.
+    fn poll_hold(&mut self, cx: &mut Context<'_>, cache_length: usize, read_done: bool) -> bool {
+        if cache_length == 0 {
+            self.state = CoalescerState::Idle;
+            return false;
+        }
+        if read_done || cache_length >= self.max_bytes {
+            self.state = CoalescerState::Writing;
+            return false;
+        }
+        match self.state {
+            CoalescerState::Writing => return false,
+            CoalescerState::Idle => {
+                self.flush_timer
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + self.max_delay);
+                self.state = CoalescerState::Holding;
+            }
+            CoalescerState::Holding => (),
+        }
+        if self.flush_timer.as_mut().poll(cx).is_ready() {
+            self.state = CoalescerState::Writing;
+            return false;
+        }
+        true
+    }
+}
 
 #[derive(Debug)]
 struct CopyBuffer {
     read_done: bool,
     need_flush: bool,
     need_write_ping: bool,
+    // set when data was written, so that the ping timer restarts.
+    wrote_data: bool,
     start_index: usize,
     cache_length: usize,
     size: usize,
     buf: Box<[u8]>,
+    pool: &'static BufferPool,
+    coalescer: Option<WriteCoalescer>,
 }
 
 impl CopyBuffer {
-    pub fn new(size: usize, need_initial_flush: bool) -> Self {
-        let buf = allocate_vec(size);
+    pub fn new(
+        pool: &'static BufferPool,
+        need_initial_flush: bool,
+        write_coalescing: Option<&WriteCoalescingConfig>,
+    ) -> Self {
         Self {
             read_done: false,
             need_flush: need_initial_flush,
             need_write_ping: false,
+            wrote_data: false,
             start_index: 0,
             cache_length: 0,
-            size,
-            buf: buf.into_boxed_slice(),
+            size: pool.buffer_size(),
+            buf: pool.take(),
+            pool,
+            coalescer: write_coalescing
+                .map(|config| WriteCoalescer::new(config, pool.buffer_size())),
         }
     }
 
@@ -109,20 +185,41 @@ impl CopyBuffer {
                 }
             }
 
+            // Reads were done until they're pending, so nothing more will be buffered before the
+            // next wakeup.
+            let hold_writes = match self.coalescer {
+                Some(ref mut coalescer) => {
+                    coalescer.poll_hold(cx, self.cache_length, self.read_done)
+                }
+                None => false,
+            };
+
             // If our buffer has some data, let's write it out!
             // Loop and try to write out as much as possible to minimize forwarding
             // latency, and so that we increase the chance we have an optimal read
             // with start_index at zero.
-            while self.cache_length > 0 {
+            while !hold_writes && self.cache_length > 0 {
                 let used_start_index = self.start_index;
                 let used_end_index_exclusive =
                     std::cmp::min(self.start_index + self.cache_length, self.size);
+                // The number of bytes that wrapped around to the start of the buffer.
+                let wrapped_length =
+                    self.cache_length - (used_end_index_exclusive - used_start_index);
 
                 let me = &mut *self;
-                match writer
-                    .as_mut()
-                    .poll_write(cx, &me.buf[used_start_index..used_end_index_exclusive])
-                {
+                let first = &me.buf[used_start_index..used_end_index_exclusive];
+                // When the data wraps around, write both parts at once if the writer supports
+                // vectored writes, otherwise write up to the end of the buffer.
+                let write_result = if wrapped_length > 0 && writer.is_write_vectored() {
+                    let slices = [
+                        IoSlice::new(first),
+                        IoSlice::new(&me.buf[0..wrapped_length]),
+                    ];
+                    writer.as_mut().poll_write_vectored(cx, &slices)
+                } else {
+                    writer.as_mut().poll_write(cx, first)
+                };
+                match write_result {
                     Poll::Ready(val) => {
                         let written = val?;
                         if written == 0 {
@@ -134,10 +231,14 @@ impl CopyBuffer {
                             self.cache_length -= written;
                             if self.cache_length == 0 {
                                 self.start_index = 0;
+                                if let Some(ref mut coalescer) = self.coalescer {
+                                    coalescer.state = CoalescerState::Idle;
+                                }
                             } else {
                                 self.start_index = (self.start_index + written) % self.size;
                             }
                             self.need_flush = true;
+                            self.wrote_data = true;
                         }
                     }
                     Poll::Pending => {
@@ -159,7 +260,7 @@ impl CopyBuffer {
 
             // Previously we kept going until both read and write were pending, but
             // this might starve other tasks.
-            if read_pending || write_pending {
+            if read_pending || write_pending || hold_writes {
                 // If we got here,
                 // 1) we hit read_pending on the current iteration.
                 // 2) all data has been written successfully
@@ -170,6 +271,12 @@ impl CopyBuffer {
     }
 }
 
+impl Drop for CopyBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
 enum TransferState {
     Running,
     ShuttingDown,
@@ -184,6 +291,7 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     a_to_b: TransferState,
     b_to_a: TransferState,
     sleep_future: Option<Pin<Box<tokio::time::Sleep>>>,
+    ping_interval: Duration,
 }
 
 fn transfer_one_direction<A, B>(
@@ -232,6 +340,7 @@ where
             a_to_b,
             b_to_a,
             sleep_future,
+            ping_interval,
         } = &mut *self;
 
         if let Some(ref mut sleep) = sleep_future {
@@ -243,20 +352,35 @@ where
                 b_buf.need_write_ping = a.supports_ping();
                 sleep
                     .as_mut()
-                    .reset(tokio::time::Instant::now() + std::time::Duration::from_secs(60));
+                    .reset(tokio::time::Instant::now() + *ping_interval);
             }
         }
 
         let a_to_b = transfer_one_direction(cx, a_to_b, &mut *a_buf, &mut *a, &mut *b);
         let b_to_a = transfer_one_direction(cx, b_to_a, &mut *b_buf, &mut *b, &mut *a);
 
-        if a_to_b.is_ready() {
-            return a_to_b;
-        } else if b_to_a.is_ready() {
-            return b_to_a;
+        // Pings are only needed when the connection is idle, so the ping timer restarts whenever
+        // data is written.
+        let wrote_data =
+            std::mem::take(&mut a_buf.wrote_data) | std::mem::take(&mut b_buf.wrote_data);
+        if let Some(ref mut sleep) = sleep_future {
+            if wrote_data {
+                sleep
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + *ping_interval);
+            }
         }
 
-        Poll::Pending
+        // When a direction finishes, the other direction keeps going only if the stream that was
+        // shut down can still be read from. Otherwise the copy ends, since the other direction
+        // might never see EOF.
+        match (a_to_b, b_to_a) {
+            (Poll::Ready(Err(e)), _) | (_, Poll::Ready(Err(e))) => Poll::Ready(Err(e)),
+            (Poll::Ready(Ok(())), Poll::Ready(Ok(()))) => Poll::Ready(Ok(())),
+            (Poll::Ready(Ok(())), Poll::Pending) if !b.supports_half_close() => Poll::Ready(Ok(())),
+            (Poll::Pending, Poll::Ready(Ok(()))) if !a.supports_half_close() => Poll::Ready(Ok(())),
+            _ => Poll::Pending,
+        }
     }
 }
 
@@ -268,7 +392,8 @@ where
 ///
 /// If an EOF is observed on one stream, [`shutdown()`] will be invoked on
 /// the other, and reading from that stream will stop. Copying of data in
-/// the other direction will continue.
+/// the other direction will continue if the shut down stream supports half-close,
+/// and otherwise the future completes.
 ///
 /// The future will complete successfully once both directions of communication has been shut down.
 /// A direction is shut down when the reader reports EOF,
@@ -287,23 +412,21 @@ where
 /// # Return value
 ///
 /// Returns a tuple of bytes copied `a` to `b` and bytes copied `b` to `a`.
+///
+/// With `write_coalescing`, small reads in either direction are held back and written together.
 pub async fn copy_bidirectional<A, B>(
     a: &mut A,
     b: &mut B,
     a_need_initial_flush: bool,
     b_need_initial_flush: bool,
+    write_coalescing: Option<&WriteCoalescingConfig>,
 ) -> Result<(), std::io::Error>
 where
     A: AsyncStream + ?Sized,
     B: AsyncStream + ?Sized,
 {
-    let sleep_future = if a.supports_ping() || b.supports_ping() {
-        Some(Box::pin(tokio::time::sleep(
-            std::time::Duration::from_secs(60),
-        )))
-    } else {
-        None
-    };
+    let ping_interval = shortest_ping_interval(a, b);
+    let sleep_future = ping_interval.map(|interval| Box::pin(tokio::time::sleep(interval)));
 
     CopyBidirectional {
         a,
@@ -311,11 +434,12 @@ where
         // this is correctly reversed - CopyBuffer will copy from a (reader) to b (writer) using
         // a_buf, which means that the need_flush signal is for the writer (b), and vice versa for
         // b_buf.
-        a_buf: CopyBuffer::new(16384, b_need_initial_flush),
-        b_buf: CopyBuffer::new(16384, a_need_initial_flush),
+        a_buf: CopyBuffer::new(copy_buffer_pool(), b_need_initial_flush, write_coalescing),
+        b_buf: CopyBuffer::new(copy_buffer_pool(), a_need_initial_flush, write_coalescing),
         a_to_b: TransferState::Running,
         b_to_a: TransferState::Running,
         sleep_future,
+        ping_interval: ping_interval.unwrap_or_default(),
     }
     .await
 }
@@ -5,12 +5,10 @@ use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::async_stream::AsyncMessageStream;
-
-// Informed by https://stackoverflow.com/questions/14856639/udp-hole-punching-timeout
-pub const DEFAULT_ASSOCIATION_TIMEOUT_SECS: u32 = 200;
+use crate::async_stream::{shortest_ping_interval, AsyncMessageStream, DEFAULT_PING_INTERVAL};
+use crate::connection_registry::ConnectionInfo;
 
 #[derive(Debug)]
 struct CopyBuffer {
@@ -156,7 +154,10 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     a_to_b: TransferState,
     b_to_a: TransferState,
     sleep_future: Pin<Box<tokio::time::Sleep>>,
+    sleep_interval: Duration,
+    idle_timeout: Duration,
     last_active: Instant,
+    connection: &'a ConnectionInfo,
 }
 
 fn transfer_one_direction<A, B>(
@@ -205,7 +206,10 @@ where
             a_to_b,
             b_to_a,
             sleep_future,
+            sleep_interval,
+            idle_timeout,
             last_active,
+            connection,
         } = &mut *self;
 
         let ping_fired = sleep_future.as_mut().poll(cx).is_ready();
@@ -216,7 +220,7 @@ where
             b_buf.need_write_ping = a.supports_ping();
             sleep_future
                 .as_mut()
-                .reset(tokio::time::Instant::now() + std::time::Duration::from_secs(60));
+                .reset(tokio::time::Instant::now() + *sleep_interval);
         }
 
         let a_count = a_buf.read_count;
@@ -227,8 +231,9 @@ where
 
         if a_buf.read_count != a_count || b_buf.read_count != b_count {
             *last_active = Instant::now();
+            connection.mark_active();
         } else {
-            if last_active.elapsed().as_secs() >= DEFAULT_ASSOCIATION_TIMEOUT_SECS.into() {
+            if last_active.elapsed() >= *idle_timeout {
                 return Poll::Ready(Ok(()));
             }
         }
@@ -270,14 +275,28 @@ where
 /// # Return value
 ///
 /// Returns a tuple of bytes copied `a` to `b` and bytes copied `b` to `a`.
-pub async fn copy_bidirectional_message<A, B>(a: &mut A, b: &mut B) -> Result<(), std::io::Error>
+///
+/// The copy also finishes when nothing has been read for `idle_timeout`, and `connection` is
+/// marked active whenever messages are copied.
+pub async fn copy_bidirectional_message<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle_timeout: Duration,
+    connection: &ConnectionInfo,
+) -> Result<(), std::io::Error>
 where
     A: AsyncMessageStream + ?Sized,
     B: AsyncMessageStream + ?Sized,
 {
     // Unlike tcp copy_bidirectional, we always run a sleep future so that we can expire
     // connections.
-    let sleep_future = Box::pin(tokio::time::sleep(std::time::Duration::from_secs(60)));
+    let sleep_interval = match shortest_ping_interval(a, b) {
+        Some(interval) => std::cmp::min(interval, DEFAULT_PING_INTERVAL),
+        None => DEFAULT_PING_INTERVAL,
+    };
+    // Wake up often enough to notice when the idle timeout has passed.
+    let sleep_interval = std::cmp::min(sleep_interval, idle_timeout);
+    let sleep_future = Box::pin(tokio::time::sleep(sleep_interval));
 
     CopyBidirectional {
         a,
@@ -290,7 +309,10 @@ where
         a_to_b: TransferState::Running,
         b_to_a: TransferState::Running,
         sleep_future,
+        sleep_interval,
+        idle_timeout,
         last_active: Instant::now(),
+        connection,
     }
     .await
 }
@@ -6,10 +6,14 @@ use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::address::NetLocation;
-use crate::async_stream::{AsyncSourcedMessageStream, AsyncTargetedMessageStream};
+use crate::async_stream::{
+    shortest_ping_interval, AsyncSourcedMessageStream, AsyncTargetedMessageStream,
+    DEFAULT_PING_INTERVAL,
+};
+use crate::connection_registry::ConnectionInfo;
 
 // Informed by https://stackoverflow.com/questions/14856639/udp-hole-punching-timeout
 pub const DEFAULT_ASSOCIATION_TIMEOUT_SECS: u32 = 200;
@@ -303,8 +307,11 @@ struct CopyMultidirectional<'a, A: ?Sized, B: ?Sized> {
     a_to_b: TransferState,
     b_to_a: TransferState,
     sleep_future: Pin<Box<tokio::time::Sleep>>,
+    sleep_interval: Duration,
+    idle_timeout: Duration,
     a_last_active: Instant,
     b_last_active: Instant,
+    connection: &'a ConnectionInfo,
 }
 
 fn transfer_targeted_messages<A, B>(
@@ -382,8 +389,11 @@ where
             a_to_b,
             b_to_a,
             sleep_future,
+            sleep_interval,
+            idle_timeout,
             a_last_active,
             b_last_active,
+            connection,
         } = &mut *self;
 
         let ping_fired = sleep_future.as_mut().poll(cx).is_ready();
@@ -394,7 +404,7 @@ where
             b_buf.need_write_ping = a.supports_ping();
             sleep_future
                 .as_mut()
-                .reset(tokio::time::Instant::now() + std::time::Duration::from_secs(60));
+                .reset(tokio::time::Instant::now() + *sleep_interval);
         }
 
         let a_read_count = a_buf.read_count;
@@ -407,16 +417,18 @@ where
 
         if a_buf.read_count != a_read_count || a_buf.write_count != a_write_count {
             *a_last_active = Instant::now();
+            connection.mark_active();
         } else {
-            if a_last_active.elapsed().as_secs() >= DEFAULT_ASSOCIATION_TIMEOUT_SECS.into() {
+            if a_last_active.elapsed() >= *idle_timeout {
                 return Poll::Ready(Ok(()));
             }
         }
 
         if b_buf.read_count != b_read_count || b_buf.write_count != b_write_count {
             *b_last_active = Instant::now();
+            connection.mark_active();
         } else {
-            if b_last_active.elapsed().as_secs() >= DEFAULT_ASSOCIATION_TIMEOUT_SECS.into() {
+            if b_last_active.elapsed() >= *idle_timeout {
                 return Poll::Ready(Ok(()));
             }
         }
@@ -458,11 +470,16 @@ where
 /// # Return value
 ///
 /// Returns a tuple of bytes copied `a` to `b` and bytes copied `b` to `a`.
+///
+/// The copy also finishes when either direction has been idle for `idle_timeout`, and
+/// `connection` is marked active whenever messages are copied.
 pub async fn copy_multidirectional_message<A, B>(
     a: &mut A,
     b: &mut B,
     a_initial_flush: bool,
     b_initial_flush: bool,
+    idle_timeout: Duration,
+    connection: &ConnectionInfo,
 ) -> Result<(), std::io::Error>
 where
     A: AsyncTargetedMessageStream + ?Sized,
@@ -470,7 +487,13 @@ where
 {
     // Unlike tcp copy_bidirectional, we always run a sleep future so that we can expire
     // connections.
-    let sleep_future = Box::pin(tokio::time::sleep(std::time::Duration::from_secs(60)));
+    let sleep_interval = match shortest_ping_interval(a, b) {
+        Some(interval) => std::cmp::min(interval, DEFAULT_PING_INTERVAL),
+        None => DEFAULT_PING_INTERVAL,
+    };
+    // Wake up often enough to notice when the idle timeout has passed.
+    let sleep_interval = std::cmp::min(sleep_interval, idle_timeout);
+    let sleep_future = Box::pin(tokio::time::sleep(sleep_interval));
 
     CopyMultidirectional {
         a,
@@ -483,8 +506,11 @@ where
         a_to_b: TransferState::Running,
         b_to_a: TransferState::Running,
         sleep_future,
+        sleep_interval,
+        idle_timeout,
         a_last_active: Instant::now(),
         b_last_active: Instant::now(),
+        connection,
     }
     .await
 }
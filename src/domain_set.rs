@@ -0,0 +1,187 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+
+#[derive(Default)]
+struct DomainSetNode {
+    children: HashMap<Box<str>, DomainSetNode>,
+    // Whether the domain ending at this node matches its subdomains.
+    matches_subdomains: bool,
+    // Whether the domain ending at this node matches itself.
+    matches_domain: bool,
+}
+
+// A set of domains stored as a trie of labels from the TLD down, so that a hostname is checked
+// against every domain in the set with one lookup per label.
+#[derive(Default)]
+pub struct DomainSet {
+    root: DomainSetNode,
+    len: usize,
+}
+
+impl DomainSet {
+    // Parses a list with one domain per line. Each domain also matches its subdomains, unless it
+    // starts with full:, which only matches the domain itself. A domain: prefix is also accepted,
+    // as are GeoSite-style @attributes after a domain, which are ignored. Anything after a # is a
+    // comment.
+    pub fn from_lines(name: &str, contents: &str) -> std::io::Result<Self> {
+        let mut domain_set = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment_start) => &line[0..comment_start],
+                None => line,
+            };
+            let mut tokens = line.split_whitespace();
+            let entry = match tokens.next() {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let invalid_line = |reason: &str| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} line {}: {}: {}", name, i + 1, reason, line.trim()),
+                )
+            };
+            if tokens.any(|token| !token.starts_with('@')) {
+                return Err(invalid_line("expected one domain per line"));
+            }
+            let (domain, matches_subdomains) = if let Some(domain) = entry.strip_prefix("full:") {
+                (domain, false)
+            } else if let Some(domain) = entry.strip_prefix("domain:") {
+                (domain, true)
+            } else if entry.contains(':') {
+                return Err(invalid_line("unsupported entry type"));
+            } else {
+                (entry.strip_prefix('.').unwrap_or(entry), true)
+            };
+            if !is_valid_domain(domain) {
+                return Err(invalid_line("invalid domain"));
+            }
+            domain_set.insert(&domain.to_ascii_lowercase(), matches_subdomains);
+        }
+        Ok(domain_set)
+    }
+
+    fn insert(&mut self, domain: &str, matches_subdomains: bool) {
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if !node.matches_domain {
+            self.len += 1;
+        }
+        node.matches_domain = true;
+        node.matches_subdomains |= matches_subdomains;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Whether the hostname is a domain in the set, or a subdomain of one that matches subdomains.
+    pub fn contains(&self, hostname: &str) -> bool {
+        let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+        let hostname = if hostname.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(hostname.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(hostname)
+        };
+        let mut labels = hostname.rsplit('.').peekable();
+        let mut node = &self.root;
+        while let Some(label) = labels.next() {
+            node = match node.children.get(label) {
+                Some(child) => child,
+                None => return false,
+            };
+            if labels.peek().is_none() {
+                return node.matches_domain;
+            }
+            if node.matches_subdomains {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl std::fmt::Debug for DomainSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "DomainSet({} domains)", self.len)
+    }
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+struct CachedDomainSet {
+    modified: SystemTime,
+    len: u64,
+    domain_set: Weak<DomainSet>,
+}
+
+fn domain_set_cache() -> &'static Mutex<HashMap<PathBuf, CachedDomainSet>> {
+    static DOMAIN_SET_CACHE: OnceLock<Mutex<HashMap<PathBuf, CachedDomainSet>>> = OnceLock::new();
+    DOMAIN_SET_CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Loads a domain set file. Rules in groups are validated for every server that uses them, so a
+// file is only read again once it has changed or every rule using it has been dropped.
+pub fn load_domain_set(path: &Path) -> std::io::Result<Arc<DomainSet>> {
+    let path = std::fs::canonicalize(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("failed to read domain set {}: {}", path.display(), e),
+        )
+    })?;
+    let metadata = std::fs::metadata(&path)?;
+    let modified = metadata.modified()?;
+
+    let mut cache = domain_set_cache().lock();
+    if let Some(cached) = cache.get(&path) {
+        if cached.modified == modified && cached.len == metadata.len() {
+            if let Some(domain_set) = cached.domain_set.upgrade() {
+                return Ok(domain_set);
+            }
+        }
+    }
+
+    let contents = std::fs::read_to_string(&path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("failed to read domain set {}: {}", path.display(), e),
+        )
+    })?;
+    let domain_set = Arc::new(DomainSet::from_lines(
+        &path.display().to_string(),
+        &contents,
+    )?);
+    cache.insert(
+        path,
+        CachedDomainSet {
+            modified,
+            len: metadata.len(),
+            domain_set: Arc::downgrade(&domain_set),
+        },
+    );
+    Ok(domain_set)
+}
+
+// The paths of every domain set that has been loaded, so that they can be watched for changes.
+pub fn domain_set_paths() -> Vec<PathBuf> {
+    domain_set_cache().lock().keys().cloned().collect()
+}
@@ -0,0 +1,346 @@
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::client::TlsStream;
+
+use crate::address::{Address, NetLocation};
+use crate::config::{DotServerConfig, ResolverConfig};
+use crate::resolver::{create_resolver, resolve_single_address, Resolver};
+use crate::rustls_util::{create_client_config, load_ca_certs, parse_spki_hash, ClientRoots};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+struct DotServer {
+    location: NetLocation,
+    server_name: rustls::ServerName,
+    connector: tokio_rustls::TlsConnector,
+    // Kept open and reused for later queries, which are sent one lookup at a time.
+    connection: Mutex<Option<TlsStream<TcpStream>>>,
+}
+
+impl DotServer {
+    fn new(config: &DotServerConfig) -> Self {
+        let DotServerConfig {
+            address,
+            sni_hostname,
+            ca_cert,
+            pin_sha256,
+        } = config;
+
+        // The server name, CA certificates and pins were checked when the config was validated.
+        let server_name = match sni_hostname {
+            Some(sni_hostname) => sni_hostname.clone(),
+            None => address.address().to_string(),
+        };
+        let server_name = rustls::ServerName::try_from(server_name.as_str()).unwrap();
+        let pinned_spki_hashes = pin_sha256
+            .iter()
+            .map(|s| parse_spki_hash(s).unwrap())
+            .collect::<Vec<_>>();
+        let roots = ClientRoots {
+            ca_certs: ca_cert
+                .as_ref()
+                .map(|ca_cert| load_ca_certs(ca_cert).unwrap())
+                .unwrap_or_default(),
+            system_roots: true,
+        };
+        let client_config = create_client_config(true, &roots, &pinned_spki_hashes, &[], true);
+
+        Self {
+            location: address.clone(),
+            server_name,
+            connector: Arc::new(client_config).into(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> std::io::Result<TlsStream<TcpStream>> {
+        let socket_addr = match self.location.to_socket_addr_nonblocking() {
+            Some(socket_addr) => socket_addr,
+            None => resolve_single_address(&create_resolver(None), &self.location).await?,
+        };
+        let stream = TcpStream::connect(socket_addr).await?;
+        stream.set_nodelay(true)?;
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+
+    async fn connect_and_query(
+        &self,
+        hostname: &str,
+        ids: [u16; 2],
+    ) -> std::io::Result<(TlsStream<TcpStream>, Vec<IpAddr>)> {
+        let mut stream = self.connect().await?;
+        let ips = query(&mut stream, hostname, ids).await?;
+        Ok((stream, ips))
+    }
+
+    async fn lookup(&self, hostname: &str, ids: [u16; 2]) -> std::io::Result<Vec<IpAddr>> {
+        let mut connection = self.connection.lock().await;
+        // The connection is taken while it's used, so that it's dropped rather than reused if
+        // the lookup fails or is cancelled before the responses are read.
+        let (stream, ips) = match connection.take() {
+            Some(mut stream) => match query(&mut stream, hostname, ids).await {
+                Ok(ips) => (stream, ips),
+                // The server may have closed the idle connection, so try once more with a new
+                // connection.
+                Err(e) => {
+                    debug!(
+                        "DNS over TLS query to {} failed on a reused connection, reconnecting: {}",
+                        self.location, e
+                    );
+                    self.connect_and_query(hostname, ids).await?
+                }
+            },
+            None => self.connect_and_query(hostname, ids).await?,
+        };
+        *connection = Some(stream);
+        Ok(ips)
+    }
+}
+
+// Sends A and AAAA queries for the hostname, and returns the IPv4 addresses followed by the
+// IPv6 addresses.
+async fn query(
+    stream: &mut TlsStream<TcpStream>,
+    hostname: &str,
+    ids: [u16; 2],
+) -> std::io::Result<Vec<IpAddr>> {
+    // Each message is prefixed by its length (RFC 7858), and both are sent at once.
+    let mut request = vec![];
+    for (id, query_type) in ids.into_iter().zip([TYPE_A, TYPE_AAAA]) {
+        let message = encode_query(id, hostname, query_type)?;
+        request.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        request.extend_from_slice(&message);
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut ipv4_addrs = vec![];
+    let mut ipv6_addrs = vec![];
+    for _ in 0..2 {
+        let message_len = stream.read_u16().await? as usize;
+        let mut message = vec![0u8; message_len];
+        stream.read_exact(&mut message).await?;
+        let (id, addrs) = decode_response(&message)?;
+        if id == ids[0] {
+            ipv4_addrs = addrs;
+        } else if id == ids[1] {
+            ipv6_addrs = addrs;
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected DNS response id {}", id),
+            ));
+        }
+    }
+    ipv4_addrs.extend(ipv6_addrs);
+    Ok(ipv4_addrs)
+}
+
+fn encode_query(id: u16, hostname: &str, query_type: u16) -> std::io::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(18 + hostname.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    // Only recursion desired is set.
+    message.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, and no other records.
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid hostname: {}", hostname),
+            ));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&query_type.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+fn truncated_response() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated DNS response")
+}
+
+fn read_u16(message: &[u8], offset: usize) -> std::io::Result<u16> {
+    match message.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(truncated_response()),
+    }
+}
+
+// Returns the offset after the name, which can end with a compression pointer.
+fn skip_name(message: &[u8], mut offset: usize) -> std::io::Result<usize> {
+    loop {
+        let len = *message.get(offset).ok_or_else(truncated_response)? as usize;
+        if len == 0 {
+            return Ok(offset + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Ok(offset + 2);
+        }
+        offset += 1 + len;
+    }
+}
+
+// Returns the id of the response and the addresses in its answers. Other records, like the
+// CNAMEs leading to the addresses, are skipped.
+fn decode_response(message: &[u8]) -> std::io::Result<(u16, Vec<IpAddr>)> {
+    let id = read_u16(message, 0)?;
+    let flags = read_u16(message, 2)?;
+    let question_count = read_u16(message, 4)?;
+    let answer_count = read_u16(message, 6)?;
+
+    let rcode = flags & 0x000f;
+    if rcode == RCODE_NXDOMAIN {
+        return Ok((id, vec![]));
+    }
+    if rcode != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("DNS server returned error code {}", rcode),
+        ));
+    }
+
+    let mut offset = 12;
+    for _ in 0..question_count {
+        // The name is followed by the type and class.
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    let mut addrs = vec![];
+    for _ in 0..answer_count {
+        offset = skip_name(message, offset)?;
+        let record_type = read_u16(message, offset)?;
+        let record_class = read_u16(message, offset + 2)?;
+        let data_len = read_u16(message, offset + 8)? as usize;
+        offset += 10;
+        let data = message
+            .get(offset..offset + data_len)
+            .ok_or_else(truncated_response)?;
+        offset += data_len;
+
+        if record_class != CLASS_IN {
+            continue;
+        }
+        match (record_type, data_len) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = data.try_into().unwrap();
+                addrs.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap();
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+    Ok((id, addrs))
+}
+
+// Resolves hostnames with DNS over TLS (RFC 7858). Servers are tried in order until one
+// answers, starting with the last one that did.
+pub struct DotResolver {
+    servers: Arc<Vec<DotServer>>,
+    timeout: Duration,
+    attempts: u32,
+    next_id: AtomicU16,
+    preferred_server: Arc<AtomicUsize>,
+}
+
+impl DotResolver {
+    pub fn new(config: &ResolverConfig) -> Self {
+        Self {
+            servers: Arc::new(config.dot_servers.iter().map(DotServer::new).collect()),
+            timeout: Duration::from_secs(config.timeout_secs),
+            attempts: config.attempts,
+            next_id: AtomicU16::new(rand::random()),
+            preferred_server: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl Resolver for DotResolver {
+    fn resolve_location(
+        &self,
+        location: &NetLocation,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+        let port = location.port();
+        let hostname = match location.address() {
+            Address::Hostname(hostname) => hostname.clone(),
+            // Addresses don't need a lookup.
+            _ => {
+                let result = location
+                    .to_socket_addr()
+                    .map(|socket_addr| vec![socket_addr]);
+                return Box::pin(async move { result });
+            }
+        };
+
+        let servers = self.servers.clone();
+        let timeout = self.timeout;
+        let attempts = self.attempts;
+        let first_id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let ids = [first_id, first_id.wrapping_add(1)];
+        let preferred_server = self.preferred_server.clone();
+
+        Box::pin(async move {
+            let mut last_error = None;
+            for _ in 0..attempts {
+                let start_index = preferred_server.load(Ordering::Relaxed);
+                for i in 0..servers.len() {
+                    let index = (start_index + i) % servers.len();
+                    let server = &servers[index];
+                    let result =
+                        match tokio::time::timeout(timeout, server.lookup(&hostname, ids)).await {
+                            Ok(result) => result,
+                            Err(_) => Err(std::io::Error::new(
+                                std::io::ErrorKind::TimedOut,
+                                format!("timed out after {} seconds", timeout.as_secs()),
+                            )),
+                        };
+                    match result {
+                        Ok(ips) => {
+                            preferred_server.store(index, Ordering::Relaxed);
+                            debug!(
+                                "DotResolver resolved {}:{} with {} -> {:?}",
+                                hostname, port, server.location, ips
+                            );
+                            return Ok(ips
+                                .into_iter()
+                                .map(|ip| SocketAddr::new(ip, port))
+                                .collect());
+                        }
+                        Err(e) => {
+                            debug!(
+                                "DNS over TLS server {} failed to resolve {}: {}",
+                                server.location, hostname, e
+                            );
+                            last_error = Some(e);
+                        }
+                    }
+                }
+            }
+            let e = last_error.unwrap();
+            Err(std::io::Error::new(
+                e.kind(),
+                format!("failed to resolve {} with DNS over TLS: {}", hostname, e),
+            ))
+        })
+    }
+}
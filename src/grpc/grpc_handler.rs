@@ -0,0 +1,284 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use log::debug;
+use tokio::sync::mpsc;
+
+use super::grpc_stream::{h2_error, GrpcStream};
+use crate::address::NetLocation;
+use crate::async_stream::AsyncStream;
+use crate::client_proxy_selector::ClientProxySelector;
+use crate::option_util::NoneOrOne;
+use crate::tcp_client_connector::TcpClientConnector;
+use crate::tcp_handler::{
+    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+};
+
+// Larger than the HTTP/2 defaults of 64KiB, which limit throughput on high latency links.
+const STREAM_WINDOW_SIZE: u32 = 1024 * 1024;
+const CONNECTION_WINDOW_SIZE: u32 = 4 * 1024 * 1024;
+
+// The most accepted calls that wait to be processed.
+const ACCEPT_CHANNEL_SIZE: usize = 16;
+
+// Some CDNs only pass gRPC requests through with the user agent of a gRPC library.
+const USER_AGENT: &str = "grpc-go/1.59.0";
+
+fn call_path(service_name: &str, multi_mode: bool) -> String {
+    if multi_mode {
+        format!("/{}/TunMulti", service_name)
+    } else {
+        format!("/{}/Tun", service_name)
+    }
+}
+
+#[derive(Debug)]
+pub struct GrpcTcpServerHandler {
+    service_name: String,
+    handler: Arc<Box<dyn TcpServerHandler>>,
+    override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+}
+
+impl GrpcTcpServerHandler {
+    pub fn new(
+        service_name: String,
+        handler: Box<dyn TcpServerHandler>,
+        override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+    ) -> Self {
+        Self {
+            service_name,
+            handler: Arc::new(handler),
+            override_proxy_provider,
+        }
+    }
+}
+
+// Responds to a call of Tun or TunMulti on the service, and rejects any other call.
+fn accept_call(
+    service_name: &str,
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) -> std::io::Result<Box<dyn AsyncStream>> {
+    let path = request.uri().path();
+    if request.method() != Method::POST
+        || (path != call_path(service_name, false) && path != call_path(service_name, true))
+    {
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unknown gRPC call: {} {}", request.method(), path),
+        );
+        // A trailers-only response with the UNIMPLEMENTED status, as gRPC servers send.
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/grpc")
+            .header("grpc-status", "12")
+            .body(())
+            .unwrap();
+        let _ = respond.send_response(response, true);
+        return Err(error);
+    }
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/grpc")
+        .body(())
+        .unwrap();
+    let send_stream = respond.send_response(response, false).map_err(h2_error)?;
+    Ok(Box::new(GrpcStream::new_server(
+        request.into_body(),
+        send_stream,
+    )))
+}
+
+// Drives the server connection and sends the streams of the accepted calls. A failed handshake
+// or first call is sent as an error, later calls that fail are only logged.
+async fn run_server_connection(
+    server_stream: Box<dyn AsyncStream>,
+    service_name: String,
+    accept_sender: mpsc::Sender<std::io::Result<Box<dyn AsyncStream>>>,
+) {
+    let handshake_result = h2::server::Builder::new()
+        .initial_window_size(STREAM_WINDOW_SIZE)
+        .initial_connection_window_size(CONNECTION_WINDOW_SIZE)
+        .handshake::<_, Bytes>(server_stream)
+        .await;
+    let mut connection = match handshake_result {
+        Ok(connection) => connection,
+        Err(e) => {
+            let _ = accept_sender.send(Err(h2_error(e))).await;
+            return;
+        }
+    };
+
+    let mut is_first_call = true;
+    loop {
+        // The connection is closed once the streams are no longer wanted, eg. when setup timed
+        // out.
+        let accept_result = tokio::select! {
+            result = connection.accept() => result,
+            _ = accept_sender.closed() => return,
+        };
+        let stream_result = match accept_result {
+            Some(Ok((request, respond))) => accept_call(&service_name, request, respond),
+            Some(Err(e)) => {
+                let error = h2_error(e);
+                if is_first_call {
+                    let _ = accept_sender.send(Err(error)).await;
+                } else {
+                    debug!("gRPC connection finished with error: {}", error);
+                }
+                return;
+            }
+            None => {
+                if is_first_call {
+                    let _ = accept_sender
+                        .send(Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "connection closed before a gRPC call",
+                        )))
+                        .await;
+                }
+                return;
+            }
+        };
+        match stream_result {
+            Ok(stream) => {
+                if accept_sender.send(Ok(stream)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) if is_first_call => {
+                let _ = accept_sender.send(Err(e)).await;
+                return;
+            }
+            Err(e) => debug!("Rejected gRPC call: {}", e),
+        }
+        is_first_call = false;
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for GrpcTcpServerHandler {
+    async fn setup_server_stream(
+        &self,
+        server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        // The connection has its own task, since polling it deep within the setup of outer
+        // layers like TLS can take more stack than the runtime threads have.
+        let (accept_sender, mut accept_receiver) = mpsc::channel(ACCEPT_CHANNEL_SIZE);
+        tokio::spawn(run_server_connection(
+            server_stream,
+            self.service_name.clone(),
+            accept_sender,
+        ));
+
+        // The first call is waited for here, so that connections that don't make a call on the
+        // service fail setup.
+        let first_stream = match accept_receiver.recv().await {
+            Some(result) => result?,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed before a gRPC call",
+                ));
+            }
+        };
+
+        // Only the first result can be an error.
+        let next_streams = stream::unfold(accept_receiver, |mut accept_receiver| async move {
+            match accept_receiver.recv().await {
+                Some(Ok(stream)) => Some((stream, accept_receiver)),
+                _ => None,
+            }
+        });
+
+        Ok(TcpServerSetupResult::MultiplexedForward {
+            streams: stream::once(async { first_stream })
+                .chain(next_streams)
+                .boxed(),
+            handler: self.handler.clone(),
+            override_proxy_provider: self.override_proxy_provider.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct GrpcTcpClientHandler {
+    service_name: String,
+    multi_mode: bool,
+    authority: String,
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl GrpcTcpClientHandler {
+    pub fn new(
+        service_name: String,
+        multi_mode: bool,
+        authority: String,
+        handler: Box<dyn TcpClientHandler>,
+    ) -> Self {
+        Self {
+            service_name,
+            multi_mode,
+            authority,
+            handler,
+        }
+    }
+}
+
+#[async_trait]
+impl TcpClientHandler for GrpcTcpClientHandler {
+    async fn setup_client_stream(
+        &self,
+        server_stream: &mut Box<dyn AsyncStream>,
+        client_stream: Box<dyn AsyncStream>,
+        remote_location: NetLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        let (send_request, connection) = h2::client::Builder::new()
+            .initial_window_size(STREAM_WINDOW_SIZE)
+            .initial_connection_window_size(CONNECTION_WINDOW_SIZE)
+            .handshake::<_, Bytes>(client_stream)
+            .await
+            .map_err(h2_error)?;
+
+        // Each stream has its own connection, which finishes once the stream is dropped.
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("gRPC client connection finished with error: {}", e);
+            }
+        });
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!(
+                "https://{}{}",
+                self.authority,
+                call_path(&self.service_name, self.multi_mode)
+            ))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .header("user-agent", HeaderValue::from_static(USER_AGENT))
+            .body(())
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid gRPC request: {}", e),
+                )
+            })?;
+
+        let mut send_request = send_request.ready().await.map_err(h2_error)?;
+        let (response, send_stream) = send_request
+            .send_request(request, false)
+            .map_err(h2_error)?;
+
+        let grpc_stream = Box::new(GrpcStream::new_client(response, send_stream));
+        self.handler
+            .setup_client_stream(server_stream, grpc_stream, remote_location)
+            .await
+    }
+}
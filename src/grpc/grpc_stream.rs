@@ -0,0 +1,362 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{ready, Future};
+use h2::{RecvStream, SendStream};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+// The most data that is sent in a single gRPC message.
+const MAX_WRITE_CHUNK_SIZE: usize = 16384;
+
+// The largest gRPC message that is accepted, which is also the default limit of grpc-go.
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+// The length of the gRPC message header, a compressed flag followed by a big-endian length.
+const MESSAGE_HEADER_LEN: usize = 5;
+
+// The protobuf tag of the data field in Hunk and MultiHunk messages, field 1 with the
+// length-delimited wire type.
+const DATA_FIELD_TAG: u8 = 0x0a;
+
+pub fn h2_error(error: h2::Error) -> std::io::Error {
+    if error.is_io() {
+        return error.into_io().unwrap();
+    }
+    std::io::Error::other(format!("http2 error: {}", error))
+}
+
+enum ReceiveState {
+    // The client is waiting for the response headers.
+    Response(h2::client::ResponseFuture),
+    Body(RecvStream),
+    Done,
+}
+
+// The stream of a gRPC Tun or TunMulti call, as used by V2Ray and Xray. Each message is a
+// protobuf Hunk with a single bytes field, or a MultiHunk where the field can repeat.
+pub struct GrpcStream {
+    receive_state: ReceiveState,
+    send_stream: SendStream<Bytes>,
+    is_client: bool,
+    // Received bytes that don't form a full gRPC message yet.
+    unprocessed: BytesMut,
+    // Data from received messages that wasn't read yet.
+    pending_data: BytesMut,
+    shut_down: bool,
+}
+
+impl GrpcStream {
+    pub fn new_server(body: RecvStream, send_stream: SendStream<Bytes>) -> Self {
+        Self::new(ReceiveState::Body(body), send_stream, false)
+    }
+
+    // The response is only waited for when reading, since servers may not send their headers
+    // until they have data to send.
+    pub fn new_client(
+        response: h2::client::ResponseFuture,
+        send_stream: SendStream<Bytes>,
+    ) -> Self {
+        Self::new(ReceiveState::Response(response), send_stream, true)
+    }
+
+    fn new(receive_state: ReceiveState, send_stream: SendStream<Bytes>, is_client: bool) -> Self {
+        Self {
+            receive_state,
+            send_stream,
+            is_client,
+            unprocessed: BytesMut::new(),
+            pending_data: BytesMut::new(),
+            shut_down: false,
+        }
+    }
+
+    // Moves the data of a complete message from unprocessed to pending_data, returning false if
+    // there isn't a complete message yet.
+    fn process_message(&mut self) -> std::io::Result<bool> {
+        if self.unprocessed.len() < MESSAGE_HEADER_LEN {
+            return Ok(false);
+        }
+        if self.unprocessed[0] != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "compressed gRPC messages are not supported",
+            ));
+        }
+        let message_len = u32::from_be_bytes(self.unprocessed[1..5].try_into().unwrap()) as usize;
+        if message_len > MAX_MESSAGE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("gRPC message is too large: {} bytes", message_len),
+            ));
+        }
+        if self.unprocessed.len() < MESSAGE_HEADER_LEN + message_len {
+            return Ok(false);
+        }
+        self.unprocessed.advance(MESSAGE_HEADER_LEN);
+        let message = self.unprocessed.split_to(message_len).freeze();
+        read_data_fields(message, &mut self.pending_data)?;
+        Ok(true)
+    }
+
+    // Whether the peer reset the stream without an error, which it does after ending the call.
+    fn is_ended_by_peer(&mut self, cx: &mut Context<'_>) -> bool {
+        matches!(
+            self.send_stream.poll_reset(cx),
+            Poll::Ready(Ok(h2::Reason::NO_ERROR))
+        )
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Bytes>>> {
+        loop {
+            match self.receive_state {
+                ReceiveState::Response(ref mut response) => {
+                    let response = ready!(Pin::new(response).poll(cx)).map_err(h2_error)?;
+                    if response.status() != StatusCode::OK {
+                        return Poll::Ready(Err(std::io::Error::other(format!(
+                            "unexpected gRPC response status: {}",
+                            response.status()
+                        ))));
+                    }
+                    // A response without a body only has the status, eg. for an unknown service.
+                    if let Some(status) = response.headers().get("grpc-status") {
+                        if status != "0" {
+                            return Poll::Ready(Err(std::io::Error::other(format!(
+                                "gRPC call failed with status {:?}",
+                                status
+                            ))));
+                        }
+                    }
+                    self.receive_state = ReceiveState::Body(response.into_body());
+                }
+                ReceiveState::Body(ref mut body) => {
+                    return match ready!(body.poll_data(cx)) {
+                        Some(Ok(data)) => {
+                            body.flow_control()
+                                .release_capacity(data.len())
+                                .map_err(h2_error)?;
+                            Poll::Ready(Ok(Some(data)))
+                        }
+                        Some(Err(e)) => Poll::Ready(Err(h2_error(e))),
+                        None => {
+                            self.receive_state = ReceiveState::Done;
+                            Poll::Ready(Ok(None))
+                        }
+                    };
+                }
+                ReceiveState::Done => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+}
+
+// Appends the data fields of a Hunk or MultiHunk message, skipping any other fields.
+fn read_data_fields(mut message: Bytes, data: &mut BytesMut) -> std::io::Result<()> {
+    while message.has_remaining() {
+        let tag = read_varint(&mut message)?;
+        match tag & 0x7 {
+            // varint
+            0 => {
+                read_varint(&mut message)?;
+            }
+            // 64-bit
+            1 => skip_bytes(&mut message, 8)?,
+            // length-delimited
+            2 => {
+                let len = read_varint(&mut message)? as usize;
+                if len > message.remaining() {
+                    return Err(truncated_message_error());
+                }
+                let field = message.split_to(len);
+                if tag == DATA_FIELD_TAG as u64 {
+                    data.extend_from_slice(&field);
+                }
+            }
+            // 32-bit
+            5 => skip_bytes(&mut message, 4)?,
+            wire_type => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unsupported protobuf wire type: {}", wire_type),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_varint(message: &mut Bytes) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !message.has_remaining() {
+            return Err(truncated_message_error());
+        }
+        let byte = message.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "protobuf varint is too long",
+    ))
+}
+
+fn skip_bytes(message: &mut Bytes, len: usize) -> std::io::Result<()> {
+    if len > message.remaining() {
+        return Err(truncated_message_error());
+    }
+    message.advance(len);
+    Ok(())
+}
+
+fn truncated_message_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated gRPC message")
+}
+
+fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+// Wraps data in a gRPC message with a single data field, which is a valid Hunk and MultiHunk.
+fn create_message(data: &[u8]) -> Bytes {
+    let mut field_header = BytesMut::with_capacity(11);
+    field_header.put_u8(DATA_FIELD_TAG);
+    put_varint(&mut field_header, data.len() as u64);
+
+    let message_len = field_header.len() + data.len();
+    let mut message = BytesMut::with_capacity(MESSAGE_HEADER_LEN + message_len);
+    message.put_u8(0);
+    message.put_u32(message_len as u32);
+    message.extend_from_slice(&field_header);
+    message.extend_from_slice(data);
+    message.freeze()
+}
+
+impl AsyncRead for GrpcStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.pending_data.is_empty() {
+                let len = std::cmp::min(this.pending_data.len(), buf.remaining());
+                buf.put_slice(&this.pending_data.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.process_message()? {
+                continue;
+            }
+
+            match ready!(this.poll_receive(cx))? {
+                Some(data) => this.unprocessed.extend_from_slice(&data),
+                None => {
+                    if !this.unprocessed.is_empty() {
+                        return Poll::Ready(Err(truncated_message_error()));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GrpcStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = std::cmp::min(buf.len(), MAX_WRITE_CHUNK_SIZE);
+
+        // Once the peer ended the call, it doesn't read anything else, eg. when the client's
+        // protocol writes its end after the server finished.
+        if this.is_ended_by_peer(cx) {
+            return Poll::Ready(Ok(len));
+        }
+
+        let message = create_message(&buf[..len]);
+
+        // Wait until the peer's flow control window allows the whole message, so that writes
+        // are held back instead of buffered without a limit.
+        this.send_stream.reserve_capacity(message.len());
+        while this.send_stream.capacity() < message.len() {
+            match ready!(this.send_stream.poll_capacity(cx)) {
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Poll::Ready(Err(h2_error(e))),
+                None if this.is_ended_by_peer(cx) => return Poll::Ready(Ok(len)),
+                None => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "gRPC stream was closed",
+                    )));
+                }
+            }
+        }
+
+        this.send_stream
+            .send_data(message, false)
+            .map_err(h2_error)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // Sent data is written by the connection.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.shut_down {
+            return Poll::Ready(Ok(()));
+        }
+        this.shut_down = true;
+        // There's nothing to end when the peer already reset the stream, which servers do
+        // after ending the call.
+        if this.send_stream.poll_reset(cx).is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+        // Clients end their side of the stream, and servers end the call with an OK status.
+        let result = if this.is_client {
+            this.send_stream.send_data(Bytes::new(), true)
+        } else {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            this.send_stream.send_trailers(trailers)
+        };
+        Poll::Ready(result.map_err(h2_error))
+    }
+}
+
+impl AsyncPing for GrpcStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for GrpcStream {
+    // The client can end its side and keep reading, while the server's trailers end the call.
+    fn supports_half_close(&self) -> bool {
+        self.is_client
+    }
+}
@@ -0,0 +1,4 @@
+mod grpc_handler;
+mod grpc_stream;
+
+pub use grpc_handler::{GrpcTcpClientHandler, GrpcTcpServerHandler};
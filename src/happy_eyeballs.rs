@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::debug;
+
+// How long a connection attempt has before the next address is also tried, as recommended by
+// RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Reorders addresses so that the address families alternate, starting with the family of the
+// first address, so that a family that can't be reached only delays every other attempt.
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addrs.first() {
+        Some(addr) => addr.ip().to_canonical().is_ipv6(),
+        None => return addrs,
+    };
+    let len = addrs.len();
+    let (mut first_family, mut other_family): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.ip().to_canonical().is_ipv6() == first_is_ipv6);
+    let mut interleaved = Vec::with_capacity(len);
+    while !first_family.is_empty() || !other_family.is_empty() {
+        interleaved.extend(first_family.pop_front());
+        interleaved.extend(other_family.pop_front());
+    }
+    interleaved
+}
+
+// Connects to each address in order until one succeeds. With an attempt delay, the next attempt
+// is started as soon as the previous one fails or has taken longer than the delay, and the first
+// connection that succeeds is used. When every attempt fails, their errors are returned together.
+pub async fn connect_to_any<T, F, Fut>(
+    description: &str,
+    addrs: &[SocketAddr],
+    attempt_delay: Option<Duration>,
+    connect: F,
+) -> std::io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    if addrs.len() == 1 {
+        return connect(addrs[0]).await;
+    }
+
+    let mut errors = Vec::with_capacity(addrs.len());
+    match attempt_delay {
+        None => {
+            for addr in addrs.iter().copied() {
+                match connect(addr).await {
+                    Ok(connection) => return Ok(connection),
+                    Err(e) => {
+                        debug!("Failed to connect to {} ({}): {}", description, addr, e);
+                        errors.push((addr, e));
+                    }
+                }
+            }
+        }
+        Some(attempt_delay) => {
+            let attempt = |addr: SocketAddr| {
+                let connect = &connect;
+                async move { (addr, connect(addr).await) }
+            };
+            let mut attempts = FuturesUnordered::new();
+            let mut next_index = 0;
+            loop {
+                if attempts.is_empty() {
+                    if next_index == addrs.len() {
+                        break;
+                    }
+                    attempts.push(attempt(addrs[next_index]));
+                    next_index += 1;
+                }
+                tokio::select! {
+                    Some((addr, result)) = attempts.next() => match result {
+                        Ok(connection) => return Ok(connection),
+                        Err(e) => {
+                            debug!("Failed to connect to {} ({}): {}", description, addr, e);
+                            errors.push((addr, e));
+                            if next_index < addrs.len() {
+                                attempts.push(attempt(addrs[next_index]));
+                                next_index += 1;
+                            }
+                        }
+                    },
+                    _ = tokio::time::sleep(attempt_delay), if next_index < addrs.len() => {
+                        attempts.push(attempt(addrs[next_index]));
+                        next_index += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let kind = errors
+        .first()
+        .map(|(_, e)| e.kind())
+        .unwrap_or(std::io::ErrorKind::NotFound);
+    let attempt_errors = errors
+        .iter()
+        .map(|(addr, e)| format!("{}: {}", addr, e))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(std::io::Error::new(
+        kind,
+        format!(
+            "failed to connect to {} on any of {} addresses: {}",
+            description,
+            addrs.len(),
+            attempt_errors
+        ),
+    ))
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::address::NetLocation;
+use crate::config::HealthConfig;
+use crate::resolver::{create_resolver, resolve_single_address, Resolver};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+static ACCEPTING_LISTENERS: AtomicUsize = AtomicUsize::new(0);
+
+// Held by a server while its listener is accepting connections.
+pub struct AcceptingListener(());
+
+impl Drop for AcceptingListener {
+    fn drop(&mut self) {
+        ACCEPTING_LISTENERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn accepting_listener() -> AcceptingListener {
+    ACCEPTING_LISTENERS.fetch_add(1, Ordering::Relaxed);
+    AcceptingListener(())
+}
+
+struct UpstreamCheck {
+    location: NetLocation,
+    timeout: Duration,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl UpstreamCheck {
+    // Returns how long the TCP connect took.
+    async fn probe(&self) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        let connect = async {
+            let socket_addr = resolve_single_address(&self.resolver, &self.location).await?;
+            TcpStream::connect(socket_addr).await
+        };
+        match tokio::time::timeout(self.timeout, connect).await {
+            Ok(Ok(_)) => Ok(start.elapsed()),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connect timed out",
+            )),
+        }
+    }
+}
+
+async fn readiness(upstream: Option<&UpstreamCheck>) -> (bool, String) {
+    if ACCEPTING_LISTENERS.load(Ordering::Relaxed) == 0 {
+        return (false, "not ready: no listener is accepting\n".to_string());
+    }
+    let upstream = match upstream {
+        Some(u) => u,
+        None => return (true, "ready\n".to_string()),
+    };
+    match upstream.probe().await {
+        Ok(latency) => (
+            true,
+            format!(
+                "ready: upstream {} connected in {}ms\n",
+                upstream.location,
+                latency.as_millis()
+            ),
+        ),
+        Err(e) => (
+            false,
+            format!("not ready: upstream {} failed: {}\n", upstream.location, e),
+        ),
+    }
+}
+
+async fn handle_health_stream(
+    mut stream: TcpStream,
+    upstream: Option<&UpstreamCheck>,
+) -> std::io::Result<()> {
+    // Only the request line is needed, and the request has no body.
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "health request is too large",
+            ));
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "health request ended early",
+            ));
+        }
+        request.extend_from_slice(&buf[0..len]);
+    }
+
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/live")) => ("200 OK", "live\n".to_string()),
+        (Some(b"GET"), Some(b"/ready")) => match readiness(upstream).await {
+            (true, body) => ("200 OK", body),
+            (false, body) => ("503 Service Unavailable", body),
+        },
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn start_health_server(config: HealthConfig) -> std::io::Result<JoinHandle<()>> {
+    let HealthConfig {
+        address,
+        upstream,
+        upstream_timeout_secs,
+    } = config;
+
+    println!("Starting health server at {}", &address);
+
+    let upstream = upstream.map(|location| {
+        Arc::new(UpstreamCheck {
+            location,
+            timeout: Duration::from_secs(upstream_timeout_secs),
+            resolver: create_resolver(None),
+        })
+    });
+
+    let socket_addr = address.to_socket_addr()?;
+    let listener = tokio::net::TcpListener::bind(socket_addr).await?;
+    // Unlike the other listeners, this one doesn't wait for the accept gate, so that liveness
+    // checks pass while readiness checks report that nothing is accepting yet.
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Health accept failed: {}", e);
+                    continue;
+                }
+            };
+            let upstream = upstream.clone();
+            tokio::spawn(async move {
+                let result = tokio::time::timeout(
+                    Duration::from_secs(10),
+                    handle_health_stream(stream, upstream.as_deref()),
+                )
+                .await;
+                match result {
+                    Ok(Ok(())) => debug!("Health client {} finished", addr),
+                    Ok(Err(e)) => error!("Health client {} finished with error: {}", addr, e),
+                    Err(_) => error!("Health client {} timed out", addr),
+                }
+            });
+        }
+    }))
+}
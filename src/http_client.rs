@@ -0,0 +1,239 @@
+// A minimal HTTP/1.0 client, which avoids chunked responses and keep-alive. Used to fetch
+// configs and to talk to ACME servers.
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::address::NetLocation;
+use crate::rustls_util::{create_client_config, ClientRoots};
+
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+struct HttpUrl {
+    is_https: bool,
+    location: NetLocation,
+    host_header: String,
+    path: String,
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> std::io::Result<Self> {
+        let (is_https, remaining) = if let Some(s) = url.strip_prefix("https://") {
+            (true, s)
+        } else if let Some(s) = url.strip_prefix("http://") {
+            (false, s)
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unsupported URL: {}", url),
+            ));
+        };
+
+        let (host_header, path) = match remaining.find('/') {
+            Some(i) => (&remaining[0..i], &remaining[i..]),
+            None => (remaining, "/"),
+        };
+        // The fragment isn't sent to the server.
+        let path = path.split('#').next().unwrap();
+        if host_header.is_empty() || host_header.contains('@') {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid host in URL: {}", url),
+            ));
+        }
+
+        let default_port = if is_https { 443 } else { 80 };
+        let location = NetLocation::from_str(host_header, Some(default_port))?;
+
+        Ok(Self {
+            is_https,
+            location,
+            host_header: host_header.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub status_line: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    // The value of the first header with the name, which is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn create_request(method: &str, url: &HttpUrl, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut request = String::with_capacity(256);
+    request.push_str(method);
+    request.push(' ');
+    request.push_str(&url.path);
+    request.push_str(" HTTP/1.0\r\nHost: ");
+    request.push_str(&url.host_header);
+    request.push_str("\r\n");
+    for (key, value) in headers {
+        request.push_str(key);
+        request.push_str(": ");
+        request.push_str(value);
+        request.push_str("\r\n");
+    }
+    if !body.is_empty() {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+async fn send_request<S>(mut stream: S, request: Vec<u8>) -> std::io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut response = vec![];
+    let mut buf = [0u8; 16384];
+    loop {
+        let len = match stream.read(&mut buf).await {
+            Ok(len) => len,
+            // Some servers close the connection without a TLS close_notify.
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+        if len == 0 {
+            break;
+        }
+        if response.len() + len > MAX_RESPONSE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "response is too large",
+            ));
+        }
+        response.extend_from_slice(&buf[0..len]);
+    }
+    Ok(response)
+}
+
+fn parse_response(response: &[u8]) -> std::io::Result<HttpResponse> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "incomplete http response headers",
+            )
+        })?;
+    let header_str = std::str::from_utf8(&response[0..header_end]).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Failed to decode response headers: {}", e),
+        )
+    })?;
+    let mut lines = header_str.split("\r\n");
+    let status_line = lines.next().unwrap();
+
+    let status_str = status_line.split(' ').nth(1).unwrap_or("");
+    let status = match status_str.parse::<u16>() {
+        Ok(status) if status_str.len() == 3 => status,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid response status: {}", status_line),
+            ));
+        }
+    };
+
+    let mut headers = vec![];
+    let mut body = &response[header_end + 4..];
+    for line in lines {
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.eq_ignore_ascii_case("content-length") {
+            let content_length = value.parse::<usize>().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid content length: {}", e),
+                )
+            })?;
+            if content_length > body.len() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "response body is truncated, expected {} bytes, got {}",
+                        content_length,
+                        body.len()
+                    ),
+                ));
+            }
+            body = &body[0..content_length];
+        }
+        headers.push((key.to_string(), value.to_string()));
+    }
+
+    Ok(HttpResponse {
+        status,
+        status_line: status_line.to_string(),
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+// Sends a request and reads the whole response. The Host and Content-Length headers are added,
+// and certificates are always verified against the system roots.
+pub async fn http_request(
+    method: &str,
+    url_str: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> std::io::Result<HttpResponse> {
+    let url = HttpUrl::parse(url_str)?;
+    let request = create_request(method, &url, headers, body);
+
+    let (address, port) = url.location.components();
+    let stream = TcpStream::connect((address.to_string(), port)).await?;
+
+    let response = if url.is_https {
+        let client_config = create_client_config(
+            true,
+            &ClientRoots::SYSTEM,
+            &[],
+            &["http/1.1".to_string()],
+            true,
+        );
+        let connector: tokio_rustls::TlsConnector = Arc::new(client_config).into();
+        let server_name = rustls::client::ServerName::try_from(address.to_string().as_str())
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid server name: {}", e),
+                )
+            })?;
+        let tls_stream = connector.connect(server_name, stream).await?;
+        send_request(tls_stream, request).await?
+    } else {
+        send_request(stream, request).await?
+    };
+
+    parse_response(&response)
+}
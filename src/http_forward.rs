@@ -0,0 +1,622 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::AsyncStream;
+use crate::client_proxy_selector::ClientProxySelector;
+use crate::connection_registry::ConnectionInfo;
+use crate::line_reader::LineReader;
+use crate::resolver::Resolver;
+use crate::tcp_client_connector::TcpClientConnector;
+use crate::tcp_server::{setup_client_stream, DestinationConnector};
+
+// The most header bytes that are read for a request or response, not counting line endings.
+pub const MAX_HEAD_LEN: usize = 16384;
+
+// How long an idle client connection is kept open while waiting for its next request.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Headers that only apply to a single connection, and are never forwarded.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+// An absolute-form request, eg. "GET http://ipinfo.io/ HTTP/1.1".
+pub struct HttpForwardRequest {
+    pub remote_location: NetLocation,
+    pub version: String,
+    // The token from a basic Proxy-Authorization header.
+    pub proxy_auth_token: Option<String>,
+    method: String,
+    // The request line and headers to send to the origin.
+    head: String,
+    body: BodyLength,
+    keep_alive: bool,
+    expect_continue: bool,
+}
+
+struct HttpResponseHead {
+    status: u16,
+    // The status line and headers to send to the client, without a Connection header.
+    head: String,
+    body: BodyLength,
+    keep_alive: bool,
+}
+
+struct OriginConnection {
+    location: NetLocation,
+    stream: Box<dyn AsyncStream>,
+    line_reader: LineReader,
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn has_header_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+fn wants_keep_alive(version: &str, headers: &[(String, String)]) -> bool {
+    if has_header_token(headers, "connection", "close")
+        || has_header_token(headers, "proxy-connection", "close")
+    {
+        return false;
+    }
+    // HTTP/1.0 connections are only persistent when asked for.
+    version == "HTTP/1.1"
+        || has_header_token(headers, "connection", "keep-alive")
+        || has_header_token(headers, "proxy-connection", "keep-alive")
+}
+
+fn body_length(headers: &[(String, String)], is_response: bool) -> std::io::Result<BodyLength> {
+    if header_value(headers, "transfer-encoding").is_some() {
+        if has_header_token(headers, "transfer-encoding", "chunked") {
+            return Ok(BodyLength::Chunked);
+        }
+        if !is_response {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Unsupported HTTP transfer encoding",
+            ));
+        }
+        return Ok(BodyLength::UntilClose);
+    }
+    match header_value(headers, "content-length") {
+        Some(value) => value.parse::<u64>().map(BodyLength::Fixed).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid HTTP content length: {}", value),
+            )
+        }),
+        None if is_response => Ok(BodyLength::UntilClose),
+        None => Ok(BodyLength::Empty),
+    }
+}
+
+// Appends the headers that should be forwarded, which excludes hop-by-hop headers and any header
+// named in the Connection header.
+fn push_end_to_end_headers(out: &mut String, headers: &[(String, String)], excluded: &[&str]) {
+    for (name, value) in headers.iter() {
+        if HOP_BY_HOP_HEADERS
+            .iter()
+            .chain(excluded.iter())
+            .any(|header| name.eq_ignore_ascii_case(header))
+            || has_header_token(headers, "connection", name)
+        {
+            continue;
+        }
+        out.push_str(name);
+        out.push_str(": ");
+        out.push_str(value);
+        out.push_str("\r\n");
+    }
+}
+
+async fn read_headers(
+    line_reader: &mut LineReader,
+    stream: &mut Box<dyn AsyncStream>,
+) -> std::io::Result<Vec<(String, String)>> {
+    let mut headers = vec![];
+    let mut head_len = 0;
+    loop {
+        let line = line_reader.read_line(stream).await?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        head_len += line.len();
+        if head_len > MAX_HEAD_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "HTTP headers are too long",
+            ));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid HTTP header: {}", line),
+            )
+        })?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+// Reads the headers of an absolute-form request, after its request line.
+pub async fn read_forward_request(
+    line_reader: &mut LineReader,
+    stream: &mut Box<dyn AsyncStream>,
+    request_line: &str,
+) -> std::io::Result<HttpForwardRequest> {
+    let unrecognized_request = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unrecognized http request: {}", request_line),
+        )
+    };
+
+    let (method, rest) = request_line
+        .split_once(' ')
+        .ok_or_else(unrecognized_request)?;
+    let (url, version) = rest.rsplit_once(' ').ok_or_else(unrecognized_request)?;
+    if version != "HTTP/1.0" && version != "HTTP/1.1" {
+        return Err(unrecognized_request());
+    }
+
+    // we can't handle https
+    let url = url.strip_prefix("http://").ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported http forward url: {}", url),
+        )
+    })?;
+
+    let (address, path) = match url.find('/') {
+        Some(i) => (&url[0..i], &url[i..]),
+        None => (url, "/"),
+    };
+
+    let remote_location = match address.rfind(':') {
+        Some(i) if !address.ends_with(']') => {
+            // Port is specified.
+            let port = address[i + 1..]
+                .parse::<u16>()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            NetLocation::new(Address::from(&address[0..i])?, port)
+        }
+        _ => NetLocation::new(Address::from(address)?, 80),
+    };
+
+    let headers = read_headers(line_reader, stream).await?;
+
+    let proxy_auth_token = header_value(&headers, "proxy-authorization").and_then(|value| {
+        let (scheme, token) = value.split_once(' ')?;
+        scheme
+            .eq_ignore_ascii_case("basic")
+            .then(|| token.trim().to_string())
+    });
+
+    // We answer "Expect: 100-continue" ourselves once the origin is connected, so that the
+    // origin never has to.
+    let expect_continue = header_value(&headers, "expect")
+        .is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+
+    let mut head = format!("{} {} {}\r\n", method, path, version);
+    if header_value(&headers, "host").is_none() {
+        head.push_str(&format!("Host: {}\r\n", address));
+    }
+    let excluded: &[&str] = if expect_continue { &["expect"] } else { &[] };
+    push_end_to_end_headers(&mut head, &headers, excluded);
+    head.push_str("Connection: keep-alive\r\n\r\n");
+
+    Ok(HttpForwardRequest {
+        remote_location,
+        version: version.to_string(),
+        proxy_auth_token,
+        method: method.to_string(),
+        head,
+        body: body_length(&headers, false)?,
+        keep_alive: wants_keep_alive(version, &headers),
+        expect_continue,
+    })
+}
+
+async fn read_response_head(
+    line_reader: &mut LineReader,
+    stream: &mut Box<dyn AsyncStream>,
+    method: &str,
+) -> std::io::Result<HttpResponseHead> {
+    let status_line = line_reader.read_line(stream).await?.to_string();
+    let invalid_response = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid http response: {}", status_line),
+        )
+    };
+
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().ok_or_else(invalid_response)?;
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid_response());
+    }
+    let status = parts
+        .next()
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(invalid_response)?;
+    if status == 101 {
+        // We never forward Upgrade, so the origin should never switch protocols.
+        return Err(invalid_response());
+    }
+
+    let headers = read_headers(line_reader, stream).await?;
+
+    let body =
+        if method.eq_ignore_ascii_case("HEAD") || status < 200 || status == 204 || status == 304 {
+            BodyLength::Empty
+        } else {
+            body_length(&headers, true)?
+        };
+
+    let mut head = format!("{}\r\n", status_line);
+    push_end_to_end_headers(&mut head, &headers, &[]);
+
+    Ok(HttpResponseHead {
+        status,
+        head,
+        keep_alive: body != BodyLength::UntilClose && wants_keep_alive(version, &headers),
+        body,
+    })
+}
+
+async fn copy_exact(
+    line_reader: &mut LineReader,
+    src: &mut Box<dyn AsyncStream>,
+    dst: &mut Box<dyn AsyncStream>,
+    len: u64,
+) -> std::io::Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        let max_len = usize::try_from(remaining).unwrap_or(usize::MAX);
+        let data = line_reader.read_bytes(src, max_len).await?;
+        if data.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "EOF while copying HTTP body",
+            ));
+        }
+        dst.write_all(data).await?;
+        dst.flush().await?;
+        remaining -= data.len() as u64;
+    }
+    Ok(())
+}
+
+async fn copy_body(
+    line_reader: &mut LineReader,
+    src: &mut Box<dyn AsyncStream>,
+    dst: &mut Box<dyn AsyncStream>,
+    body: &BodyLength,
+) -> std::io::Result<()> {
+    match body {
+        BodyLength::Empty => Ok(()),
+        BodyLength::Fixed(len) => copy_exact(line_reader, src, dst, *len).await,
+        BodyLength::Chunked => loop {
+            let line = line_reader.read_line(src).await?;
+            // Chunk extensions follow the size after a ';'.
+            let size_str = line.split(';').next().unwrap_or("").trim();
+            let size = u64::from_str_radix(size_str, 16).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid HTTP chunk size: {}", line),
+                )
+            })?;
+            dst.write_all(line.as_bytes()).await?;
+            dst.write_all(b"\r\n").await?;
+
+            if size == 0 {
+                // Copy any trailers, up to the empty line that ends the body.
+                loop {
+                    let line = line_reader.read_line(src).await?;
+                    dst.write_all(line.as_bytes()).await?;
+                    dst.write_all(b"\r\n").await?;
+                    if line.is_empty() {
+                        return Ok(());
+                    }
+                }
+            }
+
+            // The chunk is followed by \r\n.
+            copy_exact(line_reader, src, dst, size + 2).await?;
+        },
+        BodyLength::UntilClose => loop {
+            let data = line_reader.read_bytes(src, usize::MAX).await?;
+            if data.is_empty() {
+                return Ok(());
+            }
+            dst.write_all(data).await?;
+            dst.flush().await?;
+        },
+    }
+}
+
+async fn write_error_response(
+    server_stream: &mut Box<dyn AsyncStream>,
+    version: &str,
+    status: &str,
+) -> std::io::Result<()> {
+    server_stream
+        .write_all(
+            &format!(
+                "{} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                version, status
+            )
+            .into_bytes(),
+        )
+        .await?;
+    server_stream.flush().await
+}
+
+// Connects to the origin of the request, answering the client with an error response when that
+// fails. Returns None when the connection was blocked by a rule.
+async fn connect_origin(
+    server_stream: &mut Box<dyn AsyncStream>,
+    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    resolver: Arc<dyn Resolver>,
+    request: &HttpForwardRequest,
+    connection: &Arc<ConnectionInfo>,
+    destination_connector: Option<&dyn DestinationConnector>,
+) -> std::io::Result<Option<OriginConnection>> {
+    let remote_location = &request.remote_location;
+    let setup_client_stream_future = timeout(
+        Duration::from_secs(60),
+        setup_client_stream(
+            server_stream,
+            client_proxy_selector,
+            resolver,
+            remote_location.clone(),
+            connection,
+            destination_connector,
+        ),
+    );
+
+    match setup_client_stream_future.await {
+        Ok(Ok(Some(stream))) => Ok(Some(OriginConnection {
+            location: remote_location.clone(),
+            stream,
+            line_reader: LineReader::new(),
+        })),
+        Ok(Ok(None)) => {
+            // Must have been blocked.
+            write_error_response(server_stream, &request.version, "403 Forbidden").await?;
+            Ok(None)
+        }
+        Ok(Err(e)) => {
+            let _ = write_error_response(server_stream, &request.version, "502 Bad Gateway").await;
+            Err(std::io::Error::new(
+                e.kind(),
+                format!(
+                    "failed to setup client stream to {}: {}",
+                    remote_location, e
+                ),
+            ))
+        }
+        Err(elapsed) => {
+            let _ =
+                write_error_response(server_stream, &request.version, "504 Gateway Timeout").await;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("client setup to {} timed out: {}", remote_location, elapsed),
+            ))
+        }
+    }
+}
+
+// Sends the request to the origin, and reads the head of its response.
+async fn send_request(
+    server_stream: &mut Box<dyn AsyncStream>,
+    line_reader: &mut LineReader,
+    origin: &mut OriginConnection,
+    request: &HttpForwardRequest,
+) -> std::io::Result<HttpResponseHead> {
+    origin.stream.write_all(request.head.as_bytes()).await?;
+    copy_body(
+        line_reader,
+        server_stream,
+        &mut origin.stream,
+        &request.body,
+    )
+    .await?;
+    origin.stream.flush().await?;
+    read_response_head(&mut origin.line_reader, &mut origin.stream, &request.method).await
+}
+
+// Forwards requests on a client connection until either side closes it, keeping the connection
+// to the origin open while the following requests go to the same place.
+pub async fn run_http_forward(
+    mut server_stream: Box<dyn AsyncStream>,
+    mut line_reader: LineReader,
+    mut request: HttpForwardRequest,
+    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    resolver: Arc<dyn Resolver>,
+    connection: &Arc<ConnectionInfo>,
+    destination_connector: Option<&dyn DestinationConnector>,
+) -> std::io::Result<()> {
+    let mut origin: Option<OriginConnection> = None;
+
+    loop {
+        connection.set_destination(request.remote_location.clone());
+
+        let (mut origin_connection, reused) = match origin.take() {
+            Some(o) if o.location == request.remote_location => (o, true),
+            previous => {
+                if let Some(mut previous) = previous {
+                    let _ = previous.stream.shutdown().await;
+                }
+                match connect_origin(
+                    &mut server_stream,
+                    client_proxy_selector.clone(),
+                    resolver.clone(),
+                    &request,
+                    connection,
+                    destination_connector,
+                )
+                .await?
+                {
+                    Some(o) => (o, false),
+                    None => {
+                        let _ = server_stream.shutdown().await;
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        if request.expect_continue {
+            server_stream
+                .write_all(&format!("{} 100 Continue\r\n\r\n", request.version).into_bytes())
+                .await?;
+            server_stream.flush().await?;
+        }
+
+        let mut response_result = send_request(
+            &mut server_stream,
+            &mut line_reader,
+            &mut origin_connection,
+            &request,
+        )
+        .await;
+
+        // The origin may have closed an idle connection just as we reused it, so retry on a new
+        // connection when nothing from the client has been lost.
+        if reused && request.body == BodyLength::Empty {
+            if let Err(e) = response_result.as_ref() {
+                debug!(
+                    "Reconnecting to {} after reused connection failed: {}",
+                    request.remote_location, e
+                );
+                let _ = origin_connection.stream.shutdown().await;
+                origin_connection = match connect_origin(
+                    &mut server_stream,
+                    client_proxy_selector.clone(),
+                    resolver.clone(),
+                    &request,
+                    connection,
+                    destination_connector,
+                )
+                .await?
+                {
+                    Some(o) => o,
+                    None => {
+                        let _ = server_stream.shutdown().await;
+                        return Ok(());
+                    }
+                };
+                response_result = send_request(
+                    &mut server_stream,
+                    &mut line_reader,
+                    &mut origin_connection,
+                    &request,
+                )
+                .await;
+            }
+        }
+
+        let mut response = response_result?;
+        while response.status < 200 {
+            // HTTP/1.0 clients don't understand informational responses.
+            if request.version != "HTTP/1.0" {
+                server_stream
+                    .write_all(&format!("{}\r\n", response.head).into_bytes())
+                    .await?;
+                server_stream.flush().await?;
+            }
+            response = read_response_head(
+                &mut origin_connection.line_reader,
+                &mut origin_connection.stream,
+                &request.method,
+            )
+            .await?;
+        }
+
+        // Without a length, the end of the response is signalled by closing the connection.
+        let keep_alive = request.keep_alive && response.body != BodyLength::UntilClose;
+
+        server_stream
+            .write_all(
+                &format!(
+                    "{}Connection: {}\r\n\r\n",
+                    response.head,
+                    if keep_alive { "keep-alive" } else { "close" }
+                )
+                .into_bytes(),
+            )
+            .await?;
+        copy_body(
+            &mut origin_connection.line_reader,
+            &mut origin_connection.stream,
+            &mut server_stream,
+            &response.body,
+        )
+        .await?;
+        server_stream.flush().await?;
+
+        if response.keep_alive {
+            origin = Some(origin_connection);
+        } else {
+            let _ = origin_connection.stream.shutdown().await;
+        }
+
+        if !keep_alive {
+            break;
+        }
+
+        let line_result = timeout(
+            KEEP_ALIVE_TIMEOUT,
+            line_reader.read_line(&mut server_stream),
+        )
+        .await
+        .map(|result| result.map(|line| line.to_string()));
+        let line = match line_result {
+            Ok(Ok(line)) => line,
+            // The client closed the connection between requests.
+            Ok(Err(_)) if line_reader.unparsed_data().is_empty() => break,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                debug!("Closing idle HTTP connection");
+                break;
+            }
+        };
+        request = read_forward_request(&mut line_reader, &mut server_stream, &line).await?;
+    }
+
+    if let Some(mut origin) = origin {
+        let _ = origin.stream.shutdown().await;
+    }
+    let _ = server_stream.shutdown().await;
+    Ok(())
+}
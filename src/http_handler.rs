@@ -1,37 +1,86 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use log::debug;
+use log::{debug, warn};
 use tokio::io::AsyncWriteExt;
 
 use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncStream;
+use crate::client_proxy_selector::ClientProxySelector;
+use crate::connection_registry::current_trace_id;
+use crate::http_forward::{read_forward_request, MAX_HEAD_LEN};
 use crate::line_reader::LineReader;
 use crate::option_util::NoneOrOne;
+use crate::tcp_client_connector::TcpClientConnector;
 use crate::tcp_handler::{
-    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+    auth_failure_error, TcpClientHandler, TcpClientSetupResult, TcpServerHandler,
+    TcpServerSetupResult,
 };
+use crate::user_quota::user_quotas;
+use crate::util::{constant_time_eq, Redacted};
 
 const PROXY_AUTH_HEADER_PREFIX: &str = "proxy-authorization: basic ";
-const CONNECTION_HEADER_PREFIX: &str = "connection: ";
-const PROXY_CONNECTION_HEADER_PREFIX: &str = "proxy-connection: ";
+const REQUEST_ID_HEADER_PREFIX: &str = "x-request-id: ";
 
 fn create_http_auth_token(username: &str, password: &str) -> String {
     BASE64.encode(format!("{}:{}", username, password))
 }
 
+pub struct HttpUser {
+    username: String,
+    auth_token: String,
+    override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+}
+
+impl std::fmt::Debug for HttpUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpUser")
+            .field("username", &self.username)
+            .field("auth_token", &Redacted)
+            .field("override_proxy_provider", &self.override_proxy_provider)
+            .finish()
+    }
+}
+
+impl HttpUser {
+    pub fn new(
+        username: String,
+        password: String,
+        override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+    ) -> Self {
+        let auth_token = create_http_auth_token(&username, &password);
+        Self {
+            username,
+            auth_token,
+            override_proxy_provider,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpTcpServerHandler {
-    auth_token: Option<String>,
+    // Authentication is required when this is not empty.
+    users: Vec<HttpUser>,
 }
 
 unsafe impl Send for HttpTcpServerHandler {}
 unsafe impl Sync for HttpTcpServerHandler {}
 
 impl HttpTcpServerHandler {
-    pub fn new(auth_credentials: Option<(String, String)>) -> Self {
-        let auth_token = auth_credentials
-            .map(|(username, password)| create_http_auth_token(&username, &password));
-        Self { auth_token }
+    pub fn new(users: Vec<HttpUser>) -> Self {
+        Self { users }
+    }
+
+    // Checks every user so that the time taken doesn't depend on which one matched.
+    fn find_user(&self, auth_token: &str) -> Option<&HttpUser> {
+        let mut matched_user = None;
+        for user in self.users.iter() {
+            if constant_time_eq(user.auth_token.as_bytes(), auth_token.as_bytes()) {
+                matched_user = Some(user);
+            }
+        }
+        matched_user
     }
 }
 
@@ -52,246 +101,220 @@ impl TcpServerHandler for HttpTcpServerHandler {
         }
 
         let http_version = line[line.len() - 8..].to_string();
-        let (remote_location, connection_success_response, initial_remote_data, need_initial_flush) =
-            if line.starts_with("CONNECT ") {
-                let address = &line[8..line.len() - 9];
-
-                let separator_index = address.find(':').ok_or_else(|| {
-                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid address format")
-                })?;
-
-                if address.len() <= separator_index + 1 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Invalid address format",
-                    ));
-                }
-
-                let domain_name = &address[0..separator_index];
-
-                let port = address[separator_index + 1..]
-                    .parse::<u16>()
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-
-                let remote_location = NetLocation::new(Address::from(&domain_name)?, port);
 
-                // wait for an empty \r\n before connecting, and check for auth header line if needed.
-                let mut need_auth = self.auth_token.is_some();
-
-                loop {
-                    let line = line_reader.read_line(&mut server_stream).await?;
-                    if line.is_empty() {
-                        break;
-                    }
-                    if need_auth {
-                        if line.len() > PROXY_AUTH_HEADER_PREFIX.len() + 1
-                            && line[0..PROXY_AUTH_HEADER_PREFIX.len()].to_ascii_lowercase()
-                                == PROXY_AUTH_HEADER_PREFIX
-                        {
-                            if &line[PROXY_AUTH_HEADER_PREFIX.len()..]
-                                != self.auth_token.as_ref().unwrap()
-                            {
-                                debug!(
-                                    "Received incorrect HTTP CONNECT authentication: {}",
-                                    &line[PROXY_AUTH_HEADER_PREFIX.len()..]
-                                );
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::InvalidInput,
-                                    "Incorrect HTTP CONNECT authentication",
-                                ));
-                            }
-                            need_auth = false;
-                            continue;
+        if !line.starts_with("CONNECT ") {
+            // Request looks a normal HTTP request but with protocol and address:
+            // GET http://ipinfo.io/ HTTP/1.1
+            // <headers follow..>
+            // <empty line>
+            let line = line.to_string();
+            let request = read_forward_request(&mut line_reader, &mut server_stream, &line).await?;
+
+            let mut authenticated_user: Option<&HttpUser> = None;
+            if !self.users.is_empty() {
+                match request.proxy_auth_token {
+                    Some(ref token) => match self.find_user(token) {
+                        Some(user) => authenticated_user = Some(user),
+                        None => {
+                            debug!("Received incorrect HTTP GET authentication: {}", token);
+                            return Err(auth_failure_error("Incorrect HTTP GET authentication"));
                         }
+                    },
+                    None => {
+                        write_auth_required_response(&mut server_stream, &http_version).await?;
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "Missing HTTP GET authentication",
+                        ));
                     }
-                    debug!("Ignored HTTP CONNECT request header: {}", line);
-                }
-
-                if need_auth {
-                    // We need to write Proxy-Authenticate or apps such as FoxyProxy will never send Proxy-Authorization.
-                    server_stream.write_all(
-                    &format!("{} 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", http_version).into_bytes()
-                ).await?;
-                    server_stream.flush().await?;
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Missing HTTP CONNECT authentication",
-                    ));
-                }
-
-                // We need an initial flush for this line.
-                let connection_success_response = Some(
-                    format!("{} 200 Connection established\r\n\r\n", http_version)
-                        .into_bytes()
-                        .into_boxed_slice(),
-                );
-
-                let unparsed_data = line_reader.unparsed_data();
-
-                let initial_remote_data = if unparsed_data.len() > 0 {
-                    let mut initial_remote_data = Vec::with_capacity(unparsed_data.len());
-                    initial_remote_data.extend(unparsed_data.iter());
-                    Some(initial_remote_data.into_boxed_slice())
-                } else {
-                    None
-                };
-
-                (
-                    remote_location,
-                    connection_success_response,
-                    initial_remote_data,
-                    true,
-                )
-            } else {
-                // Request looks a normal HTTP request but with protocol and address:
-                // GET http://ipinfo.io/ HTTP/1.1
-                // <headers follow..>
-                // <empty line>
-
-                // get rid of the space and http version info.
-                let line = &line[0..line.len() - 9];
-
-                let space_index = line.find(' ').ok_or_else(|| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        format!("Unrecognized http request: {} {}", line, http_version),
-                    )
-                })?;
-
-                let directive = &line[0..space_index];
-                let url = &line[space_index + 1..];
-
-                if !url.starts_with("http://") {
-                    // we can't handle https
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        format!("Unsupported http forward url: {}", url),
-                    ));
                 }
+            }
+            // If some auth header was passed in and we don't have auth configured, it's simply
+            // not forwarded.
+
+            check_user_quota(authenticated_user, &mut server_stream, &http_version).await?;
+
+            // We don't write "HTTP/xxx 200 Connection established\r\n\r\n" for this type of
+            // request, the server's response (eg. "HTTP/1.1 200 OK") is what the client
+            // expects as a response.
+            let (override_proxy_provider, authenticated_user) = user_overrides(authenticated_user);
+            return Ok(TcpServerSetupResult::HttpForward {
+                stream: server_stream,
+                line_reader,
+                request,
+                override_proxy_provider,
+                authenticated_user,
+            });
+        }
 
-                // get rid of http://
-                let url = &url[7..];
+        let mut authenticated_user: Option<&HttpUser> = None;
+        let address = &line[8..line.len() - 9];
 
-                let (address, location) = match url.find('/') {
-                    Some(i) => (&url[0..i], &url[i..]),
-                    None => (url, "/"),
-                };
+        let separator_index = address.find(':').ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid address format")
+        })?;
 
-                let remote_location = match address.find(':') {
-                    Some(i) => {
-                        // Port is specified.
-                        let port = address[i + 1..]
-                            .parse::<u16>()
-                            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-                        NetLocation::new(Address::from(&address[0..i])?, port)
-                    }
-                    None => NetLocation::new(Address::from(address)?, 80),
-                };
-
-                let mut request = format!("{} {} {}\r\n", directive, location, http_version);
+        if address.len() <= separator_index + 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid address format",
+            ));
+        }
 
-                // wait for an empty \r\n before connecting, and check for auth header line if needed.
-                let mut need_auth = self.auth_token.is_some();
+        let domain_name = &address[0..separator_index];
 
-                loop {
-                    let line = line_reader.read_line(&mut server_stream).await?;
-                    if line.is_empty() {
-                        break;
-                    }
+        let port = address[separator_index + 1..]
+            .parse::<u16>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
-                    let lowercase_line = line.to_ascii_lowercase();
-
-                    // && lowercase_line[0..PROXY_AUTH_HEADER_PREFIX.len()] == PROXY_AUTH_HEADER_PREFIX
-                    if line.len() > PROXY_AUTH_HEADER_PREFIX.len() + 1
-                        && lowercase_line.starts_with(PROXY_AUTH_HEADER_PREFIX)
-                    {
-                        if need_auth {
-                            if &line[PROXY_AUTH_HEADER_PREFIX.len()..]
-                                != self.auth_token.as_ref().unwrap()
-                            {
-                                debug!(
-                                    "Received incorrect HTTP GET authentication: {}",
-                                    &line[PROXY_AUTH_HEADER_PREFIX.len()..]
-                                );
-                                return Err(std::io::Error::new(
-                                    std::io::ErrorKind::InvalidInput,
-                                    "Incorrect HTTP GET authentication",
-                                ));
-                            }
-                            need_auth = false;
-                        }
-                        // If some auth header was passed in and we don't have auth configured,
-                        // simply ignore it.
-                        continue;
-                    } else if lowercase_line.starts_with(CONNECTION_HEADER_PREFIX)
-                        || lowercase_line.starts_with(PROXY_CONNECTION_HEADER_PREFIX)
-                    {
-                        // We can't support 'Connection' or 'Proxy-Connection' for GET style proxy requests.
-                        // Because then we'd have to parse the remote server's response to know when it ends,
-                        // in order to handle subsequent GET requests.
-                        // So filter them out, and then we make sure to add a 'Connection: close' header to prevent
-                        // having to worry about that.
-                        continue;
-                    }
+        let remote_location = NetLocation::new(Address::from(domain_name)?, port);
 
-                    request.push_str(line);
-                    request.push_str("\r\n");
+        // wait for an empty \r\n before connecting, and check for auth header line if needed.
+        let mut need_auth = !self.users.is_empty();
 
-                    if request.len() > 16384 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "HTTP GET request is too long",
-                        ));
+        let mut head_len = 0;
+        loop {
+            let line = line_reader.read_line(&mut server_stream).await?;
+            if line.is_empty() {
+                break;
+            }
+            head_len += line.len();
+            if head_len > MAX_HEAD_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "HTTP CONNECT headers are too long",
+                ));
+            }
+            if need_auth
+                && line.len() > PROXY_AUTH_HEADER_PREFIX.len() + 1
+                && line[0..PROXY_AUTH_HEADER_PREFIX.len()].to_ascii_lowercase()
+                    == PROXY_AUTH_HEADER_PREFIX
+            {
+                match self.find_user(&line[PROXY_AUTH_HEADER_PREFIX.len()..]) {
+                    Some(user) => authenticated_user = Some(user),
+                    None => {
+                        debug!(
+                            "Received incorrect HTTP CONNECT authentication: {}",
+                            &line[PROXY_AUTH_HEADER_PREFIX.len()..]
+                        );
+                        return Err(auth_failure_error("Incorrect HTTP CONNECT authentication"));
                     }
                 }
+                need_auth = false;
+                continue;
+            }
+            if line
+                .get(..REQUEST_ID_HEADER_PREFIX.len())
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case(REQUEST_ID_HEADER_PREFIX))
+            {
+                // Ties the connection to the one in the logs of the previous hop.
+                debug!(
+                    "Connection #{} has request ID {}",
+                    current_trace_id().unwrap_or_default(),
+                    &line[REQUEST_ID_HEADER_PREFIX.len()..]
+                );
+                continue;
+            }
+            debug!("Ignored HTTP CONNECT request header: {}", line);
+        }
 
-                if need_auth {
-                    // We need to write Proxy-Authenticate or apps such as FoxyProxy will never send Proxy-Authorization.
-                    server_stream.write_all(
-                    &format!("{} 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", http_version).into_bytes()
-                ).await?;
-                    server_stream.flush().await?;
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Missing HTTP GET authentication",
-                    ));
-                }
-
-                // Add our Connection header and end the request with a new line.
-                request.push_str("Connection: close\r\n\r\n");
+        if need_auth {
+            write_auth_required_response(&mut server_stream, &http_version).await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Missing HTTP CONNECT authentication",
+            ));
+        }
 
-                // We don't write "HTTP/xxx 200 Connection established\r\n\r\n" for this type of
-                // request, the server's response (eg. "HTTP/1.1 200 OK") is what the client
-                // expects as a response.
+        // We need an initial flush for this line.
+        let connection_success_response = Some(
+            format!("{} 200 Connection established\r\n\r\n", http_version)
+                .into_bytes()
+                .into_boxed_slice(),
+        );
 
-                let request_bytes = request.into_bytes();
+        let unparsed_data = line_reader.unparsed_data();
 
-                let unparsed_data = line_reader.unparsed_data();
+        let initial_remote_data = if !unparsed_data.is_empty() {
+            let mut initial_remote_data = Vec::with_capacity(unparsed_data.len());
+            initial_remote_data.extend(unparsed_data.iter());
+            Some(initial_remote_data.into_boxed_slice())
+        } else {
+            None
+        };
 
-                let mut initial_remote_data =
-                    Vec::with_capacity(request_bytes.len() + unparsed_data.len());
-                initial_remote_data.extend(request_bytes.iter());
-                initial_remote_data.extend(unparsed_data.iter());
+        check_user_quota(authenticated_user, &mut server_stream, &http_version).await?;
 
-                (
-                    remote_location,
-                    None,
-                    Some(initial_remote_data.into_boxed_slice()),
-                    false,
-                )
-            };
+        let (override_proxy_provider, authenticated_user) = user_overrides(authenticated_user);
 
         Ok(TcpServerSetupResult::TcpForward {
             remote_location,
             stream: server_stream,
-            need_initial_flush,
+            need_initial_flush: true,
             connection_success_response,
             initial_remote_data,
-            override_proxy_provider: NoneOrOne::Unspecified,
+            override_proxy_provider,
+            authenticated_user,
         })
     }
 }
 
+async fn write_auth_required_response(
+    server_stream: &mut Box<dyn AsyncStream>,
+    http_version: &str,
+) -> std::io::Result<()> {
+    // We need to write Proxy-Authenticate or apps such as FoxyProxy will never send Proxy-Authorization.
+    server_stream.write_all(
+        &format!("{} 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", http_version).into_bytes()
+    ).await?;
+    server_stream.flush().await
+}
+
+async fn check_user_quota(
+    user: Option<&HttpUser>,
+    server_stream: &mut Box<dyn AsyncStream>,
+    http_version: &str,
+) -> std::io::Result<()> {
+    if let Some(user) = user {
+        if user_quotas().is_exceeded(&user.username) {
+            warn!(
+                "Rejected HTTP request from user {}, who has used up their data quota",
+                user.username
+            );
+            server_stream
+                .write_all(
+                    &format!(
+                        "{} 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        http_version
+                    )
+                    .into_bytes(),
+                )
+                .await?;
+            server_stream.flush().await?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Data quota exceeded",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn user_overrides(
+    user: Option<&HttpUser>,
+) -> (
+    NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+    Option<String>,
+) {
+    match user {
+        Some(user) => (
+            user.override_proxy_provider.clone(),
+            Some(user.username.clone()),
+        ),
+        None => (NoneOrOne::Unspecified, None),
+    }
+}
+
 fn create_http_auth_header_line(username: &str, password: &str) -> String {
     format!(
         "Proxy-Authorization: Basic {}\r\n",
@@ -299,16 +322,28 @@ fn create_http_auth_header_line(username: &str, password: &str) -> String {
     )
 }
 
-#[derive(Debug)]
 pub struct HttpTcpClientHandler {
     auth_header: Option<String>,
+    request_id_header: bool,
+}
+
+impl std::fmt::Debug for HttpTcpClientHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpTcpClientHandler")
+            .field("auth_header", &self.auth_header.as_ref().map(|_| Redacted))
+            .field("request_id_header", &self.request_id_header)
+            .finish()
+    }
 }
 
 impl HttpTcpClientHandler {
-    pub fn new(auth_credentials: Option<(String, String)>) -> Self {
+    pub fn new(auth_credentials: Option<(String, String)>, request_id_header: bool) -> Self {
         let auth_header = auth_credentials
             .map(|(username, password)| create_http_auth_header_line(&username, &password));
-        Self { auth_header }
+        Self {
+            auth_header,
+            request_id_header,
+        }
     }
 }
 
@@ -320,22 +355,22 @@ impl TcpClientHandler for HttpTcpClientHandler {
         mut client_stream: Box<dyn AsyncStream>,
         remote_location: NetLocation,
     ) -> std::io::Result<TcpClientSetupResult> {
-        // TODO: clean this up
-        let mut connect_str = match remote_location.address() {
-            Address::Ipv6(addr) => {
-                format!("CONNECT {}:{} HTTP/1.1\r\n", addr, remote_location.port())
-            }
-            Address::Ipv4(addr) => {
-                format!("CONNECT {}:{} HTTP/1.1\r\n", addr, remote_location.port())
-            }
-            Address::Hostname(d) => {
-                format!("CONNECT {}:{} HTTP/1.1\r\n", d, remote_location.port())
-            }
+        // IPv6 addresses need brackets in the authority.
+        let authority = match remote_location.address() {
+            Address::Ipv6(addr) => format!("[{}]:{}", addr, remote_location.port()),
+            Address::Ipv4(addr) => format!("{}:{}", addr, remote_location.port()),
+            Address::Hostname(d) => format!("{}:{}", d, remote_location.port()),
         };
+        let mut connect_str = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
 
         if let Some(ref header) = self.auth_header {
             connect_str.push_str(header);
         }
+        if self.request_id_header {
+            if let Some(trace_id) = current_trace_id() {
+                connect_str.push_str(&format!("X-Request-Id: {}\r\n", trace_id));
+            }
+        }
         connect_str.push_str("\r\n");
         client_stream.write_all(&connect_str.into_bytes()).await?;
         client_stream.flush().await?;
@@ -343,12 +378,40 @@ impl TcpClientHandler for HttpTcpClientHandler {
         let mut line_reader = LineReader::new();
         let line = line_reader.read_line(&mut client_stream).await?;
 
-        // Expected response: HTTP/1.1 200 Connection established\r\n\r\n
-        if !line.starts_with("HTTP/1.1 200") && !line.starts_with("HTTP/1.0 200") {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("HTTP CONNECT request failed: {}", line),
-            ));
+        // Expected response: HTTP/1.1 200 Connection established\r\n\r\n, though any 2xx
+        // status means the tunnel is open.
+        let status_code = match line.split_once(' ') {
+            Some((version, rest)) if version.starts_with("HTTP/1.") => rest
+                .split(' ')
+                .next()
+                .and_then(|code| code.parse::<u16>().ok()),
+            _ => None,
+        };
+        match status_code {
+            Some(200..=299) => (),
+            Some(407) => {
+                let reason = if self.auth_header.is_some() {
+                    "rejected the credentials"
+                } else {
+                    "requires credentials, but no username and password are set"
+                };
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("HTTP CONNECT request failed, proxy {}: {}", reason, line),
+                ));
+            }
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    format!("HTTP CONNECT request failed: {}", line),
+                ));
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("HTTP CONNECT request failed, invalid status line: {}", line),
+                ));
+            }
         }
 
         loop {
@@ -359,7 +422,7 @@ impl TcpClientHandler for HttpTcpClientHandler {
         }
 
         let unparsed_data = line_reader.unparsed_data();
-        if unparsed_data.len() > 0 {
+        if !unparsed_data.is_empty() {
             server_stream.write_all(unparsed_data).await?;
             server_stream.flush().await?;
         }
@@ -367,3 +430,254 @@ impl TcpClientHandler for HttpTcpClientHandler {
         Ok(TcpClientSetupResult { client_stream })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    // Sets up a server stream from the request bytes. The client end stays open, so reading
+    // past the request would hang instead of failing with an EOF.
+    async fn setup_server(request: &[u8]) -> std::io::Result<TcpServerSetupResult> {
+        let handler = HttpTcpServerHandler::new(vec![]);
+        let (mut client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        client_stream.write_all(request).await.unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.setup_server_stream(Box::new(server_stream)),
+        )
+        .await
+        .expect("server read past the request");
+        drop(client_stream);
+        result
+    }
+
+    fn expect_error<T>(result: std::io::Result<T>) -> std::io::Error {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        }
+    }
+
+    // A CONNECT request with a header line of head_len bytes, not counting its line ending.
+    fn connect_request(head_len: usize) -> Vec<u8> {
+        let mut request = b"CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.extend(std::iter::repeat_n(b'a', head_len - "X-Padding: ".len()));
+        request.extend_from_slice(b"\r\n\r\nfirst bytes");
+        request
+    }
+
+    // Runs the client handler against a stub proxy that answers the CONNECT request with the
+    // response, and closes the connection after it. Returns the client handler's result, the
+    // request that the proxy received, and what was written back to the server stream.
+    async fn connect_through_stub(
+        handler: &HttpTcpClientHandler,
+        response: &'static [u8],
+    ) -> (std::io::Result<TcpClientSetupResult>, String, Vec<u8>) {
+        let (client_stream, proxy_stream) = tokio::io::duplex(4096);
+        let proxy = tokio::spawn(async move {
+            let mut proxy_stream: Box<dyn AsyncStream> = Box::new(proxy_stream);
+            let mut line_reader = LineReader::new();
+            let mut request = String::new();
+            loop {
+                let line = line_reader.read_line(&mut proxy_stream).await.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                request.push_str(line);
+                request.push('\n');
+            }
+            proxy_stream.write_all(response).await.unwrap();
+            proxy_stream.shutdown().await.unwrap();
+            request
+        });
+
+        let (server_stream, mut server_peer) = tokio::io::duplex(4096);
+        let mut server_stream: Box<dyn AsyncStream> = Box::new(server_stream);
+        let remote_location = NetLocation::new(Address::Hostname("example.com".to_string()), 443);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.setup_client_stream(
+                &mut server_stream,
+                Box::new(client_stream),
+                remote_location,
+            ),
+        )
+        .await
+        .expect("client setup did not finish");
+        let request = proxy.await.unwrap();
+
+        drop(server_stream);
+        let mut forwarded = vec![];
+        server_peer.read_to_end(&mut forwarded).await.unwrap();
+        (result, request, forwarded)
+    }
+
+    #[tokio::test]
+    async fn proxy_connect_succeeds_with_2xx_status() {
+        let handler = HttpTcpClientHandler::new(None, false);
+        let (result, request, forwarded) = connect_through_stub(
+            &handler,
+            b"HTTP/1.1 200 Connection established\r\nVia: stub\r\n\r\nearly data",
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(
+            request,
+            "CONNECT example.com:443 HTTP/1.1\nHost: example.com:443\n"
+        );
+        // Data that the proxy sent after its response goes to the server stream.
+        assert_eq!(forwarded, b"early data");
+
+        let (result, _, _) =
+            connect_through_stub(&handler, b"HTTP/1.0 204 No Content\r\n\r\n").await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn proxy_connect_fails_with_407_status() {
+        let response = b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+
+        let handler = HttpTcpClientHandler::new(None, false);
+        let error = expect_error(connect_through_stub(&handler, response).await.0);
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(
+            error.to_string().contains("requires credentials"),
+            "{}",
+            error
+        );
+
+        let handler =
+            HttpTcpClientHandler::new(Some(("user".to_string(), "pass".to_string())), false);
+        let (result, request, _) = connect_through_stub(&handler, response).await;
+        let error = expect_error(result);
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(
+            error.to_string().contains("rejected the credentials"),
+            "{}",
+            error
+        );
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\n"));
+    }
+
+    #[tokio::test]
+    async fn proxy_connect_fails_with_non_2xx_status() {
+        let handler = HttpTcpClientHandler::new(None, false);
+        for response in [
+            &b"HTTP/1.1 502 Bad Gateway\r\n\r\n"[..],
+            &b"HTTP/1.1 101 Switching Protocols\r\n\r\n"[..],
+            &b"HTTP/1.1 301 Moved Permanently\r\nLocation: /\r\n\r\n"[..],
+        ] {
+            let error = expect_error(connect_through_stub(&handler, response).await.0);
+            assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+            assert!(error
+                .to_string()
+                .starts_with("HTTP CONNECT request failed: HTTP/1.1"));
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_connect_fails_with_malformed_status_line() {
+        let handler = HttpTcpClientHandler::new(None, false);
+        for response in [
+            &b"garbage\r\n\r\n"[..],
+            &b"HTTP/1.1\r\n\r\n"[..],
+            &b"HTTP/1.1 abc OK\r\n\r\n"[..],
+            &b"SSH-2.0-OpenSSH 200\r\n\r\n"[..],
+        ] {
+            let error = expect_error(connect_through_stub(&handler, response).await.0);
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            assert!(
+                error.to_string().contains("invalid status line"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_connect_fails_with_truncated_response() {
+        let handler = HttpTcpClientHandler::new(None, false);
+        for response in [
+            &b""[..],
+            &b"HTTP/1.1 200 Conn"[..],
+            &b"HTTP/1.1 200 Connection established\r\nVia: st"[..],
+        ] {
+            let error = expect_error(connect_through_stub(&handler, response).await.0);
+            assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_headers_at_max_length_are_accepted() {
+        match setup_server(&connect_request(MAX_HEAD_LEN)).await {
+            Ok(TcpServerSetupResult::TcpForward {
+                remote_location,
+                initial_remote_data,
+                ..
+            }) => {
+                assert_eq!(
+                    remote_location,
+                    NetLocation::new(Address::Hostname("example.com".to_string()), 443)
+                );
+                assert_eq!(initial_remote_data.as_deref(), Some(&b"first bytes"[..]));
+            }
+            Ok(_) => panic!("expected a tcp forward"),
+            Err(e) => panic!("request was rejected: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_headers_over_max_length_are_rejected() {
+        let error = expect_error(setup_server(&connect_request(MAX_HEAD_LEN + 1)).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "HTTP CONNECT headers are too long");
+    }
+
+    #[tokio::test]
+    async fn connect_header_line_longer_than_buffer_is_rejected() {
+        // A single line that doesn't fit in the line reader fails before the head length is
+        // checked.
+        let mut request = b"CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.extend(std::iter::repeat_n(b'a', 40000));
+        let error = expect_error(setup_server(&request).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "line is longer than 32768 bytes");
+    }
+
+    #[tokio::test]
+    async fn truncated_connect_request_is_rejected() {
+        let handler = HttpTcpServerHandler::new(vec![]);
+        let (mut client_stream, server_stream) = tokio::io::duplex(1024);
+        client_stream
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: exam")
+            .await
+            .unwrap();
+        drop(client_stream);
+
+        let error = expect_error(handler.setup_server_stream(Box::new(server_stream)).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn malformed_connect_port_is_rejected() {
+        for request in [
+            &b"CONNECT example.com:65536 HTTP/1.1\r\n\r\n"[..],
+            &b"CONNECT example.com: HTTP/1.1\r\n\r\n"[..],
+            &b"CONNECT example.com HTTP/1.1\r\n\r\n"[..],
+        ] {
+            let error = expect_error(setup_server(request).await);
+            assert!(
+                matches!(
+                    error.kind(),
+                    std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput
+                ),
+                "{}",
+                error
+            );
+        }
+    }
+}
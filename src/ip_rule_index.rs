@@ -0,0 +1,150 @@
+use crate::address::{Address, AddressMask};
+use crate::client_proxy_selector::ConnectRule;
+use crate::config::MaskMode;
+
+const NO_RULE: usize = usize::MAX;
+
+#[derive(Debug)]
+struct PrefixTrieNode {
+    // Indexes into PrefixTrie::nodes, where 0 means there's no child since the root is never a
+    // child.
+    children: [u32; 2],
+    // The earliest rule with a mask for exactly this prefix.
+    rule_index: usize,
+}
+
+// A binary trie of address prefixes, where the bits of a key are read from the most significant
+// one.
+#[derive(Debug)]
+struct PrefixTrie {
+    nodes: Vec<PrefixTrieNode>,
+}
+
+impl PrefixTrie {
+    fn new() -> Self {
+        Self {
+            nodes: vec![PrefixTrieNode {
+                children: [0, 0],
+                rule_index: NO_RULE,
+            }],
+        }
+    }
+
+    fn insert(&mut self, key: u128, prefix_len: u32, rule_index: usize) {
+        let mut node_index = 0;
+        for i in 0..prefix_len {
+            let bit = ((key >> (127 - i)) & 1) as usize;
+            let child = self.nodes[node_index].children[bit];
+            node_index = if child != 0 {
+                child as usize
+            } else {
+                self.nodes.push(PrefixTrieNode {
+                    children: [0, 0],
+                    rule_index: NO_RULE,
+                });
+                let child = self.nodes.len() - 1;
+                self.nodes[node_index].children[bit] = child as u32;
+                child
+            };
+        }
+        let node = &mut self.nodes[node_index];
+        node.rule_index = node.rule_index.min(rule_index);
+    }
+
+    // Returns the earliest rule with a prefix of the key, looking at no more than max_len bits.
+    fn earliest_rule(&self, key: u128, max_len: u32) -> usize {
+        let mut node = &self.nodes[0];
+        let mut rule_index = node.rule_index;
+        for i in 0..max_len {
+            let bit = ((key >> (127 - i)) & 1) as usize;
+            match node.children[bit] {
+                0 => break,
+                child => node = &self.nodes[child as usize],
+            }
+            rule_index = rule_index.min(node.rule_index);
+        }
+        rule_index
+    }
+}
+
+// An index of the rules that only have IP and CIDR masks, so that an address is matched against
+// all of them with one walk down a trie, rather than by checking every rule. IPv4 and IPv6 masks
+// are kept in separate tries, and IPv4 addresses are looked up in both, since an IPv6 mask can
+// cover IPv4-mapped addresses.
+#[derive(Debug)]
+pub struct IpRuleIndex {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+    indexed_rules: Vec<bool>,
+    first_indexed_rule: Option<usize>,
+}
+
+impl IpRuleIndex {
+    pub fn new<T>(rules: &[ConnectRule<T>]) -> Self {
+        let mut v4 = PrefixTrie::new();
+        let mut v6 = PrefixTrie::new();
+        let mut indexed_rules = vec![false; rules.len()];
+        for (rule_index, rule) in rules.iter().enumerate() {
+            if !is_indexable(rule) {
+                continue;
+            }
+            indexed_rules[rule_index] = true;
+            for mask in rule.masks.iter() {
+                let AddressMask { address, netmask } = &mask.address_mask;
+                match address {
+                    Address::Ipv4(ip) => {
+                        let key = (u32::from(*ip) as u128) << 96;
+                        v4.insert(key, netmask.count_ones() - 96, rule_index);
+                    }
+                    Address::Ipv6(ip) => {
+                        v6.insert(u128::from(*ip), netmask.count_ones(), rule_index);
+                    }
+                    Address::Hostname(_) => unreachable!(),
+                }
+            }
+        }
+        let first_indexed_rule = indexed_rules.iter().position(|indexed| *indexed);
+        Self {
+            v4,
+            v6,
+            indexed_rules,
+            first_indexed_rule,
+        }
+    }
+
+    pub fn is_indexed(&self, rule_index: usize) -> bool {
+        self.indexed_rules[rule_index]
+    }
+
+    pub fn has_indexed_rule_before(&self, rule_index: usize) -> bool {
+        matches!(self.first_indexed_rule, Some(i) if i < rule_index)
+    }
+
+    // The earliest indexed rule that matches the address, which is IPv4-mapped for IPv4
+    // addresses.
+    pub fn earliest_rule(&self, ip: u128) -> Option<usize> {
+        let mut rule_index = self.v6.earliest_rule(ip, 128);
+        if ip >> 32 == 0xffff {
+            rule_index = rule_index.min(self.v4.earliest_rule(ip << 96, 32));
+        }
+        if rule_index == NO_RULE {
+            None
+        } else {
+            Some(rule_index)
+        }
+    }
+}
+
+// Rules are indexed when they match exactly the addresses that their masks cover, so that the
+// trie gives the same answer as checking their masks one by one.
+fn is_indexable<T>(rule: &ConnectRule<T>) -> bool {
+    (rule.mask_mode == MaskMode::Any || rule.masks.len() == 1)
+        && rule.schedule.is_none()
+        && rule.masks.iter().all(|mask| {
+            !mask.negated
+                && mask.domain_set.is_none()
+                && mask.port == 0
+                && mask.address_mask.netmask != 0
+                && !mask.address_mask.address.is_hostname()
+        })
+}
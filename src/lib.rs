@@ -0,0 +1,76 @@
+pub mod acme;
+pub mod address;
+pub mod admin_server;
+pub mod async_stream;
+pub mod auth_ban_table;
+pub mod bandwidth_stats;
+pub mod buffer_pool;
+pub mod clash_import;
+pub mod client_export;
+pub mod client_proxy_selector;
+pub mod config;
+pub mod config_decrypt;
+pub mod config_fetch;
+pub mod config_schema;
+pub mod config_watcher;
+pub mod connection_registry;
+pub mod copy_bidirectional;
+pub mod copy_bidirectional_message;
+pub mod copy_multidirectional_message;
+pub mod domain_set;
+pub mod dot_resolver;
+pub mod grpc;
+pub mod happy_eyeballs;
+pub mod health_server;
+pub mod http_client;
+pub mod http_forward;
+pub mod http_handler;
+pub mod ip_rule_index;
+pub mod line_reader;
+pub mod metrics;
+pub mod mux;
+pub mod ocsp;
+pub mod option_util;
+pub mod port_forward_handler;
+pub mod privilege_util;
+pub mod quic_datagram;
+pub mod quic_server;
+pub mod quic_stream;
+pub mod replay_stream;
+pub mod reset_on_failure_stream;
+pub mod resolver;
+pub mod rule_schedule;
+pub mod rustls_util;
+pub mod salt_checker;
+pub mod shadowsocks;
+pub mod share_link;
+pub mod sip003_plugin;
+pub mod snell_handler;
+pub mod snell_udp_stream;
+pub mod socket_util;
+pub mod socks_handler;
+pub mod socks_udp_stream;
+pub mod source_filter;
+pub mod tcp_client_connector;
+pub mod tcp_handler;
+pub mod tcp_handler_util;
+pub mod tcp_server;
+pub mod telemetry;
+pub mod thread_util;
+pub mod timed_salt_checker;
+pub mod tls_fragment_stream;
+pub mod tls_handler;
+pub mod tls_record_stream;
+pub mod trojan_handler;
+#[cfg(target_os = "linux")]
+pub mod udp_batch;
+pub mod udp_direct_message_stream;
+pub mod udp_over_tcp;
+pub mod udp_server;
+pub mod udp_session_table;
+pub mod user_quota;
+pub mod util;
+pub mod vision_stream;
+pub mod vless_handler;
+pub mod vmess;
+pub mod websocket;
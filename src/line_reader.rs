@@ -74,13 +74,35 @@ impl LineReader {
         &self.buf[self.start_offset..self.end_offset]
     }
 
+    // Returns up to max_len bytes, reading from the stream only when nothing is buffered.
+    // Returns an empty slice on EOF.
+    pub async fn read_bytes(
+        &mut self,
+        stream: &mut Box<dyn AsyncStream>,
+        max_len: usize,
+    ) -> std::io::Result<&[u8]> {
+        if self.start_offset == self.end_offset {
+            self.start_offset = 0;
+            self.end_offset = 0;
+            match self.read(stream).await {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionAborted => return Ok(&[]),
+                Err(e) => return Err(e),
+            }
+        }
+        let len = std::cmp::min(max_len, self.end_offset - self.start_offset);
+        let start_offset = self.start_offset;
+        self.start_offset += len;
+        Ok(&self.buf[start_offset..start_offset + len])
+    }
+
     async fn read(&mut self, stream: &mut Box<dyn AsyncStream>) -> std::io::Result<()> {
         // Note that read() needs to work for blocking I/O. So we need to return
         // immediately after a single read() call.
         if self.is_cache_full() {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::ConnectionAborted,
-                "cache is full",
+                std::io::ErrorKind::InvalidData,
+                format!("line is longer than {} bytes", BUFFER_SIZE),
             ));
         }
 
@@ -1,43 +1,95 @@
-use log::debug;
-use shuttle_runtime::CustomError;
-use tokio::fs;
+use std::path::PathBuf;
+
+use log::{debug, error};
+use shuttle_runtime::{CustomError, ResourceFactory};
 use tokio::task::JoinHandle;
 
-use crate::address::NetLocation;
-use crate::config::{update_config, BindLocation, ServerConfig, Transport};
-use crate::quic_server::start_quic_server;
-use crate::tcp_server::start_tcp_server;
-use crate::thread_util::set_num_threads;
+use shoes_shuttle::address::NetLocation;
+use shoes_shuttle::admin_server::start_admin_server;
+use shoes_shuttle::bandwidth_stats::start_stats_store;
+use shoes_shuttle::config::{
+    parse_server_config, update_config, BindLocation, ServerConfig, Transport,
+};
+use shoes_shuttle::config_watcher::start_config_watcher;
+use shoes_shuttle::connection_registry::{connection_registry, start_connection_reaper};
+use shoes_shuttle::health_server::start_health_server;
+use shoes_shuttle::metrics::start_metrics_server;
+use shoes_shuttle::option_util::OneOrSome;
+use shoes_shuttle::privilege_util::hold_accepting;
+#[cfg(target_family = "unix")]
+use shoes_shuttle::privilege_util::{drop_privileges, release_accepting};
+use shoes_shuttle::quic_server::start_quic_server;
+use shoes_shuttle::tcp_server::start_tcp_server;
+use shoes_shuttle::telemetry::start_span_exporter;
+use shoes_shuttle::thread_util::{build_runtime, runtime_worker_threads, set_num_threads};
+use shoes_shuttle::udp_server::start_udp_server;
+use shoes_shuttle::user_quota::start_quota_store;
 
 #[derive(Debug)]
 struct ConfigChanged;
 
-async fn start_server(config: ServerConfig) -> std::io::Result<JoinHandle<()>> {
-    match config.transport {
-        Transport::Tcp => start_tcp_server(config).await,
-        Transport::Quic => start_quic_server(config).await,
-        Transport::Udp => todo!(),
+async fn start_server(config: ServerConfig) -> std::io::Result<Vec<JoinHandle<()>>> {
+    // The TCP server also starts the QUIC listeners it's combined with.
+    if config.has_transport(Transport::Tcp) {
+        start_tcp_server(config).await
+    } else if config.has_transport(Transport::Quic) {
+        start_quic_server(config).await
+    } else {
+        start_udp_server(config).await
     }
 }
 
 struct ShoesService(pub ServerConfig);
 
-#[shuttle_runtime::main]
-async fn shuttle_main() -> Result<ShoesService, shuttle_runtime::Error> {
-    let config_str = fs::read_to_string("config.yaml")
-        .await
-        .expect("fail to read config");
+fn load_config() -> std::io::Result<ServerConfig> {
+    let config_str = std::fs::read_to_string("config.yaml")?;
+    let mut config = parse_server_config("config.yaml", &config_str)?;
+    update_config(&mut config)?;
+    Ok(config)
+}
+
+// The QUIC client endpoints are spread over at most this many threads, unless worker_threads is
+// set in runtime_settings.
+const DEFAULT_MAX_NUM_THREADS: usize = 4;
+
+// Starts the same way as the expansion of #[shuttle_runtime::main], which always builds a default
+// multi-thread runtime, but on a runtime built from runtime_settings. That means calling the
+// doc-hidden shuttle_runtime::__internals::start, so shuttle-runtime is pinned to an exact version
+// in Cargo.toml and this needs to be checked against the macro whenever it's upgraded.
+fn main() {
+    // Config errors are reported by shuttle_main, which then runs on the default runtime.
+    let config = load_config();
+    let runtime_config = config
+        .as_ref()
+        .ok()
+        .and_then(|config| config.runtime_settings.clone())
+        .unwrap_or_default();
 
-    let num_threads = num_cpus::get().min(4);
+    let num_threads = match runtime_config.worker_threads {
+        Some(_) => runtime_worker_threads(&runtime_config),
+        None => runtime_worker_threads(&runtime_config).min(DEFAULT_MAX_NUM_THREADS),
+    };
     set_num_threads(num_threads);
 
-    let mut config = serde_yaml::from_str::<ServerConfig>(&config_str).map_err(CustomError::new)?;
-    update_config(&mut config).unwrap();
+    build_runtime(&runtime_config)
+        .expect("failed to build runtime")
+        .block_on(shuttle_runtime::__internals::start(
+            |_: ResourceFactory| async { Ok(vec![]) },
+            |_: Vec<Vec<u8>>| shuttle_main(config),
+        ));
+}
+
+async fn shuttle_main(
+    config: std::io::Result<ServerConfig>,
+) -> Result<ShoesService, shuttle_runtime::Error> {
+    let config = config.map_err(CustomError::new)?;
 
     debug!("================================================================================");
     debug!("{:#?}", &config);
     debug!("================================================================================");
 
+    connection_registry().set_config_loader(Box::new(load_config));
+
     Ok(ShoesService(config))
 }
 
@@ -45,50 +97,65 @@ async fn shuttle_main() -> Result<ShoesService, shuttle_runtime::Error> {
 impl shuttle_runtime::Service for ShoesService {
     async fn bind(self, addr: std::net::SocketAddr) -> Result<(), shuttle_runtime::Error> {
         let config = self.0;
+        // Listeners don't accept connections until the process has switched user.
+        let run_as = config.run_as.clone();
+        if run_as.is_some() {
+            hold_accepting();
+        }
+        if let Some(ref admin_config) = config.admin_settings {
+            start_admin_server(admin_config.clone())
+                .await
+                .map_err(CustomError::new)?;
+        }
+        if let Some(ref quota_config) = config.quota_settings {
+            start_quota_store(quota_config.clone())
+                .await
+                .map_err(CustomError::new)?;
+        }
+        if let Some(ref stats_config) = config.stats_settings {
+            start_stats_store(stats_config.clone())
+                .await
+                .map_err(CustomError::new)?;
+        }
+        if let Some(ref metrics_config) = config.metrics_settings {
+            start_metrics_server(metrics_config.clone())
+                .await
+                .map_err(CustomError::new)?;
+        }
+        if let Some(ref telemetry_config) = config.telemetry_settings {
+            start_span_exporter(telemetry_config.clone())
+                .await
+                .map_err(CustomError::new)?;
+        }
+        if let Some(ref health_config) = config.health_settings {
+            start_health_server(health_config.clone())
+                .await
+                .map_err(CustomError::new)?;
+        }
+        if let Some(ref reaper_config) = config.reaper_settings {
+            start_connection_reaper(reaper_config.clone());
+        }
+        // The server still runs without the watcher, and can be reloaded from the admin server.
+        if let Err(e) = start_config_watcher(&[PathBuf::from("config.yaml")]) {
+            error!("Failed to watch config.yaml for changes: {}", e);
+        }
         let config = ServerConfig {
-            bind_location: BindLocation::Address(NetLocation::from_socket_addr(addr)),
+            bind_locations: OneOrSome::One(BindLocation::Address(NetLocation::from_socket_addr(
+                addr,
+            ))),
             ..config
         };
-        start_server(config)
-            .await
-            .unwrap()
-            .await
-            .map_err(CustomError::new)?;
+        let server_handles = start_server(config).await.unwrap();
+        #[cfg(target_family = "unix")]
+        if let Some(ref run_as) = run_as {
+            // Every listener is bound by now, and failing here stops the service before anything
+            // is accepted.
+            drop_privileges(run_as).map_err(CustomError::new)?;
+            release_accepting();
+        }
+        for server_handle in server_handles {
+            server_handle.await.map_err(CustomError::new)?;
+        }
         Ok(())
     }
 }
-
-mod address;
-mod async_stream;
-mod client_proxy_selector;
-mod config;
-mod copy_bidirectional;
-mod copy_bidirectional_message;
-mod copy_multidirectional_message;
-mod http_handler;
-mod line_reader;
-mod option_util;
-mod port_forward_handler;
-mod quic_server;
-mod quic_stream;
-mod resolver;
-mod rustls_util;
-mod salt_checker;
-mod shadowsocks;
-mod snell_handler;
-mod snell_udp_stream;
-mod socket_util;
-mod socks_handler;
-mod tcp_client_connector;
-mod tcp_handler;
-mod tcp_handler_util;
-mod tcp_server;
-mod thread_util;
-mod timed_salt_checker;
-mod tls_handler;
-mod trojan_handler;
-mod udp_direct_message_stream;
-mod util;
-mod vless_handler;
-mod vmess;
-mod websocket;
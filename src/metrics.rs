@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{debug, error};
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+use crate::config::MetricsConfig;
+use crate::connection_registry::ConnectionInfo;
+use crate::privilege_util::wait_until_accepting;
+
+const SETUP_SECONDS_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const DURATION_SECONDS_BUCKETS: &[f64] = &[
+    0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 14400.0,
+];
+const BYTES_BUCKETS: &[f64] = &[
+    1024.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+];
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+struct Histogram {
+    buckets: &'static [f64],
+    // The number of observations in each bucket, not including the smaller buckets.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.buckets.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, protocol: &str) {
+        let mut cumulative_count = 0;
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()) {
+            cumulative_count += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{protocol=\"{}\",le=\"{}\"}} {}",
+                name, protocol, bound, cumulative_count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{protocol=\"{}\",le=\"+Inf\"}} {}",
+            name, protocol, self.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{protocol=\"{}\"}} {}",
+            name, protocol, self.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{protocol=\"{}\"}} {}",
+            name, protocol, self.count
+        );
+    }
+}
+
+struct ProtocolMetrics {
+    setup_seconds: Histogram,
+    duration_seconds: Histogram,
+    bytes: Histogram,
+}
+
+impl ProtocolMetrics {
+    fn new() -> Self {
+        Self {
+            setup_seconds: Histogram::new(SETUP_SECONDS_BUCKETS),
+            duration_seconds: Histogram::new(DURATION_SECONDS_BUCKETS),
+            bytes: Histogram::new(BYTES_BUCKETS),
+        }
+    }
+}
+
+// Histograms of completed connections, for each server protocol.
+pub struct ConnectionMetrics {
+    protocols: Mutex<BTreeMap<String, ProtocolMetrics>>,
+}
+
+pub fn connection_metrics() -> &'static ConnectionMetrics {
+    static INSTANCE: OnceLock<ConnectionMetrics> = OnceLock::new();
+    INSTANCE.get_or_init(|| ConnectionMetrics {
+        protocols: Mutex::new(BTreeMap::new()),
+    })
+}
+
+impl ConnectionMetrics {
+    pub fn record(&self, info: &ConnectionInfo) {
+        let mut protocols = self.protocols.lock();
+        let metrics = protocols
+            .entry(info.protocol.clone())
+            .or_insert_with(ProtocolMetrics::new);
+        // Connections that never reached their destination have no setup duration.
+        if let Some(setup_duration) = info.setup_duration() {
+            metrics.setup_seconds.observe(setup_duration.as_secs_f64());
+        }
+        metrics
+            .duration_seconds
+            .observe(info.duration().as_secs_f64());
+        metrics
+            .bytes
+            .observe((info.bytes_sent() + info.bytes_received()) as f64);
+    }
+
+    // The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let protocols = self.protocols.lock();
+        let mut out = String::new();
+        write_family(
+            &mut out,
+            "shoes_connection_setup_seconds",
+            "Time from accepting a connection until its destination was connected.",
+            protocols
+                .iter()
+                .map(|(p, metrics)| (p, &metrics.setup_seconds)),
+        );
+        write_family(
+            &mut out,
+            "shoes_connection_duration_seconds",
+            "How long completed connections were open.",
+            protocols
+                .iter()
+                .map(|(p, metrics)| (p, &metrics.duration_seconds)),
+        );
+        write_family(
+            &mut out,
+            "shoes_connection_bytes",
+            "Bytes sent and received by completed connections.",
+            protocols.iter().map(|(p, metrics)| (p, &metrics.bytes)),
+        );
+        out
+    }
+}
+
+fn write_family<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histograms: impl Iterator<Item = (&'a String, &'a Histogram)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (protocol, histogram) in histograms {
+        histogram.write(out, name, protocol);
+    }
+}
+
+async fn handle_metrics_stream(mut stream: TcpStream) -> std::io::Result<()> {
+    // Only the request line is needed, and the request has no body.
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "metrics request is too large",
+            ));
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "metrics request ended early",
+            ));
+        }
+        request.extend_from_slice(&buf[0..len]);
+    }
+
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let response = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            let body = connection_metrics().render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub async fn start_metrics_server(config: MetricsConfig) -> std::io::Result<JoinHandle<()>> {
+    let MetricsConfig { address } = config;
+
+    println!("Starting metrics server at {}", &address);
+
+    let socket_addr = address.to_socket_addr()?;
+    let listener = tokio::net::TcpListener::bind(socket_addr).await?;
+    Ok(tokio::spawn(async move {
+        wait_until_accepting().await;
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Metrics accept failed: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let result =
+                    tokio::time::timeout(Duration::from_secs(10), handle_metrics_stream(stream))
+                        .await;
+                match result {
+                    Ok(Ok(())) => debug!("Metrics client {} finished", addr),
+                    Ok(Err(e)) => error!("Metrics client {} finished with error: {}", addr, e),
+                    Err(_) => error!("Metrics client {} timed out", addr),
+                }
+            });
+        }
+    }))
+}
@@ -0,0 +1,9 @@
+mod mux_handler;
+mod mux_session;
+mod mux_stream;
+
+pub use mux_handler::MuxDestinationHandler;
+pub use mux_session::{
+    is_mux_location, mux_location, read_session_request, write_stream_request, MuxClientPool,
+    MuxSession,
+};
@@ -0,0 +1,111 @@
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::mux_session::{write_stream_error_response, STREAM_FLAG_UDP, STREAM_STATUS_SUCCESS};
+use crate::async_stream::AsyncStream;
+use crate::option_util::NoneOrOne;
+use crate::socks_handler::read_location;
+use crate::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+
+// Handles a single stream of a mux session, which starts with the stream flags and the
+// destination location in SOCKS address format.
+#[derive(Debug)]
+pub struct MuxDestinationHandler;
+
+#[async_trait]
+impl TcpServerHandler for MuxDestinationHandler {
+    async fn setup_server_stream(
+        &self,
+        mut server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let flags = server_stream.read_u16().await?;
+        let remote_location = read_location(&mut server_stream).await?;
+
+        if flags & STREAM_FLAG_UDP != 0 {
+            let message = "UDP mux streams are not supported";
+            server_stream
+                .write_all(&write_stream_error_response(message))
+                .await?;
+            let _ = server_stream.shutdown().await;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                message,
+            ));
+        }
+
+        Ok(TcpServerSetupResult::TcpForward {
+            remote_location,
+            stream: server_stream,
+            need_initial_flush: false,
+            connection_success_response: Some(Box::new([STREAM_STATUS_SUCCESS])),
+            initial_remote_data: None,
+            override_proxy_provider: NoneOrOne::Unspecified,
+            authenticated_user: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::address::NetLocation;
+    use crate::mux::mux_session::{write_stream_request, STREAM_STATUS_ERROR};
+    use crate::socks_handler::write_location_to_vec;
+
+    async fn setup(
+        request: &[u8],
+    ) -> (
+        tokio::io::DuplexStream,
+        std::io::Result<TcpServerSetupResult>,
+    ) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            MuxDestinationHandler.setup_server_stream(Box::new(server)),
+        )
+        .await
+        .expect("stream request read timed out");
+        (client, result)
+    }
+
+    #[tokio::test]
+    async fn stream_request_is_read() {
+        let location = NetLocation::from_str("example.com:443", None).unwrap();
+        let (_client, result) = setup(&write_stream_request(&location)).await;
+        match result {
+            Ok(TcpServerSetupResult::TcpForward {
+                remote_location,
+                connection_success_response,
+                ..
+            }) => {
+                assert_eq!(remote_location, location);
+                assert_eq!(
+                    connection_success_response.as_deref(),
+                    Some(&[STREAM_STATUS_SUCCESS][..])
+                );
+            }
+            Ok(_) => panic!("unexpected setup result"),
+            Err(e) => panic!("stream request failed: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_stream_is_rejected() {
+        let location = NetLocation::from_str("1.2.3.4:53", None).unwrap();
+        let mut request = STREAM_FLAG_UDP.to_be_bytes().to_vec();
+        request.extend_from_slice(&write_location_to_vec(&location));
+        let (mut client, result) = setup(&request).await;
+        match result {
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported),
+            Ok(_) => panic!("UDP stream was accepted"),
+        }
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response[0], STREAM_STATUS_ERROR);
+        assert!(response.ends_with(b"UDP mux streams are not supported"));
+    }
+}
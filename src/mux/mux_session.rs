@@ -0,0 +1,739 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use super::mux_stream::MuxStream;
+use crate::address::{Address, NetLocation};
+use crate::async_stream::AsyncStream;
+use crate::config::MuxConfig;
+use crate::socks_handler::write_location_to_vec;
+use crate::util::allocate_vec;
+
+// smux v1 framing, see https://github.com/xtaci/smux
+const SMUX_VERSION: u8 = 1;
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const HEADER_SIZE: usize = 8;
+
+pub const MAX_FRAME_DATA_SIZE: usize = 32768;
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Frames buffered for each stream. smux v1 has no per-stream flow control, so a stream that falls
+// this far behind is reset, rather than stopping the reads for every other stream.
+const STREAM_CHANNEL_SIZE: usize = 64;
+const FRAME_CHANNEL_SIZE: usize = 64;
+
+// The location the mux client asks the underlying protocol to connect to, so that the server
+// knows to demultiplex the connection. It's followed by a session request, and each stream starts
+// with a stream request and response. This is the sing-mux negotiation that sing-box uses, limited
+// to smux without padding, so the other end can be shoes, or sing-box with `protocol: smux`.
+// xray's mux.cool is a different protocol and isn't supported.
+// See https://github.com/SagerNet/sing-mux
+const MUX_HOSTNAME: &str = "sp.mux.sing-box.arpa";
+const MUX_PORT: u16 = 444;
+
+// Session request versions, version 1 adds a padding flag.
+const SESSION_VERSION_0: u8 = 0;
+const SESSION_VERSION_1: u8 = 1;
+const SESSION_PROTOCOL_SMUX: u8 = 1;
+
+pub const STREAM_FLAG_UDP: u16 = 1;
+pub const STREAM_STATUS_SUCCESS: u8 = 0;
+pub const STREAM_STATUS_ERROR: u8 = 1;
+
+pub fn mux_location() -> NetLocation {
+    NetLocation::new(Address::Hostname(MUX_HOSTNAME.to_string()), MUX_PORT)
+}
+
+pub fn is_mux_location(location: &NetLocation) -> bool {
+    location.port() == MUX_PORT && location.address().hostname() == Some(MUX_HOSTNAME)
+}
+
+// Reads the session request that the client sends after connecting to the mux location.
+pub async fn read_session_request(stream: &mut Box<dyn AsyncStream>) -> std::io::Result<()> {
+    let mut request = [0u8; 2];
+    stream.read_exact(&mut request).await?;
+    let [version, protocol] = request;
+    if version > SESSION_VERSION_1 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unsupported mux session version: {}", version),
+        ));
+    }
+    if version == SESSION_VERSION_1 && stream.read_u8().await? != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "mux padding is not supported",
+        ));
+    }
+    if protocol != SESSION_PROTOCOL_SMUX {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "unsupported mux protocol {}, only smux is supported",
+                protocol
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// The request at the start of each stream that the client opens.
+pub fn write_stream_request(location: &NetLocation) -> Vec<u8> {
+    let mut request = 0u16.to_be_bytes().to_vec();
+    request.extend_from_slice(&write_location_to_vec(location));
+    request
+}
+
+// The response the server sends when a stream fails, which has the error message as a string
+// prefixed by its varint length.
+pub fn write_stream_error_response(message: &str) -> Vec<u8> {
+    let mut response = vec![STREAM_STATUS_ERROR];
+    let mut length = message.len();
+    while length >= 0x80 {
+        response.push((length as u8) | 0x80);
+        length >>= 7;
+    }
+    response.push(length as u8);
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+// Returns the error for a stream response that isn't a success, when the rest of the response is
+// the data that followed the status.
+pub fn stream_response_error(status: u8, data: &[u8]) -> std::io::Error {
+    if status != STREAM_STATUS_ERROR {
+        return std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unknown mux stream status: {}", status),
+        );
+    }
+
+    let mut length = 0usize;
+    let mut message = None;
+    for (i, byte) in data.iter().take(4).enumerate() {
+        length |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            message = data.get(i + 1..i + 1 + length);
+            break;
+        }
+    }
+    let message = match message {
+        Some(message) => String::from_utf8_lossy(message).into_owned(),
+        // The message didn't arrive in a single frame.
+        None => "unknown error".to_string(),
+    };
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        format!("mux stream failed: {}", message),
+    )
+}
+
+pub struct MuxFrame {
+    cmd: u8,
+    stream_id: u32,
+    data: Vec<u8>,
+}
+
+impl MuxFrame {
+    pub fn push(stream_id: u32, data: Vec<u8>) -> Self {
+        Self {
+            cmd: CMD_PSH,
+            stream_id,
+            data,
+        }
+    }
+
+    pub fn fin(stream_id: u32) -> Self {
+        Self {
+            cmd: CMD_FIN,
+            stream_id,
+            data: vec![],
+        }
+    }
+
+    fn syn(stream_id: u32) -> Self {
+        Self {
+            cmd: CMD_SYN,
+            stream_id,
+            data: vec![],
+        }
+    }
+
+    fn nop() -> Self {
+        Self {
+            cmd: CMD_NOP,
+            stream_id: 0,
+            data: vec![],
+        }
+    }
+}
+
+// The session's end of a stream.
+struct StreamEntry {
+    data_sender: mpsc::Sender<Vec<u8>>,
+    // Set when the stream is reset, so that it fails instead of reading EOF.
+    reset: Arc<AtomicBool>,
+}
+
+struct SessionState {
+    streams: HashMap<u32, StreamEntry>,
+    // when the last stream was closed, used to tear down idle sessions.
+    idle_since: Instant,
+}
+
+pub struct MuxSession {
+    max_streams: usize,
+    idle_timeout: Duration,
+    state: Mutex<SessionState>,
+    closed: AtomicBool,
+    next_stream_id: AtomicU32,
+    frame_sender: mpsc::Sender<MuxFrame>,
+}
+
+impl MuxSession {
+    pub fn new_client(stream: Box<dyn AsyncStream>, config: &MuxConfig) -> Arc<Self> {
+        // smux clients use odd stream ids.
+        Self::start(stream, config, 1, None)
+    }
+
+    pub fn new_server(
+        stream: Box<dyn AsyncStream>,
+        config: &MuxConfig,
+    ) -> (Arc<Self>, mpsc::Receiver<MuxStream>) {
+        let (accept_sender, accept_receiver) = mpsc::channel(config.max_streams as usize);
+        let session = Self::start(stream, config, 2, Some(accept_sender));
+        (session, accept_receiver)
+    }
+
+    fn start(
+        stream: Box<dyn AsyncStream>,
+        config: &MuxConfig,
+        first_stream_id: u32,
+        accept_sender: Option<mpsc::Sender<MuxStream>>,
+    ) -> Arc<Self> {
+        let (frame_sender, frame_receiver) = mpsc::channel(FRAME_CHANNEL_SIZE);
+
+        let session = Arc::new(Self {
+            max_streams: config.max_streams as usize,
+            idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+            state: Mutex::new(SessionState {
+                streams: HashMap::new(),
+                idle_since: Instant::now(),
+            }),
+            closed: AtomicBool::new(false),
+            next_stream_id: AtomicU32::new(first_stream_id),
+            frame_sender,
+        });
+
+        let (reader, writer) = tokio::io::split(stream);
+        let cloned_session = session.clone();
+        tokio::spawn(async move {
+            let result = tokio::select! {
+                result = run_reader(cloned_session.clone(), reader, accept_sender) => result,
+                result = run_writer(cloned_session.clone(), writer, frame_receiver) => result,
+            };
+            if let Err(e) = result {
+                debug!("Mux session finished with error: {}", e);
+            }
+            cloned_session.close();
+        });
+
+        session
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        // Dropping the senders causes all open streams to read EOF.
+        self.state.lock().streams.clear();
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        !self.is_closed() && self.state.lock().streams.len() < self.max_streams
+    }
+
+    // Returns None when the session is closed, or already has max_streams streams. The stream is
+    // counted as soon as its slot is taken, so that concurrent callers can't go over the limit.
+    pub async fn open_stream(self: &Arc<Self>) -> Option<MuxStream> {
+        let stream = {
+            let mut state = self.state.lock();
+            if self.is_closed() || state.streams.len() >= self.max_streams {
+                return None;
+            }
+            let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
+            // The client reads the server's response at the start of each stream.
+            self.register_stream(&mut state, stream_id, true)
+        };
+
+        if self
+            .send_frame(MuxFrame::syn(stream.stream_id()))
+            .await
+            .is_err()
+        {
+            // The writer stopped, so the session is about to be closed anyway.
+            self.close();
+            return None;
+        }
+
+        Some(stream)
+    }
+
+    fn register_stream(
+        self: &Arc<Self>,
+        state: &mut SessionState,
+        stream_id: u32,
+        response_pending: bool,
+    ) -> MuxStream {
+        let (data_sender, data_receiver) = mpsc::channel(STREAM_CHANNEL_SIZE);
+        let reset = Arc::new(AtomicBool::new(false));
+        state.streams.insert(
+            stream_id,
+            StreamEntry {
+                data_sender,
+                reset: reset.clone(),
+            },
+        );
+        MuxStream::new(
+            stream_id,
+            self.clone(),
+            self.frame_sender.clone(),
+            data_receiver,
+            reset,
+            response_pending,
+        )
+    }
+
+    pub fn remove_stream(&self, stream_id: u32) {
+        self.remove_stream_entry(stream_id);
+    }
+
+    // Closes the stream on both ends. It fails once it has read the data that was already
+    // buffered.
+    async fn reset_stream(&self, stream_id: u32) -> std::io::Result<()> {
+        if let Some(entry) = self.remove_stream_entry(stream_id) {
+            entry.reset.store(true, Ordering::Relaxed);
+        }
+        self.send_frame(MuxFrame::fin(stream_id)).await
+    }
+
+    fn remove_stream_entry(&self, stream_id: u32) -> Option<StreamEntry> {
+        let mut state = self.state.lock();
+        let entry = state.streams.remove(&stream_id);
+        if entry.is_some() && state.streams.is_empty() {
+            state.idle_since = Instant::now();
+        }
+        entry
+    }
+
+    async fn send_frame(&self, frame: MuxFrame) -> std::io::Result<()> {
+        self.frame_sender.clone().send(frame).await.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "mux session is closed")
+        })
+    }
+
+    // Marks the session closed if it has been idle for too long. This is done under the state
+    // lock, so that open_stream can't add a stream to a session that is being torn down.
+    fn close_if_idle(&self) -> bool {
+        let state = self.state.lock();
+        let is_idle = state.streams.is_empty() && state.idle_since.elapsed() >= self.idle_timeout;
+        if is_idle {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        is_idle
+    }
+}
+
+async fn run_reader(
+    session: Arc<MuxSession>,
+    mut reader: ReadHalf<Box<dyn AsyncStream>>,
+    mut accept_sender: Option<mpsc::Sender<MuxStream>>,
+) -> std::io::Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    loop {
+        reader.read_exact(&mut header).await?;
+
+        if header[0] != SMUX_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported mux version: {}", header[0]),
+            ));
+        }
+
+        let cmd = header[1];
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let stream_id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        let mut data = allocate_vec(length);
+        if length > 0 {
+            reader.read_exact(&mut data).await?;
+        }
+
+        match cmd {
+            CMD_SYN => {
+                let accept_sender = match accept_sender {
+                    Some(ref mut s) => s,
+                    None => {
+                        warn!("Ignoring mux stream {} opened by server", stream_id);
+                        session.send_frame(MuxFrame::fin(stream_id)).await?;
+                        continue;
+                    }
+                };
+
+                let stream = {
+                    let mut state = session.state.lock();
+                    if state.streams.contains_key(&stream_id) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("duplicate mux stream id: {}", stream_id),
+                        ));
+                    }
+                    if state.streams.len() >= session.max_streams {
+                        None
+                    } else {
+                        Some(session.register_stream(&mut state, stream_id, false))
+                    }
+                };
+
+                match stream {
+                    Some(stream) => {
+                        if accept_sender.send(stream).await.is_err() {
+                            // Nothing is accepting streams anymore.
+                            return Ok(());
+                        }
+                    }
+                    None => {
+                        warn!(
+                            "Rejecting mux stream {}, max streams ({}) reached",
+                            stream_id, session.max_streams
+                        );
+                        session.send_frame(MuxFrame::fin(stream_id)).await?;
+                    }
+                }
+            }
+            CMD_FIN => {
+                // Dropping the sender causes the stream to read EOF after any pending data.
+                session.remove_stream(stream_id);
+            }
+            CMD_PSH => {
+                let is_full = match session.state.lock().streams.get_mut(&stream_id) {
+                    // Any other error means the stream was dropped, so there's nobody to read the
+                    // data.
+                    Some(entry) => {
+                        matches!(entry.data_sender.try_send(data), Err(e) if e.is_full())
+                    }
+                    None => false,
+                };
+                if is_full {
+                    warn!(
+                        "Resetting mux stream {}, it isn't reading its data fast enough",
+                        stream_id
+                    );
+                    session.reset_stream(stream_id).await?;
+                }
+            }
+            CMD_NOP => (),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown mux command: {}", cmd),
+                ));
+            }
+        }
+    }
+}
+
+async fn run_writer(
+    session: Arc<MuxSession>,
+    mut writer: WriteHalf<Box<dyn AsyncStream>>,
+    mut frame_receiver: mpsc::Receiver<MuxFrame>,
+) -> std::io::Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    loop {
+        let frame = match tokio::time::timeout(KEEPALIVE_INTERVAL, frame_receiver.next()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                return Ok(());
+            }
+            Err(_) => {
+                if session.is_closed() {
+                    return Ok(());
+                }
+                if session.close_if_idle() {
+                    debug!("Closing idle mux session");
+                    let _ = writer.shutdown().await;
+                    return Ok(());
+                }
+                MuxFrame::nop()
+            }
+        };
+
+        header[0] = SMUX_VERSION;
+        header[1] = frame.cmd;
+        header[2..4].copy_from_slice(&(frame.data.len() as u16).to_le_bytes());
+        header[4..8].copy_from_slice(&frame.stream_id.to_le_bytes());
+
+        writer.write_all(&header).await?;
+        if !frame.data.is_empty() {
+            writer.write_all(&frame.data).await?;
+        }
+        writer.flush().await?;
+    }
+}
+
+pub struct MuxClientPool {
+    config: MuxConfig,
+    sessions: Mutex<Vec<Arc<MuxSession>>>,
+}
+
+impl std::fmt::Debug for MuxClientPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxClientPool")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl MuxClientPool {
+    pub fn new(config: MuxConfig) -> Self {
+        Self {
+            config,
+            sessions: Mutex::new(vec![]),
+        }
+    }
+
+    // Returns a session that can take another stream, if there is one.
+    pub fn get_session(&self) -> Option<Arc<MuxSession>> {
+        let mut sessions = self.sessions.lock();
+        sessions.retain(|session| !session.is_closed());
+        sessions
+            .iter()
+            .find(|session| session.has_capacity())
+            .cloned()
+    }
+
+    // Sends the session request on a stream connected to the mux location, and starts a session
+    // on it.
+    pub async fn add_session(
+        &self,
+        mut stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<Arc<MuxSession>> {
+        stream
+            .write_all(&[SESSION_VERSION_0, SESSION_PROTOCOL_SMUX])
+            .await?;
+        let session = MuxSession::new_client(stream, &self.config);
+        self.sessions.lock().push(session.clone());
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn frame(cmd: u8, stream_id: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![SMUX_VERSION, cmd];
+        frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&stream_id.to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    async fn read_frame(stream: &mut DuplexStream) -> (u8, u32, Vec<u8>) {
+        let mut header = [0u8; HEADER_SIZE];
+        stream.read_exact(&mut header).await.unwrap();
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let mut data = vec![0u8; length];
+        stream.read_exact(&mut data).await.unwrap();
+        let stream_id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        (header[1], stream_id, data)
+    }
+
+    #[tokio::test]
+    async fn slow_stream_is_reset_without_blocking_other_streams() {
+        let config = MuxConfig {
+            max_streams: 8,
+            idle_timeout_secs: 60,
+        };
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let (_session, mut accepted_streams) = MuxSession::new_server(Box::new(server), &config);
+
+        client.write_all(&frame(CMD_SYN, 1, &[])).await.unwrap();
+        let mut slow_stream = accepted_streams.next().await.unwrap();
+
+        // The channel holds a frame more than its size, since it has a single sender. The frame
+        // after that doesn't fit.
+        let buffered_frames = STREAM_CHANNEL_SIZE + 1;
+        for i in 0..=buffered_frames {
+            client
+                .write_all(&frame(CMD_PSH, 1, &[i as u8]))
+                .await
+                .unwrap();
+        }
+        client.write_all(&frame(CMD_SYN, 3, &[])).await.unwrap();
+        client
+            .write_all(&frame(CMD_PSH, 3, b"hello"))
+            .await
+            .unwrap();
+
+        let mut other_stream = accepted_streams.next().await.unwrap();
+        let mut data = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), other_stream.read_exact(&mut data))
+            .await
+            .expect("other stream was blocked by the slow stream")
+            .unwrap();
+        assert_eq!(&data, b"hello");
+
+        // The client is told that the slow stream is closed.
+        assert_eq!(read_frame(&mut client).await, (CMD_FIN, 1, vec![]));
+
+        // The slow stream reads what was buffered, then fails rather than reading EOF.
+        let mut data = vec![];
+        let error = slow_stream.read_to_end(&mut data).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(data, (0..buffered_frames as u8).collect::<Vec<_>>());
+        assert_eq!(
+            slow_stream.write_all(b"late").await.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[tokio::test]
+    async fn open_stream_respects_max_streams() {
+        let config = MuxConfig {
+            max_streams: 2,
+            idle_timeout_secs: 60,
+        };
+        let (_server, client) = tokio::io::duplex(1 << 20);
+        let session = MuxSession::new_client(Box::new(client), &config);
+
+        let first_stream = session.open_stream().await.unwrap();
+        let _second_stream = session.open_stream().await.unwrap();
+        assert!(!session.has_capacity());
+        assert!(session.open_stream().await.is_none());
+
+        drop(first_stream);
+        assert!(session.open_stream().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn open_stream_fails_on_closed_session() {
+        let config = MuxConfig {
+            max_streams: 8,
+            idle_timeout_secs: 60,
+        };
+        let (server, client) = tokio::io::duplex(1 << 20);
+        let session = MuxSession::new_client(Box::new(client), &config);
+        drop(server);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !session.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session wasn't closed");
+        assert!(session.open_stream().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn client_stream_reads_the_stream_response() {
+        let config = MuxConfig {
+            max_streams: 8,
+            idle_timeout_secs: 60,
+        };
+        let (mut server, client) = tokio::io::duplex(1 << 20);
+        let pool = MuxClientPool::new(config);
+        let session = pool.add_session(Box::new(client)).await.unwrap();
+
+        let mut session_request = [0u8; 2];
+        server.read_exact(&mut session_request).await.unwrap();
+        assert_eq!(session_request, [SESSION_VERSION_0, SESSION_PROTOCOL_SMUX]);
+
+        let mut stream = session.open_stream().await.unwrap();
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 1, vec![]));
+        server
+            .write_all(&frame(CMD_PSH, 1, &[STREAM_STATUS_SUCCESS]))
+            .await
+            .unwrap();
+        server
+            .write_all(&frame(CMD_PSH, 1, b"hello"))
+            .await
+            .unwrap();
+        let mut data = [0u8; 5];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+
+        let mut failed_stream = session.open_stream().await.unwrap();
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 3, vec![]));
+        server
+            .write_all(&frame(
+                CMD_PSH,
+                3,
+                &write_stream_error_response("connection refused"),
+            ))
+            .await
+            .unwrap();
+        let error = failed_stream.read(&mut [0u8; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(error.to_string(), "mux stream failed: connection refused");
+
+        let mut unanswered_stream = session.open_stream().await.unwrap();
+        assert_eq!(read_frame(&mut server).await, (CMD_SYN, 5, vec![]));
+        server.write_all(&frame(CMD_FIN, 5, &[])).await.unwrap();
+        let error = unanswered_stream.read(&mut [0u8; 16]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    async fn read_session_request_from(data: &[u8]) -> std::io::Result<()> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(data).await.unwrap();
+        let mut server: Box<dyn AsyncStream> = Box::new(server);
+        // The client end is kept open, so reading too much times out.
+        tokio::time::timeout(Duration::from_secs(5), read_session_request(&mut server))
+            .await
+            .expect("session request read timed out")
+    }
+
+    #[tokio::test]
+    async fn session_requests_are_read() {
+        read_session_request_from(&[SESSION_VERSION_0, SESSION_PROTOCOL_SMUX])
+            .await
+            .unwrap();
+        read_session_request_from(&[SESSION_VERSION_1, SESSION_PROTOCOL_SMUX, 0])
+            .await
+            .unwrap();
+
+        for (request, kind) in [
+            (
+                vec![2, SESSION_PROTOCOL_SMUX],
+                std::io::ErrorKind::InvalidData,
+            ),
+            (
+                vec![SESSION_VERSION_1, SESSION_PROTOCOL_SMUX, 1],
+                std::io::ErrorKind::Unsupported,
+            ),
+            // h2mux and yamux
+            (vec![SESSION_VERSION_0, 0], std::io::ErrorKind::Unsupported),
+            (vec![SESSION_VERSION_0, 2], std::io::ErrorKind::Unsupported),
+        ] {
+            let error = read_session_request_from(&request).await.unwrap_err();
+            assert_eq!(error.kind(), kind, "request {:?}", request);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::{ready, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::mux_session::{
+    stream_response_error, MuxFrame, MuxSession, MAX_FRAME_DATA_SIZE, STREAM_STATUS_SUCCESS,
+};
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+pub struct MuxStream {
+    stream_id: u32,
+    session: Arc<MuxSession>,
+    frame_sender: mpsc::Sender<MuxFrame>,
+    data_receiver: mpsc::Receiver<Vec<u8>>,
+    // Set by the session when the stream fell too far behind in reading its data.
+    reset: Arc<AtomicBool>,
+    // Set for client streams until the status byte that the server sends first was read.
+    response_pending: bool,
+    read_data: Vec<u8>,
+    read_offset: usize,
+    write_closed: bool,
+}
+
+impl MuxStream {
+    pub fn new(
+        stream_id: u32,
+        session: Arc<MuxSession>,
+        frame_sender: mpsc::Sender<MuxFrame>,
+        data_receiver: mpsc::Receiver<Vec<u8>>,
+        reset: Arc<AtomicBool>,
+        response_pending: bool,
+    ) -> Self {
+        Self {
+            stream_id,
+            session,
+            frame_sender,
+            data_receiver,
+            reset,
+            response_pending,
+            read_data: vec![],
+            read_offset: 0,
+            write_closed: false,
+        }
+    }
+
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    fn is_reset(&self) -> bool {
+        self.reset.load(Ordering::Relaxed)
+    }
+}
+
+fn session_closed_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "mux session is closed")
+}
+
+fn stream_reset_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionReset,
+        "mux stream was reset, it didn't read its data fast enough",
+    )
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_offset < this.read_data.len() && this.response_pending {
+                this.response_pending = false;
+                let status = this.read_data[this.read_offset];
+                this.read_offset += 1;
+                if status != STREAM_STATUS_SUCCESS {
+                    let error = stream_response_error(status, &this.read_data[this.read_offset..]);
+                    this.read_offset = this.read_data.len();
+                    return Poll::Ready(Err(error));
+                }
+                continue;
+            }
+
+            if this.read_offset < this.read_data.len() {
+                let remaining = &this.read_data[this.read_offset..];
+                let read_amount = std::cmp::min(remaining.len(), buf.remaining());
+                buf.put_slice(&remaining[0..read_amount]);
+                this.read_offset += read_amount;
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(this.data_receiver.poll_next_unpin(cx)) {
+                Some(data) => {
+                    this.read_data = data;
+                    this.read_offset = 0;
+                }
+                None if this.is_reset() => return Poll::Ready(Err(stream_reset_error())),
+                None if this.response_pending => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "mux stream closed before the server responded",
+                    )));
+                }
+                // The remote end sent FIN, or the session closed.
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.is_reset() {
+            return Poll::Ready(Err(stream_reset_error()));
+        }
+        if this.write_closed || this.session.is_closed() {
+            return Poll::Ready(Err(session_closed_error()));
+        }
+
+        ready!(this.frame_sender.poll_ready(cx)).map_err(|_| session_closed_error())?;
+
+        let write_amount = std::cmp::min(buf.len(), MAX_FRAME_DATA_SIZE);
+        this.frame_sender
+            .start_send(MuxFrame::push(
+                this.stream_id,
+                buf[0..write_amount].to_vec(),
+            ))
+            .map_err(|_| session_closed_error())?;
+
+        Poll::Ready(Ok(write_amount))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // The session flushes after writing each frame.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        // The session already sent FIN for a reset stream.
+        if this.write_closed || this.is_reset() {
+            return Poll::Ready(Ok(()));
+        }
+
+        ready!(this.frame_sender.poll_ready(cx)).map_err(|_| session_closed_error())?;
+        this.frame_sender
+            .start_send(MuxFrame::fin(this.stream_id))
+            .map_err(|_| session_closed_error())?;
+        this.write_closed = true;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for MuxStream {
+    // Keepalives are sent for the whole session instead.
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for MuxStream {}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.session.remove_stream(self.stream_id);
+        if !self.write_closed && !self.is_reset() && !self.session.is_closed() {
+            // Send from a task so that the FIN is queued after any data that was already
+            // written.
+            let mut frame_sender = self.frame_sender.clone();
+            let stream_id = self.stream_id;
+            tokio::spawn(async move {
+                let _ = frame_sender.send(MuxFrame::fin(stream_id)).await;
+            });
+        }
+    }
+}
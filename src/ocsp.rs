@@ -0,0 +1,413 @@
+// Staples OCSP responses (RFC 6960) to the server certificate, so that clients don't need to ask
+// the certificate authority whether it was revoked.
+
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+use parking_lot::RwLock;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+use crate::config::OcspStaplingConfig;
+use crate::http_client::http_request;
+use crate::rustls_util::{
+    der, der_sequence, parse_certificate, parse_der_time, read_der_element, CertificateFields,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Used when a response has no next update time, after which a newer response should be
+// available.
+const DEFAULT_VALIDITY_SECS: u64 = 24 * 60 * 60;
+
+const OID_AUTHORITY_INFO_ACCESS: &[u8] =
+    &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+const OID_OCSP: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+const OID_OCSP_BASIC: &[u8] = &[
+    0x06, 0x09, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01,
+];
+const SHA1_ALGORITHM: &[u8] = &[
+    0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00,
+];
+
+fn ocsp_error(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// A certificate resolver whose certificate can be stapled, which is the one it serves to every
+// client.
+pub trait StaplingSource: ResolvesServerCert {
+    fn current_certified_key(&self) -> Option<Arc<CertifiedKey>>;
+}
+
+pub struct StaticCertResolver(Arc<CertifiedKey>);
+
+impl StaticCertResolver {
+    pub fn new(certified_key: CertifiedKey) -> Self {
+        Self(Arc::new(certified_key))
+    }
+}
+
+impl ResolvesServerCert for StaticCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+impl StaplingSource for StaticCertResolver {
+    fn current_certified_key(&self) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+struct StapledCertificate {
+    // The certificate from the source, and a copy of it with the response.
+    original: Arc<CertifiedKey>,
+    stapled: Arc<CertifiedKey>,
+    this_update: u64,
+    next_update: u64,
+}
+
+pub struct OcspStapler {
+    source: Arc<dyn StaplingSource>,
+    stapled: RwLock<Option<StapledCertificate>>,
+}
+
+impl std::fmt::Debug for OcspStapler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OcspStapler")
+            .field(
+                "next_update",
+                &self.stapled.read().as_ref().map(|s| s.next_update),
+            )
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for OcspStapler {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certified_key = self.source.resolve(client_hello)?;
+        match *self.stapled.read() {
+            Some(ref stapled)
+                if Arc::ptr_eq(&stapled.original, &certified_key)
+                    && stapled.next_update > unix_time() =>
+            {
+                Some(stapled.stapled.clone())
+            }
+            _ => Some(certified_key),
+        }
+    }
+}
+
+impl OcspStapler {
+    fn staple(&self, original: Arc<CertifiedKey>, response: Vec<u8>, status: &OcspStatus) {
+        let mut stapled = CertifiedKey::new(original.cert.clone(), original.key.clone());
+        stapled.ocsp = Some(response);
+        stapled.sct_list = original.sct_list.clone();
+        *self.stapled.write() = Some(StapledCertificate {
+            original,
+            stapled: Arc::new(stapled),
+            this_update: status.this_update,
+            next_update: status.next_update,
+        });
+    }
+
+    // Whether the certificate already has a response that doesn't need refreshing yet, which is
+    // halfway through its validity.
+    fn is_fresh(&self, certified_key: &Arc<CertifiedKey>) -> bool {
+        match *self.stapled.read() {
+            Some(ref stapled) => {
+                Arc::ptr_eq(&stapled.original, certified_key)
+                    && unix_time()
+                        < stapled.this_update + (stapled.next_update - stapled.this_update) / 2
+            }
+            None => false,
+        }
+    }
+}
+
+// Returns the resolver that staples responses to the certificates from the source, and starts
+// refreshing the responses until it's dropped.
+pub fn create_ocsp_stapler(
+    config: &OcspStaplingConfig,
+    source: Arc<dyn StaplingSource>,
+) -> Arc<OcspStapler> {
+    let stapler = Arc::new(OcspStapler {
+        source,
+        stapled: RwLock::new(None),
+    });
+    // A saved response is used right away, so that the first connections have it.
+    if let Some(ref response_file) = config.response_file {
+        if let Err(e) = load_response_file(&stapler, response_file) {
+            debug!("Failed to load OCSP response from {}: {}", response_file, e);
+        }
+    }
+    tokio::spawn(run_ocsp_refresher(config.clone(), Arc::downgrade(&stapler)));
+    stapler
+}
+
+async fn run_ocsp_refresher(config: OcspStaplingConfig, stapler: Weak<OcspStapler>) {
+    loop {
+        let stapler = match stapler.upgrade() {
+            Some(stapler) => stapler,
+            None => return,
+        };
+        if let Err(e) = refresh_response(&config, &stapler).await {
+            error!("Failed to refresh OCSP response: {}", e);
+        }
+        drop(stapler);
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+fn load_response_file(stapler: &OcspStapler, response_file: &str) -> std::io::Result<bool> {
+    let certified_key = match stapler.source.current_certified_key() {
+        Some(certified_key) => certified_key,
+        None => return Ok(false),
+    };
+    let response = std::fs::read(response_file)?;
+    if let Some(ref stapled) = *stapler.stapled.read() {
+        if Arc::ptr_eq(&stapled.original, &certified_key)
+            && stapled.stapled.ocsp.as_ref() == Some(&response)
+        {
+            return Ok(false);
+        }
+    }
+    let status = check_response(&certified_key, &response)?;
+    stapler.staple(certified_key, response, &status);
+    Ok(true)
+}
+
+async fn refresh_response(
+    config: &OcspStaplingConfig,
+    stapler: &OcspStapler,
+) -> std::io::Result<()> {
+    if !config.fetch {
+        // The file is updated by something else, so it's reread when it changes.
+        let response_file = config.response_file.as_ref().unwrap();
+        if load_response_file(stapler, response_file)? {
+            info!("Loaded OCSP response from {}", response_file);
+        }
+        return Ok(());
+    }
+
+    // The certificate isn't available yet when it's still being obtained with ACME.
+    let certified_key = match stapler.source.current_certified_key() {
+        Some(certified_key) => certified_key,
+        None => return Ok(()),
+    };
+    if stapler.is_fresh(&certified_key) {
+        return Ok(());
+    }
+
+    let response = fetch_response(&certified_key).await?;
+    let status = check_response(&certified_key, &response)?;
+    if let Some(ref response_file) = config.response_file {
+        save_response_file(Path::new(response_file), &response)?;
+    }
+    info!(
+        "Fetched OCSP response, valid for {} hours",
+        status.next_update.saturating_sub(unix_time()) / 3600
+    );
+    stapler.staple(certified_key, response, &status);
+    Ok(())
+}
+
+// Writes to a temporary file first, so that the file is never partially written.
+fn save_response_file(path: &Path, response: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, response)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+// Returns the OCSP responder URL from the authority information access extension.
+fn ocsp_responder_url(cert: &CertificateFields) -> Option<String> {
+    let (_, mut access_descriptions, _) =
+        read_der_element(cert.extension(OID_AUTHORITY_INFO_ACCESS)?)?;
+    while !access_descriptions.is_empty() {
+        let (_, access_description, next) = read_der_element(access_descriptions)?;
+        access_descriptions = next;
+        let (method, _, location) = read_der_element(access_description)?;
+        let (location, url, _) = read_der_element(location)?;
+        // The location is a uniformResourceIdentifier.
+        if method == OID_OCSP && location[0] == 0x86 {
+            return String::from_utf8(url.to_vec()).ok();
+        }
+    }
+    None
+}
+
+fn invalid_certificate() -> std::io::Error {
+    ocsp_error("failed to parse certificate".to_string())
+}
+
+// The public key in the public key info, without the unused bits count of the bit string.
+fn public_key_bits(public_key_info: &[u8]) -> Option<&[u8]> {
+    let (_, public_key_info, _) = read_der_element(public_key_info)?;
+    let remaining = read_der_element(public_key_info)?.2;
+    let (_, public_key, _) = read_der_element(remaining)?;
+    public_key.get(1..)
+}
+
+// The CertID of the certificate, which identifies it by the issuer and its serial number.
+fn create_cert_id(cert: &CertificateFields, issuer_cert_der: &[u8]) -> std::io::Result<Vec<u8>> {
+    let public_key = parse_certificate(issuer_cert_der)
+        .and_then(|issuer_cert| public_key_bits(issuer_cert.public_key_info))
+        .ok_or_else(invalid_certificate)?;
+    let issuer_name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, cert.issuer);
+    let issuer_key_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, public_key);
+    Ok(der_sequence(&[
+        SHA1_ALGORITHM,
+        &der(0x04, issuer_name_hash.as_ref()),
+        &der(0x04, issuer_key_hash.as_ref()),
+        cert.serial_number,
+    ]))
+}
+
+async fn fetch_response(certified_key: &CertifiedKey) -> std::io::Result<Vec<u8>> {
+    let cert = certified_key
+        .cert
+        .first()
+        .and_then(|cert| parse_certificate(&cert.0))
+        .ok_or_else(invalid_certificate)?;
+    let issuer_cert = certified_key
+        .cert
+        .get(1)
+        .ok_or_else(|| ocsp_error("certificate chain has no issuer certificate".to_string()))?;
+    let url = ocsp_responder_url(&cert)
+        .ok_or_else(|| ocsp_error("certificate has no OCSP responder".to_string()))?;
+
+    // The request has a single certificate, and no optional fields.
+    let cert_id = create_cert_id(&cert, &issuer_cert.0)?;
+    let request = der_sequence(&[&der_sequence(&[&der_sequence(&[&der_sequence(&[
+        &cert_id,
+    ])])])]);
+
+    debug!("Fetching OCSP response from {}", url);
+    let response = http_request(
+        "POST",
+        &url,
+        &[("Content-Type", "application/ocsp-request")],
+        &request,
+    )
+    .await?;
+    if !response.is_success() {
+        return Err(ocsp_error(format!(
+            "OCSP responder {} returned {}",
+            url, response.status_line
+        )));
+    }
+    Ok(response.body)
+}
+
+struct OcspStatus {
+    this_update: u64,
+    next_update: u64,
+}
+
+// Checks that the response says the certificate is good and hasn't expired. The signature is
+// checked by clients.
+fn check_response(certified_key: &CertifiedKey, response: &[u8]) -> std::io::Result<OcspStatus> {
+    let serial_number = certified_key
+        .cert
+        .first()
+        .and_then(|cert| parse_certificate(&cert.0))
+        .ok_or_else(invalid_certificate)?
+        .serial_number;
+    let status = parse_response(response, serial_number)?;
+    if status.next_update <= unix_time() {
+        return Err(ocsp_error("OCSP response has expired".to_string()));
+    }
+    Ok(status)
+}
+
+fn invalid_response() -> std::io::Error {
+    ocsp_error("failed to parse OCSP response".to_string())
+}
+
+// Returns the contents of the responses sequence, from the response bytes after the response
+// status. The response bytes are explicitly tagged, and have a BasicOCSPResponse.
+fn single_responses(response_bytes: &[u8]) -> Option<&[u8]> {
+    let (_, response_bytes, _) = read_der_element(response_bytes)?;
+    let (_, response_bytes, _) = read_der_element(response_bytes)?;
+    let (response_type, _, remaining) = read_der_element(response_bytes)?;
+    if response_type != OID_OCSP_BASIC {
+        return None;
+    }
+    let (_, basic_response, _) = read_der_element(remaining)?;
+    let (_, basic_response, _) = read_der_element(basic_response)?;
+    let (_, mut response_data, _) = read_der_element(basic_response)?;
+    // Skip the optional version, and the responder id and time it was produced.
+    if response_data.first() == Some(&0xa0) {
+        response_data = read_der_element(response_data)?.2;
+    }
+    response_data = read_der_element(read_der_element(response_data)?.2)?.2;
+    Some(read_der_element(response_data)?.1)
+}
+
+// The serial number follows the hash algorithm, issuer name hash and issuer key hash.
+fn cert_id_serial_number(mut cert_id: &[u8]) -> Option<&[u8]> {
+    for _ in 0..3 {
+        cert_id = read_der_element(cert_id)?.2;
+    }
+    Some(read_der_element(cert_id)?.0)
+}
+
+fn parse_response(response: &[u8], serial_number: &[u8]) -> std::io::Result<OcspStatus> {
+    let (_, response, _) = read_der_element(response).ok_or_else(invalid_response)?;
+    let (response_status, status, remaining) =
+        read_der_element(response).ok_or_else(invalid_response)?;
+    if response_status[0] != 0x0a || status != [0] {
+        return Err(ocsp_error(format!(
+            "OCSP responder returned status {:?}",
+            status
+        )));
+    }
+
+    let single_responses = single_responses(remaining).ok_or_else(invalid_response)?;
+    let mut remaining = single_responses;
+    while !remaining.is_empty() {
+        let (_, single_response, next) =
+            read_der_element(remaining).ok_or_else(invalid_response)?;
+        remaining = next;
+        let (_, cert_id, rest) = read_der_element(single_response).ok_or_else(invalid_response)?;
+        let response_serial_number = cert_id_serial_number(cert_id).ok_or_else(invalid_response)?;
+        if response_serial_number != serial_number {
+            continue;
+        }
+
+        let (cert_status, _, rest) = read_der_element(rest).ok_or_else(invalid_response)?;
+        match cert_status[0] {
+            0x80 => {}
+            0xa1 => return Err(ocsp_error("certificate was revoked".to_string())),
+            _ => return Err(ocsp_error("certificate status is unknown".to_string())),
+        }
+        let (this_update, _, rest) = read_der_element(rest).ok_or_else(invalid_response)?;
+        let this_update = parse_der_time(this_update).ok_or_else(invalid_response)?;
+        let next_update = match read_der_element(rest) {
+            Some((element, next_update, _)) if element[0] == 0xa0 => {
+                parse_der_time(next_update).ok_or_else(invalid_response)?
+            }
+            _ => this_update + DEFAULT_VALIDITY_SECS,
+        };
+        if next_update <= this_update {
+            return Err(invalid_response());
+        }
+        return Ok(OcspStatus {
+            this_update,
+            next_update,
+        });
+    }
+    Err(ocsp_error(
+        "OCSP response is for a different certificate".to_string(),
+    ))
+}
@@ -1,3 +1,4 @@
+use serde::de::IntoDeserializer;
 use serde::Deserialize;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -61,16 +62,63 @@ impl<T> NoneOrOne<T> {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+// A list in a config, which can be written as a sequence, a single item, or a comma-separated
+// string of items, eg. "h2,http/1.1".
+#[derive(Deserialize)]
 #[serde(untagged)]
+enum ListInput<T> {
+    None,
+    Separated(String),
+    One(T),
+    Some(Vec<T>),
+}
+
+impl<T> ListInput<T> {
+    fn into_none_or_some<'de, E>(self) -> Result<NoneOrSome<T>, E>
+    where
+        T: Deserialize<'de>,
+        E: serde::de::Error,
+    {
+        match self {
+            ListInput::None => Ok(NoneOrSome::None),
+            ListInput::Separated(value) if value.contains(',') => {
+                let items = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| T::deserialize(item.to_string().into_deserializer()))
+                    .collect::<Result<Vec<T>, E>>()?;
+                Ok(NoneOrSome::Some(items))
+            }
+            ListInput::Separated(value) => {
+                Ok(NoneOrSome::One(T::deserialize(value.into_deserializer())?))
+            }
+            ListInput::One(item) => Ok(NoneOrSome::One(item)),
+            ListInput::Some(v) => Ok(NoneOrSome::Some(v)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum NoneOrSome<T> {
-    #[serde(skip_deserializing)]
     Unspecified,
     None,
     One(T),
     Some(Vec<T>),
 }
 
+impl<'de, T> Deserialize<'de> for NoneOrSome<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        ListInput::deserialize(deserializer)?.into_none_or_some()
+    }
+}
+
 impl<T> NoneOrSome<T> {
     pub fn is_unspecified(&self) -> bool {
         match self {
@@ -105,17 +153,6 @@ impl<T> NoneOrSome<T> {
         }
     }
 
-    pub fn into_iter(self) -> Box<dyn Iterator<Item = T>>
-    where
-        T: 'static,
-    {
-        match self {
-            NoneOrSome::Unspecified | NoneOrSome::None => Box::new(std::iter::empty()),
-            NoneOrSome::One(item) => Box::new(SingleItemIter(Some(item))),
-            NoneOrSome::Some(v) => Box::new(v.into_iter()),
-        }
-    }
-
     pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a T> + 'a> {
         match self {
             NoneOrSome::Unspecified | NoneOrSome::None => Box::new(std::iter::empty()),
@@ -132,6 +169,14 @@ impl<T> NoneOrSome<T> {
         }
     }
 
+    pub fn len(&self) -> usize {
+        match self {
+            NoneOrSome::Unspecified | NoneOrSome::None => 0,
+            NoneOrSome::One(_) => 1,
+            NoneOrSome::Some(v) => v.len(),
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         match self {
             NoneOrSome::Unspecified => true,
@@ -185,27 +230,81 @@ impl<T> Default for NoneOrSome<T> {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
+impl<T> IntoIterator for NoneOrSome<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a NoneOrSome<T> {
+    type Item = &'a T;
+    type IntoIter = Box<dyn Iterator<Item = &'a T> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut NoneOrSome<T> {
+    type Item = &'a mut T;
+    type IntoIter = Box<dyn Iterator<Item = &'a mut T> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+// Collecting no items gives None, rather than Unspecified.
+impl<T> FromIterator<T> for NoneOrSome<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut items: Vec<T> = iter.into_iter().collect();
+        match items.len() {
+            0 => NoneOrSome::None,
+            1 => NoneOrSome::One(items.pop().unwrap()),
+            _ => NoneOrSome::Some(items),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum OneOrSome<T> {
     One(T),
-    #[serde(deserialize_with = "validate_non_empty")]
     Some(Vec<T>),
 }
 
-fn validate_non_empty<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
+impl<'de, T> Deserialize<'de> for OneOrSome<T>
 where
-    D: serde::de::Deserializer<'de>,
     T: Deserialize<'de>,
 {
-    let value = Vec::deserialize(d)?;
-    if value.is_empty() {
-        return Err(serde::de::Error::invalid_value(
-            serde::de::Unexpected::Other("empty"),
-            &"need at least one element",
-        ));
-    }
-    Ok(value)
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        match ListInput::deserialize(deserializer)?.into_none_or_some()? {
+            NoneOrSome::One(item) => Ok(OneOrSome::One(item)),
+            NoneOrSome::Some(v) if !v.is_empty() => Ok(OneOrSome::Some(v)),
+            _ => Err(serde::de::Error::invalid_value(
+                serde::de::Unexpected::Other("empty"),
+                &"need at least one element",
+            )),
+        }
+    }
+}
+
+// The items, separated by commas.
+impl<T: std::fmt::Display> std::fmt::Display for OneOrSome<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, item) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
 }
 
 impl<T> OneOrSome<T> {
@@ -249,6 +348,57 @@ impl<T> OneOrSome<T> {
             OneOrSome::Some(v) => v.contains(x),
         }
     }
+
+    pub fn len(&self) -> usize {
+        match self {
+            OneOrSome::One(_) => 1,
+            OneOrSome::Some(v) => v.len(),
+        }
+    }
+
+    // Only a OneOrSome that was collected from no items is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> IntoIterator for OneOrSome<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a OneOrSome<T> {
+    type Item = &'a T;
+    type IntoIter = Box<dyn Iterator<Item = &'a T> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut OneOrSome<T> {
+    type Item = &'a mut T;
+    type IntoIter = Box<dyn Iterator<Item = &'a mut T> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+// Collecting no items gives an empty Some, which deserializing never produces.
+impl<T> FromIterator<T> for OneOrSome<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut items: Vec<T> = iter.into_iter().collect();
+        if items.len() == 1 {
+            OneOrSome::One(items.pop().unwrap())
+        } else {
+            OneOrSome::Some(items)
+        }
+    }
 }
 
 struct SingleItemIter<T>(Option<T>);
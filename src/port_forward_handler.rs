@@ -42,6 +42,7 @@ impl TcpServerHandler for PortForwardServerHandler {
             connection_success_response: None,
             initial_remote_data: None,
             override_proxy_provider: NoneOrOne::Unspecified,
+            authenticated_user: None,
         })
     }
 }
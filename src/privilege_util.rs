@@ -0,0 +1,158 @@
+use std::sync::OnceLock;
+
+use tokio::sync::watch;
+
+#[cfg(target_family = "unix")]
+use crate::config::{IdOrName, RunAsConfig};
+
+// Listeners wait for this before accepting, so that no connection is handled with the
+// privileges that were needed to bind them.
+fn accept_gate() -> &'static watch::Sender<bool> {
+    static ACCEPT_GATE: OnceLock<watch::Sender<bool>> = OnceLock::new();
+    ACCEPT_GATE.get_or_init(|| watch::channel(true).0)
+}
+
+pub fn hold_accepting() {
+    accept_gate().send_replace(false);
+}
+
+pub fn release_accepting() {
+    accept_gate().send_replace(true);
+}
+
+pub async fn wait_until_accepting() {
+    let mut receiver = accept_gate().subscribe();
+    // The sender is static, so it's never dropped.
+    let _ = receiver.wait_for(|accepting| *accepting).await;
+}
+
+// Calls a reentrant passwd or group lookup, growing the buffer while it's too small.
+#[cfg(target_family = "unix")]
+fn lookup_entry<T>(
+    lookup: impl Fn(*mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+) -> std::io::Result<Option<T>> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry = std::mem::MaybeUninit::<T>::uninit();
+        let mut result = std::ptr::null_mut();
+        let ret = lookup(entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result);
+        if ret == libc::ERANGE && buf.len() < 1024 * 1024 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(std::io::Error::from_raw_os_error(ret));
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+        // The strings in the entry point into buf, so only its ids can be used after this.
+        return Ok(Some(unsafe { entry.assume_init() }));
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn to_cstring(name: &str) -> std::io::Result<std::ffi::CString> {
+    std::ffi::CString::new(name).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid user or group name: {}", name),
+        )
+    })
+}
+
+// Returns the uid and the user's primary gid.
+#[cfg(target_family = "unix")]
+fn resolve_user(user: &IdOrName) -> std::io::Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let passwd = match user {
+        IdOrName::Id(uid) => lookup_entry(|pwd, buf, buf_len, result| unsafe {
+            libc::getpwuid_r(*uid, pwd, buf, buf_len, result)
+        })?,
+        IdOrName::Name(name) => {
+            let name = to_cstring(name)?;
+            lookup_entry(|pwd, buf, buf_len, result| unsafe {
+                libc::getpwnam_r(name.as_ptr(), pwd, buf, buf_len, result)
+            })?
+        }
+    };
+    match (user, passwd) {
+        (_, Some(passwd)) => Ok((passwd.pw_uid, Some(passwd.pw_gid))),
+        // Users don't need a passwd entry when they're given by uid.
+        (IdOrName::Id(uid), None) => Ok((*uid, None)),
+        (IdOrName::Name(name), None) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("unknown user: {}", name),
+        )),
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn resolve_group(group: &IdOrName) -> std::io::Result<libc::gid_t> {
+    match group {
+        IdOrName::Id(gid) => Ok(*gid),
+        IdOrName::Name(name) => {
+            let c_name = to_cstring(name)?;
+            let group = lookup_entry(|grp, buf, buf_len, result| unsafe {
+                libc::getgrnam_r(c_name.as_ptr(), grp, buf, buf_len, result)
+            })?;
+            match group {
+                Some(group) => Ok(group.gr_gid),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("unknown group: {}", name),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+pub fn resolve_run_as(run_as: &RunAsConfig) -> std::io::Result<(libc::uid_t, libc::gid_t)> {
+    let (uid, primary_gid) = resolve_user(&run_as.user)?;
+    let gid = match run_as.group {
+        Some(ref group) => resolve_group(group)?,
+        None => primary_gid.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("uid {} has no passwd entry, so run_as needs a group", uid),
+            )
+        })?,
+    };
+    Ok((uid, gid))
+}
+
+#[cfg(target_family = "unix")]
+fn os_error(action: &str) -> std::io::Error {
+    let e = std::io::Error::last_os_error();
+    std::io::Error::new(e.kind(), format!("failed to {}: {}", action, e))
+}
+
+// Switches the process to the run_as user and group, and fails rather than continuing with the
+// old privileges.
+#[cfg(target_family = "unix")]
+pub fn drop_privileges(run_as: &RunAsConfig) -> std::io::Result<()> {
+    let (uid, gid) = resolve_run_as(run_as)?;
+    if unsafe { libc::geteuid() } == uid && unsafe { libc::getegid() } == gid {
+        return Ok(());
+    }
+
+    // The supplementary groups are dropped first, since that needs the old privileges.
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(os_error("drop supplementary groups"));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(os_error(&format!("set gid {}", gid)));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(os_error(&format!("set uid {}", uid)));
+    }
+
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "root privileges could be regained after switching user",
+        ));
+    }
+    println!("Switched to uid {} and gid {}", uid, gid);
+    Ok(())
+}
@@ -0,0 +1,517 @@
+// Datagrams of UDP sessions relayed over a QUIC stream, sent as QUIC DATAGRAM frames instead
+// of on the stream, so that they aren't retransmitted or held up behind lost packets.
+//
+// Each QUIC datagram is
+//
+//   stream id: QUIC variable-length integer, address: SOCKS address, payload
+//
+// where the stream id is the stream that the session was set up on, and the address is the
+// destination when sent by the client and the source when sent by the server. A datagram with
+// only a stream id announces that the sender accepts datagrams for that session.
+//
+// The server announces datagram support when a UDP session is set up on a stream, and resends
+// the announcement until the client sends a datagram. The client sends on the stream until it
+// receives the announcement, and the server sends on the stream until it receives a datagram, so
+// either end falls back to the stream when the other doesn't support datagrams.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use log::debug;
+use parking_lot::Mutex;
+use tokio::io::ReadBuf;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::address::NetLocation;
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncReadSourcedMessage,
+    AsyncReadTargetedMessage, AsyncShutdownMessage, AsyncSourcedMessageStream,
+    AsyncTargetedMessageStream, AsyncWriteMessage, AsyncWriteSourcedMessage,
+    AsyncWriteTargetedMessage,
+};
+use crate::config::OversizedDatagramPolicy;
+use crate::socks_handler::{read_location_from_slice, write_location_to_vec};
+
+// Datagrams that arrived for a session but haven't been read yet.
+const SESSION_QUEUE_LEN: usize = 256;
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn write_varint(value: u64, vec: &mut Vec<u8>) {
+    if value < 1 << 6 {
+        vec.push(value as u8);
+    } else if value < 1 << 14 {
+        vec.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes());
+    } else if value < 1 << 30 {
+        vec.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes());
+    } else {
+        vec.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+// Returns the value at the start of the data along with its length.
+fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = data.get(0..len)?;
+    let value = bytes[1..].iter().fold(u64::from(first & 0x3f), |value, b| {
+        (value << 8) | u64::from(*b)
+    });
+    Some((value, len))
+}
+
+fn stream_id_value(stream_id: quinn::StreamId) -> u64 {
+    quinn::VarInt::from(stream_id).into_inner()
+}
+
+// Dispatches the datagrams received on a QUIC connection to the sessions that they belong to.
+pub struct QuicDatagramRouter {
+    connection: quinn::Connection,
+    oversized_policy: OversizedDatagramPolicy,
+    sessions: Arc<Mutex<HashMap<u64, mpsc::Sender<Bytes>>>>,
+    // Stops the receiving task when the router is dropped, so that it doesn't keep the
+    // connection open.
+    _stop_sender: oneshot::Sender<()>,
+}
+
+impl QuicDatagramRouter {
+    pub fn start(
+        connection: quinn::Connection,
+        oversized_policy: OversizedDatagramPolicy,
+    ) -> Arc<Self> {
+        let sessions: Arc<Mutex<HashMap<u64, mpsc::Sender<Bytes>>>> =
+            Arc::new(Mutex::new(HashMap::new()));
+        let (stop_sender, mut stop_receiver) = oneshot::channel::<()>();
+
+        let task_connection = connection.clone();
+        let task_sessions = sessions.clone();
+        tokio::spawn(async move {
+            loop {
+                let datagram = tokio::select! {
+                    result = task_connection.read_datagram() => match result {
+                        Ok(d) => d,
+                        Err(e) => {
+                            debug!("Stopped reading QUIC datagrams: {}", e);
+                            break;
+                        }
+                    },
+                    _ = &mut stop_receiver => break,
+                };
+                let (stream_id, id_len) = match read_varint(&datagram) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let sessions = task_sessions.lock();
+                match sessions.get(&stream_id) {
+                    // When the session can't keep up, the datagram is dropped like it would be
+                    // on a full socket buffer.
+                    Some(sender) => {
+                        let _ = sender.try_send(datagram.slice(id_len..));
+                    }
+                    None => {
+                        debug!("Dropping QUIC datagram for unknown stream {}", stream_id);
+                    }
+                }
+            }
+        });
+
+        Arc::new(Self {
+            connection,
+            oversized_policy,
+            sessions,
+            _stop_sender: stop_sender,
+        })
+    }
+
+    // Starts receiving the datagrams of the session on the given stream. Servers announce
+    // that they accept datagrams, clients wait for the announcement.
+    pub fn register(
+        self: &Arc<Self>,
+        stream_id: quinn::StreamId,
+        is_server: bool,
+    ) -> QuicDatagramSession {
+        let stream_id = stream_id_value(stream_id);
+        let (sender, receiver) = mpsc::channel(SESSION_QUEUE_LEN);
+        self.sessions.lock().insert(stream_id, sender);
+
+        let mut header = Vec::with_capacity(8);
+        write_varint(stream_id, &mut header);
+
+        let mut session = QuicDatagramSession {
+            router: self.clone(),
+            stream_id,
+            header,
+            receiver,
+            peer_accepts_datagrams: false,
+            last_announce: None,
+            is_server,
+        };
+        session.maybe_announce();
+        session
+    }
+}
+
+// The datagrams of one UDP session.
+pub struct QuicDatagramSession {
+    router: Arc<QuicDatagramRouter>,
+    stream_id: u64,
+    header: Vec<u8>,
+    receiver: mpsc::Receiver<Bytes>,
+    // Whether a datagram was received for this session, which means that the peer supports
+    // them.
+    peer_accepts_datagrams: bool,
+    last_announce: Option<Instant>,
+    is_server: bool,
+}
+
+impl QuicDatagramSession {
+    fn maybe_announce(&mut self) {
+        if !self.is_server || self.peer_accepts_datagrams {
+            return;
+        }
+        if let Some(last_announce) = self.last_announce {
+            if last_announce.elapsed() < ANNOUNCE_INTERVAL {
+                return;
+            }
+        }
+        self.last_announce = Some(Instant::now());
+        if let Err(e) = self
+            .router
+            .connection
+            .send_datagram(Bytes::copy_from_slice(&self.header))
+        {
+            debug!(
+                "Failed to announce QUIC datagrams for stream {}: {}",
+                self.stream_id, e
+            );
+        }
+    }
+
+    // Reads a received datagram into the buffer and returns its address, or returns Pending
+    // when there are none.
+    fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<NetLocation> {
+        loop {
+            let datagram = match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(d)) => d,
+                // The connection was closed, which the stream also finds out about.
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            };
+            self.peer_accepts_datagrams = true;
+            if datagram.is_empty() {
+                continue;
+            }
+            let (location, location_len) = match read_location_from_slice(&datagram) {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!(
+                        "Dropping invalid QUIC datagram for stream {}: {}",
+                        self.stream_id, e
+                    );
+                    continue;
+                }
+            };
+            let payload = &datagram[location_len..];
+            if payload.len() > buf.remaining() {
+                debug!(
+                    "Dropping QUIC datagram for stream {} that is larger than the read buffer",
+                    self.stream_id
+                );
+                continue;
+            }
+            buf.put_slice(payload);
+            return Poll::Ready(location);
+        }
+    }
+
+    // Sends the message as a datagram, and returns whether it was handled, either by sending
+    // it or by dropping it, or whether it should be written to the stream instead.
+    fn try_send(&mut self, buf: &[u8], location: &NetLocation) -> bool {
+        if !self.peer_accepts_datagrams {
+            self.maybe_announce();
+            return false;
+        }
+        let location_bytes = write_location_to_vec(location);
+        let datagram_len = self.header.len() + location_bytes.len() + buf.len();
+        let fits = self
+            .router
+            .connection
+            .max_datagram_size()
+            .map(|max_size| datagram_len <= max_size)
+            .unwrap_or(false);
+        if fits {
+            let mut datagram = Vec::with_capacity(datagram_len);
+            datagram.extend_from_slice(&self.header);
+            datagram.extend_from_slice(&location_bytes);
+            datagram.extend_from_slice(buf);
+            match self.router.connection.send_datagram(Bytes::from(datagram)) {
+                Ok(()) => return true,
+                Err(quinn::SendDatagramError::TooLarge) => (),
+                Err(e) => {
+                    debug!(
+                        "Failed to send QUIC datagram for stream {}: {}",
+                        self.stream_id, e
+                    );
+                    return false;
+                }
+            }
+        }
+        match self.router.oversized_policy {
+            OversizedDatagramPolicy::Stream => false,
+            OversizedDatagramPolicy::Drop => {
+                debug!(
+                    "Dropping {} byte message for stream {} that doesn't fit in a QUIC datagram",
+                    buf.len(),
+                    self.stream_id
+                );
+                true
+            }
+        }
+    }
+}
+
+impl Drop for QuicDatagramSession {
+    fn drop(&mut self) {
+        self.router.sessions.lock().remove(&self.stream_id);
+    }
+}
+
+// A stream to a single destination, where messages are sent to and received from the given
+// location.
+pub struct QuicDatagramMessageStream {
+    stream: Box<dyn AsyncMessageStream>,
+    session: QuicDatagramSession,
+    location: NetLocation,
+}
+
+impl QuicDatagramMessageStream {
+    pub fn new(
+        stream: Box<dyn AsyncMessageStream>,
+        session: QuicDatagramSession,
+        location: NetLocation,
+    ) -> Self {
+        Self {
+            stream,
+            session,
+            location,
+        }
+    }
+}
+
+impl AsyncReadMessage for QuicDatagramMessageStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.session.poll_recv(cx, buf).is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+        this.session.maybe_announce();
+        Pin::new(&mut this.stream).poll_read_message(cx, buf)
+    }
+}
+
+impl AsyncWriteMessage for QuicDatagramMessageStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.session.try_send(buf, &this.location) {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_write_message(cx, buf)
+    }
+}
+
+impl AsyncFlushMessage for QuicDatagramMessageStream {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush_message(cx)
+    }
+}
+
+impl AsyncShutdownMessage for QuicDatagramMessageStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown_message(cx)
+    }
+}
+
+impl AsyncPing for QuicDatagramMessageStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncMessageStream for QuicDatagramMessageStream {}
+
+// The server stream of a session to any destination.
+pub struct QuicDatagramTargetedStream {
+    stream: Box<dyn AsyncTargetedMessageStream>,
+    session: QuicDatagramSession,
+}
+
+impl QuicDatagramTargetedStream {
+    pub fn new(stream: Box<dyn AsyncTargetedMessageStream>, session: QuicDatagramSession) -> Self {
+        Self { stream, session }
+    }
+}
+
+impl AsyncReadTargetedMessage for QuicDatagramTargetedStream {
+    fn poll_read_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<NetLocation>> {
+        let this = self.get_mut();
+        if let Poll::Ready(target) = this.session.poll_recv(cx, buf) {
+            return Poll::Ready(Ok(target));
+        }
+        this.session.maybe_announce();
+        Pin::new(&mut this.stream).poll_read_targeted_message(cx, buf)
+    }
+}
+
+impl AsyncWriteSourcedMessage for QuicDatagramTargetedStream {
+    fn poll_write_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        source: &SocketAddr,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this
+            .session
+            .try_send(buf, &NetLocation::from_socket_addr(*source))
+        {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_write_sourced_message(cx, buf, source)
+    }
+}
+
+impl AsyncFlushMessage for QuicDatagramTargetedStream {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush_message(cx)
+    }
+}
+
+impl AsyncShutdownMessage for QuicDatagramTargetedStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown_message(cx)
+    }
+}
+
+impl AsyncPing for QuicDatagramTargetedStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncTargetedMessageStream for QuicDatagramTargetedStream {}
+
+// The client stream of a session to any destination.
+pub struct QuicDatagramSourcedStream {
+    stream: Box<dyn AsyncSourcedMessageStream>,
+    session: QuicDatagramSession,
+}
+
+impl QuicDatagramSourcedStream {
+    pub fn new(stream: Box<dyn AsyncSourcedMessageStream>, session: QuicDatagramSession) -> Self {
+        Self { stream, session }
+    }
+}
+
+impl AsyncReadSourcedMessage for QuicDatagramSourcedStream {
+    fn poll_read_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<SocketAddr>> {
+        let this = self.get_mut();
+        while let Poll::Ready(source) = this.session.poll_recv(cx, buf) {
+            match source.to_socket_addr_nonblocking() {
+                Some(source) => return Poll::Ready(Ok(source)),
+                None => {
+                    debug!("Dropping QUIC datagram from unresolved source {}", source);
+                    buf.clear();
+                }
+            }
+        }
+        Pin::new(&mut this.stream).poll_read_sourced_message(cx, buf)
+    }
+}
+
+impl AsyncWriteTargetedMessage for QuicDatagramSourcedStream {
+    fn poll_write_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &NetLocation,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.session.try_send(buf, target) {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_write_targeted_message(cx, buf, target)
+    }
+}
+
+impl AsyncFlushMessage for QuicDatagramSourcedStream {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush_message(cx)
+    }
+}
+
+impl AsyncShutdownMessage for QuicDatagramSourcedStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown_message(cx)
+    }
+}
+
+impl AsyncPing for QuicDatagramSourcedStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncSourcedMessageStream for QuicDatagramSourcedStream {}
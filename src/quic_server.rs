@@ -1,55 +1,113 @@
-use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use log::{debug, error, warn};
+use parking_lot::RwLock;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
-use crate::async_stream::AsyncStream;
+use crate::async_stream::{AsyncMessageStream, AsyncStream, AsyncTargetedMessageStream};
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
-use crate::config::{BindLocation, ConfigSelection, ServerConfig, ServerQuicConfig};
+use crate::config::{
+    BindLocation, ConfigSelection, OversizedDatagramPolicy, ServerConfig, ServerQuicConfig,
+    TlsVersion,
+};
+use crate::connection_registry::{connection_registry, ConnectionHandle};
 use crate::copy_bidirectional::copy_bidirectional;
 use crate::copy_bidirectional_message::copy_bidirectional_message;
 use crate::copy_multidirectional_message::copy_multidirectional_message;
+use crate::health_server::accepting_listener;
+use crate::http_forward::run_http_forward;
+use crate::option_util::OneOrSome;
+use crate::privilege_util::wait_until_accepting;
+use crate::quic_datagram::{
+    QuicDatagramMessageStream, QuicDatagramRouter, QuicDatagramTargetedStream,
+};
 use crate::quic_stream::QuicStream;
-use crate::resolver::{resolve_single_address, NativeResolver, Resolver};
-use crate::rustls_util::create_server_config;
+use crate::resolver::{create_resolver, Resolver};
+use crate::rustls_util::{
+    cert_identity, create_client_verifier, create_server_config, create_tls_policy,
+};
+use crate::socket_util::set_dscp;
+use crate::source_filter::SourceFilter;
 use crate::tcp_client_connector::TcpClientConnector;
 use crate::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
-use crate::tcp_handler_util::{create_tcp_client_proxy_selector, create_tcp_server_handler};
-use crate::tcp_server::setup_client_stream;
-use crate::udp_direct_message_stream::UdpDirectMessageStream;
+use crate::tcp_server::{create_tcp_server_state, setup_client_stream, TcpServerState};
+use crate::udp_session_table::UdpSessionTable;
 
 async fn run_quic_server(
-    bind_address: SocketAddr,
+    socket: std::net::UdpSocket,
     server_config: Arc<rustls::ServerConfig>,
-    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
-    server_handler: Arc<Box<dyn TcpServerHandler>>,
+    server_state: Arc<RwLock<TcpServerState>>,
+    udp_sessions: Arc<UdpSessionTable>,
+    resolver: Arc<dyn Resolver>,
+    source_filter: SourceFilter,
+    datagram_policy: Option<OversizedDatagramPolicy>,
 ) -> std::io::Result<()> {
-    let resolver: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
-
     let mut server_config = quinn::ServerConfig::with_crypto(server_config);
-    Arc::get_mut(&mut server_config.transport)
-        .unwrap()
+    let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
+    if datagram_policy.is_none() {
+        transport_config.datagram_receive_buffer_size(None);
+    }
+    transport_config
         .max_concurrent_bidi_streams(1024_u32.into())
         .max_concurrent_uni_streams(0_u8.into())
         .keep_alive_interval(Some(std::time::Duration::from_secs(15).try_into().unwrap()))
         .max_idle_timeout(Some(std::time::Duration::from_secs(30).try_into().unwrap()));
 
-    let endpoint = quinn::Endpoint::server(server_config, bind_address)?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+
+    let server_label = server_state.read().server_label.clone();
+
+    wait_until_accepting().await;
+    let _accepting = accepting_listener();
 
     while let Some(conn) = endpoint.accept().await {
-        let cloned_selector = client_proxy_selector.clone();
+        if !source_filter.is_allowed(conn.remote_address().ip()) {
+            debug!(
+                "[{}] Dropped connection from disallowed source {}",
+                server_label,
+                conn.remote_address()
+            );
+            continue;
+        }
+
+        // Streams of a connection keep using the state it was accepted with.
+        let (cloned_selector, cloned_handler, cloned_protocol_name) = {
+            let state = server_state.read();
+            (
+                state.client_proxy_selector.clone(),
+                state.server_handler.clone(),
+                state.protocol_name.clone(),
+            )
+        };
         let cloned_resolver = resolver.clone();
-        let cloned_handler = server_handler.clone();
+        let cloned_server_label = server_label.clone();
+        let cloned_udp_sessions = udp_sessions.clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                process_connection(cloned_selector, cloned_resolver, cloned_handler, conn).await
+            if let Err(e) = process_connection(
+                cloned_selector,
+                cloned_resolver,
+                cloned_handler,
+                cloned_server_label.clone(),
+                cloned_protocol_name,
+                cloned_udp_sessions,
+                conn,
+                datagram_policy,
+            )
+            .await
             {
-                error!("Connection ended with error: {}", e);
+                error!(
+                    "[{}] Connection ended with error: {}",
+                    cloned_server_label, e
+                );
             }
         });
     }
@@ -61,14 +119,26 @@ async fn process_connection(
     client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
     resolver: Arc<dyn Resolver>,
     server_handler: Arc<Box<dyn TcpServerHandler>>,
+    server_label: String,
+    protocol_name: String,
+    udp_sessions: Arc<UdpSessionTable>,
     conn: quinn::Connecting,
+    // None when UDP sessions don't use QUIC datagrams.
+    datagram_policy: Option<OversizedDatagramPolicy>,
 ) -> std::io::Result<()> {
     let connection = conn.await?;
+    let datagram_router =
+        datagram_policy.map(|policy| QuicDatagramRouter::start(connection.clone(), policy));
+    // The identity in the client's certificate, which was verified during the handshake.
+    let client_identity = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+        .and_then(|certs| certs.first().and_then(|cert| cert_identity(&cert.0)));
 
     loop {
         let stream = match connection.accept_bi().await {
             Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                debug!("Connection closed");
+                debug!("[{}] Connection closed", server_label);
                 break;
             }
             Err(e) => {
@@ -82,11 +152,39 @@ async fn process_connection(
         let cloned_selector = client_proxy_selector.clone();
         let cloned_resolver = resolver.clone();
         let cloned_handler = server_handler.clone();
+        let cloned_label = server_label.clone();
+        let cloned_udp_sessions = udp_sessions.clone();
+        let cloned_datagram_router = datagram_router.clone();
+        let connection = connection_registry().register(
+            server_label.clone(),
+            connection.remote_address().to_string(),
+            Some(connection.remote_address().ip()),
+            protocol_name.clone(),
+        );
+        // Users that authenticate with the inner protocol replace it.
+        if let Some(ref client_identity) = client_identity {
+            connection.info().set_user(client_identity.clone());
+        }
+        let connection_info = connection.info().clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                process_streams(cloned_selector, cloned_resolver, cloned_handler, stream).await
+            if let Err(e) = connection_info
+                .run_until_closed(Box::pin(process_streams(
+                    cloned_selector,
+                    cloned_resolver,
+                    cloned_handler,
+                    cloned_udp_sessions,
+                    cloned_datagram_router,
+                    stream,
+                    connection,
+                )))
+                .await
             {
-                error!("Failed to process streams: {}", e);
+                error!(
+                    "[{}] {} failed to process streams: {}",
+                    cloned_label,
+                    connection_info.log_label(),
+                    e
+                );
             }
         });
     }
@@ -98,8 +196,12 @@ async fn process_streams(
     client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
     resolver: Arc<dyn Resolver>,
     server_handler: Arc<Box<dyn TcpServerHandler>>,
+    udp_sessions: Arc<UdpSessionTable>,
+    datagram_router: Option<Arc<QuicDatagramRouter>>,
     (send, recv): (quinn::SendStream, quinn::RecvStream),
+    connection: ConnectionHandle,
 ) -> std::io::Result<()> {
+    let stream_id = send.id();
     let quic_stream: Box<dyn AsyncStream> = Box::new(QuicStream::from(send, recv));
 
     let setup_server_stream_future = timeout(
@@ -108,7 +210,7 @@ async fn process_streams(
     );
 
     let setup_result = match setup_server_stream_future.await {
-        Ok(Ok(r)) => r,
+        Ok(Ok(r)) => r.resolve_fallback(),
         Ok(Err(e)) => {
             return Err(std::io::Error::new(
                 e.kind(),
@@ -131,13 +233,20 @@ async fn process_streams(
             override_proxy_provider,
             connection_success_response,
             initial_remote_data,
+            authenticated_user,
         } => {
+            if let Some(user) = authenticated_user {
+                connection.info().set_user(user);
+            }
+
             let selected_proxy_provider = if override_proxy_provider.is_one() {
                 override_proxy_provider.unwrap()
             } else {
                 client_proxy_selector
             };
 
+            connection.info().set_destination(remote_location.clone());
+
             let setup_client_stream_future = timeout(
                 Duration::from_secs(60),
                 setup_client_stream(
@@ -145,6 +254,8 @@ async fn process_streams(
                     selected_proxy_provider,
                     resolver,
                     remote_location.clone(),
+                    connection.info(),
+                    None,
                 ),
             );
 
@@ -193,6 +304,7 @@ async fn process_streams(
                 &mut client_stream,
                 server_need_initial_flush,
                 client_need_initial_flush,
+                None,
             )
             .await;
 
@@ -201,26 +313,84 @@ async fn process_streams(
             copy_result?;
             Ok(())
         }
+        TcpServerSetupResult::Fallback { .. } => unreachable!(),
+        // Each QUIC stream is already a separate stream.
+        TcpServerSetupResult::MultiplexedForward { .. } => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "multiplexed protocols like gRPC are not supported over QUIC",
+        )),
+        TcpServerSetupResult::HttpForward {
+            stream,
+            line_reader,
+            request,
+            override_proxy_provider,
+            authenticated_user,
+        } => {
+            if let Some(user) = authenticated_user {
+                connection.info().set_user(user);
+            }
+
+            let selected_proxy_provider = if override_proxy_provider.is_one() {
+                override_proxy_provider.unwrap()
+            } else {
+                client_proxy_selector
+            };
+
+            run_http_forward(
+                stream,
+                line_reader,
+                request,
+                selected_proxy_provider,
+                resolver,
+                connection.info(),
+                None,
+            )
+            .await
+        }
         TcpServerSetupResult::BidirectionalUdpForward {
             remote_location,
-            stream: mut server_stream,
+            stream: server_stream,
         } => {
-            let action = client_proxy_selector
-                .judge(remote_location, &resolver)
+            let mut server_stream: Box<dyn AsyncMessageStream> = match datagram_router {
+                Some(ref router) => Box::new(QuicDatagramMessageStream::new(
+                    server_stream,
+                    router.register(stream_id, true),
+                    remote_location.clone(),
+                )),
+                None => server_stream,
+            };
+            connection.info().set_destination(remote_location.clone());
+            let (action, rule) = client_proxy_selector
+                .judge_with_rule(
+                    remote_location.clone(),
+                    connection.info().source_ip,
+                    connection.info().user().as_deref(),
+                    &resolver,
+                )
                 .await?;
+            connection
+                .info()
+                .record_judgement(&remote_location, rule, &action);
             match action {
                 ConnectDecision::Allow {
                     client_proxy,
                     remote_location,
+                    active_connection: _active_connection,
                 } => {
-                    let remote_addr = resolve_single_address(&resolver, &remote_location).await?;
-                    let client_socket = client_proxy.configure_udp_socket()?;
-                    client_socket.connect(remote_addr).await?;
-
-                    let mut client_socket = Box::new(client_socket);
-
-                    let copy_result =
-                        copy_bidirectional_message(&mut server_stream, &mut client_socket).await;
+                    let mut client_socket = client_proxy
+                        .connect_udp(&remote_location, &resolver)
+                        .await?;
+                    connection.info().mark_setup_complete();
+
+                    let idle_timeout = udp_sessions.config().idle_timeout();
+                    let copy_result = udp_sessions
+                        .run_session(copy_bidirectional_message(
+                            &mut server_stream,
+                            &mut client_socket,
+                            idle_timeout,
+                            connection.info(),
+                        ))
+                        .await;
 
                     // TODO: add async trait ext and make this work
                     //let (_, _) = futures::join!(server_stream.shutdown_message(), client_stream.shutdown_message());
@@ -237,26 +407,42 @@ async fn process_streams(
             }
         }
         TcpServerSetupResult::MultidirectionalUdpForward {
-            stream: mut server_stream,
+            stream: server_stream,
             need_initial_flush: server_need_initial_flush,
         } => {
+            let mut server_stream: Box<dyn AsyncTargetedMessageStream> = match datagram_router {
+                Some(ref router) => Box::new(QuicDatagramTargetedStream::new(
+                    server_stream,
+                    router.register(stream_id, true),
+                )),
+                None => server_stream,
+            };
             let action = client_proxy_selector.default_decision();
             match action {
                 ConnectDecision::Allow {
                     client_proxy,
                     remote_location: _,
+                    active_connection: _active_connection,
                 } => {
-                    let client_socket = client_proxy.configure_udp_socket()?;
-                    let mut client_stream =
-                        Box::new(UdpDirectMessageStream::new(client_socket, resolver));
-
-                    let copy_result = copy_multidirectional_message(
-                        &mut server_stream,
-                        &mut client_stream,
-                        server_need_initial_flush,
-                        false,
-                    )
-                    .await;
+                    connection
+                        .info()
+                        .set_client_proxy(client_proxy.label().to_string());
+                    let mut client_stream = client_proxy
+                        .create_udp_stream(udp_sessions.config(), resolver)
+                        .await?;
+                    connection.info().mark_setup_complete();
+
+                    let idle_timeout = udp_sessions.config().idle_timeout();
+                    let copy_result = udp_sessions
+                        .run_session(copy_multidirectional_message(
+                            &mut server_stream,
+                            &mut client_stream,
+                            server_need_initial_flush,
+                            false,
+                            idle_timeout,
+                            connection.info(),
+                        ))
+                        .await;
 
                     // TODO: add async trait ext and make this work
                     //let (_, _) = futures::join!(server_stream.shutdown_message(), client_stream.shutdown_message());
@@ -265,7 +451,11 @@ async fn process_streams(
                     Ok(())
                 }
                 ConnectDecision::Block => {
-                    warn!("Blocked multidirectional udp forward, because the default action is to block.");
+                    warn!(
+                        "[{}] {} Blocked multidirectional udp forward, because the default action is to block.",
+                        connection.info().server,
+                        connection.info().log_label()
+                    );
                     // TODO: add async trait ext and make this work
                     // let _ = server_stream.shutdown_message().await;
                     Ok(())
@@ -275,34 +465,95 @@ async fn process_streams(
     }
 }
 
-pub async fn start_quic_server(config: ServerConfig) -> std::io::Result<JoinHandle<()>> {
+pub async fn start_quic_server(config: ServerConfig) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let server_label = config.label();
     let ServerConfig {
-        bind_location,
+        name,
+        bind_locations,
         quic_settings,
+        udp_settings,
+        resolver_settings,
+        allow_sources,
+        deny_sources,
+        dscp,
         protocol,
         rules,
         ..
     } = config;
 
-    println!("Starting {} QUIC server at {}", &protocol, &bind_location);
+    match name {
+        Some(name) => println!(
+            "Starting {} QUIC server {} at {}",
+            &protocol, name, &bind_locations
+        ),
+        None => println!("Starting {} QUIC server at {}", &protocol, &bind_locations),
+    }
 
     let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
     // We should always have a direct entry.
     assert!(!rules.is_empty());
 
-    let bind_address = match bind_location {
-        // TODO: switch to non-blocking resolve?
-        BindLocation::Address(a) => a.to_socket_addr()?,
-        BindLocation::Path(_) => {
-            panic!("Cannot listen on path, QUIC does not have unix domain socket support");
-        }
-    };
+    let server_state = create_tcp_server_state(server_label.clone(), protocol, rules);
+    connection_registry().set_selector(
+        server_label.clone(),
+        server_state.client_proxy_selector.clone(),
+    );
+
+    let udp_sessions = Arc::new(UdpSessionTable::new(
+        server_label.clone(),
+        udp_settings.unwrap_or_default(),
+    ));
+    connection_registry().set_udp_session_table(server_label, udp_sessions.clone());
+    let resolver = create_resolver(resolver_settings.as_ref());
+    let source_filter = SourceFilter::new(allow_sources.into_vec(), deny_sources.into_vec());
+
+    start_quic_listeners(
+        bind_locations,
+        quic_settings.unwrap(),
+        dscp,
+        Arc::new(RwLock::new(server_state)),
+        udp_sessions,
+        resolver,
+        source_filter,
+    )
+    .await
+}
+
+// Binds a QUIC endpoint at each bind location, which a TCP server also uses for the QUIC
+// transport it's combined with.
+pub(crate) async fn start_quic_listeners(
+    bind_locations: OneOrSome<BindLocation>,
+    quic_config: ServerQuicConfig,
+    dscp: Option<u8>,
+    server_state: Arc<RwLock<TcpServerState>>,
+    udp_sessions: Arc<UdpSessionTable>,
+    resolver: Arc<dyn Resolver>,
+    source_filter: SourceFilter,
+) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let bind_addresses = bind_locations
+        .into_vec()
+        .into_iter()
+        .map(|bind_location| match bind_location {
+            // TODO: switch to non-blocking resolve?
+            BindLocation::Address(a) => a.to_socket_addr(),
+            BindLocation::Path(_) => {
+                panic!("Cannot listen on path, QUIC does not have unix domain socket support");
+            }
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
 
     let ServerQuicConfig {
         cert,
         key,
         alpn_protocols,
-    } = quic_settings.unwrap();
+        cipher_suites,
+        client_ca,
+        require_client_cert,
+        udp_datagrams,
+        oversized_datagrams,
+        ..
+    } = quic_config;
+    let datagram_policy = udp_datagrams.then_some(oversized_datagrams);
 
     let mut cert_file = File::open(&cert).await?;
     let mut cert_bytes = vec![];
@@ -312,27 +563,46 @@ pub async fn start_quic_server(config: ServerConfig) -> std::io::Result<JoinHand
     let mut key_bytes = vec![];
     key_file.read_to_end(&mut key_bytes).await?;
 
+    // QUIC only supports TLS 1.3, whatever the minimum version is.
+    let tls_policy = create_tls_policy(TlsVersion::Tls13, &cipher_suites.into_vec())?;
+    let client_verifier = create_client_verifier(client_ca.as_deref(), require_client_cert)?;
     let server_config = Arc::new(create_server_config(
         &cert_bytes,
         &key_bytes,
         &alpn_protocols.into_vec(),
+        &tls_policy,
+        client_verifier,
     ));
 
-    let client_proxy_selector = Arc::new(create_tcp_client_proxy_selector(rules.clone()));
-
-    let mut rules_stack = vec![rules];
-    let tcp_handler: Arc<Box<dyn TcpServerHandler>> =
-        Arc::new(create_tcp_server_handler(protocol, &mut rules_stack));
-    debug!("TCP handler: {:?}", tcp_handler);
-
-    Ok(tokio::spawn(async move {
-        run_quic_server(
-            bind_address,
-            server_config,
-            client_proxy_selector,
-            tcp_handler,
-        )
-        .await
-        .unwrap();
-    }))
+    let mut join_handles = vec![];
+    for bind_address in bind_addresses {
+        let socket = std::net::UdpSocket::bind(bind_address)?;
+        if let Some(dscp) = dscp {
+            set_dscp(
+                socket2::SockRef::from(&socket),
+                bind_address.is_ipv6(),
+                dscp,
+            )?;
+        }
+
+        let server_config = server_config.clone();
+        let server_state = server_state.clone();
+        let udp_sessions = udp_sessions.clone();
+        let resolver = resolver.clone();
+        let source_filter = source_filter.clone();
+        join_handles.push(tokio::spawn(async move {
+            run_quic_server(
+                socket,
+                server_config,
+                server_state,
+                udp_sessions,
+                resolver,
+                source_filter,
+                datagram_policy,
+            )
+            .await
+            .unwrap();
+        }));
+    }
+    Ok(join_handles)
 }
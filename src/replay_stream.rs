@@ -0,0 +1,94 @@
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+// Returns bytes that were already read from the stream before reading from the stream again, so
+// that a handler can look at the start of a connection and pass it on unchanged.
+pub struct ReplayStream {
+    stream: Box<dyn AsyncStream>,
+    replay_data: Box<[u8]>,
+    replay_offset: usize,
+}
+
+impl ReplayStream {
+    pub fn new(stream: Box<dyn AsyncStream>, replay_data: Box<[u8]>) -> Self {
+        Self {
+            stream,
+            replay_data,
+            replay_offset: 0,
+        }
+    }
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.replay_offset < this.replay_data.len() {
+            let remaining = &this.replay_data[this.replay_offset..];
+            let len = std::cmp::min(remaining.len(), buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            this.replay_offset += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for ReplayStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncStream for ReplayStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+}
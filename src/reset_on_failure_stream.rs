@@ -0,0 +1,105 @@
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::connection_registry::ConnectionInfo;
+
+// An accepted stream that is reset when it's closed before the connection was set up, because
+// it was blocked, failed its handshake or timed out. Clients find out right away, and the socket
+// doesn't linger in the kernel. Connections that were set up are closed gracefully.
+pub struct ResetOnFailureStream {
+    stream: TcpStream,
+    info: Arc<ConnectionInfo>,
+}
+
+impl ResetOnFailureStream {
+    pub fn new(stream: TcpStream, info: Arc<ConnectionInfo>) -> Self {
+        Self { stream, info }
+    }
+
+    fn is_failed(&self) -> bool {
+        self.info.setup_duration().is_none()
+    }
+}
+
+impl Drop for ResetOnFailureStream {
+    fn drop(&mut self) {
+        if !self.is_failed() {
+            return;
+        }
+        // With a zero linger time, closing the socket sends a RST and discards unsent data.
+        if let Err(e) = socket2::SockRef::from(&self.stream).set_linger(Some(Duration::ZERO)) {
+            debug!(
+                "[{}] Failed to reset {}: {}",
+                self.info.server, self.info.source, e
+            );
+        }
+    }
+}
+
+impl AsyncRead for ResetOnFailureStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ResetOnFailureStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // A failed stream isn't shut down, so that the reset isn't preceded by a FIN.
+        if self.is_failed() {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for ResetOnFailureStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for ResetOnFailureStream {
+    fn supports_half_close(&self) -> bool {
+        true
+    }
+}
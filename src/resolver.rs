@@ -1,11 +1,17 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
 
 use log::debug;
+use parking_lot::Mutex;
 
 use crate::address::NetLocation;
+use crate::config::{IpPreference, ResolverConfig};
+use crate::dot_resolver::DotResolver;
 
 pub trait Resolver: Send + Sync {
     fn resolve_location(
@@ -14,6 +20,12 @@ pub trait Resolver: Send + Sync {
     ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>>;
 }
 
+impl std::fmt::Debug for dyn Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
 pub struct NativeResolver;
 
 impl NativeResolver {
@@ -43,6 +55,219 @@ impl Resolver for NativeResolver {
     }
 }
 
+// Gives each lookup a limited time, and tries again when it times out.
+pub struct RetryResolver {
+    resolver: Arc<dyn Resolver>,
+    timeout: Duration,
+    attempts: u32,
+}
+
+impl RetryResolver {
+    pub fn new(resolver: Arc<dyn Resolver>, config: &ResolverConfig) -> Self {
+        Self {
+            resolver,
+            timeout: Duration::from_secs(config.timeout_secs),
+            attempts: config.attempts,
+        }
+    }
+}
+
+impl Resolver for RetryResolver {
+    fn resolve_location(
+        &self,
+        location: &NetLocation,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+        let resolver = self.resolver.clone();
+        let timeout = self.timeout;
+        let attempts = self.attempts;
+        let location = location.clone();
+        Box::pin(async move {
+            for attempt in 1..=attempts {
+                // The timeout applies to each attempt rather than the whole lookup.
+                match tokio::time::timeout(timeout, resolver.resolve_location(&location)).await {
+                    Ok(result) => return result,
+                    Err(_) => {
+                        debug!(
+                            "Resolving {} timed out (attempt {} of {})",
+                            location, attempt, attempts
+                        );
+                    }
+                }
+            }
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "resolving {} timed out after {} attempts of {} seconds",
+                    location,
+                    attempts,
+                    timeout.as_secs()
+                ),
+            ))
+        })
+    }
+}
+
+// A resolver that every server and client with the same resolver settings uses, so that state
+// like DNS over TLS connections is shared. Lookups are counted for the admin server.
+pub struct SharedResolver {
+    description: String,
+    resolver: Arc<dyn Resolver>,
+    lookups: AtomicU64,
+    // Shared with lookups that are still running.
+    failures: Arc<AtomicU64>,
+}
+
+impl SharedResolver {
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+impl Resolver for SharedResolver {
+    fn resolve_location(
+        &self,
+        location: &NetLocation,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let resolve_future = self.resolver.resolve_location(location);
+        let failures = self.failures.clone();
+        Box::pin(async move {
+            let result = resolve_future.await;
+            if result.is_err() {
+                failures.fetch_add(1, Ordering::Relaxed);
+            }
+            result
+        })
+    }
+}
+
+fn describe_resolver(resolver_config: Option<&ResolverConfig>) -> String {
+    match resolver_config {
+        Some(config) if !config.dot_servers.is_empty() => format!(
+            "dns over tls via {} ({}s timeout)",
+            config
+                .dot_servers
+                .iter()
+                .map(|server| server.address.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            config.timeout_secs
+        ),
+        Some(config) => format!(
+            "native ({}s timeout, {} attempts)",
+            config.timeout_secs, config.attempts
+        ),
+        None => "native".to_string(),
+    }
+}
+
+fn shared_resolvers() -> &'static Mutex<HashMap<String, Weak<SharedResolver>>> {
+    static RESOLVERS: OnceLock<Mutex<HashMap<String, Weak<SharedResolver>>>> = OnceLock::new();
+    RESOLVERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Returns the resolver for the settings, which is only created the first time. Without resolver
+// settings, lookups are left to the OS.
+pub fn create_resolver(resolver_config: Option<&ResolverConfig>) -> Arc<dyn Resolver> {
+    let mut resolvers = shared_resolvers().lock();
+    resolvers.retain(|_, resolver| resolver.strong_count() > 0);
+
+    let key = format!("{:?}", resolver_config);
+    if let Some(resolver) = resolvers.get(&key).and_then(Weak::upgrade) {
+        return resolver;
+    }
+
+    let resolver: Arc<dyn Resolver> = match resolver_config {
+        // The DNS over TLS resolver has its own timeouts, so that it can fail over to the next
+        // server.
+        Some(config) if !config.dot_servers.is_empty() => Arc::new(DotResolver::new(config)),
+        Some(config) => Arc::new(RetryResolver::new(Arc::new(NativeResolver::new()), config)),
+        None => Arc::new(NativeResolver::new()),
+    };
+    let shared_resolver = Arc::new(SharedResolver {
+        description: describe_resolver(resolver_config),
+        resolver,
+        lookups: AtomicU64::new(0),
+        failures: Arc::new(AtomicU64::new(0)),
+    });
+    resolvers.insert(key, Arc::downgrade(&shared_resolver));
+    shared_resolver
+}
+
+// The resolvers that are in use, for the admin server.
+pub fn shared_resolver_list() -> Vec<Arc<SharedResolver>> {
+    shared_resolvers()
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+// Only returns the resolved addresses allowed by an IP preference.
+pub struct PreferenceResolver {
+    resolver: Arc<dyn Resolver>,
+    ip_preference: IpPreference,
+}
+
+impl PreferenceResolver {
+    // Returns the resolver itself when every address is allowed.
+    pub fn wrap(resolver: &Arc<dyn Resolver>, ip_preference: IpPreference) -> Arc<dyn Resolver> {
+        if ip_preference == IpPreference::Dual {
+            return resolver.clone();
+        }
+        Arc::new(Self {
+            resolver: resolver.clone(),
+            ip_preference,
+        })
+    }
+}
+
+impl Resolver for PreferenceResolver {
+    fn resolve_location(
+        &self,
+        location: &NetLocation,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+        let resolve_future = self.resolver.resolve_location(location);
+        let ip_preference = self.ip_preference;
+        let location = location.clone();
+        Box::pin(async move {
+            let socket_addrs = resolve_future.await?;
+            if socket_addrs.is_empty() {
+                // Let the caller report that nothing was resolved.
+                return Ok(socket_addrs);
+            }
+            // There is no Happy Eyeballs fallback between address families, so the preference
+            // is a hard filter.
+            let socket_addrs = socket_addrs
+                .into_iter()
+                .filter(|addr| match ip_preference {
+                    IpPreference::Ipv4 => addr.ip().to_canonical().is_ipv4(),
+                    IpPreference::Ipv6 => addr.ip().to_canonical().is_ipv6(),
+                    IpPreference::Dual => true,
+                })
+                .collect::<Vec<_>>();
+            if socket_addrs.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "no addresses for preference {}: {}",
+                        ip_preference, location
+                    ),
+                ));
+            }
+            Ok(socket_addrs)
+        })
+    }
+}
+
 pub async fn resolve_single_address(
     resolver: &Arc<dyn Resolver>,
     location: &NetLocation,
@@ -0,0 +1,378 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, Timelike, Utc, Weekday};
+
+use crate::config::{ScheduleConfig, ScheduleDay};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+// A start-end range of minutes since midnight, with the end excluded. The range spans midnight
+// when the end is earlier than the start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl TimeRange {
+    // Parses ranges like 09:00-17:30 or 22:00-06:00. 24:00 can be used as an end.
+    pub fn from(s: &str) -> std::io::Result<Self> {
+        let (start_str, end_str) = s.split_once('-').ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("time range should look like 09:00-17:00: {}", s),
+            )
+        })?;
+        let start = parse_time_of_day(start_str.trim())?;
+        let end = parse_time_of_day(end_str.trim())?;
+        if start == MINUTES_PER_DAY {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("time range can't start at 24:00: {}", s),
+            ));
+        }
+        if start == end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("time range is empty: {}", s),
+            ));
+        }
+        Ok(Self { start, end })
+    }
+
+    fn contains(&self, minute_of_day: u16) -> bool {
+        if self.start < self.end {
+            self.start <= minute_of_day && minute_of_day < self.end
+        } else {
+            self.start <= minute_of_day || minute_of_day < self.end
+        }
+    }
+
+    fn spans_midnight(&self) -> bool {
+        self.end < self.start
+    }
+}
+
+impl std::fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+fn parse_time_of_day(s: &str) -> std::io::Result<u16> {
+    let invalid_time = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid time of day, expected HH:MM: {}", s),
+        )
+    };
+    let (hours_str, minutes_str) = s.split_once(':').ok_or_else(invalid_time)?;
+    let hours = hours_str.parse::<u16>().map_err(|_| invalid_time())?;
+    let minutes = minutes_str.parse::<u16>().map_err(|_| invalid_time())?;
+    if minutes_str.len() != 2 || minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        return Err(invalid_time());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScheduleTimezone {
+    // The timezone of the host, following its daylight saving changes.
+    #[default]
+    Local,
+    Utc,
+    Offset(FixedOffset),
+}
+
+impl ScheduleTimezone {
+    // Parses local, utc, or a fixed offset like +08:00, -05:30 or +8.
+    pub fn from(s: &str) -> std::io::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => return Ok(ScheduleTimezone::Local),
+            "utc" | "z" => return Ok(ScheduleTimezone::Utc),
+            _ => (),
+        }
+        let invalid_timezone = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid timezone, expected local, utc or an offset like +08:00: {}",
+                    s
+                ),
+            )
+        };
+        let (sign, offset_str) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(invalid_timezone()),
+        };
+        let (hours_str, minutes_str) = offset_str.split_once(':').unwrap_or((offset_str, "0"));
+        let hours = hours_str.parse::<i32>().map_err(|_| invalid_timezone())?;
+        let minutes = minutes_str.parse::<i32>().map_err(|_| invalid_timezone())?;
+        if minutes >= 60 {
+            return Err(invalid_timezone());
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(ScheduleTimezone::Offset)
+            .ok_or_else(invalid_timezone)
+    }
+}
+
+impl std::fmt::Display for ScheduleTimezone {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScheduleTimezone::Local => write!(f, "local"),
+            ScheduleTimezone::Utc => write!(f, "utc"),
+            ScheduleTimezone::Offset(offset) => write!(f, "{}", offset),
+        }
+    }
+}
+
+fn to_weekday(day: ScheduleDay) -> Weekday {
+    match day {
+        ScheduleDay::Mon => Weekday::Mon,
+        ScheduleDay::Tue => Weekday::Tue,
+        ScheduleDay::Wed => Weekday::Wed,
+        ScheduleDay::Thu => Weekday::Thu,
+        ScheduleDay::Fri => Weekday::Fri,
+        ScheduleDay::Sat => Weekday::Sat,
+        ScheduleDay::Sun => Weekday::Sun,
+    }
+}
+
+// Whether a rule applies at the current time. Days are the days that a window starts on, so a
+// Friday 22:00-06:00 window includes early Saturday morning.
+#[derive(Debug)]
+pub struct RuleSchedule {
+    // A bit for each day, indexed by the number of days from Monday.
+    days: u8,
+    time: Option<TimeRange>,
+    timezone: ScheduleTimezone,
+    // The minute since the epoch that was last checked, shifted left by one, with whether the
+    // schedule was active in the lowest bit. Windows only start and end on whole minutes, so the
+    // result is reused until the minute changes.
+    cached_minute: AtomicU64,
+}
+
+impl RuleSchedule {
+    pub fn new(config: ScheduleConfig) -> Self {
+        let ScheduleConfig {
+            days,
+            time,
+            timezone,
+        } = config;
+        let days = if days.is_unspecified() {
+            0x7f
+        } else {
+            days.into_iter()
+                .map(|day| 1u8 << to_weekday(day).num_days_from_monday())
+                .fold(0, |days, day| days | day)
+        };
+        Self {
+            days,
+            time,
+            timezone,
+            cached_minute: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active_at(Utc::now())
+    }
+
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        let minute = (now.timestamp() / 60) as u64;
+        let cached_minute = self.cached_minute.load(Ordering::Relaxed);
+        if cached_minute >> 1 == minute {
+            return cached_minute & 1 == 1;
+        }
+        let is_active = self.is_active_uncached(now);
+        self.cached_minute
+            .store((minute << 1) | is_active as u64, Ordering::Relaxed);
+        is_active
+    }
+
+    fn is_active_uncached(&self, now: DateTime<Utc>) -> bool {
+        let (weekday, minute_of_day) = match self.timezone {
+            ScheduleTimezone::Local => local_time(now.with_timezone(&Local)),
+            ScheduleTimezone::Utc => local_time(now),
+            ScheduleTimezone::Offset(offset) => local_time(now.with_timezone(&offset)),
+        };
+        let time = match self.time {
+            Some(time) => time,
+            None => return self.includes_day(weekday),
+        };
+        if !time.contains(minute_of_day) {
+            return false;
+        }
+        if time.spans_midnight() && minute_of_day < time.end {
+            // This is the part of the window after midnight, which started the day before.
+            self.includes_day(weekday.pred())
+        } else {
+            self.includes_day(weekday)
+        }
+    }
+
+    fn includes_day(&self, weekday: Weekday) -> bool {
+        self.days & (1 << weekday.num_days_from_monday()) != 0
+    }
+}
+
+fn local_time<Tz: chrono::TimeZone>(time: DateTime<Tz>) -> (Weekday, u16) {
+    (time.weekday(), (time.hour() * 60 + time.minute()) as u16)
+}
+
+impl std::fmt::Display for RuleSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.days != 0x7f {
+            let days = WEEKDAYS
+                .iter()
+                .filter(|weekday| self.includes_day(**weekday))
+                .map(|weekday| weekday.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            write!(f, "{} ", days)?;
+        }
+        match self.time {
+            Some(time) => write!(f, "{} {}", time, self.timezone),
+            None => write!(f, "all day {}", self.timezone),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn schedule(config: &str) -> RuleSchedule {
+        RuleSchedule::new(serde_yaml::from_str(config).unwrap())
+    }
+
+    // 2026-10-16 is a Friday.
+    fn utc(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn range_spanning_midnight() {
+        let schedule = schedule("time: 22:00-02:00\ntimezone: utc");
+        assert!(!schedule.is_active_at(utc(16, 21, 59)));
+        assert!(schedule.is_active_at(utc(16, 22, 0)));
+        assert!(schedule.is_active_at(utc(16, 23, 59)));
+        assert!(schedule.is_active_at(utc(17, 0, 0)));
+        assert!(schedule.is_active_at(utc(17, 1, 59)));
+        assert!(!schedule.is_active_at(utc(17, 2, 0)));
+        assert!(!schedule.is_active_at(utc(17, 12, 0)));
+    }
+
+    #[test]
+    fn range_spanning_midnight_belongs_to_its_start_day() {
+        let schedule = schedule("days: fri\ntime: 22:00-02:00\ntimezone: utc");
+        // Friday night continues into Saturday morning.
+        assert!(schedule.is_active_at(utc(16, 23, 59)));
+        assert!(schedule.is_active_at(utc(17, 0, 0)));
+        assert!(!schedule.is_active_at(utc(17, 2, 0)));
+        assert!(!schedule.is_active_at(utc(17, 23, 0)));
+        // Early Friday morning is part of Thursday's window.
+        assert!(!schedule.is_active_at(utc(16, 1, 0)));
+    }
+
+    #[test]
+    fn range_ending_at_midnight() {
+        let schedule = schedule("time: 18:00-24:00\ntimezone: utc");
+        assert!(schedule.is_active_at(utc(16, 23, 59)));
+        assert!(!schedule.is_active_at(utc(17, 0, 0)));
+        assert!(!schedule.is_active_at(utc(17, 12, 0)));
+    }
+
+    #[test]
+    fn fixed_offset_timezone() {
+        let east = schedule("time: 09:00-17:00\ntimezone: \"+08:00\"");
+        assert!(!east.is_active_at(utc(16, 0, 59)));
+        assert!(east.is_active_at(utc(16, 1, 0)));
+        assert!(east.is_active_at(utc(16, 8, 59)));
+        assert!(!east.is_active_at(utc(16, 9, 0)));
+
+        let west = schedule("time: 09:00-17:00\ntimezone: \"-05:30\"");
+        assert!(!west.is_active_at(utc(16, 14, 29)));
+        assert!(west.is_active_at(utc(16, 14, 30)));
+        assert!(!west.is_active_at(utc(16, 22, 30)));
+    }
+
+    #[test]
+    fn days_follow_the_timezone() {
+        // Saturday 16:30 UTC is already Sunday in +08:00.
+        let schedule = schedule("days: sun\ntimezone: \"+08:00\"");
+        assert!(!schedule.is_active_at(utc(17, 15, 59)));
+        assert!(schedule.is_active_at(utc(17, 16, 0)));
+        assert!(schedule.is_active_at(utc(18, 15, 59)));
+        assert!(!schedule.is_active_at(utc(18, 16, 0)));
+    }
+
+    #[test]
+    fn parse_timezones() {
+        assert_eq!(
+            ScheduleTimezone::from("UTC").unwrap(),
+            ScheduleTimezone::Utc
+        );
+        assert_eq!(
+            ScheduleTimezone::from("local").unwrap(),
+            ScheduleTimezone::Local
+        );
+        assert_eq!(
+            ScheduleTimezone::from("+8").unwrap(),
+            ScheduleTimezone::Offset(FixedOffset::east_opt(8 * 3600).unwrap())
+        );
+        assert_eq!(
+            ScheduleTimezone::from("-05:30").unwrap(),
+            ScheduleTimezone::Offset(FixedOffset::west_opt(5 * 3600 + 30 * 60).unwrap())
+        );
+        for s in ["08:00", "+08:60", "+25:00", "Asia/Shanghai", ""] {
+            assert!(ScheduleTimezone::from(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn parse_time_ranges() {
+        assert_eq!(
+            TimeRange::from("22:00-02:00").unwrap(),
+            TimeRange {
+                start: 22 * 60,
+                end: 2 * 60
+            }
+        );
+        assert_eq!(
+            TimeRange::from("09:00 - 17:30").unwrap().to_string(),
+            "09:00-17:30"
+        );
+        for s in [
+            "24:00-06:00",
+            "09:00-09:00",
+            "09:00",
+            "25:00-06:00",
+            "09:0-17:00",
+            "24:01-01:00",
+        ] {
+            assert!(TimeRange::from(s).is_err(), "{}", s);
+        }
+    }
+}
@@ -1,31 +1,78 @@
 use std::sync::Arc;
 use std::sync::OnceLock;
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+    NoClientAuth,
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::TlsVersion;
+
+// The root certificates that a client trusts when verifying a server's certificate.
+pub struct ClientRoots {
+    pub ca_certs: Vec<rustls::Certificate>,
+    // Whether the bundled root certificates are also trusted.
+    pub system_roots: bool,
+}
+
+impl ClientRoots {
+    pub const SYSTEM: Self = ClientRoots {
+        ca_certs: vec![],
+        system_roots: true,
+    };
+}
+
+// When there are pinned SPKI hashes, the server's certificate must match one of them, and
+// is also verified against the root certificates unless `verify` is false.
 pub fn create_client_config(
     verify: bool,
+    roots: &ClientRoots,
+    pinned_spki_hashes: &[[u8; 32]],
     alpn_protocols: &[String],
     enable_sni: bool,
 ) -> rustls::ClientConfig {
     let builder = rustls::ClientConfig::builder().with_safe_defaults();
 
-    let mut config = if !verify {
-        builder
-            .with_custom_certificate_verifier(get_disabled_verifier())
-            .with_no_client_auth()
+    let verifier: Option<Arc<dyn rustls::client::ServerCertVerifier>> = if !verify {
+        None
     } else {
         let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-        builder
-            .with_root_certificates(root_store)
-            .with_no_client_auth()
+        if roots.system_roots {
+            root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+        for ca_cert in roots.ca_certs.iter() {
+            // The certificates were checked when they were loaded.
+            root_store.add(ca_cert).unwrap();
+        }
+        Some(Arc::new(rustls::client::WebPkiVerifier::new(
+            root_store, None,
+        )))
+    };
+
+    let verifier: Arc<dyn rustls::client::ServerCertVerifier> = if !pinned_spki_hashes.is_empty() {
+        Arc::new(PinnedVerifier {
+            pinned_spki_hashes: pinned_spki_hashes.to_vec(),
+            verifier,
+        })
+    } else {
+        match verifier {
+            Some(verifier) => verifier,
+            None => get_disabled_verifier(),
+        }
     };
 
+    let mut config = builder
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+
     config.alpn_protocols = alpn_protocols
         .iter()
         .map(|s| s.as_bytes().to_vec())
@@ -50,6 +97,323 @@ impl rustls::client::ServerCertVerifier for DisabledVerifier {
     }
 }
 
+// Accepts a certificate only if the SHA-256 hash of its SubjectPublicKeyInfo is pinned.
+pub struct PinnedVerifier {
+    pinned_spki_hashes: Vec<[u8; 32]>,
+    // Also checks the certificate when set.
+    verifier: Option<Arc<dyn rustls::client::ServerCertVerifier>>,
+}
+
+impl rustls::client::ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::client::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> std::result::Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let spki_hash = spki_sha256(&end_entity.0).ok_or(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::BadEncoding,
+        ))?;
+        if !self.pinned_spki_hashes.contains(&spki_hash) {
+            let expected = self
+                .pinned_spki_hashes
+                .iter()
+                .map(|hash| BASE64.encode(hash))
+                .collect::<Vec<_>>();
+            return Err(rustls::Error::General(format!(
+                "certificate SPKI SHA-256 {} does not match pinned {}",
+                BASE64.encode(spki_hash),
+                expected.join(", ")
+            )));
+        }
+        match self.verifier {
+            Some(ref verifier) => verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            None => Ok(rustls::client::ServerCertVerified::assertion()),
+        }
+    }
+}
+
+// Returns the whole element, its contents, and the bytes after the DER element at the start of
+// `data`.
+pub fn read_der_element(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first_len_byte = *data.get(1)?;
+    let (len, header_len) = if first_len_byte < 0x80 {
+        (first_len_byte as usize, 2)
+    } else {
+        let num_len_bytes = (first_len_byte & 0x7f) as usize;
+        if num_len_bytes == 0 || num_len_bytes > 4 {
+            return None;
+        }
+        let mut len = 0usize;
+        for b in data.get(2..2 + num_len_bytes)? {
+            len = (len << 8) | *b as usize;
+        }
+        (len, 2 + num_len_bytes)
+    };
+    let element = data.get(0..header_len.checked_add(len)?)?;
+    Some((element, &element[header_len..], &data[element.len()..]))
+}
+
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+
+pub struct CertificateFields<'a> {
+    // The whole serial number, issuer, subject and public key info elements.
+    pub serial_number: &'a [u8],
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    pub public_key_info: &'a [u8],
+    // The contents of the extensions sequence.
+    extensions: Option<&'a [u8]>,
+}
+
+impl<'a> CertificateFields<'a> {
+    // Returns the value of the extension with the OID element, without its octet string.
+    pub fn extension(&self, oid: &[u8]) -> Option<&'a [u8]> {
+        let mut extensions = self.extensions?;
+        while !extensions.is_empty() {
+            let (_, extension, next) = read_der_element(extensions)?;
+            extensions = next;
+            let (extension_oid, _, mut remaining) = read_der_element(extension)?;
+            if extension_oid != oid {
+                continue;
+            }
+            // Skip the critical flag.
+            if remaining.first() == Some(&0x01) {
+                remaining = read_der_element(remaining)?.2;
+            }
+            return Some(read_der_element(remaining)?.1);
+        }
+        None
+    }
+}
+
+pub fn parse_certificate(cert_der: &[u8]) -> Option<CertificateFields<'_>> {
+    let (_, cert, _) = read_der_element(cert_der)?;
+    let (_, mut tbs_certificate, _) = read_der_element(cert)?;
+    // Skip the optional version.
+    if tbs_certificate.first() == Some(&0xa0) {
+        tbs_certificate = read_der_element(tbs_certificate)?.2;
+    }
+    let (serial_number, _, remaining) = read_der_element(tbs_certificate)?;
+    // Skip the signature algorithm.
+    let remaining = read_der_element(remaining)?.2;
+    let (issuer, _, remaining) = read_der_element(remaining)?;
+    // Skip the validity.
+    let remaining = read_der_element(remaining)?.2;
+    let (subject, _, remaining) = read_der_element(remaining)?;
+    let (public_key_info, _, mut remaining) = read_der_element(remaining)?;
+
+    let mut extensions = None;
+    while !remaining.is_empty() {
+        let (element, contents, next) = read_der_element(remaining)?;
+        if element[0] == 0xa3 {
+            extensions = Some(read_der_element(contents)?.1);
+        }
+        remaining = next;
+    }
+    Some(CertificateFields {
+        serial_number,
+        issuer,
+        subject,
+        public_key_info,
+        extensions,
+    })
+}
+
+// Returns who a client certificate identifies, which is the subject common name, or the first
+// DNS name, email address or URI in the subject alternative names.
+pub fn cert_identity(cert_der: &[u8]) -> Option<String> {
+    let cert = parse_certificate(cert_der)?;
+
+    let (_, mut relative_names, _) = read_der_element(cert.subject)?;
+    while !relative_names.is_empty() {
+        let (_, relative_name, next) = read_der_element(relative_names)?;
+        relative_names = next;
+        let (_, attribute, _) = read_der_element(relative_name)?;
+        let (oid, _, value) = read_der_element(attribute)?;
+        if oid == OID_COMMON_NAME {
+            let (_, common_name, _) = read_der_element(value)?;
+            return String::from_utf8(common_name.to_vec()).ok();
+        }
+    }
+
+    let (_, mut names, _) = read_der_element(cert.extension(OID_SUBJECT_ALT_NAME)?)?;
+    while !names.is_empty() {
+        let (name, value, next) = read_der_element(names)?;
+        names = next;
+        // rfc822Name, dNSName and uniformResourceIdentifier are IA5 strings.
+        if matches!(name[0], 0x81 | 0x82 | 0x86) {
+            return String::from_utf8(value.to_vec()).ok();
+        }
+    }
+    None
+}
+
+// Returns the SHA-256 hash of a DER certificate's SubjectPublicKeyInfo.
+fn spki_sha256(cert_der: &[u8]) -> Option<[u8; 32]> {
+    let (_, cert, _) = read_der_element(cert_der)?;
+    let (_, mut tbs_certificate, _) = read_der_element(cert)?;
+    // Skip the optional version.
+    if tbs_certificate.first() == Some(&0xa0) {
+        tbs_certificate = read_der_element(tbs_certificate)?.2;
+    }
+    // Skip the serial number, signature algorithm, issuer, validity and subject.
+    for _ in 0..5 {
+        tbs_certificate = read_der_element(tbs_certificate)?.2;
+    }
+    let (spki, _, _) = read_der_element(tbs_certificate)?;
+    Some(Sha256::digest(spki).into())
+}
+
+// Returns when a DER certificate expires, as seconds since the unix epoch.
+pub fn cert_not_after(cert_der: &[u8]) -> Option<u64> {
+    let (_, cert, _) = read_der_element(cert_der)?;
+    let (_, mut tbs_certificate, _) = read_der_element(cert)?;
+    // Skip the optional version.
+    if tbs_certificate.first() == Some(&0xa0) {
+        tbs_certificate = read_der_element(tbs_certificate)?.2;
+    }
+    // Skip the serial number, signature algorithm and issuer.
+    for _ in 0..3 {
+        tbs_certificate = read_der_element(tbs_certificate)?.2;
+    }
+    let (_, validity, _) = read_der_element(tbs_certificate)?;
+    let not_before_remaining = read_der_element(validity)?.2;
+    let (not_after, _, _) = read_der_element(not_before_remaining)?;
+    parse_der_time(not_after)
+}
+
+// Returns a DER UTCTime or GeneralizedTime element as seconds since the unix epoch.
+pub fn parse_der_time(element: &[u8]) -> Option<u64> {
+    let (_, time, _) = read_der_element(element)?;
+    let time = std::str::from_utf8(time).ok()?;
+    // UTCTime has a two digit year, and GeneralizedTime has four.
+    let (year, time) = match element[0] {
+        0x17 => {
+            let year: u64 = time.get(0..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        0x18 => (time.get(0..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<u64> { time.get(i..i + 2)?.parse().ok() };
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    Some(days * 86400 + field(4)? * 3600 + field(6)? * 60 + field(8)?)
+}
+
+// Encodes a DER element with the tag and contents.
+pub fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let skip = len_bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (len_bytes.len() - skip) as u8);
+        out.extend_from_slice(&len_bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+pub fn der_sequence(parts: &[&[u8]]) -> Vec<u8> {
+    der(0x30, &parts.concat())
+}
+
+// The number of days from 1970-01-01 to the date, from Howard Hinnant's date algorithms.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Loads CA certificates from a PEM file, or from PEM data when `ca_cert` is inline.
+pub fn load_ca_certs(ca_cert: &str) -> std::io::Result<Vec<rustls::Certificate>> {
+    let (source, pem_bytes) = if ca_cert.contains("-----BEGIN") {
+        ("inline ca_cert".to_string(), ca_cert.as_bytes().to_vec())
+    } else {
+        let pem_bytes = std::fs::read(ca_cert).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("Failed to read ca_cert file {}: {}", ca_cert, e),
+            )
+        })?;
+        (format!("ca_cert file {}", ca_cert), pem_bytes)
+    };
+
+    let mut reader = std::io::Cursor::new(pem_bytes);
+    let mut certs = vec![];
+    for item in std::iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose()) {
+        let item = item.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to parse {}: {}", source, e),
+            )
+        })?;
+        if let rustls_pemfile::Item::X509Certificate(cert) = item {
+            certs.push(rustls::Certificate(cert));
+        }
+    }
+    if certs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", source),
+        ));
+    }
+
+    // Make sure that every certificate can be used as a trust anchor.
+    let mut root_store = rustls::RootCertStore::empty();
+    for cert in certs.iter() {
+        root_store.add(cert).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid certificate in {}: {}", source, e),
+            )
+        })?;
+    }
+
+    Ok(certs)
+}
+
+// Parses a base64 SPKI SHA-256 hash from a config.
+pub fn parse_spki_hash(s: &str) -> std::io::Result<[u8; 32]> {
+    let hash = BASE64.decode(s.trim()).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid base64 in pin_sha256 {}: {}", s, e),
+        )
+    })?;
+    hash.try_into().map_err(|hash: Vec<u8>| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid pin_sha256 {}, expected 32 bytes but got {}",
+                s,
+                hash.len()
+            ),
+        )
+    })
+}
+
 fn get_disabled_verifier() -> Arc<DisabledVerifier> {
     static INSTANCE: OnceLock<Arc<DisabledVerifier>> = OnceLock::new();
     INSTANCE
@@ -87,19 +451,148 @@ fn load_private_key(key_bytes: &[u8]) -> rustls::PrivateKey {
     panic!("No private key found");
 }
 
+fn cipher_suite_name(suite: &rustls::SupportedCipherSuite) -> String {
+    format!("{:?}", suite.suite())
+}
+
+// The TLS versions and cipher suites that a connection may use.
+pub struct TlsPolicy {
+    pub versions: Vec<&'static rustls::SupportedProtocolVersion>,
+    pub cipher_suites: Vec<rustls::SupportedCipherSuite>,
+}
+
+// Checks the version and cipher suite names from a config, where no cipher suites means the
+// rustls defaults.
+pub fn create_tls_policy(
+    min_tls_version: TlsVersion,
+    cipher_suite_names: &[String],
+) -> std::io::Result<TlsPolicy> {
+    let versions: Vec<&'static rustls::SupportedProtocolVersion> = match min_tls_version {
+        TlsVersion::Tls12 => vec![&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => vec![&rustls::version::TLS13],
+    };
+
+    let cipher_suites = if cipher_suite_names.is_empty() {
+        rustls::DEFAULT_CIPHER_SUITES.to_vec()
+    } else {
+        let mut cipher_suites = vec![];
+        for name in cipher_suite_names {
+            let suite = rustls::ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| cipher_suite_name(suite).eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    let allowed_names = rustls::ALL_CIPHER_SUITES
+                        .iter()
+                        .map(cipher_suite_name)
+                        .collect::<Vec<_>>();
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "Unknown cipher suite {}, expected one of: {}",
+                            name,
+                            allowed_names.join(", ")
+                        ),
+                    )
+                })?;
+            cipher_suites.push(*suite);
+        }
+        cipher_suites
+    };
+
+    if !cipher_suites
+        .iter()
+        .any(|suite| versions.contains(&suite.version()))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "None of the cipher suites ({}) can be used with TLS {}",
+                cipher_suite_names.join(", "),
+                match min_tls_version {
+                    TlsVersion::Tls12 => "1.2 or 1.3",
+                    TlsVersion::Tls13 => "1.3",
+                }
+            ),
+        ));
+    }
+
+    Ok(TlsPolicy {
+        versions,
+        cipher_suites,
+    })
+}
+
 pub fn create_server_config(
     cert_bytes: &[u8],
     key_bytes: &[u8],
     alpn_protocols: &[String],
+    tls_policy: &TlsPolicy,
+    client_verifier: Arc<dyn ClientCertVerifier>,
 ) -> rustls::ServerConfig {
     let certs = load_certs(cert_bytes);
     let privkey = load_private_key(key_bytes);
-    let mut config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
+    let config = server_config_builder(tls_policy, client_verifier)
         .with_single_cert(certs, privkey)
         .expect("bad certificate/key");
+    finish_server_config(config, alpn_protocols)
+}
 
+pub fn create_certified_key(cert_bytes: &[u8], key_bytes: &[u8]) -> rustls::sign::CertifiedKey {
+    let certs = load_certs(cert_bytes);
+    let privkey = load_private_key(key_bytes);
+    let signing_key = rustls::sign::any_supported_type(&privkey).expect("bad certificate/key");
+    rustls::sign::CertifiedKey::new(certs, signing_key)
+}
+
+// Like create_server_config, but the certificate is chosen for each connection, eg. so that it
+// can be replaced when it's renewed.
+pub fn create_server_config_with_resolver(
+    resolver: Arc<dyn rustls::server::ResolvesServerCert>,
+    alpn_protocols: &[String],
+    tls_policy: &TlsPolicy,
+    client_verifier: Arc<dyn ClientCertVerifier>,
+) -> rustls::ServerConfig {
+    let config = server_config_builder(tls_policy, client_verifier).with_cert_resolver(resolver);
+    finish_server_config(config, alpn_protocols)
+}
+
+fn server_config_builder(
+    tls_policy: &TlsPolicy,
+    client_verifier: Arc<dyn ClientCertVerifier>,
+) -> rustls::ConfigBuilder<rustls::ServerConfig, rustls::server::WantsServerCert> {
+    rustls::ServerConfig::builder()
+        .with_cipher_suites(&tls_policy.cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&tls_policy.versions)
+        .expect("invalid TLS policy")
+        .with_client_cert_verifier(client_verifier)
+}
+
+// Verifies client certificates against the CA certificates, when they're set. Clients without a
+// certificate are still accepted unless one is required.
+pub fn create_client_verifier(
+    client_ca: Option<&str>,
+    require_client_cert: bool,
+) -> std::io::Result<Arc<dyn ClientCertVerifier>> {
+    let client_ca = match client_ca {
+        Some(client_ca) => client_ca,
+        None => return Ok(NoClientAuth::boxed()),
+    };
+    let mut root_store = rustls::RootCertStore::empty();
+    for cert in load_ca_certs(client_ca)? {
+        root_store.add(&cert).unwrap();
+    }
+    Ok(if require_client_cert {
+        AllowAnyAuthenticatedClient::new(root_store).boxed()
+    } else {
+        AllowAnyAnonymousOrAuthenticatedClient::new(root_store).boxed()
+    })
+}
+
+fn finish_server_config(
+    mut config: rustls::ServerConfig,
+    alpn_protocols: &[String],
+) -> rustls::ServerConfig {
     config.alpn_protocols = alpn_protocols
         .iter()
         .map(|s| s.as_bytes().to_vec())
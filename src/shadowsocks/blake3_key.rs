@@ -1,12 +1,21 @@
 use super::shadowsocks_key::ShadowsocksKey;
-use crate::util::allocate_vec;
+use crate::util::{allocate_vec, Redacted};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Blake3Key {
     key_bytes: Box<[u8]>,
     session_key_len: usize,
 }
 
+impl std::fmt::Debug for Blake3Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blake3Key")
+            .field("key_bytes", &Redacted)
+            .field("session_key_len", &self.session_key_len)
+            .finish()
+    }
+}
+
 impl Blake3Key {
     pub fn new(key_bytes: Box<[u8]>, session_key_len: usize) -> Self {
         Self {
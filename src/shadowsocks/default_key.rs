@@ -1,14 +1,23 @@
 use md5::{Digest, Md5};
 
 use super::shadowsocks_key::ShadowsocksKey;
-use crate::util::allocate_vec;
+use crate::util::{allocate_vec, Redacted};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct DefaultKey {
     key_bytes: Box<[u8]>,
     key_len: usize,
 }
 
+impl std::fmt::Debug for DefaultKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DefaultKey")
+            .field("key_bytes", &Redacted)
+            .field("key_len", &self.key_len)
+            .finish()
+    }
+}
+
 impl DefaultKey {
     pub fn new(password: &str, key_len: usize) -> Self {
         Self {
@@ -6,10 +6,14 @@ mod shadowsocks_key;
 mod shadowsocks_stream;
 mod shadowsocks_stream_type;
 mod shadowsocks_tcp_handler;
+mod shadowsocks_udp_cipher;
+mod shadowsocks_udp_stream;
 
 pub use default_key::DefaultKey;
-pub use shadowsocks_cipher::ShadowsocksCipher;
+pub use shadowsocks_cipher::{is_none_cipher, ShadowsocksCipher};
 pub use shadowsocks_key::ShadowsocksKey;
 pub use shadowsocks_stream::ShadowsocksStream;
 pub use shadowsocks_stream_type::ShadowsocksStreamType;
 pub use shadowsocks_tcp_handler::ShadowsocksTcpHandler;
+pub use shadowsocks_udp_cipher::{ShadowsocksUdpCipher, ShadowsocksUdpPacket, UdpPacketSender};
+pub use shadowsocks_udp_stream::{ShadowsocksUdpClientStream, ShadowsocksUdpServerStream};
@@ -2,6 +2,12 @@ use ring::aead::{Algorithm, AES_128_GCM, AES_256_GCM, CHACHA20_POLY1305};
 
 use super::aead_util::TAG_LEN;
 
+// The "none" cipher sends the stream unencrypted after the address header, for setups where a
+// plugin or an outer TLS layer secures it. It's never the default.
+pub fn is_none_cipher(name: &str) -> bool {
+    matches!(name, "none" | "plain")
+}
+
 #[derive(Debug)]
 pub struct ShadowsocksCipher {
     algorithm: &'static Algorithm,
@@ -38,17 +44,24 @@ impl ShadowsocksCipher {
     pub fn salt_len(&self) -> usize {
         self.salt_len
     }
-}
 
-impl From<&str> for ShadowsocksCipher {
-    fn from(name: &str) -> Self {
+    pub fn from_name(name: &str) -> Option<Self> {
         match name {
             "chacha20-ietf-poly1305" | "chacha20-poly1305" => {
-                ShadowsocksCipher::chacha20_ietf_poly1305()
+                Some(ShadowsocksCipher::chacha20_ietf_poly1305())
             }
-            "aes-256-gcm" => ShadowsocksCipher::aes_256_gcm(),
-            "aes-128-gcm" => ShadowsocksCipher::aes_128_gcm(),
-            _ => {
+            "aes-256-gcm" => Some(ShadowsocksCipher::aes_256_gcm()),
+            "aes-128-gcm" => Some(ShadowsocksCipher::aes_128_gcm()),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for ShadowsocksCipher {
+    fn from(name: &str) -> Self {
+        match ShadowsocksCipher::from_name(name) {
+            Some(cipher) => cipher,
+            None => {
                 panic!("Unknown cipher: {}", name);
             }
         }
@@ -1,7 +1,7 @@
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use futures::ready;
 use parking_lot::Mutex;
@@ -923,6 +923,10 @@ impl AsyncPing for ShadowsocksStream {
         self.stream.supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
     fn poll_write_ping(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -946,7 +950,11 @@ impl AsyncShutdownMessage for ShadowsocksStream {
     }
 }
 
-impl AsyncStream for ShadowsocksStream {}
+impl AsyncStream for ShadowsocksStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+}
 impl AsyncMessageStream for ShadowsocksStream {}
 
 #[inline]
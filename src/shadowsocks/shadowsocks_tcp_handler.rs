@@ -14,35 +14,68 @@ use crate::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
 use crate::timed_salt_checker::TimedSaltChecker;
+use crate::udp_over_tcp::{
+    is_udp_over_tcp_location, setup_udp_over_tcp_server_stream, unsupported_udp_over_tcp_error,
+};
 use crate::util::allocate_vec;
 
 use super::blake3_key::Blake3Key;
 use super::default_key::DefaultKey;
-use super::shadowsocks_cipher::ShadowsocksCipher;
+use super::shadowsocks_cipher::{is_none_cipher, ShadowsocksCipher};
 use super::shadowsocks_key::ShadowsocksKey;
 use super::shadowsocks_stream::ShadowsocksStream;
 use super::shadowsocks_stream_type::ShadowsocksStreamType;
 
 #[derive(Debug)]
-pub struct ShadowsocksTcpHandler {
+struct StreamEncryption {
     cipher: ShadowsocksCipher,
     key: Arc<Box<dyn ShadowsocksKey>>,
+}
+
+impl StreamEncryption {
+    fn wrap_stream(
+        &self,
+        stream: Box<dyn AsyncStream>,
+        stream_type: ShadowsocksStreamType,
+        salt_checker: Option<Arc<Mutex<dyn SaltChecker>>>,
+    ) -> Box<dyn AsyncStream> {
+        Box::new(ShadowsocksStream::new(
+            stream,
+            stream_type,
+            self.cipher.algorithm(),
+            self.cipher.salt_len(),
+            self.key.clone(),
+            salt_checker,
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct ShadowsocksTcpHandler {
+    // None with the "none" cipher, where the stream isn't encrypted.
+    encryption: Option<StreamEncryption>,
     aead2022: bool,
     salt_checker: Option<Arc<Mutex<dyn SaltChecker>>>,
+    udp_over_tcp: bool,
 }
 
 impl ShadowsocksTcpHandler {
     pub fn new(cipher_name: &str, password: &str) -> Self {
-        let cipher: ShadowsocksCipher = cipher_name.into();
-        let key: Arc<Box<dyn ShadowsocksKey>> = Arc::new(Box::new(DefaultKey::new(
-            password,
-            cipher.algorithm().key_len(),
-        )));
+        let encryption = if is_none_cipher(cipher_name) {
+            None
+        } else {
+            let cipher: ShadowsocksCipher = cipher_name.into();
+            let key: Arc<Box<dyn ShadowsocksKey>> = Arc::new(Box::new(DefaultKey::new(
+                password,
+                cipher.algorithm().key_len(),
+            )));
+            Some(StreamEncryption { cipher, key })
+        };
         Self {
-            cipher,
-            key,
+            encryption,
             aead2022: false,
             salt_checker: None,
+            udp_over_tcp: false,
         }
     }
 
@@ -53,12 +86,18 @@ impl ShadowsocksTcpHandler {
             cipher.algorithm().key_len(),
         )));
         Self {
-            cipher,
-            key,
+            encryption: Some(StreamEncryption { cipher, key }),
             aead2022: true,
             salt_checker: Some(Arc::new(Mutex::new(TimedSaltChecker::new(60)))),
+            udp_over_tcp: false,
         }
     }
+
+    // Streams to the UDP-over-TCP hostname relay datagrams instead of being forwarded.
+    pub fn with_udp_over_tcp(mut self, udp_over_tcp: bool) -> Self {
+        self.udp_over_tcp = udp_over_tcp;
+        self
+    }
 }
 
 #[async_trait]
@@ -73,14 +112,12 @@ impl TcpServerHandler for ShadowsocksTcpHandler {
             ShadowsocksStreamType::AEAD
         };
 
-        let mut server_stream: Box<dyn AsyncStream> = Box::new(ShadowsocksStream::new(
-            server_stream,
-            stream_type,
-            self.cipher.algorithm(),
-            self.cipher.salt_len(),
-            self.key.clone(),
-            self.salt_checker.clone(),
-        ));
+        let mut server_stream = match self.encryption {
+            Some(ref encryption) => {
+                encryption.wrap_stream(server_stream, stream_type, self.salt_checker.clone())
+            }
+            None => server_stream,
+        };
 
         // We can do this in a blocking manner for the server, because we expect the client to
         // always send the location before we send anything.
@@ -104,6 +141,15 @@ impl TcpServerHandler for ShadowsocksTcpHandler {
             }
         }
 
+        if self.udp_over_tcp {
+            if is_udp_over_tcp_location(&remote_location) {
+                return setup_udp_over_tcp_server_stream(server_stream).await;
+            }
+            if let Some(e) = unsupported_udp_over_tcp_error(&remote_location) {
+                return Err(e);
+            }
+        }
+
         Ok(TcpServerSetupResult::TcpForward {
             remote_location,
             stream: server_stream,
@@ -112,6 +158,7 @@ impl TcpServerHandler for ShadowsocksTcpHandler {
             connection_success_response: None,
             initial_remote_data: None,
             override_proxy_provider: NoneOrOne::Unspecified,
+            authenticated_user: None,
         })
     }
 }
@@ -130,14 +177,12 @@ impl TcpClientHandler for ShadowsocksTcpHandler {
             ShadowsocksStreamType::AEAD
         };
 
-        let mut client_stream: Box<dyn AsyncStream> = Box::new(ShadowsocksStream::new(
-            client_stream,
-            stream_type,
-            self.cipher.algorithm(),
-            self.cipher.salt_len(),
-            self.key.clone(),
-            self.salt_checker.clone(),
-        ));
+        let mut client_stream = match self.encryption {
+            Some(ref encryption) => {
+                encryption.wrap_stream(client_stream, stream_type, self.salt_checker.clone())
+            }
+            None => client_stream,
+        };
 
         let mut location_vec = write_location_to_vec(&remote_location);
 
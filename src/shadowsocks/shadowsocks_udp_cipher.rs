@@ -0,0 +1,452 @@
+// Encrypts and decrypts Shadowsocks UDP packets.
+//
+// AEAD packets are a salt followed by the encrypted address and payload:
+// https://shadowsocks.org/doc/aead.html#udp
+//
+// AEAD 2022 packets start with a separate header holding the session and packet ids, which
+// is encrypted with the block cipher, followed by the encrypted body:
+// https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-1-shadowsocks-2022-edition.md
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes::{Aes128, Aes256, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use generic_array::GenericArray;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+
+use super::aead_util::TAG_LEN;
+use super::default_key::DefaultKey;
+use super::shadowsocks_cipher::{is_none_cipher, ShadowsocksCipher};
+use super::shadowsocks_key::ShadowsocksKey;
+use crate::address::NetLocation;
+use crate::config::ShadowsocksConfig;
+use crate::socks_handler::{read_location_from_slice, write_location_to_vec};
+use crate::util::{allocate_vec, Redacted};
+
+const SEPARATE_HEADER_LEN: usize = 16;
+const HEADER_TYPE_CLIENT: u8 = 0;
+const HEADER_TYPE_SERVER: u8 = 1;
+// Packets with timestamps further than this from the current time are rejected.
+const MAX_TIME_DIFFERENCE_SECS: u64 = 30;
+const SESSION_SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+
+// The number of packet ids before the newest one that are checked for replays. Older
+// packets are dropped.
+const PACKET_ID_WINDOW_WORDS: usize = 16;
+const PACKET_ID_WINDOW_SIZE: u64 = (PACKET_ID_WINDOW_WORDS * 64) as u64;
+
+enum UdpKey {
+    Aead(DefaultKey),
+    Aead2022 { psk: Box<[u8]> },
+}
+
+impl std::fmt::Debug for UdpKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Aead(key) => f.debug_tuple("Aead").field(key).finish(),
+            Self::Aead2022 { .. } => f.debug_struct("Aead2022").field("psk", &Redacted).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ShadowsocksUdpCipher {
+    cipher: ShadowsocksCipher,
+    key: UdpKey,
+}
+
+// Which side of the relay sent a packet, since AEAD 2022 packets differ for each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpPacketSender {
+    Client,
+    Server,
+}
+
+#[derive(Debug)]
+pub struct ShadowsocksUdpPacket {
+    // The target of packets from the client, or the source of packets from the server.
+    pub location: NetLocation,
+    pub payload: Box<[u8]>,
+    // The remaining fields are only set for AEAD 2022 packets.
+    session_id: u64,
+    packet_id: u64,
+    client_session_id: u64,
+}
+
+impl ShadowsocksUdpCipher {
+    // Uses udp_cipher when it's set, and the TCP cipher otherwise.
+    pub fn from_config(config: &ShadowsocksConfig) -> std::io::Result<Self> {
+        let cipher_name = config.udp_cipher.as_ref().unwrap_or(&config.cipher);
+        Self::new(cipher_name, &config.password)
+    }
+
+    pub fn new(cipher_name: &str, password: &str) -> std::io::Result<Self> {
+        let unknown_cipher = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown shadowsocks cipher: {}", cipher_name),
+            )
+        };
+
+        match cipher_name.strip_prefix("2022-blake3-") {
+            Some(name) => {
+                let cipher = ShadowsocksCipher::from_name(name).ok_or_else(unknown_cipher)?;
+                if !name.starts_with("aes-") {
+                    // The UDP variant of this cipher needs XChaCha20-Poly1305, which ring
+                    // doesn't have.
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!("UDP relay is not supported with {}", cipher_name),
+                    ));
+                }
+                let psk = BASE64.decode(password).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("could not base64 decode password: {}", e),
+                    )
+                })?;
+                if psk.len() != cipher.algorithm().key_len() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} needs a {} byte key, but the password is {} bytes",
+                            cipher_name,
+                            cipher.algorithm().key_len(),
+                            psk.len()
+                        ),
+                    ));
+                }
+                Ok(Self {
+                    cipher,
+                    key: UdpKey::Aead2022 {
+                        psk: psk.into_boxed_slice(),
+                    },
+                })
+            }
+            None => {
+                if is_none_cipher(cipher_name) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!("UDP relay is not supported with the {} cipher", cipher_name),
+                    ));
+                }
+                let cipher =
+                    ShadowsocksCipher::from_name(cipher_name).ok_or_else(unknown_cipher)?;
+                let key = DefaultKey::new(password, cipher.algorithm().key_len());
+                Ok(Self {
+                    cipher,
+                    key: UdpKey::Aead(key),
+                })
+            }
+        }
+    }
+
+    pub fn decrypt_packet(
+        &self,
+        sender: UdpPacketSender,
+        packet: &mut [u8],
+    ) -> std::io::Result<ShadowsocksUdpPacket> {
+        match self.key {
+            UdpKey::Aead(ref key) => {
+                let salt_len = self.cipher.salt_len();
+                if packet.len() < salt_len + TAG_LEN {
+                    return Err(invalid_packet("packet too short"));
+                }
+                let (salt, body) = packet.split_at_mut(salt_len);
+                let session_key = key.create_session_key(salt);
+                let plaintext = self.open(&session_key, [0u8; NONCE_LEN], body)?;
+                let (location, location_len) = read_location_from_slice(plaintext)?;
+                Ok(ShadowsocksUdpPacket {
+                    location,
+                    payload: plaintext[location_len..].into(),
+                    session_id: 0,
+                    packet_id: 0,
+                    client_session_id: 0,
+                })
+            }
+            UdpKey::Aead2022 { ref psk } => {
+                if packet.len() < SEPARATE_HEADER_LEN + TAG_LEN {
+                    return Err(invalid_packet("packet too short"));
+                }
+                let (header, body) = packet.split_at_mut(SEPARATE_HEADER_LEN);
+                decrypt_block(psk, header);
+                let session_id = u64::from_be_bytes(header[0..8].try_into().unwrap());
+                let packet_id = u64::from_be_bytes(header[8..16].try_into().unwrap());
+
+                let session_key = create_session_key(psk, session_id);
+                let plaintext = self.open(&session_key, header[4..16].try_into().unwrap(), body)?;
+
+                let (expected_type, fixed_len) = match sender {
+                    UdpPacketSender::Client => (HEADER_TYPE_CLIENT, 11),
+                    UdpPacketSender::Server => (HEADER_TYPE_SERVER, 19),
+                };
+                if plaintext.len() < fixed_len {
+                    return Err(invalid_packet("packet too short"));
+                }
+                if plaintext[0] != expected_type {
+                    return Err(invalid_packet("unexpected header type"));
+                }
+                let timestamp = u64::from_be_bytes(plaintext[1..9].try_into().unwrap());
+                if current_timestamp().abs_diff(timestamp) > MAX_TIME_DIFFERENCE_SECS {
+                    return Err(invalid_packet("packet timestamp is too old or new"));
+                }
+                let client_session_id = match sender {
+                    UdpPacketSender::Client => 0,
+                    UdpPacketSender::Server => {
+                        u64::from_be_bytes(plaintext[9..17].try_into().unwrap())
+                    }
+                };
+                let padding_len =
+                    u16::from_be_bytes(plaintext[fixed_len - 2..fixed_len].try_into().unwrap())
+                        as usize;
+                let location_start = fixed_len + padding_len;
+                if plaintext.len() < location_start {
+                    return Err(invalid_packet("packet too short for padding"));
+                }
+                let (location, location_len) =
+                    read_location_from_slice(&plaintext[location_start..])?;
+                Ok(ShadowsocksUdpPacket {
+                    location,
+                    payload: plaintext[location_start + location_len..].into(),
+                    session_id,
+                    packet_id,
+                    client_session_id,
+                })
+            }
+        }
+    }
+
+    fn encrypt_packet(
+        &self,
+        session: &mut ShadowsocksUdpSession,
+        location: &NetLocation,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let location_bytes = write_location_to_vec(location);
+        match self.key {
+            UdpKey::Aead(ref key) => {
+                let salt_len = self.cipher.salt_len();
+                let mut packet =
+                    Vec::with_capacity(salt_len + location_bytes.len() + payload.len() + TAG_LEN);
+                packet.resize(salt_len, 0);
+                rand::thread_rng().fill_bytes(&mut packet);
+                let session_key = key.create_session_key(&packet);
+                packet.extend_from_slice(&location_bytes);
+                packet.extend_from_slice(payload);
+                self.seal(&session_key, [0u8; NONCE_LEN], &mut packet, salt_len);
+                packet
+            }
+            UdpKey::Aead2022 { ref psk } => {
+                let packet_id = session.next_packet_id;
+                session.next_packet_id += 1;
+
+                let mut packet = Vec::with_capacity(
+                    SEPARATE_HEADER_LEN + 19 + location_bytes.len() + payload.len() + TAG_LEN,
+                );
+                packet.extend_from_slice(&session.session_id.to_be_bytes());
+                packet.extend_from_slice(&packet_id.to_be_bytes());
+                let nonce: [u8; NONCE_LEN] = packet[4..16].try_into().unwrap();
+
+                match session.role {
+                    UdpPacketSender::Client => {
+                        packet.push(HEADER_TYPE_CLIENT);
+                        packet.extend_from_slice(&current_timestamp().to_be_bytes());
+                    }
+                    UdpPacketSender::Server => {
+                        packet.push(HEADER_TYPE_SERVER);
+                        packet.extend_from_slice(&current_timestamp().to_be_bytes());
+                        packet
+                            .extend_from_slice(&session.peer_session_id.unwrap_or(0).to_be_bytes());
+                    }
+                }
+                // no padding
+                packet.extend_from_slice(&0u16.to_be_bytes());
+                packet.extend_from_slice(&location_bytes);
+                packet.extend_from_slice(payload);
+
+                let session_key = create_session_key(psk, session.session_id);
+                self.seal(&session_key, nonce, &mut packet, SEPARATE_HEADER_LEN);
+                encrypt_block(psk, &mut packet[0..SEPARATE_HEADER_LEN]);
+                packet
+            }
+        }
+    }
+
+    fn open<'a>(
+        &self,
+        session_key: &[u8],
+        nonce: [u8; NONCE_LEN],
+        data: &'a mut [u8],
+    ) -> std::io::Result<&'a mut [u8]> {
+        let key = LessSafeKey::new(UnboundKey::new(self.cipher.algorithm(), session_key).unwrap());
+        key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), data)
+            .map_err(|_| invalid_packet("failed to decrypt packet"))
+    }
+
+    // Encrypts the packet from body_start, and appends the tag.
+    fn seal(
+        &self,
+        session_key: &[u8],
+        nonce: [u8; NONCE_LEN],
+        packet: &mut Vec<u8>,
+        body_start: usize,
+    ) {
+        let key = LessSafeKey::new(UnboundKey::new(self.cipher.algorithm(), session_key).unwrap());
+        let tag = key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut packet[body_start..],
+            )
+            .unwrap();
+        packet.extend_from_slice(tag.as_ref());
+    }
+}
+
+// The state of one side of a UDP relay session, which is needed for AEAD 2022 packets.
+#[derive(Debug)]
+pub struct ShadowsocksUdpSession {
+    role: UdpPacketSender,
+    session_id: u64,
+    next_packet_id: u64,
+    peer_session_id: Option<u64>,
+    peer_packet_ids: PacketIdWindow,
+}
+
+impl ShadowsocksUdpSession {
+    pub fn new(role: UdpPacketSender) -> Self {
+        Self {
+            role,
+            session_id: rand::random(),
+            next_packet_id: 0,
+            peer_session_id: None,
+            peer_packet_ids: PacketIdWindow::new(),
+        }
+    }
+
+    pub fn encrypt_packet(
+        &mut self,
+        cipher: &ShadowsocksUdpCipher,
+        location: &NetLocation,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        cipher.encrypt_packet(self, location, payload)
+    }
+
+    // Returns false if the packet is a replay or belongs to another session, and should be
+    // dropped.
+    pub fn accept_packet(
+        &mut self,
+        cipher: &ShadowsocksUdpCipher,
+        packet: &ShadowsocksUdpPacket,
+    ) -> bool {
+        if let UdpKey::Aead(_) = cipher.key {
+            return true;
+        }
+        if self.role == UdpPacketSender::Client && packet.client_session_id != self.session_id {
+            return false;
+        }
+        if self.peer_session_id != Some(packet.session_id) {
+            // The peer started a new session, so the packet ids start over.
+            self.peer_session_id = Some(packet.session_id);
+            self.peer_packet_ids = PacketIdWindow::new();
+        }
+        self.peer_packet_ids.insert(packet.packet_id)
+    }
+}
+
+// Sliding window of received packet ids.
+#[derive(Debug)]
+struct PacketIdWindow {
+    newest_id: Option<u64>,
+    bits: [u64; PACKET_ID_WINDOW_WORDS],
+}
+
+impl PacketIdWindow {
+    fn new() -> Self {
+        Self {
+            newest_id: None,
+            bits: [0u64; PACKET_ID_WINDOW_WORDS],
+        }
+    }
+
+    fn bit(id: u64) -> (usize, u64) {
+        (
+            (id / 64) as usize % PACKET_ID_WINDOW_WORDS,
+            1u64 << (id % 64),
+        )
+    }
+
+    // Returns false if the id was already received, or is too old to tell.
+    fn insert(&mut self, id: u64) -> bool {
+        match self.newest_id {
+            Some(newest_id) if id <= newest_id => {
+                if newest_id - id >= PACKET_ID_WINDOW_SIZE {
+                    return false;
+                }
+            }
+            Some(newest_id) => {
+                if id - newest_id >= PACKET_ID_WINDOW_SIZE {
+                    self.bits = [0u64; PACKET_ID_WINDOW_WORDS];
+                } else {
+                    for skipped_id in newest_id + 1..=id {
+                        let (word, mask) = Self::bit(skipped_id);
+                        self.bits[word] &= !mask;
+                    }
+                }
+                self.newest_id = Some(id);
+            }
+            None => {
+                self.newest_id = Some(id);
+            }
+        }
+
+        let (word, mask) = Self::bit(id);
+        if self.bits[word] & mask != 0 {
+            return false;
+        }
+        self.bits[word] |= mask;
+        true
+    }
+}
+
+fn invalid_packet(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn current_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn create_session_key(psk: &[u8], session_id: u64) -> Box<[u8]> {
+    let mut hasher = blake3::Hasher::new_derive_key(SESSION_SUBKEY_CONTEXT);
+    hasher.update(psk);
+    hasher.update(&session_id.to_be_bytes());
+    let mut session_key = allocate_vec(psk.len());
+    hasher.finalize_xof().fill(&mut session_key);
+    session_key.into_boxed_slice()
+}
+
+// The separate header is encrypted with AES using the PSK, which is 16 bytes for
+// aes-128-gcm and 32 bytes for aes-256-gcm.
+fn encrypt_block(psk: &[u8], block: &mut [u8]) {
+    let block = GenericArray::from_mut_slice(block);
+    if psk.len() == 16 {
+        Aes128::new(GenericArray::from_slice(psk)).encrypt_block(block);
+    } else {
+        Aes256::new(GenericArray::from_slice(psk)).encrypt_block(block);
+    }
+}
+
+fn decrypt_block(psk: &[u8], block: &mut [u8]) {
+    let block = GenericArray::from_mut_slice(block);
+    if psk.len() == 16 {
+        Aes128::new(GenericArray::from_slice(psk)).decrypt_block(block);
+    } else {
+        Aes256::new(GenericArray::from_slice(psk)).decrypt_block(block);
+    }
+}
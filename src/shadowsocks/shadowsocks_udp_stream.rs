@@ -0,0 +1,274 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use log::debug;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use super::shadowsocks_udp_cipher::{
+    ShadowsocksUdpCipher, ShadowsocksUdpPacket, ShadowsocksUdpSession, UdpPacketSender,
+};
+use crate::address::NetLocation;
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncReadSourcedMessage,
+    AsyncReadTargetedMessage, AsyncShutdownMessage, AsyncSourcedMessageStream,
+    AsyncTargetedMessageStream, AsyncWriteMessage, AsyncWriteSourcedMessage,
+    AsyncWriteTargetedMessage,
+};
+
+// The server side of a UDP relay session with a single client. Packets are received on the
+// shared server socket and decrypted before they are passed to the session.
+pub struct ShadowsocksUdpServerStream {
+    socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    cipher: Arc<ShadowsocksUdpCipher>,
+    session: ShadowsocksUdpSession,
+    packets: mpsc::Receiver<ShadowsocksUdpPacket>,
+}
+
+impl ShadowsocksUdpServerStream {
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        client_addr: SocketAddr,
+        cipher: Arc<ShadowsocksUdpCipher>,
+        packets: mpsc::Receiver<ShadowsocksUdpPacket>,
+    ) -> Self {
+        Self {
+            socket,
+            client_addr,
+            cipher,
+            session: ShadowsocksUdpSession::new(UdpPacketSender::Server),
+            packets,
+        }
+    }
+}
+
+impl AsyncReadTargetedMessage for ShadowsocksUdpServerStream {
+    fn poll_read_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<NetLocation>> {
+        let this = self.get_mut();
+        loop {
+            let packet = match ready!(this.packets.poll_recv(cx)) {
+                Some(packet) => packet,
+                None => {
+                    return Poll::Ready(Ok(NetLocation::UNSPECIFIED));
+                }
+            };
+            if !this.session.accept_packet(&this.cipher, &packet) {
+                debug!("Dropping replayed UDP packet from {}", this.client_addr);
+                continue;
+            }
+            if packet.payload.len() > buf.remaining() {
+                debug!("Dropping oversized UDP packet from {}", this.client_addr);
+                continue;
+            }
+            buf.put_slice(&packet.payload);
+            return Poll::Ready(Ok(packet.location));
+        }
+    }
+}
+
+impl AsyncWriteSourcedMessage for ShadowsocksUdpServerStream {
+    fn poll_write_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        source: &SocketAddr,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let packet =
+            this.session
+                .encrypt_packet(&this.cipher, &NetLocation::from_socket_addr(*source), buf);
+        this.socket
+            .poll_send_to(cx, &packet, this.client_addr)
+            .map(|result| result.map(|_| ()))
+    }
+}
+
+impl AsyncFlushMessage for ShadowsocksUdpServerStream {
+    fn poll_flush_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncShutdownMessage for ShadowsocksUdpServerStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for ShadowsocksUdpServerStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncTargetedMessageStream for ShadowsocksUdpServerStream {}
+
+// Relays datagrams through a Shadowsocks server. The socket should be connected to the
+// server's address.
+pub struct ShadowsocksUdpClientStream {
+    socket: UdpSocket,
+    cipher: Arc<ShadowsocksUdpCipher>,
+    session: ShadowsocksUdpSession,
+    // Where messages are sent when this is used as a message stream to a single
+    // destination.
+    target: Option<NetLocation>,
+    read_buf: Box<[u8]>,
+}
+
+impl ShadowsocksUdpClientStream {
+    pub fn new(
+        socket: UdpSocket,
+        cipher: Arc<ShadowsocksUdpCipher>,
+        target: Option<NetLocation>,
+    ) -> Self {
+        Self {
+            socket,
+            cipher,
+            session: ShadowsocksUdpSession::new(UdpPacketSender::Client),
+            target,
+            read_buf: vec![0u8; 65535].into_boxed_slice(),
+        }
+    }
+}
+
+impl AsyncReadSourcedMessage for ShadowsocksUdpClientStream {
+    fn poll_read_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<SocketAddr>> {
+        let this = self.get_mut();
+        loop {
+            let mut read_buf = ReadBuf::new(&mut this.read_buf);
+            ready!(this.socket.poll_recv(cx, &mut read_buf))?;
+            let len = read_buf.filled().len();
+
+            let packet = match this
+                .cipher
+                .decrypt_packet(UdpPacketSender::Server, &mut this.read_buf[0..len])
+            {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("Dropping invalid shadowsocks UDP packet: {}", e);
+                    continue;
+                }
+            };
+            if !this.session.accept_packet(&this.cipher, &packet) {
+                debug!("Dropping replayed shadowsocks UDP packet");
+                continue;
+            }
+            let source = match packet.location.to_socket_addr_nonblocking() {
+                Some(source) => source,
+                None => {
+                    debug!(
+                        "Dropping shadowsocks UDP packet from unresolved source {}",
+                        packet.location
+                    );
+                    continue;
+                }
+            };
+            if packet.payload.len() > buf.remaining() {
+                debug!("Dropping oversized shadowsocks UDP packet from {}", source);
+                continue;
+            }
+            buf.put_slice(&packet.payload);
+            return Poll::Ready(Ok(source));
+        }
+    }
+}
+
+impl AsyncWriteTargetedMessage for ShadowsocksUdpClientStream {
+    fn poll_write_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &NetLocation,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let packet = this.session.encrypt_packet(&this.cipher, target, buf);
+        this.socket
+            .poll_send(cx, &packet)
+            .map(|result| result.map(|_| ()))
+    }
+}
+
+impl AsyncReadMessage for ShadowsocksUdpClientStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.poll_read_sourced_message(cx, buf)
+            .map(|result| result.map(|_| ()))
+    }
+}
+
+impl AsyncWriteMessage for ShadowsocksUdpClientStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let target = match self.target {
+            Some(ref target) => target.clone(),
+            None => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "no target for shadowsocks UDP message",
+                )));
+            }
+        };
+        self.poll_write_targeted_message(cx, buf, &target)
+    }
+}
+
+impl AsyncFlushMessage for ShadowsocksUdpClientStream {
+    fn poll_flush_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncShutdownMessage for ShadowsocksUdpClientStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for ShadowsocksUdpClientStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncSourcedMessageStream for ShadowsocksUdpClientStream {}
+
+impl AsyncMessageStream for ShadowsocksUdpClientStream {}
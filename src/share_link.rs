@@ -0,0 +1,428 @@
+use base64::{
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+    Engine as _,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::config::{
+    BindLocation, GrpcServerConfig, ServerConfig, ServerProxyConfig, ShadowsocksConfig,
+    TlsServerConfig, Transport, WebsocketServerConfig,
+};
+
+// A share link for one way of connecting to a server.
+pub enum ShareLink {
+    Url(String),
+    // There is no share link convention for this part of the config, for the given reason.
+    Unsupported(String),
+}
+
+// The TLS, websocket and gRPC settings that wrap a protocol, which share links put in the query.
+#[derive(Clone, Default)]
+struct Layers {
+    // The SNI hostname and ALPN protocols.
+    tls: Option<(Option<String>, Vec<String>)>,
+    // The path and Host header.
+    websocket: Option<(String, Option<String>)>,
+    // The service name.
+    grpc: Option<String>,
+}
+
+impl Layers {
+    // Whether the protocol is carried by websocket or gRPC, of which links only have one.
+    fn has_transport(&self) -> bool {
+        self.websocket.is_some() || self.grpc.is_some()
+    }
+}
+
+// Everything except unreserved characters is encoded in URL components.
+const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn encode(s: &str) -> String {
+    utf8_percent_encode(s, COMPONENT).to_string()
+}
+
+// Creates share links for each protocol that clients can connect to the server with, using the
+// public host that clients connect to.
+pub fn share_links(server_config: &ServerConfig, host: &str) -> Vec<ShareLink> {
+    // Links use the port of the first address the server listens on.
+    let bind_port =
+        server_config
+            .bind_locations
+            .iter()
+            .find_map(|bind_location| match bind_location {
+                BindLocation::Address(ref a) => Some(a.port()),
+                BindLocation::Path(_) => None,
+            });
+    let port = match bind_port {
+        Some(port) => port,
+        None => {
+            return vec![ShareLink::Unsupported(
+                "servers on unix domain sockets have no share links".to_string(),
+            )];
+        }
+    };
+    // Links are for TCP, which is also what clients fall back to from QUIC.
+    if !server_config.has_transport(Transport::Tcp) {
+        return vec![ShareLink::Unsupported(format!(
+            "{:?} transport has no share link convention",
+            server_config.transports.iter().next().unwrap()
+        ))];
+    }
+
+    // IPv6 addresses need brackets in URLs.
+    let host = if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    let name = server_config
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("{}:{}", host, port));
+
+    let mut links = vec![];
+    collect_links(
+        &server_config.protocol,
+        Layers::default(),
+        &host,
+        port,
+        &name,
+        &mut links,
+    );
+    links
+}
+
+fn collect_links(
+    protocol: &ServerProxyConfig,
+    layers: Layers,
+    host: &str,
+    port: u16,
+    name: &str,
+    links: &mut Vec<ShareLink>,
+) {
+    match protocol {
+        ServerProxyConfig::Tls {
+            sni_targets,
+            default_target,
+            ..
+        } => {
+            if layers.tls.is_some() || layers.has_transport() {
+                links.push(ShareLink::Unsupported(
+                    "TLS inside TLS, websocket or gRPC has no share link convention".to_string(),
+                ));
+                return;
+            }
+            let mut sni_hostnames = sni_targets.keys().collect::<Vec<_>>();
+            sni_hostnames.sort();
+            for sni_hostname in sni_hostnames {
+                if sni_hostname.starts_with("*.") {
+                    links.push(ShareLink::Unsupported(format!(
+                        "wildcard SNI target {} has no single hostname for clients to send",
+                        sni_hostname
+                    )));
+                    continue;
+                }
+                collect_tls_links(
+                    &sni_targets[sni_hostname],
+                    Some(sni_hostname.clone()),
+                    &layers,
+                    host,
+                    port,
+                    name,
+                    links,
+                );
+            }
+            if let Some(default_target) = default_target {
+                // Clients send the public host as the SNI hostname, unless it's an address.
+                let sni_hostname =
+                    if host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+                        None
+                    } else {
+                        Some(host.to_string())
+                    };
+                collect_tls_links(
+                    default_target,
+                    sni_hostname,
+                    &layers,
+                    host,
+                    port,
+                    name,
+                    links,
+                );
+            }
+        }
+        ServerProxyConfig::Websocket { targets } => {
+            if layers.has_transport() {
+                links.push(ShareLink::Unsupported(
+                    "websocket inside websocket or gRPC has no share link convention".to_string(),
+                ));
+                return;
+            }
+            for target in targets.iter() {
+                collect_websocket_links(target, &layers, host, port, name, links);
+            }
+        }
+        ServerProxyConfig::Grpc(grpc_config) => {
+            if layers.has_transport() {
+                links.push(ShareLink::Unsupported(
+                    "gRPC inside websocket or gRPC has no share link convention".to_string(),
+                ));
+                return;
+            }
+            collect_grpc_links(grpc_config, &layers, host, port, name, links);
+        }
+        ServerProxyConfig::Shadowsocks(shadowsocks_config) => {
+            if layers.tls.is_some() || layers.has_transport() {
+                links.push(ShareLink::Unsupported(
+                    "Shadowsocks inside TLS, websocket or gRPC has no share link convention"
+                        .to_string(),
+                ));
+                return;
+            }
+            links.push(ShareLink::Url(shadowsocks_link(
+                shadowsocks_config,
+                host,
+                port,
+                name,
+            )));
+        }
+        ServerProxyConfig::Vmess {
+            cipher,
+            user_id,
+            force_aead,
+            alter_id,
+            ..
+        } => {
+            let alter_id = if *force_aead { 0 } else { *alter_id };
+            links.push(ShareLink::Url(vmess_link(
+                cipher, user_id, alter_id, &layers, host, port, name,
+            )));
+        }
+        ServerProxyConfig::Trojan {
+            password,
+            shadowsocks,
+            ..
+        } => {
+            if shadowsocks.is_some() {
+                links.push(ShareLink::Unsupported(
+                    "Trojan with shadowsocks encryption has no share link convention".to_string(),
+                ));
+                return;
+            }
+            links.push(ShareLink::Url(format!(
+                "trojan://{}@{}:{}?{}#{}",
+                encode(password),
+                host,
+                port,
+                layers_query(&layers),
+                encode(name)
+            )));
+        }
+        ServerProxyConfig::Vless { user_id, flow, .. } => {
+            let flow_param = match flow {
+                Some(flow) => format!("flow={}&", flow.name()),
+                None => String::new(),
+            };
+            links.push(ShareLink::Url(format!(
+                "vless://{}@{}:{}?encryption=none&{}{}#{}",
+                encode(user_id),
+                host,
+                port,
+                flow_param,
+                layers_query(&layers),
+                encode(name)
+            )));
+        }
+        ServerProxyConfig::Http { .. }
+        | ServerProxyConfig::Socks { .. }
+        | ServerProxyConfig::Snell(_)
+        | ServerProxyConfig::PortForward { .. } => {
+            links.push(ShareLink::Unsupported(format!(
+                "{} has no share link convention",
+                protocol
+            )));
+        }
+    }
+}
+
+fn collect_tls_links(
+    tls_config: &TlsServerConfig,
+    sni_hostname: Option<String>,
+    layers: &Layers,
+    host: &str,
+    port: u16,
+    name: &str,
+    links: &mut Vec<ShareLink>,
+) {
+    let alpn_protocols = tls_config
+        .alpn_protocols
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    let layers = Layers {
+        tls: Some((sni_hostname, alpn_protocols)),
+        ..layers.clone()
+    };
+    collect_links(&tls_config.protocol, layers, host, port, name, links);
+}
+
+fn collect_websocket_links(
+    websocket_config: &WebsocketServerConfig,
+    layers: &Layers,
+    host: &str,
+    port: u16,
+    name: &str,
+    links: &mut Vec<ShareLink>,
+) {
+    let path = websocket_config
+        .matching_path
+        .clone()
+        .unwrap_or_else(|| "/".to_string());
+    let host_header = websocket_config
+        .matching_headers
+        .as_ref()
+        .and_then(|headers| {
+            headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        })
+        .map(|(_, value)| value.clone());
+    let layers = Layers {
+        websocket: Some((path, host_header)),
+        ..layers.clone()
+    };
+    collect_links(&websocket_config.protocol, layers, host, port, name, links);
+}
+
+fn collect_grpc_links(
+    grpc_config: &GrpcServerConfig,
+    layers: &Layers,
+    host: &str,
+    port: u16,
+    name: &str,
+    links: &mut Vec<ShareLink>,
+) {
+    let layers = Layers {
+        grpc: Some(grpc_config.service_name.clone()),
+        ..layers.clone()
+    };
+    collect_links(&grpc_config.protocol, layers, host, port, name, links);
+}
+
+// The query parameters used by trojan and vless links.
+fn layers_query(layers: &Layers) -> String {
+    let mut params = vec![];
+    match layers.tls {
+        Some((ref sni_hostname, ref alpn_protocols)) => {
+            params.push("security=tls".to_string());
+            if let Some(sni_hostname) = sni_hostname {
+                params.push(format!("sni={}", encode(sni_hostname)));
+            }
+            if !alpn_protocols.is_empty() {
+                params.push(format!("alpn={}", encode(&alpn_protocols.join(","))));
+            }
+        }
+        None => params.push("security=none".to_string()),
+    }
+    match (&layers.websocket, &layers.grpc) {
+        (Some((ref path, ref host_header)), _) => {
+            params.push("type=ws".to_string());
+            params.push(format!("path={}", encode(path)));
+            if let Some(host_header) = host_header {
+                params.push(format!("host={}", encode(host_header)));
+            }
+        }
+        // The server accepts both modes, so links use the default gun mode.
+        (None, Some(ref service_name)) => {
+            params.push("type=grpc".to_string());
+            params.push(format!("serviceName={}", encode(service_name)));
+            params.push("mode=gun".to_string());
+        }
+        (None, None) => params.push("type=tcp".to_string()),
+    }
+    params.join("&")
+}
+
+// SIP002 links, where the user info is base64 encoded except for 2022 ciphers.
+fn shadowsocks_link(
+    shadowsocks_config: &ShadowsocksConfig,
+    host: &str,
+    port: u16,
+    name: &str,
+) -> String {
+    let ShadowsocksConfig {
+        cipher,
+        password,
+        plugin,
+        plugin_opts,
+        ..
+    } = shadowsocks_config;
+    let user_info = if cipher.starts_with("2022-") {
+        format!("{}:{}", encode(cipher), encode(password))
+    } else {
+        BASE64_URL.encode(format!("{}:{}", cipher, password))
+    };
+    let query = match plugin {
+        Some(plugin) => {
+            let plugin = match plugin_opts {
+                Some(plugin_opts) => format!("{};{}", plugin, plugin_opts),
+                None => plugin.clone(),
+            };
+            format!("/?plugin={}", encode(&plugin))
+        }
+        None => String::new(),
+    };
+    format!(
+        "ss://{}@{}:{}{}#{}",
+        user_info,
+        host,
+        port,
+        query,
+        encode(name)
+    )
+}
+
+// The base64 encoded JSON format that v2rayN uses.
+fn vmess_link(
+    cipher: &str,
+    user_id: &str,
+    alter_id: u16,
+    layers: &Layers,
+    host: &str,
+    port: u16,
+    name: &str,
+) -> String {
+    // gRPC links put the service name in path and the mode in type.
+    let (network, path, host_header, header_type) = match (&layers.websocket, &layers.grpc) {
+        (Some((ref path, ref host_header)), _) => ("ws", path.clone(), host_header.clone(), "none"),
+        (None, Some(ref service_name)) => ("grpc", service_name.clone(), None, "gun"),
+        (None, None) => ("tcp", String::new(), None, "none"),
+    };
+    let (tls, sni, alpn) = match layers.tls {
+        Some((ref sni_hostname, ref alpn_protocols)) => {
+            ("tls", sni_hostname.clone(), alpn_protocols.join(","))
+        }
+        None => ("", None, String::new()),
+    };
+    let json = serde_json::json!({
+        "v": "2",
+        "ps": name,
+        "add": host.trim_start_matches('[').trim_end_matches(']'),
+        "port": port.to_string(),
+        "id": user_id,
+        "aid": alter_id.to_string(),
+        "scy": cipher,
+        "net": network,
+        "type": header_type,
+        "host": host_header.unwrap_or_default(),
+        "path": path,
+        "tls": tls,
+        "sni": sni.unwrap_or_default(),
+        "alpn": alpn,
+    });
+    format!("vmess://{}", BASE64.encode(json.to_string()))
+}
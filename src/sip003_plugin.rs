@@ -0,0 +1,79 @@
+// Runs SIP003 plugins, which carry shadowsocks TCP traffic through a subprocess:
+// https://shadowsocks.org/doc/sip003.html
+//
+// On clients the plugin listens on a local address and forwards to the remote server, and on
+// servers it listens on the public address and forwards to the local shadowsocks listener.
+
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+
+use log::{debug, error};
+
+use crate::address::NetLocation;
+
+#[derive(Debug)]
+pub struct PluginProcess {
+    plugin: String,
+    child: Child,
+    local_address: SocketAddr,
+}
+
+impl PluginProcess {
+    pub fn start(
+        plugin: &str,
+        plugin_opts: Option<&str>,
+        local_address: SocketAddr,
+        remote_location: &NetLocation,
+    ) -> std::io::Result<Self> {
+        let (remote_address, remote_port) = remote_location.components();
+        let mut command = Command::new(plugin);
+        command
+            .env("SS_LOCAL_HOST", local_address.ip().to_string())
+            .env("SS_LOCAL_PORT", local_address.port().to_string())
+            .env("SS_REMOTE_HOST", remote_address.to_string())
+            .env("SS_REMOTE_PORT", remote_port.to_string())
+            .stdin(Stdio::null());
+        if let Some(plugin_opts) = plugin_opts {
+            command.env("SS_PLUGIN_OPTIONS", plugin_opts);
+        }
+
+        let child = command.spawn().map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("failed to start plugin {}: {}", plugin, e),
+            )
+        })?;
+        debug!(
+            "Started plugin {} (pid {}) at {} for {}",
+            plugin,
+            child.id(),
+            local_address,
+            remote_location
+        );
+
+        Ok(Self {
+            plugin: plugin.to_string(),
+            child,
+            local_address,
+        })
+    }
+
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        if let Err(e) = self.child.kill() {
+            error!("Failed to stop plugin {}: {}", self.plugin, e);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+// Finds a loopback address for the plugin and the shadowsocks listener to talk over.
+pub fn unused_local_address() -> std::io::Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.local_addr()
+}
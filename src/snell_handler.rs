@@ -15,14 +15,23 @@ use crate::snell_udp_stream::SnellUdpStream;
 use crate::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
-use crate::util::allocate_vec;
+use crate::util::{allocate_vec, Redacted};
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct SnellKey {
     password_bytes: Box<[u8]>,
     key_len: usize,
 }
 
+impl std::fmt::Debug for SnellKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SnellKey")
+            .field("password_bytes", &Redacted)
+            .field("key_len", &self.key_len)
+            .finish()
+    }
+}
+
 impl SnellKey {
     pub fn new(password: &str, key_len: usize) -> Self {
         Self {
@@ -138,20 +147,12 @@ impl TcpServerHandler for SnellTcpHandler {
             let hostname_len = header[0] as usize;
             server_stream.read_exact(&mut buf[0..hostname_len]).await?;
 
-            let hostname_str = match std::str::from_utf8(&buf[0..hostname_len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Failed to decode hostname: {}", e),
-                    ));
-                }
-            };
+            let address = Address::from_header_bytes(&buf[0..hostname_len])?;
 
             server_stream.read_exact(&mut header[0..2]).await?;
             let port = ((header[0] as u16) << 8) | (header[1] as u16);
 
-            let remote_location = NetLocation::new(Address::from(hostname_str)?, port);
+            let remote_location = NetLocation::new(address, port);
 
             Ok(TcpServerSetupResult::TcpForward {
                 remote_location,
@@ -162,6 +163,7 @@ impl TcpServerHandler for SnellTcpHandler {
                 connection_success_response: Some(TCP_TUNNEL_RESPONSE.to_vec().into_boxed_slice()),
                 initial_remote_data: None,
                 override_proxy_provider: NoneOrOne::Unspecified,
+                authenticated_user: None,
             })
         } else {
             // write tunnel response.
@@ -238,3 +240,98 @@ impl TcpClientHandler for SnellTcpHandler {
         Ok(TcpClientSetupResult { client_stream })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn handler() -> SnellTcpHandler {
+        SnellTcpHandler::new("aes-128-gcm", "password")
+    }
+
+    // A connect request for the domain, without a client id.
+    fn domain_request(domain: &[u8]) -> Vec<u8> {
+        let mut request = vec![1, 1, 0, domain.len() as u8];
+        request.extend_from_slice(domain);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        request
+    }
+
+    // Sets up a server stream from the request, which is encrypted like a client would. When
+    // close is false, the client end stays open, so reading past the request would hang instead
+    // of failing with an EOF.
+    async fn setup(
+        handler: &SnellTcpHandler,
+        request: &[u8],
+        close: bool,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let mut client_stream = ShadowsocksStream::new(
+            Box::new(client_stream),
+            ShadowsocksStreamType::AEAD,
+            handler.cipher.algorithm(),
+            handler.cipher.salt_len(),
+            handler.key.clone(),
+            None,
+        );
+        client_stream.write_all(request).await.unwrap();
+        client_stream.flush().await.unwrap();
+        if close {
+            drop(client_stream);
+            return handler.setup_server_stream(Box::new(server_stream)).await;
+        }
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.setup_server_stream(Box::new(server_stream)),
+        )
+        .await
+        .expect("server read past the request");
+        drop(client_stream);
+        result
+    }
+
+    fn expect_error(result: std::io::Result<TcpServerSetupResult>) -> std::io::Error {
+        match result {
+            Ok(_) => panic!("expected the request to be rejected"),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn domain_at_max_length_is_accepted() {
+        let handler = handler();
+        match setup(&handler, &domain_request(&[b'a'; 255]), false).await {
+            Ok(TcpServerSetupResult::TcpForward {
+                remote_location, ..
+            }) => {
+                assert_eq!(
+                    remote_location,
+                    NetLocation::new(Address::Hostname("a".repeat(255)), 443)
+                );
+            }
+            Ok(_) => panic!("expected a tcp forward"),
+            Err(e) => panic!("request was rejected: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_domains_are_rejected() {
+        let handler = handler();
+        let error = expect_error(setup(&handler, &domain_request(b""), false).await);
+        assert_eq!(error.to_string(), "Empty address in header");
+
+        let error = expect_error(setup(&handler, &domain_request(b"example\xff.com"), false).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_domain_is_rejected() {
+        let handler = handler();
+        let request = domain_request(b"example.com");
+        // The connection closes before the whole domain arrives.
+        let error = expect_error(setup(&handler, &request[0..request.len() - 6], true).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -2,6 +2,7 @@ use futures::ready;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::ReadBuf;
 
 use crate::address::{Address, NetLocation};
@@ -220,6 +221,10 @@ impl AsyncPing for SnellUdpStream {
         self.stream.supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
     // Write a ping message to the stream, if supported.
     // This should end up calling the highest level stream abstraction that supports
     // pings, and should only result in a single message.
@@ -1,28 +1,59 @@
 #[inline]
-pub fn new_udp_socket(bind_interface: Option<String>) -> std::io::Result<tokio::net::UdpSocket> {
-    // TODO: this is blocking?
-    let std_socket = std::net::UdpSocket::bind("[::]:0")?;
-    std_socket.set_nonblocking(true)?;
-
-    // tokio's UdpSocket has bind_device, so construct that instead of having to
-    // handle SO_BINDTODEVICE ourselves.
-    let tokio_socket = tokio::net::UdpSocket::from_std(std_socket).unwrap();
+pub fn new_udp_socket(
+    bind_interface: Option<String>,
+    bind_port: Option<u16>,
+    reuse_port: bool,
+    dscp: Option<u8>,
+) -> std::io::Result<tokio::net::UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV6,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    // Match binding to [::] with the standard library, which also accepts IPv4.
+    socket.set_only_v6(false)?;
+
+    // Bind the device before the address, so that datagrams never go out of another interface.
     if let Some(b) = bind_interface {
         #[cfg(all(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-        tokio_socket.bind_device(Some(b.as_bytes()))?;
+        socket.bind_device(Some(b.as_bytes()))?;
 
         // This should be handled during config validation.
         #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
         panic!("Could not find to device, unsupported platform.")
     }
 
-    Ok(tokio_socket)
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(reuse_port)?;
+    // This should be handled during config validation.
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+    if reuse_port {
+        panic!("reuse_port is not supported on this platform.")
+    }
+
+    if let Some(dscp) = dscp {
+        set_dscp(socket2::SockRef::from(&socket), true, dscp)?;
+    }
+
+    let address =
+        std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, bind_port.unwrap_or(0)));
+    socket.bind(&address.into()).map_err(|e| match bind_port {
+        Some(port) => std::io::Error::new(
+            e.kind(),
+            format!("failed to bind UDP socket to port {}: {}", port, e),
+        ),
+        None => e,
+    })?;
+    socket.set_nonblocking(true)?;
+
+    tokio::net::UdpSocket::from_std(socket.into())
 }
 
 #[inline]
 pub fn new_tcp_socket(
     bind_interface: Option<String>,
     is_ipv6: bool,
+    dscp: Option<u8>,
 ) -> std::io::Result<tokio::net::TcpSocket> {
     let tcp_socket = if is_ipv6 {
         tokio::net::TcpSocket::new_v6()?
@@ -39,5 +70,77 @@ pub fn new_tcp_socket(
         panic!("Could not find to device, unsupported platform.")
     }
 
+    if let Some(dscp) = dscp {
+        set_dscp(socket2::SockRef::from(&tcp_socket), is_ipv6, dscp)?;
+    }
+
     Ok(tcp_socket)
 }
+
+// Binds a listener like tokio::net::TcpListener::bind, but with the given listen backlog.
+pub fn new_tcp_listener(
+    address: std::net::SocketAddr,
+    backlog: u32,
+) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(address),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // The standard library sets this too, so that restarted servers can bind right away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(backlog as i32)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+#[cfg(target_family = "unix")]
+pub fn new_unix_listener(
+    path: &std::path::Path,
+    backlog: u32,
+) -> std::io::Result<tokio::net::UnixListener> {
+    let socket = socket2::Socket::new(socket2::Domain::UNIX, socket2::Type::STREAM, None)?;
+    socket.bind(&socket2::SockAddr::unix(path)?)?;
+    socket.listen(backlog as i32)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::UnixListener::from_std(socket.into())
+}
+
+// Marks packets sent from the socket with the DSCP class, which is the upper six bits of the
+// IPv4 type of service and the IPv6 traffic class.
+pub fn set_dscp(socket: socket2::SockRef, is_ipv6: bool, dscp: u8) -> std::io::Result<()> {
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    {
+        let tos = u32::from(dscp) << 2;
+        if !is_ipv6 {
+            return socket.set_tos(tos);
+        }
+        socket.set_tclass_v6(tos)?;
+        // IPv6 sockets also send to IPv4-mapped addresses, which use the IPv4 option.
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        socket.set_tos(tos)?;
+        Ok(())
+    }
+
+    // This should be handled during config validation.
+    #[cfg(not(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )))]
+    panic!("dscp is not supported on this platform.")
+}
@@ -1,16 +1,21 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 
 use async_trait::async_trait;
+use log::warn;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncStream;
+use crate::client_proxy_selector::ClientProxySelector;
 use crate::option_util::NoneOrOne;
+use crate::tcp_client_connector::TcpClientConnector;
 use crate::tcp_handler::{
-    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+    auth_failure_error, TcpClientHandler, TcpClientSetupResult, TcpServerHandler,
+    TcpServerSetupResult,
 };
-use crate::util::allocate_vec;
+use crate::user_quota::user_quotas;
+use crate::util::{allocate_vec, constant_time_eq, Redacted};
 
 pub const VER_SOCKS5: u8 = 0x05;
 pub const VER_AUTH: u8 = 0x01;
@@ -24,18 +29,63 @@ pub const ADDR_TYPE_DOMAIN_NAME: u8 = 0x03;
 pub const ADDR_TYPE_IPV6: u8 = 0x04;
 
 pub const RESULT_SUCCESS: u8 = 0x0;
+pub const RESULT_AUTH_FAILURE: u8 = 0x1;
 
 pub const CMD_CONNECT: u8 = 0x01;
 pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
 
+pub struct SocksUser {
+    username: String,
+    password: String,
+    override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+}
+
+impl std::fmt::Debug for SocksUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocksUser")
+            .field("username", &self.username)
+            .field("password", &Redacted)
+            .field("override_proxy_provider", &self.override_proxy_provider)
+            .finish()
+    }
+}
+
+impl SocksUser {
+    pub fn new(
+        username: String,
+        password: String,
+        override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+    ) -> Self {
+        Self {
+            username,
+            password,
+            override_proxy_provider,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SocksTcpServerHandler {
-    auth_info: Option<(String, String)>,
+    // Authentication is required when this is not empty.
+    users: Vec<SocksUser>,
 }
 
 impl SocksTcpServerHandler {
-    pub fn new(auth_info: Option<(String, String)>) -> Self {
-        Self { auth_info }
+    pub fn new(users: Vec<SocksUser>) -> Self {
+        Self { users }
+    }
+
+    // Checks every user so that the time taken doesn't depend on which one matched.
+    fn find_user(&self, username: &[u8], password: &[u8]) -> Option<&SocksUser> {
+        let mut matched_user = None;
+        for user in self.users.iter() {
+            let username_matches = constant_time_eq(user.username.as_bytes(), username);
+            let password_matches = constant_time_eq(user.password.as_bytes(), password);
+            if username_matches && password_matches {
+                matched_user = Some(user);
+            }
+        }
+        matched_user
     }
 }
 
@@ -66,7 +116,7 @@ impl TcpServerHandler for SocksTcpServerHandler {
         let mut methods = allocate_vec(method_len);
         server_stream.read_exact(&mut methods).await?;
 
-        let supported_method = if self.auth_info.is_some() {
+        let supported_method = if !self.users.is_empty() {
             METHOD_USERNAME
         } else {
             METHOD_NONE
@@ -88,7 +138,8 @@ impl TcpServerHandler for SocksTcpServerHandler {
         data[1] = supported_method;
         server_stream.write_all(&data).await?;
 
-        if let Some((target_username, target_password)) = self.auth_info.as_ref() {
+        let mut authenticated_user = None;
+        if !self.users.is_empty() {
             server_stream.read_exact(&mut data).await?;
             if data[0] != VER_AUTH {
                 return Err(std::io::Error::new(
@@ -121,31 +172,33 @@ impl TcpServerHandler for SocksTcpServerHandler {
             let mut password = allocate_vec(password_len);
             server_stream.read_exact(&mut password).await?;
 
-            let username_str = match std::str::from_utf8(&username) {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Failed to decode username: {}", e),
-                    ));
-                }
-            };
-            let password_str = match std::str::from_utf8(&password) {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Failed to decode password: {}", e),
+            let user = match self.find_user(&username, &password) {
+                Some(user) => user,
+                None => {
+                    data[0] = VER_AUTH;
+                    data[1] = RESULT_AUTH_FAILURE;
+                    server_stream.write_all(&data).await?;
+                    server_stream.flush().await?;
+                    return Err(auth_failure_error(
+                        "Incorrect username or password provided",
                     ));
                 }
             };
-
-            if target_username != username_str || target_password != password_str {
+            if user_quotas().is_exceeded(&user.username) {
+                warn!(
+                    "Rejected SOCKS connection from user {}, who has used up their data quota",
+                    user.username
+                );
+                data[0] = VER_AUTH;
+                data[1] = RESULT_AUTH_FAILURE;
+                server_stream.write_all(&data).await?;
+                server_stream.flush().await?;
                 return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Incorrect username or password provided",
+                    std::io::ErrorKind::PermissionDenied,
+                    "Data quota exceeded",
                 ));
             }
+            authenticated_user = Some(user);
 
             data[0] = VER_AUTH;
             data[1] = RESULT_SUCCESS;
@@ -196,6 +249,14 @@ impl TcpServerHandler for SocksTcpServerHandler {
 
         let location = read_location(&mut server_stream).await?;
 
+        let (override_proxy_provider, authenticated_user) = match authenticated_user {
+            Some(user) => (
+                user.override_proxy_provider.clone(),
+                Some(user.username.clone()),
+            ),
+            None => (NoneOrOne::Unspecified, None),
+        };
+
         Ok(TcpServerSetupResult::TcpForward {
             remote_location: location,
             stream: server_stream,
@@ -204,17 +265,27 @@ impl TcpServerHandler for SocksTcpServerHandler {
                 connection_success_response.to_vec().into_boxed_slice(),
             ),
             initial_remote_data: None,
-            override_proxy_provider: NoneOrOne::Unspecified,
+            override_proxy_provider,
+            authenticated_user,
         })
     }
 }
 
-#[derive(Debug)]
 pub struct SocksTcpClientHandler {
+    // The greeting, followed by the credentials when has_auth is set.
     prefix_data: Vec<u8>,
     has_auth: bool,
 }
 
+impl std::fmt::Debug for SocksTcpClientHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SocksTcpClientHandler")
+            .field("prefix_data", &Redacted)
+            .field("has_auth", &self.has_auth)
+            .finish()
+    }
+}
+
 impl SocksTcpClientHandler {
     pub fn new(auth_info: Option<(String, String)>) -> Self {
         let mut data = vec![
@@ -232,29 +303,43 @@ impl SocksTcpClientHandler {
             data.push(password.len() as u8);
             data.extend_from_slice(password.as_bytes());
         }
-        data.extend(&[
-            VER_SOCKS5,
-            CMD_CONNECT,
-            0x0, // reserved
-        ]);
 
         Self {
             prefix_data: data,
             has_auth: auth_info.is_some(),
         }
     }
-}
 
-#[async_trait]
-impl TcpClientHandler for SocksTcpClientHandler {
-    async fn setup_client_stream(
+    // Asks the server to relay datagrams, and returns the relay's location. The relay is only
+    // kept while the stream stays open.
+    pub async fn setup_udp_associate(
         &self,
-        _server_stream: &mut Box<dyn AsyncStream>,
-        mut client_stream: Box<dyn AsyncStream>,
-        remote_location: NetLocation,
-    ) -> std::io::Result<TcpClientSetupResult> {
-        client_stream.write_all(&self.prefix_data).await?;
-        write_location(&mut client_stream, &remote_location).await?;
+        client_stream: &mut Box<dyn AsyncStream>,
+    ) -> std::io::Result<NetLocation> {
+        // The datagrams may come from any address.
+        self.send_command(
+            client_stream,
+            CMD_UDP_ASSOCIATE,
+            "UDP associate",
+            &NetLocation::UNSPECIFIED,
+        )
+        .await
+    }
+
+    // Sends the greeting, the credentials and the command together, and returns the location
+    // in the server's response.
+    async fn send_command(
+        &self,
+        client_stream: &mut Box<dyn AsyncStream>,
+        command: u8,
+        command_name: &str,
+        location: &NetLocation,
+    ) -> std::io::Result<NetLocation> {
+        let mut request = self.prefix_data.clone();
+        // The last byte is reserved.
+        request.extend(&[VER_SOCKS5, command, 0x0]);
+        request.extend(write_location_to_vec(location));
+        client_stream.write_all(&request).await?;
         client_stream.flush().await?;
 
         let mut data = [0u8; 2];
@@ -279,22 +364,33 @@ impl TcpClientHandler for SocksTcpClientHandler {
             }
         }
 
-        let mut connect_response_prefix = [0u8; 3];
-        client_stream
-            .read_exact(&mut connect_response_prefix)
-            .await?;
-        if connect_response_prefix[1] != RESULT_SUCCESS {
+        let mut response_prefix = [0u8; 3];
+        client_stream.read_exact(&mut response_prefix).await?;
+        if response_prefix[1] != RESULT_SUCCESS {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 format!(
-                    "SOCKS server connect command failed: error {}",
-                    connect_response_prefix[1]
+                    "SOCKS server {} command failed: error {}",
+                    command_name, response_prefix[1]
                 ),
             ));
         }
 
-        // Read the final location part of the connect response.
-        read_location(&mut client_stream).await?;
+        // Read the final location part of the response.
+        read_location(client_stream).await
+    }
+}
+
+#[async_trait]
+impl TcpClientHandler for SocksTcpClientHandler {
+    async fn setup_client_stream(
+        &self,
+        _server_stream: &mut Box<dyn AsyncStream>,
+        mut client_stream: Box<dyn AsyncStream>,
+        remote_location: NetLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        self.send_command(&mut client_stream, CMD_CONNECT, "connect", &remote_location)
+            .await?;
 
         Ok(TcpClientSetupResult { client_stream })
     }
@@ -349,15 +445,7 @@ pub async fn read_location(stream: &mut Box<dyn AsyncStream>) -> std::io::Result
             let mut address_bytes = allocate_vec(address_len + 2);
             stream.read_exact(&mut address_bytes).await?;
 
-            let address_str = match std::str::from_utf8(&address_bytes[0..address_len]) {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Failed to decode address: {}", e),
-                    ));
-                }
-            };
+            let address = Address::from_header_bytes(&address_bytes[0..address_len])?;
 
             let port = u16::from_be_bytes(
                 address_bytes[address_len..address_len + 2]
@@ -365,10 +453,7 @@ pub async fn read_location(stream: &mut Box<dyn AsyncStream>) -> std::io::Result
                     .unwrap(),
             );
 
-            // Although this is supposed to be a hostname, some clients will pass
-            // ipv4 and ipv6 addresses as well, so parse it rather than directly
-            // using Address:Hostname enum.
-            Ok(NetLocation::new(Address::from(address_str)?, port))
+            Ok(NetLocation::new(address, port))
         }
 
         _ => Err(std::io::Error::new(
@@ -412,6 +497,56 @@ pub async fn write_location(
     Ok(())
 }
 
+// Parses a location at the start of the data, and returns it along with its length.
+pub fn read_location_from_slice(data: &[u8]) -> std::io::Result<(NetLocation, usize)> {
+    let too_short = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "data too short for location",
+        )
+    };
+
+    let address_type = *data.first().ok_or_else(too_short)?;
+    match address_type {
+        ADDR_TYPE_IPV4 => {
+            if data.len() < 7 {
+                return Err(too_short());
+            }
+            let address_bytes: [u8; 4] = data[1..5].try_into().unwrap();
+            let port = u16::from_be_bytes(data[5..7].try_into().unwrap());
+            Ok((
+                NetLocation::new(Address::Ipv4(Ipv4Addr::from(address_bytes)), port),
+                7,
+            ))
+        }
+        ADDR_TYPE_IPV6 => {
+            if data.len() < 19 {
+                return Err(too_short());
+            }
+            let address_bytes: [u8; 16] = data[1..17].try_into().unwrap();
+            let port = u16::from_be_bytes(data[17..19].try_into().unwrap());
+            Ok((
+                NetLocation::new(Address::Ipv6(Ipv6Addr::from(address_bytes)), port),
+                19,
+            ))
+        }
+        ADDR_TYPE_DOMAIN_NAME => {
+            let address_len = *data.get(1).ok_or_else(too_short)? as usize;
+            if data.len() < address_len + 4 {
+                return Err(too_short());
+            }
+            let address = Address::from_header_bytes(&data[2..2 + address_len])?;
+            let port =
+                u16::from_be_bytes(data[2 + address_len..4 + address_len].try_into().unwrap());
+            Ok((NetLocation::new(address, port), address_len + 4))
+        }
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unknown address type: {}", address_type),
+        )),
+    }
+}
+
 pub fn write_location_to_vec(location: &NetLocation) -> Vec<u8> {
     let (address, port) = location.components();
     let mut vec = match address {
@@ -441,3 +576,96 @@ pub fn write_location_to_vec(location: &NetLocation) -> Vec<u8> {
     vec.push((port & 0xff) as u8);
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // Reads a location from the bytes. The other end stays open, so reading past the location
+    // would hang instead of failing with an EOF.
+    async fn read_location_from(bytes: &[u8]) -> std::io::Result<NetLocation> {
+        let (mut client_stream, server_stream) = tokio::io::duplex(1024);
+        client_stream.write_all(bytes).await.unwrap();
+        let mut server_stream: Box<dyn AsyncStream> = Box::new(server_stream);
+        let result =
+            tokio::time::timeout(Duration::from_secs(5), read_location(&mut server_stream))
+                .await
+                .expect("read past the location");
+        drop(client_stream);
+        result
+    }
+
+    fn domain_location(domain: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![ADDR_TYPE_DOMAIN_NAME, domain.len() as u8];
+        bytes.extend_from_slice(domain);
+        bytes.extend_from_slice(&443u16.to_be_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn domain_at_max_length_is_read() {
+        let bytes = domain_location(&[b'a'; 255]);
+        assert_eq!(
+            read_location_from(&bytes).await.unwrap(),
+            NetLocation::new(Address::Hostname("a".repeat(255)), 443)
+        );
+        assert_eq!(
+            read_location_from_slice(&bytes).unwrap(),
+            (
+                NetLocation::new(Address::Hostname("a".repeat(255)), 443),
+                bytes.len()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_domains_are_rejected() {
+        let error = read_location_from(&domain_location(b"")).await.unwrap_err();
+        assert_eq!(error.to_string(), "Empty address in header");
+
+        let error = read_location_from(&domain_location(b"example\xff.com"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let error = read_location_from(&[0x02, 1, 2, 3, 4, 0, 80])
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Unknown address type: 2");
+    }
+
+    #[tokio::test]
+    async fn truncated_location_is_rejected() {
+        // The domain length says 10 bytes follow, but the stream ends after 4.
+        let (mut client_stream, server_stream) = tokio::io::duplex(1024);
+        client_stream
+            .write_all(&[ADDR_TYPE_DOMAIN_NAME, 10, b'a', b'b', b'c', b'd'])
+            .await
+            .unwrap();
+        drop(client_stream);
+
+        let mut server_stream: Box<dyn AsyncStream> = Box::new(server_stream);
+        let error = read_location(&mut server_stream).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_location_slices_are_rejected() {
+        let mut ipv4 = vec![ADDR_TYPE_IPV4, 1, 2, 3, 4];
+        ipv4.extend_from_slice(&80u16.to_be_bytes());
+        let mut ipv6 = vec![ADDR_TYPE_IPV6];
+        ipv6.extend_from_slice(&[0u8; 16]);
+        ipv6.extend_from_slice(&80u16.to_be_bytes());
+        let domain = domain_location(b"example.com");
+
+        for bytes in [ipv4, ipv6, domain] {
+            assert_eq!(read_location_from_slice(&bytes).unwrap().1, bytes.len());
+            for len in 0..bytes.len() {
+                let error = read_location_from_slice(&bytes[0..len]).unwrap_err();
+                assert_eq!(error.to_string(), "data too short for location");
+            }
+        }
+    }
+}
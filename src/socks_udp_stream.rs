@@ -0,0 +1,196 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use log::debug;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::address::NetLocation;
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncReadSourcedMessage,
+    AsyncShutdownMessage, AsyncSourcedMessageStream, AsyncStream, AsyncWriteMessage,
+    AsyncWriteTargetedMessage,
+};
+use crate::socks_handler::{read_location_from_slice, write_location_to_vec};
+
+// The reserved bytes and fragment number that start each datagram.
+const HEADER_PREFIX: [u8; 3] = [0, 0, 0];
+
+// Relays datagrams through a SOCKS5 UDP associate relay. The socket should be connected to the
+// relay's address, and the control connection is held for as long as the relay is used, since
+// the server stops relaying when it closes.
+pub struct SocksUdpClientStream {
+    socket: UdpSocket,
+    control_stream: Box<dyn AsyncStream>,
+    // Where messages are sent when this is used as a message stream to a single
+    // destination.
+    target: Option<NetLocation>,
+    read_buf: Box<[u8]>,
+}
+
+impl SocksUdpClientStream {
+    pub fn new(
+        socket: UdpSocket,
+        control_stream: Box<dyn AsyncStream>,
+        target: Option<NetLocation>,
+    ) -> Self {
+        Self {
+            socket,
+            control_stream,
+            target,
+            read_buf: vec![0u8; 65535].into_boxed_slice(),
+        }
+    }
+
+    // Returns an error once the server closes the control connection. Nothing else is sent on
+    // it, so any data is dropped.
+    fn poll_control_stream(&mut self, cx: &mut Context<'_>) -> std::io::Result<()> {
+        let mut data = [0u8; 64];
+        loop {
+            let mut read_buf = ReadBuf::new(&mut data);
+            match Pin::new(&mut self.control_stream).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {
+                    if read_buf.filled().is_empty() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::ConnectionAborted,
+                            "SOCKS server closed the UDP associate control connection",
+                        ));
+                    }
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => return Ok(()),
+            }
+        }
+    }
+}
+
+impl AsyncReadSourcedMessage for SocksUdpClientStream {
+    fn poll_read_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<SocketAddr>> {
+        let this = self.get_mut();
+        this.poll_control_stream(cx)?;
+        loop {
+            let mut read_buf = ReadBuf::new(&mut this.read_buf);
+            ready!(this.socket.poll_recv(cx, &mut read_buf))?;
+            let packet = read_buf.filled();
+
+            if packet.len() < HEADER_PREFIX.len() {
+                debug!("Dropping truncated SOCKS UDP packet");
+                continue;
+            }
+            if packet[2] != 0 {
+                debug!("Dropping fragmented SOCKS UDP packet");
+                continue;
+            }
+            let (location, location_len) = match read_location_from_slice(&packet[3..]) {
+                Ok(result) => result,
+                Err(e) => {
+                    debug!("Dropping invalid SOCKS UDP packet: {}", e);
+                    continue;
+                }
+            };
+            let source = match location.to_socket_addr_nonblocking() {
+                Some(source) => source,
+                None => {
+                    debug!(
+                        "Dropping SOCKS UDP packet from unresolved source {}",
+                        location
+                    );
+                    continue;
+                }
+            };
+            let payload = &packet[3 + location_len..];
+            if payload.len() > buf.remaining() {
+                debug!("Dropping oversized SOCKS UDP packet from {}", source);
+                continue;
+            }
+            buf.put_slice(payload);
+            return Poll::Ready(Ok(source));
+        }
+    }
+}
+
+impl AsyncWriteTargetedMessage for SocksUdpClientStream {
+    fn poll_write_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &NetLocation,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_control_stream(cx)?;
+        let mut packet = HEADER_PREFIX.to_vec();
+        packet.extend(write_location_to_vec(target));
+        packet.extend_from_slice(buf);
+        this.socket
+            .poll_send(cx, &packet)
+            .map(|result| result.map(|_| ()))
+    }
+}
+
+impl AsyncReadMessage for SocksUdpClientStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.poll_read_sourced_message(cx, buf)
+            .map(|result| result.map(|_| ()))
+    }
+}
+
+impl AsyncWriteMessage for SocksUdpClientStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let target = match self.target {
+            Some(ref target) => target.clone(),
+            None => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "no target for SOCKS UDP message",
+                )));
+            }
+        };
+        self.poll_write_targeted_message(cx, buf, &target)
+    }
+}
+
+impl AsyncFlushMessage for SocksUdpClientStream {
+    fn poll_flush_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncShutdownMessage for SocksUdpClientStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for SocksUdpClientStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncSourcedMessageStream for SocksUdpClientStream {}
+
+impl AsyncMessageStream for SocksUdpClientStream {}
@@ -0,0 +1,55 @@
+// Decides which peers may connect to a server by their source address, before any handshake
+// work is done.
+
+use std::net::IpAddr;
+
+use crate::address::{Address, AddressMask, NetLocationMask};
+
+#[derive(Debug, Clone)]
+pub struct SourceFilter {
+    // Any source is allowed when this is empty.
+    allow_masks: Vec<AddressMask>,
+    deny_masks: Vec<AddressMask>,
+}
+
+impl SourceFilter {
+    pub fn new(allow_sources: Vec<NetLocationMask>, deny_sources: Vec<NetLocationMask>) -> Self {
+        Self {
+            allow_masks: allow_sources
+                .into_iter()
+                .map(|mask| mask.address_mask)
+                .collect(),
+            deny_masks: deny_sources
+                .into_iter()
+                .map(|mask| mask.address_mask)
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow_masks.is_empty() && self.deny_masks.is_empty()
+    }
+
+    // Denied sources are rejected even when they are also allowed.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let source_mask = AddressMask {
+            address: match ip {
+                IpAddr::V4(ip) => Address::Ipv4(ip),
+                IpAddr::V6(ip) => Address::Ipv6(ip),
+            },
+            netmask: u128::MAX,
+        };
+        if self
+            .deny_masks
+            .iter()
+            .any(|mask| mask.contains(&source_mask))
+        {
+            return false;
+        }
+        self.allow_masks.is_empty()
+            || self
+                .allow_masks
+                .iter()
+                .any(|mask| mask.contains(&source_mask))
+    }
+}
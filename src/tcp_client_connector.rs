@@ -1,40 +1,106 @@
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::Arc;
 
 use log::{debug, error};
+use tokio::io::AsyncWriteExt;
 
-use crate::address::NetLocation;
-use crate::async_stream::AsyncStream;
-use crate::config::{ClientConfig, ClientQuicConfig, TcpConfig, Transport};
+use crate::address::{Address, NetLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncSourcedMessageStream, AsyncStream};
+use crate::config::{
+    ClientConfig, ClientProxyConfig, ClientQuicConfig, IpPreference, NatType,
+    OversizedDatagramPolicy, ResolveMode, ShadowsocksConfig, TcpConfig, Transport, UdpConfig,
+};
+use crate::happy_eyeballs::{connect_to_any, interleave_families, CONNECTION_ATTEMPT_DELAY};
+use crate::mux::{mux_location, write_stream_request, MuxClientPool};
+use crate::quic_datagram::{
+    QuicDatagramMessageStream, QuicDatagramRouter, QuicDatagramSession, QuicDatagramSourcedStream,
+};
 use crate::quic_stream::QuicStream;
-use crate::resolver::{resolve_single_address, Resolver};
-use crate::rustls_util::create_client_config;
+use crate::resolver::{create_resolver, resolve_single_address, PreferenceResolver, Resolver};
+use crate::rustls_util::{create_client_config, load_ca_certs, ClientRoots};
+use crate::shadowsocks::{ShadowsocksUdpCipher, ShadowsocksUdpClientStream};
+use crate::sip003_plugin::{unused_local_address, PluginProcess};
 use crate::socket_util::{new_tcp_socket, new_udp_socket};
+use crate::socks_handler::SocksTcpClientHandler;
+use crate::socks_udp_stream::SocksUdpClientStream;
 use crate::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
-use crate::tcp_handler_util::create_tcp_client_handler;
+use crate::tcp_handler_util::{create_auth_credentials, create_tcp_client_handler};
+use crate::telemetry::child_span;
 use crate::thread_util::get_num_threads;
+use crate::udp_direct_message_stream::UdpDirectMessageStream;
+use crate::udp_over_tcp::{
+    is_udp_over_tcp_location, setup_udp_over_tcp_client_stream, udp_over_tcp_location,
+    NoServerStream, UdpOverTcpStream,
+};
 
-const ALWAYS_RESOLVE_HOSTNAMES: bool = false;
 const MAX_QUIC_ENDPOINTS: usize = 32;
 
 #[derive(Debug)]
 enum TransportConfig {
     Tcp {
         no_delay: bool,
+        // Whether connections to the addresses of a location are raced.
+        happy_eyeballs: bool,
     },
     Quic {
         sni_hostname: Option<String>,
         endpoints: Vec<Arc<quinn::Endpoint>>,
         next_endpoint_index: AtomicU8,
+        // None when relayed datagrams aren't sent as QUIC datagrams.
+        datagram_policy: Option<OversizedDatagramPolicy>,
     },
 }
 
+// How datagrams are relayed to their destinations.
+#[derive(Debug)]
+enum UdpRelay {
+    // Datagrams are sent from this host.
+    Direct,
+    Shadowsocks(Arc<ShadowsocksUdpCipher>),
+    // Datagrams are sent over a new connection to the proxy, with UDP-over-TCP v2.
+    OverTcp,
+    // Datagrams are sent to a relay that the SOCKS proxy opens for a new control connection.
+    Socks(SocksTcpClientHandler),
+    // The proxy can't relay datagrams, for the given reason.
+    Unsupported(String),
+}
+
+// The local and peer addresses of the connection that a client stream was set up on, after any
+// bind settings were applied.
+#[derive(Debug, Clone, Copy)]
+pub struct EgressAddresses {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+}
+
+impl std::fmt::Display for EgressAddresses {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.local, self.peer)
+    }
+}
+
 #[derive(Debug)]
 pub struct TcpClientConnector {
+    // Describes the proxy in logs, eg. "SOCKS proxy at 10.0.0.1:1080".
+    label: String,
     bind_interface: Option<String>,
+    // Where UDP sockets for relayed datagrams are bound.
+    bind_port: Option<u16>,
+    reuse_port: bool,
+    dscp: Option<u8>,
     location: NetLocation,
+    // Resolves the location instead of the server's resolver, when configured.
+    location_resolver: Option<Arc<dyn Resolver>>,
     transport_config: TransportConfig,
     client_handler: Option<Box<dyn TcpClientHandler>>,
+    mux_pool: Option<MuxClientPool>,
+    ip_preference: IpPreference,
+    resolve: ResolveMode,
+    udp_relay: UdpRelay,
+    // Connections to the proxy go through the plugin, which is stopped when the connector is
+    // dropped.
+    plugin: Option<PluginProcess>,
 }
 
 impl TcpClientConnector {
@@ -45,12 +111,76 @@ impl TcpClientConnector {
             .hostname()
             .map(ToString::to_string);
 
+        let label = if client_config.protocol.is_direct() {
+            "direct".to_string()
+        } else {
+            format!(
+                "{} proxy at {}",
+                client_config.protocol, client_config.address
+            )
+        };
+
+        let udp_relay = match client_config.protocol {
+            ref protocol if protocol.udp_over_tcp() => UdpRelay::OverTcp,
+            ClientProxyConfig::Shadowsocks(ref shadowsocks_config) => {
+                if client_config.transport != Transport::Tcp {
+                    UdpRelay::Unsupported(
+                        "shadowsocks UDP relay is only supported with the TCP transport"
+                            .to_string(),
+                    )
+                } else {
+                    match ShadowsocksUdpCipher::from_config(shadowsocks_config) {
+                        Ok(cipher) => UdpRelay::Shadowsocks(Arc::new(cipher)),
+                        Err(e) => UdpRelay::Unsupported(e.to_string()),
+                    }
+                }
+            }
+            ClientProxyConfig::Socks {
+                ref username,
+                ref password,
+                udp_associate: true,
+            } => UdpRelay::Socks(SocksTcpClientHandler::new(create_auth_credentials(
+                username.clone(),
+                password.clone(),
+            ))),
+            _ => UdpRelay::Direct,
+        };
+
+        let plugin = match client_config.protocol {
+            ClientProxyConfig::Shadowsocks(ShadowsocksConfig {
+                plugin: Some(ref plugin),
+                ref plugin_opts,
+                ..
+            }) => {
+                let plugin_process = unused_local_address().and_then(|local_address| {
+                    PluginProcess::start(
+                        plugin,
+                        plugin_opts.as_deref(),
+                        local_address,
+                        &client_config.address,
+                    )
+                });
+                match plugin_process {
+                    Ok(p) => Some(p),
+                    Err(e) => {
+                        error!("Failed to start shadowsocks plugin: {}", e);
+                        return None;
+                    }
+                }
+            }
+            _ => None,
+        };
+
         let transport_config = match client_config.transport {
             Transport::Quic => {
                 let ClientQuicConfig {
                     verify,
+                    ca_cert,
+                    system_roots,
                     alpn_protocols,
                     sni_hostname,
+                    udp_datagrams,
+                    oversized_datagrams,
                 } = client_config
                     .quic_settings
                     .unwrap_or_else(ClientQuicConfig::default);
@@ -67,9 +197,19 @@ impl TcpClientConnector {
                     sni_hostname.into_option()
                 };
 
+                // The CA certificates were checked when the config was validated.
+                let roots = ClientRoots {
+                    ca_certs: ca_cert
+                        .map(|ca_cert| load_ca_certs(&ca_cert).unwrap())
+                        .unwrap_or_default(),
+                    system_roots,
+                };
+
                 let mut quic_client_config =
                     quinn::ClientConfig::new(Arc::new(create_client_config(
                         verify,
+                        &roots,
+                        &[],
                         &alpn_protocols.into_vec(),
                         sni_hostname.is_some(),
                     )));
@@ -86,6 +226,9 @@ impl TcpClientConnector {
                         std::time::Duration::from_secs(15).try_into().unwrap(),
                     ))
                     .max_idle_timeout(Some(std::time::Duration::from_secs(30).try_into().unwrap()));
+                if !udp_datagrams {
+                    transport_config.datagram_receive_buffer_size(None);
+                }
 
                 quic_client_config.transport_config(Arc::new(transport_config));
 
@@ -100,6 +243,9 @@ impl TcpClientConnector {
                             .bind_interface
                             .as_option()
                             .map(ToString::to_string),
+                        None,
+                        false,
+                        client_config.dscp,
                     ) {
                         Ok(s) => s,
                         Err(e) => {
@@ -124,13 +270,21 @@ impl TcpClientConnector {
                     sni_hostname,
                     endpoints,
                     next_endpoint_index: AtomicU8::new(0),
+                    datagram_policy: udp_datagrams.then_some(oversized_datagrams),
                 }
             }
             Transport::Tcp => {
-                let TcpConfig { no_delay } = client_config
+                let TcpConfig {
+                    no_delay,
+                    happy_eyeballs,
+                    ..
+                } = client_config
                     .tcp_settings
                     .unwrap_or_else(TcpConfig::default);
-                TransportConfig::Tcp { no_delay }
+                TransportConfig::Tcp {
+                    no_delay,
+                    happy_eyeballs,
+                }
             }
             _ => {
                 panic!("TODO: this is an error, a non-tcp/quic client config was specified for a tcp server");
@@ -138,8 +292,16 @@ impl TcpClientConnector {
         };
 
         Some(Self {
+            label,
             bind_interface: client_config.bind_interface.clone().into_option(),
+            bind_port: client_config.bind_port,
+            reuse_port: client_config.reuse_port,
+            dscp: client_config.dscp,
             location: client_config.address,
+            location_resolver: client_config
+                .address_resolver_settings
+                .as_ref()
+                .map(|resolver_config| create_resolver(Some(resolver_config))),
             transport_config,
             client_handler: if client_config.protocol.is_direct() {
                 None
@@ -149,105 +311,506 @@ impl TcpClientConnector {
                     default_sni_hostname,
                 ))
             },
+            mux_pool: client_config.mux_settings.map(MuxClientPool::new),
+            ip_preference: client_config.ip_preference,
+            resolve: client_config.resolve,
+            udp_relay,
+            plugin,
         })
     }
 
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
     pub fn configure_udp_socket(&self) -> std::io::Result<tokio::net::UdpSocket> {
-        let udp_socket = new_udp_socket(self.bind_interface.clone())?;
+        let udp_socket = new_udp_socket(
+            self.bind_interface.clone(),
+            self.bind_port,
+            self.reuse_port,
+            self.dscp,
+        )?;
         Ok(udp_socket)
     }
 
+    // Resolves the location to an address allowed by the IP preference.
+    pub async fn resolve_address(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        location: &NetLocation,
+    ) -> std::io::Result<SocketAddr> {
+        let resolver = PreferenceResolver::wrap(resolver, self.ip_preference);
+        child_span(
+            "resolve",
+            location,
+            resolve_single_address(&resolver, location),
+        )
+        .await
+    }
+
+    // Resolves the location to every address allowed by the IP preference, in the order that
+    // they're tried.
+    async fn resolve_addresses(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        location: &NetLocation,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        let resolver = PreferenceResolver::wrap(resolver, self.ip_preference);
+        let socket_addrs =
+            child_span("resolve", location, resolver.resolve_location(location)).await?;
+        if socket_addrs.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "could not resolve location: {}",
+                location
+            )));
+        }
+        Ok(socket_addrs)
+    }
+
+    // Resolves the proxy's own location to every allowed address.
+    async fn resolve_proxy_addresses(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        let resolver = self.location_resolver.as_ref().unwrap_or(resolver);
+        self.resolve_addresses(resolver, &self.location).await
+    }
+
+    // Connects to the first of the addresses that accepts a TCP connection.
+    async fn connect_tcp(
+        &self,
+        description: &str,
+        target_addrs: Vec<SocketAddr>,
+        happy_eyeballs: bool,
+    ) -> std::io::Result<tokio::net::TcpStream> {
+        let (target_addrs, attempt_delay) = if happy_eyeballs {
+            (
+                interleave_families(target_addrs),
+                Some(CONNECTION_ATTEMPT_DELAY),
+            )
+        } else {
+            (target_addrs, None)
+        };
+        let connect_future = connect_to_any(
+            description,
+            &target_addrs,
+            attempt_delay,
+            |target_addr| async move {
+                let tcp_socket = new_tcp_socket(
+                    self.bind_interface.clone(),
+                    target_addr.is_ipv6(),
+                    self.dscp,
+                )?;
+                tcp_socket.connect(target_addr).await
+            },
+        );
+        child_span("connect", &description, connect_future).await
+    }
+
+    // Resolves the proxy's own location.
+    async fn resolve_proxy_address(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<SocketAddr> {
+        let resolver = self.location_resolver.as_ref().unwrap_or(resolver);
+        self.resolve_address(resolver, &self.location).await
+    }
+
+    // The location to send to the proxy, which is resolved here when configured.
+    async fn proxied_location(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        remote_location: NetLocation,
+    ) -> std::io::Result<NetLocation> {
+        if self.resolve == ResolveMode::Remote
+            || !remote_location.address().is_hostname()
+            || is_udp_over_tcp_location(&remote_location)
+        {
+            return Ok(remote_location);
+        }
+        let socket_addr = self.resolve_address(resolver, &remote_location).await?;
+        Ok(NetLocation::from_ip_addr(
+            socket_addr.ip(),
+            socket_addr.port(),
+        ))
+    }
+
+    // Creates a socket connected to the shadowsocks server.
+    async fn connect_shadowsocks_udp(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<tokio::net::UdpSocket> {
+        let server_addr = self.resolve_proxy_address(resolver).await?;
+        let socket = self.configure_udp_socket()?;
+        socket.connect(server_addr).await?;
+        Ok(socket)
+    }
+
+    // Opens a control connection to the SOCKS proxy and asks it for a UDP relay, and returns a
+    // socket connected to the relay along with the connection, which has to stay open while the
+    // relay is used.
+    async fn connect_socks_udp(
+        &self,
+        socks_handler: &SocksTcpClientHandler,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<(tokio::net::UdpSocket, Box<dyn AsyncStream>)> {
+        let proxy_addrs = self.resolve_proxy_addresses(resolver).await?;
+        let happy_eyeballs = matches!(
+            self.transport_config,
+            TransportConfig::Tcp {
+                happy_eyeballs: true,
+                ..
+            }
+        );
+        let tcp_stream = self
+            .connect_tcp(&self.label, proxy_addrs, happy_eyeballs)
+            .await?;
+        let proxy_addr = tcp_stream.peer_addr()?;
+        let mut control_stream: Box<dyn AsyncStream> = Box::new(tcp_stream);
+        let relay_location = socks_handler
+            .setup_udp_associate(&mut control_stream)
+            .await?;
+
+        // An unspecified address means the relay is at the proxy's own address.
+        let relay_addr = match relay_location.address() {
+            Address::Ipv4(ip) if ip.is_unspecified() => {
+                SocketAddr::new(proxy_addr.ip(), relay_location.port())
+            }
+            Address::Ipv6(ip) if ip.is_unspecified() => {
+                SocketAddr::new(proxy_addr.ip(), relay_location.port())
+            }
+            _ => self.resolve_address(resolver, &relay_location).await?,
+        };
+        debug!("SOCKS UDP relay for {} is at {}", self.label, relay_addr);
+
+        let socket = self.configure_udp_socket()?;
+        socket.connect(relay_addr).await?;
+        Ok((socket, control_stream))
+    }
+
+    // Opens a connection to the proxy that datagrams are relayed over. Connections aren't
+    // multiplexed, since each carries a single UDP session. Over QUIC, the session also gets
+    // the datagrams that are sent as QUIC datagrams.
+    async fn connect_udp_over_tcp(
+        &self,
+        destination: Option<&NetLocation>,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<(UdpOverTcpStream, Option<QuicDatagramSession>)> {
+        let mut no_server_stream: Box<dyn AsyncStream> = Box::new(NoServerStream);
+        let (client_stream, quic_stream, _) = self
+            .connect_stream_on_transport(&mut no_server_stream, udp_over_tcp_location(), resolver)
+            .await?;
+        let datagram_session = match (&self.transport_config, quic_stream) {
+            (
+                TransportConfig::Quic {
+                    datagram_policy: Some(policy),
+                    ..
+                },
+                Some((connection, stream_id)),
+            ) => Some(QuicDatagramRouter::start(connection, *policy).register(stream_id, false)),
+            _ => None,
+        };
+        let stream = setup_udp_over_tcp_client_stream(client_stream, destination).await?;
+        Ok((stream, datagram_session))
+    }
+
+    // Creates a stream for relaying datagrams from a client to a single destination.
+    pub async fn connect_udp(
+        &self,
+        remote_location: &NetLocation,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        match self.udp_relay {
+            UdpRelay::Direct => {
+                let remote_addr = self.resolve_address(resolver, remote_location).await?;
+                let socket = self.configure_udp_socket()?;
+                socket.connect(remote_addr).await?;
+                Ok(Box::new(socket))
+            }
+            UdpRelay::Shadowsocks(ref cipher) => {
+                let socket = self.connect_shadowsocks_udp(resolver).await?;
+                Ok(Box::new(ShadowsocksUdpClientStream::new(
+                    socket,
+                    cipher.clone(),
+                    Some(remote_location.clone()),
+                )))
+            }
+            UdpRelay::Socks(ref socks_handler) => {
+                let (socket, control_stream) =
+                    self.connect_socks_udp(socks_handler, resolver).await?;
+                Ok(Box::new(SocksUdpClientStream::new(
+                    socket,
+                    control_stream,
+                    Some(remote_location.clone()),
+                )))
+            }
+            UdpRelay::OverTcp => {
+                let (stream, datagram_session) = self
+                    .connect_udp_over_tcp(Some(remote_location), resolver)
+                    .await?;
+                match datagram_session {
+                    Some(session) => Ok(Box::new(QuicDatagramMessageStream::new(
+                        Box::new(stream),
+                        session,
+                        remote_location.clone(),
+                    ))),
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            UdpRelay::Unsupported(ref reason) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                reason.clone(),
+            )),
+        }
+    }
+
+    // Creates a stream for relaying datagrams from a client to any destination.
+    pub async fn create_udp_stream(
+        &self,
+        udp_config: &UdpConfig,
+        resolver: Arc<dyn Resolver>,
+    ) -> std::io::Result<Box<dyn AsyncSourcedMessageStream>> {
+        match self.udp_relay {
+            UdpRelay::Direct => {
+                let resolver = PreferenceResolver::wrap(&resolver, self.ip_preference);
+                match udp_config.nat {
+                    NatType::FullCone => Ok(Box::new(UdpDirectMessageStream::new(
+                        self.configure_udp_socket()?,
+                        udp_config.batch_size,
+                        resolver,
+                    ))),
+                    NatType::Symmetric => Ok(Box::new(UdpDirectMessageStream::new_symmetric(
+                        self.bind_interface.clone(),
+                        self.dscp,
+                        udp_config.idle_timeout(),
+                        resolver,
+                    ))),
+                }
+            }
+            UdpRelay::Shadowsocks(ref cipher) => {
+                // The shadowsocks server decides how datagrams are mapped.
+                let socket = self.connect_shadowsocks_udp(&resolver).await?;
+                Ok(Box::new(ShadowsocksUdpClientStream::new(
+                    socket,
+                    cipher.clone(),
+                    None,
+                )))
+            }
+            UdpRelay::Socks(ref socks_handler) => {
+                let (socket, control_stream) =
+                    self.connect_socks_udp(socks_handler, &resolver).await?;
+                Ok(Box::new(SocksUdpClientStream::new(
+                    socket,
+                    control_stream,
+                    None,
+                )))
+            }
+            UdpRelay::OverTcp => {
+                let (stream, datagram_session) = self.connect_udp_over_tcp(None, &resolver).await?;
+                match datagram_session {
+                    Some(session) => Ok(Box::new(QuicDatagramSourcedStream::new(
+                        Box::new(stream),
+                        session,
+                    ))),
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            UdpRelay::Unsupported(ref reason) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                reason.clone(),
+            )),
+        }
+    }
+
+    // Also returns the addresses of the connection that was used, which are None for mux
+    // streams, since they share a connection, and when connecting through a plugin.
     pub async fn connect(
         &self,
         server_stream: &mut Box<dyn AsyncStream>,
-        mut remote_location: NetLocation,
+        remote_location: NetLocation,
         resolver: &Arc<dyn Resolver>,
-    ) -> std::io::Result<Box<dyn AsyncStream>> {
-        let target_addr = if self.client_handler.is_some() {
+    ) -> std::io::Result<(Box<dyn AsyncStream>, Option<EgressAddresses>)> {
+        let mux_pool = match self.mux_pool {
+            Some(ref p) => p,
+            None => {
+                let (client_stream, _, egress) = self
+                    .connect_stream_on_transport(server_stream, remote_location, resolver)
+                    .await?;
+                return Ok((client_stream, egress));
+            }
+        };
+
+        let remote_location = self.proxied_location(resolver, remote_location).await?;
+
+        // A session can fill up, or be closed when it was idle, between get_session and
+        // open_stream, in which case another session is tried, or a new one is made.
+        let mut mux_stream = loop {
+            if let Some(session) = mux_pool.get_session() {
+                match session.open_stream().await {
+                    Some(stream) => break stream,
+                    None => continue,
+                }
+            }
+
+            let (stream, _, _) = self
+                .connect_stream_on_transport(server_stream, mux_location(), resolver)
+                .await?;
+            let session = mux_pool.add_session(stream).await?;
+            match session.open_stream().await {
+                Some(stream) => break stream,
+                // Another caller took every slot of the new session first.
+                None if !session.is_closed() => continue,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "mux session closed before a stream could be opened",
+                    ));
+                }
+            }
+        };
+        mux_stream
+            .write_all(&write_stream_request(&remote_location))
+            .await?;
+
+        Ok((Box::new(mux_stream), None))
+    }
+
+    // Also returns the QUIC connection and stream that the stream was opened on, when using the
+    // QUIC transport, and the addresses of the connection, unless it goes through a plugin.
+    async fn connect_stream_on_transport(
+        &self,
+        server_stream: &mut Box<dyn AsyncStream>,
+        remote_location: NetLocation,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<(
+        Box<dyn AsyncStream>,
+        Option<(quinn::Connection, quinn::StreamId)>,
+        Option<EgressAddresses>,
+    )> {
+        // Every address of the location is tried before the connection fails.
+        let (target_description, target_addrs) = if let Some(ref plugin) = self.plugin {
+            // the plugin forwards to the proxy location
+            ("plugin".to_string(), vec![plugin.local_address()])
+        } else if self.client_handler.is_some() {
             // we have a client proxy, connect to the proxy location
-            resolve_single_address(resolver, &self.location).await?
+            (
+                self.label.clone(),
+                self.resolve_proxy_addresses(resolver).await?,
+            )
         } else {
             // we are directly connecting
-            resolve_single_address(resolver, &remote_location).await?
+            (
+                remote_location.to_string(),
+                self.resolve_addresses(resolver, &remote_location).await?,
+            )
         };
 
-        let client_stream: Box<dyn AsyncStream> = match self.transport_config {
-            TransportConfig::Tcp { no_delay } => {
-                let tcp_socket =
-                    new_tcp_socket(self.bind_interface.clone(), target_addr.is_ipv6())?;
-                let client_stream = tcp_socket.connect(target_addr).await?;
-                if no_delay {
-                    if let Err(e) = client_stream.set_nodelay(true) {
-                        error!("Failed to set TCP no-delay on client socket: {}", e);
+        let (client_stream, quic_stream, egress): (Box<dyn AsyncStream>, _, _) =
+            match self.transport_config {
+                TransportConfig::Tcp {
+                    no_delay,
+                    happy_eyeballs,
+                } => {
+                    let client_stream = self
+                        .connect_tcp(&target_description, target_addrs, happy_eyeballs)
+                        .await?;
+                    if no_delay {
+                        if let Err(e) = client_stream.set_nodelay(true) {
+                            error!("Failed to set TCP no-delay on client socket: {}", e);
+                        }
                     }
+                    // Connections to a plugin only reach the local plugin process.
+                    let egress = match (client_stream.local_addr(), client_stream.peer_addr()) {
+                        (Ok(local), Ok(peer)) if self.plugin.is_none() => {
+                            Some(EgressAddresses { local, peer })
+                        }
+                        _ => None,
+                    };
+                    (Box::new(client_stream), None, egress)
                 }
-                Box::new(client_stream)
-            }
-            TransportConfig::Quic {
-                ref endpoints,
-                ref next_endpoint_index,
-                ref sni_hostname,
-            } => {
-                let domain = match sni_hostname {
-                    Some(s) => s,
-                    // this is unused since enable_sni is false in create_client_config when we
-                    // don't have a hostname.
-                    None => self.location.address().hostname().unwrap_or("example.com"),
-                };
+                TransportConfig::Quic {
+                    ref endpoints,
+                    ref next_endpoint_index,
+                    ref sni_hostname,
+                    ..
+                } => {
+                    let domain = match sni_hostname {
+                        Some(s) => s,
+                        // this is unused since enable_sni is false in create_client_config when we
+                        // don't have a hostname.
+                        None => self.location.address().hostname().unwrap_or("example.com"),
+                    };
 
-                let endpoint = if endpoints.len() == 1 {
-                    &endpoints[0]
-                } else {
-                    let endpoint_index =
-                        next_endpoint_index.fetch_add(1, Ordering::Relaxed) as usize;
-                    &endpoints[endpoint_index % endpoints.len()]
-                };
+                    let endpoint = if endpoints.len() == 1 {
+                        &endpoints[0]
+                    } else {
+                        let endpoint_index =
+                            next_endpoint_index.fetch_add(1, Ordering::Relaxed) as usize;
+                        &endpoints[endpoint_index % endpoints.len()]
+                    };
+
+                    // The addresses are tried one at a time, since a QUIC handshake to an
+                    // address that can't be reached only fails after its idle timeout.
+                    let connect_future = connect_to_any(
+                        &target_description,
+                        &target_addrs,
+                        None,
+                        |target_addr| async move {
+                            endpoint
+                                .connect(target_addr, domain)
+                                .map_err(|e| {
+                                    std::io::Error::other(format!(
+                                        "Failed to connect to quic endpoint: {}",
+                                        e
+                                    ))
+                                })?
+                                .await
+                                .map_err(|e| {
+                                    std::io::Error::other(format!(
+                                        "Failed to connect to quic endpoint: {}",
+                                        e
+                                    ))
+                                })
+                        },
+                    );
+                    let conn = child_span("connect", &target_description, connect_future).await?;
 
-                let conn = endpoint
-                    .connect(target_addr, domain)
-                    .map_err(|e| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("Failed to connect to quic endpoint: {}", e),
-                        )
-                    })?
-                    .await
-                    .map_err(|e| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("Failed to connect to quic endpoint: {}", e),
-                        )
+                    let (send, recv) = conn.open_bi().await.map_err(|e| {
+                        let message = format!("Failed to open stream to quic endpoint: {}", e);
+                        std::io::Error::other(message)
                     })?;
 
-                let (send, recv) = conn.open_bi().await.map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to open stream to quic endpoint: {}", e),
-                    )
-                })?;
+                    // The endpoint's socket may be bound to an unspecified address, so the connection's
+                    // local IP is used when it's known.
+                    let egress = endpoint.local_addr().ok().map(|mut local| {
+                        if let Some(local_ip) = conn.local_ip() {
+                            local.set_ip(local_ip);
+                        }
+                        EgressAddresses {
+                            local,
+                            peer: conn.remote_address(),
+                        }
+                    });
 
-                Box::new(QuicStream::from(send, recv))
-            }
-        };
+                    let stream_id = send.id();
+                    (
+                        Box::new(QuicStream::from(send, recv)),
+                        Some((conn, stream_id)),
+                        egress,
+                    )
+                }
+            };
 
         match self.client_handler {
             Some(ref client_handler) => {
-                // TODO: make this configurable
-                if ALWAYS_RESOLVE_HOSTNAMES {
-                    if remote_location.address().is_hostname() {
-                        let socket_addr =
-                            resolve_single_address(resolver, &remote_location).await?;
-                        remote_location =
-                            NetLocation::from_ip_addr(socket_addr.ip(), socket_addr.port());
-                    }
-                }
+                let remote_location = self.proxied_location(resolver, remote_location).await?;
                 let TcpClientSetupResult { client_stream } = client_handler
                     .setup_client_stream(server_stream, client_stream, remote_location)
                     .await?;
 
-                Ok(client_stream)
+                Ok((client_stream, quic_stream, egress))
             }
-            None => Ok(client_stream),
+            None => Ok((client_stream, quic_stream, egress)),
         }
     }
 }
@@ -2,10 +2,13 @@ use std::fmt::Debug;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use futures::stream::BoxStream;
 
 use crate::address::NetLocation;
 use crate::async_stream::{AsyncMessageStream, AsyncStream, AsyncTargetedMessageStream};
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::http_forward::HttpForwardRequest;
+use crate::line_reader::LineReader;
 use crate::option_util::NoneOrOne;
 use crate::tcp_client_connector::TcpClientConnector;
 
@@ -20,6 +23,25 @@ pub enum TcpServerSetupResult {
         // initial data to send to the remote location.
         initial_remote_data: Option<Box<[u8]>>,
         override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+        // the user that the client authenticated as, for protocols that have multiple users.
+        authenticated_user: Option<String>,
+    },
+    // A plain HTTP proxy request, where each request on the connection is forwarded to its origin
+    // and the connection is kept alive for the next one.
+    HttpForward {
+        stream: Box<dyn AsyncStream>,
+        // the reader holding any data read after the first request's headers.
+        line_reader: LineReader,
+        request: HttpForwardRequest,
+        override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+        authenticated_user: Option<String>,
+    },
+    // A connection that carries several streams, like a gRPC connection. Each stream is set up
+    // with the handler and forwarded as its own connection.
+    MultiplexedForward {
+        streams: BoxStream<'static, Box<dyn AsyncStream>>,
+        handler: Arc<Box<dyn TcpServerHandler>>,
+        override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
     },
     // TODO: support udp client proxy selector
     BidirectionalUdpForward {
@@ -30,6 +52,63 @@ pub enum TcpServerSetupResult {
         need_initial_flush: bool,
         stream: Box<dyn AsyncTargetedMessageStream>,
     },
+    // The client failed authentication, and is forwarded to a decoy location so that it sees a
+    // normal service. Wrapping handlers may replace the location.
+    Fallback {
+        remote_location: NetLocation,
+        stream: Box<dyn AsyncStream>,
+        need_initial_flush: bool,
+        // the bytes that were read from the client, which are replayed to the remote location.
+        initial_remote_data: Box<[u8]>,
+    },
+}
+
+impl TcpServerSetupResult {
+    // Turns a fallback into a forward to the fallback location.
+    pub fn resolve_fallback(self) -> Self {
+        match self {
+            TcpServerSetupResult::Fallback {
+                remote_location,
+                stream,
+                need_initial_flush,
+                initial_remote_data,
+            } => TcpServerSetupResult::TcpForward {
+                remote_location,
+                stream,
+                need_initial_flush,
+                connection_success_response: None,
+                initial_remote_data: Some(initial_remote_data),
+                override_proxy_provider: NoneOrOne::Unspecified,
+                authenticated_user: None,
+            },
+            result => result,
+        }
+    }
+}
+
+// Marks an error as a failed authentication, which servers count towards banning the source.
+#[derive(Debug)]
+struct AuthFailure(String);
+
+impl std::fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for AuthFailure {}
+
+pub fn auth_failure_error(message: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        AuthFailure(message.to_string()),
+    )
+}
+
+pub fn is_auth_failure(error: &std::io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<AuthFailure>())
 }
 
 #[async_trait]
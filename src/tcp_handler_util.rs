@@ -1,34 +1,48 @@
 use std::collections::HashMap;
 use std::io::Read;
 use std::sync::Arc;
+use std::time::Duration;
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use log::debug;
+use rustls::server::{NoClientAuth, ResolvesServerCert};
 
-use crate::client_proxy_selector::{ClientProxySelector, ConnectAction, ConnectRule};
+use crate::acme::{acme_cert_resolver, ACME_TLS_ALPN_PROTOCOL};
+use crate::client_proxy_selector::{
+    ClientProxySelector, ConnectAction, ConnectRule, ProxyGroupSelector,
+};
 use crate::config::{
-    ClientProxyConfig, ConfigSelection, RuleActionConfig, RuleConfig, ServerProxyConfig,
-    ShadowsocksConfig, TlsClientConfig, TlsServerConfig, WebsocketClientConfig,
+    ClientConfig, ClientProxyConfig, ConfigSelection, GrpcClientConfig, GrpcServerConfig,
+    ProxyUserConfig, RuleActionConfig, RuleConfig, ServerProxyConfig, ShadowsocksConfig,
+    TlsClientConfig, TlsServerConfig, TlsVersion, UnknownAlpnConfig, WebsocketClientConfig,
     WebsocketServerConfig,
 };
-use crate::http_handler::{HttpTcpClientHandler, HttpTcpServerHandler};
-use crate::option_util::NoneOrOne;
+use crate::grpc::{GrpcTcpClientHandler, GrpcTcpServerHandler};
+use crate::http_handler::{HttpTcpClientHandler, HttpTcpServerHandler, HttpUser};
+use crate::ocsp::{create_ocsp_stapler, StaplingSource, StaticCertResolver};
+use crate::option_util::{NoneOrOne, NoneOrSome};
 use crate::port_forward_handler::PortForwardServerHandler;
-use crate::rustls_util::{create_client_config, create_server_config};
+use crate::rule_schedule::RuleSchedule;
+use crate::rustls_util::{
+    create_certified_key, create_client_config, create_client_verifier, create_server_config,
+    create_server_config_with_resolver, create_tls_policy, load_ca_certs, parse_spki_hash,
+    ClientRoots,
+};
 use crate::shadowsocks::ShadowsocksTcpHandler;
 use crate::snell_handler::SnellTcpHandler;
-use crate::socks_handler::{SocksTcpClientHandler, SocksTcpServerHandler};
+use crate::socks_handler::{SocksTcpClientHandler, SocksTcpServerHandler, SocksUser};
 use crate::tcp_client_connector::TcpClientConnector;
 use crate::tcp_handler::{TcpClientHandler, TcpServerHandler};
-use crate::tls_handler::{TlsClientHandler, TlsServerHandler, TlsServerTarget};
+use crate::tls_handler::{TlsClientHandler, TlsServerHandler, TlsServerTarget, UnknownAlpnAction};
 use crate::trojan_handler::TrojanTcpHandler;
+use crate::user_quota::user_quotas;
 use crate::vless_handler::VlessTcpHandler;
 use crate::vmess::{VmessTcpClientHandler, VmessTcpServerHandler};
 use crate::websocket::{
     WebsocketServerTarget, WebsocketTcpClientHandler, WebsocketTcpServerHandler,
 };
 
-fn create_auth_credentials(
+pub(crate) fn create_auth_credentials(
     username: Option<String>,
     password: Option<String>,
 ) -> Option<(String, String)> {
@@ -46,36 +60,64 @@ pub fn create_tcp_server_handler(
     rules_stack: &mut Vec<Vec<RuleConfig>>,
 ) -> Box<dyn TcpServerHandler> {
     match server_proxy_config {
-        ServerProxyConfig::Http { username, password } => Box::new(HttpTcpServerHandler::new(
-            create_auth_credentials(username, password),
-        )),
-        ServerProxyConfig::Socks { username, password } => Box::new(SocksTcpServerHandler::new(
-            create_auth_credentials(username, password),
-        )),
-        ServerProxyConfig::Shadowsocks(ShadowsocksConfig { cipher, password }) => {
-            if cipher.starts_with("2022-blake3-") {
+        ServerProxyConfig::Http {
+            username,
+            password,
+            users,
+        } => {
+            let users = create_proxy_users(username, password, *users, HttpUser::new);
+            Box::new(HttpTcpServerHandler::new(users))
+        }
+        ServerProxyConfig::Socks {
+            username,
+            password,
+            users,
+        } => {
+            let users = create_proxy_users(username, password, *users, SocksUser::new);
+            Box::new(SocksTcpServerHandler::new(users))
+        }
+        ServerProxyConfig::Shadowsocks(ShadowsocksConfig {
+            cipher,
+            password,
+            udp_over_tcp,
+            ..
+        }) => {
+            let handler = if let Some(cipher_name) = cipher.strip_prefix("2022-blake3-") {
                 let key_bytes = BASE64
                     .decode(password)
                     .expect("could not base64 decode password");
-                Box::new(ShadowsocksTcpHandler::new_aead2022(
-                    &cipher[12..],
-                    &key_bytes,
-                ))
+                ShadowsocksTcpHandler::new_aead2022(cipher_name, &key_bytes)
             } else {
-                Box::new(ShadowsocksTcpHandler::new(&cipher, &password))
-            }
-        }
-        ServerProxyConfig::Snell(ShadowsocksConfig { cipher, password }) => {
-            Box::new(SnellTcpHandler::new(&cipher, &password))
+                ShadowsocksTcpHandler::new(&cipher, &password)
+            };
+            Box::new(handler.with_udp_over_tcp(udp_over_tcp))
         }
-        ServerProxyConfig::Vless { user_id } => Box::new(VlessTcpHandler::new(&user_id)),
+        ServerProxyConfig::Snell(ShadowsocksConfig {
+            cipher, password, ..
+        }) => Box::new(SnellTcpHandler::new(&cipher, &password)),
+        ServerProxyConfig::Vless {
+            user_id,
+            fallback,
+            flow,
+        } => Box::new(
+            VlessTcpHandler::new(&user_id)
+                .with_fallback(fallback)
+                .with_flow(flow),
+        ),
         ServerProxyConfig::Trojan {
             password,
             shadowsocks,
-        } => Box::new(TrojanTcpHandler::new(&password, &shadowsocks)),
+            fallback,
+            udp_over_tcp,
+        } => Box::new(
+            TrojanTcpHandler::new(&password, &shadowsocks)
+                .with_fallback(fallback)
+                .with_udp_over_tcp(udp_over_tcp),
+        ),
         ServerProxyConfig::Tls {
             sni_targets,
             default_target,
+            unknown_sni,
         } => {
             let sni_targets = sni_targets
                 .into_iter()
@@ -83,17 +125,23 @@ pub fn create_tcp_server_handler(
                 .collect::<HashMap<String, TlsServerTarget>>();
             let default_target =
                 default_target.map(|config| create_tls_server_target(*config, rules_stack));
-            Box::new(TlsServerHandler::new(sni_targets, default_target))
+            Box::new(TlsServerHandler::new(
+                sni_targets,
+                default_target,
+                unknown_sni,
+            ))
         }
         ServerProxyConfig::Vmess {
             cipher,
             user_id,
             force_aead,
+            alter_id,
             udp_enabled,
         } => Box::new(VmessTcpServerHandler::new(
             &cipher,
             &user_id,
             force_aead,
+            alter_id,
             udp_enabled,
         )),
         ServerProxyConfig::Websocket { targets } => {
@@ -104,6 +152,9 @@ pub fn create_tcp_server_handler(
                 .collect::<Vec<_>>();
             Box::new(WebsocketTcpServerHandler::new(server_targets))
         }
+        ServerProxyConfig::Grpc(grpc_server_config) => {
+            create_grpc_server_handler(*grpc_server_config, rules_stack)
+        }
         ServerProxyConfig::PortForward { targets } => {
             let targets = targets.into_vec();
             Box::new(PortForwardServerHandler::new(targets))
@@ -111,6 +162,51 @@ pub fn create_tcp_server_handler(
     }
 }
 
+// Creates each user with the selector to use in place of the server's rules.
+fn create_proxy_users<U>(
+    username: Option<String>,
+    password: Option<String>,
+    users: NoneOrSome<ProxyUserConfig>,
+    new_user: impl Fn(String, String, NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>) -> U,
+) -> Vec<U> {
+    let mut proxy_users = vec![];
+    if let Some((username, password)) = create_auth_credentials(username, password) {
+        proxy_users.push(new_user(username, password, NoneOrOne::Unspecified));
+    }
+    for user in users.into_iter() {
+        let ProxyUserConfig {
+            username,
+            password,
+            quota_bytes,
+            override_rules,
+        } = user;
+        user_quotas().set_quota(&username, quota_bytes);
+        let override_proxy_provider = if override_rules.is_empty() {
+            NoneOrOne::Unspecified
+        } else {
+            let rules = override_rules
+                .map(ConfigSelection::unwrap_config)
+                .into_vec();
+            NoneOrOne::One(Arc::new(create_tcp_client_proxy_selector(rules)))
+        };
+        proxy_users.push(new_user(username, password, override_proxy_provider));
+    }
+    proxy_users
+}
+
+// TODO: do this asynchronously
+fn read_cert_and_key(cert: &str, key: &str) -> (Vec<u8>, Vec<u8>) {
+    let mut cert_file = std::fs::File::open(cert).unwrap();
+    let mut cert_bytes = vec![];
+    cert_file.read_to_end(&mut cert_bytes).unwrap();
+
+    let mut key_file = std::fs::File::open(key).unwrap();
+    let mut key_bytes = vec![];
+    key_file.read_to_end(&mut key_bytes).unwrap();
+
+    (cert_bytes, key_bytes)
+}
+
 fn create_tls_server_target(
     tls_server_config: TlsServerConfig,
     rules_stack: &mut Vec<Vec<RuleConfig>>,
@@ -118,25 +214,80 @@ fn create_tls_server_target(
     let TlsServerConfig {
         cert,
         key,
+        acme,
+        ocsp_stapling,
+        client_ca,
+        require_client_cert,
+        client_identities,
         alpn_protocols,
+        unknown_alpn,
+        min_tls_version,
+        cipher_suites,
+        alpn_fallbacks,
+        alpn_override_rules,
         protocol,
         override_rules,
     } = tls_server_config;
 
-    // TODO: do this asynchronously
-    let mut cert_file = std::fs::File::open(&cert).unwrap();
-    let mut cert_bytes = vec![];
-    cert_file.read_to_end(&mut cert_bytes).unwrap();
-
-    let mut key_file = std::fs::File::open(&key).unwrap();
-    let mut key_bytes = vec![];
-    key_file.read_to_end(&mut key_bytes).unwrap();
+    // The policy was checked when the config was validated.
+    let tls_policy = create_tls_policy(
+        TlsVersion::from_config(min_tls_version.as_deref()).unwrap(),
+        &cipher_suites.into_vec(),
+    )
+    .unwrap();
+    // The CA certificates were checked when the config was validated.
+    let client_verifier =
+        create_client_verifier(client_ca.as_deref(), require_client_cert).unwrap();
 
-    let server_config = Arc::new(create_server_config(
-        &cert_bytes,
-        &key_bytes,
-        &alpn_protocols.into_vec(),
-    ));
+    // Either a certificate and key or ACME is set, which was checked when the config was
+    // validated.
+    let (server_config, acme_challenge_config) = match (acme, ocsp_stapling) {
+        (None, None) => {
+            let (cert_bytes, key_bytes) = read_cert_and_key(&cert.unwrap(), &key.unwrap());
+            let server_config = create_server_config(
+                &cert_bytes,
+                &key_bytes,
+                &alpn_protocols.into_vec(),
+                &tls_policy,
+                client_verifier,
+            );
+            (Arc::new(server_config), None)
+        }
+        (acme, ocsp_stapling) => {
+            let acme_resolver = acme.map(|acme| acme_cert_resolver(&acme));
+            let source: Arc<dyn StaplingSource> = match acme_resolver {
+                Some(ref acme_resolver) => acme_resolver.clone(),
+                None => {
+                    let (cert_bytes, key_bytes) = read_cert_and_key(&cert.unwrap(), &key.unwrap());
+                    Arc::new(StaticCertResolver::new(create_certified_key(
+                        &cert_bytes,
+                        &key_bytes,
+                    )))
+                }
+            };
+            let resolver: Arc<dyn ResolvesServerCert> = match ocsp_stapling {
+                Some(ocsp_stapling) => create_ocsp_stapler(&ocsp_stapling, source),
+                None => source,
+            };
+            let server_config = create_server_config_with_resolver(
+                resolver,
+                &alpn_protocols.into_vec(),
+                &tls_policy,
+                client_verifier,
+            );
+            // ACME challenges are answered with their own certificates, which aren't stapled,
+            // and ACME servers don't have client certificates.
+            let challenge_config = acme_resolver.map(|acme_resolver| {
+                Arc::new(create_server_config_with_resolver(
+                    acme_resolver,
+                    &[String::from_utf8(ACME_TLS_ALPN_PROTOCOL.to_vec()).unwrap()],
+                    &tls_policy,
+                    NoClientAuth::boxed(),
+                ))
+            });
+            (Arc::new(server_config), challenge_config)
+        }
+    };
 
     let pushed_rules = !override_rules.is_empty();
     if pushed_rules {
@@ -148,6 +299,7 @@ fn create_tls_server_target(
         );
     }
 
+    let vision_flow = matches!(protocol, ServerProxyConfig::Vless { flow: Some(_), .. });
     let handler = create_tcp_server_handler(protocol, rules_stack);
 
     let override_proxy_provider = if override_rules.is_empty() {
@@ -161,10 +313,58 @@ fn create_tls_server_target(
         rules_stack.pop().unwrap();
     }
 
+    let client_identity_proxy_providers = client_identities
+        .into_iter()
+        .filter(|client_identity| !client_identity.override_rules.is_empty())
+        .map(|client_identity| {
+            let rules = client_identity
+                .override_rules
+                .map(ConfigSelection::unwrap_config)
+                .into_vec();
+            (
+                client_identity.identity,
+                Arc::new(create_tcp_client_proxy_selector(rules)),
+            )
+        })
+        .collect();
+
+    let alpn_proxy_providers = alpn_override_rules
+        .into_iter()
+        .filter(|(_, override_rules)| !override_rules.is_empty())
+        .map(|(alpn_protocol, override_rules)| {
+            let rules = override_rules
+                .map(ConfigSelection::unwrap_config)
+                .into_vec();
+            (
+                alpn_protocol,
+                Arc::new(create_tcp_client_proxy_selector(rules)),
+            )
+        })
+        .collect();
+
+    // Clients that offer none of the protocols are accepted by a config without ALPN.
+    let unknown_alpn = unknown_alpn.map(|unknown_alpn| match unknown_alpn {
+        UnknownAlpnConfig::Reject => UnknownAlpnAction::Reject,
+        UnknownAlpnConfig::Protocol(alpn_protocol) => {
+            let mut server_config = (*server_config).clone();
+            server_config.alpn_protocols.clear();
+            UnknownAlpnAction::Default {
+                alpn_protocol,
+                server_config: Arc::new(server_config),
+            }
+        }
+    });
+
     TlsServerTarget {
         server_config,
+        acme_challenge_config,
+        unknown_alpn,
         handler,
         override_proxy_provider,
+        client_identity_proxy_providers,
+        alpn_proxy_providers,
+        alpn_fallbacks,
+        vision_flow,
     }
 }
 
@@ -175,7 +375,11 @@ fn create_websocket_server_target(
     let WebsocketServerConfig {
         matching_path,
         matching_headers,
+        response_headers,
         ping_type,
+        ping_interval_secs,
+        max_missed_pongs,
+        compression,
         protocol,
         override_rules,
     } = websocket_server_config;
@@ -215,12 +419,57 @@ fn create_websocket_server_target(
     WebsocketServerTarget {
         matching_path,
         matching_headers,
+        response_headers,
         ping_type,
+        ping_interval: Duration::from_secs(ping_interval_secs),
+        max_missed_pongs,
+        compression,
         handler,
         override_proxy_provider,
     }
 }
 
+fn create_grpc_server_handler(
+    grpc_server_config: GrpcServerConfig,
+    rules_stack: &mut Vec<Vec<RuleConfig>>,
+) -> Box<dyn TcpServerHandler> {
+    let GrpcServerConfig {
+        service_name,
+        protocol,
+        override_rules,
+    } = grpc_server_config;
+
+    let pushed_rules = !override_rules.is_empty();
+    if pushed_rules {
+        rules_stack.push(
+            override_rules
+                .clone()
+                .map(ConfigSelection::unwrap_config)
+                .into_vec(),
+        );
+    }
+
+    let handler = create_tcp_server_handler(protocol, rules_stack);
+
+    // Without override rules, the rules of outer layers like TLS are used.
+    let override_proxy_provider = if override_rules.is_empty() {
+        NoneOrOne::Unspecified
+    } else {
+        let rules = rules_stack.last().unwrap().clone();
+        NoneOrOne::One(Arc::new(create_tcp_client_proxy_selector(rules)))
+    };
+
+    if pushed_rules {
+        rules_stack.pop().unwrap();
+    }
+
+    Box::new(GrpcTcpServerHandler::new(
+        service_name,
+        handler,
+        override_proxy_provider,
+    ))
+}
+
 pub fn create_tcp_client_handler(
     client_proxy_config: ClientProxyConfig,
     default_sni_hostname: Option<String>,
@@ -229,38 +478,51 @@ pub fn create_tcp_client_handler(
         ClientProxyConfig::Direct => {
             panic!("Tried to create a direct tcp client handler");
         }
-        ClientProxyConfig::Http { username, password } => Box::new(HttpTcpClientHandler::new(
-            create_auth_credentials(username, password),
-        )),
-        ClientProxyConfig::Socks { username, password } => Box::new(SocksTcpClientHandler::new(
+        ClientProxyConfig::Http {
+            username,
+            password,
+            request_id_header,
+        } => Box::new(HttpTcpClientHandler::new(
             create_auth_credentials(username, password),
+            request_id_header,
         )),
-        ClientProxyConfig::Shadowsocks(ShadowsocksConfig { cipher, password }) => {
-            if cipher.starts_with("2022-blake3-") {
+        ClientProxyConfig::Socks {
+            username, password, ..
+        } => Box::new(SocksTcpClientHandler::new(create_auth_credentials(
+            username, password,
+        ))),
+        ClientProxyConfig::Shadowsocks(ShadowsocksConfig {
+            cipher, password, ..
+        }) => {
+            if let Some(cipher_name) = cipher.strip_prefix("2022-blake3-") {
                 let key_bytes = BASE64
                     .decode(password)
                     .expect("could not base64 decode password");
-                Box::new(ShadowsocksTcpHandler::new_aead2022(
-                    &cipher[12..],
-                    &key_bytes,
-                ))
+                Box::new(ShadowsocksTcpHandler::new_aead2022(cipher_name, &key_bytes))
             } else {
                 Box::new(ShadowsocksTcpHandler::new(&cipher, &password))
             }
         }
-        ClientProxyConfig::Snell(ShadowsocksConfig { cipher, password }) => {
-            Box::new(SnellTcpHandler::new(&cipher, &password))
+        ClientProxyConfig::Snell(ShadowsocksConfig {
+            cipher, password, ..
+        }) => Box::new(SnellTcpHandler::new(&cipher, &password)),
+        ClientProxyConfig::Vless { user_id, flow } => {
+            Box::new(VlessTcpHandler::new(&user_id).with_flow(flow))
         }
-        ClientProxyConfig::Vless { user_id } => Box::new(VlessTcpHandler::new(&user_id)),
         ClientProxyConfig::Trojan {
             password,
             shadowsocks,
+            ..
         } => Box::new(TrojanTcpHandler::new(&password, &shadowsocks)),
         ClientProxyConfig::Tls(tls_client_config) => {
             let TlsClientConfig {
                 verify,
+                ca_cert,
+                system_roots,
+                pin_sha256,
                 sni_hostname,
                 alpn_protocols,
+                fragment,
                 protocol,
             } = tls_client_config;
 
@@ -276,22 +538,45 @@ pub fn create_tcp_client_handler(
                 sni_hostname.into_option()
             };
 
+            // The CA certificates and pins were checked when the config was validated.
+            let roots = ClientRoots {
+                ca_certs: ca_cert
+                    .map(|ca_cert| load_ca_certs(&ca_cert).unwrap())
+                    .unwrap_or_default(),
+                system_roots,
+            };
+            let pinned_spki_hashes = pin_sha256
+                .iter()
+                .map(|s| parse_spki_hash(s).unwrap())
+                .collect::<Vec<_>>();
+
             let client_config = Arc::new(create_client_config(
                 verify,
+                &roots,
+                &pinned_spki_hashes,
                 &alpn_protocols.into_vec(),
                 sni_hostname.is_some(),
             ));
 
             let server_name = match sni_hostname {
-                Some(s) => s.as_str().try_into().unwrap(),
+                Some(ref s) => s.as_str().try_into().unwrap(),
                 // This is unused, since enable_sni is false, but connect_with still requires a
                 // parameter.
                 None => "example.com".try_into().unwrap(),
             };
 
-            let handler = create_tcp_client_handler(*protocol, None);
+            // gRPC calls default to the SNI hostname as their authority, as CDNs route on it.
+            let inner_default_hostname = match *protocol {
+                ClientProxyConfig::Grpc(_) => sni_hostname,
+                _ => None,
+            };
+            let vision_flow = matches!(*protocol, ClientProxyConfig::Vless { flow: Some(_), .. });
+            let handler = create_tcp_client_handler(*protocol, inner_default_hostname);
 
-            Box::new(TlsClientHandler::new(client_config, server_name, handler))
+            Box::new(
+                TlsClientHandler::new(client_config, server_name, fragment, handler)
+                    .with_vision_flow(vision_flow),
+            )
         }
         ClientProxyConfig::Vmess {
             cipher,
@@ -302,16 +587,50 @@ pub fn create_tcp_client_handler(
             let WebsocketClientConfig {
                 matching_path,
                 matching_headers,
+                host_header,
                 ping_type,
+                ping_interval_secs,
+                max_missed_pongs,
+                compression,
                 protocol,
             } = websocket_client_config;
 
+            // Validation ensured that matching_headers doesn't have a Host header.
+            let matching_headers = match host_header {
+                Some(host_header) => {
+                    let mut headers = matching_headers.unwrap_or_default();
+                    headers.insert("Host".to_string(), host_header);
+                    Some(headers)
+                }
+                None => matching_headers,
+            };
+
             let handler = create_tcp_client_handler(*protocol, None);
 
             Box::new(WebsocketTcpClientHandler::new(
                 matching_path,
                 matching_headers,
                 ping_type,
+                Duration::from_secs(ping_interval_secs),
+                max_missed_pongs,
+                compression,
+                handler,
+            ))
+        }
+        ClientProxyConfig::Grpc(GrpcClientConfig {
+            service_name,
+            multi_mode,
+            authority,
+            protocol,
+        }) => {
+            let authority = authority
+                .or(default_sni_hostname)
+                .unwrap_or_else(|| "localhost".to_string());
+            let handler = create_tcp_client_handler(*protocol, None);
+            Box::new(GrpcTcpClientHandler::new(
+                service_name,
+                multi_mode,
+                authority,
                 handler,
             ))
         }
@@ -321,25 +640,65 @@ pub fn create_tcp_client_handler(
 pub fn create_tcp_client_proxy_selector(
     rules: Vec<RuleConfig>,
 ) -> ClientProxySelector<TcpClientConnector> {
+    create_client_proxy_selector(rules, |client_config| {
+        TcpClientConnector::try_from(client_config).unwrap()
+    })
+}
+
+pub fn create_client_proxy_selector<T, F>(
+    rules: Vec<RuleConfig>,
+    mut create_client_proxy: F,
+) -> ClientProxySelector<T>
+where
+    F: FnMut(ClientConfig) -> T,
+{
     let rules = rules
         .into_iter()
         .map(|rule_config| {
-            let RuleConfig { masks, action } = rule_config;
+            let RuleConfig {
+                name,
+                masks,
+                mask_mode,
+                schedule,
+                action,
+            } = rule_config;
             let connect_action = match action {
                 RuleActionConfig::Allow {
                     override_address,
                     client_proxies,
+                    sticky,
+                    balance,
+                    select_by,
                 } => ConnectAction::new_allow(
                     override_address,
                     client_proxies
                         .map(ConfigSelection::unwrap_config)
-                        .map(TcpClientConnector::try_from)
-                        // .filter(Option::is_some)
-                        .map(Option::unwrap),
+                        .map(&mut create_client_proxy),
+                    sticky,
+                    balance,
+                    select_by.map(|select_by| {
+                        let groups = select_by
+                            .groups
+                            .into_iter()
+                            .map(|(value, client_proxies)| {
+                                let client_proxies = client_proxies
+                                    .map(ConfigSelection::unwrap_config)
+                                    .map(&mut create_client_proxy);
+                                (value, client_proxies)
+                            })
+                            .collect();
+                        ProxyGroupSelector::new(select_by.key, groups)
+                    }),
                 ),
                 RuleActionConfig::Block => ConnectAction::new_block(),
             };
-            ConnectRule::new(masks.into_vec(), connect_action)
+            ConnectRule::new(
+                name,
+                masks.into_vec(),
+                mask_mode,
+                schedule.map(RuleSchedule::new),
+                connect_action,
+            )
         })
         .collect::<Vec<_>>();
     ClientProxySelector::new(rules)
@@ -1,107 +1,278 @@
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
+use async_trait::async_trait;
+use futures::StreamExt;
 use log::{debug, error, warn};
+use parking_lot::RwLock;
 use tokio::io::AsyncWriteExt;
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
 use crate::address::NetLocation;
 use crate::async_stream::AsyncStream;
+use crate::auth_ban_table::{AuthBanTable, AuthSource};
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
-use crate::config::{BindLocation, ConfigSelection, ServerConfig, TcpConfig};
+use crate::config::{
+    BindLocation, ConfigSelection, MuxConfig, RuleConfig, ServerConfig, ServerProxyConfig,
+    ShadowsocksConfig, TcpConfig, Transport, WriteCoalescingConfig,
+};
+use crate::connection_registry::{
+    connection_registry, ConnectionHandle, ConnectionInfo, CountingStream,
+};
 use crate::copy_bidirectional::copy_bidirectional;
 use crate::copy_bidirectional_message::copy_bidirectional_message;
 use crate::copy_multidirectional_message::copy_multidirectional_message;
-use crate::resolver::{resolve_single_address, NativeResolver, Resolver};
+use crate::health_server::accepting_listener;
+use crate::http_forward::run_http_forward;
+use crate::mux::{is_mux_location, read_session_request, MuxDestinationHandler, MuxSession};
+use crate::privilege_util::wait_until_accepting;
+use crate::quic_server::start_quic_listeners;
+use crate::reset_on_failure_stream::ResetOnFailureStream;
+use crate::resolver::{create_resolver, Resolver};
+use crate::sip003_plugin::{unused_local_address, PluginProcess};
+use crate::socket_util::{new_tcp_listener, set_dscp};
+use crate::source_filter::SourceFilter;
 use crate::tcp_client_connector::TcpClientConnector;
 use crate::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
 use crate::tcp_handler_util::{create_tcp_client_proxy_selector, create_tcp_server_handler};
-use crate::udp_direct_message_stream::UdpDirectMessageStream;
+use crate::udp_session_table::UdpSessionTable;
+
+// The listen backlog when the config doesn't set one, which is what tokio uses.
+const DEFAULT_BACKLOG: u32 = 1024;
+
+// Replaced when the config is reloaded. Connections that were already accepted keep using the
+// previous state.
+pub(crate) struct TcpServerState {
+    pub(crate) server_label: String,
+    pub(crate) protocol_name: String,
+    pub(crate) client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    pub(crate) server_handler: Arc<Box<dyn TcpServerHandler>>,
+}
+
+// Connects to destinations in place of the matched rules' client proxies, for running
+// connections without a network. The rules still decide whether a connection is allowed and
+// where it goes.
+#[async_trait]
+pub trait DestinationConnector: Send + Sync {
+    async fn connect(&self, remote_location: &NetLocation)
+        -> std::io::Result<Box<dyn AsyncStream>>;
+}
+
+// The server settings that each stream is processed with, which are passed on to the streams
+// inside multiplexed connections.
+#[derive(Clone)]
+struct StreamSettings {
+    resolver: Arc<dyn Resolver>,
+    mux_config: Option<MuxConfig>,
+    write_coalescing: Option<WriteCoalescingConfig>,
+    udp_sessions: Arc<UdpSessionTable>,
+    destination_connector: Option<Arc<dyn DestinationConnector>>,
+}
+
+impl TcpServerState {
+    fn register_connection(&self, source: String, source_ip: Option<IpAddr>) -> ConnectionHandle {
+        connection_registry().register(
+            self.server_label.clone(),
+            source,
+            source_ip,
+            self.protocol_name.clone(),
+        )
+    }
+}
 
 async fn run_tcp_server(
-    bind_address: SocketAddr,
+    listener: tokio::net::TcpListener,
     tcp_config: TcpConfig,
-    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
-    server_handler: Arc<Box<dyn TcpServerHandler>>,
+    settings: StreamSettings,
+    server_state: Arc<RwLock<TcpServerState>>,
+    source_filter: SourceFilter,
+    auth_bans: Option<Arc<AuthBanTable>>,
+    dscp: Option<u8>,
 ) -> std::io::Result<()> {
-    let TcpConfig { no_delay } = tcp_config;
+    let TcpConfig {
+        no_delay,
+        reset_on_failure,
+        ..
+    } = tcp_config;
+    let is_ipv6 = listener.local_addr()?.is_ipv6();
 
-    let resolver: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
+    let server_label = server_state.read().server_label.clone();
 
-    let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
+    wait_until_accepting().await;
+    let _accepting = accepting_listener();
 
     loop {
         let (stream, addr) = match listener.accept().await {
             Ok(v) => v,
             Err(e) => {
-                error!("Accept failed: {}", e);
+                error!("[{}] Accept failed: {}", server_label, e);
                 continue;
             }
         };
 
+        if !source_filter.is_allowed(addr.ip()) {
+            debug!(
+                "[{}] Dropped connection from disallowed source {}",
+                server_label, addr
+            );
+            continue;
+        }
+
+        if let Some(ref auth_bans) = auth_bans {
+            if auth_bans.is_banned(addr.ip()) {
+                debug!(
+                    "[{}] Dropped connection from banned source {}",
+                    server_label, addr
+                );
+                continue;
+            }
+        }
+
         if no_delay {
             if let Err(e) = stream.set_nodelay(true) {
-                error!("Failed to set TCP nodelay: {}", e);
+                error!("[{}] Failed to set TCP nodelay: {}", server_label, e);
+            }
+        }
+
+        if let Some(dscp) = dscp {
+            if let Err(e) = set_dscp(socket2::SockRef::from(&stream), is_ipv6, dscp) {
+                error!("[{}] Failed to set DSCP: {}", server_label, e);
             }
         }
 
         // TODO: allow this be to Option<Arc<ClientProxySelector<..>>> when
         // there are no rules or proxies specified.
-        let cloned_provider = client_proxy_selector.clone();
-        let cloned_cache = resolver.clone();
-        let cloned_handler = server_handler.clone();
+        let (cloned_provider, cloned_handler, connection) = {
+            let state = server_state.read();
+            (
+                state.client_proxy_selector.clone(),
+                state.server_handler.clone(),
+                state
+                    .register_connection(format!("{}:{}", addr.ip(), addr.port()), Some(addr.ip())),
+            )
+        };
+        let cloned_settings = settings.clone();
+        let cloned_label = server_label.clone();
+        let auth_source = auth_bans
+            .as_ref()
+            .map(|auth_bans| AuthSource::new(auth_bans.clone(), addr.ip()));
+        let connection_info = connection.info().clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                process_stream(stream, cloned_handler, cloned_provider, cloned_cache).await
-            {
-                error!("{}:{} finished with error: {:?}", addr.ip(), addr.port(), e);
+            let process_future: Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>> =
+                if reset_on_failure {
+                    let stream = ResetOnFailureStream::new(stream, connection_info.clone());
+                    Box::pin(process_stream(
+                        stream,
+                        cloned_handler,
+                        cloned_provider,
+                        cloned_settings,
+                        connection,
+                        auth_source,
+                    ))
+                } else {
+                    Box::pin(process_stream(
+                        stream,
+                        cloned_handler,
+                        cloned_provider,
+                        cloned_settings,
+                        connection,
+                        auth_source,
+                    ))
+                };
+            if let Err(e) = connection_info.run_until_closed(process_future).await {
+                error!(
+                    "[{}] {} finished with error: {:?}",
+                    cloned_label,
+                    connection_info.log_label(),
+                    e
+                );
             } else {
-                debug!("{}:{} finished successfully", addr.ip(), addr.port());
+                debug!(
+                    "[{}] {} finished successfully",
+                    cloned_label,
+                    connection_info.log_label()
+                );
             }
         });
     }
 }
 
 #[cfg(target_family = "unix")]
-async fn run_unix_server(
-    path_buf: PathBuf,
-    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
-    server_handler: Arc<Box<dyn TcpServerHandler>>,
-) -> std::io::Result<()> {
-    let resolver: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
-
-    if tokio::fs::symlink_metadata(&path_buf).await.is_ok() {
+async fn bind_unix_listener(
+    path_buf: &PathBuf,
+    backlog: u32,
+) -> std::io::Result<tokio::net::UnixListener> {
+    if tokio::fs::symlink_metadata(path_buf).await.is_ok() {
         println!(
             "WARNING: replacing file at socket path {}",
             path_buf.display()
         );
-        let _ = tokio::fs::remove_file(&path_buf).await;
+        let _ = tokio::fs::remove_file(path_buf).await;
     }
 
-    let listener = tokio::net::UnixListener::bind(path_buf).unwrap();
+    crate::socket_util::new_unix_listener(path_buf, backlog)
+}
+
+#[cfg(target_family = "unix")]
+async fn run_unix_server(
+    listener: tokio::net::UnixListener,
+    settings: StreamSettings,
+    server_state: Arc<RwLock<TcpServerState>>,
+) -> std::io::Result<()> {
+    let server_label = server_state.read().server_label.clone();
+
+    wait_until_accepting().await;
+    let _accepting = accepting_listener();
 
     loop {
         let (stream, addr) = match listener.accept().await {
             Ok(v) => v,
             Err(e) => {
-                error!("Accept failed: {:?}", e);
+                error!("[{}] Accept failed: {:?}", server_label, e);
                 continue;
             }
         };
 
-        let cloned_provider = client_proxy_selector.clone();
-        let cloned_cache = resolver.clone();
-        let cloned_handler = server_handler.clone();
+        let (cloned_provider, cloned_handler, connection) = {
+            let state = server_state.read();
+            (
+                state.client_proxy_selector.clone(),
+                state.server_handler.clone(),
+                state.register_connection(format!("{:?}", addr), None),
+            )
+        };
+        let cloned_settings = settings.clone();
+        let cloned_label = server_label.clone();
+        let connection_info = connection.info().clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                process_stream(stream, cloned_handler, cloned_provider, cloned_cache).await
+            if let Err(e) = connection_info
+                .run_until_closed(Box::pin(process_stream(
+                    stream,
+                    cloned_handler,
+                    cloned_provider,
+                    cloned_settings,
+                    connection,
+                    None,
+                )))
+                .await
             {
-                error!("{:?} finished with error: {:?}", addr, e);
+                error!(
+                    "[{}] {} finished with error: {:?}",
+                    cloned_label,
+                    connection_info.log_label(),
+                    e
+                );
             } else {
-                debug!("{:?} finished successfully", addr);
+                debug!(
+                    "[{}] {} finished successfully",
+                    cloned_label,
+                    connection_info.log_label()
+                );
             }
         });
     }
@@ -122,18 +293,32 @@ async fn process_stream<AS>(
     stream: AS,
     server_handler: Arc<Box<dyn TcpServerHandler>>,
     client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
-    resolver: Arc<dyn Resolver>,
+    settings: StreamSettings,
+    connection: ConnectionHandle,
+    auth_source: Option<AuthSource>,
 ) -> std::io::Result<()>
 where
     AS: AsyncStream + 'static,
 {
+    let StreamSettings {
+        resolver,
+        mux_config,
+        write_coalescing,
+        udp_sessions,
+        destination_connector,
+    } = settings;
     let setup_server_stream_future = timeout(
         Duration::from_secs(60),
         setup_server_stream(stream, server_handler),
     );
 
-    let setup_result = match setup_server_stream_future.await {
-        Ok(Ok(r)) => r,
+    let setup_result = setup_server_stream_future.await;
+    if let (Some(auth_source), Ok(result)) = (&auth_source, &setup_result) {
+        auth_source.record_setup_result(result);
+    }
+
+    let setup_result = match setup_result {
+        Ok(Ok(r)) => r.resolve_fallback(),
         Ok(Err(e)) => {
             return Err(std::io::Error::new(
                 e.kind(),
@@ -156,13 +341,51 @@ where
             override_proxy_provider,
             connection_success_response,
             initial_remote_data,
+            authenticated_user,
         } => {
+            if let Some(user) = authenticated_user {
+                connection.info().set_user(user);
+            }
+
             let selected_proxy_provider = if override_proxy_provider.is_one() {
                 override_proxy_provider.unwrap()
             } else {
                 client_proxy_selector
             };
 
+            connection.info().set_destination(remote_location.clone());
+
+            if is_mux_location(&remote_location) {
+                if let Some(mux_config) = mux_config {
+                    if initial_remote_data.is_some() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "unexpected initial data for mux connection",
+                        ));
+                    }
+                    if let Some(data) = connection_success_response {
+                        server_stream.write_all(&data).await?;
+                    }
+                    server_stream.flush().await?;
+                    // Mux streams can't start another mux session.
+                    let settings = StreamSettings {
+                        resolver,
+                        mux_config: None,
+                        write_coalescing,
+                        udp_sessions,
+                        destination_connector,
+                    };
+                    return process_mux_session(
+                        server_stream,
+                        mux_config,
+                        selected_proxy_provider,
+                        settings,
+                        connection.info().clone(),
+                    )
+                    .await;
+                }
+            }
+
             let setup_client_stream_future = timeout(
                 Duration::from_secs(60),
                 setup_client_stream(
@@ -170,6 +393,8 @@ where
                     selected_proxy_provider,
                     resolver,
                     remote_location.clone(),
+                    connection.info(),
+                    destination_connector.as_deref(),
                 ),
             );
 
@@ -218,6 +443,7 @@ where
                 &mut client_stream,
                 server_need_initial_flush,
                 client_need_initial_flush,
+                write_coalescing.as_ref(),
             )
             .await;
 
@@ -226,26 +452,107 @@ where
             copy_result?;
             Ok(())
         }
+        TcpServerSetupResult::Fallback { .. } => unreachable!(),
+        TcpServerSetupResult::MultiplexedForward {
+            streams,
+            handler,
+            override_proxy_provider,
+        } => {
+            let selected_proxy_provider = if override_proxy_provider.is_one() {
+                override_proxy_provider.unwrap()
+            } else {
+                client_proxy_selector
+            };
+
+            // The connection itself is set up, the streams are set up separately.
+            connection.info().mark_setup_complete();
+            let mut stream_index = 0;
+            let streams = streams.map(move |stream| {
+                stream_index += 1;
+                (format!("stream {}", stream_index), stream)
+            });
+            let protocol = connection.info().protocol.clone();
+            let settings = StreamSettings {
+                resolver,
+                mux_config,
+                write_coalescing,
+                udp_sessions,
+                destination_connector,
+            };
+            process_multiplexed_streams(
+                streams,
+                protocol,
+                handler,
+                selected_proxy_provider,
+                settings,
+                connection.info().clone(),
+            )
+            .await
+        }
+        TcpServerSetupResult::HttpForward {
+            stream,
+            line_reader,
+            request,
+            override_proxy_provider,
+            authenticated_user,
+        } => {
+            if let Some(user) = authenticated_user {
+                connection.info().set_user(user);
+            }
+
+            let selected_proxy_provider = if override_proxy_provider.is_one() {
+                override_proxy_provider.unwrap()
+            } else {
+                client_proxy_selector
+            };
+
+            run_http_forward(
+                stream,
+                line_reader,
+                request,
+                selected_proxy_provider,
+                resolver,
+                connection.info(),
+                destination_connector.as_deref(),
+            )
+            .await
+        }
         TcpServerSetupResult::BidirectionalUdpForward {
             remote_location,
             stream: mut server_stream,
         } => {
-            let action = client_proxy_selector
-                .judge(remote_location, &resolver)
+            connection.info().set_destination(remote_location.clone());
+            let (action, rule) = client_proxy_selector
+                .judge_with_rule(
+                    remote_location.clone(),
+                    connection.info().source_ip,
+                    connection.info().user().as_deref(),
+                    &resolver,
+                )
                 .await?;
+            connection
+                .info()
+                .record_judgement(&remote_location, rule, &action);
             match action {
                 ConnectDecision::Allow {
                     client_proxy,
                     remote_location,
+                    active_connection: _active_connection,
                 } => {
-                    let remote_addr = resolve_single_address(&resolver, &remote_location).await?;
-                    let client_socket = client_proxy.configure_udp_socket()?;
-                    client_socket.connect(remote_addr).await?;
-
-                    let mut client_socket = Box::new(client_socket);
+                    let mut client_socket = client_proxy
+                        .connect_udp(&remote_location, &resolver)
+                        .await?;
+                    connection.info().mark_setup_complete();
 
-                    let copy_result =
-                        copy_bidirectional_message(&mut server_stream, &mut client_socket).await;
+                    let idle_timeout = udp_sessions.config().idle_timeout();
+                    let copy_result = udp_sessions
+                        .run_session(copy_bidirectional_message(
+                            &mut server_stream,
+                            &mut client_socket,
+                            idle_timeout,
+                            connection.info(),
+                        ))
+                        .await;
 
                     // TODO: add async trait ext and make this work
                     //let (_, _) = futures::join!(server_stream.shutdown_message(), client_stream.shutdown_message());
@@ -270,18 +577,27 @@ where
                 ConnectDecision::Allow {
                     client_proxy,
                     remote_location: _,
+                    active_connection: _active_connection,
                 } => {
-                    let client_socket = client_proxy.configure_udp_socket()?;
-                    let mut client_stream =
-                        Box::new(UdpDirectMessageStream::new(client_socket, resolver));
-
-                    let copy_result = copy_multidirectional_message(
-                        &mut server_stream,
-                        &mut client_stream,
-                        server_need_initial_flush,
-                        false,
-                    )
-                    .await;
+                    connection
+                        .info()
+                        .set_client_proxy(client_proxy.label().to_string());
+                    let mut client_stream = client_proxy
+                        .create_udp_stream(udp_sessions.config(), resolver)
+                        .await?;
+                    connection.info().mark_setup_complete();
+
+                    let idle_timeout = udp_sessions.config().idle_timeout();
+                    let copy_result = udp_sessions
+                        .run_session(copy_multidirectional_message(
+                            &mut server_stream,
+                            &mut client_stream,
+                            server_need_initial_flush,
+                            false,
+                            idle_timeout,
+                            connection.info(),
+                        ))
+                        .await;
 
                     // TODO: add async trait ext and make this work
                     //let (_, _) = futures::join!(server_stream.shutdown_message(), client_stream.shutdown_message());
@@ -290,7 +606,11 @@ where
                     Ok(())
                 }
                 ConnectDecision::Block => {
-                    warn!("Blocked multidirectional udp forward, because the default action is to block.");
+                    warn!(
+                        "[{}] {} Blocked multidirectional udp forward, because the default action is to block.",
+                        connection.info().server,
+                        connection.info().log_label()
+                    );
                     // TODO: add async trait ext and make this work
                     // let _ = server_stream.shutdown_message().await;
                     Ok(())
@@ -300,75 +620,588 @@ where
     }
 }
 
+fn process_mux_session(
+    mut server_stream: Box<dyn AsyncStream>,
+    mux_config: MuxConfig,
+    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    settings: StreamSettings,
+    session_info: Arc<ConnectionInfo>,
+) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>> {
+    Box::pin(async move {
+        read_session_request(&mut server_stream).await?;
+        // The session is kept until all of its streams were accepted.
+        let (_session, mux_streams) = MuxSession::new_server(server_stream, &mux_config);
+        let mux_streams = mux_streams.map(|mux_stream| {
+            let stream_label = format!("mux stream {}", mux_stream.stream_id());
+            let stream: Box<dyn AsyncStream> = Box::new(mux_stream);
+            (stream_label, stream)
+        });
+        process_multiplexed_streams(
+            mux_streams,
+            "mux".to_string(),
+            Arc::new(Box::new(MuxDestinationHandler)),
+            client_proxy_selector,
+            settings,
+            session_info,
+        )
+        .await
+    })
+}
+
+// Processes each stream like a separate connection from the same source, until the session
+// stops yielding streams. Returns a boxed future since process_stream would otherwise be a
+// recursive future.
+fn process_multiplexed_streams<S>(
+    mut streams: S,
+    protocol: String,
+    stream_handler: Arc<Box<dyn TcpServerHandler>>,
+    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    settings: StreamSettings,
+    session_info: Arc<ConnectionInfo>,
+) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>
+where
+    S: futures::Stream<Item = (String, Box<dyn AsyncStream>)> + Unpin + Send + 'static,
+{
+    Box::pin(async move {
+        while let Some((stream_label, stream)) = streams.next().await {
+            let cloned_handler = stream_handler.clone();
+            let cloned_provider = client_proxy_selector.clone();
+            let cloned_settings = settings.clone();
+            let connection = connection_registry().create_connection(
+                session_info.server.clone(),
+                format!("{} ({})", session_info.source, stream_label),
+                session_info.source_ip,
+                protocol.clone(),
+                session_info.is_registered(),
+            );
+            if let Some(user) = session_info.user() {
+                connection.info().set_user(user);
+            }
+            let server_label = session_info.server.clone();
+            let connection_info = connection.info().clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection_info
+                    .run_until_closed(Box::pin(process_stream(
+                        stream,
+                        cloned_handler,
+                        cloned_provider,
+                        cloned_settings,
+                        connection,
+                        None,
+                    )))
+                    .await
+                {
+                    error!(
+                        "[{}] {} finished with error: {:?}",
+                        server_label,
+                        connection_info.log_label(),
+                        e
+                    );
+                } else {
+                    debug!(
+                        "[{}] {} finished successfully",
+                        server_label,
+                        connection_info.log_label()
+                    );
+                }
+            });
+        }
+
+        Ok(())
+    })
+}
+
 pub async fn setup_client_stream(
     server_stream: &mut Box<dyn AsyncStream>,
     client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
     resolver: Arc<dyn Resolver>,
     remote_location: NetLocation,
+    connection: &Arc<ConnectionInfo>,
+    destination_connector: Option<&dyn DestinationConnector>,
 ) -> std::io::Result<Option<Box<dyn AsyncStream>>> {
-    let action = client_proxy_selector
-        .judge(remote_location, &resolver)
+    let (action, rule) = client_proxy_selector
+        .judge_with_rule(
+            remote_location.clone(),
+            connection.source_ip,
+            connection.user().as_deref(),
+            &resolver,
+        )
         .await?;
+    connection.record_judgement(&remote_location, rule, &action);
 
     match action {
         ConnectDecision::Allow {
             client_proxy,
             remote_location,
+            active_connection,
         } => {
-            let client_stream = client_proxy
-                .connect(server_stream, remote_location, &resolver)
-                .await?;
-            Ok(Some(client_stream))
+            let (client_stream, egress) = match destination_connector {
+                Some(connector) => (connector.connect(&remote_location).await?, None),
+                None => {
+                    client_proxy
+                        .connect(server_stream, remote_location.clone(), &resolver)
+                        .await?
+                }
+            };
+            match egress {
+                Some(egress) => {
+                    debug!(
+                        "[{}] {} -> {} egress {} via {}",
+                        connection.server,
+                        connection.log_label(),
+                        remote_location,
+                        egress,
+                        client_proxy.label()
+                    );
+                    connection.set_egress(egress);
+                }
+                None => {
+                    debug!(
+                        "[{}] {} -> {} via {}",
+                        connection.server,
+                        connection.log_label(),
+                        remote_location,
+                        client_proxy.label()
+                    );
+                }
+            }
+            connection.mark_setup_complete();
+            Ok(Some(Box::new(CountingStream::new(
+                client_stream,
+                connection.clone(),
+                active_connection,
+            ))))
         }
         ConnectDecision::Block => Ok(None),
     }
 }
 
-pub async fn start_tcp_server(config: ServerConfig) -> std::io::Result<JoinHandle<()>> {
+pub(crate) fn create_tcp_server_state(
+    server_label: String,
+    protocol: ServerProxyConfig,
+    rules: Vec<RuleConfig>,
+) -> TcpServerState {
+    let protocol_name = protocol.to_string();
+
+    let client_proxy_selector = Arc::new(create_tcp_client_proxy_selector(rules.clone()));
+
+    let mut rules_stack = vec![rules];
+    let server_handler: Arc<Box<dyn TcpServerHandler>> =
+        Arc::new(create_tcp_server_handler(protocol, &mut rules_stack));
+    debug!("[{}] TCP handler: {:?}", server_label, server_handler);
+
+    TcpServerState {
+        server_label,
+        protocol_name,
+        client_proxy_selector,
+        server_handler,
+    }
+}
+
+// Runs a single connection through the server's handler and rules, the same way as one that its
+// listener accepted, without the listener's source filter or auth bans. This lets handlers and
+// rules be tested with in-memory streams, such as one end of tokio::io::duplex. The config needs
+// to be validated first, with load_configs or update_config. TCP destinations are connected to
+// with the destination connector when one is given, and otherwise through the matched rule's
+// client proxy. UDP forwards always go through the client proxy. The connection isn't added to
+// the connection registry, so it isn't listed or counted in metrics.
+pub async fn run_server_connection<AS>(
+    config: ServerConfig,
+    stream: AS,
+    source: SocketAddr,
+    resolver: Arc<dyn Resolver>,
+    destination_connector: Option<Arc<dyn DestinationConnector>>,
+) -> std::io::Result<()>
+where
+    AS: AsyncStream + 'static,
+{
+    let server_label = config.label();
     let ServerConfig {
-        bind_location,
         tcp_settings,
+        mux_settings,
+        udp_settings,
         protocol,
         rules,
         ..
     } = config;
 
-    println!("Starting {} TCP server at {}", &protocol, &bind_location);
+    let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
+    let write_coalescing = tcp_settings.and_then(|tcp_config| tcp_config.write_coalescing);
+    let udp_sessions = Arc::new(UdpSessionTable::new(
+        server_label.clone(),
+        udp_settings.unwrap_or_default(),
+    ));
+    let server_state = create_tcp_server_state(server_label, protocol, rules);
+    let connection = connection_registry().create_connection(
+        server_state.server_label.clone(),
+        format!("{}:{}", source.ip(), source.port()),
+        Some(source.ip()),
+        server_state.protocol_name.clone(),
+        false,
+    );
+    let settings = StreamSettings {
+        resolver,
+        mux_config: mux_settings,
+        write_coalescing,
+        udp_sessions,
+        destination_connector,
+    };
+    let connection_info = connection.info().clone();
+    connection_info
+        .run_until_closed(Box::pin(process_stream(
+            stream,
+            server_state.server_handler,
+            server_state.client_proxy_selector,
+            settings,
+            connection,
+            None,
+        )))
+        .await
+}
+
+pub async fn start_tcp_server(config: ServerConfig) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let server_label = config.label();
+    let ServerConfig {
+        name,
+        bind_locations,
+        transports,
+        tcp_settings,
+        quic_settings,
+        mux_settings,
+        udp_settings,
+        resolver_settings,
+        auth_ban_settings,
+        allow_sources,
+        deny_sources,
+        dscp,
+        backlog,
+        protocol,
+        rules,
+        ..
+    } = config;
+
+    let quic_config = quic_settings.filter(|_| transports.contains(&Transport::Quic));
+    let transport_name = match quic_config {
+        Some(_) => "TCP and QUIC",
+        None => "TCP",
+    };
+    match name {
+        Some(name) => println!(
+            "Starting {} {} server {} at {}",
+            &protocol, transport_name, name, &bind_locations
+        ),
+        None => println!(
+            "Starting {} {} server at {}",
+            &protocol, transport_name, &bind_locations
+        ),
+    }
 
     let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
     // We should always have a direct entry.
     assert!(!rules.is_empty());
 
+    let protocol_plugin = match protocol {
+        ServerProxyConfig::Shadowsocks(ShadowsocksConfig {
+            plugin: Some(ref plugin),
+            ref plugin_opts,
+            ..
+        }) => Some((plugin.clone(), plugin_opts.clone())),
+        _ => None,
+    };
+
     let tcp_config = tcp_settings.unwrap_or_else(TcpConfig::default);
+    let udp_sessions = Arc::new(UdpSessionTable::new(
+        server_label.clone(),
+        udp_settings.unwrap_or_default(),
+    ));
+    connection_registry().set_udp_session_table(server_label.clone(), udp_sessions.clone());
+    let resolver = create_resolver(resolver_settings.as_ref());
+    let source_filter = SourceFilter::new(allow_sources.into_vec(), deny_sources.into_vec());
+    let auth_bans = auth_ban_settings.map(|auth_ban_config| {
+        let auth_bans = Arc::new(AuthBanTable::new(server_label.clone(), auth_ban_config));
+        connection_registry().set_auth_ban_table(server_label.clone(), auth_bans.clone());
+        auth_bans
+    });
 
-    let client_proxy_selector = Arc::new(create_tcp_client_proxy_selector(rules.clone()));
+    let server_state = create_tcp_server_state(server_label.clone(), protocol, rules);
+    connection_registry().set_selector(
+        server_label.clone(),
+        server_state.client_proxy_selector.clone(),
+    );
+    let server_state = Arc::new(RwLock::new(server_state));
 
-    let mut rules_stack = vec![rules];
-    let tcp_handler: Arc<Box<dyn TcpServerHandler>> =
-        Arc::new(create_tcp_server_handler(protocol, &mut rules_stack));
-    debug!("TCP handler: {:?}", tcp_handler);
+    // Only the protocol and rules are reloaded, listener settings need a restart.
+    let reload_state = server_state.clone();
+    connection_registry().add_server_reloader(Box::new(move |config: ServerConfig| {
+        let ServerConfig {
+            protocol, rules, ..
+        } = config;
+        let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
+        // Server handlers read certificates and panic when they are missing, so catch that
+        // rather than taking down the caller.
+        // The server keeps its original name, since the reloaded config may have renamed it.
+        let new_label = server_label.clone();
+        let new_state =
+            std::panic::catch_unwind(move || create_tcp_server_state(new_label, protocol, rules))
+                .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "failed to create server handler from reloaded config",
+                )
+            })?;
+        connection_registry().set_selector(
+            server_label.clone(),
+            new_state.client_proxy_selector.clone(),
+        );
+        *reload_state.write() = new_state;
+        Ok(())
+    }));
+
+    let settings = StreamSettings {
+        resolver: resolver.clone(),
+        mux_config: mux_settings,
+        write_coalescing: tcp_config.write_coalescing,
+        udp_sessions: udp_sessions.clone(),
+        destination_connector: None,
+    };
+
+    // Listeners are bound before returning, so that the caller knows when they're all bound.
+    let backlog = backlog.unwrap_or(DEFAULT_BACKLOG);
+    let mut join_handles = match quic_config {
+        Some(quic_config) => {
+            start_quic_listeners(
+                bind_locations.clone(),
+                quic_config,
+                dscp,
+                server_state.clone(),
+                udp_sessions.clone(),
+                resolver.clone(),
+                source_filter.clone(),
+            )
+            .await?
+        }
+        None => vec![],
+    };
+    for bind_location in bind_locations.into_vec() {
+        // The plugin listens on the bind address and forwards to a local listener. It's stopped
+        // when the server task finishes.
+        let plugin = match (&protocol_plugin, &bind_location) {
+            (Some((plugin, plugin_opts)), BindLocation::Address(a)) => {
+                let local_address = unused_local_address()?;
+                Some(PluginProcess::start(
+                    plugin,
+                    plugin_opts.as_deref(),
+                    local_address,
+                    a,
+                )?)
+            }
+            _ => None,
+        };
 
-    Ok(tokio::spawn(async move {
-        match bind_location {
+        let listener = match bind_location {
             BindLocation::Address(a) => {
                 // TODO: make this non-blocking?
-                let socket_addr = a.to_socket_addr().unwrap();
-                run_tcp_server(socket_addr, tcp_config, client_proxy_selector, tcp_handler)
-                    .await
-                    .unwrap();
+                let socket_addr = match plugin {
+                    Some(ref plugin) => plugin.local_address(),
+                    None => a.to_socket_addr()?,
+                };
+                let listener = new_tcp_listener(socket_addr, backlog)?;
+                ServerListener::Tcp(listener)
             }
             BindLocation::Path(path_buf) => {
                 #[cfg(target_family = "unix")]
                 {
-                    run_unix_server(path_buf, client_proxy_selector, tcp_handler)
-                        .await
-                        .unwrap();
+                    ServerListener::Unix(bind_unix_listener(&path_buf, backlog).await?)
                 }
                 #[cfg(not(target_family = "unix"))]
                 {
                     panic!("Unix sockets are not supported on non-unix OSes.");
                 }
             }
+        };
+
+        let tcp_config = tcp_config.clone();
+        let settings = settings.clone();
+        let server_state = server_state.clone();
+        let source_filter = source_filter.clone();
+        let auth_bans = auth_bans.clone();
+        join_handles.push(tokio::spawn(async move {
+            let _plugin = plugin;
+            match listener {
+                ServerListener::Tcp(listener) => {
+                    run_tcp_server(
+                        listener,
+                        tcp_config,
+                        settings,
+                        server_state,
+                        source_filter,
+                        auth_bans,
+                        dscp,
+                    )
+                    .await
+                    .unwrap();
+                }
+                #[cfg(target_family = "unix")]
+                ServerListener::Unix(listener) => {
+                    run_unix_server(listener, settings, server_state)
+                        .await
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    Ok(join_handles)
+}
+
+enum ServerListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(target_family = "unix")]
+    Unix(tokio::net::UnixListener),
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::address::Address;
+    use crate::config::{parse_server_config, update_config};
+    use crate::resolver::NativeResolver;
+
+    // Connects each destination to one end of a duplex stream, and hands the other end to the
+    // test.
+    struct DuplexConnector {
+        destinations: mpsc::UnboundedSender<(NetLocation, DuplexStream)>,
+    }
+
+    #[async_trait]
+    impl DestinationConnector for DuplexConnector {
+        async fn connect(
+            &self,
+            remote_location: &NetLocation,
+        ) -> std::io::Result<Box<dyn AsyncStream>> {
+            let (stream, destination_stream) = tokio::io::duplex(4096);
+            self.destinations
+                .send((remote_location.clone(), destination_stream))
+                .map_err(|_| std::io::Error::other("test stopped accepting destinations"))?;
+            Ok(Box::new(stream))
         }
-    }))
+    }
+
+    // Starts a connection through the server config, returning the client's end of it and the
+    // destinations that it connects to.
+    fn start_connection(
+        config: &str,
+    ) -> (
+        DuplexStream,
+        mpsc::UnboundedReceiver<(NetLocation, DuplexStream)>,
+        JoinHandle<std::io::Result<()>>,
+    ) {
+        let mut config = parse_server_config("test.yaml", config).unwrap();
+        update_config(&mut config).unwrap();
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let (destinations, destination_receiver) = mpsc::unbounded_channel();
+        let connector = DuplexConnector { destinations };
+        let handle = tokio::spawn(run_server_connection(
+            config,
+            server_stream,
+            "127.0.0.1:40000".parse().unwrap(),
+            Arc::new(NativeResolver::new()),
+            Some(Arc::new(connector)),
+        ));
+        (client_stream, destination_receiver, handle)
+    }
+
+    // Checks that data goes both ways between the client and the destination.
+    async fn check_forwarded(client_stream: &mut DuplexStream, destination: &mut DuplexStream) {
+        client_stream.write_all(b"ping").await.unwrap();
+        let mut data = [0u8; 4];
+        destination.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"ping");
+
+        destination.write_all(b"pong").await.unwrap();
+        client_stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"pong");
+    }
+
+    #[tokio::test]
+    async fn socks_connection_is_forwarded_to_destination() {
+        let (mut client_stream, mut destinations, handle) = start_connection(
+            "
+name: duplex-socks
+address: 127.0.0.1:0
+protocol:
+  type: socks
+",
+        );
+
+        client_stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut response = [0u8; 2];
+        client_stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [5, 0]);
+
+        let mut request = vec![5, 1, 0, 3, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client_stream.write_all(&request).await.unwrap();
+        let mut response = [0u8; 10];
+        client_stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[0..2], [5, 0]);
+
+        let (remote_location, mut destination) = destinations.recv().await.unwrap();
+        assert_eq!(
+            remote_location,
+            NetLocation::new(Address::Hostname("example.com".to_string()), 443)
+        );
+        check_forwarded(&mut client_stream, &mut destination).await;
+
+        // The connection isn't added to the registry.
+        assert!(connection_registry()
+            .connections()
+            .iter()
+            .all(|info| info.server != "duplex-socks"));
+
+        drop(client_stream);
+        drop(destination);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect_uses_overridden_destination() {
+        let (mut client_stream, mut destinations, handle) = start_connection(
+            "
+name: duplex-http
+address: 127.0.0.1:0
+protocol:
+  type: http
+rules:
+  - mask: example.com
+    action: allow
+    override_address: 10.0.0.1:8080
+    client_proxy: direct
+",
+        );
+
+        client_stream
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let response = b"HTTP/1.1 200 Connection established\r\n\r\n";
+        let mut data = vec![0u8; response.len()];
+        client_stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, response);
+
+        let (remote_location, mut destination) = destinations.recv().await.unwrap();
+        assert_eq!(
+            remote_location,
+            NetLocation::new(Address::Ipv4("10.0.0.1".parse().unwrap()), 8080)
+        );
+        check_forwarded(&mut client_stream, &mut destination).await;
+
+        drop(client_stream);
+        drop(destination);
+        handle.await.unwrap().unwrap();
+    }
 }
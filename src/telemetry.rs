@@ -0,0 +1,476 @@
+// Exports a span for each connection to an OpenTelemetry collector, as OTLP over HTTP with the
+// JSON encoding. The resolve and upstream connect steps of a connection are exported as its child
+// spans. The JSON is built and posted here rather than with the opentelemetry crates, so the otel
+// feature adds no dependencies. Without the otel feature, connections never have a span, and the functions here only
+// run the futures that they're given.
+//
+// Span context is only ever sent to the collector. Nothing is added to the proxied streams, so
+// they're the same whether or not a connection is traced.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+#[cfg(feature = "otel")]
+use std::sync::OnceLock;
+#[cfg(feature = "otel")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "otel")]
+use log::{debug, warn};
+#[cfg(feature = "otel")]
+use parking_lot::Mutex;
+#[cfg(feature = "otel")]
+use serde_json::{json, Value};
+#[cfg(feature = "otel")]
+use tokio::sync::mpsc;
+
+use crate::config::TelemetryConfig;
+use crate::connection_registry::ConnectionInfo;
+#[cfg(feature = "otel")]
+use crate::http_client::http_request;
+
+#[cfg(feature = "otel")]
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(feature = "otel")]
+const MAX_BATCH_SIZE: usize = 512;
+// Spans are dropped rather than queued when the collector can't keep up with full batches.
+#[cfg(feature = "otel")]
+const MAX_QUEUED_SPANS: usize = 8192;
+
+// OTLP span kinds.
+#[cfg(feature = "otel")]
+const SPAN_KIND_INTERNAL: u8 = 1;
+#[cfg(feature = "otel")]
+const SPAN_KIND_SERVER: u8 = 2;
+#[cfg(feature = "otel")]
+const SPAN_KIND_CLIENT: u8 = 3;
+#[cfg(feature = "otel")]
+const STATUS_CODE_ERROR: u8 = 2;
+
+#[cfg(feature = "otel")]
+struct SpanExporter {
+    sample_rate: f64,
+    sender: mpsc::Sender<Value>,
+}
+
+#[cfg(feature = "otel")]
+static EXPORTER: OnceLock<SpanExporter> = OnceLock::new();
+
+#[cfg(feature = "otel")]
+tokio::task_local! {
+    // The span of the connection that the task is running, which child spans are added to.
+    static CURRENT_SPAN: Arc<ConnectionSpan>;
+}
+
+#[cfg(feature = "otel")]
+pub async fn start_span_exporter(config: TelemetryConfig) -> std::io::Result<()> {
+    let TelemetryConfig {
+        endpoint,
+        sample_rate,
+        service_name,
+    } = config;
+
+    println!("Exporting connection spans to {}", &endpoint);
+
+    let (sender, mut receiver) = mpsc::channel(MAX_QUEUED_SPANS);
+    if EXPORTER
+        .set(SpanExporter {
+            sample_rate,
+            sender,
+        })
+        .is_err()
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "span exporter was already started",
+        ));
+    }
+
+    tokio::spawn(async move {
+        // Spans are sent once a full batch is queued, and otherwise at each interval, so the
+        // queue only fills up when the collector is slower than the connections.
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + EXPORT_INTERVAL,
+            EXPORT_INTERVAL,
+        );
+        let mut spans = Vec::with_capacity(MAX_BATCH_SIZE);
+        loop {
+            tokio::select! {
+                span = receiver.recv() => match span {
+                    Some(span) => {
+                        spans.push(span);
+                        if spans.len() < MAX_BATCH_SIZE {
+                            continue;
+                        }
+                    }
+                    None => {
+                        if !spans.is_empty() {
+                            export_spans(&endpoint, &service_name, spans).await;
+                        }
+                        break;
+                    }
+                },
+                _ = interval.tick() => {
+                    if spans.is_empty() {
+                        continue;
+                    }
+                }
+            }
+            let batch = std::mem::replace(&mut spans, Vec::with_capacity(MAX_BATCH_SIZE));
+            export_spans(&endpoint, &service_name, batch).await;
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+async fn export_spans(endpoint: &str, service_name: &str, spans: Vec<Value>) {
+    let span_count = spans.len();
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [string_attribute("service.name", service_name)],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "shoes" },
+                "spans": spans,
+            }],
+        }],
+    });
+    let result = http_request(
+        "POST",
+        endpoint,
+        &[("Content-Type", "application/json")],
+        body.to_string().as_bytes(),
+    )
+    .await;
+    match result {
+        Ok(response) if response.is_success() => {
+            debug!("Exported {} connection spans", span_count);
+        }
+        Ok(response) => warn!(
+            "Failed to export {} connection spans: {}",
+            span_count, response.status_line
+        ),
+        Err(e) => warn!("Failed to export {} connection spans: {}", span_count, e),
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub async fn start_span_exporter(_config: TelemetryConfig) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "telemetry_settings needs shoes to be built with the otel feature",
+    ))
+}
+
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+struct ChildSpan {
+    name: &'static str,
+    target: String,
+    span_id: [u8; 8],
+    start_unix_nanos: u64,
+    end_unix_nanos: u64,
+    error: Option<String>,
+}
+
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+pub struct ConnectionSpan {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    start_unix_nanos: u64,
+    children: Mutex<Vec<ChildSpan>>,
+}
+
+#[cfg(feature = "otel")]
+impl ConnectionSpan {
+    // Starts the span of a new connection, unless spans aren't exported or the connection isn't
+    // sampled.
+    pub fn start() -> Option<Arc<Self>> {
+        let exporter = EXPORTER.get()?;
+        if rand::random::<f64>() >= exporter.sample_rate {
+            return None;
+        }
+        Some(Arc::new(Self {
+            trace_id: rand::random(),
+            span_id: rand::random(),
+            start_unix_nanos: unix_nanos_now(),
+            children: Mutex::new(vec![]),
+        }))
+    }
+
+    // Queues the span, along with its children, to be exported.
+    pub fn finish(&self, info: &ConnectionInfo, error: Option<&std::io::Error>) {
+        let exporter = match EXPORTER.get() {
+            Some(exporter) => exporter,
+            None => return,
+        };
+        let trace_id = hex_string(&self.trace_id);
+        let span_id = hex_string(&self.span_id);
+
+        let mut attributes = vec![
+            string_attribute("shoes.server", &info.server),
+            string_attribute("shoes.protocol", &info.protocol),
+            string_attribute("shoes.source", &info.source),
+            string_attribute("shoes.trace_id", &info.trace_id),
+            int_attribute("shoes.bytes_sent", info.bytes_sent()),
+            int_attribute("shoes.bytes_received", info.bytes_received()),
+        ];
+        if let Some(destination) = info.destination() {
+            attributes.push(string_attribute(
+                "shoes.destination",
+                &destination.to_string(),
+            ));
+        }
+        if let Some(matched_rule) = info.matched_rule() {
+            attributes.push(string_attribute("shoes.rule", &matched_rule));
+        }
+        // The data phase starts once the destination is connected.
+        let events = match info.setup_duration() {
+            Some(setup_duration) => vec![json!({
+                "timeUnixNano": (self.start_unix_nanos + setup_duration.as_nanos() as u64)
+                    .to_string(),
+                "name": "setup complete",
+            })],
+            None => vec![],
+        };
+
+        let mut spans = vec![span_json(
+            &trace_id,
+            &span_id,
+            None,
+            &format!("{} connection", info.protocol),
+            SPAN_KIND_SERVER,
+            self.start_unix_nanos,
+            unix_nanos_now(),
+            attributes,
+            events,
+            error.map(ToString::to_string).as_deref(),
+        )];
+        for child in self.children.lock().iter() {
+            let kind = if child.name == "connect" {
+                SPAN_KIND_CLIENT
+            } else {
+                SPAN_KIND_INTERNAL
+            };
+            spans.push(span_json(
+                &trace_id,
+                &hex_string(&child.span_id),
+                Some(&span_id),
+                child.name,
+                kind,
+                child.start_unix_nanos,
+                child.end_unix_nanos,
+                vec![string_attribute("shoes.target", &child.target)],
+                vec![],
+                child.error.as_deref(),
+            ));
+        }
+
+        for span in spans {
+            if exporter.sender.try_send(span).is_err() {
+                debug!("Dropping connection span, the export queue is full");
+            }
+        }
+    }
+}
+
+// Connections never have a span without the otel feature.
+#[cfg(not(feature = "otel"))]
+#[derive(Debug)]
+pub enum ConnectionSpan {}
+
+#[cfg(not(feature = "otel"))]
+impl ConnectionSpan {
+    pub fn start() -> Option<Arc<Self>> {
+        None
+    }
+
+    pub fn finish(&self, _info: &ConnectionInfo, _error: Option<&std::io::Error>) {
+        match *self {}
+    }
+}
+
+// Runs the connection's future with its span, if any, as the parent of the child spans that the
+// future records.
+#[cfg(feature = "otel")]
+pub async fn with_span<F: Future>(span: Option<Arc<ConnectionSpan>>, future: F) -> F::Output {
+    match span {
+        Some(span) => CURRENT_SPAN.scope(span, future).await,
+        None => future.await,
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub async fn with_span<F: Future>(_span: Option<Arc<ConnectionSpan>>, future: F) -> F::Output {
+    future.await
+}
+
+// Records the future as a child span of the current connection, when it's traced. The target is
+// only formatted then.
+#[cfg(feature = "otel")]
+pub async fn child_span<T, F>(name: &'static str, target: &impl Display, future: F) -> F::Output
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    let span = match CURRENT_SPAN.try_with(Arc::clone) {
+        Ok(span) => span,
+        Err(_) => return future.await,
+    };
+    let start_unix_nanos = unix_nanos_now();
+    let result = future.await;
+    span.children.lock().push(ChildSpan {
+        name,
+        target: target.to_string(),
+        span_id: rand::random(),
+        start_unix_nanos,
+        end_unix_nanos: unix_nanos_now(),
+        error: result.as_ref().err().map(ToString::to_string),
+    });
+    result
+}
+
+#[cfg(not(feature = "otel"))]
+pub async fn child_span<T, F>(_name: &'static str, _target: &impl Display, future: F) -> F::Output
+where
+    F: Future<Output = std::io::Result<T>>,
+{
+    future.await
+}
+
+#[cfg(feature = "otel")]
+#[allow(clippy::too_many_arguments)]
+fn span_json(
+    trace_id: &str,
+    span_id: &str,
+    parent_span_id: Option<&str>,
+    name: &str,
+    kind: u8,
+    start_unix_nanos: u64,
+    end_unix_nanos: u64,
+    attributes: Vec<Value>,
+    events: Vec<Value>,
+    error: Option<&str>,
+) -> Value {
+    let mut span = json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "name": name,
+        "kind": kind,
+        // 64-bit integers are strings in the JSON encoding.
+        "startTimeUnixNano": start_unix_nanos.to_string(),
+        "endTimeUnixNano": end_unix_nanos.to_string(),
+        "attributes": attributes,
+        "events": events,
+    });
+    if let Some(parent_span_id) = parent_span_id {
+        span["parentSpanId"] = json!(parent_span_id);
+    }
+    if let Some(error) = error {
+        span["status"] = json!({ "code": STATUS_CODE_ERROR, "message": error });
+    }
+    span
+}
+
+#[cfg(feature = "otel")]
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+#[cfg(feature = "otel")]
+fn int_attribute(key: &str, value: u64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+#[cfg(feature = "otel")]
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(feature = "otel")]
+fn unix_nanos_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Answers each export request, and sends on the number of spans that it had.
+    async fn run_collector(listener: TcpListener, batch_sizes: mpsc::UnboundedSender<usize>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let body = loop {
+                let mut buf = [0u8; 65536];
+                let len = stream.read(&mut buf).await.unwrap();
+                assert!(len > 0, "export request was cut off");
+                request.extend_from_slice(&buf[0..len]);
+                let request_str = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = request_str.split_once("\r\n\r\n") {
+                    let content_length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(|len| len.parse::<usize>().unwrap())
+                        })
+                        .unwrap();
+                    if body.len() == content_length {
+                        break body.to_string();
+                    }
+                }
+            };
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            let spans = body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                .as_array()
+                .unwrap()
+                .len();
+            if batch_sizes.send(spans).is_err() {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn full_batch_is_exported_before_the_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (batch_sender, mut batch_sizes) = mpsc::unbounded_channel();
+        tokio::spawn(run_collector(listener, batch_sender));
+
+        start_span_exporter(TelemetryConfig {
+            endpoint,
+            sample_rate: 1.0,
+            service_name: "shoes-test".to_string(),
+        })
+        .await
+        .unwrap();
+
+        let sender = EXPORTER.get().unwrap().sender.clone();
+        for i in 0..2 * MAX_BATCH_SIZE + 10 {
+            sender.send(json!({ "name": i })).await.unwrap();
+        }
+
+        // Both full batches go out right away, and the remaining spans wait for the interval.
+        for _ in 0..2 {
+            let batch_size = tokio::time::timeout(EXPORT_INTERVAL / 2, batch_sizes.recv())
+                .await
+                .expect("full batch wasn't exported right away")
+                .unwrap();
+            assert_eq!(batch_size, MAX_BATCH_SIZE);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use std::sync::OnceLock;
 
+use crate::config::{RuntimeConfig, RuntimeFlavor};
+
 static NUM_THREADS: OnceLock<usize> = OnceLock::new();
 
 pub fn set_num_threads(num_threads: usize) {
@@ -9,3 +11,32 @@ pub fn set_num_threads(num_threads: usize) {
 pub fn get_num_threads() -> usize {
     *NUM_THREADS.get().unwrap()
 }
+
+// The number of threads that run tasks.
+pub fn runtime_worker_threads(runtime_config: &RuntimeConfig) -> usize {
+    match runtime_config.flavor {
+        RuntimeFlavor::MultiThread => runtime_config.worker_threads.unwrap_or_else(num_cpus::get),
+        RuntimeFlavor::CurrentThread => 1,
+    }
+}
+
+pub fn build_runtime(runtime_config: &RuntimeConfig) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = match runtime_config.flavor {
+        RuntimeFlavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+    };
+    builder.enable_all();
+    if let Some(worker_threads) = runtime_config.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    if let Some(max_blocking_threads) = runtime_config.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads);
+    }
+    if let Some(ref thread_name) = runtime_config.thread_name {
+        builder.thread_name(thread_name);
+    }
+    if let Some(thread_stack_size) = runtime_config.thread_stack_size {
+        builder.thread_stack_size(thread_stack_size);
+    }
+    builder.build()
+}
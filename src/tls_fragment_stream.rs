@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+const TLS_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+enum FragmentState {
+    // Waiting for the first write, which is the ClientHello.
+    Initial,
+    Writing {
+        records: Vec<Vec<u8>>,
+        // The length of the original record, which is reported as written once all the
+        // fragments are written.
+        consumed_len: usize,
+        index: usize,
+        offset: usize,
+        sleep_future: Option<Pin<Box<tokio::time::Sleep>>>,
+    },
+    Done,
+}
+
+// Splits the first TLS record written to the stream into records with at most fragment_size
+// bytes of handshake data each, and writes them one at a time. Anything after that is written
+// unchanged.
+pub struct TlsFragmentStream {
+    stream: Box<dyn AsyncStream>,
+    fragment_size: usize,
+    delay: Duration,
+    state: FragmentState,
+}
+
+impl TlsFragmentStream {
+    pub fn new(stream: Box<dyn AsyncStream>, fragment_size: usize, delay: Duration) -> Self {
+        Self {
+            stream,
+            fragment_size,
+            delay,
+            state: FragmentState::Initial,
+        }
+    }
+
+    // Returns the fragmented records, or None if buf doesn't start with a whole handshake
+    // record.
+    fn fragment(&self, buf: &[u8]) -> Option<(Vec<Vec<u8>>, usize)> {
+        if buf.len() < TLS_HEADER_LEN || buf[0] != CONTENT_TYPE_HANDSHAKE {
+            return None;
+        }
+        let payload_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let record_len = TLS_HEADER_LEN + payload_len;
+        if buf.len() < record_len {
+            return None;
+        }
+        let records = buf[TLS_HEADER_LEN..record_len]
+            .chunks(self.fragment_size)
+            .map(|chunk| {
+                let mut record = Vec::with_capacity(TLS_HEADER_LEN + chunk.len());
+                record.extend_from_slice(&buf[0..3]);
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect();
+        Some((records, record_len))
+    }
+}
+
+impl AsyncRead for TlsFragmentStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsFragmentStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let FragmentState::Initial = this.state {
+            this.state = match this.fragment(buf) {
+                Some((records, consumed_len)) => FragmentState::Writing {
+                    records,
+                    consumed_len,
+                    index: 0,
+                    offset: 0,
+                    sleep_future: None,
+                },
+                None => FragmentState::Done,
+            };
+        }
+
+        let FragmentState::Writing {
+            ref records,
+            consumed_len,
+            ref mut index,
+            ref mut offset,
+            ref mut sleep_future,
+        } = this.state
+        else {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        };
+
+        loop {
+            if let Some(ref mut sleep) = sleep_future {
+                ready!(sleep.as_mut().poll(cx));
+                *sleep_future = None;
+            }
+            if *index == records.len() {
+                this.state = FragmentState::Done;
+                return Poll::Ready(Ok(consumed_len));
+            }
+
+            let record = &records[*index];
+            while *offset < record.len() {
+                let written =
+                    ready!(Pin::new(&mut this.stream).poll_write(cx, &record[*offset..]))?;
+                if written == 0 {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "failed to write TLS record fragment",
+                    )));
+                }
+                *offset += written;
+            }
+            ready!(Pin::new(&mut this.stream).poll_flush(cx))?;
+
+            *index += 1;
+            *offset = 0;
+            if *index < records.len() && !this.delay.is_zero() {
+                *sleep_future = Some(Box::pin(tokio::time::sleep(this.delay)));
+            }
+        }
+    }
+
+    // Until the ClientHello is written, vectored writes are written one slice at a time, so
+    // that the ClientHello is seen by poll_write.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        if let FragmentState::Done = self.state {
+            return Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs);
+        }
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| &**buf);
+        self.poll_write(cx, buf)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for TlsFragmentStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncStream for TlsFragmentStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+}
@@ -1,34 +1,73 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
+use log::debug;
+use rustls::server::{Accepted, ClientHello};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_rustls::LazyConfigAcceptor;
 
+use crate::acme::is_acme_challenge;
 use crate::address::NetLocation;
 use crate::async_stream::AsyncStream;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::config::{TlsFragmentConfig, UnknownSniConfig};
 use crate::option_util::NoneOrOne;
+use crate::replay_stream::ReplayStream;
+use crate::rustls_util::cert_identity;
 use crate::tcp_client_connector::TcpClientConnector;
 use crate::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
+use crate::tls_fragment_stream::TlsFragmentStream;
+use crate::tls_record_stream::TlsRecordStream;
 
 #[derive(Debug)]
 pub struct TlsServerHandler {
     sni_targets: HashMap<String, TlsServerTarget>,
     default_target: Option<TlsServerTarget>,
+    unknown_sni: UnknownSniConfig,
 }
 
+// A fatal unrecognized_name alert, which servers send for SNI hostnames that they don't host.
+const UNRECOGNIZED_NAME_ALERT: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x70];
+
+// A fatal no_application_protocol alert, which servers send when they support none of the
+// client's ALPN protocols.
+const NO_APPLICATION_PROTOCOL_ALERT: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x78];
+
+// Larger ClientHellos aren't sent by real clients.
+const MAX_CLIENT_HELLO_LEN: usize = 65536;
+
 impl TlsServerHandler {
     pub fn new(
         sni_targets: HashMap<String, TlsServerTarget>,
         default_target: Option<TlsServerTarget>,
+        unknown_sni: UnknownSniConfig,
     ) -> Self {
+        // SNI hostnames are matched case-insensitively.
+        let sni_targets = sni_targets
+            .into_iter()
+            .map(|(sni_hostname, target)| (sni_hostname.to_ascii_lowercase(), target))
+            .collect();
         Self {
             sni_targets,
             default_target,
+            unknown_sni,
         }
     }
+
+    // Exact hostnames take precedence over wildcards, which match a single label like
+    // certificate wildcards do.
+    fn find_sni_target(&self, hostname: &str) -> Option<&TlsServerTarget> {
+        let hostname = hostname.to_ascii_lowercase();
+        if let Some(target) = self.sni_targets.get(&hostname) {
+            return Some(target);
+        }
+        let (_, parent) = hostname.split_once('.')?;
+        self.sni_targets.get(&format!("*.{}", parent))
+    }
 }
 
 #[async_trait]
@@ -37,9 +76,9 @@ impl TcpServerHandler for TlsServerHandler {
         &self,
         server_stream: Box<dyn AsyncStream>,
     ) -> std::io::Result<TcpServerSetupResult> {
-        let acceptor = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), server_stream);
-        let start_handshake = acceptor.await?;
-        let client_hello = start_handshake.client_hello();
+        let mut server_stream = server_stream;
+        let (client_hello_data, accepted) = read_client_hello(&mut server_stream).await?;
+        let client_hello = accepted.client_hello();
         let target = match client_hello.server_name() {
             None => match self.default_target {
                 Some(ref t) => t,
@@ -50,40 +89,189 @@ impl TcpServerHandler for TlsServerHandler {
                     ));
                 }
             },
-            Some(hostname) => match self.sni_targets.get(hostname) {
-                Some(ref t) => t,
-                None => match self.default_target {
-                    Some(ref t) => t,
-                    None => {
+            Some(hostname) => match self.find_sni_target(hostname) {
+                Some(t) => t,
+                None => match self.unknown_sni {
+                    UnknownSniConfig::Default => match self.default_target {
+                        Some(ref t) => t,
+                        None => {
+                            return Err(std::io::Error::other("No default target for unknown SNI"));
+                        }
+                    },
+                    UnknownSniConfig::Reject => {
+                        let _ = server_stream.write_all(&UNRECOGNIZED_NAME_ALERT).await;
+                        let _ = server_stream.shutdown().await;
                         return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "No default target for unknown SNI",
+                            std::io::ErrorKind::ConnectionRefused,
+                            format!("rejected unknown SNI hostname {}", hostname),
                         ));
                     }
+                    UnknownSniConfig::Fallback(ref fallback) => {
+                        // The decoy location terminates TLS itself, so the ClientHello is replayed
+                        // to it unchanged.
+                        return Ok(TcpServerSetupResult::Fallback {
+                            remote_location: fallback.clone(),
+                            stream: server_stream,
+                            need_initial_flush: false,
+                            initial_remote_data: client_hello_data.into_boxed_slice(),
+                        });
+                    }
                 },
             },
         };
 
-        let tls_stream: Box<dyn AsyncStream> = Box::new(
-            start_handshake
-                .into_stream_with(target.server_config.clone(), |server_conn| {
-                    server_conn.set_buffer_limit(Some(32768));
-                })
-                .await?,
-        );
+        let acme_challenge =
+            target.acme_challenge_config.is_some() && is_acme_challenge(&client_hello);
+        let mut server_config = &target.server_config;
+        let mut default_alpn_protocol = None;
+        if !acme_challenge && !offers_alpn_protocol(&client_hello, &server_config.alpn_protocols) {
+            match target.unknown_alpn {
+                // rustls rejects clients that offer other protocols, and accepts clients that
+                // don't offer any without one.
+                None => (),
+                Some(UnknownAlpnAction::Reject) => {
+                    let _ = server_stream
+                        .write_all(&NO_APPLICATION_PROTOCOL_ALERT)
+                        .await;
+                    let _ = server_stream.shutdown().await;
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "rejected client without a supported ALPN protocol",
+                    ));
+                }
+                Some(UnknownAlpnAction::Default {
+                    ref alpn_protocol,
+                    server_config: ref default_server_config,
+                }) => {
+                    server_config = default_server_config;
+                    default_alpn_protocol = Some(alpn_protocol.clone());
+                }
+            }
+        }
+
+        // The ClientHello is parsed again by the acceptor, which needs it to start the handshake.
+        let server_stream: Box<dyn AsyncStream> = Box::new(ReplayStream::new(
+            server_stream,
+            client_hello_data.into_boxed_slice(),
+        ));
+        let server_stream: Box<dyn AsyncStream> = if target.vision_flow {
+            Box::new(TlsRecordStream::new(server_stream))
+        } else {
+            server_stream
+        };
+        let acceptor = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), server_stream);
+        let start_handshake = acceptor.await?;
+
+        if let Some(ref challenge_config) = target.acme_challenge_config {
+            if acme_challenge {
+                // The ACME server only checks the certificate, and then closes the connection.
+                let mut tls_stream = start_handshake
+                    .into_stream(challenge_config.clone())
+                    .await?;
+                let _ = tls_stream.shutdown().await;
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "answered ACME TLS-ALPN-01 challenge",
+                ));
+            }
+        }
+
+        let tls_stream = start_handshake
+            .into_stream_with(server_config.clone(), |server_conn| {
+                server_conn.set_buffer_limit(Some(32768));
+            })
+            .await?;
+        let alpn_protocol = tls_stream
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .and_then(|alpn| std::str::from_utf8(alpn).ok())
+            .map(str::to_string)
+            .or(default_alpn_protocol);
+        if let Some(ref alpn_protocol) = alpn_protocol {
+            debug!("Negotiated ALPN protocol {}", alpn_protocol);
+        }
+        let alpn_fallback = alpn_protocol
+            .as_ref()
+            .and_then(|alpn| target.alpn_fallbacks.get(alpn));
+        // The certificate was verified during the handshake, when client certificates are
+        // requested.
+        let client_identity = tls_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| cert_identity(&cert.0));
+        // Client identity rules take precedence over ALPN protocol rules.
+        let override_proxy_provider = match client_identity
+            .as_ref()
+            .and_then(|identity| target.client_identity_proxy_providers.get(identity))
+            .or_else(|| {
+                alpn_protocol
+                    .as_ref()
+                    .and_then(|alpn| target.alpn_proxy_providers.get(alpn))
+            }) {
+            Some(proxy_provider) => NoneOrOne::One(proxy_provider.clone()),
+            None => target.override_proxy_provider.clone(),
+        };
 
-        let mut target_setup_result = target.handler.setup_server_stream(tls_stream).await;
+        let mut target_setup_result = target
+            .handler
+            .setup_server_stream(Box::new(tls_stream))
+            .await;
+        if let Ok(TcpServerSetupResult::Fallback {
+            ref mut remote_location,
+            ref mut need_initial_flush,
+            ..
+        }) = target_setup_result.as_mut()
+        {
+            *need_initial_flush = true;
+            if let Some(alpn_fallback) = alpn_fallback {
+                *remote_location = alpn_fallback.clone();
+            }
+        }
         if let Ok(TcpServerSetupResult::TcpForward {
             ref mut need_initial_flush,
             override_proxy_provider: ref mut inner_override_proxy_provider,
+            ref mut authenticated_user,
             ..
         }) = target_setup_result.as_mut()
         {
             *need_initial_flush = true;
             if inner_override_proxy_provider.is_unspecified()
-                && !target.override_proxy_provider.is_unspecified()
+                && !override_proxy_provider.is_unspecified()
             {
-                *inner_override_proxy_provider = target.override_proxy_provider.clone();
+                *inner_override_proxy_provider = override_proxy_provider.clone();
+            }
+            if authenticated_user.is_none() {
+                *authenticated_user = client_identity.clone();
+            }
+        }
+        if let Ok(TcpServerSetupResult::HttpForward {
+            override_proxy_provider: ref mut inner_override_proxy_provider,
+            ref mut authenticated_user,
+            ..
+        }) = target_setup_result.as_mut()
+        {
+            if inner_override_proxy_provider.is_unspecified()
+                && !override_proxy_provider.is_unspecified()
+            {
+                *inner_override_proxy_provider = override_proxy_provider.clone();
+            }
+            if authenticated_user.is_none() {
+                *authenticated_user = client_identity.clone();
+            }
+        }
+
+        if let Ok(TcpServerSetupResult::MultiplexedForward {
+            override_proxy_provider: ref mut inner_override_proxy_provider,
+            ..
+        }) = target_setup_result.as_mut()
+        {
+            if inner_override_proxy_provider.is_unspecified()
+                && !override_proxy_provider.is_unspecified()
+            {
+                *inner_override_proxy_provider = override_proxy_provider.clone();
             }
         }
 
@@ -91,25 +279,82 @@ impl TcpServerHandler for TlsServerHandler {
     }
 }
 
+fn offers_alpn_protocol(client_hello: &ClientHello, alpn_protocols: &[Vec<u8>]) -> bool {
+    client_hello.alpn().is_some_and(|mut offered_protocols| {
+        offered_protocols.any(|offered| alpn_protocols.iter().any(|p| p == offered))
+    })
+}
+
+// Reads from the stream until a whole ClientHello was received, and returns the bytes that were
+// read along with the parsed ClientHello.
+async fn read_client_hello(
+    stream: &mut Box<dyn AsyncStream>,
+) -> std::io::Result<(Vec<u8>, Accepted)> {
+    let mut acceptor = rustls::server::Acceptor::default();
+    let mut client_hello_data = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "EOF while reading TLS ClientHello",
+            ));
+        }
+        client_hello_data.extend_from_slice(&buf[0..len]);
+        if client_hello_data.len() > MAX_CLIENT_HELLO_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "TLS ClientHello is too long",
+            ));
+        }
+
+        let mut data = &buf[0..len];
+        while !data.is_empty() && acceptor.read_tls(&mut data)? > 0 {}
+        match acceptor.accept() {
+            Ok(Some(accepted)) => return Ok((client_hello_data, accepted)),
+            Ok(None) => (),
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid TLS ClientHello: {}", e),
+                ));
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TlsClientHandler {
     pub client_config: Arc<rustls::ClientConfig>,
     pub server_name: rustls::client::ServerName,
+    pub fragment: Option<TlsFragmentConfig>,
     pub handler: Box<dyn TcpClientHandler>,
+    // Whether the inner VLESS stream has the Vision flow, which needs the TLS layer to be set up
+    // over a TlsRecordStream.
+    pub vision_flow: bool,
 }
 
 impl TlsClientHandler {
     pub fn new(
         client_config: Arc<rustls::ClientConfig>,
         server_name: rustls::client::ServerName,
+        fragment: Option<TlsFragmentConfig>,
         handler: Box<dyn TcpClientHandler>,
     ) -> Self {
         Self {
             client_config,
             server_name,
+            fragment,
             handler,
+            vision_flow: false,
         }
     }
+
+    pub fn with_vision_flow(mut self, vision_flow: bool) -> Self {
+        self.vision_flow = vision_flow;
+        self
+    }
 }
 
 #[async_trait]
@@ -120,6 +365,19 @@ impl TcpClientHandler for TlsClientHandler {
         client_stream: Box<dyn AsyncStream>,
         remote_location: NetLocation,
     ) -> std::io::Result<TcpClientSetupResult> {
+        let client_stream: Box<dyn AsyncStream> = match self.fragment {
+            Some(ref fragment) => Box::new(TlsFragmentStream::new(
+                client_stream,
+                fragment.size,
+                Duration::from_millis(fragment.delay_ms),
+            )),
+            None => client_stream,
+        };
+        let client_stream: Box<dyn AsyncStream> = if self.vision_flow {
+            Box::new(TlsRecordStream::new(client_stream))
+        } else {
+            client_stream
+        };
         let connector: tokio_rustls::TlsConnector = self.client_config.clone().into();
         let tls_stream = Box::new(
             connector
@@ -138,6 +396,30 @@ impl TcpClientHandler for TlsClientHandler {
 #[derive(Debug)]
 pub struct TlsServerTarget {
     pub server_config: Arc<rustls::ServerConfig>,
+    // Answers ACME TLS-ALPN-01 challenges, when the certificate is obtained with ACME.
+    pub acme_challenge_config: Option<Arc<rustls::ServerConfig>>,
+    pub unknown_alpn: Option<UnknownAlpnAction>,
     pub handler: Box<dyn TcpServerHandler>,
     pub override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
+    // Rules for clients by the identity in their certificate, in place of the override rules.
+    pub client_identity_proxy_providers:
+        HashMap<String, Arc<ClientProxySelector<TcpClientConnector>>>,
+    // Rules by the negotiated ALPN protocol, in place of the override rules.
+    pub alpn_proxy_providers: HashMap<String, Arc<ClientProxySelector<TcpClientConnector>>>,
+    pub alpn_fallbacks: HashMap<String, NetLocation>,
+    // Whether the inner VLESS stream has the Vision flow, which needs the TLS layer to be set up
+    // over a TlsRecordStream.
+    pub vision_flow: bool,
+}
+
+// What happens when a client offers none of the target's ALPN protocols.
+#[derive(Debug)]
+pub enum UnknownAlpnAction {
+    Reject,
+    // The client is handled as if it negotiated alpn_protocol, and the handshake uses a config
+    // without ALPN so that it isn't rejected.
+    Default {
+        alpn_protocol: String,
+        server_config: Arc<rustls::ServerConfig>,
+    },
 }
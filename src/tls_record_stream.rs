@@ -0,0 +1,165 @@
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+const TLS_HEADER_LEN: usize = 5;
+
+// Enough for the largest record that TLS 1.2 allows, so that a record is usually received in a
+// single read.
+const BUFFER_SIZE: usize = TLS_HEADER_LEN + 16384 + 2048;
+
+// The stream beneath a TLS stream, which never reads past the end of the current TLS record.
+// Since the TLS layer only gets whole records, the bytes after the last record it processed are
+// still here, and the stream can be read and written directly from then on. The XTLS Vision flow
+// of VLESS does that once the proxied connection carries its own TLS traffic.
+pub struct TlsRecordStream {
+    stream: Box<dyn AsyncStream>,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    // The bytes of the current record that weren't read yet, or 0 before a record header.
+    record_remaining: usize,
+}
+
+impl TlsRecordStream {
+    pub fn new(stream: Box<dyn AsyncStream>) -> Self {
+        Self {
+            stream,
+            buf: vec![0u8; BUFFER_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            record_remaining: 0,
+        }
+    }
+
+    // Reads more data after the buffered bytes, returning false on EOF.
+    fn poll_fill_buf(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        } else if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        let mut read_buf = ReadBuf::new(&mut self.buf[self.end..]);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf))?;
+        let len = read_buf.filled().len();
+        self.end += len;
+        Poll::Ready(Ok(len > 0))
+    }
+
+    fn read_buffered(&mut self, buf: &mut ReadBuf<'_>, limit: usize) {
+        let len = std::cmp::min(std::cmp::min(self.end - self.start, buf.remaining()), limit);
+        buf.put_slice(&self.buf[self.start..self.start + len]);
+        self.start += len;
+    }
+
+    // Reads directly, without the TLS layer. This starts with the bytes that were received after
+    // the last record that the TLS layer read.
+    pub fn poll_read_raw(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.start < self.end {
+            self.read_buffered(buf, usize::MAX);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for TlsRecordStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.record_remaining == 0 {
+                if this.end - this.start < TLS_HEADER_LEN {
+                    if !ready!(this.poll_fill_buf(cx))? {
+                        // The TLS layer reports a truncated record.
+                        this.read_buffered(buf, usize::MAX);
+                        return Poll::Ready(Ok(()));
+                    }
+                    continue;
+                }
+                let header = &this.buf[this.start..this.start + TLS_HEADER_LEN];
+                let payload_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+                this.record_remaining = TLS_HEADER_LEN + payload_len;
+            }
+
+            if this.start == this.end && !ready!(this.poll_fill_buf(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+
+            let previous_start = this.start;
+            this.read_buffered(buf, this.record_remaining);
+            this.record_remaining -= this.start - previous_start;
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl AsyncWrite for TlsRecordStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for TlsRecordStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncStream for TlsRecordStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+
+    fn tls_record_stream(&mut self) -> Option<&mut TlsRecordStream> {
+        Some(self)
+    }
+}
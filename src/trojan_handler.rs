@@ -13,8 +13,14 @@ use crate::shadowsocks::{
 };
 use crate::socks_handler::{read_location, write_location, CMD_CONNECT, CMD_UDP_ASSOCIATE};
 use crate::tcp_handler::{
-    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+    auth_failure_error, TcpClientHandler, TcpClientSetupResult, TcpServerHandler,
+    TcpServerSetupResult,
 };
+use crate::udp_over_tcp::{
+    is_udp_over_tcp_location, setup_udp_over_tcp_server_stream, unsupported_udp_over_tcp_error,
+    UdpOverTcpFraming, UdpOverTcpStream,
+};
+use crate::util::Redacted;
 
 #[derive(Debug)]
 struct ShadowsocksData {
@@ -22,10 +28,22 @@ struct ShadowsocksData {
     key: Arc<Box<dyn ShadowsocksKey>>,
 }
 
-#[derive(Debug)]
 pub struct TrojanTcpHandler {
     password_hash: Box<[u8]>,
     shadowsocks_data: Option<ShadowsocksData>,
+    fallback: Option<NetLocation>,
+    udp_over_tcp: bool,
+}
+
+impl std::fmt::Debug for TrojanTcpHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TrojanTcpHandler")
+            .field("password_hash", &Redacted)
+            .field("shadowsocks_data", &self.shadowsocks_data)
+            .field("fallback", &self.fallback)
+            .field("udp_over_tcp", &self.udp_over_tcp)
+            .finish()
+    }
 }
 
 impl TrojanTcpHandler {
@@ -35,6 +53,7 @@ impl TrojanTcpHandler {
             let ShadowsocksConfig {
                 cipher,
                 password: shadowsocks_password,
+                ..
             } = config;
             let cipher: ShadowsocksCipher = cipher.as_str().into();
             let key: Arc<Box<dyn ShadowsocksKey>> = Arc::new(Box::new(DefaultKey::new(
@@ -47,8 +66,23 @@ impl TrojanTcpHandler {
         Self {
             password_hash,
             shadowsocks_data,
+            fallback: None,
+            udp_over_tcp: false,
         }
     }
+
+    // Connections with a wrong password are forwarded to the fallback, along with the bytes
+    // that were already read.
+    pub fn with_fallback(mut self, fallback: Option<NetLocation>) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    // Accepts UDP associate requests, and streams to the UDP-over-TCP hostname.
+    pub fn with_udp_over_tcp(mut self, udp_over_tcp: bool) -> Self {
+        self.udp_over_tcp = udp_over_tcp;
+        self
+    }
 }
 
 #[async_trait]
@@ -73,14 +107,31 @@ impl TcpServerHandler for TrojanTcpHandler {
         }
 
         let mut received_hash = [0u8; 56];
-        server_stream.read_exact(&mut received_hash).await?;
-        for (b1, b2) in self.password_hash.iter().zip(received_hash.iter()) {
-            if b1 != b2 {
+        let mut read_len = 0;
+        while read_len < received_hash.len() {
+            let len = server_stream.read(&mut received_hash[read_len..]).await?;
+            if len == 0 {
+                break;
+            }
+            read_len += len;
+        }
+
+        if read_len < received_hash.len() || self.password_hash[..] != received_hash[..] {
+            if let Some(ref fallback) = self.fallback {
+                return Ok(TcpServerSetupResult::Fallback {
+                    remote_location: fallback.clone(),
+                    stream: server_stream,
+                    need_initial_flush: false,
+                    initial_remote_data: received_hash[0..read_len].into(),
+                });
+            }
+            if read_len < received_hash.len() {
                 return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Invalid password hash",
+                    std::io::ErrorKind::UnexpectedEof,
+                    "EOF while reading password hash",
                 ));
             }
+            return Err(auth_failure_error("Invalid password hash"));
         }
 
         let mut request_prefix = [0u8; 3];
@@ -96,14 +147,14 @@ impl TcpServerHandler for TrojanTcpHandler {
             ));
         }
 
-        if request_prefix[2] == CMD_UDP_ASSOCIATE {
+        if request_prefix[2] == CMD_UDP_ASSOCIATE && !self.udp_over_tcp {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 "UDP associate command is not supported",
             ));
         }
 
-        if request_prefix[2] != CMD_CONNECT {
+        if request_prefix[2] != CMD_CONNECT && request_prefix[2] != CMD_UDP_ASSOCIATE {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 format!("Invalid command code: {}", request_prefix[2]),
@@ -122,6 +173,26 @@ impl TcpServerHandler for TrojanTcpHandler {
             ));
         }
 
+        if request_prefix[2] == CMD_UDP_ASSOCIATE {
+            // The request's location is unused, since each datagram has its own.
+            return Ok(TcpServerSetupResult::MultidirectionalUdpForward {
+                stream: Box::new(UdpOverTcpStream::new(
+                    server_stream,
+                    UdpOverTcpFraming::Trojan,
+                )),
+                need_initial_flush: false,
+            });
+        }
+
+        if self.udp_over_tcp {
+            if is_udp_over_tcp_location(&remote_location) {
+                return setup_udp_over_tcp_server_stream(server_stream).await;
+            }
+            if let Some(e) = unsupported_udp_over_tcp_error(&remote_location) {
+                return Err(e);
+            }
+        }
+
         Ok(TcpServerSetupResult::TcpForward {
             remote_location,
             stream: server_stream,
@@ -129,6 +200,7 @@ impl TcpServerHandler for TrojanTcpHandler {
             connection_success_response: None,
             initial_remote_data: None,
             override_proxy_provider: NoneOrOne::Unspecified,
+            authenticated_user: None,
         })
     }
 }
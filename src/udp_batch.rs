@@ -0,0 +1,212 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::task::{Context, Poll};
+
+use futures::ready;
+use socket2::SockAddr;
+use tokio::io::{Interest, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::config::MAX_UDP_BATCH_SIZE;
+use crate::util::allocate_vec;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// Receives and sends datagrams on a socket with recvmmsg and sendmmsg, so that a burst of
+// datagrams takes a single syscall instead of one for each datagram. Every datagram keeps its
+// own source or destination address.
+pub struct UdpBatch {
+    batch_size: usize,
+    // Allocated on the first receive, since many sessions only ever send.
+    recv_bufs: Vec<Box<[u8]>>,
+    // The length and source of each datagram from the last recvmmsg.
+    received: Vec<(usize, SocketAddr)>,
+    recv_index: usize,
+    // Kept after sending, so that their allocations are reused.
+    send_bufs: Vec<Vec<u8>>,
+    send_addrs: Vec<SockAddr>,
+    send_count: usize,
+    send_index: usize,
+}
+
+impl UdpBatch {
+    pub fn new(batch_size: usize) -> Self {
+        assert!(batch_size > 0 && batch_size <= MAX_UDP_BATCH_SIZE);
+        Self {
+            batch_size,
+            recv_bufs: vec![],
+            received: Vec::with_capacity(batch_size),
+            recv_index: 0,
+            send_bufs: vec![],
+            send_addrs: vec![],
+            send_count: 0,
+            send_index: 0,
+        }
+    }
+
+    pub fn poll_recv_from(
+        &mut self,
+        socket: &UdpSocket,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        if self.recv_index == self.received.len() {
+            if self.recv_bufs.is_empty() {
+                self.recv_bufs = (0..self.batch_size)
+                    .map(|_| allocate_vec(MAX_DATAGRAM_SIZE).into_boxed_slice())
+                    .collect();
+            }
+            loop {
+                ready!(socket.poll_recv_ready(cx))?;
+                let recv_bufs = &mut self.recv_bufs;
+                let received = &mut self.received;
+                match socket.try_io(Interest::READABLE, || {
+                    recv_batch(socket.as_raw_fd(), recv_bufs, received)
+                }) {
+                    Ok(()) => break,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+            self.recv_index = 0;
+        }
+
+        let (len, source_addr) = self.received[self.recv_index];
+        // Like recv_from, the datagram is truncated if it doesn't fit.
+        let len = std::cmp::min(len, buf.remaining());
+        buf.put_slice(&self.recv_bufs[self.recv_index][0..len]);
+        self.recv_index += 1;
+        Poll::Ready(Ok(source_addr))
+    }
+
+    // Queues the datagram, which is sent by poll_flush or once the batch is full.
+    pub fn poll_send_to(
+        &mut self,
+        socket: &UdpSocket,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target_addr: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        if self.send_count == self.batch_size {
+            ready!(self.poll_flush(socket, cx))?;
+        }
+        if self.send_count == self.send_bufs.len() {
+            self.send_bufs.push(buf.to_vec());
+            self.send_addrs.push(target_addr.into());
+        } else {
+            let send_buf = &mut self.send_bufs[self.send_count];
+            send_buf.clear();
+            send_buf.extend_from_slice(buf);
+            self.send_addrs[self.send_count] = target_addr.into();
+        }
+        self.send_count += 1;
+        Poll::Ready(Ok(()))
+    }
+
+    pub fn poll_flush(&mut self, socket: &UdpSocket, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.send_index < self.send_count {
+            ready!(socket.poll_send_ready(cx))?;
+            let send_bufs = &self.send_bufs[self.send_index..self.send_count];
+            let send_addrs = &self.send_addrs[self.send_index..self.send_count];
+            match socket.try_io(Interest::WRITABLE, || {
+                send_batch(socket.as_raw_fd(), send_bufs, send_addrs)
+            }) {
+                Ok(sent) => self.send_index += sent,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    self.send_index = 0;
+                    self.send_count = 0;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        self.send_index = 0;
+        self.send_count = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn recv_batch(
+    fd: RawFd,
+    recv_bufs: &mut [Box<[u8]>],
+    received: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<()> {
+    // SAFETY: these are plain C structs, which are valid when zeroed.
+    let mut iovecs: [libc::iovec; MAX_UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut addrs: [libc::sockaddr_storage; MAX_UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+    for (((recv_buf, iovec), addr), msg) in recv_bufs
+        .iter_mut()
+        .zip(iovecs.iter_mut())
+        .zip(addrs.iter_mut())
+        .zip(msgs.iter_mut())
+    {
+        *iovec = libc::iovec {
+            iov_base: recv_buf.as_mut_ptr().cast(),
+            iov_len: recv_buf.len(),
+        };
+        msg.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+        msg.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_hdr.msg_iov = iovec;
+        msg.msg_hdr.msg_iovlen = 1;
+    }
+
+    let ret = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            recv_bufs.len() as libc::c_uint,
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    received.clear();
+    for (msg, addr) in msgs.iter().zip(addrs.iter()).take(ret as usize) {
+        // SAFETY: the kernel wrote an address of msg_namelen bytes.
+        let source_addr = unsafe { SockAddr::new(*addr, msg.msg_hdr.msg_namelen) };
+        let source_addr = source_addr.as_socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received a datagram without an IP source address",
+            )
+        })?;
+        received.push((msg.msg_len as usize, source_addr));
+    }
+    Ok(())
+}
+
+// Returns how many datagrams were sent, which can be fewer than given.
+fn send_batch(fd: RawFd, send_bufs: &[Vec<u8>], send_addrs: &[SockAddr]) -> io::Result<usize> {
+    // SAFETY: these are plain C structs, which are valid when zeroed.
+    let mut iovecs: [libc::iovec; MAX_UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+    for (((send_buf, send_addr), iovec), msg) in send_bufs
+        .iter()
+        .zip(send_addrs.iter())
+        .zip(iovecs.iter_mut())
+        .zip(msgs.iter_mut())
+    {
+        // The kernel only reads from these.
+        *iovec = libc::iovec {
+            iov_base: send_buf.as_ptr() as *mut libc::c_void,
+            iov_len: send_buf.len(),
+        };
+        msg.msg_hdr.msg_name = send_addr.as_ptr() as *mut libc::c_void;
+        msg.msg_hdr.msg_namelen = send_addr.len();
+        msg.msg_hdr.msg_iov = iovec;
+        msg.msg_hdr.msg_iovlen = 1;
+    }
+
+    let ret = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), send_bufs.len() as libc::c_uint, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
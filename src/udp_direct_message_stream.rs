@@ -1,10 +1,13 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
+use futures::ready;
 use tokio::io::ReadBuf;
 use tokio::net::UdpSocket;
 
@@ -14,27 +17,140 @@ use crate::async_stream::{
     AsyncSourcedMessageStream, AsyncWriteTargetedMessage,
 };
 use crate::resolver::Resolver;
+use crate::socket_util::new_udp_socket;
+#[cfg(target_os = "linux")]
+use crate::udp_batch::UdpBatch;
 
 type ResolveFuture = Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>>;
 
-/// A thin wrapper around a directly connecting UdpSocket to support hostname resolution.
-pub struct UdpDirectMessageStream {
+struct SymmetricSocket {
     socket: UdpSocket,
+    last_active: Instant,
+}
+
+enum UdpSockets {
+    // A single socket is used for every destination, and datagrams from any source are
+    // delivered.
+    FullCone(UdpSocket),
+    // Each destination gets its own socket, which only delivers datagrams from that
+    // destination.
+    Symmetric {
+        bind_interface: Option<String>,
+        dscp: Option<u8>,
+        // How long a destination's socket is kept after it was last used.
+        idle_timeout: Duration,
+        sockets: HashMap<SocketAddr, SymmetricSocket>,
+        // Woken when a socket is added, so that the reader also polls the new socket.
+        read_waker: Option<Waker>,
+    },
+}
+
+/// A thin wrapper around directly connecting UdpSockets to support hostname resolution.
+pub struct UdpDirectMessageStream {
+    sockets: UdpSockets,
     resolver: Arc<dyn Resolver>,
     location_cache: HashMap<NetLocation, SocketAddr>,
     resolving_locations: HashMap<NetLocation, ResolveFuture>,
+    // Only used for full cone sockets, when datagrams are batched.
+    #[cfg(target_os = "linux")]
+    batch: Option<UdpBatch>,
 }
 
 impl UdpDirectMessageStream {
-    pub fn new(socket: UdpSocket, resolver: Arc<dyn Resolver>) -> Self {
+    // Datagrams are received and sent batch_size at a time on Linux, and one at a time
+    // elsewhere.
+    pub fn new(socket: UdpSocket, batch_size: usize, resolver: Arc<dyn Resolver>) -> Self {
+        #[allow(unused_mut)]
+        let mut stream = Self::with_sockets(UdpSockets::FullCone(socket), resolver);
+        #[cfg(target_os = "linux")]
+        if batch_size > 1 {
+            stream.batch = Some(UdpBatch::new(batch_size));
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = batch_size;
+        stream
+    }
+
+    pub fn new_symmetric(
+        bind_interface: Option<String>,
+        dscp: Option<u8>,
+        idle_timeout: Duration,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
+        Self::with_sockets(
+            UdpSockets::Symmetric {
+                bind_interface,
+                dscp,
+                idle_timeout,
+                sockets: HashMap::new(),
+                read_waker: None,
+            },
+            resolver,
+        )
+    }
+
+    fn with_sockets(sockets: UdpSockets, resolver: Arc<dyn Resolver>) -> Self {
         Self {
-            socket,
+            sockets,
             resolver,
             // TODO: use a LRU cache
             location_cache: HashMap::new(),
             resolving_locations: HashMap::new(),
+            #[cfg(target_os = "linux")]
+            batch: None,
         }
     }
+
+    fn poll_resolve(
+        &mut self,
+        cx: &mut Context<'_>,
+        target: &NetLocation,
+    ) -> Poll<std::io::Result<SocketAddr>> {
+        // TODO: check if NetLocation is already an IP first?
+        if let Some(s) = target.to_socket_addr_nonblocking() {
+            return Poll::Ready(Ok(s));
+        }
+        if let Some(s) = self.location_cache.get(target) {
+            return Poll::Ready(Ok(*s));
+        }
+        let resolve_results = match self.resolving_locations.get_mut(target) {
+            None => {
+                let mut resolve_future: ResolveFuture = self.resolver.resolve_location(target);
+                match resolve_future.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        self.resolving_locations
+                            .insert(target.clone(), resolve_future);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(result) => result,
+                }
+            }
+            Some(resolve_future) => match resolve_future.as_mut().poll(cx) {
+                Poll::Pending => {
+                    return Poll::Pending;
+                }
+                Poll::Ready(result) => {
+                    self.resolving_locations.remove(target).unwrap();
+                    result
+                }
+            },
+        };
+        let socket_addrs = resolve_results?;
+        if socket_addrs.is_empty() {
+            return Poll::Ready(Err(std::io::Error::other(format!(
+                "Failed to resolve {}",
+                target
+            ))));
+        }
+        let socket_addr = socket_addrs.into_iter().next().unwrap();
+        self.location_cache.insert(target.clone(), socket_addr);
+        Poll::Ready(Ok(socket_addr))
+    }
+}
+
+// Sockets are bound to [::], so replies from IPv4 addresses have IPv4-mapped sources.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
 }
 
 impl AsyncReadSourcedMessage for UdpDirectMessageStream {
@@ -43,7 +159,46 @@ impl AsyncReadSourcedMessage for UdpDirectMessageStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<SocketAddr>> {
-        self.get_mut().socket.poll_recv_from(cx, buf)
+        let this = self.get_mut();
+        match this.sockets {
+            UdpSockets::FullCone(ref socket) => {
+                #[cfg(target_os = "linux")]
+                if let Some(ref mut batch) = this.batch {
+                    return batch.poll_recv_from(socket, cx, buf);
+                }
+                socket.poll_recv_from(cx, buf)
+            }
+            UdpSockets::Symmetric {
+                ref idle_timeout,
+                ref mut sockets,
+                ref mut read_waker,
+                ..
+            } => {
+                let now = Instant::now();
+                // Dropping a socket closes it.
+                sockets.retain(|_, s| now.duration_since(s.last_active) < *idle_timeout);
+
+                for (target_addr, entry) in sockets.iter_mut() {
+                    loop {
+                        match entry.socket.poll_recv_from(cx, buf) {
+                            Poll::Pending => break,
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Ready(Ok(source_addr)) => {
+                                if canonical_addr(source_addr) == *target_addr {
+                                    entry.last_active = now;
+                                    return Poll::Ready(Ok(source_addr));
+                                }
+                                // Drop datagrams from anywhere other than the destination.
+                                buf.clear();
+                            }
+                        }
+                    }
+                }
+
+                *read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
     }
 }
 
@@ -54,72 +209,62 @@ impl AsyncWriteTargetedMessage for UdpDirectMessageStream {
         buf: &[u8],
         target: &NetLocation,
     ) -> Poll<std::io::Result<()>> {
-        // TODO: check if NetLocation is already an IP first?
         let this = self.get_mut();
-        let socket_addr = match target.to_socket_addr_nonblocking() {
-            Some(s) => s,
-            None => {
-                match this.location_cache.get(target) {
-                    Some(s) => s.clone(),
-                    None => {
-                        let resolve_results = match this.resolving_locations.get_mut(target) {
-                            None => {
-                                let mut resolve_future: Pin<
-                                    Box<
-                                        dyn Future<Output = std::io::Result<Vec<SocketAddr>>>
-                                            + Send,
-                                    >,
-                                > = this.resolver.resolve_location(target);
-                                match resolve_future.as_mut().poll(cx) {
-                                    Poll::Pending => {
-                                        this.resolving_locations
-                                            .insert(target.clone(), resolve_future);
-                                        return Poll::Pending;
-                                    }
-                                    Poll::Ready(result) => result,
-                                }
-                            }
-                            Some(resolve_future) => match resolve_future.as_mut().poll(cx) {
-                                Poll::Pending => {
-                                    return Poll::Pending;
-                                }
-                                Poll::Ready(result) => {
-                                    this.resolving_locations.remove(target).unwrap();
-                                    result
-                                }
-                            },
-                        };
-                        match resolve_results {
-                            Err(e) => {
-                                return Poll::Ready(Err(e));
-                            }
-                            Ok(socket_addrs) => {
-                                if socket_addrs.is_empty() {
-                                    return Poll::Ready(Err(std::io::Error::new(
-                                        std::io::ErrorKind::Other,
-                                        format!("Failed to resolve {}", target),
-                                    )));
-                                }
-                                let socket_addr = socket_addrs.into_iter().next().unwrap();
-                                // TODO: switch to using entry()
-                                this.location_cache
-                                    .insert(target.clone(), socket_addr.clone());
-                                socket_addr
-                            }
+        let socket_addr = ready!(this.poll_resolve(cx, target))?;
+        let socket = match this.sockets {
+            UdpSockets::FullCone(ref socket) => {
+                #[cfg(target_os = "linux")]
+                if let Some(ref mut batch) = this.batch {
+                    return batch.poll_send_to(socket, cx, buf, socket_addr);
+                }
+                socket
+            }
+            UdpSockets::Symmetric {
+                ref bind_interface,
+                dscp,
+                ref mut sockets,
+                ref mut read_waker,
+                ..
+            } => {
+                let socket_addr = canonical_addr(socket_addr);
+                let entry = match sockets.entry(socket_addr) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        // Each destination needs its own port, so bind_port doesn't apply.
+                        let socket = new_udp_socket(bind_interface.clone(), None, false, dscp)?;
+                        if let Some(waker) = read_waker.take() {
+                            waker.wake();
                         }
+                        entry.insert(SymmetricSocket {
+                            socket,
+                            last_active: Instant::now(),
+                        })
                     }
-                }
+                };
+                entry.last_active = Instant::now();
+                &entry.socket
             }
         };
         // TODO: do we need to check usize result here?
-        this.socket
+        socket
             .poll_send_to(cx, buf, socket_addr)
             .map(|result| result.map(|_| ()))
     }
 }
 
 impl AsyncFlushMessage for UdpDirectMessageStream {
-    fn poll_flush_message(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        #[cfg(not(target_os = "linux"))]
+        let _ = cx;
+        #[cfg(target_os = "linux")]
+        {
+            let this = self.get_mut();
+            if let (UdpSockets::FullCone(ref socket), Some(ref mut batch)) =
+                (&this.sockets, &mut this.batch)
+            {
+                return batch.poll_flush(socket, cx);
+            }
+        }
         Poll::Ready(Ok(()))
     }
 }
@@ -127,9 +272,10 @@ impl AsyncFlushMessage for UdpDirectMessageStream {
 impl AsyncShutdownMessage for UdpDirectMessageStream {
     fn poll_shutdown_message(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+        // Send anything still queued.
+        self.poll_flush_message(cx)
     }
 }
 
@@ -0,0 +1,484 @@
+// Datagrams carried over a proxy's stream, for protocols that can't relay UDP natively.
+//
+// UDP-over-TCP v2 (as implemented by sing-box and used by Shadowsocks clients): the client opens
+// a stream to sp.v2.udp-over-tcp.arpa, and sends a request of
+//
+//   is_connect: u8, destination: SOCKS address
+//
+// When is_connect is 0, each datagram in either direction is
+//
+//   address: SOCKS address, length: u16 big endian, payload
+//
+// where the client writes the destination and the server writes the source. When is_connect is 1,
+// every datagram goes to the request's destination, and each is only
+//
+//   length: u16 big endian, payload
+//
+// Trojan UDP associate: the request has command 3, and each datagram in either direction is
+//
+//   address: SOCKS address, length: u16 big endian, CRLF, payload
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::ready;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncReadSourcedMessage,
+    AsyncReadTargetedMessage, AsyncShutdownMessage, AsyncSourcedMessageStream, AsyncStream,
+    AsyncTargetedMessageStream, AsyncWriteMessage, AsyncWriteSourcedMessage,
+    AsyncWriteTargetedMessage,
+};
+use crate::socks_handler::{
+    read_location, read_location_from_slice, write_location_to_vec, ADDR_TYPE_DOMAIN_NAME,
+    ADDR_TYPE_IPV4, ADDR_TYPE_IPV6,
+};
+use crate::tcp_handler::TcpServerSetupResult;
+
+pub const UDP_OVER_TCP_V2_HOSTNAME: &str = "sp.v2.udp-over-tcp.arpa";
+// The first version has a different address encoding, and isn't supported.
+const UDP_OVER_TCP_V1_HOSTNAME: &str = "sp.udp-over-tcp.arpa";
+
+// Large enough for the largest datagram and its header.
+const MAX_FRAME_LEN: usize = 65535 + 262 + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpOverTcpFraming {
+    UdpOverTcp,
+    Trojan,
+}
+
+pub fn udp_over_tcp_location() -> NetLocation {
+    NetLocation::new(Address::Hostname(UDP_OVER_TCP_V2_HOSTNAME.to_string()), 0)
+}
+
+pub fn is_udp_over_tcp_location(location: &NetLocation) -> bool {
+    location.address().hostname() == Some(UDP_OVER_TCP_V2_HOSTNAME)
+}
+
+// Reads the UDP-over-TCP request from a stream that a client opened to the magic hostname.
+pub async fn setup_udp_over_tcp_server_stream(
+    mut server_stream: Box<dyn AsyncStream>,
+) -> std::io::Result<TcpServerSetupResult> {
+    let is_connect = server_stream.read_u8().await?;
+    let destination = read_location(&mut server_stream).await?;
+    if is_connect == 1 {
+        Ok(TcpServerSetupResult::BidirectionalUdpForward {
+            remote_location: destination.clone(),
+            stream: Box::new(UdpOverTcpStream::new_connected(server_stream, destination)),
+        })
+    } else {
+        Ok(TcpServerSetupResult::MultidirectionalUdpForward {
+            stream: Box::new(UdpOverTcpStream::new(
+                server_stream,
+                UdpOverTcpFraming::UdpOverTcp,
+            )),
+            need_initial_flush: false,
+        })
+    }
+}
+
+// Writes the UDP-over-TCP request to a stream opened to the magic hostname. A destination makes
+// it a connect request.
+pub async fn setup_udp_over_tcp_client_stream(
+    mut client_stream: Box<dyn AsyncStream>,
+    destination: Option<&NetLocation>,
+) -> std::io::Result<UdpOverTcpStream> {
+    let mut request = vec![destination.is_some() as u8];
+    request.extend_from_slice(&write_location_to_vec(
+        destination.unwrap_or(&NetLocation::UNSPECIFIED),
+    ));
+    client_stream.write_all(&request).await?;
+    Ok(match destination {
+        Some(destination) => UdpOverTcpStream::new_connected(client_stream, destination.clone()),
+        None => UdpOverTcpStream::new(client_stream, UdpOverTcpFraming::UdpOverTcp),
+    })
+}
+
+pub fn unsupported_udp_over_tcp_error(location: &NetLocation) -> Option<std::io::Error> {
+    if location.address().hostname() == Some(UDP_OVER_TCP_V1_HOSTNAME) {
+        Some(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "UDP-over-TCP version 1 is not supported, clients must use version 2",
+        ))
+    } else {
+        None
+    }
+}
+
+pub struct UdpOverTcpStream {
+    stream: Box<dyn AsyncStream>,
+    framing: UdpOverTcpFraming,
+    // The destination of every datagram for connect requests, where datagrams have no address.
+    connected_location: Option<NetLocation>,
+
+    read_buf: Box<[u8]>,
+    read_start: usize,
+    read_end: usize,
+    is_eof: bool,
+
+    write_buf: Vec<u8>,
+    write_offset: usize,
+}
+
+impl UdpOverTcpStream {
+    pub fn new(stream: Box<dyn AsyncStream>, framing: UdpOverTcpFraming) -> Self {
+        Self {
+            stream,
+            framing,
+            connected_location: None,
+            read_buf: vec![0u8; MAX_FRAME_LEN].into_boxed_slice(),
+            read_start: 0,
+            read_end: 0,
+            is_eof: false,
+            write_buf: Vec::with_capacity(MAX_FRAME_LEN),
+            write_offset: 0,
+        }
+    }
+
+    fn new_connected(stream: Box<dyn AsyncStream>, location: NetLocation) -> Self {
+        Self {
+            connected_location: Some(location),
+            ..Self::new(stream, UdpOverTcpFraming::UdpOverTcp)
+        }
+    }
+
+    // The length of the frame at the start of data, or None if more data is needed to know.
+    fn frame_len(&self, data: &[u8]) -> std::io::Result<Option<(usize, usize)>> {
+        let header_len = if self.connected_location.is_some() {
+            0
+        } else {
+            match data.first() {
+                None => return Ok(None),
+                Some(&ADDR_TYPE_IPV4) => 7,
+                Some(&ADDR_TYPE_IPV6) => 19,
+                Some(&ADDR_TYPE_DOMAIN_NAME) => match data.get(1) {
+                    Some(len) => 4 + *len as usize,
+                    None => return Ok(None),
+                },
+                Some(address_type) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unknown address type in UDP frame: {}", address_type),
+                    ));
+                }
+            }
+        };
+        let length_end = header_len + 2;
+        if data.len() < length_end {
+            return Ok(None);
+        }
+        let payload_len =
+            u16::from_be_bytes(data[header_len..length_end].try_into().unwrap()) as usize;
+        let payload_start = match self.framing {
+            UdpOverTcpFraming::UdpOverTcp => length_end,
+            UdpOverTcpFraming::Trojan => length_end + 2,
+        };
+        let frame_len = payload_start + payload_len;
+        if data.len() < frame_len {
+            return Ok(None);
+        }
+        Ok(Some((payload_start, frame_len)))
+    }
+
+    // Reads the next datagram into buf, returning its address, or None at EOF. Datagrams that
+    // don't fit in buf are dropped.
+    fn poll_read_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<Option<NetLocation>>> {
+        loop {
+            let data = &self.read_buf[self.read_start..self.read_end];
+            if let Some((payload_start, frame_len)) = self.frame_len(data)? {
+                let location = match self.connected_location {
+                    Some(ref location) => location.clone(),
+                    None => read_location_from_slice(data)?.0,
+                };
+                if self.framing == UdpOverTcpFraming::Trojan
+                    && data[payload_start - 2..payload_start] != [0x0d, 0x0a]
+                {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "missing CRLF in trojan UDP frame",
+                    )));
+                }
+                let frame_start = self.read_start;
+                self.read_start += frame_len;
+                let payload = &self.read_buf[frame_start + payload_start..frame_start + frame_len];
+                // Empty datagrams would be read as EOF.
+                if payload.is_empty() {
+                    continue;
+                }
+                if payload.len() > buf.remaining() {
+                    debug!("Dropping oversized UDP-over-TCP datagram for {}", location);
+                    continue;
+                }
+                buf.put_slice(payload);
+                return Poll::Ready(Ok(Some(location)));
+            }
+
+            if self.is_eof {
+                if self.read_start < self.read_end {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "EOF in the middle of a UDP frame",
+                    )));
+                }
+                return Poll::Ready(Ok(None));
+            }
+
+            if self.read_start > 0 {
+                self.read_buf.copy_within(self.read_start..self.read_end, 0);
+                self.read_end -= self.read_start;
+                self.read_start = 0;
+            }
+
+            let mut read_buf = ReadBuf::new(&mut self.read_buf[self.read_end..]);
+            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf))?;
+            let len = read_buf.filled().len();
+            if len == 0 {
+                self.is_eof = true;
+            }
+            self.read_end += len;
+        }
+    }
+
+    // Queues a datagram, first writing out any previously queued one.
+    fn poll_write_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        location: Option<&NetLocation>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_buf(cx))?;
+
+        if buf.len() > u16::MAX as usize {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("datagram is too large: {} bytes", buf.len()),
+            )));
+        }
+
+        self.write_buf.clear();
+        self.write_offset = 0;
+        if let Some(location) = location {
+            self.write_buf
+                .extend_from_slice(&write_location_to_vec(location));
+        }
+        self.write_buf
+            .extend_from_slice(&(buf.len() as u16).to_be_bytes());
+        if self.framing == UdpOverTcpFraming::Trojan {
+            self.write_buf.extend_from_slice(&[0x0d, 0x0a]);
+        }
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.write_offset < self.write_buf.len() {
+            let len = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_offset..])
+            )?;
+            if len == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_offset += len;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // The address written with datagrams, which connect requests leave out.
+    fn frame_location<'a>(&self, location: &'a NetLocation) -> Option<&'a NetLocation> {
+        if self.connected_location.is_some() {
+            None
+        } else {
+            Some(location)
+        }
+    }
+}
+
+impl AsyncReadTargetedMessage for UdpOverTcpStream {
+    fn poll_read_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<NetLocation>> {
+        let this = self.get_mut();
+        let location = ready!(this.poll_read_frame(cx, buf))?;
+        Poll::Ready(Ok(location.unwrap_or(NetLocation::UNSPECIFIED)))
+    }
+}
+
+impl AsyncWriteSourcedMessage for UdpOverTcpStream {
+    fn poll_write_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        source: &SocketAddr,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let source = NetLocation::from_socket_addr(*source);
+        let location = this.frame_location(&source);
+        this.poll_write_frame(cx, buf, location)
+    }
+}
+
+impl AsyncReadSourcedMessage for UdpOverTcpStream {
+    fn poll_read_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<SocketAddr>> {
+        let this = self.get_mut();
+        loop {
+            let location = match ready!(this.poll_read_frame(cx, buf))? {
+                Some(location) => location,
+                None => return Poll::Ready(Ok(SocketAddr::from(([0, 0, 0, 0], 0)))),
+            };
+            match location.to_socket_addr_nonblocking() {
+                Some(source) => return Poll::Ready(Ok(source)),
+                None => {
+                    debug!(
+                        "Dropping UDP-over-TCP datagram from unresolved source {}",
+                        location
+                    );
+                    buf.clear();
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWriteTargetedMessage for UdpOverTcpStream {
+    fn poll_write_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: &NetLocation,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let location = this.frame_location(target);
+        this.poll_write_frame(cx, buf, location)
+    }
+}
+
+impl AsyncReadMessage for UdpOverTcpStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_read_frame(cx, buf))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWriteMessage for UdpOverTcpStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.connected_location.is_none() {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no target for UDP-over-TCP message",
+            )));
+        }
+        this.poll_write_frame(cx, buf, None)
+    }
+}
+
+impl AsyncFlushMessage for UdpOverTcpStream {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+}
+
+impl AsyncShutdownMessage for UdpOverTcpStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for UdpOverTcpStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncMessageStream for UdpOverTcpStream {}
+
+impl AsyncTargetedMessageStream for UdpOverTcpStream {}
+
+impl AsyncSourcedMessageStream for UdpOverTcpStream {}
+
+// Stands in for the server stream when a client stream is set up for relaying datagrams, where
+// there is no server stream to forward unexpected data to.
+pub struct NoServerStream;
+
+impl AsyncRead for NoServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for NoServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "no server stream to write to",
+        )))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for NoServerStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for NoServerStream {}
@@ -0,0 +1,282 @@
+// Receives datagrams for protocols that run directly over UDP, which is only Shadowsocks.
+// Each client address gets its own relay session.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::config::{BindLocation, ConfigSelection, ServerConfig, ServerProxyConfig};
+use crate::connection_registry::{connection_registry, ConnectionInfo};
+use crate::copy_multidirectional_message::copy_multidirectional_message;
+use crate::health_server::accepting_listener;
+use crate::privilege_util::wait_until_accepting;
+use crate::resolver::{create_resolver, Resolver};
+use crate::shadowsocks::{
+    ShadowsocksUdpCipher, ShadowsocksUdpPacket, ShadowsocksUdpServerStream, UdpPacketSender,
+};
+use crate::socket_util::set_dscp;
+use crate::source_filter::SourceFilter;
+use crate::tcp_client_connector::TcpClientConnector;
+use crate::tcp_handler_util::create_tcp_client_proxy_selector;
+use crate::udp_session_table::UdpSessionTable;
+
+// The number of decrypted packets that can wait for a session before more are dropped.
+const SESSION_QUEUE_SIZE: usize = 64;
+
+type SessionSenders = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<ShadowsocksUdpPacket>>>>;
+
+// Shared by the sockets of every bind address of a server.
+struct UdpServerState {
+    server_label: String,
+    protocol_name: String,
+    cipher: Arc<ShadowsocksUdpCipher>,
+    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    udp_sessions: Arc<UdpSessionTable>,
+    resolver: Arc<dyn Resolver>,
+    source_filter: SourceFilter,
+}
+
+async fn run_udp_server(
+    socket: UdpSocket,
+    server_state: Arc<UdpServerState>,
+) -> std::io::Result<()> {
+    let UdpServerState {
+        ref server_label,
+        ref protocol_name,
+        ref cipher,
+        ref client_proxy_selector,
+        ref udp_sessions,
+        ref resolver,
+        ref source_filter,
+    } = *server_state;
+    let socket = Arc::new(socket);
+    let session_senders: SessionSenders = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; 65535];
+
+    wait_until_accepting().await;
+    let _accepting = accepting_listener();
+
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("[{}] Receive failed: {}", server_label, e);
+                continue;
+            }
+        };
+
+        if !source_filter.is_allowed(addr.ip()) {
+            debug!(
+                "[{}] Dropped packet from disallowed source {}",
+                server_label, addr
+            );
+            continue;
+        }
+
+        let packet = match cipher.decrypt_packet(UdpPacketSender::Client, &mut buf[0..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("[{}] Invalid packet from {}: {}", server_label, addr, e);
+                continue;
+            }
+        };
+
+        let packet = {
+            let mut senders = session_senders.lock();
+            match senders.get(&addr) {
+                Some(sender) => match sender.try_send(packet) {
+                    Ok(()) => continue,
+                    Err(TrySendError::Full(_)) => {
+                        debug!("[{}] Session for {} is full", server_label, addr);
+                        continue;
+                    }
+                    Err(TrySendError::Closed(packet)) => {
+                        senders.remove(&addr);
+                        packet
+                    }
+                },
+                None => packet,
+            }
+        };
+
+        let (sender, receiver) = mpsc::channel(SESSION_QUEUE_SIZE);
+        sender.try_send(packet).unwrap();
+        session_senders.lock().insert(addr, sender.clone());
+
+        let server_stream =
+            ShadowsocksUdpServerStream::new(socket.clone(), addr, cipher.clone(), receiver);
+        let cloned_selector = client_proxy_selector.clone();
+        let cloned_resolver = resolver.clone();
+        let cloned_udp_sessions = udp_sessions.clone();
+        let cloned_senders = session_senders.clone();
+        let cloned_label = server_label.clone();
+        let connection = connection_registry().register(
+            server_label.clone(),
+            format!("{}:{}", addr.ip(), addr.port()),
+            Some(addr.ip()),
+            protocol_name.clone(),
+        );
+        tokio::spawn(async move {
+            let connection_info = connection.info();
+            let result = connection_info
+                .run_until_closed(Box::pin(process_session(
+                    server_stream,
+                    cloned_selector,
+                    cloned_resolver,
+                    cloned_udp_sessions,
+                    connection_info,
+                )))
+                .await;
+            let log_label = connection_info.log_label();
+            drop(connection);
+
+            // Packets that arrive later start a new session.
+            let mut senders = cloned_senders.lock();
+            if senders.get(&addr).is_some_and(|s| s.same_channel(&sender)) {
+                senders.remove(&addr);
+            }
+            drop(senders);
+
+            if let Err(e) = result {
+                error!(
+                    "[{}] {} finished with error: {:?}",
+                    cloned_label, log_label, e
+                );
+            } else {
+                debug!("[{}] {} finished successfully", cloned_label, log_label);
+            }
+        });
+    }
+}
+
+async fn process_session(
+    mut server_stream: ShadowsocksUdpServerStream,
+    client_proxy_selector: Arc<ClientProxySelector<TcpClientConnector>>,
+    resolver: Arc<dyn Resolver>,
+    udp_sessions: Arc<UdpSessionTable>,
+    connection: &ConnectionInfo,
+) -> std::io::Result<()> {
+    match client_proxy_selector.default_decision() {
+        ConnectDecision::Allow {
+            client_proxy,
+            remote_location: _,
+            active_connection: _active_connection,
+        } => {
+            connection.set_client_proxy(client_proxy.label().to_string());
+            let mut client_stream = client_proxy
+                .create_udp_stream(udp_sessions.config(), resolver)
+                .await?;
+            connection.mark_setup_complete();
+
+            let idle_timeout = udp_sessions.config().idle_timeout();
+            udp_sessions
+                .run_session(copy_multidirectional_message(
+                    &mut server_stream,
+                    &mut client_stream,
+                    false,
+                    false,
+                    idle_timeout,
+                    connection,
+                ))
+                .await
+        }
+        ConnectDecision::Block => {
+            warn!("Blocked UDP session, because the default action is to block.");
+            Ok(())
+        }
+    }
+}
+
+pub async fn start_udp_server(config: ServerConfig) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let server_label = config.label();
+    let ServerConfig {
+        name,
+        bind_locations,
+        udp_settings,
+        resolver_settings,
+        allow_sources,
+        deny_sources,
+        dscp,
+        protocol,
+        rules,
+        ..
+    } = config;
+
+    match name {
+        Some(name) => println!(
+            "Starting {} UDP server {} at {}",
+            &protocol, name, &bind_locations
+        ),
+        None => println!("Starting {} UDP server at {}", &protocol, &bind_locations),
+    }
+    let protocol_name = protocol.to_string();
+
+    let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
+    // We should always have a direct entry.
+    assert!(!rules.is_empty());
+
+    let bind_addresses = bind_locations
+        .into_vec()
+        .into_iter()
+        .map(|bind_location| match bind_location {
+            BindLocation::Address(a) => a.to_socket_addr(),
+            BindLocation::Path(_) => {
+                panic!("Cannot listen on path, UDP does not have unix domain socket support");
+            }
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    // The protocol was checked when the config was validated.
+    let cipher = match protocol {
+        ServerProxyConfig::Shadowsocks(ref shadowsocks_config) => {
+            Arc::new(ShadowsocksUdpCipher::from_config(shadowsocks_config)?)
+        }
+        _ => {
+            panic!("UDP transport is only supported for shadowsocks");
+        }
+    };
+
+    let client_proxy_selector = Arc::new(create_tcp_client_proxy_selector(rules));
+    connection_registry().set_selector(server_label.clone(), client_proxy_selector.clone());
+
+    let udp_sessions = Arc::new(UdpSessionTable::new(
+        server_label.clone(),
+        udp_settings.unwrap_or_default(),
+    ));
+    connection_registry().set_udp_session_table(server_label.clone(), udp_sessions.clone());
+    let server_state = Arc::new(UdpServerState {
+        server_label,
+        protocol_name,
+        cipher,
+        client_proxy_selector,
+        udp_sessions,
+        resolver: create_resolver(resolver_settings.as_ref()),
+        source_filter: SourceFilter::new(allow_sources.into_vec(), deny_sources.into_vec()),
+    });
+
+    let mut join_handles = vec![];
+    for bind_address in bind_addresses {
+        let socket = UdpSocket::bind(bind_address).await?;
+        if let Some(dscp) = dscp {
+            set_dscp(
+                socket2::SockRef::from(&socket),
+                bind_address.is_ipv6(),
+                dscp,
+            )?;
+        }
+
+        let server_state = server_state.clone();
+        join_handles.push(tokio::spawn(async move {
+            run_udp_server(socket, server_state).await.unwrap();
+        }));
+    }
+    Ok(join_handles)
+}
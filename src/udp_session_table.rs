@@ -0,0 +1,97 @@
+// Keeps track of the UDP sessions relayed by a server, so that the number of sessions can be
+// limited. When a new session would go over the limit, the oldest session is closed.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use crate::config::UdpConfig;
+
+pub struct UdpSessionTable {
+    server_label: String,
+    config: UdpConfig,
+    next_session_id: AtomicU64,
+    // Session ids increase, so the first entry is the oldest session.
+    sessions: Mutex<BTreeMap<u64, Arc<Notify>>>,
+}
+
+impl UdpSessionTable {
+    pub fn new(server_label: String, config: UdpConfig) -> Self {
+        Self {
+            server_label,
+            config,
+            next_session_id: AtomicU64::new(1),
+            sessions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &UdpConfig {
+        &self.config
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    pub fn max_sessions(&self) -> usize {
+        self.config.max_sessions
+    }
+
+    fn start_session(self: &Arc<Self>) -> UdpSession {
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let evict_notify = Arc::new(Notify::new());
+
+        let mut sessions = self.sessions.lock();
+        while sessions.len() >= self.config.max_sessions {
+            let (oldest_id, oldest_notify) = sessions.pop_first().unwrap();
+            debug!(
+                "[{}] Too many UDP sessions ({}), closing the oldest session {}",
+                self.server_label, self.config.max_sessions, oldest_id
+            );
+            // notify_one stores a permit, so the session is closed even if it isn't waiting yet.
+            oldest_notify.notify_one();
+        }
+        sessions.insert(id, evict_notify.clone());
+
+        UdpSession {
+            id,
+            table: self.clone(),
+            evict_notify,
+        }
+    }
+
+    // Runs a UDP session until it finishes or is evicted to make room for newer sessions.
+    // The streams and sockets used by the future are dropped, and so closed, when it is
+    // evicted.
+    pub async fn run_session<F>(self: &Arc<Self>, session_future: F) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        let session = self.start_session();
+        tokio::select! {
+            result = session_future => result,
+            _ = session.evict_notify.notified() => {
+                debug!("[{}] UDP session {} evicted", self.server_label, session.id);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Removes the session from the table when dropped.
+struct UdpSession {
+    id: u64,
+    table: Arc<UdpSessionTable>,
+    evict_notify: Arc<Notify>,
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.table.sessions.lock().remove(&self.id);
+    }
+}
@@ -0,0 +1,242 @@
+// Counts the bytes that each authenticated user sends and receives, so that users who have used
+// up their quota can be rejected. Usage is added when a connection finishes, and the bytes of
+// connections that are still open are read from the connection registry.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::QuotaConfig;
+use crate::connection_registry::connection_registry;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserUsage {
+    #[serde(skip)]
+    quota_bytes: Option<u64>,
+    used_bytes: u64,
+    // seconds since the unix epoch when usage started being counted.
+    period_start: u64,
+}
+
+impl UserUsage {
+    fn new(quota_bytes: Option<u64>) -> Self {
+        Self {
+            quota_bytes,
+            used_bytes: 0,
+            period_start: unix_time_secs(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.used_bytes = 0;
+        self.period_start = unix_time_secs();
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QuotaState {
+    users: HashMap<String, UserUsage>,
+}
+
+#[derive(Debug)]
+pub struct UserUsageInfo {
+    pub username: String,
+    pub used_bytes: u64,
+    pub quota_bytes: Option<u64>,
+    pub period_start: u64,
+}
+
+pub struct UserQuotaStore {
+    users: Mutex<HashMap<String, UserUsage>>,
+    period: Mutex<Option<Duration>>,
+}
+
+pub fn user_quotas() -> &'static UserQuotaStore {
+    static INSTANCE: OnceLock<UserQuotaStore> = OnceLock::new();
+    INSTANCE.get_or_init(|| UserQuotaStore {
+        users: Mutex::new(HashMap::new()),
+        period: Mutex::new(None),
+    })
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl UserQuotaStore {
+    pub fn set_quota(&self, username: &str, quota_bytes: Option<u64>) {
+        let mut users = self.users.lock();
+        match users.get_mut(username) {
+            Some(usage) => usage.quota_bytes = quota_bytes,
+            None => {
+                users.insert(username.to_string(), UserUsage::new(quota_bytes));
+            }
+        }
+    }
+
+    pub fn add_usage(&self, username: &str, bytes: u64) {
+        let mut users = self.users.lock();
+        let usage = users
+            .entry(username.to_string())
+            .or_insert_with(|| UserUsage::new(None));
+        usage.used_bytes = usage.used_bytes.saturating_add(bytes);
+    }
+
+    // Starts a new period for users whose period has ended.
+    fn expire_periods(&self, users: &mut HashMap<String, UserUsage>) {
+        let period = match *self.period.lock() {
+            Some(period) => period.as_secs(),
+            None => return,
+        };
+        let now = unix_time_secs();
+        for (username, usage) in users.iter_mut() {
+            if now.saturating_sub(usage.period_start) >= period {
+                debug!("Quota period for user {} ended, resetting usage", username);
+                usage.reset();
+            }
+        }
+    }
+
+    pub fn is_exceeded(&self, username: &str) -> bool {
+        let (used_bytes, quota_bytes) = {
+            let mut users = self.users.lock();
+            self.expire_periods(&mut users);
+            match users.get(username) {
+                Some(usage) => (usage.used_bytes, usage.quota_bytes),
+                None => return false,
+            }
+        };
+        match quota_bytes {
+            Some(quota_bytes) => {
+                used_bytes.saturating_add(connection_registry().user_bytes(username)) >= quota_bytes
+            }
+            None => false,
+        }
+    }
+
+    // Resets the usage of a single user, or of all users when no username is given. Returns
+    // false if the user is unknown.
+    pub fn reset(&self, username: Option<&str>) -> bool {
+        let mut users = self.users.lock();
+        match username {
+            Some(username) => match users.get_mut(username) {
+                Some(usage) => {
+                    usage.reset();
+                    true
+                }
+                None => false,
+            },
+            None => {
+                for usage in users.values_mut() {
+                    usage.reset();
+                }
+                true
+            }
+        }
+    }
+
+    // Includes the bytes of connections that are still open.
+    pub fn usage(&self) -> Vec<UserUsageInfo> {
+        let mut users = self.users.lock();
+        self.expire_periods(&mut users);
+        let mut usage = users
+            .iter()
+            .map(|(username, usage)| UserUsageInfo {
+                username: username.clone(),
+                used_bytes: usage
+                    .used_bytes
+                    .saturating_add(connection_registry().user_bytes(username)),
+                quota_bytes: usage.quota_bytes,
+                period_start: usage.period_start,
+            })
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| a.username.cmp(&b.username));
+        usage
+    }
+
+    fn load(&self, state_file: &str) -> std::io::Result<()> {
+        let state_bytes = match std::fs::read(state_file) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let state: QuotaState = serde_json::from_slice(&state_bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("failed to parse quota state file {}: {}", state_file, e),
+            )
+        })?;
+
+        let mut users = self.users.lock();
+        for (username, loaded_usage) in state.users.into_iter() {
+            let usage = users
+                .entry(username)
+                .or_insert_with(|| UserUsage::new(None));
+            usage.used_bytes = loaded_usage.used_bytes;
+            usage.period_start = loaded_usage.period_start;
+        }
+        Ok(())
+    }
+
+    async fn save(&self, state_file: &str) -> std::io::Result<()> {
+        let state = QuotaState {
+            users: self
+                .usage()
+                .into_iter()
+                .map(|info| {
+                    (
+                        info.username,
+                        UserUsage {
+                            quota_bytes: info.quota_bytes,
+                            used_bytes: info.used_bytes,
+                            period_start: info.period_start,
+                        },
+                    )
+                })
+                .collect(),
+        };
+        let state_bytes = serde_json::to_vec(&state)?;
+
+        // Write to a temporary file first so that a partially written file is never loaded.
+        let tmp_path = format!("{}.tmp", state_file);
+        tokio::fs::write(&tmp_path, state_bytes).await?;
+        tokio::fs::rename(&tmp_path, state_file).await
+    }
+}
+
+// Loads saved usage, and saves usage periodically when there is a state file.
+pub async fn start_quota_store(config: QuotaConfig) -> std::io::Result<()> {
+    let QuotaConfig {
+        state_file,
+        save_interval_secs,
+        period_days,
+    } = config;
+
+    let store = user_quotas();
+    *store.period.lock() =
+        period_days.map(|days| Duration::from_secs(u64::from(days) * 24 * 60 * 60));
+
+    if let Some(state_file) = state_file {
+        store.load(&state_file)?;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(save_interval_secs));
+            // The first tick completes immediately.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = store.save(&state_file).await {
+                    error!("Failed to save quota state to {}: {}", state_file, e);
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -6,3 +6,26 @@ pub fn allocate_vec<T>(len: usize) -> Vec<T> {
     }
     ret
 }
+
+// Compares without returning early, so that the time taken doesn't reveal how much of a secret
+// matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
+
+// Printed in place of passwords, user ids and keys in Debug output, so that debug logs can be
+// shared.
+pub struct Redacted;
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "***")
+    }
+}
@@ -0,0 +1,492 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{BufMut, BytesMut};
+use futures::ready;
+use log::debug;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::tls_record_stream::TlsRecordStream;
+
+const USER_ID_LEN: usize = 16;
+
+// The command, followed by the big-endian content and padding lengths.
+const BLOCK_HEADER_LEN: usize = 5;
+
+// Blocks are at most this long including the user id, as in Xray.
+const MAX_BLOCK_LEN: usize = 8192;
+const MAX_CONTENT_LEN: usize = MAX_BLOCK_LEN - USER_ID_LEN - BLOCK_HEADER_LEN;
+
+const COMMAND_CONTINUE: u8 = 0;
+const COMMAND_END: u8 = 1;
+const COMMAND_DIRECT: u8 = 2;
+
+// How many reads and writes are inspected for the TLS handshake of the proxied connection.
+const PACKETS_TO_FILTER: u32 = 8;
+
+const READ_BUFFER_SIZE: usize = 16384;
+
+const TLS_CLIENT_HANDSHAKE_START: [u8; 2] = [0x16, 0x03];
+const TLS_SERVER_HANDSHAKE_START: [u8; 3] = [0x16, 0x03, 0x03];
+const TLS_APPLICATION_DATA_START: [u8; 3] = [0x17, 0x03, 0x03];
+const TLS_HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const TLS_HANDSHAKE_TYPE_SERVER_HELLO: u8 = 0x02;
+// The supported_versions extension of a ServerHello that selected TLS 1.3.
+const TLS13_SUPPORTED_VERSIONS: [u8; 6] = [0x00, 0x2b, 0x00, 0x02, 0x03, 0x04];
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+// The TLS 1.3 cipher suites up to TLS_AES_128_CCM_SHA256 can be spliced, but not
+// TLS_AES_128_CCM_8_SHA256, whose short tag is too easy to tell apart.
+const TLS_AES_128_CCM_SHA256: u16 = 0x1304;
+
+// What was learnt about the TLS handshake of the proxied connection, from both directions.
+struct TrafficState {
+    packets_to_filter: u32,
+    is_tls: bool,
+    is_tls12_or_above: bool,
+    // Whether the proxied connection uses TLS 1.3 with a cipher suite that allows the outer TLS
+    // layer to be skipped.
+    enable_direct: bool,
+    remaining_server_hello: i32,
+    cipher: u16,
+}
+
+impl TrafficState {
+    fn new() -> Self {
+        Self {
+            packets_to_filter: PACKETS_TO_FILTER,
+            is_tls: false,
+            is_tls12_or_above: false,
+            enable_direct: false,
+            remaining_server_hello: -1,
+            cipher: 0,
+        }
+    }
+
+    fn filter_tls(&mut self, data: &[u8]) {
+        self.packets_to_filter -= 1;
+        if data.len() >= 6 {
+            if data.starts_with(&TLS_SERVER_HANDSHAKE_START)
+                && data[5] == TLS_HANDSHAKE_TYPE_SERVER_HELLO
+            {
+                self.remaining_server_hello = (u16::from_be_bytes([data[3], data[4]]) as i32) + 5;
+                self.is_tls12_or_above = true;
+                self.is_tls = true;
+                if data.len() >= 79 && self.remaining_server_hello >= 79 {
+                    let cipher_index = 43 + data[43] as usize + 1;
+                    if data.len() >= cipher_index + 2 {
+                        self.cipher =
+                            u16::from_be_bytes([data[cipher_index], data[cipher_index + 1]]);
+                    }
+                }
+            } else if data.starts_with(&TLS_CLIENT_HANDSHAKE_START)
+                && data[5] == TLS_HANDSHAKE_TYPE_CLIENT_HELLO
+            {
+                self.is_tls = true;
+            }
+        }
+
+        if self.remaining_server_hello > 0 {
+            let end = std::cmp::min(self.remaining_server_hello as usize, data.len());
+            self.remaining_server_hello -= data.len() as i32;
+            if data[..end]
+                .windows(TLS13_SUPPORTED_VERSIONS.len())
+                .any(|window| window == TLS13_SUPPORTED_VERSIONS)
+            {
+                self.enable_direct =
+                    (TLS_AES_128_GCM_SHA256..=TLS_AES_128_CCM_SHA256).contains(&self.cipher);
+                self.packets_to_filter = 0;
+            } else if self.remaining_server_hello <= 0 {
+                self.packets_to_filter = 0;
+            }
+        }
+    }
+}
+
+// Removes the padding of received blocks, as XtlsUnpadding in Xray does.
+struct Unpadding {
+    user_id: Box<[u8]>,
+    // These are -1 before the first block and after the last one.
+    remaining_command: i32,
+    remaining_content: i32,
+    remaining_padding: i32,
+    current_command: u8,
+}
+
+impl Unpadding {
+    fn new(user_id: Box<[u8]>) -> Self {
+        Self {
+            user_id,
+            remaining_command: -1,
+            remaining_content: -1,
+            remaining_padding: -1,
+            current_command: COMMAND_CONTINUE,
+        }
+    }
+
+    fn unpad(&mut self, mut data: &[u8], content: &mut BytesMut) {
+        if self.remaining_command == -1
+            && self.remaining_content == -1
+            && self.remaining_padding == -1
+        {
+            // Padding starts with the user id.
+            if data.len() >= USER_ID_LEN + BLOCK_HEADER_LEN && data.starts_with(&self.user_id) {
+                data = &data[USER_ID_LEN..];
+                self.remaining_command = BLOCK_HEADER_LEN as i32;
+            } else {
+                content.extend_from_slice(data);
+                return;
+            }
+        }
+
+        while !data.is_empty() {
+            if self.remaining_command > 0 {
+                let byte = data[0];
+                data = &data[1..];
+                match self.remaining_command {
+                    5 => self.current_command = byte,
+                    4 => self.remaining_content = (byte as i32) << 8,
+                    3 => self.remaining_content |= byte as i32,
+                    2 => self.remaining_padding = (byte as i32) << 8,
+                    1 => self.remaining_padding |= byte as i32,
+                    _ => unreachable!(),
+                }
+                self.remaining_command -= 1;
+            } else if self.remaining_content > 0 {
+                let len = std::cmp::min(self.remaining_content as usize, data.len());
+                content.extend_from_slice(&data[..len]);
+                data = &data[len..];
+                self.remaining_content -= len as i32;
+            } else {
+                let len = std::cmp::min(self.remaining_padding as usize, data.len());
+                data = &data[len..];
+                self.remaining_padding -= len as i32;
+            }
+
+            if self.remaining_command <= 0
+                && self.remaining_content <= 0
+                && self.remaining_padding <= 0
+            {
+                if self.current_command == COMMAND_CONTINUE {
+                    self.remaining_command = BLOCK_HEADER_LEN as i32;
+                } else {
+                    self.remaining_command = -1;
+                    self.remaining_content = -1;
+                    self.remaining_padding = -1;
+                    // Nothing should follow the last block.
+                    content.extend_from_slice(data);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn is_within_block(&self) -> bool {
+        self.remaining_content > 0
+            || self.remaining_padding > 0
+            || self.current_command == COMMAND_CONTINUE
+    }
+}
+
+// A VLESS stream with the xtls-rprx-vision flow. The first packets in each direction are
+// padded to hide the lengths of the proxied TLS handshake, and once the proxied connection
+// sends TLS 1.3 application data, the outer TLS layer is skipped and the already encrypted
+// data is copied directly. The stream must be a TLS stream over a TlsRecordStream.
+pub struct VisionStream {
+    stream: Box<dyn AsyncStream>,
+    traffic_state: TrafficState,
+
+    unpadding: Unpadding,
+    // The VLESS response header that the client still has to receive.
+    response_header: Option<Vec<u8>>,
+    // Received data without its padding that wasn't read yet.
+    pending_data: BytesMut,
+    is_reading_padding: bool,
+    is_reading_direct: bool,
+
+    user_id: Option<Box<[u8]>>,
+    // Data that's written through the TLS layer before anything else.
+    write_buf: BytesMut,
+    is_writing_padding: bool,
+    is_writing_direct: bool,
+    is_tls_flushed: bool,
+}
+
+impl VisionStream {
+    // The VLESS response header is written before the first padded block.
+    pub fn new_server(
+        stream: Box<dyn AsyncStream>,
+        user_id: Box<[u8]>,
+        response_header: &[u8],
+    ) -> Self {
+        let mut vision_stream = Self::new(stream, user_id);
+        vision_stream.write_buf.extend_from_slice(response_header);
+        vision_stream
+    }
+
+    // The VLESS request header is written along with a padded block without content, so that
+    // the request is sent right away without showing the header length.
+    pub fn new_client(
+        stream: Box<dyn AsyncStream>,
+        user_id: Box<[u8]>,
+        request_header: &[u8],
+    ) -> Self {
+        let mut vision_stream = Self::new(stream, user_id);
+        vision_stream.response_header = Some(vec![]);
+        vision_stream.write_buf.extend_from_slice(request_header);
+        vision_stream.write_padded(&[], COMMAND_CONTINUE, true);
+        vision_stream
+    }
+
+    fn new(stream: Box<dyn AsyncStream>, user_id: Box<[u8]>) -> Self {
+        Self {
+            stream,
+            traffic_state: TrafficState::new(),
+            unpadding: Unpadding::new(user_id.clone()),
+            response_header: None,
+            pending_data: BytesMut::new(),
+            is_reading_padding: true,
+            is_reading_direct: false,
+            user_id: Some(user_id),
+            write_buf: BytesMut::new(),
+            is_writing_padding: true,
+            is_writing_direct: false,
+            is_tls_flushed: false,
+        }
+    }
+
+    fn raw_stream(&mut self) -> &mut TlsRecordStream {
+        // The VLESS handler checked that there is one.
+        self.stream.tls_record_stream().unwrap()
+    }
+
+    fn write_padded(&mut self, content: &[u8], command: u8, long_padding: bool) {
+        let mut rng = rand::thread_rng();
+        let padding_len = if content.len() < 900 && long_padding {
+            rng.gen_range(0..500) + 900 - content.len()
+        } else {
+            rng.gen_range(0..256)
+        };
+        let padding_len = std::cmp::min(padding_len, MAX_CONTENT_LEN - content.len());
+
+        // The user id is only sent with the first block.
+        if let Some(user_id) = self.user_id.take() {
+            self.write_buf.extend_from_slice(&user_id);
+        }
+        self.write_buf.put_u8(command);
+        self.write_buf.put_u16(content.len() as u16);
+        self.write_buf.put_u16(padding_len as u16);
+        self.write_buf.extend_from_slice(content);
+        self.write_buf.put_bytes(0, padding_len);
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let len = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+            if len == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            let _ = self.write_buf.split_to(len);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Flushes the TLS layer before the first direct write, so that its records come first.
+    fn poll_flush_tls(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.is_tls_flushed {
+            ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+            self.is_tls_flushed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Returns the received data that's left after the response header, or None if the header
+    // isn't complete yet.
+    fn read_response_header(&mut self, data: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        let response_header = self.response_header.as_mut().unwrap();
+        response_header.extend_from_slice(data);
+        if response_header.len() < 2 {
+            return Ok(None);
+        }
+        if response_header[0] != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "invalid server protocol version, expected 0, got {}",
+                    response_header[0]
+                ),
+            ));
+        }
+        // Addons in the response are ignored.
+        let header_len = 2 + response_header[1] as usize;
+        if response_header.len() < header_len {
+            return Ok(None);
+        }
+        let data = response_header.split_off(header_len);
+        self.response_header = None;
+        Ok(Some(data))
+    }
+
+    fn process_received(&mut self, data: &[u8]) {
+        let start_len = self.pending_data.len();
+        if self.is_reading_padding || self.traffic_state.packets_to_filter > 0 {
+            self.unpadding.unpad(data, &mut self.pending_data);
+            if self.unpadding.is_within_block() {
+                self.is_reading_padding = true;
+            } else if self.unpadding.current_command == COMMAND_END {
+                self.is_reading_padding = false;
+            } else if self.unpadding.current_command == COMMAND_DIRECT {
+                debug!("Vision stream is reading directly");
+                self.is_reading_padding = false;
+                self.is_reading_direct = true;
+            }
+        } else {
+            self.pending_data.extend_from_slice(data);
+        }
+        if self.traffic_state.packets_to_filter > 0 && self.pending_data.len() > start_len {
+            self.traffic_state
+                .filter_tls(&self.pending_data[start_len..]);
+        }
+    }
+}
+
+impl AsyncRead for VisionStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.pending_data.is_empty() {
+                let len = std::cmp::min(this.pending_data.len(), buf.remaining());
+                buf.put_slice(&this.pending_data.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.is_reading_direct {
+                return this.raw_stream().poll_read_raw(cx, buf);
+            }
+
+            if this.response_header.is_none()
+                && !this.is_reading_padding
+                && this.traffic_state.packets_to_filter == 0
+            {
+                return Pin::new(&mut this.stream).poll_read(cx, buf);
+            }
+
+            let mut data = vec![0u8; READ_BUFFER_SIZE];
+            let mut read_buf = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+            let len = read_buf.filled().len();
+            if len == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            data.truncate(len);
+
+            if this.response_header.is_some() {
+                match this.read_response_header(&data)? {
+                    Some(remaining_data) => data = remaining_data,
+                    None => continue,
+                }
+                if data.is_empty() {
+                    continue;
+                }
+            }
+
+            this.process_received(&data);
+        }
+    }
+}
+
+impl AsyncWrite for VisionStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+
+        if this.is_writing_direct {
+            ready!(this.poll_flush_tls(cx))?;
+            return Pin::new(this.raw_stream()).poll_write(cx, buf);
+        }
+
+        if !this.is_writing_padding {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let content = &buf[..std::cmp::min(buf.len(), MAX_CONTENT_LEN)];
+        let traffic_state = &mut this.traffic_state;
+        if traffic_state.packets_to_filter > 0 {
+            traffic_state.filter_tls(content);
+        }
+
+        let (command, long_padding) = if traffic_state.is_tls
+            && content.len() >= 6
+            && content.starts_with(&TLS_APPLICATION_DATA_START)
+        {
+            // The proxied connection finished its handshake, so the remaining data is already
+            // encrypted.
+            this.is_writing_padding = false;
+            if traffic_state.enable_direct {
+                debug!("Vision stream is writing directly");
+                this.is_writing_direct = true;
+                (COMMAND_DIRECT, true)
+            } else {
+                (COMMAND_END, true)
+            }
+        } else if !traffic_state.is_tls12_or_above && traffic_state.packets_to_filter <= 1 {
+            // Padding ends a packet early for compatibility with older receivers.
+            this.is_writing_padding = false;
+            (COMMAND_END, traffic_state.is_tls)
+        } else {
+            (COMMAND_CONTINUE, traffic_state.is_tls)
+        };
+
+        this.write_padded(content, command, long_padding);
+        Poll::Ready(Ok(content.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        if this.is_writing_direct {
+            ready!(this.poll_flush_tls(cx))?;
+            return Pin::new(this.raw_stream()).poll_flush(cx);
+        }
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        if this.is_writing_direct {
+            ready!(this.poll_flush_tls(cx))?;
+            return Pin::new(this.raw_stream()).poll_shutdown(cx);
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for VisionStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for VisionStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+}
@@ -1,29 +1,56 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use async_trait::async_trait;
-use log::info;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncStream;
+use crate::config::VlessFlow;
 use crate::option_util::NoneOrOne;
 use crate::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
+use crate::vision_stream::VisionStream;
 
-use crate::util::allocate_vec;
+use crate::util::{allocate_vec, Redacted};
 
-#[derive(Debug)]
 pub struct VlessTcpHandler {
     user_id: Box<[u8]>,
+    fallback: Option<NetLocation>,
+    flow: Option<VlessFlow>,
+}
+
+impl std::fmt::Debug for VlessTcpHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VlessTcpHandler")
+            .field("user_id", &Redacted)
+            .field("fallback", &self.fallback)
+            .field("flow", &self.flow)
+            .finish()
+    }
 }
 
 impl VlessTcpHandler {
     pub fn new(user_id: &str) -> Self {
         Self {
             user_id: parse_hex(&user_id),
+            fallback: None,
+            flow: None,
         }
     }
+
+    // The flow is requested by clients, and required from them by servers.
+    pub fn with_flow(mut self, flow: Option<VlessFlow>) -> Self {
+        self.flow = flow;
+        self
+    }
+
+    // Connections with an unknown user id are forwarded to the fallback, along with the bytes
+    // that were already read.
+    pub fn with_fallback(mut self, fallback: Option<NetLocation>) -> Self {
+        self.fallback = fallback;
+        self
+    }
 }
 
 const SERVER_RESPONSE_HEADER: &[u8] = &[
@@ -31,6 +58,9 @@ const SERVER_RESPONSE_HEADER: &[u8] = &[
     0u8, // addons length
 ];
 
+// The protobuf tag of the flow field in the addons, field 1 with the length-delimited wire type.
+const ADDONS_FLOW_TAG: u8 = 0x0a;
+
 #[async_trait]
 impl TcpServerHandler for VlessTcpHandler {
     async fn setup_server_stream(
@@ -38,7 +68,32 @@ impl TcpServerHandler for VlessTcpHandler {
         mut server_stream: Box<dyn AsyncStream>,
     ) -> std::io::Result<TcpServerSetupResult> {
         let mut prefix = [0u8; 18];
-        server_stream.read_exact(&mut prefix).await?;
+        let mut read_len = 0;
+        while read_len < prefix.len() {
+            let len = server_stream.read(&mut prefix[read_len..]).await?;
+            if len == 0 {
+                break;
+            }
+            read_len += len;
+        }
+
+        if let Some(ref fallback) = self.fallback {
+            if read_len < prefix.len() || prefix[0] != 0 || prefix[1..17] != self.user_id[..] {
+                return Ok(TcpServerSetupResult::Fallback {
+                    remote_location: fallback.clone(),
+                    stream: server_stream,
+                    need_initial_flush: false,
+                    initial_remote_data: prefix[0..read_len].into(),
+                });
+            }
+        }
+
+        if read_len < prefix.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "EOF while reading request header",
+            ));
+        }
 
         if prefix[0] != 0 {
             return Err(std::io::Error::new(
@@ -62,8 +117,33 @@ impl TcpServerHandler for VlessTcpHandler {
 
         let addon_length = prefix[17];
 
-        if addon_length > 0 {
-            read_addons(&mut server_stream, addon_length).await?;
+        let requested_flow = if addon_length > 0 {
+            read_addons(&mut server_stream, addon_length).await?
+        } else {
+            None
+        };
+
+        // Without the flow, the client's TLS handshake would be visible inside the outer TLS
+        // connection, so a server with a flow doesn't accept clients without it.
+        match (self.flow, requested_flow.as_deref()) {
+            (None, None) => (),
+            (Some(flow), Some(requested_flow)) if flow.name() == requested_flow => (),
+            (Some(flow), _) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!(
+                        "client did not request the required flow {}, got {:?}",
+                        flow.name(),
+                        requested_flow
+                    ),
+                ));
+            }
+            (None, Some(requested_flow)) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("unsupported flow requested: {}", requested_flow),
+                ));
+            }
         }
 
         let mut address_prefix = [0u8; 4];
@@ -111,20 +191,7 @@ impl TcpServerHandler for VlessTcpHandler {
                 let mut domain_name_bytes = allocate_vec(domain_name_len[0] as usize);
                 server_stream.read_exact(&mut domain_name_bytes).await?;
 
-                let address_str = match std::str::from_utf8(&domain_name_bytes) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            format!("Failed to decode address: {}", e),
-                        ));
-                    }
-                };
-
-                // Although this is supposed to be a hostname, some clients will pass
-                // ipv4 and ipv6 addresses as well, so parse it rather than directly
-                // using Address:Hostname enum.
-                NetLocation::new(Address::from(address_str)?, port)
+                NetLocation::new(Address::from_header_bytes(&domain_name_bytes)?, port)
             }
             3 => {
                 // 16 byte ipv6 address
@@ -152,13 +219,31 @@ impl TcpServerHandler for VlessTcpHandler {
             }
         };
 
+        // With a flow, the response header is written before the first padded block.
+        let (server_stream, connection_success_response) = match self.flow {
+            Some(VlessFlow::XtlsRprxVision) => {
+                check_vision_stream(&mut server_stream)?;
+                let vision_stream: Box<dyn AsyncStream> = Box::new(VisionStream::new_server(
+                    server_stream,
+                    self.user_id.clone(),
+                    SERVER_RESPONSE_HEADER,
+                ));
+                (vision_stream, None)
+            }
+            None => (
+                server_stream,
+                Some(SERVER_RESPONSE_HEADER.to_vec().into_boxed_slice()),
+            ),
+        };
+
         Ok(TcpServerSetupResult::TcpForward {
             remote_location,
             stream: server_stream,
             need_initial_flush: true,
-            connection_success_response: Some(SERVER_RESPONSE_HEADER.to_vec().into_boxed_slice()),
+            connection_success_response,
             initial_remote_data: None,
             override_proxy_provider: NoneOrOne::Unspecified,
+            authenticated_user: None,
         })
     }
 }
@@ -171,34 +256,39 @@ impl TcpClientHandler for VlessTcpHandler {
         mut client_stream: Box<dyn AsyncStream>,
         remote_location: NetLocation,
     ) -> std::io::Result<TcpClientSetupResult> {
-        // version + user id + header addon length + command + port + address type
-        let mut header_bytes = [0u8; 1 + 16 + 1 + 1 + 2 + 1];
+        if self.flow.is_some() {
+            check_vision_stream(&mut client_stream)?;
+        }
 
-        // version 0 is fine, no need to write.
-        header_bytes[1..17].copy_from_slice(&self.user_id);
+        // version 0 + user id
+        let mut header_bytes = vec![0u8];
+        header_bytes.extend_from_slice(&self.user_id);
+
+        match self.flow {
+            Some(flow) => {
+                let flow_name = flow.name().as_bytes();
+                header_bytes.push((2 + flow_name.len()) as u8);
+                header_bytes.push(ADDONS_FLOW_TAG);
+                header_bytes.push(flow_name.len() as u8);
+                header_bytes.extend_from_slice(flow_name);
+            }
+            None => header_bytes.push(0),
+        }
 
-        // header addon length of 0 is fine, no need to write.
         // tcp
-        header_bytes[18] = 1;
+        header_bytes.push(1);
 
         let (remote_address, remote_port) = remote_location.unwrap_components();
-        header_bytes[19] = (remote_port >> 8) as u8;
-        header_bytes[20] = (remote_port & 0xff) as u8;
+        header_bytes.extend_from_slice(&remote_port.to_be_bytes());
 
         match remote_address {
             Address::Ipv4(v4addr) => {
-                header_bytes[21] = 1;
-                client_stream.write_all(&header_bytes).await?;
-
-                let address_bytes = v4addr.octets();
-                client_stream.write_all(&address_bytes).await?;
+                header_bytes.push(1);
+                header_bytes.extend_from_slice(&v4addr.octets());
             }
             Address::Ipv6(v6addr) => {
-                header_bytes[21] = 3;
-                client_stream.write_all(&header_bytes).await?;
-
-                let address_bytes = v6addr.octets();
-                client_stream.write_all(&address_bytes).await?;
+                header_bytes.push(3);
+                header_bytes.extend_from_slice(&v6addr.octets());
             }
             Address::Hostname(hostname) => {
                 if hostname.len() > 255 {
@@ -208,16 +298,24 @@ impl TcpClientHandler for VlessTcpHandler {
                     ));
                 }
 
-                header_bytes[21] = 2;
-                client_stream.write_all(&header_bytes).await?;
-
-                let hostname_len_byte: [u8; 1] = [hostname.len() as u8];
-                client_stream.write_all(&hostname_len_byte).await?;
-
-                let hostname_bytes = hostname.into_bytes();
-                client_stream.write_all(&hostname_bytes).await?;
+                header_bytes.push(2);
+                header_bytes.push(hostname.len() as u8);
+                header_bytes.extend_from_slice(hostname.as_bytes());
             }
         }
+
+        // With a flow, the response header is read along with the first padded block, since
+        // servers like Xray only send it once the remote sends data.
+        if self.flow.is_some() {
+            let mut vision_stream =
+                VisionStream::new_client(client_stream, self.user_id.clone(), &header_bytes);
+            vision_stream.flush().await?;
+            return Ok(TcpClientSetupResult {
+                client_stream: Box::new(vision_stream),
+            });
+        }
+
+        client_stream.write_all(&header_bytes).await?;
         client_stream.flush().await?;
 
         let mut response_header = [0u8; 2];
@@ -242,6 +340,18 @@ impl TcpClientHandler for VlessTcpHandler {
     }
 }
 
+// The Vision flow reads and writes beneath the TLS layer, which the TLS handlers only allow when
+// the config has the flow.
+fn check_vision_stream(stream: &mut Box<dyn AsyncStream>) -> std::io::Result<()> {
+    if stream.tls_record_stream().is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the xtls-rprx-vision flow requires VLESS directly inside TLS",
+        ));
+    }
+    Ok(())
+}
+
 fn parse_hex(hex_asm: &str) -> Box<[u8]> {
     let mut hex_bytes = hex_asm
         .as_bytes()
@@ -261,57 +371,141 @@ fn parse_hex(hex_asm: &str) -> Box<[u8]> {
     bytes.into_boxed_slice()
 }
 
-fn read_varint(data: &[u8]) -> std::io::Result<(u64, usize)> {
-    let mut cursor = 0usize;
-    let mut length = 0u64;
-    loop {
-        let byte = data[cursor];
-        if (byte & 0b10000000) != 0 {
-            length = (length << 8) | ((byte ^ 0b10000000) as u64);
-        } else {
-            length = (length << 8) | (byte as u64);
-            return Ok((length, cursor + 1));
+fn read_varint(data: &mut &[u8]) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or_else(truncated_addons_error)?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
         }
-        if cursor == 7 || cursor == data.len() {
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Varint is too long",
+    ))
+}
+
+fn truncated_addons_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Truncated addons")
+}
+
+// Reads the addons, a protobuf message with the flow and a seed, and returns the flow if it's
+// set. The seed is unused.
+async fn read_addons(
+    stream: &mut Box<dyn AsyncStream>,
+    addon_length: u8,
+) -> std::io::Result<Option<String>> {
+    let mut addon_bytes = allocate_vec(addon_length as usize).into_boxed_slice();
+    stream.read_exact(&mut addon_bytes).await?;
+
+    let mut data = &addon_bytes[..];
+    let mut flow = None;
+    while !data.is_empty() {
+        let tag = read_varint(&mut data)?;
+        // All fields are length-delimited.
+        if tag & 0x7 != 2 {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Varint is too long",
+                std::io::ErrorKind::InvalidData,
+                format!("Unexpected addon field tag: {}", tag),
             ));
         }
-        cursor += 1;
+        let len = read_varint(&mut data)? as usize;
+        if len > data.len() {
+            return Err(truncated_addons_error());
+        }
+        let (field, rest) = data.split_at(len);
+        data = rest;
+        if tag == ADDONS_FLOW_TAG as u64 && !field.is_empty() {
+            flow = Some(String::from_utf8_lossy(field).into_owned());
+        }
     }
+
+    Ok(flow)
 }
 
-async fn read_addons(stream: &mut Box<dyn AsyncStream>, addon_length: u8) -> std::io::Result<()> {
-    let mut addon_bytes = allocate_vec(addon_length as usize).into_boxed_slice();
-    stream.read_exact(&mut addon_bytes).await?;
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-    let mut addon_cursor = 0;
-    let (flow_length, bytes_used) = read_varint(&addon_bytes)?;
-    addon_cursor += bytes_used;
+    use super::*;
 
-    let flow_bytes = &addon_bytes[addon_cursor..addon_cursor + flow_length as usize];
-    addon_cursor += flow_length as usize;
+    const USER_ID: &str = "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4";
 
-    let (seed_length, bytes_used) = read_varint(&addon_bytes[addon_cursor..])?;
-    addon_cursor += bytes_used;
-    let seed_bytes = &addon_bytes[addon_cursor..addon_cursor + seed_length as usize];
-    addon_cursor += seed_length as usize;
+    // A TCP request for the domain, without addons.
+    fn domain_request(domain: &[u8]) -> Vec<u8> {
+        let mut request = vec![0];
+        request.extend_from_slice(&parse_hex(USER_ID));
+        request.extend_from_slice(&[0, 1]);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        request.extend_from_slice(&[2, domain.len() as u8]);
+        request.extend_from_slice(domain);
+        request
+    }
 
-    if addon_cursor as u8 != addon_length {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!(
-                "Did not consume all addon bytes, cursor is at {}, length is {}",
-                addon_cursor, addon_length
-            ),
-        ));
+    // Sets up a server stream from the request bytes. The client end stays open, so reading
+    // past the request would hang instead of failing with an EOF.
+    async fn setup(request: &[u8]) -> std::io::Result<TcpServerSetupResult> {
+        let handler = VlessTcpHandler::new(USER_ID);
+        let (mut client_stream, server_stream) = tokio::io::duplex(1024);
+        client_stream.write_all(request).await.unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.setup_server_stream(Box::new(server_stream)),
+        )
+        .await
+        .expect("server read past the request");
+        drop(client_stream);
+        result
     }
 
-    info!(
-        "Read addon bytes: flow: {:?}, seed: {:?}",
-        &flow_bytes, &seed_bytes
-    );
+    fn expect_error(result: std::io::Result<TcpServerSetupResult>) -> std::io::Error {
+        match result {
+            Ok(_) => panic!("expected the request to be rejected"),
+            Err(e) => e,
+        }
+    }
 
-    Ok(())
+    #[tokio::test]
+    async fn domain_at_max_length_is_accepted() {
+        match setup(&domain_request(&[b'a'; 255])).await {
+            Ok(TcpServerSetupResult::TcpForward {
+                remote_location, ..
+            }) => {
+                assert_eq!(
+                    remote_location,
+                    NetLocation::new(Address::Hostname("a".repeat(255)), 443)
+                );
+            }
+            Ok(_) => panic!("expected a tcp forward"),
+            Err(e) => panic!("request was rejected: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_domains_are_rejected() {
+        let error = expect_error(setup(&domain_request(b"")).await);
+        assert_eq!(error.to_string(), "Empty address in header");
+
+        let error = expect_error(setup(&domain_request(b"example\xff.com")).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_domain_is_rejected() {
+        let handler = VlessTcpHandler::new(USER_ID);
+        let request = domain_request(b"example.com");
+
+        // The connection closes before the whole domain arrives.
+        let (mut client_stream, server_stream) = tokio::io::duplex(1024);
+        client_stream
+            .write_all(&request[0..request.len() - 4])
+            .await
+            .unwrap();
+        drop(client_stream);
+
+        let error = expect_error(handler.setup_server_stream(Box::new(server_stream)).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
 }
@@ -18,6 +18,20 @@ pub fn compute_md5_repeating(data: &[u8], data_times: usize) -> [u8; 16] {
     context.finalize().into()
 }
 
+// Derives the next legacy alter id from the previous one, the same way as v2ray.
+pub fn compute_next_alter_id(prev_id: &[u8; 16]) -> [u8; 16] {
+    let mut context = Md5::new();
+    md5::Digest::update(&mut context, prev_id);
+    md5::Digest::update(&mut context, b"16167dc8-16b6-4e6d-b8bb-65dd68113a81");
+    loop {
+        let id: [u8; 16] = context.clone().finalize().into();
+        if id != *prev_id {
+            return id;
+        }
+        md5::Digest::update(&mut context, b"533eff8a-4113-4b10-b5ce-0f5d76b98cd2");
+    }
+}
+
 #[inline]
 pub fn create_chacha_key(data: &[u8]) -> [u8; 32] {
     let mut ret = [0u8; 32];
@@ -17,7 +17,9 @@ use sha3::Shake128;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use super::fnv1a::Fnv1aHasher;
-use super::md5::{compute_hmac_md5, compute_md5, compute_md5_repeating, create_chacha_key};
+use super::md5::{
+    compute_hmac_md5, compute_md5, compute_md5_repeating, compute_next_alter_id, create_chacha_key,
+};
 use super::nonce::{SingleUseNonce, VmessNonceSequence};
 use super::vmess_stream::{ReadHeaderInfo, VmessStream};
 use crate::address::{Address, NetLocation};
@@ -26,10 +28,14 @@ use crate::option_util::NoneOrOne;
 use crate::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
-use crate::util::allocate_vec;
+use crate::util::{allocate_vec, Redacted};
 
 const TAG_LEN: usize = 16;
 
+// The longest request header: 41 (instructions up to addr type) + 256 (max domain name length
+// 255 + 1 length byte) + 15 (max margin length, 4 bits) + 4 (fnv1a hash).
+const MAX_HEADER_LEN: usize = 316;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum DataCipher {
     Any,
@@ -54,33 +60,52 @@ impl From<&str> for DataCipher {
 
 type UserHash = [u8; 16];
 
-#[derive(Debug)]
 struct CertHashProvider {
-    user_key: [u8; 16],
+    // The user id, followed by its alter ids.
+    user_keys: Vec<[u8; 16]>,
     hashes: HashMap<UserHash, u64>,
     last_hash_time_secs: u64,
 }
 
+impl std::fmt::Debug for CertHashProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertHashProvider")
+            .field("user_keys", &Redacted)
+            .field("alter_id", &(self.user_keys.len() - 1))
+            .field("last_hash_time_secs", &self.last_hash_time_secs)
+            .finish()
+    }
+}
+
 impl CertHashProvider {
-    pub fn new(user_id_bytes: &[u8]) -> Self {
+    pub fn new(user_id_bytes: &[u8], alter_id: u16) -> Self {
         if user_id_bytes.len() != 16 {
             panic!("invalid user id bytes length ({})", user_id_bytes.len());
         }
         let mut user_key = [0u8; 16];
         user_key.copy_from_slice(user_id_bytes);
+
+        let mut user_keys = Vec::with_capacity(alter_id as usize + 1);
+        user_keys.push(user_key);
+        for _ in 0..alter_id {
+            let next_key = compute_next_alter_id(user_keys.last().unwrap());
+            user_keys.push(next_key);
+        }
+
         Self {
-            user_key,
-            hashes: HashMap::with_capacity(64),
+            hashes: HashMap::with_capacity(64 * user_keys.len()),
+            user_keys,
             last_hash_time_secs: 0,
         }
     }
 
+    // Each hash only authenticates a single request, so that recorded requests can't be
+    // replayed.
     pub fn check(&mut self, hash: &UserHash) -> Option<u64> {
-        if let Some(time_secs) = self.hashes.get(hash) {
-            return Some(*time_secs);
+        if !self.hashes.contains_key(hash) {
+            self.update_hashes();
         }
-        self.update_hashes();
-        self.hashes.get(hash).map(|time_secs| *time_secs)
+        self.hashes.remove(hash)
     }
 
     fn update_hashes(&mut self) {
@@ -96,11 +121,14 @@ impl CertHashProvider {
         self.hashes
             .retain(|_, hash_time_secs| *hash_time_secs >= from_time_secs);
 
-        let mut create_time_secs = std::cmp::max(from_time_secs, self.last_hash_time_secs);
+        // Hashes are never created twice, so that used hashes aren't accepted again.
+        let mut create_time_secs = std::cmp::max(from_time_secs, self.last_hash_time_secs + 1);
         while create_time_secs <= to_time_secs {
-            let hash_bytes: [u8; 16] =
-                compute_hmac_md5(&self.user_key, &create_time_secs.to_be_bytes());
-            self.hashes.insert(hash_bytes, create_time_secs);
+            for user_key in self.user_keys.iter() {
+                let hash_bytes: [u8; 16] =
+                    compute_hmac_md5(user_key, &create_time_secs.to_be_bytes());
+                self.hashes.insert(hash_bytes, create_time_secs);
+            }
             create_time_secs += 1;
         }
 
@@ -110,7 +138,6 @@ impl CertHashProvider {
 
 type AesCfb = Cfb<Aes128>;
 
-#[derive(Debug)]
 pub struct VmessTcpServerHandler {
     data_cipher: DataCipher,
     instruction_key: [u8; 16],
@@ -119,13 +146,30 @@ pub struct VmessTcpServerHandler {
     udp_enabled: bool,
 }
 
+impl std::fmt::Debug for VmessTcpServerHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VmessTcpServerHandler")
+            .field("data_cipher", &self.data_cipher)
+            .field("instruction_key", &Redacted)
+            .field("cert_hash_provider", &self.cert_hash_provider)
+            .field("udp_enabled", &self.udp_enabled)
+            .finish()
+    }
+}
+
 impl VmessTcpServerHandler {
-    pub fn new(cipher_name: &str, user_id: &str, force_aead: bool, udp_enabled: bool) -> Self {
+    pub fn new(
+        cipher_name: &str,
+        user_id: &str,
+        force_aead: bool,
+        alter_id: u16,
+        udp_enabled: bool,
+    ) -> Self {
         let mut user_id_bytes = parse_hex(user_id);
         let cert_hash_provider = if force_aead {
             None
         } else {
-            Some(Mutex::new(CertHashProvider::new(&user_id_bytes)))
+            Some(Mutex::new(CertHashProvider::new(&user_id_bytes, alter_id)))
         };
 
         user_id_bytes.extend(b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
@@ -221,6 +265,12 @@ impl TcpServerHandler for VmessTcpServerHandler {
 
             let payload_length =
                 u16::from_be_bytes(encrypted_payload_length[0..2].try_into().unwrap());
+            if payload_length as usize > MAX_HEADER_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Header length {} is too long", payload_length),
+                ));
+            }
 
             let header_aead_key = super::sha2::kdf(
                 &self.instruction_key,
@@ -333,20 +383,7 @@ impl TcpServerHandler for VmessTcpServerHandler {
                 header_reader.read_exact(&mut domain_name_bytes).await?;
                 fnv_hasher.write(&domain_name_bytes);
 
-                let address_str = match std::str::from_utf8(&domain_name_bytes) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            format!("Failed to decode address: {}", e),
-                        ));
-                    }
-                };
-
-                // Although this is supposed to be a hostname, some clients will pass
-                // ipv4 and ipv6 addresses as well, so parse it rather than directly
-                // using Address:Hostname enum.
-                NetLocation::new(Address::from(address_str)?, port)
+                NetLocation::new(Address::from_header_bytes(&domain_name_bytes)?, port)
             }
             3 => {
                 // 16 byte ipv6 address
@@ -625,6 +662,7 @@ impl TcpServerHandler for VmessTcpServerHandler {
                 connection_success_response: None,
                 initial_remote_data: None,
                 override_proxy_provider: NoneOrOne::Unspecified,
+                authenticated_user: None,
             }),
             true => Ok(TcpServerSetupResult::BidirectionalUdpForward {
                 remote_location,
@@ -681,6 +719,13 @@ struct AeadHeaderReader {
 impl AeadHeaderReader {
     fn read_exact(&mut self, data: &mut [u8]) -> std::io::Result<()> {
         let len = data.len();
+        // The decrypted header ends with its tag, which isn't part of the header.
+        if self.cursor + len > self.decrypted_header.len() - TAG_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Header is shorter than its fields",
+            ));
+        }
         data.copy_from_slice(&self.decrypted_header[self.cursor..self.cursor + len]);
         self.cursor += len;
         Ok(())
@@ -691,7 +736,6 @@ impl AeadHeaderReader {
     }
 }
 
-#[derive(Debug)]
 pub struct VmessTcpClientHandler {
     data_cipher: DataCipher,
     user_key: [u8; 16],
@@ -700,6 +744,17 @@ pub struct VmessTcpClientHandler {
     is_aead: bool,
 }
 
+impl std::fmt::Debug for VmessTcpClientHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VmessTcpClientHandler")
+            .field("data_cipher", &self.data_cipher)
+            .field("user_key", &Redacted)
+            .field("instruction_key", &Redacted)
+            .field("is_aead", &self.is_aead)
+            .finish()
+    }
+}
+
 impl VmessTcpClientHandler {
     pub fn new(cipher_name: &str, user_id: &str, is_aead: bool) -> Self {
         let mut user_id_bytes = parse_hex(user_id);
@@ -736,19 +791,10 @@ impl TcpClientHandler for VmessTcpClientHandler {
             let random_delta: u64 = rand::thread_rng().gen_range(0..241);
             let time_secs: u64 =
                 SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() - 120u64 + random_delta;
-
-            let mut aead_bytes = [0u8; 16];
-            let time_bytes = time_secs.to_be_bytes();
-            aead_bytes[0..8].copy_from_slice(&time_bytes);
-
-            rand::thread_rng().fill_bytes(&mut aead_bytes[8..12]);
-
-            let checksum = super::crc32::crc32c(&aead_bytes[0..12]).to_be_bytes();
-            aead_bytes[12..16].copy_from_slice(&checksum);
-
-            self.aead_cipher
-                .encrypt_block(&mut GenericArray::from_mut_slice(&mut aead_bytes));
-            (aead_bytes, time_bytes)
+            (
+                create_aead_auth_id(&self.aead_cipher, time_secs),
+                time_secs.to_be_bytes(),
+            )
         } else {
             // non-AEAD only allows 30 second delta.
             let random_delta: u64 = rand::thread_rng().gen_range(0..61);
@@ -761,10 +807,7 @@ impl TcpClientHandler for VmessTcpClientHandler {
 
         client_stream.write_all(&cert_hash).await?;
 
-        // max length of encrypted header:
-        // 41 (instructions up to addr type) + 256 (max domain name length 255 + 1 length byte) +
-        // 15 (max margin length, 4 bits) + 4 (fnv1a hash) = 316 + TAG_LEN
-        let mut header_bytes = [0u8; 316 + TAG_LEN];
+        let mut header_bytes = [0u8; MAX_HEADER_LEN];
 
         header_bytes[0] = 1;
 
@@ -908,66 +951,9 @@ impl TcpClientHandler for VmessTcpClientHandler {
         cursor += 4;
 
         if self.is_aead {
-            let mut encrypted_payload_length = [0u8; 18];
-            let mut nonce = [0u8; 8];
-            rand::thread_rng().fill_bytes(&mut nonce);
-
-            let header_length_aead_key = super::sha2::kdf(
-                &self.instruction_key,
-                &[b"VMess Header AEAD Key_Length", &cert_hash, &nonce],
-            );
-
-            let header_length_nonce = super::sha2::kdf(
-                &self.instruction_key,
-                &[b"VMess Header AEAD Nonce_Length", &cert_hash, &nonce],
-            );
-
-            let unbound_key =
-                UnboundKey::new(&AES_128_GCM, &header_length_aead_key[0..16]).unwrap();
-
-            let mut sealing_key = SealingKey::new(
-                unbound_key,
-                SingleUseNonce::new(&header_length_nonce[0..12]),
-            );
-
-            encrypted_payload_length[0] = (cursor >> 8) as u8;
-            encrypted_payload_length[1] = (cursor & 0xff) as u8;
-
-            let tag = sealing_key
-                .seal_in_place_separate_tag(
-                    Aad::from(&cert_hash),
-                    &mut encrypted_payload_length[0..2],
-                )
-                .unwrap();
-
-            encrypted_payload_length[2..].copy_from_slice(tag.as_ref());
-
-            client_stream.write_all(&encrypted_payload_length).await?;
-            client_stream.write_all(&nonce).await?;
-
-            let header_aead_key = super::sha2::kdf(
-                &self.instruction_key,
-                &[b"VMess Header AEAD Key", &cert_hash, &nonce],
-            );
-
-            let header_nonce = super::sha2::kdf(
-                &self.instruction_key,
-                &[b"VMess Header AEAD Nonce", &cert_hash, &nonce],
-            );
-
-            // TODO: don't unwrap
-            let unbound_key = UnboundKey::new(&AES_128_GCM, &header_aead_key[0..16]).unwrap();
-
-            let mut sealing_key =
-                SealingKey::new(unbound_key, SingleUseNonce::new(&header_nonce[0..12]));
-
-            let tag = sealing_key
-                .seal_in_place_separate_tag(Aad::from(&cert_hash), &mut header_bytes[0..cursor])
-                .unwrap();
-
-            header_bytes[cursor..cursor + TAG_LEN].copy_from_slice(tag.as_ref());
-            cursor += TAG_LEN;
-            client_stream.write_all(&header_bytes[0..cursor]).await?;
+            let sealed_header =
+                seal_aead_header(&self.instruction_key, &cert_hash, &header_bytes[0..cursor]);
+            client_stream.write_all(&sealed_header).await?;
         } else {
             let instruction_iv: [u8; 16] = compute_md5_repeating(&time_bytes, 4);
             let mut cipher =
@@ -1003,6 +989,76 @@ impl TcpClientHandler for VmessTcpClientHandler {
     }
 }
 
+// The auth ID that starts an AEAD request, which is the request time and a checksum encrypted
+// with the user's key.
+fn create_aead_auth_id(aead_cipher: &Aes128, time_secs: u64) -> [u8; 16] {
+    let mut aead_bytes = [0u8; 16];
+    aead_bytes[0..8].copy_from_slice(&time_secs.to_be_bytes());
+
+    rand::thread_rng().fill_bytes(&mut aead_bytes[8..12]);
+
+    let checksum = super::crc32::crc32c(&aead_bytes[0..12]).to_be_bytes();
+    aead_bytes[12..16].copy_from_slice(&checksum);
+
+    aead_cipher.encrypt_block(GenericArray::from_mut_slice(&mut aead_bytes));
+    aead_bytes
+}
+
+// Encrypts an AEAD request header, which follows the auth ID: the sealed header length, the
+// nonce that the keys are derived with, then the sealed header.
+fn seal_aead_header(instruction_key: &[u8; 16], auth_id: &[u8; 16], header: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let header_length_aead_key = super::sha2::kdf(
+        instruction_key,
+        &[b"VMess Header AEAD Key_Length", auth_id, &nonce],
+    );
+
+    let header_length_nonce = super::sha2::kdf(
+        instruction_key,
+        &[b"VMess Header AEAD Nonce_Length", auth_id, &nonce],
+    );
+
+    let unbound_key = UnboundKey::new(&AES_128_GCM, &header_length_aead_key[0..16]).unwrap();
+
+    let mut sealing_key = SealingKey::new(
+        unbound_key,
+        SingleUseNonce::new(&header_length_nonce[0..12]),
+    );
+
+    let mut sealed_header = Vec::with_capacity(2 + TAG_LEN + nonce.len() + header.len() + TAG_LEN);
+    sealed_header.extend_from_slice(&(header.len() as u16).to_be_bytes());
+
+    let tag = sealing_key
+        .seal_in_place_separate_tag(Aad::from(auth_id), &mut sealed_header[0..2])
+        .unwrap();
+    sealed_header.extend_from_slice(tag.as_ref());
+    sealed_header.extend_from_slice(&nonce);
+
+    let header_aead_key = super::sha2::kdf(
+        instruction_key,
+        &[b"VMess Header AEAD Key", auth_id, &nonce],
+    );
+
+    let header_nonce = super::sha2::kdf(
+        instruction_key,
+        &[b"VMess Header AEAD Nonce", auth_id, &nonce],
+    );
+
+    let unbound_key = UnboundKey::new(&AES_128_GCM, &header_aead_key[0..16]).unwrap();
+
+    let mut sealing_key = SealingKey::new(unbound_key, SingleUseNonce::new(&header_nonce[0..12]));
+
+    let header_start = sealed_header.len();
+    sealed_header.extend_from_slice(header);
+    let tag = sealing_key
+        .seal_in_place_separate_tag(Aad::from(auth_id), &mut sealed_header[header_start..])
+        .unwrap();
+    sealed_header.extend_from_slice(tag.as_ref());
+    sealed_header
+}
+
 // taken from https://codereview.stackexchange.com/questions/201698/convert-string-of-hex-into-vector-of-bytes
 fn parse_hex(hex_asm: &str) -> Vec<u8> {
     let mut hex_bytes = hex_asm
@@ -1022,3 +1078,157 @@ fn parse_hex(hex_asm: &str) -> Vec<u8> {
     }
     bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const USER_ID: &str = "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4";
+
+    fn server_handler() -> VmessTcpServerHandler {
+        VmessTcpServerHandler::new("any", USER_ID, true, 0, false)
+    }
+
+    // Builds an AES-128-GCM TCP request header for a domain, with the margin that is skipped
+    // before the checksum.
+    fn request_header(domain: &[u8], margin_len: u8) -> Vec<u8> {
+        let mut header = vec![0u8; 41];
+        header[0] = 1;
+        rand::thread_rng().fill_bytes(&mut header[1..34]);
+        header[34] = 0x01 | 0x04;
+        header[35] = (margin_len << 4) | 3;
+        header[37] = 1;
+        header[38..40].copy_from_slice(&443u16.to_be_bytes());
+        header[40] = 2;
+        header.push(domain.len() as u8);
+        header.extend_from_slice(domain);
+        header.extend(std::iter::repeat_n(0u8, margin_len as usize));
+
+        let mut fnv_hasher = Fnv1aHasher::new();
+        fnv_hasher.write(&header);
+        header.extend_from_slice(&fnv_hasher.finish().to_be_bytes());
+        header
+    }
+
+    // Seals the header into an AEAD request that the server handler accepts.
+    fn seal_request(handler: &VmessTcpServerHandler, header: &[u8]) -> Vec<u8> {
+        let time_secs = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+        let auth_id = create_aead_auth_id(&handler.aead_cipher, time_secs);
+        let mut request = auth_id.to_vec();
+        request.extend(seal_aead_header(&handler.instruction_key, &auth_id, header));
+        request
+    }
+
+    // Sets up a server stream from the request bytes. The client end stays open, so reading
+    // past the request would hang instead of failing with an EOF.
+    async fn setup(
+        handler: &VmessTcpServerHandler,
+        request: &[u8],
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let (mut client_stream, server_stream) = tokio::io::duplex(4096);
+        client_stream.write_all(request).await.unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            handler.setup_server_stream(Box::new(server_stream)),
+        )
+        .await
+        .expect("server read past the request");
+        drop(client_stream);
+        result
+    }
+
+    fn expect_error(result: std::io::Result<TcpServerSetupResult>) -> std::io::Error {
+        match result {
+            Ok(_) => panic!("expected the request to be rejected"),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn header_at_max_length_is_accepted() {
+        let handler = server_handler();
+        let header = request_header(&[b'a'; 255], 15);
+        assert_eq!(header.len(), MAX_HEADER_LEN);
+
+        match setup(&handler, &seal_request(&handler, &header)).await {
+            Ok(TcpServerSetupResult::TcpForward {
+                remote_location, ..
+            }) => {
+                assert_eq!(
+                    remote_location,
+                    NetLocation::new(Address::Hostname("a".repeat(255)), 443)
+                );
+            }
+            Ok(_) => panic!("expected a tcp forward"),
+            Err(e) => panic!("request was rejected: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn header_over_max_length_is_rejected() {
+        let handler = server_handler();
+        let mut header = request_header(&[b'a'; 255], 15);
+        header.push(0);
+        assert_eq!(header.len(), MAX_HEADER_LEN + 1);
+
+        // Only the auth ID, length and nonce are sent, so the server has to reject the length
+        // before it reads the header.
+        let request = seal_request(&handler, &header);
+        let error = expect_error(setup(&handler, &request[0..16 + 18 + 8]).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Header length 317 is too long");
+    }
+
+    #[tokio::test]
+    async fn header_shorter_than_its_fields_is_rejected() {
+        let handler = server_handler();
+        // The domain length says 200 bytes follow, but the header ends after 4.
+        let mut header = request_header(b"abcd", 0);
+        header[41] = 200;
+
+        let error = expect_error(setup(&handler, &seal_request(&handler, &header)).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Header is shorter than its fields");
+    }
+
+    #[tokio::test]
+    async fn header_without_instructions_is_rejected() {
+        let handler = server_handler();
+        let header = request_header(b"example.com", 0);
+
+        let error = expect_error(setup(&handler, &seal_request(&handler, &header[0..20])).await);
+        assert_eq!(error.to_string(), "Header is shorter than its fields");
+    }
+
+    #[tokio::test]
+    async fn truncated_request_is_rejected() {
+        let handler = server_handler();
+        let header = request_header(b"example.com", 0);
+        let request = seal_request(&handler, &header);
+
+        // The connection closes before the whole header arrives.
+        let (mut client_stream, server_stream) = tokio::io::duplex(4096);
+        client_stream
+            .write_all(&request[0..request.len() - 10])
+            .await
+            .unwrap();
+        drop(client_stream);
+
+        let error = expect_error(handler.setup_server_stream(Box::new(server_stream)).await);
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn malformed_length_is_rejected() {
+        let handler = server_handler();
+        let header = request_header(b"example.com", 0);
+        let mut request = seal_request(&handler, &header);
+        // Flip a bit in the sealed length, which follows the 16 byte auth ID.
+        request[16] ^= 0x01;
+
+        let error = expect_error(setup(&handler, &request).await);
+        assert_eq!(error.to_string(), "failed to open encrypted header length");
+    }
+}
@@ -1,5 +1,6 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use aes::Aes128;
 use cfb_mode::cipher::{AsyncStreamCipher, NewCipher};
@@ -930,6 +931,10 @@ impl AsyncPing for VmessStream {
         self.stream.supports_ping()
     }
 
+    fn ping_interval(&self) -> Duration {
+        self.stream.ping_interval()
+    }
+
     fn poll_write_ping(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -938,7 +943,11 @@ impl AsyncPing for VmessStream {
     }
 }
 
-impl AsyncStream for VmessStream {}
+impl AsyncStream for VmessStream {
+    fn supports_half_close(&self) -> bool {
+        self.stream.supports_half_close()
+    }
+}
 
 impl AsyncReadMessage for VmessStream {
     fn poll_read_message(
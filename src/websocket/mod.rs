@@ -1,3 +1,4 @@
+mod websocket_deflate;
 mod websocket_handler;
 mod websocket_stream;
 
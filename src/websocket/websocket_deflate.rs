@@ -0,0 +1,421 @@
+// permessage-deflate support, see https://www.rfc-editor.org/rfc/rfc7692
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use crate::config::WebsocketCompressionConfig;
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+
+// Every compressed message ends with an empty stored block, which is removed before sending
+// and needs to be added back before inflating.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Upper bound on how much larger deflating a frame's worth of incompressible data can make it.
+pub const MAX_DEFLATE_EXPANSION: usize = 64;
+
+const INFLATE_CHUNK_SIZE: usize = 16384;
+
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    // None if the parameter is not included.
+    pub client_max_window_bits: Option<u8>,
+}
+
+impl DeflateParams {
+    pub fn create_offer(config: &WebsocketCompressionConfig) -> Self {
+        Self {
+            server_no_context_takeover: config.server_no_context_takeover,
+            client_no_context_takeover: config.client_no_context_takeover,
+            server_max_window_bits: config.server_max_window_bits,
+            // Always include this to let the server know that we can limit our window size.
+            client_max_window_bits: Some(config.client_max_window_bits),
+        }
+    }
+
+    // Picks the first acceptable offer from the client's Sec-WebSocket-Extensions header, or
+    // None if there was nothing we can accept, in which case compression is not used.
+    pub fn accept_offer(header_value: &str, config: &WebsocketCompressionConfig) -> Option<Self> {
+        for extension in header_value.split(',') {
+            let offer = match Self::parse(extension) {
+                Some(Ok(offer)) => offer,
+                _ => continue,
+            };
+
+            let server_max_window_bits =
+                std::cmp::min(offer.server_max_window_bits, config.server_max_window_bits);
+            if server_max_window_bits < MIN_WINDOW_BITS {
+                continue;
+            }
+
+            // We can only ask the client to limit its window if it said it supports it.
+            let client_max_window_bits = offer
+                .client_max_window_bits
+                .map(|bits| std::cmp::min(bits, config.client_max_window_bits));
+
+            return Some(Self {
+                server_no_context_takeover: offer.server_no_context_takeover
+                    || config.server_no_context_takeover,
+                client_no_context_takeover: offer.client_no_context_takeover
+                    || config.client_no_context_takeover,
+                server_max_window_bits,
+                client_max_window_bits,
+            });
+        }
+        None
+    }
+
+    // Parses the server's Sec-WebSocket-Extensions response to our offer.
+    pub fn from_response(
+        header_value: &str,
+        config: &WebsocketCompressionConfig,
+    ) -> std::io::Result<Self> {
+        let mut params = match Self::parse(header_value) {
+            Some(params) => params?,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unsupported websocket extension response: {}", header_value),
+                ));
+            }
+        };
+
+        if params.server_max_window_bits < MIN_WINDOW_BITS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported websocket server_max_window_bits: {}",
+                    params.server_max_window_bits
+                ),
+            ));
+        }
+
+        let client_max_window_bits = match params.client_max_window_bits {
+            Some(bits) if bits < MIN_WINDOW_BITS => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unsupported websocket client_max_window_bits: {}", bits),
+                ));
+            }
+            Some(bits) => std::cmp::min(bits, config.client_max_window_bits),
+            None => config.client_max_window_bits,
+        };
+        params.client_max_window_bits = Some(client_max_window_bits);
+        params.client_no_context_takeover |= config.client_no_context_takeover;
+
+        Ok(params)
+    }
+
+    // Returns None if this is not a permessage-deflate extension, or an error if it is but
+    // the parameters are invalid.
+    fn parse(extension: &str) -> Option<std::io::Result<Self>> {
+        let mut tokens = extension.split(';').map(str::trim);
+        if !tokens.next()?.eq_ignore_ascii_case(EXTENSION_NAME) {
+            return None;
+        }
+
+        let mut params = Self {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_max_window_bits: None,
+        };
+        let mut seen_names: Vec<String> = vec![];
+
+        for token in tokens {
+            let (name, value) = match token.split_once('=') {
+                Some((name, value)) => (
+                    name.trim().to_lowercase(),
+                    Some(value.trim().trim_matches('"')),
+                ),
+                None => (token.to_lowercase(), None),
+            };
+
+            if seen_names.contains(&name) {
+                return Some(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("duplicate websocket extension parameter: {}", name),
+                )));
+            }
+
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) => {
+                    params.server_no_context_takeover = true;
+                }
+                ("client_no_context_takeover", None) => {
+                    params.client_no_context_takeover = true;
+                }
+                ("server_max_window_bits", Some(value)) => match parse_window_bits(value) {
+                    Some(bits) => params.server_max_window_bits = bits,
+                    None => return Some(Err(invalid_param_error(token))),
+                },
+                ("client_max_window_bits", None) => {
+                    params.client_max_window_bits = Some(MAX_WINDOW_BITS);
+                }
+                ("client_max_window_bits", Some(value)) => match parse_window_bits(value) {
+                    Some(bits) => params.client_max_window_bits = Some(bits),
+                    None => return Some(Err(invalid_param_error(token))),
+                },
+                _ => return Some(Err(invalid_param_error(token))),
+            }
+
+            seen_names.push(name);
+        }
+
+        Some(Ok(params))
+    }
+
+    pub fn to_header_value(&self) -> String {
+        let mut value = String::from(EXTENSION_NAME);
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits < MAX_WINDOW_BITS {
+            value.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+        match self.client_max_window_bits {
+            Some(bits) if bits < MAX_WINDOW_BITS => {
+                value.push_str(&format!("; client_max_window_bits={}", bits));
+            }
+            Some(_) => {
+                value.push_str("; client_max_window_bits");
+            }
+            None => (),
+        }
+        value
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    // The RFC allows 8, but zlib does not support raw deflate with 8 bits, so callers have to
+    // check the lower bound themselves and decide whether to reject the offer.
+    match value.parse::<u8>() {
+        Ok(bits) if (8..=MAX_WINDOW_BITS).contains(&bits) => Some(bits),
+        _ => None,
+    }
+}
+
+fn invalid_param_error(token: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid websocket extension parameter: {}", token),
+    )
+}
+
+pub struct MessageDeflate {
+    compress: Compress,
+    compress_no_context_takeover: bool,
+    compress_buf: Vec<u8>,
+
+    decompress: Decompress,
+    decompress_no_context_takeover: bool,
+    max_decompressed_size: usize,
+    // compressed data for the message that is currently being read.
+    compressed_message: Vec<u8>,
+
+    // decompressed data that has not been returned from poll_read yet.
+    decompressed_buf: Vec<u8>,
+    decompressed_offset: usize,
+}
+
+impl MessageDeflate {
+    pub fn new(params: &DeflateParams, is_client: bool, max_decompressed_size: usize) -> Self {
+        let (compress_window_bits, compress_no_context_takeover, decompress_no_context_takeover) =
+            if is_client {
+                (
+                    params.client_max_window_bits.unwrap_or(MAX_WINDOW_BITS),
+                    params.client_no_context_takeover,
+                    params.server_no_context_takeover,
+                )
+            } else {
+                (
+                    params.server_max_window_bits,
+                    params.server_no_context_takeover,
+                    params.client_no_context_takeover,
+                )
+            };
+
+        Self {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                compress_window_bits,
+            ),
+            compress_no_context_takeover,
+            compress_buf: Vec::with_capacity(32768),
+            // We always inflate with the largest window, which can handle any smaller window
+            // the peer compresses with.
+            decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            decompress_no_context_takeover,
+            max_decompressed_size,
+            compressed_message: vec![],
+            decompressed_buf: vec![],
+            decompressed_offset: 0,
+        }
+    }
+
+    // Compresses a full message, returning the payload to send with RSV1 set.
+    pub fn compress_message(&mut self, input: &[u8]) -> std::io::Result<&[u8]> {
+        self.compress_buf.clear();
+
+        let start_in = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start_in) as usize;
+            self.compress_buf.reserve(input.len() - consumed + 64);
+            self.compress
+                .compress_vec(
+                    &input[consumed..],
+                    &mut self.compress_buf,
+                    FlushCompress::Sync,
+                )
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("failed to compress websocket message: {}", e),
+                    )
+                })?;
+            let consumed = (self.compress.total_in() - start_in) as usize;
+            // The sync flush is complete once all input was consumed and there was space left.
+            if consumed == input.len() && self.compress_buf.len() < self.compress_buf.capacity() {
+                break;
+            }
+        }
+
+        if !self.compress_buf.ends_with(&DEFLATE_TAIL) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "compressed websocket message is missing sync flush",
+            ));
+        }
+        self.compress_buf
+            .truncate(self.compress_buf.len() - DEFLATE_TAIL.len());
+
+        if self.compress_no_context_takeover {
+            self.compress.reset();
+        }
+
+        Ok(&self.compress_buf)
+    }
+
+    pub fn append_compressed_data(&mut self, data: &[u8]) -> std::io::Result<()> {
+        // Compressed data is never much larger than the data it inflates to, so a message this
+        // large would exceed the limit anyway.
+        if self.compressed_message.len() + data.len() > self.max_decompressed_size {
+            return Err(self.size_limit_error());
+        }
+        self.compressed_message.extend_from_slice(data);
+        Ok(())
+    }
+
+    // Inflates the message collected by append_compressed_data, and appends it to the
+    // decompressed data.
+    pub fn finish_message(&mut self) -> std::io::Result<()> {
+        if self.decompressed_offset == self.decompressed_buf.len() {
+            self.decompressed_buf.clear();
+            self.decompressed_offset = 0;
+        }
+
+        let start_len = self.decompressed_buf.len();
+        let mut stream_ended = false;
+
+        self.compressed_message.extend_from_slice(&DEFLATE_TAIL);
+        let start_in = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start_in) as usize;
+            let decompressed_len = self.decompressed_buf.len() - start_len;
+            if stream_ended
+                || (consumed == self.compressed_message.len()
+                    && self.decompressed_buf.len() < self.decompressed_buf.capacity())
+            {
+                break;
+            }
+            if decompressed_len >= self.max_decompressed_size {
+                self.compressed_message.clear();
+                return Err(self.size_limit_error());
+            }
+
+            // Don't allocate past the limit, we only need to know if it would be exceeded.
+            self.decompressed_buf.reserve(std::cmp::min(
+                INFLATE_CHUNK_SIZE,
+                self.max_decompressed_size - decompressed_len + 1,
+            ));
+
+            let before_out = self.decompressed_buf.len();
+            let status = self
+                .decompress
+                .decompress_vec(
+                    &self.compressed_message[consumed..],
+                    &mut self.decompressed_buf,
+                    FlushDecompress::Sync,
+                )
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("failed to decompress websocket message: {}", e),
+                    )
+                })?;
+
+            match status {
+                // The peer finished the deflate stream, a new one starts with the next message.
+                Status::StreamEnd => stream_ended = true,
+                Status::BufError => {
+                    let now_consumed = (self.decompress.total_in() - start_in) as usize;
+                    if now_consumed == consumed && self.decompressed_buf.len() == before_out {
+                        // No progress could be made, so the rest of the input is not usable.
+                        break;
+                    }
+                }
+                Status::Ok => (),
+            }
+        }
+
+        self.compressed_message.clear();
+
+        if self.decompressed_buf.len() - start_len > self.max_decompressed_size {
+            return Err(self.size_limit_error());
+        }
+
+        if stream_ended || self.decompress_no_context_takeover {
+            self.decompress.reset(false);
+        }
+
+        Ok(())
+    }
+
+    pub fn has_decompressed_data(&self) -> bool {
+        self.decompressed_offset < self.decompressed_buf.len()
+    }
+
+    pub fn take_decompressed_data(&mut self, output: &mut [u8]) -> usize {
+        let remaining = &self.decompressed_buf[self.decompressed_offset..];
+        let amount = std::cmp::min(remaining.len(), output.len());
+        output[0..amount].copy_from_slice(&remaining[0..amount]);
+        self.decompressed_offset += amount;
+        if self.decompressed_offset == self.decompressed_buf.len() {
+            self.decompressed_buf.clear();
+            self.decompressed_offset = 0;
+        }
+        amount
+    }
+
+    fn size_limit_error(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "websocket message exceeds max decompressed size ({})",
+                self.max_decompressed_size
+            ),
+        )
+    }
+}
@@ -1,15 +1,17 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
 use tokio::io::AsyncWriteExt;
 
+use super::websocket_deflate::{DeflateParams, MessageDeflate};
 use super::websocket_stream::WebsocketStream;
 use crate::address::NetLocation;
 use crate::async_stream::AsyncStream;
 use crate::client_proxy_selector::ClientProxySelector;
-use crate::config::WebsocketPingType;
+use crate::config::{WebsocketCompressionConfig, WebsocketPingType};
 use crate::line_reader::LineReader;
 use crate::option_util::NoneOrOne;
 use crate::tcp_client_connector::TcpClientConnector;
@@ -21,7 +23,11 @@ use crate::tcp_handler::{
 pub struct WebsocketServerTarget {
     pub matching_path: Option<String>,
     pub matching_headers: Option<HashMap<String, String>>,
+    pub response_headers: HashMap<String, String>,
     pub ping_type: WebsocketPingType,
+    pub ping_interval: Duration,
+    pub max_missed_pongs: u32,
+    pub compression: Option<WebsocketCompressionConfig>,
     pub handler: Box<dyn TcpServerHandler>,
     pub override_proxy_provider: NoneOrOne<Arc<ClientProxySelector<TcpClientConnector>>>,
 }
@@ -78,7 +84,11 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
             let WebsocketServerTarget {
                 matching_path,
                 matching_headers,
+                response_headers,
                 ping_type,
+                ping_interval,
+                max_missed_pongs,
+                compression,
                 handler,
                 override_proxy_provider,
             } = server_target;
@@ -110,6 +120,28 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
                     None => "".to_string(),
                 };
 
+            // Fall back to uncompressed messages if the client didn't offer anything we accept.
+            let deflate_params = compression.as_ref().and_then(|config| {
+                request_headers
+                    .get("sec-websocket-extensions")
+                    .and_then(|offer| DeflateParams::accept_offer(offer, config))
+            });
+
+            let extensions_response_header = match deflate_params {
+                Some(ref params) => {
+                    format!("Sec-WebSocket-Extensions: {}\r\n", params.to_header_value())
+                }
+                None => "".to_string(),
+            };
+
+            let mut custom_response_headers = String::new();
+            for (header_key, header_val) in response_headers {
+                custom_response_headers.push_str(header_key);
+                custom_response_headers.push_str(": ");
+                custom_response_headers.push_str(header_val);
+                custom_response_headers.push_str("\r\n");
+            }
+
             let http_response = format!(
                 concat!(
                     "HTTP/1.1 101 Switching Protocol\r\n",
@@ -117,22 +149,46 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
                     "Upgrade: websocket\r\n",
                     "Connection: Upgrade\r\n",
                     "{}",
+                    "{}",
                     "Sec-WebSocket-Accept: {}\r\n",
+                    "{}",
                     "\r\n"
                 ),
-                host_response_header, websocket_version_response_header, websocket_key_response,
+                host_response_header,
+                websocket_version_response_header,
+                extensions_response_header,
+                websocket_key_response,
+                custom_response_headers,
             );
 
+            let deflate = deflate_params.map(|params| {
+                MessageDeflate::new(
+                    &params,
+                    false,
+                    compression.as_ref().unwrap().max_decompressed_size,
+                )
+            });
+
             server_stream.write_all(http_response.as_bytes()).await?;
 
             let websocket_stream = Box::new(WebsocketStream::new(
                 server_stream,
                 false,
                 ping_type.clone(),
+                *ping_interval,
+                *max_missed_pongs,
+                deflate,
                 line_reader.unparsed_data(),
             ));
 
             let mut target_setup_result = handler.setup_server_stream(websocket_stream).await;
+            if let Ok(TcpServerSetupResult::Fallback {
+                ref mut need_initial_flush,
+                ..
+            }) = target_setup_result.as_mut()
+            {
+                *need_initial_flush = true;
+            }
             if let Ok(TcpServerSetupResult::TcpForward {
                 ref mut need_initial_flush,
                 override_proxy_provider: ref mut inner_override_proxy_provider,
@@ -146,6 +202,17 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
                     *inner_override_proxy_provider = override_proxy_provider.clone();
                 }
             }
+            if let Ok(TcpServerSetupResult::HttpForward {
+                override_proxy_provider: ref mut inner_override_proxy_provider,
+                ..
+            }) = target_setup_result.as_mut()
+            {
+                if inner_override_proxy_provider.is_unspecified()
+                    && !override_proxy_provider.is_unspecified()
+                {
+                    *inner_override_proxy_provider = override_proxy_provider.clone();
+                }
+            }
 
             return target_setup_result;
         }
@@ -162,6 +229,9 @@ pub struct WebsocketTcpClientHandler {
     matching_path: Option<String>,
     matching_headers: Option<HashMap<String, String>>,
     ping_type: WebsocketPingType,
+    ping_interval: Duration,
+    max_missed_pongs: u32,
+    compression: Option<WebsocketCompressionConfig>,
     handler: Box<dyn TcpClientHandler>,
 }
 
@@ -170,12 +240,18 @@ impl WebsocketTcpClientHandler {
         matching_path: Option<String>,
         matching_headers: Option<HashMap<String, String>>,
         ping_type: WebsocketPingType,
+        ping_interval: Duration,
+        max_missed_pongs: u32,
+        compression: Option<WebsocketCompressionConfig>,
         handler: Box<dyn TcpClientHandler>,
     ) -> Self {
         Self {
             matching_path,
             matching_headers,
             ping_type,
+            ping_interval,
+            max_missed_pongs,
+            compression,
             handler,
         }
     }
@@ -211,6 +287,12 @@ impl TcpClientHandler for WebsocketTcpClientHandler {
             }
         }
 
+        if let Some(ref compression) = self.compression {
+            http_request.push_str("Sec-WebSocket-Extensions: ");
+            http_request.push_str(&DeflateParams::create_offer(compression).to_header_value());
+            http_request.push_str("\r\n");
+        }
+
         http_request.push_str(concat!(
             "Sec-WebSocket-Version: 13\r\n",
             "Sec-WebSocket-Key: "
@@ -255,10 +337,32 @@ impl TcpClientHandler for WebsocketTcpClientHandler {
             ));
         }
 
+        // The server may ignore our offer, in which case messages are sent uncompressed.
+        let deflate = match response_headers.get("sec-websocket-extensions") {
+            Some(extensions) => {
+                let compression = self.compression.as_ref().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unexpected websocket extensions: {}", extensions),
+                    )
+                })?;
+                let params = DeflateParams::from_response(extensions, compression)?;
+                Some(MessageDeflate::new(
+                    &params,
+                    true,
+                    compression.max_decompressed_size,
+                ))
+            }
+            None => None,
+        };
+
         let websocket_stream = Box::new(WebsocketStream::new(
             client_stream,
             true,
             self.ping_type.clone(),
+            self.ping_interval,
+            self.max_missed_pongs,
+            deflate,
             line_reader.unparsed_data(),
         ));
         self.handler
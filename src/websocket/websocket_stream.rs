@@ -1,11 +1,14 @@
+use std::io::IoSlice;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use futures::ready;
 use log::warn;
 use rand::RngCore;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+use super::websocket_deflate::{MessageDeflate, MAX_DEFLATE_EXPANSION};
 use crate::async_stream::{AsyncPing, AsyncStream};
 use crate::config::WebsocketPingType;
 use crate::util::allocate_vec;
@@ -14,10 +17,20 @@ pub struct WebsocketStream {
     stream: Box<dyn AsyncStream>,
     is_client: bool,
     ping_type: WebsocketPingType,
+    ping_interval: Duration,
+    // the number of ping frames we can write without receiving a pong before erroring, or 0 if
+    // unlimited.
+    max_missed_pongs: u32,
+    missed_pongs: u32,
     pending_initial_data: bool,
+    // set when permessage-deflate was negotiated during the handshake.
+    deflate: Option<MessageDeflate>,
 
     read_state: ReadState,
+    read_frame_final: bool,
     read_frame_masked: bool,
+    // whether the message that the current frame belongs to is compressed.
+    read_message_compressed: bool,
     read_frame_opcode: OpCode,
     read_frame_length: u64,
     read_frame_mask: [u8; 4],
@@ -34,6 +47,8 @@ pub struct WebsocketStream {
     ping_data: Box<[u8]>,
     ping_data_size: usize,
     pending_write_pong: bool,
+    // set when a pong was written from the read side and still needs to be flushed.
+    pong_needs_flush: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -42,6 +57,7 @@ enum ReadState {
     ReadLength { length_bytes_len: usize },
     ReadMask,
     ReadBinaryContent,
+    ReadCompressedContent,
     ReadPingContent,
     SkipContent,
 }
@@ -76,6 +92,9 @@ impl WebsocketStream {
         stream: Box<dyn AsyncStream>,
         is_client: bool,
         ping_type: WebsocketPingType,
+        ping_interval: Duration,
+        max_missed_pongs: u32,
+        deflate: Option<MessageDeflate>,
         unprocessed_data: &[u8],
     ) -> Self {
         let mut unprocessed_buf = allocate_vec(16384).into_boxed_slice();
@@ -95,9 +114,15 @@ impl WebsocketStream {
             stream,
             is_client,
             ping_type,
+            ping_interval,
+            max_missed_pongs,
+            missed_pongs: 0,
             pending_initial_data,
+            deflate,
             read_state: ReadState::Init,
+            read_frame_final: false,
             read_frame_masked: false,
+            read_message_compressed: false,
             read_frame_opcode: OpCode::Unknown(99),
             read_frame_length: 0,
             read_frame_mask: [0u8; 4],
@@ -111,6 +136,7 @@ impl WebsocketStream {
             ping_data,
             ping_data_size: 0,
             pending_write_pong: false,
+            pong_needs_flush: false,
         }
     }
 
@@ -128,7 +154,9 @@ impl WebsocketStream {
             self.unprocessed_end_offset = 0;
         }
 
-        let read_frame_final = first & 0x80 != 0;
+        self.read_frame_final = first & 0x80 != 0;
+        // RSV1 marks a compressed message when permessage-deflate is in use.
+        let read_frame_rsv1 = first & 0x40 != 0;
 
         self.read_frame_masked = second & 0x80 != 0;
 
@@ -144,7 +172,7 @@ impl WebsocketStream {
 
         self.read_frame_opcode = OpCode::from(first & 0x0f);
 
-        if !read_frame_final
+        if !self.read_frame_final
             && self.read_frame_opcode != OpCode::Binary
             && self.read_frame_opcode != OpCode::Continue
         {
@@ -157,6 +185,27 @@ impl WebsocketStream {
             ));
         }
 
+        match self.read_frame_opcode {
+            OpCode::Binary | OpCode::Text => {
+                if read_frame_rsv1 && self.deflate.is_none() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "received compressed frame without permessage-deflate",
+                    ));
+                }
+                self.read_message_compressed = read_frame_rsv1;
+            }
+            _ => {
+                // RSV1 is only set on the first frame of a message.
+                if read_frame_rsv1 {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unexpected RSV1 bit on {:?} frame", self.read_frame_opcode),
+                    ));
+                }
+            }
+        }
+
         let length = second & 0x7f;
 
         // We don't bother checking max length when it's <= 125,
@@ -251,6 +300,10 @@ impl WebsocketStream {
         buf: &mut ReadBuf<'_>,
     ) -> std::io::Result<()> {
         match self.read_frame_opcode {
+            OpCode::Binary | OpCode::Continue if self.read_message_compressed => {
+                self.read_state = ReadState::ReadCompressedContent;
+                self.step_read_compressed_content(cx, buf)
+            }
             OpCode::Binary | OpCode::Continue => {
                 if self.read_frame_length == 0 {
                     self.read_state = ReadState::Init;
@@ -288,10 +341,6 @@ impl WebsocketStream {
                 }
             }
             OpCode::Pong => {
-                // Note that pongs might be delayed, and only written when there's data to write,
-                // because copy_bidirectional does not know we need a flush, and wouldn't call
-                // poll_write until new data arrives.
-
                 // We don't keep track if we're expecting a pong, because
                 // it's allowed for the other side to only respond to the latest
                 // ping, ie. we could send 5 pings and only 1 pong response arrives.
@@ -305,6 +354,7 @@ impl WebsocketStream {
                         format!("unexpected pong data length ({})", self.read_frame_length),
                     ));
                 }
+                self.missed_pongs = 0;
                 self.read_state = ReadState::Init;
                 self.step_init(cx, buf)
             }
@@ -376,12 +426,16 @@ impl WebsocketStream {
         self.ping_data_size += read_amount;
         self.read_frame_length -= read_amount as u64;
 
+        self.unprocessed_start_offset += read_amount;
+        if self.unprocessed_start_offset == self.unprocessed_end_offset {
+            self.unprocessed_start_offset = 0;
+            self.unprocessed_end_offset = 0;
+        }
+
         if self.read_frame_length == 0 {
             self.read_frame_mask_offset = 0;
             self.read_state = ReadState::Init;
-            // this previously tried to pack_write_pong_frame right away - but there's no
-            // point in doing it, because copy_bidirectional wouldn't know there's anything
-            // to flush unless poll_write or poll_write_ping is called anyway.
+            // the pong is written by poll_read once the frames that were read are processed.
             self.pending_write_pong = true;
             return self.step_init(cx, buf);
         }
@@ -445,6 +499,60 @@ impl WebsocketStream {
         Ok(())
     }
 
+    fn step_read_compressed_content(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> std::io::Result<()> {
+        let unprocessed_len = self.unprocessed_end_offset - self.unprocessed_start_offset;
+        let read_amount = std::cmp::min(unprocessed_len as u64, self.read_frame_length) as usize;
+
+        if read_amount > 0 {
+            let content_bytes = &mut self.unprocessed_buf
+                [self.unprocessed_start_offset..self.unprocessed_start_offset + read_amount];
+            if self.read_frame_masked {
+                let iter = content_bytes.iter_mut().zip(
+                    self.read_frame_mask
+                        .iter()
+                        .cycle()
+                        .skip(self.read_frame_mask_offset),
+                );
+                for (byte, &key) in iter {
+                    *byte ^= key
+                }
+                self.read_frame_mask_offset = (self.read_frame_mask_offset + read_amount) % 4;
+            }
+
+            self.deflate
+                .as_mut()
+                .unwrap()
+                .append_compressed_data(content_bytes)?;
+
+            self.unprocessed_start_offset += read_amount;
+            if self.unprocessed_start_offset == self.unprocessed_end_offset {
+                self.unprocessed_start_offset = 0;
+                self.unprocessed_end_offset = 0;
+            }
+            self.read_frame_length -= read_amount as u64;
+        }
+
+        if self.read_frame_length > 0 {
+            return Ok(());
+        }
+
+        self.read_frame_mask_offset = 0;
+
+        if self.read_frame_final {
+            let deflate = self.deflate.as_mut().unwrap();
+            deflate.finish_message()?;
+            let written = deflate.take_decompressed_data(buf.initialize_unfilled());
+            buf.advance(written);
+        }
+
+        self.read_state = ReadState::Init;
+        self.step_init(cx, buf)
+    }
+
     fn pack_write_ping_frame(&mut self) -> bool {
         let available_space = self.write_frame.len() - self.write_frame_end_offset;
         if available_space < 6 {
@@ -499,12 +607,16 @@ impl WebsocketStream {
         true
     }
 
-    fn pack_write_frame(&mut self, input: &[u8]) -> usize {
+    fn pack_write_frame(&mut self, input: &[u8]) -> std::io::Result<usize> {
+        if self.deflate.is_some() {
+            return self.pack_write_compressed_frame(input);
+        }
+
         let available_space = self.write_frame.len() - self.write_frame_end_offset;
 
         // we need up to 14 bytes just for the header and mask.
         if available_space < 40 {
-            return 0;
+            return Ok(0);
         }
 
         let pack_amount = std::cmp::min(input.len(), available_space - 14);
@@ -518,7 +630,80 @@ impl WebsocketStream {
         );
         self.write_frame_end_offset += written;
 
-        pack_amount
+        Ok(pack_amount)
+    }
+
+    fn pack_write_compressed_frame(&mut self, input: &[u8]) -> std::io::Result<usize> {
+        let available_space = self.write_frame.len() - self.write_frame_end_offset;
+
+        // we need up to 14 bytes for the header and mask, and space in case the data
+        // doesn't compress.
+        if available_space < 40 + MAX_DEFLATE_EXPANSION {
+            return Ok(0);
+        }
+
+        let pack_amount = std::cmp::min(input.len(), available_space - 14 - MAX_DEFLATE_EXPANSION);
+
+        let compressed = self
+            .deflate
+            .as_mut()
+            .unwrap()
+            .compress_message(&input[0..pack_amount])?;
+
+        // 0x02 is binary, 0x40 is RSV1 to mark it as compressed.
+        let written = pack_frame(
+            0x42,
+            self.is_client,
+            compressed,
+            &mut self.write_frame[self.write_frame_end_offset..],
+        );
+        self.write_frame_end_offset += written;
+
+        Ok(pack_amount)
+    }
+
+    // Writes the header and data of an unmasked frame together. Only the part of the frame that
+    // couldn't be written is copied into the write frame, which is written before the next frame.
+    fn poll_write_frame_vectored(
+        &mut self,
+        cx: &mut Context<'_>,
+        input: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // Leave space for a header, like pack_write_frame, in case all of it is copied.
+        let pack_amount = std::cmp::min(input.len(), self.write_frame.len() - 14);
+        let payload = &input[0..pack_amount];
+
+        let mut header = [0u8; 10];
+        // 0x02 is binary
+        let header_len = pack_frame_header(0x02, pack_amount, None, &mut header);
+        let header = &header[0..header_len];
+
+        let slices = [IoSlice::new(header), IoSlice::new(payload)];
+        let written = match Pin::new(&mut self.stream).poll_write_vectored(cx, &slices) {
+            Poll::Ready(Ok(0)) => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "write frame eof",
+                )));
+            }
+            Poll::Ready(Ok(written)) => written,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => 0,
+        };
+
+        if written < header_len {
+            let remaining_header = &header[written..];
+            self.write_frame[0..remaining_header.len()].copy_from_slice(remaining_header);
+            self.write_frame[remaining_header.len()..remaining_header.len() + pack_amount]
+                .copy_from_slice(payload);
+            self.write_frame_end_offset = remaining_header.len() + pack_amount;
+        } else {
+            let remaining_payload = &payload[written - header_len..];
+            self.write_frame[0..remaining_payload.len()].copy_from_slice(remaining_payload);
+            self.write_frame_end_offset = remaining_payload.len();
+        }
+
+        Poll::Ready(Ok(pack_amount))
     }
 
     fn do_write_frame(&mut self, cx: &mut Context<'_>) -> std::io::Result<()> {
@@ -554,6 +739,40 @@ impl WebsocketStream {
         Ok(())
     }
 
+    // Writes the pong for a ping that was read. This is done from the read side, since when the
+    // connection is idle, nothing calls poll_write, and poll_write_ping is only called when we
+    // write pings ourselves. Otherwise the peer would close the connection after its pings went
+    // unanswered.
+    fn write_pending_pong(&mut self, cx: &mut Context<'_>) -> std::io::Result<()> {
+        if self.pending_write_pong {
+            if !self.pack_write_pong_frame() {
+                // Make space by writing out the earlier frames.
+                self.do_write_frame(cx)?;
+                if !self.pack_write_pong_frame() {
+                    // Retried when the stream is writable, since that wakes up the reader.
+                    return Ok(());
+                }
+            }
+            self.pending_write_pong = false;
+            self.pong_needs_flush = true;
+        }
+
+        if !self.pong_needs_flush {
+            return Ok(());
+        }
+        if self.write_frame_end_offset > 0 {
+            self.do_write_frame(cx)?;
+            if self.write_frame_end_offset > 0 {
+                return Ok(());
+            }
+        }
+        if let Poll::Ready(result) = Pin::new(&mut self.stream).poll_flush(cx) {
+            result?;
+            self.pong_needs_flush = false;
+        }
+        Ok(())
+    }
+
     fn reset_unprocessed_buf_offset(&mut self) {
         assert!(
             self.unprocessed_start_offset > 0
@@ -577,6 +796,15 @@ impl AsyncRead for WebsocketStream {
     ) -> std::task::Poll<std::io::Result<()>> {
         let this = self.get_mut();
 
+        // Return any data left over from a decompressed message before reading more frames.
+        if let Some(ref mut deflate) = this.deflate {
+            if deflate.has_decompressed_data() {
+                let written = deflate.take_decompressed_data(buf.initialize_unfilled());
+                buf.advance(written);
+                return Poll::Ready(Ok(()));
+            }
+        }
+
         // If there is unprocessed data and we are reading content, it must be because there
         // is data still to be read, but the passed in `buf` from the previous iteration
         // didn't have enough space to read it all.
@@ -616,6 +844,9 @@ impl AsyncRead for WebsocketStream {
                         this.unprocessed_end_offset += len;
                     }
                     Poll::Pending => {
+                        if let Err(e) = this.write_pending_pong(cx) {
+                            return Poll::Ready(Err(e));
+                        }
                         return Poll::Pending;
                     }
                 }
@@ -631,6 +862,7 @@ impl AsyncRead for WebsocketStream {
                 ReadState::ReadMask => this.step_read_mask(cx, buf),
                 ReadState::SkipContent => this.step_skip_content(cx, buf),
                 ReadState::ReadBinaryContent => this.step_read_binary_content(cx, buf),
+                ReadState::ReadCompressedContent => this.step_read_compressed_content(cx, buf),
                 ReadState::ReadPingContent => this.step_read_ping_content(cx, buf),
             };
 
@@ -639,6 +871,9 @@ impl AsyncRead for WebsocketStream {
             }
 
             if buf.filled().len() > 0 {
+                if let Err(e) = this.write_pending_pong(cx) {
+                    return Poll::Ready(Err(e));
+                }
                 return Poll::Ready(Ok(()));
             }
         }
@@ -670,6 +905,17 @@ impl AsyncWrite for WebsocketStream {
             }
         }
 
+        // Unmasked, uncompressed frames can be written without copying the data into the write
+        // frame, when there's no earlier frame still waiting to be written.
+        if !this.is_client
+            && this.deflate.is_none()
+            && this.write_frame_end_offset == 0
+            && !buf.is_empty()
+            && this.stream.is_write_vectored()
+        {
+            return this.poll_write_frame_vectored(cx, buf);
+        }
+
         let mut written = 0;
         loop {
             let input = &buf[written..];
@@ -677,7 +923,14 @@ impl AsyncWrite for WebsocketStream {
                 break;
             }
 
-            written += this.pack_write_frame(input);
+            match this.pack_write_frame(input) {
+                Ok(packed) => {
+                    written += packed;
+                }
+                Err(e) => {
+                    return Poll::Ready(Err(e));
+                }
+            }
 
             if let Err(e) = this.do_write_frame(cx) {
                 return Poll::Ready(Err(e));
@@ -737,6 +990,10 @@ impl AsyncPing for WebsocketStream {
         self.ping_type != WebsocketPingType::Disabled
     }
 
+    fn ping_interval(&self) -> Duration {
+        self.ping_interval
+    }
+
     fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
         let this = self.get_mut();
 
@@ -762,7 +1019,16 @@ impl AsyncPing for WebsocketStream {
         }
 
         let written = match this.ping_type {
-            WebsocketPingType::PingFrame => this.pack_write_ping_frame(),
+            WebsocketPingType::PingFrame => {
+                if this.max_missed_pongs > 0 && this.missed_pongs >= this.max_missed_pongs {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("no pong received for {} pings", this.missed_pongs),
+                    )));
+                }
+                this.missed_pongs += 1;
+                this.pack_write_ping_frame()
+            }
             WebsocketPingType::EmptyFrame => this.pack_write_empty_frame(),
             _ => {
                 panic!("Unexpected ping type: {:?}", this.ping_type);
@@ -782,6 +1048,40 @@ impl AsyncStream for WebsocketStream {}
 fn pack_frame(opcode: u8, use_mask: bool, input: &[u8], output: &mut [u8]) -> usize {
     let input_len = input.len();
 
+    // Client must be masked, but optional for server.
+    let mask = if use_mask {
+        let mut mask_bytes = [0u8; 4];
+        let mut rng = rand::thread_rng();
+        rng.fill_bytes(&mut mask_bytes);
+        Some(mask_bytes)
+    } else {
+        None
+    };
+
+    let offset = pack_frame_header(opcode, input_len, mask, output);
+
+    if input_len > 0 {
+        output[offset..offset + input_len].copy_from_slice(input);
+        if let Some(mask_bytes) = mask {
+            let iter = output[offset..offset + input_len]
+                .iter_mut()
+                .zip(mask_bytes.iter().cycle());
+            for (byte, &key) in iter {
+                *byte ^= key
+            }
+        }
+    }
+
+    offset + input_len
+}
+
+// Writes the frame header, with the mask if there is one, and returns its length.
+fn pack_frame_header(
+    opcode: u8,
+    input_len: usize,
+    mask: Option<[u8; 4]>,
+    output: &mut [u8],
+) -> usize {
     // 0x80 is final
     output[0] = opcode | 0x80;
 
@@ -800,34 +1100,104 @@ fn pack_frame(opcode: u8, use_mask: bool, input: &[u8], output: &mut [u8]) -> us
         10
     };
 
-    // Client must be masked, but optional for server.
-    let mask = if use_mask {
+    if let Some(mask_bytes) = mask {
         // set the masking bit
         output[1] |= 0x80;
-
-        let mut mask_bytes = [0u8; 4];
-        let mut rng = rand::thread_rng();
-        rng.fill_bytes(&mut mask_bytes);
-
         output[offset..offset + 4].copy_from_slice(&mask_bytes);
         offset += 4;
+    }
 
-        Some(mask_bytes)
-    } else {
-        None
-    };
+    offset
+}
 
-    if input_len > 0 {
-        output[offset..offset + input_len].copy_from_slice(&input);
-        if let Some(mask_bytes) = mask {
-            let iter = output[offset..offset + input_len]
-                .iter_mut()
-                .zip(mask_bytes.iter().cycle());
-            for (byte, &key) in iter {
-                *byte ^= key
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::copy_bidirectional::copy_bidirectional;
+
+    fn server_stream(
+        ping_type: WebsocketPingType,
+        ping_interval: Duration,
+        max_missed_pongs: u32,
+    ) -> (DuplexStream, WebsocketStream) {
+        let (peer, stream) = tokio::io::duplex(1024);
+        let stream = WebsocketStream::new(
+            Box::new(stream),
+            false,
+            ping_type,
+            ping_interval,
+            max_missed_pongs,
+            None,
+            &[],
+        );
+        (peer, stream)
     }
 
-    offset + input_len
+    #[tokio::test]
+    async fn ping_is_answered_on_idle_stream() {
+        // Without local pings, nothing would write the pong unless the read side did.
+        let (mut peer, mut stream) =
+            server_stream(WebsocketPingType::Disabled, Duration::from_secs(60), 0);
+        let (_other_peer, mut other) = tokio::io::duplex(1024);
+        let copy = tokio::spawn(async move {
+            copy_bidirectional(&mut stream, &mut other, false, false, None).await
+        });
+
+        // A masked ping with a zero mask.
+        peer.write_all(&[0x89, 0x84, 0, 0, 0, 0, b'p', b'i', b'n', b'g'])
+            .await
+            .unwrap();
+        let mut pong = [0u8; 6];
+        tokio::time::timeout(Duration::from_secs(5), peer.read_exact(&mut pong))
+            .await
+            .expect("ping wasn't answered")
+            .unwrap();
+        assert_eq!(pong, [0x8a, 0x04, b'p', b'i', b'n', b'g']);
+        assert!(!copy.is_finished());
+        copy.abort();
+    }
+
+    #[tokio::test]
+    async fn dead_peer_is_closed_after_missed_pongs() {
+        let (mut peer, mut stream) =
+            server_stream(WebsocketPingType::PingFrame, Duration::from_millis(50), 2);
+        let (_other_peer, mut other) = tokio::io::duplex(1024);
+        // The peer reads the pings but never answers them.
+        tokio::spawn(async move {
+            let mut data = vec![];
+            let _ = peer.read_to_end(&mut data).await;
+        });
+
+        let error = tokio::time::timeout(
+            Duration::from_secs(5),
+            copy_bidirectional(&mut stream, &mut other, false, false, None),
+        )
+        .await
+        .expect("dead peer wasn't closed")
+        .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn no_pings_are_written_while_data_is_written() {
+        let (mut peer, mut stream) =
+            server_stream(WebsocketPingType::PingFrame, Duration::from_millis(100), 1);
+        let (mut other_peer, mut other) = tokio::io::duplex(1024);
+        let copy = tokio::spawn(async move {
+            copy_bidirectional(&mut stream, &mut other, false, false, None).await
+        });
+
+        for _ in 0..15 {
+            other_peer.write_all(b"x").await.unwrap();
+            let mut frame = [0u8; 3];
+            peer.read_exact(&mut frame).await.unwrap();
+            // A ping would be [0x89, 0x00].
+            assert_eq!(frame, [0x82, 0x01, b'x']);
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        assert!(!copy.is_finished());
+        copy.abort();
+    }
 }